@@ -0,0 +1,61 @@
+//! Embeds the git description, build time and target triple for `version.rs`.
+
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-env-changed=AIRSYNC_GIT_DESCRIBE");
+
+    // Source tarballs have no .git; the description is then left unset, unless the
+    // packager passes one in.
+    let describe = std::env::var("AIRSYNC_GIT_DESCRIBE").ok().or_else(git_describe);
+    if let Some(describe) = describe {
+        println!("cargo:rustc-env=AIRSYNC_GIT_DESCRIBE={describe}");
+    }
+    // Rebuild when HEAD moves or the branch it names gets a new commit; otherwise the
+    // description (and build time) are only refreshed when something else triggers a rerun.
+    let head = Path::new("../../.git/HEAD");
+    if head.exists() {
+        println!("cargo:rerun-if-changed={}", head.display());
+        if let Some(branch) = std::fs::read_to_string(head)
+            .ok()
+            .and_then(|head| head.trim().strip_prefix("ref: ").map(String::from))
+        {
+            // A missing path would rerun this script on every build, e.g. for packed refs.
+            let branch = Path::new("../../.git").join(branch);
+            if branch.exists() {
+                println!("cargo:rerun-if-changed={}", branch.display());
+            }
+        }
+    }
+
+    // Honour SOURCE_DATE_EPOCH so reproducible builds embed a fixed time.
+    let timestamp = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        });
+    println!("cargo:rustc-env=AIRSYNC_BUILD_TIMESTAMP={timestamp}");
+    println!(
+        "cargo:rustc-env=AIRSYNC_BUILD_TARGET={}",
+        std::env::var("TARGET").unwrap_or_default()
+    );
+}
+
+fn git_describe() -> Option<String> {
+    let output = Command::new("git")
+        .args(["describe", "--always", "--dirty", "--tags"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let describe = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    (!describe.is_empty()).then_some(describe)
+}
@@ -0,0 +1,205 @@
+//! One line per HTTP request, plus the last few kept in memory for
+//! `/admin/recent-requests`. Only the method, path, status, timing and who asked are
+//! recorded: bodies and headers never are, credentials in the query string are redacted
+//! and of `Authorization` only the scheme is kept.
+
+use crate::http::now_millis;
+use crate::network::ConnectionInfo;
+use crate::request_id::{current_request_id, log_info};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::header::{AUTHORIZATION, USER_AGENT};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Requests kept for `/admin/recent-requests` unless the builder says otherwise.
+pub const DEFAULT_RECENT_REQUESTS: usize = 100;
+
+/// Query parameters whose values are replaced with `[redacted]`.
+const SENSITIVE_PARAMS: &[&str] = &["token", "access_token", "password", "secret", "key", "auth"];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccessLogEntry {
+    pub at_ms: u64,
+    pub method: String,
+    /// Path and query, with sensitive query values redacted.
+    pub path: String,
+    pub status: u16,
+    pub duration_ms: f64,
+    /// Peer address; `None` when the server wasn't started with connection info.
+    pub client_ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    /// Scheme of the `Authorization` header (`Bearer`, `Basic`, ...), never its value.
+    pub auth_scheme: Option<String>,
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecentRequestsResponse {
+    /// Requests handled since startup.
+    pub total: u64,
+    /// Requests handled since startup by status class (`2xx`, `4xx`, ...).
+    pub by_status: BTreeMap<String, u64>,
+    /// The most recent requests, oldest first.
+    pub requests: Vec<AccessLogEntry>,
+}
+
+struct LogState {
+    capacity: usize,
+    entries: VecDeque<AccessLogEntry>,
+    total: u64,
+    by_status: BTreeMap<String, u64>,
+}
+
+/// Shared ring buffer of the last `capacity` requests, with running counts.
+#[derive(Clone)]
+pub struct AccessLog {
+    inner: Arc<Mutex<LogState>>,
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_RECENT_REQUESTS)
+    }
+}
+
+impl AccessLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(LogState {
+                capacity,
+                entries: VecDeque::with_capacity(capacity),
+                total: 0,
+                by_status: BTreeMap::new(),
+            })),
+        }
+    }
+
+    pub fn record(&self, entry: AccessLogEntry) {
+        let mut state = self.inner.lock().unwrap();
+        state.total += 1;
+        *state.by_status.entry(format!("{}xx", entry.status / 100)).or_default() += 1;
+        if state.capacity == 0 {
+            return;
+        }
+        if state.entries.len() == state.capacity {
+            state.entries.pop_front();
+        }
+        state.entries.push_back(entry);
+    }
+
+    pub fn snapshot(&self) -> RecentRequestsResponse {
+        let state = self.inner.lock().unwrap();
+        RecentRequestsResponse {
+            total: state.total,
+            by_status: state.by_status.clone(),
+            requests: state.entries.iter().cloned().collect(),
+        }
+    }
+}
+
+/// Middleware logging and recording every request that passes through it.
+pub async fn log_access(
+    State(log): State<AccessLog>,
+    connection: Option<ConnectInfo<ConnectionInfo>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let at_ms = now_millis();
+    let method = request.method().to_string();
+    let path = match request.uri().query() {
+        Some(query) => format!("{}?{}", request.uri().path(), redact_query(query)),
+        None => request.uri().path().to_string(),
+    };
+    let headers = request.headers();
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    let user_agent = header(USER_AGENT).map(str::to_string);
+    let auth_scheme = header(AUTHORIZATION).and_then(|v| v.split_whitespace().next()).map(str::to_string);
+    let client_ip = connection.map(|ConnectInfo(conn)| conn.remote.ip());
+
+    let response = next.run(request).await;
+
+    let entry = AccessLogEntry {
+        at_ms,
+        method,
+        path,
+        status: response.status().as_u16(),
+        duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+        client_ip,
+        user_agent,
+        auth_scheme,
+        request_id: current_request_id(),
+    };
+    log_info!(
+        "[access] {} {} {} {:.1}ms client={} ua={:?}",
+        entry.method,
+        entry.path,
+        entry.status,
+        entry.duration_ms,
+        entry.client_ip.map_or_else(|| "-".to_string(), |ip| ip.to_string()),
+        entry.user_agent.as_deref().unwrap_or("-"),
+    );
+    log.record(entry);
+    response
+}
+
+/// `query` with the values of [`SENSITIVE_PARAMS`] replaced.
+fn redact_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if SENSITIVE_PARAMS.iter().any(|p| p.eq_ignore_ascii_case(name)) => {
+                format!("{name}=[redacted]")
+            }
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(status: u16) -> AccessLogEntry {
+        AccessLogEntry {
+            at_ms: 0,
+            method: "GET".into(),
+            path: format!("/{status}"),
+            status,
+            duration_ms: 0.0,
+            client_ip: None,
+            user_agent: None,
+            auth_scheme: None,
+            request_id: None,
+        }
+    }
+
+    #[test]
+    fn ring_buffer_keeps_the_latest_and_counts_everything() {
+        let log = AccessLog::new(2);
+        for status in [200, 404, 200, 500] {
+            log.record(entry(status));
+        }
+        let snapshot = log.snapshot();
+        assert_eq!(snapshot.total, 4);
+        assert_eq!(snapshot.by_status["2xx"], 2);
+        assert_eq!(snapshot.by_status["4xx"], 1);
+        assert_eq!(snapshot.by_status["5xx"], 1);
+        let paths: Vec<_> = snapshot.requests.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["/200", "/500"]);
+    }
+
+    #[test]
+    fn sensitive_query_values_are_redacted() {
+        assert_eq!(
+            redact_query("token=abc&limit=5&Password=hunter2&flag"),
+            "token=[redacted]&limit=5&Password=[redacted]&flag"
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! The token `/admin/*` routes expect in `X-Admin-Token`, and its rotation. A rotated-out
+//! token keeps working for a grace period, so scripts holding it can fetch the new one.
+
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// File inside the receiver state dir holding the admin token once it has been rotated.
+pub const ADMIN_TOKEN_STATE_FILE: &str = "admin_token";
+/// How long a rotated-out token is still accepted unless configured otherwise.
+pub const DEFAULT_ROTATION_GRACE: Duration = Duration::from_secs(5 * 60);
+
+/// Random bytes in a generated token, before encoding.
+const TOKEN_BYTES: usize = 32;
+
+/// Body of `POST /admin/rotate-token`, the only response that carries the new token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RotatedToken {
+    pub token: String,
+    /// When the token used for the rotation stops being accepted.
+    pub previous_valid_until_ms: u64,
+}
+
+struct Tokens {
+    current: Arc<str>,
+    /// The token rotated out last, and when it stops being accepted.
+    previous: Option<(Arc<str>, u64)>,
+}
+
+/// The admin token shared by the handlers, persisted on rotation when backed by a path.
+#[derive(Clone)]
+pub struct AdminTokens {
+    tokens: Arc<Mutex<Tokens>>,
+    path: Option<PathBuf>,
+    grace: Duration,
+}
+
+impl AdminTokens {
+    pub fn new(token: impl Into<String>) -> Self {
+        Self {
+            tokens: Arc::new(Mutex::new(Tokens {
+                current: token.into().into(),
+                previous: None,
+            })),
+            path: None,
+            grace: DEFAULT_ROTATION_GRACE,
+        }
+    }
+
+    /// The token last rotated into `<state_dir>/admin_token`, or `configured` if it was
+    /// never rotated; later rotations are saved there. `None` when there is neither.
+    pub fn open(state_dir: &Path, configured: Option<String>) -> Result<Option<Self>> {
+        let path = state_dir.join(ADMIN_TOKEN_STATE_FILE);
+        let stored = match std::fs::read_to_string(&path) {
+            Ok(stored) => Some(stored.trim().to_string()),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err).with_context(|| format!("reading {}", path.display())),
+        };
+        let token = stored.into_iter().chain(configured).find(|token| !token.is_empty());
+        Ok(token.map(|token| Self {
+            path: Some(path),
+            ..Self::new(token)
+        }))
+    }
+
+    /// Keep accepting a rotated-out token for `grace`.
+    pub fn grace_period(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
+    }
+
+    /// Whether `presented` is the current token, or the previous one within its grace period.
+    pub fn accepts(&self, presented: &[u8], now_ms: u64) -> bool {
+        let tokens = self.tokens.lock().unwrap();
+        let previous = tokens
+            .previous
+            .as_ref()
+            .is_some_and(|(token, until_ms)| now_ms < *until_ms && tokens_match(presented, token.as_bytes()));
+        // Check both either way, so timing doesn't tell which one matched.
+        tokens_match(presented, tokens.current.as_bytes()) | previous
+    }
+
+    /// Replace the token with a new random one, saved before it takes effect. The current
+    /// token becomes the previous one, replacing any still in its grace period.
+    pub fn rotate(&self, now_ms: u64) -> Result<RotatedToken> {
+        let mut tokens = self.tokens.lock().unwrap();
+        let mut bytes = [0u8; TOKEN_BYTES];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = URL_SAFE_NO_PAD.encode(bytes);
+        if let Some(path) = &self.path {
+            write_private(path, &token)?;
+        }
+        let previous_valid_until_ms = now_ms.saturating_add(self.grace.as_millis() as u64);
+        let previous = std::mem::replace(&mut tokens.current, token.as_str().into());
+        tokens.previous = Some((previous, previous_valid_until_ms));
+        Ok(RotatedToken {
+            token,
+            previous_valid_until_ms,
+        })
+    }
+}
+
+/// Compare without stopping at the first difference, so timing doesn't reveal a prefix.
+fn tokens_match(presented: &[u8], expected: &[u8]) -> bool {
+    presented.len() == expected.len() && presented.iter().zip(expected).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Write `contents` readable by the owner only, replacing `path` by a rename so a crash
+/// mid-write leaves the old token.
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = path.parent().ok_or_else(|| anyhow!("{} has no parent directory", path.display()))?;
+    std::fs::create_dir_all(dir)?;
+    let mut staged = tempfile::NamedTempFile::new_in(dir).with_context(|| format!("writing {}", path.display()))?;
+    staged.as_file().set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    staged.write_all(contents.as_bytes())?;
+    staged.as_file().sync_all()?;
+    staged
+        .persist(path)
+        .map_err(|err| anyhow!(err.error).context(format!("writing {}", path.display())))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotated_token_is_saved_privately_and_outranks_the_configured_one() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        assert!(AdminTokens::open(dir.path(), None).unwrap().is_none());
+        assert!(AdminTokens::open(dir.path(), Some(String::new())).unwrap().is_none());
+
+        let tokens = AdminTokens::open(dir.path(), Some("configured".into())).unwrap().unwrap();
+        let rotated = tokens.rotate(1_000).unwrap();
+        let path = dir.path().join(ADMIN_TOKEN_STATE_FILE);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), rotated.token);
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let reopened = AdminTokens::open(dir.path(), Some("configured".into())).unwrap().unwrap();
+        assert!(reopened.accepts(rotated.token.as_bytes(), 1_000));
+        assert!(!reopened.accepts(b"configured", 1_000));
+    }
+
+    #[test]
+    fn failed_save_keeps_the_current_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let tokens = AdminTokens::open(&dir.path().join("missing"), Some("configured".into())).unwrap().unwrap();
+        std::fs::write(dir.path().join("missing"), "a file where the state dir should be").unwrap();
+
+        assert!(tokens.rotate(1_000).is_err());
+        assert!(tokens.accepts(b"configured", 1_000));
+    }
+}
@@ -1,4 +1,5 @@
-use airsync_shared_protocol::AudioOutput;
+use crate::calibration::schedule::{SelfCalSchedule, SelfCalScheduleError};
+use airsync_shared_protocol::{AudioOutput, OutputDeviceSpec};
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::io;
@@ -7,8 +8,164 @@ use std::path::Path;
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ShairportConfig {
     pub device_name: String,
-    pub output_device: String,
+    pub output_device: OutputDeviceSpec,
     pub latency_offset_seconds: f32,
+    /// Playback gain for calibration signals, overriding the per-output default. Not a
+    /// shairport-sync setting; kept in an `airsync` group that shairport-sync ignores.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub calibration_gain: Option<f32>,
+    /// Playback EQ, applied by routing shairport-sync through [`EQ_PCM_NAME`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eq: Option<EqSettings>,
+    /// When the receiver measures its own latency through a loopback microphone. Not
+    /// written to the config file; it lives only in the settings store.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub self_calibration: Option<SelfCalSchedule>,
+}
+
+/// Software preamp and optional high-pass filter for small speakers.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EqSettings {
+    pub preamp_db: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub high_pass_hz: Option<u32>,
+}
+
+/// The preamp only attenuates, so the EQ can never clip a full-scale stream.
+pub const PREAMP_DB_RANGE: std::ops::RangeInclusive<f32> = -20.0..=0.0;
+pub const HIGH_PASS_HZ_RANGE: std::ops::RangeInclusive<u32> = 20..=500;
+
+/// ALSA PCM defined by the EQ fragment; shairport-sync plays into it while EQ is set.
+pub const EQ_PCM_NAME: &str = "airsync_eq";
+/// Where the EQ fragment is written; alsa-lib reads every `.conf` in this directory.
+pub const EQ_FRAGMENT_PATH: &str = "/etc/alsa/conf.d/60-airsync-eq.conf";
+/// Directory holding the CMT LADSPA plugins, which provide the `hpf` filter.
+pub const LADSPA_PATH: &str = "/usr/lib/ladspa";
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum EqSettingsError {
+    #[error("preamp_db must be between {} and {} dB, got {0}", PREAMP_DB_RANGE.start(), PREAMP_DB_RANGE.end())]
+    PreampOutOfRange(f32),
+    #[error("high_pass_hz must be between {} and {} Hz, got {0}", HIGH_PASS_HZ_RANGE.start(), HIGH_PASS_HZ_RANGE.end())]
+    HighPassOutOfRange(u32),
+}
+
+impl EqSettings {
+    pub fn validate(&self) -> Result<(), EqSettingsError> {
+        if !PREAMP_DB_RANGE.contains(&self.preamp_db) {
+            return Err(EqSettingsError::PreampOutOfRange(self.preamp_db));
+        }
+        match self.high_pass_hz {
+            Some(hz) if !HIGH_PASS_HZ_RANGE.contains(&hz) => Err(EqSettingsError::HighPassOutOfRange(hz)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Linear amplitude factor for `preamp_db`.
+    pub fn preamp_gain(&self) -> f32 {
+        10f32.powf(self.preamp_db / 20.0)
+    }
+}
+
+/// Longest device name accepted; AirPlay clients truncate anything past this.
+pub const MAX_DEVICE_NAME_LEN: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ConfigValidationError {
+    #[error("device_name is empty")]
+    EmptyDeviceName,
+    #[error("device_name is {0} characters, at most {MAX_DEVICE_NAME_LEN} are allowed")]
+    DeviceNameTooLong(usize),
+    #[error("device_name contains a character that can't be rendered: {0:?}")]
+    DeviceNameInvalidCharacter(char),
+    #[error("output_device `{0}` is not a valid ALSA PCM")]
+    InvalidOutputDevice(String),
+    #[error("latency_offset_seconds must be finite, got {0}")]
+    NonFiniteLatencyOffset(f32),
+    #[error("calibration_gain must be between 0.0 and 1.0, got {0}")]
+    CalibrationGainOutOfRange(f32),
+    #[error(transparent)]
+    Eq(#[from] EqSettingsError),
+    #[error(transparent)]
+    SelfCalibration(#[from] SelfCalScheduleError),
+}
+
+impl ShairportConfig {
+    /// Check every field, collecting all problems rather than stopping at the first.
+    pub fn validate(&self) -> Result<(), Vec<ConfigValidationError>> {
+        let mut errors = Vec::new();
+        let name_len = self.device_name.chars().count();
+        if self.device_name.trim().is_empty() {
+            errors.push(ConfigValidationError::EmptyDeviceName);
+        } else if name_len > MAX_DEVICE_NAME_LEN {
+            errors.push(ConfigValidationError::DeviceNameTooLong(name_len));
+        }
+        if let Some(c) = self.device_name.chars().find(|&c| c == '"' || c == '\\' || c.is_control()) {
+            errors.push(ConfigValidationError::DeviceNameInvalidCharacter(c));
+        }
+        // A spec built in code can hold a name the parser would reject; it must survive
+        // the round trip through its rendered form.
+        let rendered = self.output_device.to_string();
+        if rendered.parse::<OutputDeviceSpec>().ok().as_ref() != Some(&self.output_device) {
+            errors.push(ConfigValidationError::InvalidOutputDevice(rendered));
+        }
+        if !self.latency_offset_seconds.is_finite() {
+            errors.push(ConfigValidationError::NonFiniteLatencyOffset(self.latency_offset_seconds));
+        }
+        if let Some(gain) = self.calibration_gain.filter(|g| !(0.0..=1.0).contains(g)) {
+            errors.push(ConfigValidationError::CalibrationGainOutOfRange(gain));
+        }
+        if let Some(Err(err)) = self.eq.as_ref().map(EqSettings::validate) {
+            errors.push(err.into());
+        }
+        if let Some(Err(err)) = self.self_calibration.as_ref().map(SelfCalSchedule::validate) {
+            errors.push(err.into());
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Compare names and devices exactly and the latency offset within `epsilon`,
+    /// tolerating the rounding introduced by rendering to three decimal places.
+    pub fn approx_eq(&self, other: &ShairportConfig, epsilon: f32) -> bool {
+        self.device_name == other.device_name
+            && self.output_device == other.output_device
+            && (self.latency_offset_seconds - other.latency_offset_seconds).abs() <= epsilon
+            && match (self.calibration_gain, other.calibration_gain) {
+                (Some(a), Some(b)) => (a - b).abs() <= epsilon,
+                (a, b) => a == b,
+            }
+            && match (&self.eq, &other.eq) {
+                (Some(a), Some(b)) => (a.preamp_db - b.preamp_db).abs() <= epsilon && a.high_pass_hz == b.high_pass_hz,
+                (a, b) => a == b,
+            }
+    }
+}
+
+/// Assert two `ShairportConfig`s are equal up to `ShairportConfig::approx_eq`.
+#[macro_export]
+macro_rules! assert_config_approx_eq {
+    ($left:expr, $right:expr, $eps:expr $(,)?) => {{
+        let (left, right) = (&$left, &$right);
+        assert!(
+            left.approx_eq(right, $eps),
+            "configs differ beyond epsilon {}:\n  left: {:?}\n right: {:?}",
+            $eps,
+            left,
+            right
+        );
+    }};
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ConfigParseError {
+    #[error("missing `{0}` in shairport-sync config")]
+    MissingField(&'static str),
+    #[error("invalid value for `{field}`: {value}")]
+    InvalidValue { field: &'static str, value: String },
 }
 
 /// Generate high-quality shairport-sync configuration
@@ -21,10 +178,10 @@ pub fn generate_config(
     preferred_output: AudioOutput,
 ) -> ShairportConfig {
     let output_device = match preferred_output {
-        AudioOutput::I2S => "hw:0,0".to_string(),
-        AudioOutput::USB => "hw:1,0".to_string(),
-        AudioOutput::HDMI => "hdmi".to_string(),
-        AudioOutput::Headphone => "hw:0,0".to_string(),
+        AudioOutput::I2S => OutputDeviceSpec::hw(0, 0),
+        AudioOutput::USB => OutputDeviceSpec::hw(1, 0),
+        AudioOutput::HDMI => OutputDeviceSpec::Named("hdmi".into()),
+        AudioOutput::Headphone => OutputDeviceSpec::hw(0, 0),
     };
 
     ShairportConfig {
@@ -33,11 +190,14 @@ pub fn generate_config(
             .unwrap_or_else(|| "AirSync".to_string()),
         output_device,
         latency_offset_seconds: 0.0,
+        calibration_gain: None,
+        eq: None,
+        self_calibration: None,
     }
 }
 
 pub fn render_config_file(config: &ShairportConfig) -> String {
-    format!(
+    let mut rendered = format!(
         r#"general = {{
     name = "{name}";
     interpolation = "soxr";
@@ -56,7 +216,7 @@ alsa = {{
 metadata = {{
     enabled = "yes";
     include_cover_art = "yes";
-    pipe_name = "/tmp/shairport-sync-metadata";
+    pipe_name = "{metadata_pipe}";
 }};
 
 sessioncontrol = {{
@@ -64,9 +224,185 @@ sessioncontrol = {{
 }};
 "#,
         name = config.device_name,
-        output_device = config.output_device,
-        latency_offset = format!("{:.3}", config.latency_offset_seconds),
-    )
+        output_device = output_device_value(config),
+        latency_offset = latency_offset_value(config),
+        metadata_pipe = super::METADATA_PIPE_PATH,
+    );
+    rendered.push_str(&airsync_group(config));
+    rendered
+}
+
+/// What the `alsa` group's `output_device` is set to: the EQ PCM while EQ is set.
+pub(super) fn output_device_value(config: &ShairportConfig) -> String {
+    match config.eq {
+        Some(_) => EQ_PCM_NAME.to_string(),
+        None => config.output_device.to_string(),
+    }
+}
+
+pub(super) fn latency_offset_value(config: &ShairportConfig) -> String {
+    // `+ 0.0` turns -0.0 (a zero measured latency, negated) into 0.0.
+    format!("{:.3}", config.latency_offset_seconds + 0.0)
+}
+
+/// The `airsync` group holding the settings shairport-sync doesn't know, preceded by a
+/// blank line; empty when there are none.
+pub(super) fn airsync_group(config: &ShairportConfig) -> String {
+    let mut airsync = String::new();
+    if let Some(gain) = config.calibration_gain {
+        airsync.push_str(&format!("    calibration_gain = {gain:.3};\n"));
+    }
+    if let Some(eq) = &config.eq {
+        // The alsa group names the EQ PCM, so the device it feeds is kept here.
+        airsync.push_str(&format!("    output_device = \"{}\";\n", config.output_device));
+        airsync.push_str(&format!("    eq_preamp_db = {:.3};\n", eq.preamp_db + 0.0));
+        if let Some(hz) = eq.high_pass_hz {
+            airsync.push_str(&format!("    eq_high_pass_hz = {hz};\n"));
+        }
+    }
+    if airsync.is_empty() {
+        return airsync;
+    }
+    format!("\nairsync = {{\n{airsync}}};\n")
+}
+
+/// ALSA fragment defining [`EQ_PCM_NAME`]: a `route` gain stage for the preamp, then the
+/// CMT `hpf` LADSPA filter when a high-pass is set, into the configured output device.
+/// Without EQ only the header is rendered, so writing it clears a previous definition.
+pub fn render_eq_fragment(config: &ShairportConfig) -> String {
+    let mut rendered = String::from("# Managed by AirSync; rewritten whenever the EQ settings change.\n");
+    let Some(eq) = &config.eq else {
+        return rendered;
+    };
+    let output = format!("plug:'{}'", config.output_device);
+    let gain_slave = match eq.high_pass_hz {
+        Some(hz) => {
+            rendered.push_str(&format!(
+                r#"
+pcm.airsync_eq_highpass {{
+    type ladspa
+    slave.pcm "{output}"
+    path "{LADSPA_PATH}"
+    plugins [
+        {{
+            label hpf
+            input {{ controls [ {hz} ] }}
+        }}
+    ]
+}}
+"#
+            ));
+            "airsync_eq_highpass".to_string()
+        }
+        None => output,
+    };
+    let gain = eq.preamp_gain();
+    rendered.push_str(&format!(
+        r#"
+pcm.{EQ_PCM_NAME} {{
+    type plug
+    slave.pcm {{
+        type route
+        slave.pcm "{gain_slave}"
+        slave.channels 2
+        ttable.0.0 {gain:.4}
+        ttable.1.1 {gain:.4}
+    }}
+}}
+"#
+    ));
+    rendered
+}
+
+/// Parse the fields AirSync manages back out of a rendered shairport-sync config.
+pub fn parse_config_file(contents: &str) -> Result<ShairportConfig, ConfigParseError> {
+    let mut section = "";
+    let mut device_name = None;
+    let mut output_device = None;
+    let mut latency_offset = None;
+    let mut calibration_gain = None;
+    let mut eq_output_device = None;
+    let mut eq_preamp_db = None;
+    let mut eq_high_pass_hz = None;
+
+    for raw in contents.lines() {
+        let line = raw.split("//").next().unwrap_or("").trim();
+        if let Some(name) = line.strip_suffix('{').map(|l| l.trim_end().trim_end_matches('=').trim()) {
+            section = match name {
+                "general" | "alsa" | "airsync" => name,
+                _ => "other",
+            };
+            continue;
+        }
+        if line.starts_with('}') {
+            section = "";
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_end_matches(';').trim();
+        let unquoted = value.trim_matches('"').to_string();
+        match (section, key.trim()) {
+            ("general", "name") => device_name = Some(unquoted),
+            ("general", "audio_backend_latency_offset_in_seconds") => {
+                let parsed = value.parse::<f32>().map_err(|_| ConfigParseError::InvalidValue {
+                    field: "audio_backend_latency_offset_in_seconds",
+                    value: value.to_string(),
+                })?;
+                latency_offset = Some(parsed);
+            }
+            ("airsync", "calibration_gain") => {
+                let parsed = value.parse::<f32>().map_err(|_| ConfigParseError::InvalidValue {
+                    field: "calibration_gain",
+                    value: value.to_string(),
+                })?;
+                calibration_gain = Some(parsed);
+            }
+            ("airsync", "eq_preamp_db") => {
+                let parsed = value.parse::<f32>().map_err(|_| ConfigParseError::InvalidValue {
+                    field: "eq_preamp_db",
+                    value: value.to_string(),
+                })?;
+                eq_preamp_db = Some(parsed);
+            }
+            ("airsync", "eq_high_pass_hz") => {
+                let parsed = value.parse::<u32>().map_err(|_| ConfigParseError::InvalidValue {
+                    field: "eq_high_pass_hz",
+                    value: value.to_string(),
+                })?;
+                eq_high_pass_hz = Some(parsed);
+            }
+            ("alsa", "output_device") if unquoted == EQ_PCM_NAME => {}
+            ("alsa" | "airsync", "output_device") => {
+                let parsed = unquoted.parse().map_err(|_| ConfigParseError::InvalidValue {
+                    field: "output_device",
+                    value: unquoted.clone(),
+                })?;
+                if section == "alsa" {
+                    output_device = Some(parsed);
+                } else {
+                    eq_output_device = Some(parsed);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let eq = eq_preamp_db.map(|preamp_db| EqSettings {
+        preamp_db,
+        high_pass_hz: eq_high_pass_hz,
+    });
+    Ok(ShairportConfig {
+        device_name: device_name.ok_or(ConfigParseError::MissingField("name"))?,
+        output_device: output_device
+            .or(eq_output_device)
+            .ok_or(ConfigParseError::MissingField("output_device"))?,
+        latency_offset_seconds: latency_offset.unwrap_or(0.0),
+        calibration_gain,
+        eq,
+        self_calibration: None,
+    })
 }
 
 /// Write shairport-sync configuration to a file
@@ -89,7 +425,7 @@ mod tests {
         let config = generate_config(None, AudioOutput::Headphone);
 
         assert_eq!(config.device_name, "AirSync");
-        assert_eq!(config.output_device, "hw:0,0");
+        assert_eq!(config.output_device, OutputDeviceSpec::hw(0, 0));
     }
 
     #[test]
@@ -102,19 +438,19 @@ mod tests {
     #[test]
     fn selects_correct_output_device_for_i2s() {
         let config = generate_config(None, AudioOutput::I2S);
-        assert_eq!(config.output_device, "hw:0,0");
+        assert_eq!(config.output_device, OutputDeviceSpec::hw(0, 0));
     }
 
     #[test]
     fn selects_correct_output_device_for_usb() {
         let config = generate_config(None, AudioOutput::USB);
-        assert_eq!(config.output_device, "hw:1,0");
+        assert_eq!(config.output_device, OutputDeviceSpec::hw(1, 0));
     }
 
     #[test]
     fn selects_correct_output_device_for_hdmi() {
         let config = generate_config(None, AudioOutput::HDMI);
-        assert_eq!(config.output_device, "hdmi");
+        assert_eq!(config.output_device.to_string(), "hdmi");
     }
 
     #[test]
@@ -165,6 +501,227 @@ mod tests {
         assert!(rendered.contains("audio_backend_latency_offset_in_seconds = -0.055"));
     }
 
+    #[test]
+    fn approx_eq_tolerates_rounding_but_not_field_changes() {
+        let a = generate_config(Some("Den"), AudioOutput::USB);
+        let mut b = a.clone();
+        b.latency_offset_seconds += 0.0004;
+        assert!(a.approx_eq(&b, 0.0005));
+        b.latency_offset_seconds += 0.001;
+        assert!(!a.approx_eq(&b, 0.0005));
+
+        let mut renamed = a.clone();
+        renamed.device_name = "Kitchen".into();
+        assert!(!a.approx_eq(&renamed, 0.0005));
+
+        let mut moved = a.clone();
+        moved.output_device = OutputDeviceSpec::hw(0, 0);
+        assert!(!a.approx_eq(&moved, 0.0005));
+    }
+
+    #[test]
+    fn render_parse_roundtrip_preserves_config() {
+        for offset in [0.0, -0.055, 0.0204, -0.12345, 0.25] {
+            let mut config = generate_config(Some("Living Room"), AudioOutput::USB);
+            config.latency_offset_seconds = offset;
+            let parsed = parse_config_file(&render_config_file(&config)).unwrap();
+            assert_config_approx_eq!(parsed, config, 0.0005);
+        }
+    }
+
+    #[test]
+    fn calibration_gain_roundtrips_in_its_own_group() {
+        let mut config = generate_config(None, AudioOutput::I2S);
+        assert!(!render_config_file(&config).contains("airsync"));
+        config.calibration_gain = Some(0.35);
+        let rendered = render_config_file(&config);
+        assert!(rendered.contains("airsync = {\n    calibration_gain = 0.350;\n};"));
+        assert_config_approx_eq!(parse_config_file(&rendered).unwrap(), config, 0.0005);
+
+        let mut unset_gain = config.clone();
+        unset_gain.calibration_gain = None;
+        assert!(!config.approx_eq(&unset_gain, 0.0005));
+    }
+
+    #[test]
+    fn eq_routes_output_through_the_eq_pcm_and_roundtrips() {
+        let mut config = generate_config(None, AudioOutput::USB);
+        config.eq = Some(EqSettings {
+            preamp_db: -6.0,
+            high_pass_hz: Some(80),
+        });
+        let rendered = render_config_file(&config);
+        assert!(rendered.contains(&format!("output_device = \"{EQ_PCM_NAME}\";\n    audio_backend")));
+        assert!(rendered.contains("airsync = {\n    output_device = \"hw:1,0\";\n    eq_preamp_db = -6.000;\n    eq_high_pass_hz = 80;\n};"));
+        assert_config_approx_eq!(parse_config_file(&rendered).unwrap(), config, 0.0005);
+
+        config.eq = Some(EqSettings {
+            preamp_db: -3.5,
+            high_pass_hz: None,
+        });
+        let rendered = render_config_file(&config);
+        assert!(!rendered.contains("eq_high_pass_hz"));
+        assert_config_approx_eq!(parse_config_file(&rendered).unwrap(), config, 0.0005);
+
+        let mut cleared = config.clone();
+        cleared.eq = None;
+        assert!(!config.approx_eq(&cleared, 0.0005));
+        let rendered = render_config_file(&cleared);
+        assert!(!rendered.contains("airsync"));
+        assert!(!rendered.contains(EQ_PCM_NAME));
+        assert_eq!(rendered, render_config_file(&generate_config(None, AudioOutput::USB)));
+    }
+
+    #[test]
+    fn eq_fragment_defines_gain_and_optional_high_pass() {
+        let mut config = generate_config(None, AudioOutput::USB);
+        config.eq = Some(EqSettings {
+            preamp_db: -6.0,
+            high_pass_hz: None,
+        });
+        let fragment = render_eq_fragment(&config);
+        assert!(fragment.contains(&format!("pcm.{EQ_PCM_NAME} {{")));
+        assert!(fragment.contains("slave.pcm \"plug:'hw:1,0'\""));
+        assert!(fragment.contains("ttable.0.0 0.5012"));
+        assert!(!fragment.contains("ladspa"));
+
+        config.eq.as_mut().unwrap().high_pass_hz = Some(120);
+        let fragment = render_eq_fragment(&config);
+        assert!(fragment.contains("label hpf"));
+        assert!(fragment.contains("controls [ 120 ]"));
+        assert!(fragment.contains("slave.pcm \"airsync_eq_highpass\""));
+
+        config.eq = None;
+        let cleared = render_eq_fragment(&config);
+        assert!(cleared.lines().all(|l| l.starts_with('#')));
+    }
+
+    #[test]
+    fn eq_validation_bounds_preamp_and_high_pass() {
+        let eq = |preamp_db, high_pass_hz| EqSettings { preamp_db, high_pass_hz };
+        assert_eq!(eq(0.0, None).validate(), Ok(()));
+        assert_eq!(eq(-20.0, Some(20)).validate(), Ok(()));
+        assert_eq!(eq(0.5, None).validate(), Err(EqSettingsError::PreampOutOfRange(0.5)));
+        assert_eq!(eq(-20.5, None).validate(), Err(EqSettingsError::PreampOutOfRange(-20.5)));
+        assert!(eq(f32::NAN, None).validate().is_err());
+        assert_eq!(eq(-3.0, Some(10)).validate(), Err(EqSettingsError::HighPassOutOfRange(10)));
+    }
+
+    #[test]
+    fn validate_reports_each_problem() {
+        let valid = generate_config(Some("Living Room"), AudioOutput::USB);
+        assert_eq!(valid.validate(), Ok(()));
+        for device in ["hdmi", "hw:1,0", "plughw:CARD=Device,DEV=0", "hdmi:CARD=vc4hdmi0"] {
+            let config = ShairportConfig {
+                output_device: device.parse().unwrap(),
+                ..valid.clone()
+            };
+            assert_eq!(config.validate(), Ok(()), "{device}");
+        }
+
+        let invalid = |update: fn(&mut ShairportConfig)| {
+            let mut config = valid.clone();
+            update(&mut config);
+            config.validate().unwrap_err()
+        };
+        assert_eq!(
+            invalid(|c| c.device_name = " ".into()),
+            vec![ConfigValidationError::EmptyDeviceName]
+        );
+        assert_eq!(
+            invalid(|c| c.device_name = "x".repeat(65)),
+            vec![ConfigValidationError::DeviceNameTooLong(65)]
+        );
+        assert_eq!(
+            invalid(|c| c.device_name = "Den \"2\"".into()),
+            vec![ConfigValidationError::DeviceNameInvalidCharacter('"')]
+        );
+        assert_eq!(
+            invalid(|c| c.output_device = OutputDeviceSpec::Named("my pcm".into())),
+            vec![ConfigValidationError::InvalidOutputDevice("my pcm".into())]
+        );
+        assert_eq!(
+            invalid(|c| c.output_device = OutputDeviceSpec::Plug(String::new())),
+            vec![ConfigValidationError::InvalidOutputDevice("plug:".into())]
+        );
+        assert_eq!(
+            invalid(|c| c.latency_offset_seconds = f32::INFINITY),
+            vec![ConfigValidationError::NonFiniteLatencyOffset(f32::INFINITY)]
+        );
+        assert_eq!(
+            invalid(|c| c.calibration_gain = Some(1.5)),
+            vec![ConfigValidationError::CalibrationGainOutOfRange(1.5)]
+        );
+        assert_eq!(
+            invalid(|c| {
+                c.eq = Some(EqSettings {
+                    preamp_db: 6.0,
+                    high_pass_hz: None,
+                })
+            }),
+            vec![ConfigValidationError::Eq(EqSettingsError::PreampOutOfRange(6.0))]
+        );
+    }
+
+    #[test]
+    fn validate_collects_every_error() {
+        let config = ShairportConfig {
+            device_name: String::new(),
+            output_device: OutputDeviceSpec::Named("a;b".into()),
+            latency_offset_seconds: f32::NAN,
+            calibration_gain: Some(-0.1),
+            eq: None,
+            self_calibration: None,
+        };
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.len(), 4);
+        assert_eq!(errors[0], ConfigValidationError::EmptyDeviceName);
+        assert_eq!(errors[1], ConfigValidationError::InvalidOutputDevice("a;b".into()));
+        assert!(matches!(errors[2], ConfigValidationError::NonFiniteLatencyOffset(v) if v.is_nan()));
+        assert_eq!(errors[3], ConfigValidationError::CalibrationGainOutOfRange(-0.1));
+    }
+
+    #[test]
+    #[should_panic(expected = "configs differ")]
+    fn assert_config_approx_eq_panics_on_mismatch() {
+        let a = generate_config(None, AudioOutput::HDMI);
+        let mut b = a.clone();
+        b.latency_offset_seconds = 0.1;
+        assert_config_approx_eq!(a, b, 0.0005);
+    }
+
+    #[test]
+    fn parse_reports_missing_and_invalid_fields() {
+        let rendered = render_config_file(&generate_config(None, AudioOutput::HDMI));
+        let without_device: String = rendered
+            .lines()
+            .filter(|l| !l.contains("output_device ="))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(
+            parse_config_file(&without_device),
+            Err(ConfigParseError::MissingField("output_device"))
+        );
+
+        let bad_device = rendered.replace("output_device = \"hdmi\"", "output_device = \"hw:x,y\"");
+        assert_eq!(
+            parse_config_file(&bad_device),
+            Err(ConfigParseError::InvalidValue {
+                field: "output_device",
+                value: "hw:x,y".into(),
+            })
+        );
+
+        let corrupted = rendered.replace(
+            "audio_backend_latency_offset_in_seconds = 0.000",
+            "audio_backend_latency_offset_in_seconds = abc",
+        );
+        assert!(matches!(
+            parse_config_file(&corrupted),
+            Err(ConfigParseError::InvalidValue { .. })
+        ));
+    }
+
     #[test]
     fn config_prevents_soxr_crash_with_proper_alsa_settings() {
         // This test ensures the generated config includes all necessary ALSA settings
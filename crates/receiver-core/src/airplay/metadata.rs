@@ -0,0 +1,252 @@
+use crate::status::{StatusEvent, StatusTracker};
+use airsync_shared_protocol::Metadata;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+
+/// Pipe shairport-sync writes metadata to, matching `metadata.pipe_name` in the rendered config.
+pub const METADATA_PIPE_PATH: &str = "/tmp/shairport-sync-metadata";
+
+/// One `<item>` from the shairport-sync metadata pipe, with type and code decoded from hex.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataItem {
+    pub kind: String,
+    pub code: String,
+    pub data: Vec<u8>,
+}
+
+impl MetadataItem {
+    fn parse(raw: &str) -> Option<MetadataItem> {
+        let kind = decode_fourcc(tag_contents(raw, "<type>", "</type>")?)?;
+        let code = decode_fourcc(tag_contents(raw, "<code>", "</code>")?)?;
+        let data = match tag_contents(raw, "<data encoding=\"base64\">", "</data>") {
+            Some(encoded) => {
+                let compact: String = encoded.chars().filter(|c| !c.is_whitespace()).collect();
+                STANDARD.decode(compact).ok()?
+            }
+            None => Vec::new(),
+        };
+        Some(MetadataItem { kind, code, data })
+    }
+
+    fn text(&self) -> Option<String> {
+        let text = String::from_utf8_lossy(&self.data).trim().to_string();
+        (!text.is_empty()).then_some(text)
+    }
+
+    /// Map the items AirSync cares about onto status events; everything else is ignored.
+    pub fn status_event(&self) -> Option<StatusEvent> {
+        let field = |artist, title, album| {
+            StatusEvent::Metadata(Metadata {
+                artist,
+                title,
+                album,
+            })
+        };
+        match (self.kind.as_str(), self.code.as_str()) {
+            ("ssnc", "pbeg") => Some(StatusEvent::SessionStarted),
+            ("ssnc", "pend") => Some(StatusEvent::SessionEnded),
+            ("ssnc", "paus") => Some(StatusEvent::Paused),
+            ("ssnc", "pres") => Some(StatusEvent::Resumed),
+            ("ssnc", "prgr") => Some(StatusEvent::Progress),
+            ("ssnc", "mdst") => Some(StatusEvent::MetadataStarted),
+            ("core", "asar") => Some(field(self.text(), None, None)),
+            ("core", "minm") => Some(field(None, self.text(), None)),
+            ("core", "asal") => Some(field(None, None, self.text())),
+            _ => None,
+        }
+    }
+}
+
+fn tag_contents<'a>(raw: &'a str, open: &str, close: &str) -> Option<&'a str> {
+    let start = raw.find(open)? + open.len();
+    let end = start + raw[start..].find(close)?;
+    Some(&raw[start..end])
+}
+
+fn decode_fourcc(hex: &str) -> Option<String> {
+    let value = u32::from_str_radix(hex.trim(), 16).ok()?;
+    let bytes = value.to_be_bytes();
+    bytes
+        .iter()
+        .all(u8::is_ascii_graphic)
+        .then(|| String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Incremental parser for the pipe's XML-ish stream; items may span reads.
+#[derive(Debug, Default)]
+pub struct MetadataParser {
+    buffer: String,
+}
+
+impl MetadataParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append `chunk` and return every item completed by it. Malformed items are dropped.
+    pub fn push(&mut self, chunk: &str) -> Vec<MetadataItem> {
+        const END: &str = "</item>";
+        self.buffer.push_str(chunk);
+        let mut items = Vec::new();
+        while let Some(end) = self.buffer.find(END) {
+            let raw: String = self.buffer.drain(..end + END.len()).collect();
+            if let Some(start) = raw.rfind("<item>") {
+                items.extend(MetadataItem::parse(&raw[start..]));
+            }
+        }
+        items
+    }
+}
+
+/// Follow the metadata pipe, reopening it whenever shairport-sync closes or recreates it,
+/// and feed recognised events into `tracker`.
+pub fn spawn_metadata_reader(
+    path: PathBuf,
+    tracker: StatusTracker,
+    now_ms: fn() -> u64,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match tokio::fs::File::open(&path).await {
+                Ok(mut pipe) => {
+                    let mut parser = MetadataParser::new();
+                    let mut buf = vec![0u8; 4096];
+                    loop {
+                        match pipe.read(&mut buf).await {
+                            Ok(0) => break,
+                            Ok(n) => {
+                                for item in parser.push(&String::from_utf8_lossy(&buf[..n])) {
+                                    if let Some(event) = item.status_event() {
+                                        tracker.record(event, now_ms());
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                eprintln!("[metadata] read error on {}: {e}", path.display());
+                                break;
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    eprintln!("[metadata] cannot open {}: {e}", path.display());
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use airsync_shared_protocol::PlaybackStatus;
+
+    fn hex(code: &str) -> String {
+        code.bytes().map(|b| format!("{b:02x}")).collect()
+    }
+
+    fn item(kind: &str, code: &str, data: Option<&str>) -> String {
+        let mut out = format!(
+            "<item><type>{}</type><code>{}</code><length>{}</length>",
+            hex(kind),
+            hex(code),
+            data.map_or(0, str::len)
+        );
+        if let Some(data) = data {
+            out.push_str(&format!(
+                "\n<data encoding=\"base64\">\n{}</data>",
+                STANDARD.encode(data)
+            ));
+        }
+        out.push_str("</item>\n");
+        out
+    }
+
+    #[test]
+    fn parses_items_with_and_without_data() {
+        let mut parser = MetadataParser::new();
+        let stream = item("ssnc", "pbeg", None) + &item("core", "minm", Some("Blue in Green"));
+        let items = parser.push(&stream);
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].kind, "ssnc");
+        assert_eq!(items[0].code, "pbeg");
+        assert!(items[0].data.is_empty());
+        assert_eq!(items[1].data, b"Blue in Green");
+    }
+
+    #[test]
+    fn handles_items_split_across_reads() {
+        let mut parser = MetadataParser::new();
+        let stream = item("core", "asar", Some("Miles Davis"));
+        let (a, b) = stream.split_at(stream.len() / 2);
+        assert!(parser.push(a).is_empty());
+        let items = parser.push(b);
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].code, "asar");
+    }
+
+    #[test]
+    fn drops_malformed_items_and_keeps_going() {
+        let mut parser = MetadataParser::new();
+        let stream = "<item><type>zz</type><code>6d696e6d</code></item>\n".to_string()
+            + &item("ssnc", "pend", None);
+        let items = parser.push(&stream);
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].code, "pend");
+    }
+
+    #[test]
+    fn pause_and_resume_items_drive_the_tracker() {
+        let mut parser = MetadataParser::new();
+        let tracker = StatusTracker::new(0);
+        let mut feed = |stream: String, at_ms| {
+            for event in parser.push(&stream).iter().filter_map(MetadataItem::status_event) {
+                tracker.record(event, at_ms);
+            }
+            tracker.refresh(at_ms).status
+        };
+
+        assert_eq!(feed(item("ssnc", "pbeg", None), 10), PlaybackStatus::Playing);
+        assert_eq!(feed(item("ssnc", "paus", None), 20), PlaybackStatus::Paused);
+        assert_eq!(feed(item("ssnc", "pres", None) + &item("ssnc", "prgr", Some("1/2/3")), 30), PlaybackStatus::Playing);
+        assert_eq!(feed(item("ssnc", "paus", None), 40), PlaybackStatus::Paused);
+        assert_eq!(feed(item("ssnc", "pend", None), 50), PlaybackStatus::Idle);
+    }
+
+    #[test]
+    fn maps_items_to_status_events() {
+        let mut parser = MetadataParser::new();
+        let stream = [
+            item("ssnc", "pbeg", None),
+            item("ssnc", "mdst", None),
+            item("core", "asal", Some("Kind of Blue")),
+            item("ssnc", "prgr", Some("1/2/3")),
+            item("ssnc", "pvol", Some("-20.0")),
+            item("ssnc", "pend", None),
+        ]
+        .concat();
+        let events: Vec<StatusEvent> = parser
+            .push(&stream)
+            .iter()
+            .filter_map(MetadataItem::status_event)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                StatusEvent::SessionStarted,
+                StatusEvent::MetadataStarted,
+                StatusEvent::Metadata(Metadata {
+                    artist: None,
+                    title: None,
+                    album: Some("Kind of Blue".into()),
+                }),
+                StatusEvent::Progress,
+                StatusEvent::SessionEnded,
+            ]
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use crate::events::ReceiverEvent;
+use crate::status::{StatusSnapshot, StatusTracker};
+use airsync_shared_protocol::Metadata;
+use anyhow::{Context, Result};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// File inside the receiver state dir holding the last track metadata seen.
+pub const METADATA_STATE_FILE: &str = "last_metadata.json";
+
+/// The last track metadata shairport-sync reported, kept as JSON so `/api/metadata` can
+/// still answer while nothing is playing, including after a restart.
+#[derive(Clone)]
+pub struct MetadataStore {
+    path: PathBuf,
+    current: Arc<Mutex<Option<Metadata>>>,
+}
+
+impl MetadataStore {
+    /// Load `<state_dir>/last_metadata.json`. A missing or unreadable file starts empty;
+    /// the cache is only a convenience and shouldn't keep the receiver from starting.
+    pub fn open(state_dir: &Path) -> Self {
+        let path = state_dir.join(METADATA_STATE_FILE);
+        let current = read(&path).unwrap_or_else(|e| {
+            eprintln!("[metadata] ignoring cached metadata: {e:#}");
+            None
+        });
+        Self {
+            path,
+            current: Arc::new(Mutex::new(current)),
+        }
+    }
+
+    pub fn load(&self) -> Option<Metadata> {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Remember `meta` and rewrite the file when it differs from what is stored. It is
+    /// kept in memory even when writing fails.
+    pub fn update(&self, meta: Metadata) -> Result<()> {
+        let mut current = self.current.lock().unwrap();
+        if current.as_ref() == Some(&meta) {
+            return Ok(());
+        }
+        *current = Some(meta);
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&self.path, serde_json::to_vec_pretty(&*current)?)
+            .with_context(|| format!("writing {}", self.path.display()))
+    }
+}
+
+fn read(path: &Path) -> Result<Option<Metadata>> {
+    match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).with_context(|| format!("parsing {}", path.display())),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("reading {}", path.display())),
+    }
+}
+
+/// Store the tracker's metadata whenever it changes. Sessions ending clear the tracker
+/// but leave the store alone.
+pub fn spawn_metadata_persistence(tracker: StatusTracker, store: MetadataStore) -> tokio::task::JoinHandle<()> {
+    let (_, mut updates) = tracker.subscribe();
+    tokio::spawn(async move {
+        while let Some(event) = updates.recv().await {
+            if let ReceiverEvent::Status(StatusSnapshot {
+                metadata: Some(metadata),
+                ..
+            }) = event
+            {
+                if let Err(e) = store.update(metadata) {
+                    eprintln!("[metadata] {e:#}");
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::StatusEvent;
+    use tempfile::tempdir;
+
+    fn track(title: &str) -> Metadata {
+        Metadata {
+            artist: Some("Miles Davis".into()),
+            title: Some(title.into()),
+            album: Some("Kind of Blue".into()),
+        }
+    }
+
+    #[test]
+    fn update_writes_the_file_and_reopening_reads_it() {
+        let dir = tempdir().unwrap();
+        let store = MetadataStore::open(dir.path());
+        assert_eq!(store.load(), None);
+
+        store.update(track("So What")).unwrap();
+        let written: Metadata =
+            serde_json::from_slice(&std::fs::read(dir.path().join(METADATA_STATE_FILE)).unwrap()).unwrap();
+        assert_eq!(written, track("So What"));
+
+        store.update(track("Blue in Green")).unwrap();
+        assert_eq!(MetadataStore::open(dir.path()).load(), Some(track("Blue in Green")));
+    }
+
+    #[test]
+    fn corrupt_file_starts_empty() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join(METADATA_STATE_FILE), b"{not json").unwrap();
+        let store = MetadataStore::open(dir.path());
+        assert_eq!(store.load(), None);
+        store.update(track("So What")).unwrap();
+        assert_eq!(MetadataStore::open(dir.path()).load(), Some(track("So What")));
+    }
+
+    #[tokio::test]
+    async fn persistence_keeps_metadata_after_the_session_ends() {
+        let dir = tempdir().unwrap();
+        let store = MetadataStore::open(dir.path());
+        let tracker = StatusTracker::new(0);
+        let task = spawn_metadata_persistence(tracker.clone(), store.clone());
+
+        tracker.record(StatusEvent::SessionStarted, 10);
+        tracker.record(StatusEvent::Metadata(track("Freddie Freeloader")), 20);
+        for _ in 0..100 {
+            if store.load().is_some() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        tracker.record(StatusEvent::SessionEnded, 30);
+        for _ in 0..100 {
+            tokio::task::yield_now().await;
+        }
+        task.abort();
+
+        assert_eq!(store.load(), Some(track("Freddie Freeloader")));
+        assert_eq!(MetadataStore::open(dir.path()).load(), Some(track("Freddie Freeloader")));
+    }
+}
@@ -1,3 +1,11 @@
 mod config;
+mod metadata;
+mod metadata_store;
+mod template;
+mod transport;
 
 pub use config::*;
+pub use metadata::*;
+pub use metadata_store::*;
+pub use template::*;
+pub use transport::*;
@@ -0,0 +1,252 @@
+//! User templates for the shairport-sync config, for sections AirSync doesn't model
+//! (diagnostics, dsp, custom sessioncontrol values) that would otherwise be lost on
+//! every apply. The fields AirSync manages are filled into `{{placeholders}}`, and its
+//! `airsync` group is appended as with the built-in format.
+
+use super::config::{airsync_group, latency_offset_value, output_device_value, render_config_file, ShairportConfig};
+use anyhow::{Context, Result};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// Template used instead of the built-in format when it exists.
+pub const SHAIRPORT_TEMPLATE_PATH: &str = "/etc/airsync/shairport-template.conf";
+
+/// Placeholders every template must contain, so the fields AirSync manages are written.
+/// `{{metadata_pipe}}` may be used as well.
+pub const REQUIRED_PLACEHOLDERS: [&str; 4] = ["name", "output_device", "latency_offset", "mixer_control"];
+
+/// ALSA mixer control filled into `{{mixer_control}}`, for shairport-sync's
+/// `mixer_control_name`.
+pub const MIXER_CONTROL: &str = "PCM";
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum TemplateError {
+    #[error("shairport-sync template is missing {}", placeholder_list(.0))]
+    MissingPlaceholders(Vec<&'static str>),
+    #[error("shairport-sync template has unknown {}", placeholder_list(.0))]
+    UnknownPlaceholders(Vec<String>),
+    #[error("shairport-sync template has an unclosed `{{{{` at byte {0}")]
+    Unclosed(usize),
+}
+
+fn placeholder_list<S: AsRef<str>>(names: &[S]) -> String {
+    names.iter().map(|n| format!("{{{{{}}}}}", n.as_ref())).collect::<Vec<_>>().join(", ")
+}
+
+/// Renders the shairport-sync config file for a [`ShairportConfig`].
+pub trait ConfigRenderer: Send + Sync {
+    fn render(&self, config: &ShairportConfig) -> Result<String>;
+}
+
+/// The built-in format, [`render_config_file`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BuiltinRenderer;
+
+impl ConfigRenderer for BuiltinRenderer {
+    fn render(&self, config: &ShairportConfig) -> Result<String> {
+        Ok(render_config_file(config))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Placeholder {
+    Name,
+    OutputDevice,
+    LatencyOffset,
+    MixerControl,
+    MetadataPipe,
+}
+
+impl Placeholder {
+    const ALL: [Placeholder; 5] = [
+        Placeholder::Name,
+        Placeholder::OutputDevice,
+        Placeholder::LatencyOffset,
+        Placeholder::MixerControl,
+        Placeholder::MetadataPipe,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Placeholder::Name => "name",
+            Placeholder::OutputDevice => "output_device",
+            Placeholder::LatencyOffset => "latency_offset",
+            Placeholder::MixerControl => "mixer_control",
+            Placeholder::MetadataPipe => "metadata_pipe",
+        }
+    }
+}
+
+enum Segment {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+/// A validated template.
+pub struct ConfigTemplate {
+    segments: Vec<Segment>,
+}
+
+impl ConfigTemplate {
+    /// Split `source` at its placeholders, failing when one is unknown or a required one
+    /// is missing. Whitespace inside the braces is ignored.
+    pub fn parse(source: &str) -> Result<Self, TemplateError> {
+        let mut segments = Vec::new();
+        let mut unknown = Vec::new();
+        let mut rest = source;
+        while let Some(open) = rest.find("{{") {
+            let close = rest[open..]
+                .find("}}")
+                .ok_or(TemplateError::Unclosed(source.len() - rest.len() + open))?;
+            segments.push(Segment::Text(rest[..open].to_string()));
+            let name = rest[open + 2..open + close].trim();
+            match Placeholder::ALL.into_iter().find(|p| p.name() == name) {
+                Some(known) => segments.push(Segment::Placeholder(known)),
+                None => unknown.push(name.to_string()),
+            }
+            rest = &rest[open + close + 2..];
+        }
+        segments.push(Segment::Text(rest.to_string()));
+        if !unknown.is_empty() {
+            return Err(TemplateError::UnknownPlaceholders(unknown));
+        }
+        let missing: Vec<_> = REQUIRED_PLACEHOLDERS
+            .into_iter()
+            .filter(|required| !segments.iter().any(|s| matches!(s, Segment::Placeholder(p) if p.name() == *required)))
+            .collect();
+        if !missing.is_empty() {
+            return Err(TemplateError::MissingPlaceholders(missing));
+        }
+        Ok(Self { segments })
+    }
+
+    /// The template with `config` filled in, in one pass so a value that looks like a
+    /// placeholder stays as it is, and the `airsync` group appended.
+    pub fn render_config(&self, config: &ShairportConfig) -> String {
+        let mut rendered = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => rendered.push_str(text),
+                Segment::Placeholder(Placeholder::Name) => rendered.push_str(&config.device_name),
+                Segment::Placeholder(Placeholder::OutputDevice) => rendered.push_str(&output_device_value(config)),
+                Segment::Placeholder(Placeholder::LatencyOffset) => rendered.push_str(&latency_offset_value(config)),
+                Segment::Placeholder(Placeholder::MixerControl) => rendered.push_str(MIXER_CONTROL),
+                Segment::Placeholder(Placeholder::MetadataPipe) => rendered.push_str(super::METADATA_PIPE_PATH),
+            }
+        }
+        rendered.push_str(&airsync_group(config));
+        rendered
+    }
+}
+
+impl ConfigRenderer for ConfigTemplate {
+    fn render(&self, config: &ShairportConfig) -> Result<String> {
+        Ok(self.render_config(config))
+    }
+}
+
+/// Renders through the template at `path`, read on every render so edits take effect
+/// on the next apply, or the built-in format while there is no file.
+#[derive(Debug, Clone)]
+pub struct TemplateFileRenderer {
+    path: PathBuf,
+}
+
+impl TemplateFileRenderer {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl ConfigRenderer for TemplateFileRenderer {
+    fn render(&self, config: &ShairportConfig) -> Result<String> {
+        let source = match std::fs::read_to_string(&self.path) {
+            Ok(source) => source,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(render_config_file(config)),
+            Err(err) => return Err(err).with_context(|| format!("reading {}", self.path.display())),
+        };
+        let template = ConfigTemplate::parse(&source).with_context(|| format!("in {}", self.path.display()))?;
+        Ok(template.render_config(config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::airplay::{generate_config, parse_config_file, EqSettings, EQ_PCM_NAME};
+    use airsync_shared_protocol::AudioOutput;
+
+    const TEMPLATE: &str = r#"general = {
+    name = "{{name}}";
+    audio_backend_latency_offset_in_seconds = {{ latency_offset }};
+};
+
+alsa = {
+    output_device = "{{output_device}}";
+    mixer_control_name = "{{mixer_control}}";
+};
+
+diagnostics = {
+    log_verbosity = 1;
+};
+"#;
+
+    fn config() -> ShairportConfig {
+        let mut config = generate_config(Some("Den {{output_device}}"), AudioOutput::USB);
+        config.latency_offset_seconds = -0.0421;
+        config
+    }
+
+    #[test]
+    fn placeholders_are_filled_and_the_rest_kept() {
+        let rendered = ConfigTemplate::parse(TEMPLATE).unwrap().render_config(&config());
+        assert!(rendered.contains(r#"name = "Den {{output_device}}";"#));
+        assert!(rendered.contains("audio_backend_latency_offset_in_seconds = -0.042;"));
+        assert!(rendered.contains(r#"output_device = "hw:1,0";"#));
+        assert!(rendered.contains("log_verbosity = 1;"));
+        assert!(rendered.contains(r#"mixer_control_name = "PCM";"#));
+        crate::assert_config_approx_eq!(parse_config_file(&rendered).unwrap(), config(), 0.001);
+
+        let mut with_eq = config();
+        with_eq.eq = Some(EqSettings {
+            preamp_db: -3.0,
+            high_pass_hz: None,
+        });
+        let rendered = ConfigTemplate::parse(TEMPLATE).unwrap().render_config(&with_eq);
+        assert!(rendered.contains(&format!(r#"output_device = "{EQ_PCM_NAME}";"#)));
+        assert_eq!(parse_config_file(&rendered).unwrap().eq, with_eq.eq);
+    }
+
+    #[test]
+    fn missing_and_unknown_placeholders_are_listed() {
+        let err = ConfigTemplate::parse("general = { name = \"{{name}}\"; };").err().unwrap();
+        assert_eq!(
+            err,
+            TemplateError::MissingPlaceholders(vec!["output_device", "latency_offset", "mixer_control"])
+        );
+        assert_eq!(
+            err.to_string(),
+            "shairport-sync template is missing {{output_device}}, {{latency_offset}}, {{mixer_control}}"
+        );
+        let err = ConfigTemplate::parse(&TEMPLATE.replace("{{mixer_control}}", "PCM")).err().unwrap();
+        assert_eq!(err, TemplateError::MissingPlaceholders(vec!["mixer_control"]));
+        let err = ConfigTemplate::parse(&TEMPLATE.replace("{{mixer_control}}", "{{mixer}}")).err().unwrap();
+        assert_eq!(err, TemplateError::UnknownPlaceholders(vec!["mixer".into()]));
+        assert!(matches!(ConfigTemplate::parse("{{name"), Err(TemplateError::Unclosed(0))));
+    }
+
+    #[test]
+    fn template_file_falls_back_to_the_built_in_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shairport-template.conf");
+        let renderer = TemplateFileRenderer::new(&path);
+        assert_eq!(renderer.render(&config()).unwrap(), render_config_file(&config()));
+
+        std::fs::write(&path, TEMPLATE).unwrap();
+        assert!(renderer.render(&config()).unwrap().contains("diagnostics = {"));
+
+        std::fs::write(&path, "general = {};").unwrap();
+        let err = renderer.render(&config()).unwrap_err();
+        assert!(format!("{err:#}").contains("missing {{name}}"), "{err:#}");
+    }
+}
@@ -0,0 +1,64 @@
+use crate::group::BoxFuture;
+use anyhow::{anyhow, Result};
+
+/// Bus name shairport-sync registers when built with its MPRIS interface.
+pub const SHAIRPORT_MPRIS_DEST: &str = "org.mpris.MediaPlayer2.ShairportSync";
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const MPRIS_PLAYER: &str = "org.mpris.MediaPlayer2.Player";
+
+/// Pauses and resumes the AirPlay source feeding shairport-sync, so calibration signals
+/// aren't mixed into program material.
+pub trait AirplayTransportControl: Send + Sync {
+    fn pause(&self) -> BoxFuture<'_, Result<()>>;
+    fn resume(&self) -> BoxFuture<'_, Result<()>>;
+}
+
+/// Sends MPRIS `Pause`/`Play` to shairport-sync over the system bus with `dbus-send`.
+pub struct MprisTransportControl;
+
+impl MprisTransportControl {
+    async fn call(method: &str) -> Result<()> {
+        let output = tokio::process::Command::new("dbus-send")
+            .args([
+                "--system",
+                "--print-reply",
+                &format!("--dest={SHAIRPORT_MPRIS_DEST}"),
+                MPRIS_PATH,
+                &format!("{MPRIS_PLAYER}.{method}"),
+            ])
+            .output()
+            .await
+            .map_err(|err| anyhow!("failed to run dbus-send: {err}"))?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "dbus-send {method} failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        }
+    }
+}
+
+impl AirplayTransportControl for MprisTransportControl {
+    fn pause(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(Self::call("Pause"))
+    }
+
+    fn resume(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(Self::call("Play"))
+    }
+}
+
+/// Leaves the AirPlay stream alone; the default when no control is configured.
+pub struct NoopTransportControl;
+
+impl AirplayTransportControl for NoopTransportControl {
+    fn pause(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(std::future::ready(Ok(())))
+    }
+
+    fn resume(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(std::future::ready(Ok(())))
+    }
+}
@@ -1,20 +1,59 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
-use airsync_receiver_core::airplay::generate_config;
-use airsync_receiver_core::calibration::{CalibrationApplier, FileConfigWriter, SystemdShairportController};
+use airsync_receiver_core::airplay::{
+    generate_config, spawn_metadata_persistence, spawn_metadata_reader, MetadataStore, TemplateFileRenderer, EQ_FRAGMENT_PATH,
+    METADATA_PIPE_PATH, SHAIRPORT_TEMPLATE_PATH,
+};
+#[cfg(not(feature = "embedded"))]
+use airsync_receiver_core::airplay::MprisTransportControl;
+use airsync_receiver_core::calibration::signal::{
+    load_or_generate_structured_signal, signal_id_for_receiver, SignalLayout, StructuredSignalConfig,
+};
+use airsync_receiver_core::hardware::{HardwareDetector, HARDWARE_CACHE_MAX_AGE};
+use airsync_receiver_core::calibration::store::CalibrationStore;
+use airsync_receiver_core::calibration::{CalibrationApplier, ConfigWriter, MultiConfigWriter, SystemdShairportController};
+#[cfg(feature = "atomic-writes")]
+use airsync_receiver_core::calibration::AtomicFileConfigWriter as ShairportConfigWriter;
+#[cfg(not(feature = "atomic-writes"))]
+use airsync_receiver_core::calibration::FileConfigWriter as ShairportConfigWriter;
 use airsync_receiver_core::http::{
-    load_or_create_receiver_id, render_avahi_service, router, serve, ReceiverInfo, ReceiverState,
-    ShairportCalibrationSink, ShairportSettingsManager, SystemPlaybackSink,
+    load_or_create_receiver_id_async, now_millis, render_avahi_service, render_avahi_service_from_caps, router, serve, serve_dual_stack, socket_activate, ConfigStore,
+    CachedHardware, ReceiverInfo, ReceiverState, ShairportCalibrationSink, ShairportSettingsManager, SystemPlaybackSink,
 };
-use airsync_shared_protocol::AudioOutput;
+use airsync_shared_protocol::{AudioOutput, Capability};
 use std::path::PathBuf;
+use airsync_receiver_core::admin_token::{AdminTokens, DEFAULT_ROTATION_GRACE};
+use airsync_receiver_core::config_changes::ConfigChangeLog;
+use airsync_receiver_core::discovery::PeerDirectory;
+use airsync_receiver_core::group::GroupStore;
+use airsync_receiver_core::setup::SetupMode;
+use airsync_receiver_core::firmware::FirmwareUpdater;
+use airsync_receiver_core::startup::{reconcile_startup_config, sync_eq_fragment, SettingsFile, SETTINGS_STATE_FILE};
+use airsync_receiver_core::status::{spawn_status_refresh, StatusEvent, StatusTracker};
+use airsync_receiver_core::version::VersionInfo;
+use airsync_receiver_core::cli::{parse_binary, ServiceArgs};
+use airsync_receiver_core::request_id::configure_logging;
+use airsync_receiver_core::watchdog::{ShairportWatchdog, WatchdogConfig};
+#[cfg(feature = "tls")]
+use airsync_receiver_core::tls::{ReloadableIdentity, TlsIdentity};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::signal;
 
+const PORT: u16 = 5000;
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let receiver_id_path = PathBuf::from("/var/lib/airsync/receiver.json");
-    let receiver_id = load_or_create_receiver_id(&receiver_id_path)?;
+    let invocation = parse_binary::<ServiceArgs>("airsync-receiver-service");
+    configure_logging(&invocation.common);
+    let args = invocation.args;
+    let shairport_config_path = invocation.common.config_path;
+    println!("{}", VersionInfo::current().banner());
+    let bind = args.bind;
+
+    let state_dir = PathBuf::from("/var/lib/airsync");
+    let receiver_id_path = state_dir.join("receiver.json");
+    let receiver_id = load_or_create_receiver_id_async(receiver_id_path).await?;
     let name = hostname();
 
     let capabilities = vec!["calibration".to_string()];
@@ -24,20 +63,80 @@ async fn main() -> anyhow::Result<()> {
         capabilities: capabilities.clone(),
     };
 
-    let config = Arc::new(std::sync::Mutex::new(generate_config(Some(&name), AudioOutput::Headphone)));
+    let detector = HardwareDetector::from_system();
+    let hardware = match detector.load_or_detect(&state_dir.join("hardware.json"), HARDWARE_CACHE_MAX_AGE, args.force_detect) {
+        Ok(caps) => Some(caps),
+        Err(e) => {
+            eprintln!("Hardware detection failed: {e:?}");
+            None
+        }
+    };
+    #[cfg(feature = "tls")]
+    let tls_identity = tls_identity(&args, &state_dir, &name);
+    #[cfg(feature = "tls")]
+    let tls_endpoint = tls_identity.as_ref().zip(args.tls_port).map(|((identity, _), port)| identity.endpoint(port));
+    #[cfg(not(feature = "tls"))]
+    let tls_endpoint = None;
+    #[cfg(not(feature = "tls"))]
+    if args.tls_port.is_some() {
+        eprintln!("Built without the tls feature; ignoring --tls-port and serving plain HTTP only");
+    }
+    let cards = detector.detect_alsa_cards();
 
-    let writer = FileConfigWriter::new("/etc/shairport-sync.conf");
+    let fallback = generate_config(Some(&name), AudioOutput::Headphone);
+    let detected = hardware.as_ref().map(|caps| generate_config(Some(&name), caps.preferred_output));
+    let settings_file = SettingsFile::new(state_dir.join(SETTINGS_STATE_FILE));
+    let reconciled = reconcile_startup_config(
+        &ShairportConfigWriter::new(&shairport_config_path),
+        &TemplateFileRenderer::new(SHAIRPORT_TEMPLATE_PATH),
+        &settings_file,
+        detected.clone(),
+        fallback.clone(),
+        cards.as_deref().ok(),
+    );
+    let (initial_config, needs_attention, setup_required) = match reconciled {
+        Ok(reconciled) => (reconciled.config, reconciled.needs_attention, reconciled.setup_required),
+        Err(e) => {
+            eprintln!("Failed to reconcile the startup config: {e:?}");
+            (detected.unwrap_or(fallback), None, false)
+        }
+    };
+    if setup_required {
+        println!("No settings stored yet; starting in setup mode");
+    }
+    let avahi_service = match &hardware {
+        Some(caps) => render_avahi_service_from_caps(&name, &receiver_id, PORT, caps, tls_endpoint.as_ref(), setup_required),
+        None => render_avahi_service(&name, &receiver_id, PORT, &[Capability::Calibration], tls_endpoint.as_ref(), setup_required),
+    };
+    if let Err(e) = sync_eq_fragment(&ShairportConfigWriter::unchecked(EQ_FRAGMENT_PATH), &initial_config) {
+        eprintln!("Failed to write the EQ fragment: {e:?}");
+    }
+    let config = ConfigStore::new(initial_config).persist_to(settings_file);
+
+    let config_changes = ConfigChangeLog::default();
+    let writer = config_writer(&shairport_config_path, &args.mirror_configs);
     let controller = SystemdShairportController;
-    let applier = CalibrationApplier::new(writer, controller);
+    let applier = CalibrationApplier::new(writer, controller)
+        .renderer(TemplateFileRenderer::new(SHAIRPORT_TEMPLATE_PATH))
+        .change_log(config_changes.clone())
+        .verify_writes(env_flag("AIRSYNC_VERIFY_CONFIG_WRITES"));
     let sink = Arc::new(ShairportCalibrationSink::new(applier, config.clone()));
-    let settings = Arc::new(ShairportSettingsManager::new(
-        FileConfigWriter::new("/etc/shairport-sync.conf"),
-        SystemdShairportController,
-        config.clone(),
-    ));
+    let settings = Arc::new(
+        ShairportSettingsManager::new(
+            config_writer(&shairport_config_path, &args.mirror_configs),
+            SystemdShairportController,
+            config.clone(),
+        )
+        .eq_writer(ShairportConfigWriter::unchecked(EQ_FRAGMENT_PATH))
+        .renderer(TemplateFileRenderer::new(SHAIRPORT_TEMPLATE_PATH))
+        .change_log(config_changes.clone()),
+    );
 
-    let structured = match airsync_receiver_core::calibration::signal::generate_structured_signal(
+    let layout = SignalLayout::with_signal_id(signal_id_for_receiver(&receiver_id));
+    let structured = match load_or_generate_structured_signal(
         "/usr/local/share/airsync/structured_cal.wav",
+        layout,
+        StructuredSignalConfig::default(),
     ) {
         Ok(s) => Some(s),
         Err(e) => {
@@ -46,24 +145,120 @@ async fn main() -> anyhow::Result<()> {
         }
     };
 
+    let hardware = hardware.map(|caps| CachedHardware::new(caps, HardwareDetector::from_system()));
+    let cards = cards.unwrap_or_else(|e| {
+        eprintln!("Failed to list sound cards, using the fallback calibration gain: {e:?}");
+        Vec::new()
+    });
     let playback = Arc::new(SystemPlaybackSink::new(
         48_000,
         config.clone(),
-        1.0,
+        cards,
         Some(std::path::PathBuf::from("/usr/local/share/airsync/chirp.wav")),
     ));
-    let state = ReceiverState::new(info, sink, settings, playback, structured);
+    let status = StatusTracker::new(now_millis());
+    if let Some(reason) = needs_attention {
+        status.record(StatusEvent::Failed(reason), now_millis());
+    }
+    spawn_metadata_reader(PathBuf::from(METADATA_PIPE_PATH), status.clone(), now_millis);
+    let metadata_store = MetadataStore::open(&state_dir);
+    spawn_metadata_persistence(status.clone(), metadata_store.clone());
+    spawn_status_refresh(status.clone(), Duration::from_secs(1), now_millis);
+    #[cfg(feature = "led")]
+    spawn_led_from_env(&status);
+    let peers = PeerDirectory::new(receiver_id.clone());
+    #[cfg(feature = "mdns")]
+    airsync_receiver_core::discovery::spawn_peer_browser(peers.clone(), now_millis);
+
+    let calibration_store = CalibrationStore::open(&state_dir)?;
+    match calibration_store.current() {
+        Some(current) => println!(
+            "Calibration in effect: offset {:.1}ms ({:?}, applied at {})",
+            current.applied_offset_ms, current.source, current.timestamp
+        ),
+        None => println!("No calibration applied yet"),
+    }
+
+    let mut builder = ReceiverState::builder()
+        .info(info)
+        .calibration(sink)
+        .settings(settings)
+        .playback(playback)
+        .status_tracker(status)
+        .metadata_store(metadata_store)
+        .peer_directory(peers)
+        .group_store(GroupStore::open(&state_dir)?)
+        .calibration_store(calibration_store)
+        .config_change_log(config_changes)
+        .setup_mode(SetupMode::new(setup_required));
+    if let Some(hardware) = hardware {
+        builder = builder.hardware(Arc::new(hardware));
+    }
+    if let Some(structured) = structured {
+        builder = builder.structured(structured);
+    }
+    if let Some(endpoint) = tls_endpoint {
+        builder = builder.tls(endpoint);
+    }
+    #[cfg(feature = "tls")]
+    if let Some((_, served)) = &tls_identity {
+        builder = builder.certificate_rotator(Arc::new(served.clone()));
+    }
+    if env_flag("AIRSYNC_SHAIRPORT_WATCHDOG") {
+        let watchdog = ShairportWatchdog::new(Arc::new(SystemdShairportController), WatchdogConfig::default(), now_millis);
+        builder = builder.watchdog(watchdog.handle());
+        watchdog.spawn();
+    }
+    if let Some(tokens) = AdminTokens::open(&state_dir, std::env::var("AIRSYNC_ADMIN_TOKEN").ok())? {
+        builder = builder.admin_tokens(tokens.grace_period(admin_token_grace()));
+    }
+    if let Some(updater) = FirmwareUpdater::from_env() {
+        builder = builder.firmware_updater(updater);
+    }
+    #[cfg(not(feature = "embedded"))]
+    {
+        builder = builder.transport_control(Arc::new(MprisTransportControl));
+    }
+    let state = builder.build();
     let app = router(state);
 
-    let addr: SocketAddr = "0.0.0.0:5000".parse()?;
-    println!("AirSync receiver HTTP service listening on {}", addr);
-    println!(
-        "Avahi service example:\n{}",
-        render_avahi_service(&name, &receiver_id, 5000, &["calibration"])
-    );
+    match avahi_service {
+        Ok(avahi_service) => println!("Avahi service example:\n{}", avahi_service),
+        Err(e) => eprintln!("Can't advertise over avahi: {e}"),
+    }
+
+    #[cfg(feature = "tls")]
+    if let (Some((identity, served)), Some(port)) = (tls_identity, args.tls_port) {
+        let addr = SocketAddr::new(bind, port);
+        let app = app.clone();
+        println!("AirSync receiver HTTPS service listening on {} (sha256 {})", addr, identity.fingerprint());
+        tokio::spawn(async move {
+            if let Err(e) = served.serve(app, addr).await {
+                eprintln!("HTTPS service stopped: {e:?}");
+            }
+        });
+    }
+
+    let server = async move {
+        if args.socket_activation {
+            println!("AirSync receiver HTTP service using the socket passed by systemd");
+            return socket_activate(app).await;
+        }
+        match bind {
+            IpAddr::V6(ip) if ip.is_unspecified() => {
+                println!("AirSync receiver HTTP service listening on [::]:{} (dual-stack)", PORT);
+                serve_dual_stack(app, PORT).await
+            }
+            ip => {
+                let addr = SocketAddr::new(ip, PORT);
+                println!("AirSync receiver HTTP service listening on {}", addr);
+                serve(app, addr).await
+            }
+        }
+    };
 
     tokio::select! {
-        res = serve(app, addr) => res?,
+        res = server => res?,
         _ = signal::ctrl_c() => {
             println!("Shutdown requested");
         }
@@ -72,6 +267,74 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Start the status LED when `AIRSYNC_LED_GPIO_LINE` names a line (on
+/// `AIRSYNC_LED_GPIO_CHIP`, default gpiochip0). An unusable GPIO only logs a warning.
+#[cfg(feature = "led")]
+fn spawn_led_from_env(status: &StatusTracker) {
+    use airsync_receiver_core::hardware::{spawn_status_led, GpioStatusLed, DEFAULT_GPIO_CHIP};
+
+    let Ok(line) = std::env::var("AIRSYNC_LED_GPIO_LINE") else {
+        return;
+    };
+    let Ok(line) = line.trim().parse::<u32>() else {
+        eprintln!("[led] ignoring invalid AIRSYNC_LED_GPIO_LINE={line:?}");
+        return;
+    };
+    let chip = std::env::var("AIRSYNC_LED_GPIO_CHIP").unwrap_or_else(|_| DEFAULT_GPIO_CHIP.to_string());
+    match GpioStatusLed::open(&chip, line) {
+        Ok(led) => {
+            spawn_status_led(Arc::new(led), status);
+        }
+        Err(e) => eprintln!("[led] status LED disabled: {e:#}"),
+    }
+}
+
+/// How long a rotated-out admin token keeps working: `AIRSYNC_ADMIN_TOKEN_GRACE_SECS`, or
+/// the default when unset or invalid.
+fn admin_token_grace() -> Duration {
+    let Ok(secs) = std::env::var("AIRSYNC_ADMIN_TOKEN_GRACE_SECS") else {
+        return DEFAULT_ROTATION_GRACE;
+    };
+    secs.trim().parse().map(Duration::from_secs).unwrap_or_else(|_| {
+        eprintln!("[admin] ignoring invalid AIRSYNC_ADMIN_TOKEN_GRACE_SECS={secs:?}");
+        DEFAULT_ROTATION_GRACE
+    })
+}
+
+/// The certificate to serve HTTPS with when `--tls-port` is given, from the state dir or
+/// newly generated, and the handle serving it. Failing to get one leaves the receiver on
+/// plain HTTP.
+#[cfg(feature = "tls")]
+fn tls_identity(args: &ServiceArgs, state_dir: &std::path::Path, name: &str) -> Option<(TlsIdentity, ReloadableIdentity)> {
+    args.tls_port?;
+    let names = vec![format!("{name}.local"), "localhost".to_string()];
+    let identity = if args.regenerate_tls_cert {
+        TlsIdentity::regenerate(state_dir, names.clone())
+    } else {
+        TlsIdentity::load_or_generate(state_dir, names.clone())
+    };
+    let served = identity.and_then(|identity| {
+        let served = ReloadableIdentity::new(&identity, state_dir, names)?;
+        Ok((identity, served))
+    });
+    served.map_err(|e| eprintln!("HTTPS disabled: {e:?}")).ok()
+}
+
+/// Writes the shairport-sync config at `path`, then at each of `mirrors`.
+fn config_writer(path: &std::path::Path, mirrors: &[PathBuf]) -> MultiConfigWriter {
+    let writers = std::iter::once(path)
+        .chain(mirrors.iter().map(PathBuf::as_path))
+        .map(|path| Box::new(ShairportConfigWriter::new(path)) as Box<dyn ConfigWriter>)
+        .collect();
+    MultiConfigWriter::new(writers)
+}
+
+fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
 fn hostname() -> String {
     hostname::get()
         .ok()
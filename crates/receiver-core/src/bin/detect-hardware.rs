@@ -1,13 +1,29 @@
+use airsync_receiver_core::cli::{parse_binary, DetectHardwareArgs};
 use airsync_receiver_core::HardwareDetector;
 use airsync_shared_protocol::{is_capable, MIN_CPU_CORES, MIN_RAM_MB};
 
 fn main() {
-    println!("AirSync Hardware Detection\n");
-    println!("Detecting hardware capabilities...\n");
+    let common = parse_binary::<DetectHardwareArgs>("detect-hardware").common;
+    if !common.json {
+        println!("AirSync Hardware Detection\n");
+        println!("Detecting hardware capabilities...\n");
+    }
 
     let detector = HardwareDetector::from_system();
 
     match detector.detect() {
+        Ok(capabilities) if common.json => {
+            match serde_json::to_string_pretty(&capabilities) {
+                Ok(json) => println!("{json}"),
+                Err(e) => {
+                    eprintln!("Error encoding hardware capabilities: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            if !is_capable(&capabilities) {
+                std::process::exit(1);
+            }
+        }
         Ok(capabilities) => {
             println!("Hardware Capabilities:");
             println!("  CPU Cores:        {}", capabilities.cpu_cores);
@@ -15,6 +31,9 @@ fn main() {
             println!("  Board ID:         {}", capabilities.board_id);
             println!("  Audio Outputs:    {:?}", capabilities.audio_outputs);
             println!("  Preferred Output: {:?}", capabilities.preferred_output);
+            if let Some(os) = &capabilities.os {
+                println!("  OS:               {} (kernel {})", os.pretty_name, os.kernel);
+            }
 
             println!("\nMinimum Requirements:");
             println!("  CPU Cores:        {} (required: {})",
@@ -1,36 +1,26 @@
 use std::fs;
-use std::path::PathBuf;
 
-use airsync_receiver_core::chirp::generate_chirp_samples;
+use airsync_receiver_core::calibration::signal::write_samples;
+use airsync_receiver_core::chirp::generate_chirp_signal;
+use airsync_receiver_core::cli::{parse_binary, GenerateChirpWavArgs, LogLevel};
 use airsync_shared_protocol::ChirpConfig;
-use hound;
 
 fn main() -> anyhow::Result<()> {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: generate-chirp-wav <output_path> [sample_rate] [gain]");
-        std::process::exit(1);
-    }
-    let path = PathBuf::from(&args[1]);
-    let sample_rate: u32 = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(48_000);
-    let gain: f32 = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(1.0);
+    let invocation = parse_binary::<GenerateChirpWavArgs>("generate-chirp-wav");
+    let args = invocation.args;
 
-    if let Some(parent) = path.parent() {
+    if let Some(parent) = args.output_path.parent() {
         fs::create_dir_all(parent)?;
     }
 
-    let spec = hound::WavSpec {
-        channels: 1,
-        sample_rate,
-        bits_per_sample: 16,
-        sample_format: hound::SampleFormat::Int,
-    };
-    let mut writer = hound::WavWriter::create(&path, spec)?;
-    let samples = generate_chirp_samples(&ChirpConfig::default(), sample_rate, gain);
-    for s in samples {
-        writer.write_sample(s)?;
+    let samples = generate_chirp_signal(&ChirpConfig::default(), args.sample_rate, args.gain);
+    write_samples(&args.output_path, &samples, args.sample_rate, args.format, 1.0)?;
+    if invocation.common.shows(LogLevel::Info) {
+        println!(
+            "Wrote {}-bit chirp WAV to {}",
+            args.format.bits_per_sample(),
+            args.output_path.display()
+        );
     }
-    writer.finalize()?;
-    println!("Wrote chirp WAV to {}", path.display());
     Ok(())
 }
@@ -1,62 +1,27 @@
 use airsync_receiver_core::HardwareDetector;
 use airsync_receiver_core::airplay::{generate_config, write_config_file};
-use airsync_shared_protocol::AudioOutput;
-use std::env;
-use std::path::PathBuf;
+use airsync_receiver_core::cli::{parse_binary, GenerateConfigArgs};
+use airsync_shared_protocol::{AudioOutput, CardRef, OutputDeviceSpec};
 use std::process;
 
-fn parse_audio_output(device: &str) -> AudioOutput {
-    // Try to determine audio output type from hw:X,Y format
+fn parse_audio_output(device: &OutputDeviceSpec) -> AudioOutput {
+    // Try to determine audio output type from the ALSA device
     // This is a simple heuristic - hw:0,0 is usually headphone/I2S
     // hw:0,1 is usually HDMI, hw:1,0 is usually USB
     match device {
-        d if d.starts_with("hdmi") => AudioOutput::HDMI,
-        d if d.starts_with("hw:1,") => AudioOutput::USB,
-        d if d.starts_with("hw:0,1") => AudioOutput::HDMI,
+        OutputDeviceSpec::Named(name) if name.starts_with("hdmi") => AudioOutput::HDMI,
+        OutputDeviceSpec::Hw { card: CardRef::Name(name), .. } if name.contains("hdmi") => AudioOutput::HDMI,
+        OutputDeviceSpec::Hw { card: CardRef::Index(1), .. } => AudioOutput::USB,
+        OutputDeviceSpec::Hw { card: CardRef::Index(0), device: 1 } => AudioOutput::HDMI,
         _ => AudioOutput::Headphone, // Default for hw:0,0 and others
     }
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-
-    if args.len() < 2 {
-        eprintln!("Usage: generate-config <output-path> [device-name] [--device hw:X,Y]");
-        eprintln!("\nExamples:");
-        eprintln!("  generate-config /etc/shairport-sync.conf");
-        eprintln!("  generate-config /etc/shairport-sync.conf \"Living Room\"");
-        eprintln!("  generate-config /etc/shairport-sync.conf \"Kitchen\" --device hw:1,0");
-        process::exit(1);
-    }
-
-    let output_path = PathBuf::from(&args[1]);
-
-    // Parse arguments
-    let mut device_name = None;
-    let mut device_override = None;
-
-    let mut i = 2;
-    while i < args.len() {
-        match args[i].as_str() {
-            "--device" => {
-                if i + 1 < args.len() {
-                    device_override = Some(args[i + 1].clone());
-                    i += 2;
-                } else {
-                    eprintln!("Error: --device flag requires a value (e.g., hw:0,0)");
-                    process::exit(1);
-                }
-            }
-            arg if !arg.starts_with("--") => {
-                device_name = Some(arg.to_string());
-                i += 1;
-            }
-            _ => {
-                eprintln!("Error: Unknown flag: {}", args[i]);
-                process::exit(1);
-            }
-        }
-    }
+    let invocation = parse_binary::<GenerateConfigArgs>("generate-config");
+    let output_path = invocation.args.output_path.unwrap_or(invocation.common.config_path);
+    let device_name = invocation.args.device_name;
+    let device_override = invocation.args.device;
 
     println!("AirSync Config Generator\n");
 
@@ -0,0 +1,50 @@
+use std::fs;
+
+use airsync_receiver_core::calibration::signal::validation::validate_wav_length;
+use airsync_receiver_core::calibration::signal::{generate_structured_signal_with, SignalLayout, StructuredSignalConfig};
+use airsync_receiver_core::cli::{parse_binary, GenerateStructuredSignalArgs, LogLevel};
+
+fn main() -> anyhow::Result<()> {
+    let invocation = parse_binary::<GenerateStructuredSignalArgs>("generate-structured-signal");
+    let (common, args) = (invocation.common, invocation.args);
+    let path = args.output_path;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let config = StructuredSignalConfig {
+        sample_format: args.format,
+        ..StructuredSignalConfig::default()
+    };
+    let signal = generate_structured_signal_with(&path, SignalLayout::default(), config)?;
+    if common.json {
+        println!("{}", serde_json::to_string_pretty(&signal.spec)?);
+    } else if common.shows(LogLevel::Info) {
+        println!(
+            "Wrote structured signal to {} ({} samples, {} markers)",
+            path.display(),
+            signal.spec.length_samples,
+            signal.spec.markers.len()
+        );
+    }
+
+    if args.validate {
+        match validate_wav_length(&path, signal.spec.length_samples) {
+            Ok(info) if common.shows(LogLevel::Info) && !common.json => println!(
+                "Validated {}: {}Hz {}ch {}-bit, {} samples ({}ms)",
+                path.display(),
+                info.sample_rate,
+                info.channels,
+                info.bits_per_sample,
+                info.num_samples,
+                info.duration_ms
+            ),
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("Validation failed: {err:#}");
+                std::process::exit(1);
+            }
+        }
+    }
+    Ok(())
+}
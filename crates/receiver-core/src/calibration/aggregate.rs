@@ -0,0 +1,408 @@
+use airsync_shared_protocol::MarkerKind;
+use serde::{Deserialize, Serialize};
+
+/// Scale factor turning a median absolute deviation into a standard-deviation estimate
+/// for normally distributed measurements.
+const MAD_TO_STDDEV: f32 = 1.4826;
+/// Rounds further than this many (MAD-derived) standard deviations from the median are rejected.
+const OUTLIER_SIGMAS: f32 = 3.0;
+/// Floor for the rejection threshold so tightly clustered rounds don't reject sub-ms jitter.
+const MIN_OUTLIER_THRESHOLD_MS: f32 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RoundMeasurement {
+    pub latency_ms: f32,
+    pub confidence: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoundAggregate {
+    pub latency_ms: f32,
+    pub confidence: f32,
+    pub median_ms: f32,
+    pub mad_ms: f32,
+    /// Indices into the input rounds that contributed to the aggregate.
+    pub accepted: Vec<usize>,
+    /// Indices into the input rounds discarded as outliers.
+    pub rejected: Vec<usize>,
+}
+
+fn median(values: &mut [f32]) -> f32 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+/// Combine several calibration rounds into one robust latency estimate.
+///
+/// Rounds are screened against the median using a MAD-based threshold; the survivors
+/// are averaged weighted by their confidence. The aggregate confidence is the mean
+/// confidence of the accepted rounds scaled by the fraction of rounds accepted.
+/// Returns `None` when no usable rounds are supplied.
+pub fn aggregate_rounds(rounds: &[RoundMeasurement]) -> Option<RoundAggregate> {
+    let usable: Vec<usize> = (0..rounds.len())
+        .filter(|&i| rounds[i].latency_ms.is_finite() && rounds[i].confidence.is_finite())
+        .collect();
+    if usable.is_empty() {
+        return None;
+    }
+
+    let mut latencies: Vec<f32> = usable.iter().map(|&i| rounds[i].latency_ms).collect();
+    let median_ms = median(&mut latencies);
+    let mut deviations: Vec<f32> = usable
+        .iter()
+        .map(|&i| (rounds[i].latency_ms - median_ms).abs())
+        .collect();
+    let mad_ms = median(&mut deviations);
+    let threshold = (OUTLIER_SIGMAS * MAD_TO_STDDEV * mad_ms).max(MIN_OUTLIER_THRESHOLD_MS);
+
+    let (accepted, mut rejected): (Vec<usize>, Vec<usize>) = usable
+        .iter()
+        .partition(|&&i| (rounds[i].latency_ms - median_ms).abs() <= threshold);
+    rejected.extend((0..rounds.len()).filter(|i| !usable.contains(i)));
+    rejected.sort_unstable();
+
+    let weight_sum: f32 = accepted.iter().map(|&i| rounds[i].confidence.max(0.0)).sum();
+    let latency_ms = if weight_sum > 0.0 {
+        accepted
+            .iter()
+            .map(|&i| rounds[i].latency_ms * rounds[i].confidence.max(0.0))
+            .sum::<f32>()
+            / weight_sum
+    } else {
+        accepted.iter().map(|&i| rounds[i].latency_ms).sum::<f32>() / accepted.len() as f32
+    };
+    let mean_confidence =
+        accepted.iter().map(|&i| rounds[i].confidence).sum::<f32>() / accepted.len() as f32;
+    let confidence =
+        (mean_confidence * accepted.len() as f32 / rounds.len() as f32).clamp(0.0, 1.0);
+
+    Some(RoundAggregate {
+        latency_ms,
+        confidence,
+        median_ms,
+        mad_ms,
+        accepted,
+        rejected,
+    })
+}
+
+/// How reliably a marker is detected, from its kind. Swept chirps correlate sharply even
+/// through a small phone mic; pure tones sit on one frequency that rolloff can wipe out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MarkerClass {
+    Sweep,
+    Click,
+    Tone,
+}
+
+impl MarkerClass {
+    pub fn of(kind: &MarkerKind) -> Self {
+        match kind {
+            MarkerKind::Chirp {
+                start_freq, end_freq, ..
+            } if start_freq != end_freq => MarkerClass::Sweep,
+            MarkerKind::Chirp { .. } => MarkerClass::Tone,
+            MarkerKind::Click => MarkerClass::Click,
+        }
+    }
+
+    pub fn weight(self) -> f32 {
+        MARKER_WEIGHTS
+            .iter()
+            .find(|(class, _)| *class == self)
+            .map_or(UNKNOWN_MARKER_WEIGHT, |(_, weight)| *weight)
+    }
+}
+
+/// Weight each marker class's detections get in the latency average.
+pub const MARKER_WEIGHTS: [(MarkerClass, f32); 3] =
+    [(MarkerClass::Sweep, 1.0), (MarkerClass::Click, 0.6), (MarkerClass::Tone, 0.3)];
+/// Weight for markers the signal spec doesn't describe.
+pub const UNKNOWN_MARKER_WEIGHT: f32 = 0.3;
+
+/// Which per-marker detections are good enough to apply.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DetectionPolicy {
+    /// Fewest markers that must survive screening for a result to be applied.
+    pub min_markers: usize,
+    /// Detections correlating below this are discarded.
+    pub min_correlation: f32,
+}
+
+impl Default for DetectionPolicy {
+    fn default() -> Self {
+        Self {
+            min_markers: 3,
+            min_correlation: 0.2,
+        }
+    }
+}
+
+/// A phone-reported latency for one marker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MarkerDetection {
+    pub marker_id: String,
+    pub latency_ms: f32,
+    pub correlation: f32,
+    pub class: Option<MarkerClass>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscardReason {
+    BelowConfidenceFloor,
+    Outlier,
+    NonFinite,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiscardedMarker {
+    pub marker_id: String,
+    pub reason: DiscardReason,
+}
+
+/// Which markers a detection-based latency was computed from.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MarkerUsage {
+    pub used: Vec<String>,
+    pub discarded: Vec<DiscardedMarker>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DetectionAggregate {
+    pub latency_ms: f32,
+    pub confidence: f32,
+    pub markers: MarkerUsage,
+}
+
+/// Combine per-marker detections into one latency. Detections under the confidence
+/// floor are dropped, the rest are screened for outliers like rounds and averaged
+/// weighted by correlation times their class weight. Fails with the usage so far when
+/// fewer than `policy.min_markers` survive.
+pub fn aggregate_detections(
+    detections: &[MarkerDetection],
+    policy: &DetectionPolicy,
+) -> Result<DetectionAggregate, MarkerUsage> {
+    let mut usage = MarkerUsage::default();
+    let mut candidates = Vec::new();
+    for detection in detections {
+        let reason = if !detection.latency_ms.is_finite() || !detection.correlation.is_finite() {
+            DiscardReason::NonFinite
+        } else if detection.correlation < policy.min_correlation {
+            DiscardReason::BelowConfidenceFloor
+        } else {
+            candidates.push(detection);
+            continue;
+        };
+        usage.discarded.push(DiscardedMarker {
+            marker_id: detection.marker_id.clone(),
+            reason,
+        });
+    }
+
+    let measurements: Vec<RoundMeasurement> = candidates
+        .iter()
+        .map(|d| RoundMeasurement {
+            latency_ms: d.latency_ms,
+            confidence: d.correlation * d.class.map_or(UNKNOWN_MARKER_WEIGHT, MarkerClass::weight),
+        })
+        .collect();
+    let aggregate = aggregate_rounds(&measurements);
+    if let Some(aggregate) = &aggregate {
+        usage.used = aggregate.accepted.iter().map(|&i| candidates[i].marker_id.clone()).collect();
+        usage.discarded.extend(aggregate.rejected.iter().map(|&i| DiscardedMarker {
+            marker_id: candidates[i].marker_id.clone(),
+            reason: DiscardReason::Outlier,
+        }));
+    }
+    match aggregate {
+        Some(aggregate) if usage.used.len() >= policy.min_markers.max(1) => {
+            let mean_correlation = aggregate
+                .accepted
+                .iter()
+                .map(|&i| candidates[i].correlation)
+                .sum::<f32>()
+                / aggregate.accepted.len() as f32;
+            let coverage = usage.used.len() as f32 / detections.len() as f32;
+            Ok(DetectionAggregate {
+                latency_ms: aggregate.latency_ms,
+                confidence: (mean_correlation * coverage).clamp(0.0, 1.0),
+                markers: usage,
+            })
+        }
+        _ => Err(usage),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round(latency_ms: f32, confidence: f32) -> RoundMeasurement {
+        RoundMeasurement {
+            latency_ms,
+            confidence,
+        }
+    }
+
+    #[test]
+    fn empty_input_has_no_aggregate() {
+        assert!(aggregate_rounds(&[]).is_none());
+        assert!(aggregate_rounds(&[round(f32::NAN, 0.9)]).is_none());
+    }
+
+    #[test]
+    fn single_round_passes_through() {
+        let agg = aggregate_rounds(&[round(42.0, 0.8)]).unwrap();
+        assert!((agg.latency_ms - 42.0).abs() < 1e-4);
+        assert!((agg.confidence - 0.8).abs() < 1e-6);
+        assert_eq!(agg.accepted, vec![0]);
+        assert!(agg.rejected.is_empty());
+    }
+
+    #[test]
+    fn rejects_outlier_round() {
+        let rounds = [
+            round(40.0, 0.9),
+            round(41.0, 0.9),
+            round(39.5, 0.9),
+            round(120.0, 0.9),
+            round(40.5, 0.9),
+        ];
+        let agg = aggregate_rounds(&rounds).unwrap();
+        assert_eq!(agg.rejected, vec![3]);
+        assert_eq!(agg.accepted, vec![0, 1, 2, 4]);
+        assert!((agg.latency_ms - 40.25).abs() < 0.01);
+        assert_eq!(agg.median_ms, 40.5);
+        assert!((agg.confidence - 0.9 * 4.0 / 5.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn weights_accepted_rounds_by_confidence() {
+        let rounds = [round(40.0, 0.9), round(42.0, 0.3)];
+        let agg = aggregate_rounds(&rounds).unwrap();
+        assert!(agg.rejected.is_empty());
+        assert!((agg.latency_ms - 40.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn identical_rounds_tolerate_small_jitter() {
+        let rounds = [round(30.0, 0.7), round(30.0, 0.7), round(30.0, 0.7), round(31.5, 0.7)];
+        let agg = aggregate_rounds(&rounds).unwrap();
+        assert_eq!(agg.mad_ms, 0.0);
+        assert!(agg.rejected.is_empty());
+    }
+
+    #[test]
+    fn zero_confidence_falls_back_to_plain_mean() {
+        let rounds = [round(10.0, 0.0), round(12.0, 0.0)];
+        let agg = aggregate_rounds(&rounds).unwrap();
+        assert_eq!(agg.latency_ms, 11.0);
+        assert_eq!(agg.confidence, 0.0);
+    }
+
+    #[test]
+    fn non_finite_rounds_are_rejected() {
+        let rounds = [round(20.0, 0.8), round(f32::INFINITY, 0.8), round(21.0, 0.8)];
+        let agg = aggregate_rounds(&rounds).unwrap();
+        assert_eq!(agg.rejected, vec![1]);
+        assert_eq!(agg.accepted, vec![0, 2]);
+    }
+
+    fn detection(id: &str, latency_ms: f32, correlation: f32, class: MarkerClass) -> MarkerDetection {
+        MarkerDetection {
+            marker_id: id.into(),
+            latency_ms,
+            correlation,
+            class: Some(class),
+        }
+    }
+
+    #[test]
+    fn classifies_marker_kinds() {
+        let chirp = |start_freq, end_freq| MarkerKind::Chirp {
+            start_freq,
+            end_freq,
+            duration_ms: 100,
+        };
+        assert_eq!(MarkerClass::of(&chirp(400, 9_000)), MarkerClass::Sweep);
+        assert_eq!(MarkerClass::of(&chirp(3_000, 3_000)), MarkerClass::Tone);
+        assert_eq!(MarkerClass::of(&MarkerKind::Click), MarkerClass::Click);
+        assert!(MarkerClass::Sweep.weight() > MarkerClass::Click.weight());
+        assert!(MarkerClass::Click.weight() > MarkerClass::Tone.weight());
+    }
+
+    #[test]
+    fn accepts_enough_markers_and_lists_discards() {
+        let detections = [
+            detection("sweep_anchor", 80.0, 0.9, MarkerClass::Sweep),
+            detection("click_a", 81.0, 0.7, MarkerClass::Click),
+            detection("chirp_1", 79.0, 0.6, MarkerClass::Tone),
+            detection("chirp_5", 82.0, 0.05, MarkerClass::Tone),
+            detection("chirp_6", 140.0, 0.8, MarkerClass::Tone),
+        ];
+        let agg = aggregate_detections(&detections, &DetectionPolicy::default()).unwrap();
+        assert_eq!(agg.markers.used, vec!["sweep_anchor", "click_a", "chirp_1"]);
+        assert_eq!(
+            agg.markers.discarded,
+            vec![
+                DiscardedMarker {
+                    marker_id: "chirp_5".into(),
+                    reason: DiscardReason::BelowConfidenceFloor,
+                },
+                DiscardedMarker {
+                    marker_id: "chirp_6".into(),
+                    reason: DiscardReason::Outlier,
+                },
+            ]
+        );
+        assert!((agg.latency_ms - 80.0).abs() < 1.0, "{}", agg.latency_ms);
+        assert!(agg.confidence > 0.0 && agg.confidence < 1.0);
+    }
+
+    #[test]
+    fn rejects_too_few_markers() {
+        let detections = [
+            detection("sweep_anchor", 80.0, 0.9, MarkerClass::Sweep),
+            detection("chirp_2", 81.0, 0.8, MarkerClass::Tone),
+            detection("chirp_3", 79.0, 0.1, MarkerClass::Tone),
+        ];
+        let usage = aggregate_detections(&detections, &DetectionPolicy::default()).unwrap_err();
+        assert_eq!(usage.used, vec!["sweep_anchor", "chirp_2"]);
+        assert_eq!(usage.discarded.len(), 1);
+        assert_eq!(usage.discarded[0].reason, DiscardReason::BelowConfidenceFloor);
+
+        let lenient = DetectionPolicy {
+            min_markers: 2,
+            ..DetectionPolicy::default()
+        };
+        assert!(aggregate_detections(&detections, &lenient).is_ok());
+        assert!(aggregate_detections(&[], &DetectionPolicy { min_markers: 0, ..lenient }).is_err());
+    }
+
+    #[test]
+    fn sweeps_outweigh_tones_at_equal_correlation() {
+        let detections = [
+            detection("sweep_anchor", 100.0, 0.8, MarkerClass::Sweep),
+            detection("chirp_1", 102.0, 0.8, MarkerClass::Tone),
+            detection("chirp_2", 102.0, 0.8, MarkerClass::Tone),
+        ];
+        let agg = aggregate_detections(&detections, &DetectionPolicy::default()).unwrap();
+        // Weights 1.0, 0.3, 0.3: (100 + 0.6 * 102) / 1.6.
+        assert!((agg.latency_ms - 100.75).abs() < 0.01, "{}", agg.latency_ms);
+
+        let unknown = MarkerDetection {
+            class: None,
+            ..detections[0].clone()
+        };
+        let agg = aggregate_detections(&[unknown, detections[1].clone(), detections[2].clone()], &DetectionPolicy::default())
+            .unwrap();
+        assert!((agg.latency_ms - 101.333).abs() < 0.01, "{}", agg.latency_ms);
+    }
+}
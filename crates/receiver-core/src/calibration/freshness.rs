@@ -0,0 +1,242 @@
+//! Whether the calibration in effect can still be trusted.
+//!
+//! Latency through USB DACs drifts with temperature and uptime, and a restart or a new
+//! output device can change it outright. Nothing here recalibrates; the report only lets
+//! the app suggest it.
+
+use airsync_shared_protocol::OutputDeviceSpec;
+use serde::{Deserialize, Serialize};
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Points at which each signal makes a calibration aging or stale.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FreshnessThresholds {
+    pub aging_after_ms: u64,
+    pub stale_after_ms: u64,
+    /// shairport-sync restarts since the calibration, from settings changes and the watchdog.
+    pub aging_after_restarts: u32,
+    pub stale_after_restarts: u32,
+    /// Change in SoC temperature since the calibration, in either direction.
+    pub aging_temperature_delta_c: f32,
+    pub stale_temperature_delta_c: f32,
+}
+
+impl Default for FreshnessThresholds {
+    fn default() -> Self {
+        Self {
+            aging_after_ms: 14 * DAY_MS,
+            stale_after_ms: 60 * DAY_MS,
+            aging_after_restarts: 5,
+            stale_after_restarts: 20,
+            aging_temperature_delta_c: 10.0,
+            stale_temperature_delta_c: 20.0,
+        }
+    }
+}
+
+/// Ordered from most to least trustworthy, so the worst signal wins with `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Freshness {
+    Fresh,
+    Aging,
+    Stale,
+}
+
+/// A signal that made the calibration less than fresh.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum FreshnessReason {
+    Age { age_ms: u64 },
+    Restarts { restarts: u32 },
+    OutputDeviceChanged { calibrated: OutputDeviceSpec, current: OutputDeviceSpec },
+    TemperatureChanged { calibrated_c: f32, current_c: f32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FreshnessReport {
+    pub status: Freshness,
+    /// Every signal past its aging threshold; empty when fresh.
+    #[serde(default)]
+    pub reasons: Vec<FreshnessReason>,
+}
+
+/// What is known about the calibration and the receiver now.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FreshnessInputs {
+    pub now_ms: u64,
+    pub calibrated_at_ms: u64,
+    pub restarts_since: u32,
+    pub calibrated_output: OutputDeviceSpec,
+    pub current_output: OutputDeviceSpec,
+    /// `None` on either side skips the temperature check.
+    pub calibrated_temperature_c: Option<f32>,
+    pub current_temperature_c: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CalibrationFreshness {
+    pub thresholds: FreshnessThresholds,
+}
+
+impl CalibrationFreshness {
+    pub fn new(thresholds: FreshnessThresholds) -> Self {
+        Self { thresholds }
+    }
+
+    pub fn evaluate(&self, inputs: &FreshnessInputs) -> FreshnessReport {
+        let t = &self.thresholds;
+        let mut status = Freshness::Fresh;
+        let mut reasons = Vec::new();
+        let mut note = |level: Freshness, reason: FreshnessReason| {
+            if level > Freshness::Fresh {
+                status = status.max(level);
+                reasons.push(reason);
+            }
+        };
+
+        let age_ms = inputs.now_ms.saturating_sub(inputs.calibrated_at_ms);
+        note(grade(age_ms, t.aging_after_ms, t.stale_after_ms), FreshnessReason::Age { age_ms });
+        note(
+            grade(inputs.restarts_since, t.aging_after_restarts, t.stale_after_restarts),
+            FreshnessReason::Restarts {
+                restarts: inputs.restarts_since,
+            },
+        );
+        if inputs.calibrated_output != inputs.current_output {
+            note(
+                Freshness::Stale,
+                FreshnessReason::OutputDeviceChanged {
+                    calibrated: inputs.calibrated_output.clone(),
+                    current: inputs.current_output.clone(),
+                },
+            );
+        }
+        if let (Some(calibrated_c), Some(current_c)) = (inputs.calibrated_temperature_c, inputs.current_temperature_c) {
+            note(
+                grade(
+                    (current_c - calibrated_c).abs(),
+                    t.aging_temperature_delta_c,
+                    t.stale_temperature_delta_c,
+                ),
+                FreshnessReason::TemperatureChanged {
+                    calibrated_c,
+                    current_c,
+                },
+            );
+        }
+        FreshnessReport { status, reasons }
+    }
+}
+
+fn grade<T: PartialOrd>(value: T, aging: T, stale: T) -> Freshness {
+    if value >= stale {
+        Freshness::Stale
+    } else if value >= aging {
+        Freshness::Aging
+    } else {
+        Freshness::Fresh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inputs() -> FreshnessInputs {
+        FreshnessInputs {
+            now_ms: 1_000 * DAY_MS,
+            calibrated_at_ms: 1_000 * DAY_MS - 1_000,
+            restarts_since: 0,
+            calibrated_output: OutputDeviceSpec::hw(1, 0),
+            current_output: OutputDeviceSpec::hw(1, 0),
+            calibrated_temperature_c: Some(45.0),
+            current_temperature_c: Some(47.0),
+        }
+    }
+
+    #[test]
+    fn recent_calibration_is_fresh() {
+        let report = CalibrationFreshness::default().evaluate(&inputs());
+        assert_eq!(report.status, Freshness::Fresh);
+        assert!(report.reasons.is_empty());
+    }
+
+    #[test]
+    fn age_and_restarts_grade_against_thresholds() {
+        let freshness = CalibrationFreshness::new(FreshnessThresholds {
+            aging_after_ms: 10 * DAY_MS,
+            stale_after_ms: 30 * DAY_MS,
+            aging_after_restarts: 2,
+            stale_after_restarts: 4,
+            ..FreshnessThresholds::default()
+        });
+        let at = |age_days: u64, restarts_since: u32| FreshnessInputs {
+            calibrated_at_ms: 1_000 * DAY_MS - age_days * DAY_MS,
+            restarts_since,
+            ..inputs()
+        };
+
+        let report = freshness.evaluate(&at(12, 1));
+        assert_eq!(report.status, Freshness::Aging);
+        assert_eq!(report.reasons, vec![FreshnessReason::Age { age_ms: 12 * DAY_MS }]);
+
+        let report = freshness.evaluate(&at(12, 4));
+        assert_eq!(report.status, Freshness::Stale);
+        assert_eq!(report.reasons.len(), 2);
+        assert_eq!(report.reasons[1], FreshnessReason::Restarts { restarts: 4 });
+
+        assert_eq!(freshness.evaluate(&at(30, 0)).status, Freshness::Stale);
+    }
+
+    #[test]
+    fn output_device_change_is_stale() {
+        let report = CalibrationFreshness::default().evaluate(&FreshnessInputs {
+            current_output: OutputDeviceSpec::hw(0, 0),
+            ..inputs()
+        });
+        assert_eq!(report.status, Freshness::Stale);
+        assert!(matches!(report.reasons[..], [FreshnessReason::OutputDeviceChanged { .. }]));
+    }
+
+    #[test]
+    fn temperature_drift_counts_only_when_both_readings_exist() {
+        let warmer = FreshnessInputs {
+            current_temperature_c: Some(57.5),
+            ..inputs()
+        };
+        let report = CalibrationFreshness::default().evaluate(&warmer);
+        assert_eq!(report.status, Freshness::Aging);
+        assert_eq!(
+            report.reasons,
+            vec![FreshnessReason::TemperatureChanged {
+                calibrated_c: 45.0,
+                current_c: 57.5
+            }]
+        );
+        let colder = FreshnessInputs {
+            current_temperature_c: Some(20.0),
+            ..inputs()
+        };
+        assert_eq!(CalibrationFreshness::default().evaluate(&colder).status, Freshness::Stale);
+
+        let unknown = FreshnessInputs {
+            calibrated_temperature_c: None,
+            ..warmer
+        };
+        assert_eq!(CalibrationFreshness::default().evaluate(&unknown).status, Freshness::Fresh);
+    }
+
+    #[test]
+    fn report_serializes_with_tagged_reasons() {
+        let report = FreshnessReport {
+            status: Freshness::Aging,
+            reasons: vec![FreshnessReason::Restarts { restarts: 6 }],
+        };
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({"status": "aging", "reasons": [{"reason": "restarts", "restarts": 6}]})
+        );
+    }
+}
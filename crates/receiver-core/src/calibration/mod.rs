@@ -1,25 +1,154 @@
-use crate::airplay::{render_config_file, ShairportConfig};
+use crate::airplay::{parse_config_file, BuiltinRenderer, ConfigRenderer, ShairportConfig};
+use crate::calibration::store::HistoryEntry;
+use crate::config_changes::{ConfigChangeLog, ConfigChangeSource, ConfigDiff};
+use crate::group::BoxFuture;
+pub use airsync_shared_protocol::CalibrationOutcome;
 use airsync_shared_protocol::CalibrationSubmission;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+#[cfg(not(feature = "embedded"))]
 use std::process::Command;
 
-pub trait ConfigWriter {
+pub trait ConfigWriter: Send + Sync + 'static {
     fn write(&self, contents: &str) -> Result<()>;
+
+    /// Write without blocking the runtime. By default the blocking `write` runs on tokio's
+    /// blocking pool, since a worn SD card can take hundreds of milliseconds to write.
+    fn write_async(self: Arc<Self>, contents: String) -> BoxFuture<'static, Result<()>> {
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || self.write(&contents))
+                .await
+                .map_err(|err| anyhow!("config write task failed: {err}"))?
+        })
+    }
+
+    /// File the writer writes to, for reading it back; `None` when there isn't one.
+    fn target_path(&self) -> Option<&Path> {
+        None
+    }
+
+    /// What the writer last wrote, read back from [`target_path`](Self::target_path);
+    /// `None` when there is no file yet or no path to read.
+    fn read_current(&self) -> Result<Option<String>> {
+        let Some(path) = self.target_path() else {
+            return Ok(None);
+        };
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(anyhow!("failed to read {}: {err}", path.display())),
+        }
+    }
+}
+
+/// The diff from the config `writer` holds now to `rendered`, read before `rendered` is
+/// written. A config that can't be read back diffs as empty, so every line shows as added.
+pub async fn diff_against_current<W: ConfigWriter + ?Sized>(writer: &Arc<W>, rendered: &str) -> ConfigDiff {
+    let reader = Arc::clone(writer);
+    let current = match tokio::task::spawn_blocking(move || reader.read_current()).await {
+        Ok(Ok(current)) => current.unwrap_or_default(),
+        Ok(Err(err)) => {
+            eprintln!("[config] can't read the current config to diff against: {err:#}");
+            String::new()
+        }
+        Err(err) => {
+            eprintln!("[config] config read task failed: {err}");
+            String::new()
+        }
+    };
+    ConfigDiff::between(&current, rendered)
 }
 
-pub trait ShairportController {
+/// Latency offsets are rendered to three decimal places, so a read-back offset can be
+/// up to half a millisecond from what was written.
+pub const READBACK_EPSILON: f32 = 0.0005;
+
+/// Measured latencies are clamped to ±this before being applied as an offset.
+pub const MAX_LATENCY_MS: f32 = 250.0;
+
+pub trait ShairportController: Send + Sync + 'static {
     fn restart(&self) -> Result<()>;
+
+    /// Restart without blocking the runtime. By default the blocking `restart` runs on
+    /// tokio's blocking pool; controllers that can restart natively async override this.
+    fn restart_async(self: Arc<Self>) -> BoxFuture<'static, Result<()>> {
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || self.restart())
+                .await
+                .map_err(|err| anyhow!("shairport restart task failed: {err}"))?
+        })
+    }
+
+    /// Current state of the shairport-sync service. Controllers that can't tell report
+    /// `Unknown`, which the watchdog never acts on.
+    fn status(self: Arc<Self>) -> BoxFuture<'static, Result<ServiceState>> {
+        Box::pin(std::future::ready(Ok(ServiceState::Unknown)))
+    }
 }
 
+/// What the service manager reports for shairport-sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceState {
+    Active,
+    Activating,
+    Deactivating,
+    /// Stopped cleanly, e.g. by `systemctl stop`; left alone.
+    Stopped,
+    /// Crashed or exited with an error.
+    Failed,
+    Unknown,
+}
+
+impl ServiceState {
+    /// Classify `systemctl is-active` output; for inactive units the `Result` property
+    /// tells an intentional stop (`success`) from a crash.
+    pub fn from_systemd(is_active: &str, result: &str) -> Self {
+        match is_active.trim() {
+            "active" | "reloading" => ServiceState::Active,
+            "activating" => ServiceState::Activating,
+            "deactivating" => ServiceState::Deactivating,
+            "failed" => ServiceState::Failed,
+            "inactive" if result.trim() == "success" => ServiceState::Stopped,
+            "inactive" => ServiceState::Failed,
+            _ => ServiceState::Unknown,
+        }
+    }
+}
+
+/// Refuse to write a shairport-sync config that doesn't parse back or fails
+/// [`ShairportConfig::validate`], so a bad value never reaches shairport.
+pub fn check_shairport_config(contents: &str) -> Result<()> {
+    let config = parse_config_file(contents).map_err(|err| anyhow!("refusing to write shairport-sync config: {err}"))?;
+    config.validate().map_err(|errors| {
+        let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        anyhow!("refusing to write invalid shairport-sync config: {}", errors.join("; "))
+    })
+}
+
+/// Writes in place. `new` writers hold the shairport-sync config and check it with
+/// [`check_shairport_config`] first; `unchecked` ones write any file, e.g. the EQ fragment.
 pub struct FileConfigWriter {
     path: PathBuf,
+    checked: bool,
 }
 
 impl FileConfigWriter {
     pub fn new(path: impl Into<PathBuf>) -> Self {
-        Self { path: path.into() }
+        Self {
+            path: path.into(),
+            checked: true,
+        }
+    }
+
+    pub fn unchecked(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            checked: false,
+        }
     }
 
     pub fn path(&self) -> &Path {
@@ -29,33 +158,203 @@ impl FileConfigWriter {
 
 impl ConfigWriter for FileConfigWriter {
     fn write(&self, contents: &str) -> Result<()> {
+        if self.checked {
+            check_shairport_config(contents)?;
+        }
         fs::write(&self.path, contents)?;
         Ok(())
     }
+
+    fn target_path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+}
+
+/// Writers that can replace their target without a reader ever seeing a partial file.
+#[cfg(feature = "atomic-writes")]
+pub trait ConfigWriterExt: ConfigWriter {
+    fn write_atomic(&self, contents: &str) -> Result<()>;
+}
+
+/// Writes `<path>.new`, fsyncs it, then renames it over `path`, so a crash or full disk
+/// mid-write leaves the previous config in place. Checked like [`FileConfigWriter`].
+#[cfg(feature = "atomic-writes")]
+pub struct AtomicFileConfigWriter {
+    path: PathBuf,
+    checked: bool,
+}
+
+#[cfg(feature = "atomic-writes")]
+impl AtomicFileConfigWriter {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            checked: true,
+        }
+    }
+
+    pub fn unchecked(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            checked: false,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn staging_path(&self) -> PathBuf {
+        let mut staging = self.path.clone().into_os_string();
+        staging.push(".new");
+        PathBuf::from(staging)
+    }
+}
+
+#[cfg(feature = "atomic-writes")]
+impl ConfigWriter for AtomicFileConfigWriter {
+    fn write(&self, contents: &str) -> Result<()> {
+        if self.checked {
+            check_shairport_config(contents)?;
+        }
+        self.write_atomic(contents)
+    }
+
+    fn target_path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+}
+
+#[cfg(feature = "atomic-writes")]
+impl ConfigWriterExt for AtomicFileConfigWriter {
+    fn write_atomic(&self, contents: &str) -> Result<()> {
+        use std::io::Write;
+
+        let staging = self.staging_path();
+        let staged = (|| -> Result<()> {
+            let mut file = fs::File::create(&staging)?;
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&staging, &self.path)?;
+            Ok(())
+        })();
+        if let Err(err) = staged {
+            let _ = fs::remove_file(&staging);
+            return Err(err.context(format!("writing {}", self.path.display())));
+        }
+        // Persist the rename itself; not every filesystem supports syncing a directory.
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            let _ = fs::File::open(dir).and_then(|d| d.sync_all());
+        }
+        Ok(())
+    }
+}
+
+/// Writes the same contents through every writer in turn, e.g. to the local config and a
+/// copy on a network share. A failing writer doesn't stop the ones after it; the
+/// failures are reported together as a [`MultiWriteError`]. Reads back from the first.
+pub struct MultiConfigWriter {
+    writers: Vec<Box<dyn ConfigWriter>>,
+}
+
+impl MultiConfigWriter {
+    pub fn new(writers: Vec<Box<dyn ConfigWriter>>) -> Self {
+        Self { writers }
+    }
+
+    pub fn len(&self) -> usize {
+        self.writers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writers.is_empty()
+    }
+}
+
+/// The writers of a [`MultiConfigWriter`] that failed, by index, with their errors.
+#[derive(Debug, thiserror::Error)]
+#[error("{} of {total} config writes failed: {}", failures.len(), describe_failures(failures))]
+pub struct MultiWriteError {
+    pub total: usize,
+    pub failures: Vec<(usize, anyhow::Error)>,
+}
+
+fn describe_failures(failures: &[(usize, anyhow::Error)]) -> String {
+    failures
+        .iter()
+        .map(|(index, err)| format!("[{index}] {err:#}"))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+impl ConfigWriter for MultiConfigWriter {
+    fn write(&self, contents: &str) -> Result<()> {
+        let failures: Vec<(usize, anyhow::Error)> = self
+            .writers
+            .iter()
+            .enumerate()
+            .filter_map(|(index, writer)| writer.write(contents).err().map(|err| (index, err)))
+            .collect();
+        if failures.is_empty() {
+            return Ok(());
+        }
+        Err(MultiWriteError {
+            total: self.writers.len(),
+            failures,
+        }
+        .into())
+    }
+
+    fn target_path(&self) -> Option<&Path> {
+        self.writers.first()?.target_path()
+    }
+
+    fn read_current(&self) -> Result<Option<String>> {
+        match self.writers.first() {
+            Some(writer) => writer.read_current(),
+            None => Ok(None),
+        }
+    }
 }
 
+/// Restarts shairport-sync through systemctl; a no-op under the `embedded` feature,
+/// where images have no systemd.
 pub struct SystemdShairportController;
 
+#[cfg(feature = "embedded")]
 impl ShairportController for SystemdShairportController {
     fn restart(&self) -> Result<()> {
-        if std::env::var("AIRSYNC_SKIP_SHAIRPORT_RESTART")
-            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
-            .unwrap_or(false)
-        {
+        println!("[calibration] shairport-sync restart skipped (embedded build)");
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "embedded"))]
+fn restart_skipped() -> bool {
+    std::env::var("AIRSYNC_SKIP_SHAIRPORT_RESTART")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+#[cfg(not(feature = "embedded"))]
+const SUDO_RESTART: [&str; 4] = ["-n", "/usr/bin/systemctl", "restart", "shairport-sync"];
+#[cfg(not(feature = "embedded"))]
+const DIRECT_RESTART: [&str; 2] = ["restart", "shairport-sync"];
+
+#[cfg(not(feature = "embedded"))]
+impl ShairportController for SystemdShairportController {
+    fn restart(&self) -> Result<()> {
+        if restart_skipped() {
             println!("[calibration] shairport-sync restart skipped (AIRSYNC_SKIP_SHAIRPORT_RESTART set)");
             return Ok(());
         }
 
-        let try_sudo = Command::new("sudo")
-            .args(["-n", "/usr/bin/systemctl", "restart", "shairport-sync"])
-            .status();
+        let try_sudo = Command::new("sudo").args(SUDO_RESTART).status();
 
         match try_sudo {
             Ok(status) if status.success() => Ok(()),
             _ => {
-                let direct = Command::new("/usr/bin/systemctl")
-                    .args(["restart", "shairport-sync"])
-                    .status();
+                let direct = Command::new("/usr/bin/systemctl").args(DIRECT_RESTART).status();
                 match direct {
                     Ok(status) if status.success() => Ok(()),
                     _ => {
@@ -67,129 +366,248 @@ impl ShairportController for SystemdShairportController {
             }
         }
     }
+
+    fn restart_async(self: Arc<Self>) -> BoxFuture<'static, Result<()>> {
+        Box::pin(async move {
+            if restart_skipped() {
+                println!("[calibration] shairport-sync restart skipped (AIRSYNC_SKIP_SHAIRPORT_RESTART set)");
+                return Ok(());
+            }
+            let try_sudo = tokio::process::Command::new("sudo").args(SUDO_RESTART).status().await;
+            if try_sudo.is_ok_and(|status| status.success()) {
+                return Ok(());
+            }
+            let direct = tokio::process::Command::new("/usr/bin/systemctl")
+                .args(DIRECT_RESTART)
+                .status()
+                .await;
+            if !direct.is_ok_and(|status| status.success()) {
+                // Do not block calibration if restart fails; log and continue
+                eprintln!("shairport-sync restart failed (ignored)");
+            }
+            Ok(())
+        })
+    }
+
+    fn status(self: Arc<Self>) -> BoxFuture<'static, Result<ServiceState>> {
+        Box::pin(async move {
+            let systemctl = |args: &'static [&'static str]| async move {
+                let output = tokio::process::Command::new("/usr/bin/systemctl")
+                    .args(args)
+                    .output()
+                    .await
+                    .map_err(|err| anyhow!("failed to run systemctl: {err}"))?;
+                Ok::<_, anyhow::Error>(String::from_utf8_lossy(&output.stdout).into_owned())
+            };
+            let is_active = systemctl(&["is-active", "shairport-sync"]).await?;
+            let result = if is_active.trim() == "inactive" {
+                systemctl(&["show", "shairport-sync", "--property=Result", "--value"]).await?
+            } else {
+                String::new()
+            };
+            Ok(ServiceState::from_systemd(&is_active, &result))
+        })
+    }
+}
+
+#[cfg(not(test))]
+fn forced_latency_override() -> Option<f32> {
+    std::env::var("AIRSYNC_FORCE_LATENCY_MS")
+        .ok()
+        .and_then(|v| v.parse::<f32>().ok())
+}
+
+#[cfg(test)]
+fn forced_latency_override() -> Option<f32> {
+    None
 }
 
+type BeforeApplyHook = Box<dyn Fn(&ShairportConfig, f32) + Send + Sync>;
+type AfterApplyHook = Box<dyn Fn(&CalibrationOutcome) + Send + Sync>;
+
 pub struct CalibrationApplier<W: ConfigWriter, C: ShairportController> {
-    writer: W,
-    controller: C,
+    writer: Arc<W>,
+    controller: Arc<C>,
+    renderer: Arc<dyn ConfigRenderer>,
+    on_before_apply: Option<BeforeApplyHook>,
+    on_after_apply: Option<AfterApplyHook>,
+    verify_writes: bool,
+    change_log: Option<ConfigChangeLog>,
 }
 
 impl<W: ConfigWriter, C: ShairportController> CalibrationApplier<W, C> {
     pub fn new(writer: W, controller: C) -> Self {
-        Self { writer, controller }
+        Self {
+            writer: Arc::new(writer),
+            controller: Arc::new(controller),
+            renderer: Arc::new(BuiltinRenderer),
+            on_before_apply: None,
+            on_after_apply: None,
+            verify_writes: false,
+            change_log: None,
+        }
+    }
+
+    /// Render the config file with `renderer` instead of the built-in format.
+    pub fn renderer(mut self, renderer: impl ConfigRenderer + 'static) -> Self {
+        self.renderer = Arc::new(renderer);
+        self
     }
 
-    pub fn apply_latency(
+    /// Record the diff of every config written in `log`.
+    pub fn change_log(mut self, log: ConfigChangeLog) -> Self {
+        self.change_log = Some(log);
+        self
+    }
+
+    /// Read the config back after writing it, rewriting once if it doesn't match and
+    /// failing the apply, before the restart, if it still doesn't.
+    pub fn verify_writes(mut self, enabled: bool) -> Self {
+        self.verify_writes = enabled;
+        self
+    }
+
+    /// Whether the config at `path` parses and matches `expected`. A file that doesn't
+    /// parse is a mismatch; only failing to read it is an error.
+    pub async fn verify_applied(&self, path: &Path, expected: &ShairportConfig) -> Result<bool> {
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|err| anyhow!("failed to read back {}: {err}", path.display()))?;
+        Ok(parse_config_file(&contents).is_ok_and(|on_disk| on_disk.approx_eq(expected, READBACK_EPSILON)))
+    }
+
+    async fn write_checked(&self, rendered: &str, config: &ShairportConfig) -> Result<()> {
+        Arc::clone(&self.writer).write_async(rendered.to_string()).await?;
+        if !self.verify_writes {
+            return Ok(());
+        }
+        let Some(path) = self.writer.target_path() else {
+            eprintln!("[calibration] config writer has no file to verify; skipping read-back");
+            return Ok(());
+        };
+        if self.verify_applied(path, config).await? {
+            return Ok(());
+        }
+        eprintln!("[calibration] {} doesn't match the written config; rewriting", path.display());
+        Arc::clone(&self.writer).write_async(rendered.to_string()).await?;
+        if self.verify_applied(path, config).await? {
+            return Ok(());
+        }
+        Err(anyhow!("{} doesn't match the written config", path.display()))
+    }
+
+    /// Called with the config about to be written and the effective latency in ms,
+    /// before anything touches disk.
+    pub fn on_before_apply<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&ShairportConfig, f32) + Send + Sync + 'static,
+    {
+        self.on_before_apply = Some(Box::new(hook));
+        self
+    }
+
+    /// Called with the outcome once the config is written and shairport restarted.
+    pub fn on_after_apply<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&CalibrationOutcome) + Send + Sync + 'static,
+    {
+        self.on_after_apply = Some(Box::new(hook));
+        self
+    }
+
+    pub async fn apply_latency(
         &self,
         mut config: ShairportConfig,
         measured_latency_ms: f32,
     ) -> Result<CalibrationOutcome> {
-        #[cfg(not(test))]
-        let override_latency = std::env::var("AIRSYNC_FORCE_LATENCY_MS")
-            .ok()
-            .and_then(|v| v.parse::<f32>().ok());
-        #[cfg(test)]
-        let override_latency: Option<f32> = None;
+        let override_latency = forced_latency_override();
         let effective_latency_ms = override_latency.unwrap_or(measured_latency_ms);
         if let Some(val) = override_latency {
             println!("[calibration] applying forced latency from env AIRSYNC_FORCE_LATENCY_MS={}ms", val);
         }
 
-        let clamped_latency_ms = effective_latency_ms.clamp(-250.0, 250.0);
+        let clamped_latency_ms = effective_latency_ms.clamp(-MAX_LATENCY_MS, MAX_LATENCY_MS);
         let offset_seconds = -clamped_latency_ms / 1000.0;
         config.latency_offset_seconds = offset_seconds;
 
-        let rendered = render_config_file(&config);
-        self.writer.write(&rendered)?;
-        self.controller.restart()?;
+        if let Some(hook) = &self.on_before_apply {
+            hook(&config, effective_latency_ms);
+        }
+
+        let rendered = self.renderer.render(&config)?;
+        let diff = diff_against_current(&self.writer, &rendered).await;
+        self.write_checked(&rendered, &config).await?;
+        let changed_keys = diff.changed_keys();
+        if let Some(log) = &self.change_log {
+            log.record(ConfigChangeSource::Calibration, diff);
+        }
+        Arc::clone(&self.controller).restart_async().await?;
 
-        Ok(CalibrationOutcome {
+        let outcome = CalibrationOutcome {
             measured_latency_ms: effective_latency_ms,
             applied_offset_ms: offset_seconds * 1000.0,
             was_clamped: clamped_latency_ms != effective_latency_ms,
-        })
+            changed_keys,
+        };
+        if let Some(hook) = &self.on_after_apply {
+            hook(&outcome);
+        }
+        Ok(outcome)
     }
 
-    pub fn apply_submission(
+    pub async fn apply_submission(
         &self,
         config: ShairportConfig,
         submission: &CalibrationSubmission,
     ) -> Result<CalibrationOutcome> {
-        self.apply_latency(config, submission.latency_ms)
+        self.apply_latency(config, submission.latency_ms).await
     }
-}
 
-pub struct CalibrationOutcome {
-    pub measured_latency_ms: f32,
-    pub applied_offset_ms: f32,
-    pub was_clamped: bool,
+    /// Re-apply the most recent entry of `history`: its config snapshot is written back
+    /// with the latency it measured and shairport-sync restarted, without playing anything.
+    pub async fn replay_history(&self, history: &[HistoryEntry]) -> Result<CalibrationOutcome> {
+        let entry = history
+            .last()
+            .ok_or_else(|| anyhow!("no calibration history to replay"))?;
+        self.apply_latency(entry.config_snapshot.clone(), entry.applied.measured_latency_ms)
+            .await
+    }
 }
 
+pub mod aggregate;
+pub mod freshness;
+pub mod playback;
+pub mod schedule;
+pub mod session;
+pub mod store;
 pub mod signal;
+pub mod timing;
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use airsync_shared_protocol::AudioOutput;
-    use crate::airplay::generate_config;
+    use airsync_shared_protocol::{AudioOutput, CalibrationMessage, OutputDeviceSpec};
+    use crate::airplay::{generate_config, render_config_file};
+    use crate::test_util::{MockController, MockWriter};
     use std::sync::{Arc, Mutex};
 
-    #[derive(Clone)]
-    struct MockWriter {
-        contents: Arc<Mutex<Option<String>>>,
-    }
-
-    impl MockWriter {
-        fn new() -> Self {
-            Self {
-                contents: Arc::new(Mutex::new(None)),
-            }
-        }
-
-        fn last_contents(&self) -> Option<String> {
-            self.contents.lock().unwrap().clone()
-        }
-    }
-
-    impl ConfigWriter for MockWriter {
-        fn write(&self, contents: &str) -> Result<()> {
-            *self.contents.lock().unwrap() = Some(contents.to_string());
-            Ok(())
-        }
-    }
-
-    #[derive(Clone)]
-    struct MockController {
-        restart_calls: Arc<Mutex<u32>>,
-    }
-
-    impl MockController {
-        fn new() -> Self {
-            Self {
-                restart_calls: Arc::new(Mutex::new(0)),
-            }
-        }
-
-        fn calls(&self) -> u32 {
-            *self.restart_calls.lock().unwrap()
-        }
-    }
-
-    impl ShairportController for MockController {
-        fn restart(&self) -> Result<()> {
-            *self.restart_calls.lock().unwrap() += 1;
-            Ok(())
-        }
+    #[test]
+    fn systemd_states_tell_clean_stops_from_crashes() {
+        assert_eq!(ServiceState::from_systemd("active\n", ""), ServiceState::Active);
+        assert_eq!(ServiceState::from_systemd("failed\n", ""), ServiceState::Failed);
+        assert_eq!(ServiceState::from_systemd("inactive\n", "success\n"), ServiceState::Stopped);
+        assert_eq!(ServiceState::from_systemd("inactive\n", "signal\n"), ServiceState::Failed);
+        assert_eq!(ServiceState::from_systemd("", ""), ServiceState::Unknown);
     }
 
-    #[test]
-    fn writes_latency_offset_and_restarts() {
+    #[tokio::test]
+    async fn writes_latency_offset_and_restarts() {
         let writer = MockWriter::new();
         let restarter = MockController::new();
         let applier = CalibrationApplier::new(writer.clone(), restarter.clone());
 
         let config = generate_config(Some("Living Room"), AudioOutput::I2S);
-        let outcome = applier.apply_latency(config, 55.0).unwrap();
+        let outcome = applier.apply_latency(config, 55.0).await.unwrap();
 
         assert_eq!(outcome.measured_latency_ms, 55.0);
         assert_eq!(format!("{:.3}", outcome.applied_offset_ms), "-55.000");
@@ -198,16 +616,57 @@ mod tests {
         let rendered = writer.last_contents().expect("config should be written");
         assert!(rendered.contains("audio_backend_latency_offset_in_seconds = -0.055"));
         assert_eq!(restarter.calls(), 1);
+        assert_eq!(restarter.blocking_calls(), 0);
     }
 
-    #[test]
-    fn clamps_excessive_latency_to_supported_range() {
+    #[tokio::test]
+    async fn replay_rewrites_the_latest_snapshot_with_its_latency() {
+        use crate::calibration::store::{AppliedCalibration, CalibrationSource};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shairport-sync.conf");
+        let restarter = MockController::new();
+        let applier = CalibrationApplier::new(FileConfigWriter::new(&path), restarter.clone());
+        let entry = |timestamp: u64, latency_ms: f32, name: &str| HistoryEntry {
+            applied: AppliedCalibration {
+                timestamp,
+                measured_latency_ms: latency_ms,
+                applied_offset_ms: -latency_ms,
+                confidence: 0.9,
+                output_device: OutputDeviceSpec::hw(1, 0),
+                source: CalibrationSource::Phone,
+                config_generation: Some(2),
+                temperature_c: None,
+            },
+            config_snapshot: ShairportConfig {
+                output_device: OutputDeviceSpec::hw(1, 0),
+                ..generate_config(Some(name), AudioOutput::USB)
+            },
+        };
+
+        assert!(applier.replay_history(&[]).await.is_err());
+        assert_eq!(restarter.calls(), 0);
+
+        let history = [entry(1_000, 12.0, "Old"), entry(2_000, 48.0, "Den")];
+        let outcome = applier.replay_history(&history).await.unwrap();
+        assert_eq!(outcome.measured_latency_ms, 48.0);
+        assert_eq!(format!("{:.3}", outcome.applied_offset_ms), "-48.000");
+
+        let on_disk = parse_config_file(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(on_disk.device_name, "Den");
+        assert_eq!(on_disk.output_device, OutputDeviceSpec::hw(1, 0));
+        assert!((on_disk.latency_offset_seconds + 0.048).abs() < 1e-6);
+        assert_eq!(restarter.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn clamps_excessive_latency_to_supported_range() {
         let writer = MockWriter::new();
         let restarter = MockController::new();
         let applier = CalibrationApplier::new(writer.clone(), restarter.clone());
 
         let config = generate_config(None, AudioOutput::USB);
-        let outcome = applier.apply_latency(config, 800.0).unwrap();
+        let outcome = applier.apply_latency(config, 800.0).await.unwrap();
 
         assert!(outcome.was_clamped);
         assert_eq!(format!("{:.3}", outcome.applied_offset_ms), "-250.000");
@@ -216,14 +675,14 @@ mod tests {
         assert!(rendered.contains("audio_backend_latency_offset_in_seconds = -0.250"));
     }
 
-    #[test]
-    fn delays_playback_when_audio_is_early() {
+    #[tokio::test]
+    async fn delays_playback_when_audio_is_early() {
         let writer = MockWriter::new();
         let restarter = MockController::new();
         let applier = CalibrationApplier::new(writer.clone(), restarter.clone());
 
         let config = generate_config(None, AudioOutput::Headphone);
-        let outcome = applier.apply_latency(config, -20.0).unwrap();
+        let outcome = applier.apply_latency(config, -20.0).await.unwrap();
 
         assert_eq!(format!("{:.3}", outcome.applied_offset_ms), "20.000");
         assert!(!outcome.was_clamped);
@@ -232,25 +691,269 @@ mod tests {
         assert!(rendered.contains("audio_backend_latency_offset_in_seconds = 0.020"));
     }
 
-    #[test]
-    fn applies_submission_payload() {
+    #[tokio::test]
+    async fn applies_submission_payload() {
         let writer = MockWriter::new();
         let restarter = MockController::new();
         let applier = CalibrationApplier::new(writer.clone(), restarter.clone());
 
         let config = generate_config(Some("Studio"), AudioOutput::HDMI);
-        let submission = CalibrationSubmission {
-            timestamp: 1_234,
-            latency_ms: 30.0,
-            confidence: 0.92,
-            detections: vec![],
-        };
-
-        let outcome = applier.apply_submission(config, &submission).unwrap();
+        let submission = CalibrationSubmission::builder()
+            .timestamp(1_234)
+            .latency_ms(30.0)
+            .confidence(0.92)
+            .build()
+            .unwrap();
+
+        let outcome = applier.apply_submission(config, &submission).await.unwrap();
         assert_eq!(format!("{:.3}", outcome.applied_offset_ms), "-30.000");
         assert_eq!(restarter.calls(), 1);
 
         let rendered = writer.last_contents().unwrap();
         assert!(rendered.contains("audio_backend_latency_offset_in_seconds = -0.030"));
     }
+
+    #[tokio::test]
+    async fn restart_failure_fails_the_apply() {
+        let writer = MockWriter::new();
+        let restarter = MockController::failing("unit masked");
+        let applier = CalibrationApplier::new(writer.clone(), restarter.clone());
+
+        let err = applier
+            .apply_latency(generate_config(None, AudioOutput::Headphone), 10.0)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("unit masked"));
+        assert_eq!(restarter.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn default_restart_async_runs_blocking_restart_off_the_runtime() {
+        struct ThreadRecorder(Mutex<Option<std::thread::ThreadId>>);
+        impl ShairportController for ThreadRecorder {
+            fn restart(&self) -> Result<()> {
+                *self.0.lock().unwrap() = Some(std::thread::current().id());
+                Ok(())
+            }
+        }
+
+        let recorder = Arc::new(ThreadRecorder(Mutex::new(None)));
+        recorder.clone().restart_async().await.unwrap();
+        let ran_on = recorder.0.lock().unwrap().expect("restart ran");
+        assert_ne!(ran_on, std::thread::current().id());
+    }
+
+    #[tokio::test]
+    async fn hooks_observe_config_before_write_and_outcome_after() {
+        let writer = MockWriter::new();
+        let before_calls = Arc::new(Mutex::new(Vec::new()));
+        let after_calls = Arc::new(Mutex::new(Vec::new()));
+
+        let applier = CalibrationApplier::new(writer.clone(), MockController::new())
+            .on_before_apply({
+                let calls = before_calls.clone();
+                let writer = writer.clone();
+                move |config: &ShairportConfig, latency_ms| {
+                    calls.lock().unwrap().push((
+                        config.latency_offset_seconds,
+                        latency_ms,
+                        writer.last_contents().is_some(),
+                    ));
+                }
+            })
+            .on_after_apply({
+                let calls = after_calls.clone();
+                move |outcome: &CalibrationOutcome| calls.lock().unwrap().push(outcome.clone())
+            });
+
+        let config = generate_config(None, AudioOutput::Headphone);
+        let outcome = applier.apply_latency(config, 300.0).await.unwrap();
+
+        assert_eq!(*before_calls.lock().unwrap(), vec![(-0.25, 300.0, false)]);
+        assert_eq!(*after_calls.lock().unwrap(), vec![outcome]);
+        assert!(after_calls.lock().unwrap()[0].was_clamped);
+    }
+
+    #[tokio::test]
+    async fn after_hook_is_skipped_when_write_fails() {
+        struct FailingWriter;
+        impl ConfigWriter for FailingWriter {
+            fn write(&self, _contents: &str) -> Result<()> {
+                Err(anyhow::anyhow!("disk full"))
+            }
+        }
+
+        let before = Arc::new(Mutex::new(0));
+        let after = Arc::new(Mutex::new(0));
+        let applier = CalibrationApplier::new(FailingWriter, MockController::new())
+            .on_before_apply({
+                let before = before.clone();
+                move |_: &ShairportConfig, _| *before.lock().unwrap() += 1
+            })
+            .on_after_apply({
+                let after = after.clone();
+                move |_: &CalibrationOutcome| *after.lock().unwrap() += 1
+            });
+
+        assert!(applier
+            .apply_latency(generate_config(None, AudioOutput::Headphone), 20.0)
+            .await
+            .is_err());
+        assert_eq!(*before.lock().unwrap(), 1);
+        assert_eq!(*after.lock().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn clamped_outcome_roundtrips_through_result_message() {
+        let applier = CalibrationApplier::new(MockWriter::new(), MockController::new());
+        let outcome = applier
+            .apply_latency(generate_config(None, AudioOutput::Headphone), 400.0)
+            .await
+            .unwrap();
+        assert!(outcome.was_clamped);
+        let msg: CalibrationMessage = outcome.clone().into();
+        // The message doesn't carry the changed keys.
+        let expected = CalibrationOutcome {
+            changed_keys: Vec::new(),
+            ..outcome
+        };
+        assert_eq!(CalibrationOutcome::from_result_message(&msg), Some(expected));
+    }
+
+
+    #[tokio::test]
+    async fn verify_applied_detects_a_corrupted_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shairport-sync.conf");
+        let applier = CalibrationApplier::new(FileConfigWriter::new(&path), MockController::new()).verify_writes(true);
+        let config = generate_config(Some("Living Room"), AudioOutput::I2S);
+        applier.apply_latency(config.clone(), 55.0).await.unwrap();
+
+        let applied = ShairportConfig {
+            latency_offset_seconds: -0.055,
+            ..config.clone()
+        };
+        assert!(applier.verify_applied(&path, &applied).await.unwrap());
+        assert!(!applier.verify_applied(&path, &config).await.unwrap());
+        fs::write(&path, "general = {\n  name = \"Living").unwrap();
+        assert!(!applier.verify_applied(&path, &applied).await.unwrap());
+        assert!(applier.verify_applied(&dir.path().join("missing.conf"), &applied).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unverifiable_write_fails_before_restart() {
+        /// Writes the config, then clobbers it as a full disk or rogue process might.
+        struct CorruptingWriter {
+            inner: FileConfigWriter,
+            writes: Mutex<u32>,
+        }
+        impl ConfigWriter for CorruptingWriter {
+            fn write(&self, contents: &str) -> Result<()> {
+                *self.writes.lock().unwrap() += 1;
+                self.inner.write(contents)?;
+                fs::write(self.inner.path(), "")?;
+                Ok(())
+            }
+
+            fn target_path(&self) -> Option<&Path> {
+                self.inner.target_path()
+            }
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let writer = CorruptingWriter {
+            inner: FileConfigWriter::new(dir.path().join("shairport-sync.conf")),
+            writes: Mutex::new(0),
+        };
+        let restarter = MockController::new();
+        let config = generate_config(Some("Living Room"), AudioOutput::I2S);
+
+        let unchecked = CalibrationApplier::new(writer, restarter.clone());
+        assert!(unchecked.apply_latency(config.clone(), 55.0).await.is_ok());
+        let checked = unchecked.verify_writes(true);
+        assert!(checked.apply_latency(config, 55.0).await.is_err());
+        assert_eq!(*checked.writer.writes.lock().unwrap(), 3);
+        assert_eq!(restarter.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn multi_writer_keeps_writing_past_a_failure() {
+        struct FailingWriter(&'static str);
+        impl ConfigWriter for FailingWriter {
+            fn write(&self, _contents: &str) -> Result<()> {
+                Err(anyhow!(self.0))
+            }
+        }
+
+        let (first, last) = (MockWriter::new(), MockWriter::new());
+        let writer = MultiConfigWriter::new(vec![
+            Box::new(first.clone()),
+            Box::new(FailingWriter("share unmounted")),
+            Box::new(last.clone()),
+            Box::new(FailingWriter("read-only")),
+        ]);
+        let restarter = MockController::new();
+        let applier = CalibrationApplier::new(writer, restarter.clone());
+        let err = applier
+            .apply_latency(generate_config(None, AudioOutput::Headphone), 20.0)
+            .await
+            .unwrap_err();
+
+        assert!(first.last_contents().unwrap().contains("latency_offset_in_seconds = -0.020"));
+        assert_eq!(last.last_contents(), first.last_contents());
+        let failures = &err.downcast_ref::<MultiWriteError>().unwrap().failures;
+        let failures: Vec<_> = failures.iter().map(|(index, err)| (*index, err.to_string())).collect();
+        assert_eq!(failures, [(1, "share unmounted".to_string()), (3, "read-only".to_string())]);
+        assert_eq!(err.to_string(), "2 of 4 config writes failed: [1] share unmounted; [3] read-only");
+        assert_eq!(restarter.calls(), 0);
+    }
+
+    #[test]
+    fn invalid_configs_are_not_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shairport-sync.conf");
+        fs::write(&path, "original").unwrap();
+        let writer = FileConfigWriter::new(&path);
+
+        let mut config = generate_config(Some("Living Room"), AudioOutput::I2S);
+        config.latency_offset_seconds = f32::NAN;
+        config.calibration_gain = Some(2.0);
+        let err = writer.write(&render_config_file(&config)).unwrap_err().to_string();
+        assert!(err.contains("latency_offset_seconds") && err.contains("calibration_gain"), "{err}");
+        assert!(writer.write("general = {};\n").is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "original");
+
+        config.latency_offset_seconds = -0.05;
+        config.calibration_gain = None;
+        writer.write(&render_config_file(&config)).unwrap();
+        FileConfigWriter::unchecked(&path).write("# fragment\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "# fragment\n");
+    }
+
+    #[cfg(feature = "atomic-writes")]
+    #[test]
+    fn atomic_writer_replaces_file_and_leaves_no_staging_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shairport-sync.conf");
+        fs::write(&path, "old").unwrap();
+        let writer = AtomicFileConfigWriter::new(&path);
+
+        let rendered = render_config_file(&generate_config(None, AudioOutput::USB));
+        writer.write(&rendered).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), rendered);
+        assert!(!writer.staging_path().exists());
+    }
+
+    #[cfg(feature = "atomic-writes")]
+    #[test]
+    fn failed_atomic_write_leaves_original_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shairport-sync.conf");
+        fs::write(&path, "original").unwrap();
+        let writer = AtomicFileConfigWriter::new(&path);
+        // A directory squatting on the staging path makes the staged write fail.
+        fs::create_dir(writer.staging_path()).unwrap();
+
+        assert!(writer.write_atomic("replacement").is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "original");
+    }
 }
@@ -0,0 +1,151 @@
+//! When a calibration playback starts, worked out from the ready call without touching
+//! a clock so every case can be checked directly.
+
+use crate::http::CalibrationLimits;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlaybackSchedule {
+    /// The start the caller asked for.
+    pub requested_start_ms: u64,
+    /// When playback starts: the requested start, pushed back to leave the minimum lead.
+    /// Never before the `now_ms` it was computed at.
+    pub start_at_ms: u64,
+    /// How far the requested start had already passed when the ready call arrived; 0 when
+    /// it was still ahead.
+    pub lateness_ms: u64,
+    /// Whether `start_at_ms` differs from the requested start.
+    pub adjusted: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ScheduleError {
+    #[error("target_start_ms {requested_start_ms} is {lateness_ms}ms in the past (max lateness {max_lateness_ms}ms)")]
+    Missed {
+        requested_start_ms: u64,
+        lateness_ms: u64,
+        max_lateness_ms: u64,
+    },
+    #[error("target_start_ms {requested_start_ms} is more than {max_delay_ms}ms in the future")]
+    BeyondHorizon { requested_start_ms: u64, max_delay_ms: u64 },
+}
+
+impl ScheduleError {
+    /// The `error` code reported for this rejection.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ScheduleError::Missed { .. } => "target_missed",
+            ScheduleError::BeyondHorizon { .. } => "target_beyond_horizon",
+        }
+    }
+}
+
+/// Schedule playback for a ready call arriving at `now_ms`: at `target_start_ms` when
+/// given, else `delay_ms` after `requested_at_ms`. Starts more than
+/// `limits.max_lateness_ms` in the past or `limits.max_delay_ms` in the future are
+/// rejected; anything sooner than `limits.min_lead_ms` from now is pushed back to it.
+pub fn compute_playback_schedule(
+    now_ms: u64,
+    requested_at_ms: u64,
+    delay_ms: u64,
+    target_start_ms: Option<u64>,
+    limits: &CalibrationLimits,
+) -> Result<PlaybackSchedule, ScheduleError> {
+    let requested_start_ms = target_start_ms.unwrap_or(requested_at_ms + delay_ms);
+    let lateness_ms = now_ms.saturating_sub(requested_start_ms);
+    if lateness_ms > limits.max_lateness_ms {
+        return Err(ScheduleError::Missed {
+            requested_start_ms,
+            lateness_ms,
+            max_lateness_ms: limits.max_lateness_ms,
+        });
+    }
+    if requested_start_ms > now_ms + limits.max_delay_ms {
+        return Err(ScheduleError::BeyondHorizon {
+            requested_start_ms,
+            max_delay_ms: limits.max_delay_ms,
+        });
+    }
+    let start_at_ms = requested_start_ms.max(now_ms + limits.min_lead_ms);
+    Ok(PlaybackSchedule {
+        requested_start_ms,
+        start_at_ms,
+        lateness_ms,
+        adjusted: start_at_ms != requested_start_ms,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000_000;
+
+    fn limits() -> CalibrationLimits {
+        CalibrationLimits {
+            min_lead_ms: 1_500,
+            max_lateness_ms: 1_000,
+            max_delay_ms: 30_000,
+            ..CalibrationLimits::default()
+        }
+    }
+
+    #[test]
+    fn on_time_targets_start_as_requested() {
+        let schedule = compute_playback_schedule(NOW, NOW, 0, Some(NOW + 2_000), &limits()).unwrap();
+        assert_eq!(
+            schedule,
+            PlaybackSchedule {
+                requested_start_ms: NOW + 2_000,
+                start_at_ms: NOW + 2_000,
+                lateness_ms: 0,
+                adjusted: false,
+            }
+        );
+        // Without a target the delay counts from the request.
+        let delayed = compute_playback_schedule(NOW, NOW - 500, 3_000, None, &limits()).unwrap();
+        assert_eq!((delayed.start_at_ms, delayed.adjusted), (NOW + 2_500, false));
+    }
+
+    #[test]
+    fn targets_inside_the_lead_are_pushed_back() {
+        let soon = compute_playback_schedule(NOW, NOW, 0, Some(NOW + 200), &limits()).unwrap();
+        assert_eq!((soon.start_at_ms, soon.lateness_ms, soon.adjusted), (NOW + 1_500, 0, true));
+    }
+
+    #[test]
+    fn slightly_late_targets_report_their_lateness() {
+        let late = compute_playback_schedule(NOW, NOW, 0, Some(NOW - 700), &limits()).unwrap();
+        assert_eq!(late.lateness_ms, 700);
+        assert_eq!(late.start_at_ms, NOW + 1_500);
+        assert!(late.adjusted);
+
+        // With no lead the start is now, and the lateness is still reported.
+        let no_lead = CalibrationLimits {
+            min_lead_ms: 0,
+            ..limits()
+        };
+        let late = compute_playback_schedule(NOW, NOW, 0, Some(NOW - 700), &no_lead).unwrap();
+        assert_eq!((late.start_at_ms, late.lateness_ms), (NOW, 700));
+        let edge = compute_playback_schedule(NOW, NOW, 0, Some(NOW - 1_000), &no_lead).unwrap();
+        assert_eq!(edge.lateness_ms, 1_000);
+    }
+
+    #[test]
+    fn very_late_and_far_off_targets_are_rejected() {
+        let missed = compute_playback_schedule(NOW, NOW, 0, Some(NOW - 1_001), &limits()).unwrap_err();
+        assert_eq!(
+            missed,
+            ScheduleError::Missed {
+                requested_start_ms: NOW - 1_001,
+                lateness_ms: 1_001,
+                max_lateness_ms: 1_000,
+            }
+        );
+        assert_eq!(missed.code(), "target_missed");
+
+        let far = compute_playback_schedule(NOW, NOW, 0, Some(NOW + 30_001), &limits()).unwrap_err();
+        assert_eq!(far.code(), "target_beyond_horizon");
+        assert!(compute_playback_schedule(NOW, NOW, 0, Some(NOW + 30_000), &limits()).is_ok());
+    }
+}
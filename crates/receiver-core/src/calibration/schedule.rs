@@ -0,0 +1,516 @@
+//! Nightly self-calibration through a microphone on the receiver itself.
+//!
+//! At the scheduled time, and only while nothing is playing, the receiver plays the
+//! calibration signal quietly, measures it back and applies the result when it is both
+//! confident and different enough from the offset in effect. Every attempt is logged; a
+//! missed run waits for the next scheduled one rather than retrying.
+
+use crate::group::BoxFuture;
+use crate::http::ReceiverState;
+use anyhow::Result;
+use airsync_shared_protocol::PlaybackStatus;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const MINUTE_MS: i64 = 60 * 1000;
+const DAY_MS: i64 = 24 * 60 * MINUTE_MS;
+
+/// Attempts kept for `/api/health`.
+pub const SELFCAL_LOG_LEN: usize = 20;
+
+/// Largest UTC offset accepted, in minutes (UTC+14 is the furthest any zone goes).
+pub const MAX_UTC_OFFSET_MINUTES: i16 = 14 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Weekday {
+    const ALL: [Weekday; 7] = [
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+        Weekday::Sun,
+    ];
+
+    /// The weekday of the `day`th day since 1970-01-01, which was a Thursday.
+    fn of_epoch_day(day: i64) -> Self {
+        Self::ALL[(day + 3).rem_euclid(7) as usize]
+    }
+}
+
+/// When to self-calibrate: a local time of day on some or all days of the week. The
+/// receiver keeps UTC, so the local time comes from a fixed `utc_offset_minutes`, set by
+/// the app from the phone's time zone; a daylight-saving change moves the run by an hour
+/// until the app updates it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SelfCalSchedule {
+    pub hour: u8,
+    pub minute: u8,
+    /// Days to run on; empty runs every day.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<Weekday>,
+    #[serde(default)]
+    pub utc_offset_minutes: i16,
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum SelfCalScheduleError {
+    #[error("hour must be below 24, got {0}")]
+    HourOutOfRange(u8),
+    #[error("minute must be below 60, got {0}")]
+    MinuteOutOfRange(u8),
+    #[error("utc_offset_minutes must be within ±{MAX_UTC_OFFSET_MINUTES}, got {0}")]
+    UtcOffsetOutOfRange(i16),
+}
+
+impl SelfCalSchedule {
+    pub fn validate(&self) -> Result<(), SelfCalScheduleError> {
+        if self.hour >= 24 {
+            return Err(SelfCalScheduleError::HourOutOfRange(self.hour));
+        }
+        if self.minute >= 60 {
+            return Err(SelfCalScheduleError::MinuteOutOfRange(self.minute));
+        }
+        if self.utc_offset_minutes.abs() > MAX_UTC_OFFSET_MINUTES {
+            return Err(SelfCalScheduleError::UtcOffsetOutOfRange(self.utc_offset_minutes));
+        }
+        Ok(())
+    }
+
+    fn runs_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    /// The first scheduled time strictly after `now_ms`, in Unix milliseconds.
+    pub fn next_run_ms(&self, now_ms: u64) -> u64 {
+        let offset_ms = i64::from(self.utc_offset_minutes) * MINUTE_MS;
+        let now_ms = now_ms as i64;
+        let time_of_day_ms = (i64::from(self.hour) * 60 + i64::from(self.minute)) * MINUTE_MS;
+        let today = (now_ms + offset_ms).div_euclid(DAY_MS);
+        // Every weekday comes up within eight days, including today's slot if it passed.
+        (today..=today + 7)
+            .filter(|&day| self.runs_on(Weekday::of_epoch_day(day)))
+            .map(|day| day * DAY_MS + time_of_day_ms - offset_ms)
+            .find(|&at| at > now_ms)
+            .expect("every weekday comes up within eight days") as u64
+    }
+}
+
+/// How scheduled self-calibration measures and when it applies the result.
+#[derive(Debug, Clone, Copy)]
+pub struct SelfCalConfig {
+    /// Playback amplitude for the measurement, quieter than a phone calibration.
+    pub amplitude: f32,
+    /// Results at or below this confidence are logged but not applied.
+    pub min_confidence: f32,
+    /// Results within this many milliseconds of the latency in effect are not applied.
+    pub min_change_ms: f32,
+    /// Longest wait between checks of the schedule, so settings changes and clock
+    /// corrections are noticed.
+    pub recheck_interval: Duration,
+    /// A run noticed later than this after its time, e.g. because the clock jumped when
+    /// NTP synced, is logged as missed instead of run.
+    pub max_lateness: Duration,
+}
+
+impl Default for SelfCalConfig {
+    fn default() -> Self {
+        Self {
+            amplitude: 0.25,
+            min_confidence: 0.8,
+            min_change_ms: 2.0,
+            recheck_interval: Duration::from_secs(60),
+            max_lateness: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// A latency measured through the receiver's own microphone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoopbackMeasurement {
+    pub latency_ms: f32,
+    pub confidence: f32,
+}
+
+/// Plays the calibration signal and records it through a microphone on the receiver.
+pub trait LoopbackCalibrator: Send + Sync {
+    /// Measure the output latency playing at `amplitude`. Errors are misses, such as the
+    /// audio device being busy or the microphone unplugged.
+    fn measure(&self, amplitude: f32) -> BoxFuture<'_, Result<LoopbackMeasurement>>;
+}
+
+/// What came of a scheduled run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum SelfCalOutcome {
+    Applied {
+        measured_latency_ms: f32,
+        applied_offset_ms: f32,
+        confidence: f32,
+    },
+    /// Measured, but not confident enough to apply.
+    LowConfidence { measured_latency_ms: f32, confidence: f32 },
+    /// Measured within `min_change_ms` of the latency in effect.
+    Unchanged { measured_latency_ms: f32, change_ms: f32 },
+    /// Not measured because the receiver was playing or calibrating.
+    Busy { status: PlaybackStatus },
+    /// The measurement failed or the run came too late.
+    Missed { error: String },
+    ApplyFailed { error: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SelfCalAttempt {
+    pub at_ms: u64,
+    /// When the run was scheduled for.
+    pub scheduled_ms: u64,
+    #[serde(flatten)]
+    pub outcome: SelfCalOutcome,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SelfCalSnapshot {
+    /// `None` while no schedule is set.
+    pub next_run_ms: Option<u64>,
+    /// Most recent attempts, oldest first.
+    pub attempts: Vec<SelfCalAttempt>,
+}
+
+/// Shared view of the scheduler's progress, read by the health endpoint. Empty until a
+/// scheduler runs, which it only does on receivers with a loopback microphone.
+#[derive(Clone, Default)]
+pub struct SelfCalHandle {
+    state: Arc<Mutex<Option<SelfCalSnapshot>>>,
+}
+
+impl SelfCalHandle {
+    pub fn snapshot(&self) -> Option<SelfCalSnapshot> {
+        self.state.lock().unwrap().clone()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut SelfCalSnapshot)) {
+        f(self.state.lock().unwrap().get_or_insert_with(SelfCalSnapshot::default));
+    }
+}
+
+/// Runs self-calibration at the times set in the receiver's settings.
+pub struct SelfCalScheduler {
+    state: ReceiverState,
+    calibrator: Arc<dyn LoopbackCalibrator>,
+    config: SelfCalConfig,
+    handle: SelfCalHandle,
+}
+
+impl SelfCalScheduler {
+    /// Report into the handle `state` serves from `/api/health`, and read the time from
+    /// its clock.
+    pub fn new(state: ReceiverState, calibrator: Arc<dyn LoopbackCalibrator>, config: SelfCalConfig) -> Self {
+        let handle = state.self_calibration_handle();
+        Self {
+            state,
+            calibrator,
+            config,
+            handle,
+        }
+    }
+
+    pub fn handle(&self) -> SelfCalHandle {
+        self.handle.clone()
+    }
+
+    pub fn spawn(self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            self.handle.update(|_| {});
+            let mut due = None;
+            loop {
+                let now = self.state.now_ms();
+                if let Some(scheduled) = due.filter(|&at| now >= at) {
+                    self.run(scheduled, now).await;
+                    due = None;
+                    continue;
+                }
+                // Re-read every time, so a changed schedule replaces the pending run.
+                due = self.state.self_calibration_schedule().map(|s| s.next_run_ms(now));
+                self.handle.update(|snapshot| snapshot.next_run_ms = due);
+                let wait = due.map_or(self.config.recheck_interval, |at| {
+                    Duration::from_millis(at - now).min(self.config.recheck_interval)
+                });
+                tokio::time::sleep(wait).await;
+            }
+        })
+    }
+
+    async fn run(&self, scheduled_ms: u64, now_ms: u64) {
+        let outcome = self.attempt(scheduled_ms, now_ms).await;
+        let at_ms = self.state.now_ms();
+        match &outcome {
+            SelfCalOutcome::Applied { .. } => eprintln!("[selfcal] run scheduled at {scheduled_ms}: {outcome:?}"),
+            _ => eprintln!("[selfcal] run scheduled at {scheduled_ms} not applied: {outcome:?}"),
+        }
+        self.handle.update(|snapshot| {
+            snapshot.attempts.push(SelfCalAttempt {
+                at_ms,
+                scheduled_ms,
+                outcome,
+            });
+            let excess = snapshot.attempts.len().saturating_sub(SELFCAL_LOG_LEN);
+            snapshot.attempts.drain(..excess);
+        });
+    }
+
+    async fn attempt(&self, scheduled_ms: u64, now_ms: u64) -> SelfCalOutcome {
+        let late = Duration::from_millis(now_ms - scheduled_ms);
+        if late > self.config.max_lateness {
+            return SelfCalOutcome::Missed {
+                error: format!("woke {}s after the scheduled time", late.as_secs()),
+            };
+        }
+        let status = self.state.playback_status();
+        if status != PlaybackStatus::Idle {
+            return SelfCalOutcome::Busy { status };
+        }
+
+        let measured = self.state.measure_self_calibration(&*self.calibrator, self.config.amplitude).await;
+        let measurement = match measured {
+            Ok(measurement) => measurement,
+            Err(err) => return SelfCalOutcome::Missed { error: format!("{err:#}") },
+        };
+        if measurement.confidence.is_nan() || measurement.confidence <= self.config.min_confidence {
+            return SelfCalOutcome::LowConfidence {
+                measured_latency_ms: measurement.latency_ms,
+                confidence: measurement.confidence,
+            };
+        }
+        let change_ms = measurement.latency_ms - self.state.latency_in_effect_ms();
+        if change_ms.abs() < self.config.min_change_ms {
+            return SelfCalOutcome::Unchanged {
+                measured_latency_ms: measurement.latency_ms,
+                change_ms,
+            };
+        }
+        match self.state.apply_self_calibration(&measurement).await {
+            Ok(applied) => SelfCalOutcome::Applied {
+                measured_latency_ms: applied.measured_latency_ms,
+                applied_offset_ms: applied.applied_offset_ms,
+                confidence: measurement.confidence,
+            },
+            Err(err) => SelfCalOutcome::ApplyFailed { error: format!("{err:#}") },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::airplay::ShairportConfig;
+    use crate::http::{ConfigStore, InMemorySettingsManager};
+    use crate::status::{StatusEvent, StatusTracker};
+    use crate::test_util::{MockCalibrationSink, MockLoopbackCalibrator};
+    use airsync_shared_protocol::OutputDeviceSpec;
+
+    /// Monday 2024-01-01, 02:59 UTC.
+    const T0: u64 = 1_704_067_200_000 + (2 * 60 + 59) * 60 * 1000;
+    const HOUR_MS: u64 = 60 * 60 * 1000;
+
+    thread_local! {
+        static STARTED: tokio::time::Instant = tokio::time::Instant::now();
+    }
+
+    /// Wall clock that follows tokio's paused time from `T0`.
+    fn clock() -> u64 {
+        T0 + STARTED.with(|started| started.elapsed().as_millis() as u64)
+    }
+
+    fn at_three(days: Vec<Weekday>) -> SelfCalSchedule {
+        SelfCalSchedule {
+            hour: 3,
+            minute: 0,
+            days,
+            utc_offset_minutes: 0,
+        }
+    }
+
+    fn measured(latency_ms: f32, confidence: f32) -> Result<LoopbackMeasurement, String> {
+        Ok(LoopbackMeasurement { latency_ms, confidence })
+    }
+
+    struct Harness {
+        sink: MockCalibrationSink,
+        status: StatusTracker,
+        handle: SelfCalHandle,
+    }
+
+    fn start(calibrator: &MockLoopbackCalibrator) -> Harness {
+        clock();
+        let settings = InMemorySettingsManager::new(ConfigStore::new(ShairportConfig {
+            device_name: "AirSync".into(),
+            output_device: OutputDeviceSpec::hw(0, 0),
+            latency_offset_seconds: 0.0,
+            calibration_gain: None,
+            eq: None,
+            self_calibration: Some(at_three(Vec::new())),
+        }));
+        let sink = MockCalibrationSink::new();
+        let status = StatusTracker::new(T0);
+        let state = ReceiverState::builder()
+            .settings(Arc::new(settings))
+            .calibration(Arc::new(sink.clone()))
+            .status_tracker(status.clone())
+            .clock(clock)
+            .build();
+        let scheduler = SelfCalScheduler::new(state, Arc::new(calibrator.clone()), SelfCalConfig::default());
+        let handle = scheduler.handle();
+        scheduler.spawn();
+        Harness { sink, status, handle }
+    }
+
+    async fn advance(ms: u64) {
+        tokio::time::sleep(Duration::from_millis(ms)).await;
+    }
+
+    fn outcomes(handle: &SelfCalHandle) -> Vec<SelfCalOutcome> {
+        handle.snapshot().unwrap().attempts.into_iter().map(|a| a.outcome).collect()
+    }
+
+    #[test]
+    fn next_run_follows_days_and_utc_offset() {
+        let monday = 1_704_067_200_000;
+        assert_eq!(at_three(Vec::new()).next_run_ms(T0), monday + 3 * HOUR_MS);
+        assert_eq!(
+            at_three(Vec::new()).next_run_ms(monday + 3 * HOUR_MS),
+            monday + 27 * HOUR_MS
+        );
+        assert_eq!(
+            at_three(vec![Weekday::Wed, Weekday::Sun]).next_run_ms(T0),
+            monday + (2 * 24 + 3) * HOUR_MS
+        );
+        assert_eq!(
+            at_three(vec![Weekday::Mon]).next_run_ms(monday + 4 * HOUR_MS),
+            monday + (7 * 24 + 3) * HOUR_MS
+        );
+        // 03:00 at UTC+1 is 02:00 UTC, which has passed by T0.
+        let berlin = SelfCalSchedule {
+            utc_offset_minutes: 60,
+            ..at_three(Vec::new())
+        };
+        assert_eq!(berlin.next_run_ms(T0), monday + 26 * HOUR_MS);
+    }
+
+    #[test]
+    fn out_of_range_schedules_are_invalid() {
+        assert_eq!(at_three(Vec::new()).validate(), Ok(()));
+        let invalid = [
+            (SelfCalSchedule { hour: 24, ..at_three(Vec::new()) }, SelfCalScheduleError::HourOutOfRange(24)),
+            (SelfCalSchedule { minute: 60, ..at_three(Vec::new()) }, SelfCalScheduleError::MinuteOutOfRange(60)),
+            (
+                SelfCalSchedule {
+                    utc_offset_minutes: -900,
+                    ..at_three(Vec::new())
+                },
+                SelfCalScheduleError::UtcOffsetOutOfRange(-900),
+            ),
+        ];
+        for (schedule, error) in invalid {
+            assert_eq!(schedule.validate(), Err(error));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn confident_change_is_applied_at_the_scheduled_time() {
+        let calibrator = MockLoopbackCalibrator::new([measured(42.0, 0.95)]);
+        let harness = start(&calibrator);
+
+        advance(30_000).await;
+        assert!(calibrator.amplitudes().is_empty());
+        assert_eq!(harness.handle.snapshot().unwrap().next_run_ms, Some(T0 + 60_000));
+
+        advance(31_000).await;
+        assert_eq!(calibrator.amplitudes(), vec![SelfCalConfig::default().amplitude]);
+        assert_eq!(harness.sink.last().unwrap().latency_ms, 42.0);
+        let snapshot = harness.handle.snapshot().unwrap();
+        assert_eq!(snapshot.attempts[0].scheduled_ms, T0 + 60_000);
+        assert_eq!(
+            snapshot.attempts[0].outcome,
+            SelfCalOutcome::Applied {
+                measured_latency_ms: 42.0,
+                applied_offset_ms: 42.0,
+                confidence: 0.95
+            }
+        );
+        assert_eq!(snapshot.next_run_ms, Some(T0 + 60_000 + 24 * HOUR_MS));
+        assert_eq!(harness.status.refresh(clock()).status, PlaybackStatus::Idle);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn low_confidence_and_small_changes_are_logged_but_not_applied() {
+        let calibrator =
+            MockLoopbackCalibrator::new([measured(42.0, 0.5), measured(42.0, 0.95), measured(43.0, 0.95)]);
+        let harness = start(&calibrator);
+
+        advance(2 * 24 * HOUR_MS + 2 * 60_000).await;
+        assert_eq!(calibrator.amplitudes().len(), 3);
+        assert_eq!(
+            outcomes(&harness.handle),
+            vec![
+                SelfCalOutcome::LowConfidence {
+                    measured_latency_ms: 42.0,
+                    confidence: 0.5
+                },
+                SelfCalOutcome::Applied {
+                    measured_latency_ms: 42.0,
+                    applied_offset_ms: 42.0,
+                    confidence: 0.95
+                },
+                SelfCalOutcome::Unchanged {
+                    measured_latency_ms: 43.0,
+                    change_ms: 1.0
+                },
+            ]
+        );
+        assert_eq!(harness.sink.last().unwrap().latency_ms, 42.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn busy_and_failed_runs_are_logged_without_retrying() {
+        let calibrator = MockLoopbackCalibrator::new([Err("arecord: audio device busy".to_string())]);
+        let harness = start(&calibrator);
+        harness.status.record(StatusEvent::SessionStarted, T0);
+
+        advance(2 * 60_000).await;
+        assert!(calibrator.amplitudes().is_empty());
+        assert_eq!(
+            outcomes(&harness.handle),
+            vec![SelfCalOutcome::Busy {
+                status: PlaybackStatus::Playing
+            }]
+        );
+
+        harness.status.record(StatusEvent::SessionEnded, clock());
+        advance(24 * HOUR_MS).await;
+        assert_eq!(calibrator.amplitudes().len(), 1);
+        advance(23 * HOUR_MS).await;
+        assert_eq!(calibrator.amplitudes().len(), 1);
+        let outcomes = outcomes(&harness.handle);
+        assert_eq!(
+            outcomes[1],
+            SelfCalOutcome::Missed {
+                error: "arecord: audio device busy".into()
+            }
+        );
+        assert_eq!(outcomes.len(), 2);
+        assert!(harness.sink.last().is_none());
+    }
+}
@@ -0,0 +1,254 @@
+use crate::events::{EventHub, EventKind, ReceiverEvent, Subscription};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionPhase {
+    #[default]
+    Idle,
+    Requested,
+    Ready,
+    Playing,
+    Played,
+    Applied,
+    Rejected,
+}
+
+/// A calibration session transition, in the order a round goes through them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SessionEvent {
+    Requested {
+        at_ms: u64,
+        delay_ms: u64,
+    },
+    Ready {
+        at_ms: u64,
+        scheduled_start_ms: u64,
+    },
+    /// `at_ms` is when playback actually began; `slip_ms` is how far that was from schedule.
+    /// The session's result must be applied against the same `config_generation`.
+    /// `sample_rate` is the rate the output is fed at, where the sink reports one.
+    /// `gain_db` is the playback gain relative to the signal as rendered, and `level_db`
+    /// the structured signal's level after it, in dBFS.
+    PlaybackStarted {
+        at_ms: u64,
+        scheduled_start_ms: u64,
+        slip_ms: i64,
+        config_generation: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sample_rate: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        gain_db: Option<f32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        level_db: Option<f32>,
+    },
+    /// A marker of the structured signal going out, while playback proceeds. `at_ms` is
+    /// the reported playback start plus the marker's `offset_ms` into the signal.
+    MarkerEmitted {
+        at_ms: u64,
+        marker_id: String,
+        offset_ms: f64,
+    },
+    PlaybackFinished {
+        at_ms: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    ResultApplied {
+        at_ms: u64,
+        applied_offset_ms: f32,
+        config_generation: u64,
+    },
+    /// `reason` is the conflict code returned to the submitter, or `apply_failed`.
+    ResultRejected {
+        at_ms: u64,
+        reason: String,
+    },
+}
+
+impl SessionEvent {
+    /// Event name on the wire, matching the `event` tag.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SessionEvent::Requested { .. } => "requested",
+            SessionEvent::Ready { .. } => "ready",
+            SessionEvent::PlaybackStarted { .. } => "playback_started",
+            SessionEvent::MarkerEmitted { .. } => "marker_emitted",
+            SessionEvent::PlaybackFinished { .. } => "playback_finished",
+            SessionEvent::ResultApplied { .. } => "result_applied",
+            SessionEvent::ResultRejected { .. } => "result_rejected",
+        }
+    }
+
+    pub fn at_ms(&self) -> u64 {
+        match self {
+            SessionEvent::Requested { at_ms, .. }
+            | SessionEvent::Ready { at_ms, .. }
+            | SessionEvent::PlaybackStarted { at_ms, .. }
+            | SessionEvent::MarkerEmitted { at_ms, .. }
+            | SessionEvent::PlaybackFinished { at_ms, .. }
+            | SessionEvent::ResultApplied { at_ms, .. }
+            | SessionEvent::ResultRejected { at_ms, .. } => *at_ms,
+        }
+    }
+
+    /// The phase this event moves the session to; `None` for progress within a phase.
+    fn phase(&self) -> Option<SessionPhase> {
+        Some(match self {
+            SessionEvent::Requested { .. } => SessionPhase::Requested,
+            SessionEvent::Ready { .. } => SessionPhase::Ready,
+            SessionEvent::PlaybackStarted { .. } => SessionPhase::Playing,
+            SessionEvent::MarkerEmitted { .. } => return None,
+            SessionEvent::PlaybackFinished { .. } => SessionPhase::Played,
+            SessionEvent::ResultApplied { .. } => SessionPhase::Applied,
+            SessionEvent::ResultRejected { .. } => SessionPhase::Rejected,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionSnapshot {
+    pub phase: SessionPhase,
+    pub since_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_event: Option<SessionEvent>,
+}
+
+/// Where the current calibration round stands. Handlers record transitions; streaming
+/// clients take a snapshot and then follow the same events in order, published as
+/// [`ReceiverEvent::Calibration`] on the session's hub.
+#[derive(Clone)]
+pub struct CalibrationSession {
+    state: Arc<Mutex<SessionSnapshot>>,
+    events: EventHub,
+}
+
+impl CalibrationSession {
+    pub fn new(now_ms: u64) -> Self {
+        Self::with_hub(now_ms, EventHub::default())
+    }
+
+    pub fn with_hub(now_ms: u64, events: EventHub) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SessionSnapshot {
+                since_ms: now_ms,
+                ..SessionSnapshot::default()
+            })),
+            events,
+        }
+    }
+
+    /// Record a transition, or only broadcast progress that doesn't change the phase.
+    pub fn record(&self, event: SessionEvent) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(phase) = event.phase() {
+            state.phase = phase;
+            state.since_ms = event.at_ms();
+            state.last_event = Some(event.clone());
+        }
+        // Published under the state lock so a subscriber's snapshot and events never overlap.
+        self.events.publish(ReceiverEvent::Calibration(event));
+    }
+
+    pub fn snapshot(&self) -> SessionSnapshot {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// The current state and every transition after it.
+    pub fn subscribe(&self) -> (SessionSnapshot, SessionEvents) {
+        let state = self.state.lock().unwrap();
+        let (_, events) = self.events.subscribe(&[EventKind::Calibration]);
+        (state.clone(), SessionEvents(events))
+    }
+}
+
+/// A [`Subscription`] to the calibration events of one session.
+pub struct SessionEvents(Subscription);
+
+impl SessionEvents {
+    pub async fn recv(&mut self) -> Option<SessionEvent> {
+        loop {
+            if let ReceiverEvent::Calibration(event) = self.0.recv().await? {
+                return Some(event);
+            }
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<SessionEvent> {
+        loop {
+            if let ReceiverEvent::Calibration(event) = self.0.try_recv()? {
+                return Some(event);
+            }
+        }
+    }
+
+    /// Events dropped because the subscriber fell behind.
+    pub fn dropped_count(&self) -> u64 {
+        self.0.dropped_count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribers_see_transitions_after_their_snapshot() {
+        let session = CalibrationSession::new(5);
+        session.record(SessionEvent::Requested {
+            at_ms: 10,
+            delay_ms: 2_000,
+        });
+        let (snapshot, mut events) = session.subscribe();
+        assert_eq!(snapshot.phase, SessionPhase::Requested);
+        assert_eq!(snapshot.since_ms, 10);
+
+        session.record(SessionEvent::Ready {
+            at_ms: 20,
+            scheduled_start_ms: 2_020,
+        });
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.name(), "ready");
+        assert!(events.try_recv().is_none());
+        assert_eq!(session.snapshot().phase, SessionPhase::Ready);
+    }
+
+    #[test]
+    fn marker_progress_is_broadcast_without_changing_the_phase() {
+        let session = CalibrationSession::new(0);
+        let started = SessionEvent::PlaybackStarted {
+            at_ms: 100,
+            scheduled_start_ms: 100,
+            slip_ms: 0,
+            config_generation: 1,
+            sample_rate: None,
+            gain_db: None,
+            level_db: None,
+        };
+        session.record(started.clone());
+        let (_, mut events) = session.subscribe();
+        session.record(SessionEvent::MarkerEmitted {
+            at_ms: 350,
+            marker_id: "sweep_anchor".into(),
+            offset_ms: 250.0,
+        });
+        assert_eq!(events.try_recv().unwrap().name(), "marker_emitted");
+        let snapshot = session.snapshot();
+        assert_eq!(snapshot.phase, SessionPhase::Playing);
+        assert_eq!(snapshot.since_ms, 100);
+        assert_eq!(snapshot.last_event, Some(started));
+    }
+
+    #[test]
+    fn events_serialize_with_their_name_as_tag() {
+        let event = SessionEvent::ResultRejected {
+            at_ms: 7,
+            reason: "stale_result".into(),
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event"], event.name());
+        assert_eq!(json["reason"], "stale_result");
+    }
+}
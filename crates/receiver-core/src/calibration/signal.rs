@@ -1,11 +1,28 @@
-use airsync_shared_protocol::{CalibrationSignalSpec, MarkerKind, MarkerSpec};
-use anyhow::Result;
+use airsync_shared_protocol::{
+    reference_samples, signal_id_tones, CalibrationSignalSpec, MarkerKind, MarkerSpec, SampleFormat, MAX_SIGNAL_ID,
+    SIGNAL_ID_TONES,
+};
+use super::MAX_LATENCY_MS;
+use anyhow::{anyhow, Result};
 use hound::WavWriter;
-use std::f32::consts::PI;
 use std::path::{Path, PathBuf};
 
+pub mod hash;
+pub mod loudness;
+pub mod resample;
+pub mod validation;
+
 const SAMPLE_RATE: u32 = 48_000;
 const TARGET_LENGTH_MS: u32 = 4_700;
+/// Peak a normalized signal is scaled to, leaving headroom below the clipping guard.
+const NORMALIZED_PEAK: f32 = 0.9;
+/// Clipping guard for rendered signals, relative to full scale.
+const SIGNAL_HEADROOM_PEAK: f32 = 0.97;
+/// Loudness targets outside this range are clamped to it, in dBFS.
+pub const TARGET_LEVEL_RANGE_DB: std::ops::RangeInclusive<f32> = -60.0..=0.0;
+
+const ID_TONE_MS: u32 = 60;
+const ID_GAP_MS: u32 = 30;
 
 #[derive(Clone)]
 pub struct StructuredSignal {
@@ -13,227 +30,677 @@ pub struct StructuredSignal {
     pub path: PathBuf,
 }
 
-fn ms_to_samples(ms: u32) -> usize {
-    ((ms as u64 * SAMPLE_RATE as u64) / 1000) as usize
+impl StructuredSignal {
+    /// See [`CalibrationSignalSpec::total_duration_ms`].
+    pub fn total_duration_ms(&self) -> f64 {
+        self.spec.total_duration_ms()
+    }
+
+    pub fn time_at_sample(&self, sample: u32) -> f64 {
+        self.spec.time_at_sample(sample)
+    }
+
+    pub fn sample_at_time_ms(&self, time_ms: f64) -> u32 {
+        self.spec.sample_at_time_ms(time_ms)
+    }
+}
+
+/// Optional parts of the structured signal layout.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SignalLayout {
+    /// Insert an identifier tone sequence after the sweep anchor so a phone hearing
+    /// several receivers can tell which one it measured.
+    pub signal_id: Option<u16>,
+    /// Scale the mix so its RMS level over the markers reads this many dBFS, as far as
+    /// the peak allows without clipping. Takes precedence over `normalize`.
+    pub target_level_db: Option<f32>,
 }
 
-fn raised_cosine_window(n: usize, len: usize, fade_samples: usize) -> f32 {
-    if len <= 1 {
-        return 1.0;
+impl SignalLayout {
+    pub fn with_signal_id(id: u16) -> Self {
+        Self {
+            signal_id: Some(id),
+            ..Self::default()
+        }
     }
-    let fade = fade_samples.max(1).min(len / 2);
-    if n < fade {
-        0.5 - 0.5 * (PI * n as f32 / fade as f32).cos()
-    } else if n >= len - fade {
-        let k = len - 1 - n;
-        0.5 - 0.5 * (PI * k as f32 / fade as f32).cos()
-    } else {
-        1.0
+}
+
+/// Rendering parameters; marker positions are laid out in milliseconds and converted at
+/// `sample_rate`, so the timeline is the same at every rate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StructuredSignalConfig {
+    pub sample_rate: u32,
+    /// Scale the mixed signal so its peak sits at `NORMALIZED_PEAK` instead of using the
+    /// marker amplitudes as they are. Marker amplitudes are scaled to match.
+    pub normalize: bool,
+    /// Minimum length; the signal is padded with silence up to it.
+    pub target_length_ms: u32,
+    pub sample_format: SampleFormat,
+    /// Fail with [`SignalMixError::Clipped`] when markers mix above full scale instead of
+    /// scaling the whole signal down.
+    pub strict_headroom: bool,
+}
+
+impl Default for StructuredSignalConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: SAMPLE_RATE,
+            normalize: false,
+            target_length_ms: TARGET_LENGTH_MS,
+            sample_format: SampleFormat::I16,
+            strict_headroom: false,
+        }
     }
 }
 
-struct SignalBuilder {
+/// How close the mixed markers came to full scale before anything was clamped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MixReport {
+    /// Peak of the whole mix before clamping or scaling.
+    pub peak: f32,
+    /// Each marker whose region went over 1.0, with the peak in that region.
+    pub clipped: Vec<(String, f32)>,
+    /// Gain applied to the mix and to the markers' amplitudes to bring it back under full
+    /// scale; 1.0 when nothing clipped.
+    pub gain: f32,
+}
+
+impl MixReport {
+    pub fn is_clean(&self) -> bool {
+        self.clipped.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum SignalMixError {
+    #[error("markers {markers:?} mix above full scale (peak {peak:.3})")]
+    Clipped { markers: Vec<String>, peak: f32 },
+}
+
+/// Stable identifier in `0..=MAX_SIGNAL_ID` derived from a receiver id (FNV-1a).
+pub fn signal_id_for_receiver(receiver_id: &str) -> u16 {
+    let hash = receiver_id
+        .bytes()
+        .fold(0x811c_9dc5u32, |h, b| (h ^ b as u32).wrapping_mul(0x0100_0193));
+    (hash % (MAX_SIGNAL_ID as u32 + 1)) as u16
+}
+
+fn ms_to_samples(ms: u32, sample_rate: u32) -> usize {
+    ((ms as u64 * sample_rate as u64) / 1000) as usize
+}
+
+/// Mono mix at full scale ±1.0 that markers are added into. One builder can be reused
+/// across renders with [`SignalBuilder::reset`], or cloned and joined for streaming.
+#[derive(Debug, Clone)]
+pub struct SignalBuilder {
     sample_rate: u32,
     samples: Vec<f32>,
 }
 
 impl SignalBuilder {
-    fn new(sample_rate: u32) -> Self {
+    pub fn new(sample_rate: u32) -> Self {
         Self {
             sample_rate,
             samples: Vec::new(),
         }
     }
 
-    fn len(&self) -> usize {
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn len(&self) -> usize {
         self.samples.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Drop every sample, keeping the sample rate and the buffer's capacity.
+    pub fn reset(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Keep the first `len` samples; a builder already that short is left alone.
+    pub fn truncate(&mut self, len: usize) {
+        self.samples.truncate(len);
+    }
+
+    /// Add `other`'s samples after this builder's.
+    ///
+    /// # Panics
+    /// When `other` was mixed at a different sample rate.
+    pub fn append(&mut self, other: &SignalBuilder) {
+        assert_eq!(
+            self.sample_rate, other.sample_rate,
+            "appending a signal mixed at a different sample rate"
+        );
+        self.samples.extend_from_slice(&other.samples);
+    }
+
+    fn ms_to_samples(&self, ms: u32) -> usize {
+        ms_to_samples(ms, self.sample_rate)
+    }
+
     fn ensure_len(&mut self, len: usize) {
         if self.samples.len() < len {
             self.samples.resize(len, 0.0);
         }
     }
 
-    fn mix_wave<F>(&mut self, start: usize, duration: usize, amp: f32, fade_samples: usize, mut f: F)
-    where
-        F: FnMut(usize, f32) -> f32,
-    {
-        if duration == 0 {
-            return;
+    /// Mix `marker`'s reference waveform, so what is played is exactly what
+    /// `reference_samples` hands to a matched filter.
+    pub fn mix_marker(&mut self, marker: &MarkerSpec) {
+        let start = marker.start_sample as usize;
+        let reference = reference_samples(marker, self.sample_rate);
+        self.ensure_len(start + reference.len());
+        for (out, s) in self.samples[start..].iter_mut().zip(reference) {
+            *out += s;
+        }
+    }
+
+    fn peak(&self) -> f32 {
+        self.samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()))
+    }
+
+    /// Scale the mix by `gain`, and `markers`' amplitudes with it so they keep describing it.
+    fn scale(&mut self, markers: &mut [MarkerSpec], gain: f32) {
+        for sample in &mut self.samples {
+            *sample *= gain;
         }
-        let end = start + duration;
-        self.ensure_len(end);
-        let sr = self.sample_rate as f32;
-        for n in 0..duration {
-            let env = raised_cosine_window(n, duration, fade_samples);
-            let t = n as f32 / sr;
-            self.samples[start + n] += f(n, t) * amp * env;
+        for marker in markers {
+            marker.amplitude *= gain;
         }
     }
 
-    fn mix_sine(&mut self, start: usize, duration: usize, freq_hz: f32, amp: f32, fade_samples: usize) {
-        self.mix_wave(start, duration, amp, fade_samples, |_, t| (2.0 * PI * freq_hz * t).sin());
+    /// Flat RMS level over `markers`' regions, in dBFS.
+    fn level_db(&self, markers: &[MarkerSpec]) -> Option<f32> {
+        loudness::active_level_db(&self.samples, markers, self.sample_rate, loudness::Weighting::Flat)
     }
 
-    fn mix_constant(&mut self, start: usize, duration: usize, amp: f32, fade_samples: usize) {
-        self.mix_wave(start, duration, amp, fade_samples, |_, _| 1.0);
+    /// Scale the mix so [`Self::level_db`] reads `target_db`, clamped to
+    /// [`TARGET_LEVEL_RANGE_DB`] and to the gain that keeps the peak at `NORMALIZED_PEAK`.
+    /// Returns the level reached.
+    fn scale_to_level(&mut self, markers: &mut [MarkerSpec], target_db: f32) -> Option<f32> {
+        let target_db = target_db.clamp(*TARGET_LEVEL_RANGE_DB.start(), *TARGET_LEVEL_RANGE_DB.end());
+        let level = self.level_db(markers)?;
+        let peak = self.peak();
+        if peak <= 0.0 {
+            return Some(level);
+        }
+        let gain = loudness::db_to_gain(target_db - level).min(NORMALIZED_PEAK / peak);
+        self.scale(markers, gain);
+        let reached = self.level_db(markers)?;
+        if reached < target_db - 0.05 {
+            eprintln!(
+                "[calibration] target level {target_db:.1} dBFS needs more than the signal's headroom; holding it at {reached:.1} dBFS"
+            );
+        }
+        Some(reached)
     }
 
-    fn mix_sweep(
-        &mut self,
-        start: usize,
-        duration: usize,
-        start_freq: f32,
-        end_freq: f32,
-        amp: f32,
-        fade_samples: usize,
-    ) {
-        let total_seconds = duration as f32 / self.sample_rate as f32;
-        let k = (end_freq - start_freq) / total_seconds;
-        self.mix_wave(start, duration, amp, fade_samples, |_, t| {
-            (2.0 * PI * (start_freq * t + 0.5 * k * t * t)).sin()
-        });
+    /// Pre-clamp peak of the mix overall and over each of `markers`' regions.
+    fn mix_report(&self, markers: &[MarkerSpec]) -> MixReport {
+        let peak_in = |samples: &[f32]| samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let clipped = markers
+            .iter()
+            .filter_map(|marker| {
+                let start = (marker.start_sample as usize).min(self.samples.len());
+                let end = (marker.end_sample() as usize).min(self.samples.len());
+                let peak = peak_in(&self.samples[start..end]);
+                (peak > 1.0).then(|| (marker.id.clone(), peak))
+            })
+            .collect();
+        MixReport {
+            peak: peak_in(&self.samples),
+            clipped,
+            gain: 1.0,
+        }
+    }
+
+    /// Keep the mix from being clamped: when any marker region went over full scale,
+    /// scale the whole mix so it peaks at `NORMALIZED_PEAK` and scale `markers`'
+    /// amplitudes to match, keeping relative levels. `strict` fails instead.
+    fn settle_headroom(&mut self, markers: &mut [MarkerSpec], strict: bool) -> Result<MixReport> {
+        let mut report = self.mix_report(markers);
+        if report.is_clean() {
+            return Ok(report);
+        }
+        if strict {
+            return Err(SignalMixError::Clipped {
+                markers: report.clipped.iter().map(|(id, _)| id.clone()).collect(),
+                peak: report.peak,
+            }
+            .into());
+        }
+        report.gain = NORMALIZED_PEAK / report.peak;
+        self.scale(markers, report.gain);
+        Ok(report)
+    }
+
+    /// Mix the tone sequence encoding `id` from `start`, returning its markers and the
+    /// sample just past the last tone.
+    fn mix_id_sequence(&mut self, start: usize, id: u16) -> Result<(Vec<MarkerSpec>, usize)> {
+        let tones = signal_id_tones(id).ok_or_else(|| anyhow!("signal id {id} exceeds {MAX_SIGNAL_ID}"))?;
+        let tone_len = self.ms_to_samples(ID_TONE_MS);
+        let mut cursor = start;
+        let mut markers = Vec::with_capacity(SIGNAL_ID_TONES);
+        for (idx, freq) in tones.iter().enumerate() {
+            let marker = MarkerSpec {
+                id: format!("signal_id_{}", idx + 1),
+                kind: MarkerKind::Chirp {
+                    start_freq: *freq,
+                    end_freq: *freq,
+                    duration_ms: ID_TONE_MS,
+                },
+                start_sample: cursor as u32,
+                duration_samples: tone_len as u32,
+                fade_samples: (tone_len / 8) as u32,
+                amplitude: 0.7,
+                search_window_samples: None,
+            };
+            self.mix_marker(&marker);
+            markers.push(marker);
+            cursor += tone_len + self.ms_to_samples(ID_GAP_MS);
+        }
+        Ok((markers, cursor))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum SequenceStep {
+    Marker(SequenceMarker),
+    /// The tone sequence encoding a signal id, each tone followed by its gap.
+    SignalId(u16),
+    Gap(u32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct SequenceMarker {
+    id: String,
+    /// What unnamed markers of this shape are numbered after, e.g. `click` for `click_2`.
+    prefix: &'static str,
+    kind: MarkerKind,
+    duration_ms: u32,
+    amplitude: f32,
+    /// Fade at each end as `(numerator, denominator)` of the marker's length.
+    fade: (u32, u32),
+}
+
+/// A marker sequence laid out in milliseconds, one marker or gap after another, and
+/// mixed at whatever sample rate it is built for. Markers are named after their shape
+/// (`click_1`, `tone_3`, ...) unless [`Self::named`], and fade over a default share of
+/// their length for that shape unless [`Self::with_fade`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MarkerSequenceBuilder {
+    steps: Vec<SequenceStep>,
+}
+
+impl MarkerSequenceBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The receiver's structured calibration signal, with the identifier tones for
+    /// `signal_id` after the sweep anchor when there is one.
+    pub fn structured(signal_id: Option<u16>) -> Self {
+        // Low-level pre-roll hum that wakes the output path and ends just before the first
+        // click, so no two markers share samples.
+        let mut sequence = Self::new()
+            .add_preroll(300, 120.0, 0.09)
+            .named("warmup")
+            .gap(20)
+            // Leading click with soft envelope.
+            .add_click(12, 0.72)
+            .named("click_a")
+            .gap(20)
+            // Sweep anchor for robust detection.
+            .add_sweep(150, 400.0, 9_000.0, 0.65)
+            .named("sweep_anchor")
+            .gap(200);
+        if let Some(id) = signal_id {
+            sequence = sequence.add_signal_id(id).gap(170);
+        }
+        // Multi-tone markers.
+        for (idx, freq) in [800.0, 1_000.0, 3_000.0, 6_000.0, 8_000.0, 10_000.0, 4_000.0].into_iter().enumerate() {
+            sequence = sequence.add_tone(freq, 120, 0.85).named(format!("chirp_{}", idx + 1)).gap(260);
+        }
+        // Trailing click and warm-down hum to avoid pops at the end.
+        sequence
+            .gap(200)
+            .add_click(14, 0.45)
+            .named("click_b")
+            .with_fade(3, 4)
+            .gap(60)
+            .add_tone(200.0, 220, 0.035)
+            .named("warmdown")
+            .with_fade(1, 8)
+    }
+
+    /// Low constant hum that wakes the output path. Its length and the gaps straight after
+    /// it are converted to samples as one span, so the marker after it lands on the sample
+    /// for its offset from the pre-roll's start at every rate.
+    pub fn add_preroll(self, duration_ms: u32, freq: f32, amp: f32) -> Self {
+        self.push("preroll", constant(freq, duration_ms), duration_ms, amp, (1, 8))
+    }
+
+    pub fn add_click(self, duration_ms: u32, amp: f32) -> Self {
+        self.push("click", MarkerKind::Click, duration_ms, amp, (1, 2))
+    }
+
+    /// Linear sweep from `start` to `end` Hz.
+    pub fn add_sweep(self, duration_ms: u32, start: f32, end: f32, amp: f32) -> Self {
+        let kind = MarkerKind::Chirp {
+            start_freq: hz(start),
+            end_freq: hz(end),
+            duration_ms,
+        };
+        self.push("sweep", kind, duration_ms, amp, (1, 10))
+    }
+
+    pub fn add_tone(self, freq: f32, duration_ms: u32, amp: f32) -> Self {
+        self.push("tone", constant(freq, duration_ms), duration_ms, amp, (1, 12))
+    }
+
+    /// The tones encoding `id`, named `signal_id_1` onwards, each followed by a short gap.
+    /// An id above `MAX_SIGNAL_ID` fails the build.
+    pub fn add_signal_id(mut self, id: u16) -> Self {
+        self.steps.push(SequenceStep::SignalId(id));
+        self
+    }
+
+    /// Silence before whatever is added next.
+    pub fn gap(mut self, ms: u32) -> Self {
+        self.steps.push(SequenceStep::Gap(ms));
+        self
+    }
+
+    /// Name the marker added last.
+    pub fn named(mut self, id: impl Into<String>) -> Self {
+        if let Some(marker) = self.last_marker() {
+            marker.id = id.into();
+        }
+        self
+    }
+
+    /// Fade the marker added last over `numerator / denominator` of its length at each end.
+    pub fn with_fade(mut self, numerator: u32, denominator: u32) -> Self {
+        if let Some(marker) = self.last_marker() {
+            marker.fade = (numerator, denominator.max(1));
+        }
+        self
+    }
+
+    /// Mix the sequence at `sample_rate`, returning the samples, through the last gap, and
+    /// the markers in them.
+    pub fn build(&self, sample_rate: u32) -> Result<(Vec<f32>, Vec<MarkerSpec>)> {
+        let (builder, markers) = self.mix(sample_rate)?;
+        Ok((builder.samples, markers))
+    }
+
+    fn mix(&self, sample_rate: u32) -> Result<(SignalBuilder, Vec<MarkerSpec>)> {
+        if sample_rate == 0 {
+            return Err(anyhow!("sample rate must be above 0 Hz"));
+        }
+        let mut builder = SignalBuilder::new(sample_rate);
+        let mut markers: Vec<MarkerSpec> = Vec::new();
+        let mut cursor = 0;
+        // Start of a pre-roll and the milliseconds from it still to convert.
+        let mut span: Option<(usize, u32)> = None;
+        for step in &self.steps {
+            if let Some((start, ms)) = span.filter(|_| !matches!(step, SequenceStep::Gap(_))) {
+                cursor = start + ms_to_samples(ms, sample_rate);
+                span = None;
+            }
+            match step {
+                SequenceStep::Gap(ms) => match &mut span {
+                    Some((_, span_ms)) => *span_ms += ms,
+                    None => cursor += ms_to_samples(*ms, sample_rate),
+                },
+                SequenceStep::SignalId(id) => {
+                    let (id_markers, end) = builder.mix_id_sequence(cursor, *id)?;
+                    markers.extend(id_markers);
+                    cursor = end;
+                }
+                SequenceStep::Marker(step) => {
+                    if matches!(step.kind, MarkerKind::Chirp { start_freq, end_freq, .. } if start_freq == 0 || end_freq == 0) {
+                        return Err(anyhow!("marker {} needs a frequency above 0 Hz", step.id));
+                    }
+                    let len = ms_to_samples(step.duration_ms, sample_rate);
+                    let (numerator, denominator) = step.fade;
+                    let marker = MarkerSpec {
+                        id: step.id.clone(),
+                        kind: step.kind.clone(),
+                        start_sample: cursor as u32,
+                        duration_samples: len as u32,
+                        fade_samples: ((len * numerator as usize) / denominator as usize) as u32,
+                        amplitude: step.amplitude,
+                        search_window_samples: None,
+                    };
+                    builder.mix_marker(&marker);
+                    markers.push(marker);
+                    if step.prefix == "preroll" {
+                        span = Some((cursor, step.duration_ms));
+                    } else {
+                        cursor += len;
+                    }
+                }
+            }
+        }
+        if let Some((start, ms)) = span {
+            cursor = start + ms_to_samples(ms, sample_rate);
+        }
+        if let Some((idx, marker)) = markers
+            .iter()
+            .enumerate()
+            .find(|(idx, marker)| markers[..*idx].iter().any(|m| m.id == marker.id))
+        {
+            return Err(anyhow!("marker {idx} reuses the id {}", marker.id));
+        }
+        builder.ensure_len(cursor);
+        Ok((builder, markers))
+    }
+
+    fn push(mut self, prefix: &'static str, kind: MarkerKind, duration_ms: u32, amplitude: f32, fade: (u32, u32)) -> Self {
+        let number = 1 + self
+            .steps
+            .iter()
+            .filter(|step| matches!(step, SequenceStep::Marker(m) if m.prefix == prefix))
+            .count();
+        self.steps.push(SequenceStep::Marker(SequenceMarker {
+            id: format!("{prefix}_{number}"),
+            prefix,
+            kind,
+            duration_ms,
+            amplitude,
+            fade,
+        }));
+        self
+    }
+
+    fn last_marker(&mut self) -> Option<&mut SequenceMarker> {
+        self.steps.iter_mut().rev().find_map(|step| match step {
+            SequenceStep::Marker(marker) => Some(marker),
+            _ => None,
+        })
+    }
+}
+
+fn hz(freq: f32) -> u32 {
+    freq.max(0.0).round() as u32
+}
+
+fn constant(freq: f32, duration_ms: u32) -> MarkerKind {
+    MarkerKind::Chirp {
+        start_freq: hz(freq),
+        end_freq: hz(freq),
+        duration_ms,
     }
 }
 
 pub fn generate_structured_signal(path: impl AsRef<Path>) -> Result<StructuredSignal> {
+    generate_structured_signal_with(path, SignalLayout::default(), StructuredSignalConfig::default())
+}
+
+/// Reuse the signal an earlier run left at `path` when it was generated from the same
+/// layout and is intact, otherwise generate it.
+pub fn load_or_generate_structured_signal(
+    path: impl AsRef<Path>,
+    layout: SignalLayout,
+    config: StructuredSignalConfig,
+) -> Result<StructuredSignal> {
+    match hash::cached_signal(path.as_ref(), &hash::layout_hash(&layout, &config)) {
+        Some(signal) => Ok(signal),
+        None => generate_structured_signal_with(path, layout, config),
+    }
+}
+
+/// Render the structured signal to `path` and record its spec and hashes in the sidecar
+/// next to it. A mix that had to be scaled down is logged.
+pub fn generate_structured_signal_with(
+    path: impl AsRef<Path>,
+    layout: SignalLayout,
+    config: StructuredSignalConfig,
+) -> Result<StructuredSignal> {
+    let (signal, report) = build_structured_signal(path, layout, config)?;
+    log_scaled_mix(&report);
+    Ok(signal)
+}
+
+fn log_scaled_mix(report: &MixReport) {
+    if !report.is_clean() {
+        eprintln!(
+            "[calibration] markers {:?} mixed above full scale (peak {:.3}); scaled the signal by {:.3}",
+            report.clipped.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>(),
+            report.peak,
+            report.gain
+        );
+    }
+}
+
+/// [`generate_structured_signal_with`], also returning how the mix fit under full scale.
+pub fn build_structured_signal(
+    path: impl AsRef<Path>,
+    layout: SignalLayout,
+    config: StructuredSignalConfig,
+) -> Result<(StructuredSignal, MixReport)> {
     let path = path.as_ref().to_path_buf();
-    let mut markers: Vec<MarkerSpec> = Vec::new();
-    let mut builder = SignalBuilder::new(SAMPLE_RATE);
-
-    // Low-level pre-roll hum that overlaps the first marker.
-    let preroll_ms = 520;
-    let preroll_len = ms_to_samples(preroll_ms);
-    let preroll_fade = preroll_len / 8;
-    builder.mix_sine(0, preroll_len, 120.0, 0.09, preroll_fade);
-    markers.push(MarkerSpec {
-        id: "warmup".into(),
-        kind: MarkerKind::Chirp {
-            start_freq: 120,
-            end_freq: 120,
-            duration_ms: preroll_ms,
-        },
-        start_sample: 0,
-        duration_samples: preroll_len as u32,
-    });
-
-    let mut cursor: usize = ms_to_samples(320);
-
-    // Leading click with soft envelope.
-    let click_a_len = ms_to_samples(12);
-    builder.mix_constant(cursor, click_a_len, 0.72, click_a_len / 2);
-    markers.push(MarkerSpec {
-        id: "click_a".into(),
-        kind: MarkerKind::Click,
-        start_sample: cursor as u32,
-        duration_samples: click_a_len as u32,
-    });
-    cursor += click_a_len;
-    cursor += ms_to_samples(20);
-
-    // Sweep anchor for robust detection.
-    let sweep_ms = 150;
-    let sweep_len = ms_to_samples(sweep_ms);
-    let sweep_start = cursor;
-    builder.mix_sweep(sweep_start, sweep_len, 400.0, 9_000.0, 0.65, sweep_len / 10);
-    markers.push(MarkerSpec {
-        id: "sweep_anchor".into(),
-        kind: MarkerKind::Chirp {
-            start_freq: 400,
-            end_freq: 9_000,
-            duration_ms: sweep_ms,
-        },
-        start_sample: sweep_start as u32,
-        duration_samples: sweep_len as u32,
-    });
-    cursor += sweep_len;
-    cursor += ms_to_samples(200);
-
-    // Multi-tone markers.
-    let chirp_duration_ms = 120;
-    let chirp_len = ms_to_samples(chirp_duration_ms);
-    let gap_ms = 260;
-    let freqs = [800, 1_000, 3_000, 6_000, 8_000, 10_000, 4_000];
-    for (idx, freq) in freqs.iter().enumerate() {
-        let start = cursor;
-        builder.mix_sine(start, chirp_len, *freq as f32, 0.85, chirp_len / 12);
-        markers.push(MarkerSpec {
-            id: format!("chirp_{}", idx + 1),
-            kind: MarkerKind::Chirp {
-                start_freq: *freq,
-                end_freq: *freq,
-                duration_ms: chirp_duration_ms,
-            },
-            start_sample: start as u32,
-            duration_samples: chirp_len as u32,
-        });
-        cursor += chirp_len;
-        cursor += ms_to_samples(gap_ms);
-    }
-
-    // Trailing click and warm-down hum to avoid pops at the end.
-    cursor += ms_to_samples(200);
-    let click_b_len = ms_to_samples(14);
-    let click_b_start = cursor;
-    builder.mix_constant(click_b_start, click_b_len, 0.45, (click_b_len * 3) / 4);
-    markers.push(MarkerSpec {
-        id: "click_b".into(),
-        kind: MarkerKind::Click,
-        start_sample: click_b_start as u32,
-        duration_samples: click_b_len as u32,
-    });
-    cursor += click_b_len;
-    cursor += ms_to_samples(60);
-
-    let warmdown_ms = 220;
-    let warmdown_len = ms_to_samples(warmdown_ms);
-    let warmdown_start = cursor;
-    builder.mix_sine(warmdown_start, warmdown_len, 200.0, 0.035, warmdown_len / 8);
-    markers.push(MarkerSpec {
-        id: "warmdown".into(),
-        kind: MarkerKind::Chirp {
-            start_freq: 200,
-            end_freq: 200,
-            duration_ms: warmdown_ms,
-        },
-        start_sample: warmdown_start as u32,
-        duration_samples: warmdown_len as u32,
-    });
-    cursor += warmdown_len;
+    let (mut builder, mut markers) = MarkerSequenceBuilder::structured(layout.signal_id).mix(config.sample_rate)?;
 
-    let target_len = ms_to_samples(TARGET_LENGTH_MS).max(cursor);
+    let target_len = ms_to_samples(config.target_length_ms, config.sample_rate).max(builder.len());
     builder.ensure_len(target_len);
     let length_samples = builder.len() as u32;
+    let report = builder.settle_headroom(&mut markers, config.strict_headroom)?;
+    let level_db = match layout.target_level_db {
+        Some(target) => builder.scale_to_level(&mut markers, target),
+        None => {
+            let peak = builder.peak();
+            if config.normalize && peak > 0.0 {
+                builder.scale(&mut markers, NORMALIZED_PEAK / peak);
+            }
+            builder.level_db(&markers)
+        }
+    };
+
+    let mut signal_spec = CalibrationSignalSpec {
+        sample_rate: config.sample_rate,
+        sample_format: config.sample_format,
+        length_samples,
+        markers,
+        signal_id: layout.signal_id,
+        content_hash: None,
+        level_db,
+        suggested_decimation: 1,
+        max_expected_latency_ms: None,
+    };
+    signal_spec.annotate_search_hints(MAX_LATENCY_MS as u32);
+    signal_spec.validate()?;
+    write_wav(&path, &builder, config.sample_format)?;
+    signal_spec.content_hash = Some(hash::content_hash(&signal_spec, &path)?);
+    hash::write_sidecar(
+        &path,
+        &hash::SignalSidecar {
+            layout_hash: hash::layout_hash(&layout, &config),
+            spec: signal_spec.clone(),
+        },
+    )?;
+
+    Ok((
+        StructuredSignal {
+            spec: signal_spec,
+            path,
+        },
+        report,
+    ))
+}
 
-    let pcm: Vec<i16> = builder
-        .samples
-        .iter()
-        .map(|s| (s.clamp(-0.97, 0.97) * i16::MAX as f32) as i16)
-        .collect();
+/// Render a signal laid out by an externally supplied spec, e.g. one posted to
+/// `/api/calibration/signal/spec`. Markers are mixed at their amplitudes without normalizing
+/// unless they would clip, and any `content_hash` and search hints in `spec` are replaced
+/// by the rendered signal's.
+pub fn render_structured_signal(path: impl AsRef<Path>, mut spec: CalibrationSignalSpec) -> Result<StructuredSignal> {
+    if spec.sample_rate == 0 {
+        return Err(anyhow!("sample rate must be above 0 Hz"));
+    }
+    spec.validate()?;
+    let path = path.as_ref().to_path_buf();
+    let mut builder = SignalBuilder::new(spec.sample_rate);
+    for marker in &spec.markers {
+        builder.mix_marker(marker);
+    }
+    builder.ensure_len(spec.length_samples as usize);
+    log_scaled_mix(&builder.settle_headroom(&mut spec.markers, false)?);
+    spec.level_db = builder.level_db(&spec.markers);
+    spec.annotate_search_hints(MAX_LATENCY_MS as u32);
+    write_wav(&path, &builder, spec.sample_format)?;
+    spec.content_hash = Some(hash::content_hash(&spec, &path)?);
+    Ok(StructuredSignal { spec, path })
+}
 
+fn write_wav(path: &Path, builder: &SignalBuilder, format: SampleFormat) -> Result<()> {
+    write_samples(path, &builder.samples, builder.sample_rate, format, SIGNAL_HEADROOM_PEAK)
+}
+
+/// Write mono `samples` (full scale at ±1.0) as a WAV in `format`, clipped to ±`peak`.
+/// Integer formats scale to their own full scale, so the same `peak` leaves the same
+/// headroom at every bit depth; float samples are written as they are.
+pub fn write_samples(path: &Path, samples: &[f32], sample_rate: u32, format: SampleFormat, peak: f32) -> Result<()> {
     let spec = hound::WavSpec {
         channels: 1,
-        sample_rate: SAMPLE_RATE,
-        bits_per_sample: 16,
-        sample_format: hound::SampleFormat::Int,
+        sample_rate,
+        bits_per_sample: format.bits_per_sample(),
+        sample_format: match format {
+            SampleFormat::I16 | SampleFormat::I24 => hound::SampleFormat::Int,
+            SampleFormat::F32 => hound::SampleFormat::Float,
+        },
     };
-    let mut writer = WavWriter::create(&path, spec)?;
-    for s in pcm.iter() {
-        writer.write_sample(*s)?;
+    let mut writer = WavWriter::create(path, spec)?;
+    let clipped = samples.iter().map(|s| s.clamp(-peak, peak));
+    match format {
+        SampleFormat::I16 => {
+            for s in clipped {
+                writer.write_sample((s * i16::MAX as f32).round() as i16)?;
+            }
+        }
+        SampleFormat::I24 => {
+            const I24_MAX: f32 = ((1 << 23) - 1) as f32;
+            for s in clipped {
+                writer.write_sample((s * I24_MAX).round() as i32)?;
+            }
+        }
+        SampleFormat::F32 => {
+            for s in clipped {
+                writer.write_sample(s)?;
+            }
+        }
     }
     writer.finalize()?;
-
-    let signal_spec = CalibrationSignalSpec {
-        sample_rate: SAMPLE_RATE,
-        length_samples,
-        markers,
-    };
-
-    Ok(StructuredSignal {
-        spec: signal_spec,
-        path,
-    })
+    Ok(())
 }
 
 #[cfg(test)]
@@ -242,15 +709,106 @@ mod tests {
     use hound::WavReader;
     use tempfile::tempdir;
 
+    #[test]
+    fn sequences_compose_markers_and_gaps_in_order() {
+        let (samples, markers) = MarkerSequenceBuilder::new()
+            .add_click(10, 0.5)
+            .gap(5)
+            .add_sweep(100, 500.0, 2_000.0, 0.4)
+            .add_tone(1_000.0, 50, 0.3)
+            .named("beep")
+            .add_click(10, 0.5)
+            .with_fade(1, 4)
+            .gap(20)
+            .build(SAMPLE_RATE)
+            .unwrap();
+        let layout: Vec<_> = markers
+            .iter()
+            .map(|m| (m.id.as_str(), m.start_sample, m.duration_samples, m.fade_samples))
+            .collect();
+        assert_eq!(
+            layout,
+            [
+                ("click_1", 0, 480, 240),
+                ("sweep_1", 720, 4_800, 480),
+                ("beep", 5_520, 2_400, 200),
+                ("click_2", 7_920, 480, 120),
+            ]
+        );
+        assert_eq!(
+            markers[1].kind,
+            MarkerKind::Chirp {
+                start_freq: 500,
+                end_freq: 2_000,
+                duration_ms: 100
+            }
+        );
+        assert_eq!(samples.len(), 7_920 + 480 + 960);
+        assert!(samples[8_400..].iter().all(|s| *s == 0.0));
+        let spec = CalibrationSignalSpec {
+            sample_rate: SAMPLE_RATE,
+            sample_format: SampleFormat::I16,
+            length_samples: samples.len() as u32,
+            markers,
+            signal_id: None,
+            content_hash: None,
+            level_db: None,
+            suggested_decimation: 1,
+            max_expected_latency_ms: None,
+        };
+        assert!(spec.validate().is_ok());
+    }
+
+    #[test]
+    fn preroll_and_its_gap_are_one_span_from_the_start() {
+        // At 11025 Hz, 300 ms and 20 ms each round down half a sample; together they don't.
+        let (_, markers) = MarkerSequenceBuilder::new()
+            .add_preroll(300, 120.0, 0.1)
+            .gap(20)
+            .add_click(12, 0.7)
+            .gap(20)
+            .add_signal_id(5)
+            .add_tone(800.0, 120, 0.8)
+            .build(11_025)
+            .unwrap();
+        assert_eq!(markers[0].id, "preroll_1");
+        assert_eq!(markers[1].start_sample, 3_528);
+        assert_eq!(markers[2].id, "signal_id_1");
+        assert_eq!(markers[2].start_sample, 3_528 + 132 + 220);
+        assert_eq!(markers.last().unwrap().id, "tone_1");
+    }
+
+    #[test]
+    fn invalid_sequences_fail_to_build() {
+        let twice = MarkerSequenceBuilder::new().add_click(10, 0.5).named("a").add_click(10, 0.5).named("a");
+        assert!(twice.build(SAMPLE_RATE).unwrap_err().to_string().contains("reuses the id a"));
+        assert!(MarkerSequenceBuilder::new().add_tone(0.0, 50, 0.5).build(SAMPLE_RATE).is_err());
+        assert!(MarkerSequenceBuilder::new().add_signal_id(MAX_SIGNAL_ID + 1).build(SAMPLE_RATE).is_err());
+        assert!(MarkerSequenceBuilder::structured(None).build(0).is_err());
+    }
+
+    #[test]
+    fn structured_sequence_matches_the_generated_signal() {
+        let dir = tempdir().unwrap();
+        let layout = SignalLayout::with_signal_id(42);
+        let signal = generate_structured_signal_with(dir.path().join("s.wav"), layout, StructuredSignalConfig::default()).unwrap();
+        let (samples, markers) = MarkerSequenceBuilder::structured(Some(42)).build(SAMPLE_RATE).unwrap();
+        let starts = |markers: &[MarkerSpec]| markers.iter().map(|m| (m.id.clone(), m.start_sample)).collect::<Vec<_>>();
+        assert_eq!(starts(&markers), starts(&signal.spec.markers));
+        assert!(samples.len() as u32 <= signal.spec.length_samples);
+    }
+
     #[test]
     fn generates_markers_and_file() {
         let dir = tempdir().unwrap();
         let path = dir.path().join("structured.wav");
         let signal = generate_structured_signal(&path).unwrap();
-        assert!(path.exists());
+        let info = validation::validate_wav_length(&path, signal.spec.length_samples).unwrap();
+        assert_eq!(info.sample_rate, SAMPLE_RATE);
+        assert_eq!(info.channels, 1);
         assert_eq!(signal.spec.sample_rate, SAMPLE_RATE);
-        assert!(signal.spec.length_samples >= ms_to_samples(4_000) as u32);
-        assert!(signal.spec.length_samples <= ms_to_samples(5_000) as u32);
+        assert!(signal.spec.length_samples >= ms_to_samples(4_000, SAMPLE_RATE) as u32);
+        assert!(signal.spec.length_samples <= ms_to_samples(5_000, SAMPLE_RATE) as u32);
         assert!(signal.spec.markers.len() >= 10);
         assert!(signal
             .spec
@@ -265,6 +823,36 @@ mod tests {
             .max()
             .unwrap();
         assert!(max_start <= signal.spec.length_samples);
+        assert!(signal.spec.conflicts().is_empty());
+    }
+
+    #[test]
+    fn default_signal_runs_for_its_target_length() {
+        let dir = tempdir().unwrap();
+        let signal = generate_structured_signal(dir.path().join("structured.wav")).unwrap();
+        assert_eq!(signal.total_duration_ms(), TARGET_LENGTH_MS as f64);
+        assert_eq!(signal.sample_at_time_ms(TARGET_LENGTH_MS as f64), signal.spec.length_samples);
+        let sweep = signal.spec.markers.iter().find(|m| m.id == "sweep_anchor").unwrap();
+        // The sweep follows the 12 ms click 20 ms after its 320 ms start.
+        assert_eq!(signal.time_at_sample(sweep.start_sample), 352.0);
+        assert_eq!(signal.sample_at_time_ms(352.0), sweep.start_sample);
+    }
+
+    #[test]
+    fn rendering_a_generated_spec_reproduces_its_samples() {
+        let dir = tempdir().unwrap();
+        let generated = generate_structured_signal(dir.path().join("generated.wav")).unwrap();
+        let rendered = render_structured_signal(dir.path().join("rendered.wav"), generated.spec.clone()).unwrap();
+        assert_eq!(rendered.spec, generated.spec);
+        let read = |path: &Path| -> Vec<i16> {
+            WavReader::open(path).unwrap().samples::<i16>().map(|s| s.unwrap()).collect()
+        };
+        assert_eq!(read(&rendered.path), read(&generated.path));
+
+        let mut overlapping = generated.spec;
+        overlapping.markers[1].start_sample = overlapping.markers[2].start_sample;
+        assert!(render_structured_signal(dir.path().join("bad.wav"), overlapping).is_err());
+        assert!(!dir.path().join("bad.wav").exists());
     }
 
     #[test]
@@ -308,4 +896,440 @@ mod tests {
             _ => panic!("sweep marker should be chirp"),
         }
     }
+
+    fn id_tones(signal: &StructuredSignal) -> Vec<u32> {
+        signal
+            .spec
+            .markers
+            .iter()
+            .filter(|m| m.id.starts_with("signal_id_"))
+            .map(|m| match m.kind {
+                MarkerKind::Chirp { start_freq, .. } => start_freq,
+                MarkerKind::Click => panic!("id marker should be a tone"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn signal_ids_produce_distinct_tone_markers() {
+        let dir = tempdir().unwrap();
+        let with_id = |name: &str, id| {
+            generate_structured_signal_with(
+                dir.path().join(name),
+                SignalLayout::with_signal_id(id),
+                StructuredSignalConfig::default(),
+            )
+        };
+        let a = with_id("a.wav", 5).unwrap();
+        let b = with_id("b.wav", 0o123).unwrap();
+        assert_eq!(a.spec.signal_id, Some(5));
+        assert_eq!(id_tones(&a), signal_id_tones(5).unwrap().to_vec());
+        assert_eq!(id_tones(&b), vec![1_336, 1_477, 1_633]);
+        assert_ne!(id_tones(&a), id_tones(&b));
+
+        let detected: Vec<f32> = id_tones(&b).iter().map(|&hz| hz as f32).collect();
+        assert_eq!(airsync_shared_protocol::decode_signal_id(&detected), Some(0o123));
+
+        // The sequence follows the sweep anchor and the layout still fits its budget.
+        let sweep = a.spec.markers.iter().position(|m| m.id == "sweep_anchor").unwrap();
+        assert_eq!(a.spec.markers[sweep + 1].id, "signal_id_1");
+        assert!(a.spec.length_samples <= ms_to_samples(5_000, SAMPLE_RATE) as u32);
+        validation::validate_wav_length(&a.path, a.spec.length_samples).unwrap();
+    }
+
+    #[test]
+    fn default_layout_has_no_signal_id() {
+        let dir = tempdir().unwrap();
+        let signal = generate_structured_signal(dir.path().join("s.wav")).unwrap();
+        assert_eq!(signal.spec.signal_id, None);
+        assert!(id_tones(&signal).is_empty());
+        assert!(generate_structured_signal_with(
+            dir.path().join("bad.wav"),
+            SignalLayout::with_signal_id(MAX_SIGNAL_ID + 1),
+            StructuredSignalConfig::default(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn marker_starts_scale_with_sample_rate() {
+        let dir = tempdir().unwrap();
+        let base = generate_structured_signal(dir.path().join("48k.wav")).unwrap();
+        for sample_rate in [44_100, 96_000] {
+            let config = StructuredSignalConfig {
+                sample_rate,
+                ..StructuredSignalConfig::default()
+            };
+            let path = dir.path().join(format!("{sample_rate}.wav"));
+            let signal = generate_structured_signal_with(&path, SignalLayout::default(), config).unwrap();
+            let info = validation::validate_wav_length(&path, signal.spec.length_samples).unwrap();
+            assert_eq!(info.sample_rate, sample_rate);
+            assert_eq!(signal.spec.sample_rate, sample_rate);
+            assert_eq!(signal.spec.length_samples as usize, ms_to_samples(TARGET_LENGTH_MS, sample_rate));
+            assert!(signal.spec.conflicts().is_empty());
+
+            let ratio = sample_rate as f64 / SAMPLE_RATE as f64;
+            assert_eq!(signal.spec.markers.len(), base.spec.markers.len());
+            for (marker, reference) in signal.spec.markers.iter().zip(&base.spec.markers) {
+                assert_eq!(marker.id, reference.id);
+                let expected = reference.start_sample as f64 * ratio;
+                // Each millisecond segment rounds down on its own, so allow a sample per marker.
+                let slack = base.spec.markers.len() as f64;
+                assert!(
+                    (marker.start_sample as f64 - expected).abs() <= slack,
+                    "{} at {sample_rate} Hz starts at {}, expected about {expected:.0}",
+                    marker.id,
+                    marker.start_sample
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn normalize_scales_the_peak() {
+        let dir = tempdir().unwrap();
+        let config = StructuredSignalConfig {
+            normalize: true,
+            ..StructuredSignalConfig::default()
+        };
+        let path = dir.path().join("normalized.wav");
+        generate_structured_signal_with(&path, SignalLayout::default(), config).unwrap();
+        let peak = WavReader::open(&path)
+            .unwrap()
+            .samples::<i16>()
+            .map(|s| (s.unwrap() as i32).abs())
+            .max()
+            .unwrap();
+        let expected = NORMALIZED_PEAK * i16::MAX as f32;
+        assert!((peak as f32 - expected).abs() <= 2.0, "peak {peak}, expected {expected}");
+    }
+
+    #[test]
+    fn default_layout_mixes_cleanly() {
+        let dir = tempdir().unwrap();
+        for layout in [SignalLayout::default(), SignalLayout::with_signal_id(7)] {
+            let (_, report) =
+                build_structured_signal(dir.path().join("clean.wav"), layout, StructuredSignalConfig::default()).unwrap();
+            assert!(report.is_clean(), "{report:?}");
+            assert_eq!(report.gain, 1.0);
+            assert!(report.peak <= 1.0);
+        }
+    }
+
+    #[test]
+    fn search_windows_fit_between_generated_markers() {
+        let dir = tempdir().unwrap();
+        let layout = SignalLayout::with_signal_id(300);
+        let signal =
+            generate_structured_signal_with(dir.path().join("windows.wav"), layout, StructuredSignalConfig::default()).unwrap();
+        for spec in [signal.spec.clone(), resample::resample_spec(&signal.spec, 44_100)] {
+            let latency_samples = (MAX_LATENCY_MS as u64 * spec.sample_rate as u64 / 1000) as u32;
+            assert_eq!(spec.max_expected_latency_ms, Some(MAX_LATENCY_MS as u32));
+            assert!(spec.suggested_decimation >= 1);
+            let anchor = spec.anchor().unwrap();
+            assert_eq!(anchor.id, "sweep_anchor");
+            let (start, end) = anchor.search_window_samples.unwrap();
+            assert_eq!(start, anchor.start_sample);
+            assert_eq!(end as u64, anchor.end_sample() + latency_samples as u64);
+
+            for pair in spec.markers.windows(2) {
+                let [a, b] = pair else { unreachable!() };
+                if a.id != anchor.id {
+                    assert!(a.search_window_samples.unwrap().1 <= b.start_sample, "{} reaches into {}", a.id, b.id);
+                }
+                if b.id != anchor.id {
+                    assert!(b.search_window_samples.unwrap().0 as u64 >= a.end_sample(), "{} reaches into {}", b.id, a.id);
+                }
+            }
+            // A decimated rate still carries the highest tone.
+            assert!(spec.sample_rate / spec.suggested_decimation > 2 * 10_000);
+        }
+        assert_eq!(signal.spec.suggested_decimation, 2);
+    }
+
+    fn read_level_db(signal: &StructuredSignal) -> (f32, f32) {
+        let samples: Vec<f32> = WavReader::open(&signal.path)
+            .unwrap()
+            .samples::<i16>()
+            .map(|s| s.unwrap() as f32 / i16::MAX as f32)
+            .collect();
+        let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let level = loudness::active_level_db(&samples, &signal.spec.markers, SAMPLE_RATE, loudness::Weighting::Flat);
+        (level.unwrap(), peak)
+    }
+
+    #[test]
+    fn target_level_sets_the_measured_rms() {
+        let dir = tempdir().unwrap();
+        let untargeted = generate_structured_signal(dir.path().join("untargeted.wav")).unwrap();
+        let reported = untargeted.spec.level_db.unwrap();
+        assert!((read_level_db(&untargeted).0 - reported).abs() < 0.1);
+
+        for target in [-30.0, -24.0, -18.0] {
+            let layout = SignalLayout {
+                target_level_db: Some(target),
+                ..SignalLayout::default()
+            };
+            let signal = generate_structured_signal_with(
+                dir.path().join(format!("target_{target}.wav")),
+                layout,
+                StructuredSignalConfig::default(),
+            )
+            .unwrap();
+            let (measured, _) = read_level_db(&signal);
+            assert!((measured - target).abs() <= 0.5, "target {target}, measured {measured}");
+            assert!((signal.spec.level_db.unwrap() - target).abs() <= 0.5);
+            // Relative levels survive: every marker was scaled by the same gain.
+            let gain = signal.spec.markers[0].amplitude / untargeted.spec.markers[0].amplitude;
+            for (scaled, before) in signal.spec.markers.iter().zip(&untargeted.spec.markers) {
+                assert!((scaled.amplitude - before.amplitude * gain).abs() < 1e-5, "{}", scaled.id);
+            }
+        }
+    }
+
+    #[test]
+    fn extreme_targets_are_clamped_instead_of_clipped() {
+        let dir = tempdir().unwrap();
+        let generate = |name: &str, target| {
+            let layout = SignalLayout {
+                target_level_db: Some(target),
+                ..SignalLayout::default()
+            };
+            generate_structured_signal_with(dir.path().join(name), layout, StructuredSignalConfig::default()).unwrap()
+        };
+        for (name, target) in [("zero.wav", 0.0), ("hot.wav", 12.0)] {
+            let signal = generate(name, target);
+            let (measured, peak) = read_level_db(&signal);
+            assert!(peak <= NORMALIZED_PEAK + 1e-4, "{name} peaks at {peak}");
+            assert!((peak - NORMALIZED_PEAK).abs() < 1e-3, "{name} peaks at {peak}");
+            assert!(measured < target - 0.5);
+            assert!((signal.spec.level_db.unwrap() - measured).abs() < 0.1);
+        }
+        let floor = generate("quiet.wav", -200.0);
+        assert!((floor.spec.level_db.unwrap() - TARGET_LEVEL_RANGE_DB.start()).abs() <= 0.5);
+    }
+
+    fn mix_click(builder: &mut SignalBuilder) {
+        builder.mix_marker(&MarkerSpec {
+            id: "click".into(),
+            kind: MarkerKind::Click,
+            start_sample: 100,
+            duration_samples: 480,
+            fade_samples: 120,
+            amplitude: 0.7,
+            search_window_samples: None,
+        });
+        builder.ensure_len(1_000);
+    }
+
+    fn click_builder() -> SignalBuilder {
+        let mut builder = SignalBuilder::new(SAMPLE_RATE);
+        mix_click(&mut builder);
+        builder
+    }
+
+    #[test]
+    fn reset_empties_a_builder_for_reuse() {
+        let mut builder = click_builder();
+        assert_eq!(builder.len(), 1_000);
+        builder.reset();
+        assert!(builder.is_empty());
+        assert_eq!(builder.samples(), &[] as &[f32]);
+        assert_eq!(builder.sample_rate(), SAMPLE_RATE);
+
+        // A reset builder mixes the same samples as a fresh one.
+        mix_click(&mut builder);
+        assert_eq!(builder.samples(), click_builder().samples());
+    }
+
+    #[test]
+    fn truncate_keeps_the_leading_samples_exactly() {
+        let full = click_builder();
+        let mut cut = full.clone();
+        cut.truncate(300);
+        assert_eq!(cut.samples(), &full.samples()[..300]);
+        cut.truncate(5_000);
+        assert_eq!(cut.len(), 300);
+    }
+
+    #[test]
+    fn append_concatenates_the_buffers() {
+        let first = click_builder();
+        let mut joined = first.clone();
+        joined.append(&first);
+        assert_eq!(joined.len(), 2 * first.len());
+        assert_eq!(&joined.samples()[..first.len()], first.samples());
+        assert_eq!(&joined.samples()[first.len()..], first.samples());
+    }
+
+    /// Two chirps at 0.8 laid on top of a click at 0.9: every region sums past full scale.
+    fn overlapping_markers() -> (SignalBuilder, Vec<MarkerSpec>) {
+        let chirp = |id: &str, start_sample: u32| MarkerSpec {
+            id: id.to_string(),
+            kind: MarkerKind::Chirp {
+                start_freq: 1_000,
+                end_freq: 1_000,
+                duration_ms: 100,
+            },
+            start_sample,
+            duration_samples: 4_800,
+            fade_samples: 240,
+            amplitude: 0.8,
+            search_window_samples: None,
+        };
+        let markers = vec![
+            chirp("low", 0),
+            MarkerSpec {
+                id: "click".to_string(),
+                kind: MarkerKind::Click,
+                start_sample: 2_400,
+                duration_samples: 48,
+                fade_samples: 0,
+                amplitude: 0.9,
+                search_window_samples: None,
+            },
+            chirp("high", 1_200),
+        ];
+        let mut builder = SignalBuilder::new(SAMPLE_RATE);
+        builder.ensure_len(9_600);
+        for marker in &markers {
+            builder.mix_marker(marker);
+        }
+        (builder, markers)
+    }
+
+    #[test]
+    fn overlapping_markers_are_scaled_under_full_scale() {
+        let (mut builder, mut markers) = overlapping_markers();
+        let original = markers.clone();
+        let report = builder.settle_headroom(&mut markers, false).unwrap();
+        let clipped: Vec<&str> = report.clipped.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(clipped, ["low", "click", "high"]);
+        assert!(report.clipped.iter().all(|(_, peak)| *peak > 1.0 && *peak <= report.peak));
+        assert!((report.gain - NORMALIZED_PEAK / report.peak).abs() < 1e-6);
+
+        let peak = builder.peak();
+        assert!((peak - NORMALIZED_PEAK).abs() < 1e-4, "peak {peak}");
+        for (scaled, before) in markers.iter().zip(&original) {
+            assert!((scaled.amplitude - before.amplitude * report.gain).abs() < 1e-6);
+        }
+        assert!((markers[1].amplitude / markers[0].amplitude - 0.9 / 0.8).abs() < 1e-5);
+        assert!(builder.mix_report(&markers).is_clean());
+    }
+
+    #[test]
+    fn strict_headroom_rejects_overlapping_markers() {
+        let (mut builder, mut markers) = overlapping_markers();
+        let original = markers.clone();
+        let err = builder.settle_headroom(&mut markers, true).unwrap_err();
+        match err.downcast_ref::<SignalMixError>() {
+            Some(SignalMixError::Clipped { markers: ids, peak }) => {
+                assert_eq!(ids, &["low", "click", "high"]);
+                assert!(*peak > 1.0);
+            }
+            None => panic!("unexpected error {err:#}"),
+        }
+        assert_eq!(markers, original);
+    }
+
+    #[test]
+    fn rendering_a_loud_spec_scales_its_amplitudes() {
+        let dir = tempdir().unwrap();
+        let mut spec = generate_structured_signal(dir.path().join("generated.wav")).unwrap().spec;
+        spec.markers[0].amplitude = 1.5;
+        let quiet = spec.markers[1].amplitude;
+        let rendered = render_structured_signal(dir.path().join("loud.wav"), spec).unwrap();
+        let gain = rendered.spec.markers[0].amplitude / 1.5;
+        assert!(gain < 1.0);
+        assert!((rendered.spec.markers[1].amplitude - quiet * gain).abs() < 1e-6);
+        let peak = WavReader::open(&rendered.path)
+            .unwrap()
+            .samples::<i16>()
+            .map(|s| (s.unwrap() as i32).abs())
+            .max()
+            .unwrap();
+        assert!((peak as f32 - NORMALIZED_PEAK * i16::MAX as f32).abs() <= 2.0, "peak {peak}");
+    }
+
+    #[test]
+    fn every_sample_format_reads_back_aligned() {
+        let dir = tempdir().unwrap();
+        let render = |format: SampleFormat| {
+            let config = StructuredSignalConfig {
+                sample_format: format,
+                ..StructuredSignalConfig::default()
+            };
+            let path = dir.path().join(format!("structured_{format:?}.wav"));
+            generate_structured_signal_with(&path, SignalLayout::default(), config).unwrap()
+        };
+        let i16_signal = render(SampleFormat::I16);
+        let i24_signal = render(SampleFormat::I24);
+        let f32_signal = render(SampleFormat::F32);
+        assert_eq!(i24_signal.spec.sample_format, SampleFormat::I24);
+        assert_eq!(serde_json::to_value(&f32_signal.spec).unwrap()["sample_format"], "f32");
+
+        let expected = [
+            (&i16_signal, 16, hound::SampleFormat::Int),
+            (&i24_signal, 24, hound::SampleFormat::Int),
+            (&f32_signal, 32, hound::SampleFormat::Float),
+        ];
+        for (signal, bits, sample_format) in expected {
+            let reader = WavReader::open(&signal.path).unwrap();
+            assert_eq!(reader.spec().bits_per_sample, bits);
+            assert_eq!(reader.spec().sample_format, sample_format);
+            assert_eq!(reader.len(), signal.spec.length_samples);
+        }
+
+        let i16_samples: Vec<i32> = WavReader::open(&i16_signal.path)
+            .unwrap()
+            .samples::<i16>()
+            .map(|s| s.unwrap() as i32)
+            .collect();
+        let i24_samples: Vec<i32> = WavReader::open(&i24_signal.path)
+            .unwrap()
+            .samples::<i32>()
+            .map(|s| s.unwrap())
+            .collect();
+        let first_loud = |samples: &[i32], threshold: i32| samples.iter().position(|s| s.abs() > threshold).unwrap();
+        assert_eq!(first_loud(&i16_samples, 10_000), first_loud(&i24_samples, 10_000 << 8));
+        for (a, b) in i16_samples.iter().zip(&i24_samples) {
+            assert!((a - b / 256).abs() <= 1, "16-bit {a} vs 24-bit {b}");
+        }
+    }
+
+    #[test]
+    fn references_correlate_at_marker_starts() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("structured.wav");
+        let signal = generate_structured_signal(&path).unwrap();
+        let mut reader = WavReader::open(&path).unwrap();
+        let samples: Vec<f32> = reader
+            .samples::<i16>()
+            .map(|s| s.unwrap() as f32 / i16::MAX as f32)
+            .collect();
+
+        for marker in &signal.spec.markers {
+            let reference = reference_samples(marker, SAMPLE_RATE);
+            let ref_energy = reference.iter().map(|r| r * r).sum::<f32>().sqrt();
+            let start = marker.start_sample as i64;
+            let last = samples.len() as i64 - reference.len() as i64;
+            let peak = (start - 500..=start + 500)
+                .filter(|&at| at >= 0 && at <= last)
+                .map(|at| {
+                    let window = &samples[at as usize..at as usize + reference.len()];
+                    let dot: f32 = window.iter().zip(&reference).map(|(s, r)| s * r).sum();
+                    let energy = window.iter().map(|s| s * s).sum::<f32>().sqrt();
+                    (at, dot / (energy * ref_energy).max(f32::EPSILON))
+                })
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .unwrap();
+            assert_eq!(peak.0, start, "{} correlates best at {}", marker.id, peak.0);
+        }
+    }
+
+    #[test]
+    fn receiver_signal_ids_are_stable_and_in_range() {
+        assert_eq!(signal_id_for_receiver("rx-1"), signal_id_for_receiver("rx-1"));
+        assert!(signal_id_for_receiver("d0c5a9f3-receiver") <= MAX_SIGNAL_ID);
+    }
 }
@@ -0,0 +1,190 @@
+//! Hashes identifying a rendered calibration signal. Phones cache the WAV and spec
+//! against `content_hash`; the receiver keeps a sidecar next to the generated WAV so a
+//! restart with the same layout can reuse it instead of rendering it again.
+
+use super::{SignalLayout, StructuredSignal, StructuredSignalConfig};
+use airsync_shared_protocol::{CalibrationSignalSpec, SampleFormat};
+use anyhow::{Context, Result};
+use hound::WavReader;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+/// Part of every layout hash. Bump it when a change to the generator alters the samples
+/// it renders for an unchanged layout, so receivers render the signal again.
+pub const SIGNAL_RENDER_REVISION: u32 = 3;
+
+/// Written next to a generated WAV as `<name>.json`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignalSidecar {
+    pub layout_hash: String,
+    pub spec: CalibrationSignalSpec,
+}
+
+/// What goes into a layout hash: everything the generator renders from.
+#[derive(Serialize)]
+struct LayoutDescriptor {
+    revision: u32,
+    signal_id: Option<u16>,
+    target_level_db: Option<f32>,
+    sample_rate: u32,
+    normalize: bool,
+    target_length_ms: u32,
+    sample_format: SampleFormat,
+}
+
+/// Hash of the inputs to `generate_structured_signal_with`, known before rendering.
+pub fn layout_hash(layout: &SignalLayout, config: &StructuredSignalConfig) -> String {
+    let descriptor = LayoutDescriptor {
+        revision: SIGNAL_RENDER_REVISION,
+        signal_id: layout.signal_id,
+        target_level_db: layout.target_level_db,
+        sample_rate: config.sample_rate,
+        normalize: config.normalize,
+        target_length_ms: config.target_length_ms,
+        sample_format: config.sample_format,
+    };
+    let json = serde_json::to_vec(&descriptor).expect("layout descriptor serializes");
+    hex(Sha256::digest(json).as_slice())
+}
+
+/// Hash of `spec`, minus its own `content_hash`, followed by the samples of the WAV at
+/// `path` as little-endian values of the file's sample type.
+pub fn content_hash(spec: &CalibrationSignalSpec, path: &Path) -> Result<String> {
+    let spec = CalibrationSignalSpec {
+        content_hash: None,
+        ..spec.clone()
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_vec(&spec)?);
+    let mut reader = WavReader::open(path).with_context(|| format!("opening {}", path.display()))?;
+    match reader.spec().sample_format {
+        hound::SampleFormat::Int => {
+            for sample in reader.samples::<i32>() {
+                hasher.update(sample?.to_le_bytes());
+            }
+        }
+        hound::SampleFormat::Float => {
+            for sample in reader.samples::<f32>() {
+                hasher.update(sample?.to_le_bytes());
+            }
+        }
+    }
+    Ok(hex(hasher.finalize().as_slice()))
+}
+
+/// Hash for a signal derived from one hashed as `source`, e.g. resampled to `rate`.
+pub fn derived_hash(source: &str, rate: u32) -> String {
+    hex(Sha256::digest(format!("{source}@{rate}")).as_slice())
+}
+
+pub fn sidecar_path(wav: &Path) -> PathBuf {
+    wav.with_extension("json")
+}
+
+pub fn write_sidecar(wav: &Path, sidecar: &SignalSidecar) -> Result<()> {
+    let path = sidecar_path(wav);
+    std::fs::write(&path, serde_json::to_vec_pretty(sidecar)?).with_context(|| format!("writing {}", path.display()))
+}
+
+/// The signal an earlier run rendered at `wav`, when its sidecar was written for
+/// `layout_hash` and the file still hashes to what the sidecar recorded.
+pub fn cached_signal(wav: &Path, layout_hash: &str) -> Option<StructuredSignal> {
+    let bytes = std::fs::read(sidecar_path(wav)).ok()?;
+    let sidecar: SignalSidecar = serde_json::from_slice(&bytes).ok()?;
+    if sidecar.layout_hash != layout_hash {
+        return None;
+    }
+    let recorded = sidecar.spec.content_hash.as_deref()?;
+    if content_hash(&sidecar.spec, wav).ok()? != recorded {
+        return None;
+    }
+    Some(StructuredSignal {
+        spec: sidecar.spec,
+        path: wav.to_path_buf(),
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::signal::{generate_structured_signal_with, load_or_generate_structured_signal};
+    use tempfile::tempdir;
+
+    fn generate(dir: &Path, name: &str, layout: SignalLayout, config: StructuredSignalConfig) -> StructuredSignal {
+        generate_structured_signal_with(dir.join(name), layout, config).unwrap()
+    }
+
+    #[test]
+    fn same_layout_renders_the_same_hash() {
+        let dir = tempdir().unwrap();
+        let layout = SignalLayout::with_signal_id(9);
+        let a = generate(dir.path(), "a.wav", layout, StructuredSignalConfig::default());
+        let b = generate(dir.path(), "b.wav", layout, StructuredSignalConfig::default());
+        let hash = a.spec.content_hash.clone().unwrap();
+        assert_eq!(hash.len(), 64);
+        assert_eq!(b.spec.content_hash, Some(hash.clone()));
+        assert_eq!(content_hash(&a.spec, &a.path).unwrap(), hash);
+        assert_eq!(
+            layout_hash(&layout, &StructuredSignalConfig::default()),
+            layout_hash(&layout, &StructuredSignalConfig::default())
+        );
+    }
+
+    #[test]
+    fn layout_changes_change_both_hashes() {
+        let dir = tempdir().unwrap();
+        let config = StructuredSignalConfig::default();
+        let base = generate(dir.path(), "base.wav", SignalLayout::default(), config);
+        let variants = [
+            (SignalLayout::with_signal_id(3), config),
+            (
+                SignalLayout::default(),
+                StructuredSignalConfig {
+                    sample_rate: 44_100,
+                    ..config
+                },
+            ),
+            (
+                SignalLayout::default(),
+                StructuredSignalConfig {
+                    normalize: true,
+                    ..config
+                },
+            ),
+        ];
+        for (i, (layout, variant)) in variants.into_iter().enumerate() {
+            assert_ne!(layout_hash(&layout, &variant), layout_hash(&SignalLayout::default(), &config));
+            let changed = generate(dir.path(), &format!("variant_{i}.wav"), layout, variant);
+            assert_ne!(changed.spec.content_hash, base.spec.content_hash, "variant {i}");
+        }
+    }
+
+    #[test]
+    fn startup_reuses_a_matching_signal_and_renders_a_changed_one() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("structured.wav");
+        let layout = SignalLayout::with_signal_id(1);
+        let config = StructuredSignalConfig::default();
+        let first = load_or_generate_structured_signal(&path, layout, config).unwrap();
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+
+        let reused = load_or_generate_structured_signal(&path, layout, config).unwrap();
+        assert_eq!(reused.spec, first.spec);
+        assert_eq!(std::fs::metadata(&path).unwrap().modified().unwrap(), modified);
+
+        let relaid = load_or_generate_structured_signal(&path, SignalLayout::with_signal_id(2), config).unwrap();
+        assert_ne!(relaid.spec.content_hash, first.spec.content_hash);
+        assert_eq!(relaid.spec.signal_id, Some(2));
+
+        // A WAV that no longer matches its sidecar is rendered again.
+        std::fs::write(&path, b"not a wav").unwrap();
+        let repaired = load_or_generate_structured_signal(&path, SignalLayout::with_signal_id(2), config).unwrap();
+        assert_eq!(repaired.spec, relaid.spec);
+        assert_eq!(content_hash(&repaired.spec, &path).unwrap(), repaired.spec.content_hash.unwrap());
+    }
+}
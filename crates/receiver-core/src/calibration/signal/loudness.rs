@@ -0,0 +1,184 @@
+//! Loudness of a rendered signal, measured over the samples its markers cover so the
+//! silence between them doesn't dilute it. Levels are in dB relative to full scale; the
+//! K-weighted level follows BS.1770's filter and offset, so it reads like LUFS without
+//! the gating.
+
+use airsync_shared_protocol::MarkerSpec;
+use std::f64::consts::PI;
+
+/// Levels and gains below this are treated as silence.
+pub const SILENCE_DB: f32 = -120.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Weighting {
+    /// Plain RMS.
+    #[default]
+    Flat,
+    /// BS.1770 K-weighting: a high shelf around 1.5 kHz over a 38 Hz high-pass.
+    K,
+}
+
+/// Level of `samples` over the regions `markers` cover, or `None` when they cover none.
+pub fn active_level_db(samples: &[f32], markers: &[MarkerSpec], sample_rate: u32, weighting: Weighting) -> Option<f32> {
+    let weighted;
+    let samples = match weighting {
+        Weighting::Flat => samples,
+        Weighting::K => {
+            weighted = k_weight(samples, sample_rate);
+            &weighted
+        }
+    };
+    let mut active = vec![false; samples.len()];
+    for marker in markers {
+        let start = (marker.start_sample as usize).min(samples.len());
+        let end = (marker.end_sample() as usize).min(samples.len());
+        active[start..end].fill(true);
+    }
+    let (sum, count) = samples
+        .iter()
+        .zip(&active)
+        .filter(|(_, active)| **active)
+        .fold((0.0f64, 0usize), |(sum, count), (s, _)| (sum + (*s as f64).powi(2), count + 1));
+    if count == 0 {
+        return None;
+    }
+    let offset = match weighting {
+        Weighting::Flat => 0.0,
+        Weighting::K => -0.691,
+    };
+    let level = offset + 10.0 * (sum / count as f64).log10();
+    Some((level as f32).max(SILENCE_DB))
+}
+
+/// Linear gain as dB; silence is [`SILENCE_DB`].
+pub fn gain_to_db(gain: f32) -> f32 {
+    if gain <= 0.0 {
+        SILENCE_DB
+    } else {
+        (20.0 * gain.log10()).max(SILENCE_DB)
+    }
+}
+
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// `samples` through the two BS.1770 pre-filter stages, designed for `sample_rate`.
+fn k_weight(samples: &[f32], sample_rate: u32) -> Vec<f32> {
+    let rate = sample_rate as f64;
+    let shelf = Biquad::high_shelf(1_500.0, 4.0, std::f64::consts::FRAC_1_SQRT_2, rate);
+    let high_pass = Biquad::high_pass(38.0, 0.5, rate);
+    high_pass.filter(&shelf.filter(samples))
+}
+
+/// Direct form I biquad with `a0` normalized to 1.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+}
+
+impl Biquad {
+    fn normalized(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b: b.map(|b| b / a[0]),
+            a: [a[1] / a[0], a[2] / a[0]],
+        }
+    }
+
+    fn high_shelf(freq: f64, gain_db: f64, q: f64, rate: f64) -> Self {
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * freq / rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
+        let root = 2.0 * a.sqrt() * alpha;
+        Self::normalized(
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos + root),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - root),
+            ],
+            [
+                (a + 1.0) - (a - 1.0) * cos + root,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - root,
+            ],
+        )
+    }
+
+    fn high_pass(freq: f64, q: f64, rate: f64) -> Self {
+        let w0 = 2.0 * PI * freq / rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
+        Self::normalized(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    fn filter(&self, samples: &[f32]) -> Vec<f32> {
+        let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+        samples
+            .iter()
+            .map(|&s| {
+                let x = s as f64;
+                let y = self.b[0] * x + self.b[1] * x1 + self.b[2] * x2 - self.a[0] * y1 - self.a[1] * y2;
+                (x2, x1, y2, y1) = (x1, x, y1, y);
+                y as f32
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use airsync_shared_protocol::MarkerKind;
+
+    fn tone(freq: f32, amplitude: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| amplitude * (2.0 * std::f32::consts::PI * freq * i as f32 / 48_000.0).sin())
+            .collect()
+    }
+
+    fn marker(start_sample: u32, duration_samples: u32) -> MarkerSpec {
+        MarkerSpec {
+            id: format!("m{start_sample}"),
+            kind: MarkerKind::Click,
+            start_sample,
+            duration_samples,
+            fade_samples: 0,
+            amplitude: 1.0,
+            search_window_samples: None,
+        }
+    }
+
+    #[test]
+    fn flat_level_only_counts_marker_regions() {
+        let mut samples = tone(1_000.0, 0.5, 48_000);
+        samples[24_000..].fill(0.0);
+        let level = active_level_db(&samples, &[marker(0, 24_000)], 48_000, Weighting::Flat).unwrap();
+        // A sine's RMS is its peak over √2.
+        assert!((level - gain_to_db(0.5 / 2f32.sqrt())).abs() < 0.01, "{level}");
+        let diluted = active_level_db(&samples, &[marker(0, 48_000)], 48_000, Weighting::Flat).unwrap();
+        assert!((level - diluted - 3.01).abs() < 0.05, "{diluted}");
+        assert_eq!(active_level_db(&samples, &[], 48_000, Weighting::Flat), None);
+    }
+
+    #[test]
+    fn k_weighting_favours_presence_over_rumble() {
+        let markers = [marker(4_800, 43_200)];
+        let level = |freq| active_level_db(&tone(freq, 0.5, 48_000), &markers, 48_000, Weighting::K).unwrap();
+        let flat = active_level_db(&tone(1_000.0, 0.5, 48_000), &markers, 48_000, Weighting::Flat).unwrap();
+        // BS.1770 calibrates a 1 kHz tone to read within a few tenths of a dB of its RMS.
+        assert!((level(1_000.0) - flat).abs() < 0.5, "{} vs {flat}", level(1_000.0));
+        assert!(level(4_000.0) > level(1_000.0) + 2.0);
+        assert!(level(20.0) < level(1_000.0) - 5.0);
+    }
+
+    #[test]
+    fn gains_round_trip_through_db() {
+        assert!((gain_to_db(0.5) + 6.02).abs() < 0.01);
+        assert!((db_to_gain(gain_to_db(0.7)) - 0.7).abs() < 1e-6);
+        assert_eq!(gain_to_db(0.0), SILENCE_DB);
+    }
+}
@@ -0,0 +1,172 @@
+//! Converting the pregenerated signals for outputs that can't play them at their rendered
+//! rate. Letting ALSA's plug layer convert instead shifts marker timing by an amount the
+//! receiver can't see, so the conversion and the spec describing it happen here.
+
+use super::hash::derived_hash;
+use airsync_shared_protocol::{CalibrationSignalSpec, MarkerSpec};
+use anyhow::{Context, Result};
+use hound::{WavReader, WavWriter};
+use std::path::Path;
+
+/// Linearly interpolate `samples` from `from_rate` to `to_rate`. Sample `i` of the output
+/// lands at the same time as position `i * from_rate / to_rate` of the input.
+pub fn resample_linear(samples: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    if from_rate == to_rate || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let out_len = convert_position(samples.len() as u64, from_rate, to_rate) as usize;
+    let step = from_rate as f64 / to_rate as f64;
+    (0..out_len)
+        .map(|i| {
+            let pos = i as f64 * step;
+            let index = pos.floor() as usize;
+            let frac = (pos - index as f64) as f32;
+            let a = samples[index.min(samples.len() - 1)];
+            let b = samples[(index + 1).min(samples.len() - 1)];
+            a + (b - a) * frac
+        })
+        .collect()
+}
+
+/// The spec of `spec`'s signal after resampling it to `to_rate`: marker boundaries are
+/// moved to the nearest output sample, so every marker keeps its position in milliseconds
+/// to within half a sample and markers that didn't overlap still don't. The content hash
+/// is derived from the original's and the new rate, and search hints are worked out again
+/// at the new rate.
+pub fn resample_spec(spec: &CalibrationSignalSpec, to_rate: u32) -> CalibrationSignalSpec {
+    let from_rate = spec.sample_rate;
+    let at = |sample: u64| convert_position(sample, from_rate, to_rate);
+    let markers = spec
+        .markers
+        .iter()
+        .map(|marker| {
+            let start = at(marker.start_sample as u64);
+            MarkerSpec {
+                start_sample: start as u32,
+                duration_samples: (at(marker.end_sample()) - start) as u32,
+                fade_samples: at(marker.fade_samples as u64) as u32,
+                ..marker.clone()
+            }
+        })
+        .collect();
+    let mut converted = CalibrationSignalSpec {
+        sample_rate: to_rate,
+        sample_format: spec.sample_format,
+        length_samples: at(spec.length_samples as u64) as u32,
+        markers,
+        signal_id: spec.signal_id,
+        content_hash: spec.content_hash.as_deref().map(|hash| derived_hash(hash, to_rate)),
+        // Interpolating the markers' tones leaves their level where it was.
+        level_db: spec.level_db,
+        suggested_decimation: spec.suggested_decimation,
+        max_expected_latency_ms: spec.max_expected_latency_ms,
+    };
+    if let Some(latency) = spec.max_expected_latency_ms {
+        converted.annotate_search_hints(latency);
+    }
+    converted
+}
+
+/// Write a copy of the 16-bit WAV at `src` to `dst`, resampled to `to_rate` and scaled by
+/// `gain`.
+pub fn resample_wav(src: &Path, dst: &Path, to_rate: u32, gain: f32) -> Result<()> {
+    let mut reader = WavReader::open(src).with_context(|| format!("opening {}", src.display()))?;
+    let spec = reader.spec();
+    let channels = spec.channels.max(1) as usize;
+    let samples = reader.samples::<i16>().collect::<Result<Vec<i16>, _>>()?;
+    let resampled: Vec<Vec<f32>> = (0..channels)
+        .map(|channel| {
+            let mono: Vec<f32> = samples.iter().skip(channel).step_by(channels).map(|&s| s as f32).collect();
+            resample_linear(&mono, spec.sample_rate, to_rate)
+        })
+        .collect();
+    let mut writer = WavWriter::create(
+        dst,
+        hound::WavSpec {
+            sample_rate: to_rate,
+            ..spec
+        },
+    )?;
+    for frame in 0..resampled[0].len() {
+        for channel in &resampled {
+            let sample = (channel[frame] * gain).round().clamp(i16::MIN as f32, i16::MAX as f32);
+            writer.write_sample(sample as i16)?;
+        }
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+fn convert_position(sample: u64, from_rate: u32, to_rate: u32) -> u64 {
+    (sample * to_rate as u64 + from_rate as u64 / 2) / from_rate as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::signal::{generate_structured_signal_with, SignalLayout, StructuredSignalConfig};
+    use tempfile::tempdir;
+
+    fn ms(sample: u64, rate: u32) -> f64 {
+        sample as f64 * 1000.0 / rate as f64
+    }
+
+    #[test]
+    fn resampled_spec_keeps_marker_times_within_a_sample() {
+        let dir = tempdir().unwrap();
+        let signal = generate_structured_signal_with(
+            dir.path().join("structured.wav"),
+            SignalLayout::with_signal_id(5),
+            StructuredSignalConfig::default(),
+        )
+        .unwrap();
+        let converted = resample_spec(&signal.spec, 44_100);
+        assert_eq!(converted.sample_rate, 44_100);
+        converted.validate().unwrap();
+
+        let one_sample_ms = 1000.0 / 44_100.0;
+        for (before, after) in signal.spec.markers.iter().zip(&converted.markers) {
+            assert_eq!(before.id, after.id);
+            let start_error = ms(after.start_sample as u64, 44_100) - ms(before.start_sample as u64, 48_000);
+            let end_error = ms(after.end_sample(), 44_100) - ms(before.end_sample(), 48_000);
+            assert!(start_error.abs() <= one_sample_ms, "{} start off by {start_error}ms", before.id);
+            assert!(end_error.abs() <= one_sample_ms, "{} end off by {end_error}ms", before.id);
+        }
+        let length_error = ms(converted.length_samples as u64, 44_100) - ms(signal.spec.length_samples as u64, 48_000);
+        assert!(length_error.abs() <= one_sample_ms);
+    }
+
+    #[test]
+    fn resampled_wav_matches_its_spec() {
+        let dir = tempdir().unwrap();
+        let signal = generate_structured_signal_with(
+            dir.path().join("structured.wav"),
+            SignalLayout::default(),
+            StructuredSignalConfig::default(),
+        )
+        .unwrap();
+        let converted = dir.path().join("structured_44100.wav");
+        resample_wav(&signal.path, &converted, 44_100, 1.0).unwrap();
+
+        let mut reader = WavReader::open(&converted).unwrap();
+        assert_eq!(reader.spec().sample_rate, 44_100);
+        let samples: Vec<i16> = reader.samples::<i16>().map(Result::unwrap).collect();
+        let spec = resample_spec(&signal.spec, 44_100);
+        assert_eq!(samples.len(), spec.length_samples as usize);
+
+        // The leading click is the first loud sample in both renderings.
+        let first_loud = |samples: &[i16]| samples.iter().position(|s| s.unsigned_abs() > 10_000).unwrap();
+        let original: Vec<i16> = WavReader::open(&signal.path).unwrap().samples::<i16>().map(Result::unwrap).collect();
+        let drift_ms = ms(first_loud(&samples) as u64, 44_100) - ms(first_loud(&original) as u64, 48_000);
+        assert!(drift_ms.abs() <= 2.0 * 1000.0 / 44_100.0, "click moved {drift_ms}ms");
+    }
+
+    #[test]
+    fn linear_resampling_interpolates_between_samples() {
+        let ramp: Vec<f32> = (0..8).map(|i| i as f32).collect();
+        let doubled = resample_linear(&ramp, 1, 2);
+        assert_eq!(doubled.len(), 16);
+        assert_eq!(&doubled[..5], &[0.0, 0.5, 1.0, 1.5, 2.0]);
+        assert_eq!(resample_linear(&ramp, 48_000, 48_000), ramp);
+    }
+}
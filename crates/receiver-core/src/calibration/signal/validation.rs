@@ -0,0 +1,127 @@
+use anyhow::{bail, Context, Result};
+use hound::{SampleFormat, WavReader};
+use std::path::Path;
+
+/// Header facts of a WAV file that passed [`validate_wav`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WavInfo {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: u16,
+    /// Samples per channel (frames).
+    pub num_samples: u32,
+    pub duration_ms: u64,
+}
+
+/// Check that `path` exists, has a well-formed header, and that its data chunk holds
+/// exactly the number of samples the header declares (catching truncated writes).
+pub fn validate_wav(path: &Path) -> Result<WavInfo> {
+    if !path.is_file() {
+        bail!("{} does not exist", path.display());
+    }
+    let mut reader =
+        WavReader::open(path).with_context(|| format!("{} has a malformed WAV header", path.display()))?;
+    let spec = reader.spec();
+    if spec.channels == 0 || spec.sample_rate == 0 {
+        bail!(
+            "{} declares {} channels at {}Hz",
+            path.display(),
+            spec.channels,
+            spec.sample_rate
+        );
+    }
+    let declared = reader.len();
+    let readable = match spec.sample_format {
+        SampleFormat::Int => reader.samples::<i32>().take_while(|s| s.is_ok()).count(),
+        SampleFormat::Float => reader.samples::<f32>().take_while(|s| s.is_ok()).count(),
+    } as u32;
+    if readable != declared {
+        bail!(
+            "{} declares {declared} samples but only {readable} are readable",
+            path.display()
+        );
+    }
+    let num_samples = declared / spec.channels as u32;
+    Ok(WavInfo {
+        sample_rate: spec.sample_rate,
+        channels: spec.channels,
+        bits_per_sample: spec.bits_per_sample,
+        num_samples,
+        duration_ms: num_samples as u64 * 1000 / spec.sample_rate as u64,
+    })
+}
+
+/// [`validate_wav`], additionally requiring `expected_samples` samples per channel.
+pub fn validate_wav_length(path: &Path, expected_samples: u32) -> Result<WavInfo> {
+    let info = validate_wav(path)?;
+    if info.num_samples != expected_samples {
+        bail!(
+            "{} holds {} samples, expected {expected_samples}",
+            path.display(),
+            info.num_samples
+        );
+    }
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    fn write_wav(path: &Path, channels: u16, frames: u32) {
+        let spec = hound::WavSpec {
+            channels,
+            sample_rate: 48_000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for i in 0..frames * channels as u32 {
+            writer.write_sample((i % 100) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    #[test]
+    fn reports_header_facts() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("ok.wav");
+        write_wav(&path, 2, 24_000);
+        let info = validate_wav(&path).unwrap();
+        assert_eq!(
+            info,
+            WavInfo {
+                sample_rate: 48_000,
+                channels: 2,
+                bits_per_sample: 16,
+                num_samples: 24_000,
+                duration_ms: 500,
+            }
+        );
+        assert!(validate_wav_length(&path, 24_000).is_ok());
+        assert!(validate_wav_length(&path, 24_001).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_and_garbage_files() {
+        let dir = tempdir().unwrap();
+        assert!(validate_wav(&dir.path().join("missing.wav")).is_err());
+        let garbage = dir.path().join("garbage.wav");
+        std::fs::write(&garbage, b"definitely not RIFF").unwrap();
+        assert!(validate_wav(&garbage).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_data_chunk() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("short.wav");
+        write_wav(&path, 1, 1_000);
+        let bytes = std::fs::read(&path).unwrap();
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(&bytes[..bytes.len() - 200]).unwrap();
+        drop(file);
+        assert!(validate_wav(&path).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+use crate::airplay::ShairportConfig;
+use airsync_shared_protocol::OutputDeviceSpec;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// File inside the receiver state dir holding the calibration in effect.
+pub const CALIBRATION_STATE_FILE: &str = "calibration.json";
+/// File inside the receiver state dir holding the calibrations applied before it, oldest first.
+pub const CALIBRATION_HISTORY_FILE: &str = "calibration_history.json";
+/// How many applied calibrations the history keeps.
+pub const CALIBRATION_HISTORY_LEN: usize = 20;
+
+/// Where the offset in effect came from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CalibrationSource {
+    /// Measured by the phone app.
+    #[default]
+    Phone,
+    /// Measured by the receiver through its own microphone.
+    #[serde(rename = "selfcal")]
+    SelfCal,
+    /// Set directly through `/api/settings`.
+    Manual,
+}
+
+/// The calibration result currently in effect on this receiver.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AppliedCalibration {
+    pub timestamp: u64,
+    pub measured_latency_ms: f32,
+    pub applied_offset_ms: f32,
+    pub confidence: f32,
+    /// Output the offset was applied to.
+    #[serde(default)]
+    pub output_device: OutputDeviceSpec,
+    #[serde(default)]
+    pub source: CalibrationSource,
+    /// Settings generation in effect when applied; later settings changes restarted shairport-sync.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_generation: Option<u64>,
+    /// SoC temperature when applied, where the board reports one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature_c: Option<f32>,
+}
+
+/// An applied calibration together with the shairport-sync config it was applied with,
+/// enough to put the receiver back in that state without measuring again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    #[serde(flatten)]
+    pub applied: AppliedCalibration,
+    pub config_snapshot: ShairportConfig,
+}
+
+/// The applied calibration shared by the handlers, persisted as JSON when backed by a
+/// path so it survives a reboot, along with the last [`CALIBRATION_HISTORY_LEN`] applied.
+#[derive(Clone)]
+pub struct CalibrationStore {
+    path: Option<PathBuf>,
+    current: Arc<Mutex<Option<AppliedCalibration>>>,
+    history: Arc<Mutex<Vec<HistoryEntry>>>,
+}
+
+impl CalibrationStore {
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            current: Arc::new(Mutex::new(None)),
+            history: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Load `<state_dir>/calibration.json`, starting uncalibrated if it doesn't exist yet.
+    /// An unreadable history starts empty instead; it is only needed for replays.
+    pub fn open(state_dir: &Path) -> Result<Self> {
+        let path = state_dir.join(CALIBRATION_STATE_FILE);
+        let current = match std::fs::read(&path) {
+            Ok(bytes) => {
+                Some(serde_json::from_slice(&bytes).with_context(|| format!("parsing {}", path.display()))?)
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err).with_context(|| format!("reading {}", path.display())),
+        };
+        let history = read_history(&state_dir.join(CALIBRATION_HISTORY_FILE)).unwrap_or_else(|e| {
+            eprintln!("[calibration] ignoring calibration history: {e:#}");
+            Vec::new()
+        });
+        Ok(Self {
+            path: Some(path),
+            current: Arc::new(Mutex::new(current)),
+            history: Arc::new(Mutex::new(history)),
+        })
+    }
+
+    pub fn current(&self) -> Option<AppliedCalibration> {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Calibrations applied so far, oldest first.
+    pub fn history(&self) -> Vec<HistoryEntry> {
+        self.history.lock().unwrap().clone()
+    }
+
+    /// Timestamp of the newest history entry; `None` while the history is empty.
+    pub fn last_calibrated_at(&self) -> Option<u64> {
+        self.history.lock().unwrap().last().map(|entry| entry.applied.timestamp)
+    }
+
+    /// Make `applied` the calibration in effect and append it to the history with
+    /// `config_snapshot`. Both are kept in memory even when writing the files fails, since
+    /// the offset has already been applied.
+    pub fn record(&self, applied: AppliedCalibration, config_snapshot: ShairportConfig) -> Result<()> {
+        let mut current = self.current.lock().unwrap();
+        let mut history = self.history.lock().unwrap();
+        *current = Some(applied.clone());
+        history.push(HistoryEntry {
+            applied,
+            config_snapshot,
+        });
+        let excess = history.len().saturating_sub(CALIBRATION_HISTORY_LEN);
+        history.drain(..excess);
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_vec_pretty(&*current)?)
+            .with_context(|| format!("writing {}", path.display()))?;
+        let history_path = path.with_file_name(CALIBRATION_HISTORY_FILE);
+        std::fs::write(&history_path, serde_json::to_vec_pretty(&*history)?)
+            .with_context(|| format!("writing {}", history_path.display()))
+    }
+}
+
+fn read_history(path: &Path) -> Result<Vec<HistoryEntry>> {
+    match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).with_context(|| format!("parsing {}", path.display())),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err).with_context(|| format!("reading {}", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::airplay::generate_config;
+    use airsync_shared_protocol::AudioOutput;
+    use tempfile::tempdir;
+
+    fn applied(timestamp: u64, source: CalibrationSource) -> AppliedCalibration {
+        AppliedCalibration {
+            timestamp,
+            measured_latency_ms: 42.0,
+            applied_offset_ms: -42.0,
+            confidence: 0.9,
+            output_device: OutputDeviceSpec::default(),
+            source,
+            config_generation: Some(3),
+            temperature_c: None,
+        }
+    }
+
+    #[test]
+    fn recorded_calibration_survives_reopening() {
+        let dir = tempdir().unwrap();
+        let store = CalibrationStore::open(dir.path()).unwrap();
+        assert_eq!(store.current(), None);
+
+        let config = generate_config(Some("Kitchen"), AudioOutput::Headphone);
+        store.record(applied(1_000, CalibrationSource::Phone), config.clone()).unwrap();
+        store.record(applied(2_000, CalibrationSource::Manual), config).unwrap();
+
+        let reopened = CalibrationStore::open(dir.path()).unwrap();
+        assert_eq!(reopened.current(), Some(applied(2_000, CalibrationSource::Manual)));
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(dir.path().join(CALIBRATION_STATE_FILE)).unwrap()).unwrap();
+        assert_eq!(json["source"], "manual");
+    }
+
+    #[test]
+    fn history_keeps_the_latest_entries_with_their_configs() {
+        let dir = tempdir().unwrap();
+        let store = CalibrationStore::open(dir.path()).unwrap();
+        for i in 0..CALIBRATION_HISTORY_LEN as u64 + 3 {
+            let mut config = generate_config(Some("Kitchen"), AudioOutput::Headphone);
+            config.device_name = format!("Kitchen {i}");
+            store.record(applied(i, CalibrationSource::Phone), config).unwrap();
+        }
+
+        let history = CalibrationStore::open(dir.path()).unwrap().history();
+        assert_eq!(history.len(), CALIBRATION_HISTORY_LEN);
+        assert_eq!(history[0].applied.timestamp, 3);
+        let last = history.last().unwrap();
+        assert_eq!(last.applied.timestamp, CALIBRATION_HISTORY_LEN as u64 + 2);
+        assert_eq!(last.config_snapshot.device_name, format!("Kitchen {}", CALIBRATION_HISTORY_LEN + 2));
+
+        std::fs::write(dir.path().join(CALIBRATION_HISTORY_FILE), b"[oops").unwrap();
+        let reopened = CalibrationStore::open(dir.path()).unwrap();
+        assert!(reopened.history().is_empty());
+        assert_eq!(reopened.current().unwrap().timestamp, CALIBRATION_HISTORY_LEN as u64 + 2);
+    }
+
+    #[test]
+    fn unreadable_state_is_an_error() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join(CALIBRATION_STATE_FILE), b"{not json").unwrap();
+        assert!(CalibrationStore::open(dir.path()).is_err());
+    }
+}
@@ -0,0 +1,347 @@
+//! Receiver-side latency measurement from raw detection times.
+//!
+//! The receiver knows when playback actually started and where each marker sits in the
+//! signal, so it can pair the phone's detection times with emission times itself.
+
+use super::aggregate::{aggregate_rounds, RoundMeasurement};
+use airsync_shared_protocol::{CalibrationSignalSpec, ChirpConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// How far a detection may sit from its expected arrival and still count as that marker.
+pub const ALIGNMENT_TOLERANCE_MS: f64 = 25.0;
+
+/// A marker and where it starts, in ms from the start of playback.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Emission {
+    pub marker_id: String,
+    pub offset_ms: f64,
+}
+
+/// One emission per chirp repetition, named `chirp_0`, `chirp_1`, ...
+pub fn chirp_emissions(cfg: &ChirpConfig) -> Vec<Emission> {
+    let period_ms = f64::from(cfg.duration) + f64::from(cfg.interval_ms);
+    (0..cfg.repetitions.max(1))
+        .map(|k| Emission {
+            marker_id: format!("chirp_{k}"),
+            offset_ms: f64::from(k) * period_ms,
+        })
+        .collect()
+}
+
+/// Emissions for every marker of a structured signal, in playback order.
+pub fn marker_emissions(spec: &CalibrationSignalSpec) -> Vec<Emission> {
+    let mut emissions: Vec<Emission> = spec
+        .markers
+        .iter()
+        .map(|m| Emission {
+            marker_id: m.id.clone(),
+            offset_ms: f64::from(m.start_sample) * 1000.0 / f64::from(spec.sample_rate),
+        })
+        .collect();
+    emissions.sort_by(|a, b| a.offset_ms.total_cmp(&b.offset_ms));
+    emissions
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarkerLatency {
+    pub marker_id: String,
+    pub emitted_at_ms: u64,
+    pub detected_at_ms: u64,
+    pub latency_ms: f32,
+}
+
+/// Detections paired with emissions, in emission order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Alignment {
+    pub pairs: Vec<MarkerLatency>,
+    /// Emitted markers no detection was paired with.
+    pub missing: Vec<String>,
+    /// Detection times that matched no emission.
+    pub unmatched: Vec<u64>,
+}
+
+fn pair(emission: &Emission, start_ms: u64, detected_at_ms: u64) -> MarkerLatency {
+    let emitted = start_ms as f64 + emission.offset_ms;
+    MarkerLatency {
+        marker_id: emission.marker_id.clone(),
+        emitted_at_ms: emitted.round() as u64,
+        detected_at_ms,
+        latency_ms: (detected_at_ms as f64 - emitted) as f32,
+    }
+}
+
+/// Pair detections reported with marker ids. Unknown ids are unmatched; if a marker is
+/// reported twice the first detection wins.
+pub fn align_by_id(start_ms: u64, emissions: &[Emission], detections: &[(String, u64)]) -> Alignment {
+    let mut by_id: HashMap<&str, u64> = HashMap::new();
+    let mut unmatched = Vec::new();
+    for (id, at_ms) in detections {
+        if !emissions.iter().any(|e| e.marker_id == *id) || by_id.contains_key(id.as_str()) {
+            unmatched.push(*at_ms);
+        } else {
+            by_id.insert(id, *at_ms);
+        }
+    }
+    let mut alignment = Alignment {
+        unmatched,
+        ..Alignment::default()
+    };
+    for emission in emissions {
+        match by_id.get(emission.marker_id.as_str()) {
+            Some(&at_ms) => alignment.pairs.push(pair(emission, start_ms, at_ms)),
+            None => alignment.missing.push(emission.marker_id.clone()),
+        }
+    }
+    alignment
+}
+
+/// Pair detections without ids by their timing. Every (detection, emission) pair
+/// proposes a latency; the one that places the most detections within
+/// `ALIGNMENT_TOLERANCE_MS` of an emission wins, with ties going to the tighter fit. Each
+/// detection then takes the nearest emission under that latency, so dropped markers
+/// and spurious detections don't shift the rest.
+pub fn align_by_order(start_ms: u64, emissions: &[Emission], detections: &[u64]) -> Alignment {
+    let mut detections = detections.to_vec();
+    detections.sort_unstable();
+    let expected = |e: &Emission| start_ms as f64 + e.offset_ms;
+
+    let mut best: Option<(Vec<Option<usize>>, usize, f64)> = None;
+    for &d in &detections {
+        for candidate in emissions {
+            let latency = d as f64 - expected(candidate);
+            if latency < -ALIGNMENT_TOLERANCE_MS {
+                continue;
+            }
+            let assignment = assign(&detections, emissions, start_ms, latency);
+            let matched = assignment.iter().flatten().count();
+            let residual: f64 = detections
+                .iter()
+                .zip(&assignment)
+                .filter_map(|(&d, e)| e.map(|e| (d as f64 - expected(&emissions[e]) - latency).abs()))
+                .sum();
+            let better = match &best {
+                None => true,
+                Some((_, best_matched, best_residual)) => {
+                    matched > *best_matched || (matched == *best_matched && residual < *best_residual)
+                }
+            };
+            if better {
+                best = Some((assignment, matched, residual));
+            }
+        }
+    }
+
+    let assignment = best.map(|(a, _, _)| a).unwrap_or_else(|| vec![None; detections.len()]);
+    let mut detected_at: Vec<Option<u64>> = vec![None; emissions.len()];
+    let mut alignment = Alignment::default();
+    for (&d, e) in detections.iter().zip(&assignment) {
+        match e {
+            Some(e) => detected_at[*e] = Some(d),
+            None => alignment.unmatched.push(d),
+        }
+    }
+    for (emission, detected) in emissions.iter().zip(detected_at) {
+        match detected {
+            Some(d) => alignment.pairs.push(pair(emission, start_ms, d)),
+            None => alignment.missing.push(emission.marker_id.clone()),
+        }
+    }
+    alignment
+}
+
+/// Give each detection the nearest emission within tolerance at `latency`, one detection
+/// per emission (the closer one keeps it).
+fn assign(detections: &[u64], emissions: &[Emission], start_ms: u64, latency: f64) -> Vec<Option<usize>> {
+    let mut owner: Vec<Option<(usize, f64)>> = vec![None; emissions.len()];
+    for (i, &d) in detections.iter().enumerate() {
+        let nearest = emissions
+            .iter()
+            .enumerate()
+            .map(|(e, emission)| (e, (d as f64 - (start_ms as f64 + emission.offset_ms) - latency).abs()))
+            .filter(|(_, error)| *error <= ALIGNMENT_TOLERANCE_MS)
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((e, error)) = nearest {
+            if owner[e].is_none_or(|(_, held)| error < held) {
+                owner[e] = Some((i, error));
+            }
+        }
+    }
+    let mut assignment = vec![None; detections.len()];
+    for (e, held) in owner.iter().enumerate() {
+        if let Some((i, _)) = held {
+            assignment[*i] = Some(e);
+        }
+    }
+    assignment
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LatencyMeasurement {
+    pub latency_ms: f32,
+    pub confidence: f32,
+    pub median_ms: f32,
+    pub mad_ms: f32,
+    /// Every paired marker, outliers included.
+    pub markers: Vec<MarkerLatency>,
+    /// Paired markers left out of the estimate as outliers.
+    pub rejected_markers: Vec<String>,
+    pub missing_markers: Vec<String>,
+    pub unmatched_detections: Vec<u64>,
+}
+
+/// Aggregate the paired markers with the same outlier screening as multi-round
+/// calibration. `confidence` is the detector's and is scaled by the fraction of emitted
+/// markers that were paired. Returns `None` when nothing paired.
+pub fn measure_latency(alignment: Alignment, confidence: f32) -> Option<LatencyMeasurement> {
+    let measurements: Vec<RoundMeasurement> = alignment
+        .pairs
+        .iter()
+        .map(|p| RoundMeasurement {
+            latency_ms: p.latency_ms,
+            confidence,
+        })
+        .collect();
+    let aggregate = aggregate_rounds(&measurements)?;
+    let emitted = alignment.pairs.len() + alignment.missing.len();
+    let coverage = alignment.pairs.len() as f32 / emitted as f32;
+    Some(LatencyMeasurement {
+        latency_ms: aggregate.latency_ms,
+        confidence: (aggregate.confidence * coverage).clamp(0.0, 1.0),
+        median_ms: aggregate.median_ms,
+        mad_ms: aggregate.mad_ms,
+        rejected_markers: aggregate
+            .rejected
+            .iter()
+            .map(|&i| alignment.pairs[i].marker_id.clone())
+            .collect(),
+        markers: alignment.pairs,
+        missing_markers: alignment.missing,
+        unmatched_detections: alignment.unmatched,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use airsync_shared_protocol::{MarkerKind, MarkerSpec, SampleFormat};
+
+    fn chirps(repetitions: u32) -> Vec<Emission> {
+        chirp_emissions(&ChirpConfig {
+            duration: 100,
+            interval_ms: 400,
+            repetitions,
+            ..ChirpConfig::default()
+        })
+    }
+
+    fn latencies(alignment: &Alignment) -> Vec<(&str, f32)> {
+        alignment
+            .pairs
+            .iter()
+            .map(|p| (p.marker_id.as_str(), p.latency_ms))
+            .collect()
+    }
+
+    #[test]
+    fn chirp_emissions_follow_the_repetition_period() {
+        let offsets: Vec<f64> = chirps(3).iter().map(|e| e.offset_ms).collect();
+        assert_eq!(offsets, vec![0.0, 500.0, 1_000.0]);
+    }
+
+    #[test]
+    fn marker_emissions_convert_samples_to_ms_in_order() {
+        let marker = |id: &str, start_sample| MarkerSpec {
+            id: id.into(),
+            kind: MarkerKind::Click,
+            start_sample,
+            duration_samples: 480,
+            fade_samples: 0,
+            amplitude: 1.0,
+            search_window_samples: None,
+        };
+        let spec = CalibrationSignalSpec {
+            sample_rate: 48_000,
+            sample_format: SampleFormat::I16,
+            length_samples: 96_000,
+            markers: vec![marker("late", 72_000), marker("early", 24_000)],
+            signal_id: None,
+            content_hash: None,
+            level_db: None,
+            suggested_decimation: 1,
+            max_expected_latency_ms: None,
+        };
+        assert_eq!(
+            marker_emissions(&spec),
+            vec![
+                Emission {
+                    marker_id: "early".into(),
+                    offset_ms: 500.0
+                },
+                Emission {
+                    marker_id: "late".into(),
+                    offset_ms: 1_500.0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn order_alignment_tolerates_missing_markers() {
+        // chirp_1 was never heard; latency ~ 180ms with a little jitter.
+        let alignment = align_by_order(10_000, &chirps(4), &[10_180, 11_183, 11_678]);
+        assert_eq!(
+            latencies(&alignment),
+            vec![("chirp_0", 180.0), ("chirp_2", 183.0), ("chirp_3", 178.0)]
+        );
+        assert_eq!(alignment.missing, vec!["chirp_1".to_string()]);
+        assert!(alignment.unmatched.is_empty());
+    }
+
+    #[test]
+    fn order_alignment_handles_latency_beyond_the_chirp_spacing() {
+        // 620ms latency is longer than the 500ms period, so each detection lands after
+        // the next chirp was emitted; nearest-preceding pairing would get this wrong.
+        let alignment = align_by_order(0, &chirps(3), &[620, 1_121, 1_619, 2_300]);
+        assert_eq!(
+            latencies(&alignment),
+            vec![("chirp_0", 620.0), ("chirp_1", 621.0), ("chirp_2", 619.0)]
+        );
+        assert_eq!(alignment.unmatched, vec![2_300]);
+    }
+
+    #[test]
+    fn id_alignment_pairs_reported_markers() {
+        let detections = [
+            ("chirp_2".to_string(), 1_190),
+            ("bogus".to_string(), 5),
+            ("chirp_0".to_string(), 200),
+        ];
+        let alignment = align_by_id(0, &chirps(3), &detections);
+        assert_eq!(latencies(&alignment), vec![("chirp_0", 200.0), ("chirp_2", 190.0)]);
+        assert_eq!(alignment.missing, vec!["chirp_1".to_string()]);
+        assert_eq!(alignment.unmatched, vec![5]);
+    }
+
+    #[test]
+    fn measurement_rejects_outliers_and_scales_confidence_by_coverage() {
+        let alignment = align_by_id(
+            0,
+            &chirps(5),
+            &[
+                ("chirp_0".to_string(), 100),
+                ("chirp_1".to_string(), 601),
+                ("chirp_2".to_string(), 1_099),
+                ("chirp_3".to_string(), 1_640),
+            ],
+        );
+        let measurement = measure_latency(alignment, 1.0).unwrap();
+        assert_eq!(measurement.rejected_markers, vec!["chirp_3".to_string()]);
+        assert!((measurement.latency_ms - 100.0).abs() < 1e-3);
+        assert_eq!(measurement.median_ms, 100.5);
+        // 3 of 4 pairs accepted, 4 of 5 markers paired.
+        assert!((measurement.confidence - 0.75 * 0.8).abs() < 1e-6);
+        assert_eq!(measurement.missing_markers, vec!["chirp_4".to_string()]);
+        assert!(measure_latency(Alignment::default(), 1.0).is_none());
+    }
+}
@@ -2,6 +2,14 @@ use airsync_shared_protocol::ChirpConfig;
 use std::f32::consts::PI;
 
 pub fn generate_chirp_samples(cfg: &ChirpConfig, sample_rate: u32, gain: f32) -> Vec<i16> {
+    generate_chirp_signal(cfg, sample_rate, gain)
+        .into_iter()
+        .map(|s| (s * i16::MAX as f32).round() as i16)
+        .collect()
+}
+
+/// The chirp train as samples with full scale at ±1.0, for writing at other bit depths.
+pub fn generate_chirp_signal(cfg: &ChirpConfig, sample_rate: u32, gain: f32) -> Vec<f32> {
     let sr = sample_rate as f32;
     let duration_s = cfg.duration as f32 / 1000.0;
     let interval_s = cfg.interval_ms as f32 / 1000.0;
@@ -10,12 +18,13 @@ pub fn generate_chirp_samples(cfg: &ChirpConfig, sample_rate: u32, gain: f32) ->
     let single = (0..(duration_s * sr) as usize)
         .map(|n| {
             let t = n as f32 / sr;
-            let phase = 2.0 * PI * (cfg.start_freq as f32 * t + 0.5 * sweep_k * t * t / duration_s);
-            let sample = (phase.sin() * amplitude * i16::MAX as f32).round();
-            sample as i16
+            // Linear sweep: f(t) = start_freq + sweep_k * t, so the phase integrates to
+            // start_freq * t + sweep_k * t^2 / 2 and reaches end_freq at t = duration_s.
+            let phase = 2.0 * PI * (cfg.start_freq as f32 * t + 0.5 * sweep_k * t * t);
+            phase.sin() * amplitude
         })
         .collect::<Vec<_>>();
-    let silence = (0..(interval_s * sr) as usize).map(|_| 0i16).collect::<Vec<_>>();
+    let silence = vec![0.0; (interval_s * sr) as usize];
     let mut out = Vec::new();
     for _ in 0..cfg.repetitions.max(1) {
         out.extend_from_slice(&single);
@@ -24,10 +33,64 @@ pub fn generate_chirp_samples(cfg: &ChirpConfig, sample_rate: u32, gain: f32) ->
     out
 }
 
+/// Average interleaved left/right pairs into mono, rounding halves away from zero. A
+/// trailing unpaired sample is dropped.
+pub fn downmix_to_mono(stereo: &[i16]) -> Vec<i16> {
+    stereo
+        .chunks_exact(2)
+        .map(|frame| {
+            let sum = frame[0] as i32 + frame[1] as i32;
+            ((sum + sum.signum()) / 2) as i16
+        })
+        .collect()
+}
+
+/// Mix interleaved left/right pairs into mono as `left * left_weight + right * right_weight`,
+/// clamped to the sample range. A trailing unpaired sample is dropped.
+pub fn downmix_to_mono_weighted(stereo: &[i16], left_weight: f32, right_weight: f32) -> Vec<i16> {
+    stereo
+        .chunks_exact(2)
+        .map(|frame| {
+            let mixed = frame[0] as f32 * left_weight + frame[1] as f32 * right_weight;
+            mixed.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn interleave(left: &[i16], right: &[i16]) -> Vec<i16> {
+        left.iter().zip(right).flat_map(|(l, r)| [*l, *r]).collect()
+    }
+
+    #[test]
+    fn downmix_halves_the_length_without_overflow() {
+        let cfg = ChirpConfig::default();
+        let left = generate_chirp_samples(&cfg, 48_000, 1.0);
+        let right = generate_chirp_samples(&cfg, 48_000, 0.5);
+        let stereo = interleave(&left, &right);
+        assert_eq!(downmix_to_mono(&stereo).len(), stereo.len() / 2);
+        assert_eq!(downmix_to_mono_weighted(&stereo, 0.5, 0.5).len(), stereo.len() / 2);
+
+        assert_eq!(downmix_to_mono(&[i16::MAX, i16::MAX, i16::MIN, i16::MIN]), vec![i16::MAX, i16::MIN]);
+        assert_eq!(downmix_to_mono(&[3, 4, -3, -4, 1, 0]), vec![4, -4, 1]);
+        assert_eq!(downmix_to_mono(&[10, 20, 30]), vec![15]);
+        assert_eq!(downmix_to_mono_weighted(&[i16::MAX, i16::MAX], 1.0, 1.0), vec![i16::MAX]);
+    }
+
+    #[test]
+    fn silent_left_channel_downmixes_like_the_right_alone() {
+        let right = generate_chirp_samples(&ChirpConfig::default(), 48_000, 1.0);
+        let stereo = interleave(&vec![0; right.len()], &right);
+        assert_eq!(downmix_to_mono_weighted(&stereo, 0.0, 1.0), right);
+        assert_eq!(downmix_to_mono_weighted(&stereo, 1.0, 1.0), right);
+        let halved: Vec<i16> = right.iter().map(|&s| ((s as i32 + (s as i32).signum()) / 2) as i16).collect();
+        assert_eq!(downmix_to_mono(&stereo), halved);
+        assert_eq!(downmix_to_mono_weighted(&stereo, 0.5, 0.5), halved);
+    }
+
     #[test]
     fn chirp_samples_have_energy() {
         let cfg = ChirpConfig {
@@ -43,4 +106,93 @@ mod tests {
         let expected_min = (cfg.duration as f32 / 1000.0 * 48000.0) as usize * 2;
         assert!(samples.len() >= expected_min);
     }
+
+    /// Estimate frequency over a window from its zero-crossing rate.
+    fn zero_crossing_frequency(window: &[i16], sample_rate: u32) -> f32 {
+        let crossings = window
+            .windows(2)
+            .filter(|w| (w[0] < 0) != (w[1] < 0))
+            .count();
+        crossings as f32 * sample_rate as f32 / (2.0 * window.len() as f32)
+    }
+
+    /// Peak frequency of a Hann-windowed naive DFT over `samples`, refined between bins by
+    /// fitting a parabola to the log magnitudes around the peak.
+    fn dominant_frequency_hz(samples: &[i16], sample_rate: u32) -> f32 {
+        use std::f64::consts::PI;
+        let n = samples.len();
+        let windowed: Vec<f64> = samples
+            .iter()
+            .enumerate()
+            .map(|(i, &s)| s as f64 * (0.5 - 0.5 * (2.0 * PI * i as f64 / (n - 1) as f64).cos()))
+            .collect();
+        let magnitudes: Vec<f64> = (0..n / 2)
+            .map(|k| {
+                let (re, im) = windowed.iter().enumerate().fold((0.0, 0.0), |(re, im), (i, &x)| {
+                    let angle = 2.0 * PI * (k * i) as f64 / n as f64;
+                    (re + x * angle.cos(), im - x * angle.sin())
+                });
+                (re * re + im * im).sqrt()
+            })
+            .collect();
+        let peak = (1..magnitudes.len() - 1)
+            .max_by(|&a, &b| magnitudes[a].total_cmp(&magnitudes[b]))
+            .unwrap();
+        let [left, centre, right] = [peak - 1, peak, peak + 1].map(|k| magnitudes[k].max(f64::MIN_POSITIVE).ln());
+        let shift = 0.5 * (left - right) / (left - 2.0 * centre + right);
+        ((peak as f64 + shift) * sample_rate as f64 / n as f64) as f32
+    }
+
+    #[test]
+    fn constant_tone_peaks_at_its_frequency() {
+        let cfg = ChirpConfig {
+            start_freq: 1_000,
+            end_freq: 1_000,
+            duration: 100,
+            repetitions: 1,
+            interval_ms: 0,
+            amplitude: None,
+        };
+        let samples = generate_chirp_samples(&cfg, 48_000, 1.0);
+        let window = &samples[1_000..1_000 + 2_048];
+        let peak = dominant_frequency_hz(window, 48_000);
+        assert!((peak - 1_000.0).abs() <= 5.0, "dominant frequency {peak}");
+    }
+
+    fn assert_sweep_spans_configured_range(sample_rate: u32) {
+        let cfg = ChirpConfig {
+            start_freq: 1_000,
+            end_freq: 10_000,
+            duration: 100,
+            repetitions: 1,
+            interval_ms: 0,
+            amplitude: None,
+        };
+        let samples = generate_chirp_samples(&cfg, sample_rate, 1.0);
+        let sweep_len = (cfg.duration as f32 / 1000.0 * sample_rate as f32) as usize;
+        assert_eq!(samples.len(), sweep_len);
+
+        // Over the first/last 10 ms the sweep covers 900 Hz, so the mean frequency of
+        // each window sits 450 Hz inside the configured endpoints.
+        let window = sample_rate as usize / 100;
+        let head = zero_crossing_frequency(&samples[..window], sample_rate);
+        let tail = zero_crossing_frequency(&samples[sweep_len - window..], sample_rate);
+        assert!((head - 1_450.0).abs() < 150.0, "head frequency {head} at {sample_rate} Hz");
+        assert!((tail - 9_550.0).abs() < 250.0, "tail frequency {tail} at {sample_rate} Hz");
+    }
+
+    #[test]
+    fn sweep_reaches_end_freq_at_48k() {
+        assert_sweep_spans_configured_range(48_000);
+    }
+
+    #[test]
+    fn sweep_reaches_end_freq_at_44k1() {
+        assert_sweep_spans_configured_range(44_100);
+    }
+
+    #[test]
+    fn sweep_reaches_end_freq_at_96k() {
+        assert_sweep_spans_configured_range(96_000);
+    }
 }
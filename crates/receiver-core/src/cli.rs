@@ -0,0 +1,212 @@
+//! Command-line parsing shared by the receiver binaries. Each binary parses its own
+//! arguments next to [`CommonArgs`] through [`parse_binary`].
+
+use crate::version::PKG_VERSION;
+use airsync_shared_protocol::{OutputDeviceSpec, SampleFormat};
+use clap::{ArgAction, Args, FromArgMatches, ValueEnum};
+use std::ffi::OsString;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+pub const DEFAULT_SHAIRPORT_CONFIG_PATH: &str = "/etc/shairport-sync.conf";
+
+/// A binary's own arguments next to the ones every binary takes.
+#[derive(Debug, Args)]
+#[command(about = None, long_about = None)]
+pub struct Invocation<T: Args> {
+    #[command(flatten)]
+    pub common: CommonArgs,
+    #[command(flatten)]
+    pub args: T,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct CommonArgs {
+    /// Least severe messages to print. The service also applies it to its request logs.
+    #[arg(long, value_enum, default_value_t = LogLevel::Info, global = true)]
+    pub log_level: LogLevel,
+    /// shairport-sync config to read or write.
+    #[arg(long, default_value = DEFAULT_SHAIRPORT_CONFIG_PATH, global = true)]
+    pub config_path: PathBuf,
+    /// Print results as JSON instead of text; the service logs JSON lines.
+    #[arg(long, action = ArgAction::SetTrue, global = true)]
+    pub json: bool,
+}
+
+impl CommonArgs {
+    /// Whether messages at `level` should be printed.
+    pub fn shows(&self, level: LogLevel) -> bool {
+        level <= self.log_level
+    }
+}
+
+/// Ordered from most to least severe, so `Error < Debug`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+#[derive(Debug, Args)]
+pub struct ServiceArgs {
+    /// Address to listen on; plain or bracketed, such as `0.0.0.0` or `[::]`.
+    #[arg(long, default_value = "0.0.0.0", value_parser = parse_bind)]
+    pub bind: IpAddr,
+    /// Re-detect hardware even when the cache is fresh.
+    #[arg(long)]
+    pub force_detect: bool,
+    /// Serve on the socket systemd passes in (LISTEN_FDS) instead of binding `bind`.
+    #[arg(long)]
+    pub socket_activation: bool,
+    /// Also serve HTTPS on this port, with a self-signed certificate kept in the state
+    /// directory. Needs a build with the `tls` feature.
+    #[arg(long)]
+    pub tls_port: Option<u16>,
+    /// Replace the HTTPS certificate with a new one before serving; clients have to pin
+    /// the new fingerprint.
+    #[arg(long, requires = "tls_port")]
+    pub regenerate_tls_cert: bool,
+    /// Also write the shairport-sync config here whenever it changes, e.g. to a network
+    /// share; repeat for more copies. A failed copy fails the change, after every other
+    /// copy has been written.
+    #[arg(long = "mirror-config", value_name = "PATH")]
+    pub mirror_configs: Vec<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct GenerateConfigArgs {
+    /// Where to write the config; `--config-path` when left out.
+    pub output_path: Option<PathBuf>,
+    /// AirPlay name to advertise.
+    pub device_name: Option<String>,
+    /// ALSA output to use instead of the detected one, such as `hw:1,0`.
+    #[arg(long)]
+    pub device: Option<OutputDeviceSpec>,
+}
+
+#[derive(Debug, Args)]
+pub struct GenerateChirpWavArgs {
+    pub output_path: PathBuf,
+    #[arg(default_value_t = 48_000)]
+    pub sample_rate: u32,
+    #[arg(default_value_t = 1.0)]
+    pub gain: f32,
+    /// Sample format: i16, i24 or f32.
+    #[arg(long, default_value = "i16")]
+    pub format: SampleFormat,
+}
+
+#[derive(Debug, Args)]
+pub struct GenerateStructuredSignalArgs {
+    pub output_path: PathBuf,
+    /// Read the written file back and check its length against the spec.
+    #[arg(long)]
+    pub validate: bool,
+    /// Sample format: i16, i24 or f32.
+    #[arg(long, default_value = "i16")]
+    pub format: SampleFormat,
+}
+
+#[derive(Debug, Args)]
+pub struct DetectHardwareArgs {}
+
+fn parse_bind(value: &str) -> Result<IpAddr, String> {
+    value
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .map_err(|e| format!("invalid address {value}: {e}"))
+}
+
+/// Parse the process arguments for the binary `name`, exiting with clap's message on
+/// `--help`, `--version` or an error.
+pub fn parse_binary<T: Args + FromArgMatches>(name: &'static str) -> Invocation<T> {
+    try_parse_binary_from(name, std::env::args_os()).unwrap_or_else(|e| e.exit())
+}
+
+pub fn try_parse_binary_from<T, I>(name: &'static str, args: I) -> Result<Invocation<T>, clap::Error>
+where
+    T: Args + FromArgMatches,
+    I: IntoIterator,
+    I::Item: Into<OsString> + Clone,
+{
+    let command = Invocation::<T>::augment_args(clap::Command::new(name).version(PKG_VERSION));
+    let matches = command.try_get_matches_from(args)?;
+    Invocation::<T>::from_arg_matches(&matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::error::ErrorKind;
+
+    fn parse<T: Args + FromArgMatches>(args: &[&str]) -> Result<Invocation<T>, clap::Error> {
+        try_parse_binary_from("test", std::iter::once("test").chain(args.iter().copied()))
+    }
+
+    fn help<T: Args + FromArgMatches + std::fmt::Debug>() -> String {
+        Invocation::<T>::augment_args(clap::Command::new("test")).debug_assert();
+        let err = parse::<T>(&["--help"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DisplayHelp);
+        err.render().to_string()
+    }
+
+    #[test]
+    fn help_renders_for_every_binary() {
+        assert!(help::<ServiceArgs>().contains("--tls-port"));
+        assert!(help::<GenerateConfigArgs>().contains("--device"));
+        assert!(help::<GenerateChirpWavArgs>().contains("--format"));
+        assert!(help::<GenerateStructuredSignalArgs>().contains("--validate"));
+        assert!(help::<DetectHardwareArgs>().contains("--json"));
+    }
+
+    #[test]
+    fn version_prints_the_crate_semver() {
+        let err = parse::<DetectHardwareArgs>(&["--version"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DisplayVersion);
+        let rendered = err.render().to_string();
+        let version = rendered.trim().rsplit(' ').next().unwrap();
+        assert_eq!(version, env!("CARGO_PKG_VERSION"));
+        let parts: Vec<&str> = version.split('.').collect();
+        assert_eq!(parts.len(), 3);
+        assert!(parts.iter().all(|p| p.parse::<u64>().is_ok()), "{version} is not semver");
+    }
+
+    #[test]
+    fn service_accepts_bracketed_addresses() {
+        let parsed = parse::<ServiceArgs>(&["--bind", "[::]", "--socket-activation"]).unwrap();
+        assert_eq!(parsed.args.bind, "::".parse::<IpAddr>().unwrap());
+        assert!(parsed.args.socket_activation);
+        assert!(!parsed.args.force_detect);
+        assert!(parsed.args.mirror_configs.is_empty());
+        let mirrored = parse::<ServiceArgs>(&["--mirror-config", "/mnt/a.conf", "--mirror-config", "/mnt/b.conf"]).unwrap();
+        assert_eq!(mirrored.args.mirror_configs, [PathBuf::from("/mnt/a.conf"), PathBuf::from("/mnt/b.conf")]);
+        assert!(parse::<ServiceArgs>(&["--bind", "nowhere"]).is_err());
+        assert!(parse::<ServiceArgs>(&["--unknown"]).is_err());
+    }
+
+    #[test]
+    fn positional_arguments_keep_their_defaults() {
+        let parsed = parse::<GenerateChirpWavArgs>(&["/tmp/chirp.wav"]).unwrap();
+        assert_eq!(parsed.args.sample_rate, 48_000);
+        assert_eq!(parsed.args.gain, 1.0);
+        assert_eq!(parsed.args.format, SampleFormat::I16);
+
+        let parsed = parse::<GenerateConfigArgs>(&["/tmp/shairport.conf", "Kitchen", "--device", "hw:1,0", "--json"]).unwrap();
+        assert_eq!(parsed.args.device_name.as_deref(), Some("Kitchen"));
+        assert_eq!(parsed.args.device, Some("hw:1,0".parse().unwrap()));
+        assert!(parsed.common.json);
+        assert!(parse::<GenerateConfigArgs>(&["--device", "hw:0,x"]).is_err());
+    }
+
+    #[test]
+    fn common_arguments_parse_for_every_binary() {
+        let invocation = parse::<DetectHardwareArgs>(&["--log-level", "warn", "--json"]).unwrap();
+        assert!(invocation.common.json);
+        assert!(invocation.common.shows(LogLevel::Error));
+        assert!(!invocation.common.shows(LogLevel::Info));
+        assert_eq!(invocation.common.config_path, PathBuf::from(DEFAULT_SHAIRPORT_CONFIG_PATH));
+    }
+}
@@ -0,0 +1,271 @@
+//! Which lines of the shairport-sync config each apply touched, for `/admin/config-changes`.
+//! Every settings update and calibration apply diffs the config it renders against the
+//! one in place before writing it; the diffs of the last few are kept in memory. Values
+//! of secret keys such as the AirPlay `password` are masked before a diff is stored.
+
+use crate::airplay::{render_config_file, ShairportConfig};
+use crate::http::now_millis;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Config changes kept for `/admin/config-changes` unless the builder says otherwise.
+pub const DEFAULT_CONFIG_CHANGES: usize = 20;
+
+/// Keys whose values never appear in a diff.
+const SECRET_KEYS: &[&str] = &["password"];
+
+/// Larger configs (line count before × after) are diffed as wholly replaced rather than
+/// line by line.
+const MAX_DIFF_CELLS: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffOp {
+    Removed,
+    Added,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiffLine {
+    pub op: DiffOp,
+    /// 1-based line number, in the previous config for removed lines and in the new one
+    /// for added lines.
+    pub line: usize,
+    /// `group.key` set on the line, if it sets one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub text: String,
+}
+
+/// Line-level difference between two rendered configs, secrets masked.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConfigDiff {
+    pub lines: Vec<DiffLine>,
+}
+
+impl ConfigDiff {
+    /// The lines removed from `old` and added in `new`, in file order.
+    pub fn between(old: &str, new: &str) -> Self {
+        let old: Vec<&str> = old.lines().collect();
+        let new: Vec<&str> = new.lines().collect();
+        let (old_keys, new_keys) = (line_keys(&old), line_keys(&new));
+        let line = |op, index: usize, lines: &[&str], keys: &[Option<String>]| DiffLine {
+            op,
+            line: index + 1,
+            key: keys[index].clone(),
+            text: mask_secret(lines[index]),
+        };
+        let mut lines = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        let Some(common) = common_suffix_lengths(&old, &new) else {
+            // Too large to align: diffed as wholly replaced.
+            lines.extend((0..old.len()).map(|i| line(DiffOp::Removed, i, &old, &old_keys)));
+            lines.extend((0..new.len()).map(|j| line(DiffOp::Added, j, &new, &new_keys)));
+            return Self { lines };
+        };
+        while i < old.len() || j < new.len() {
+            if i < old.len() && j < new.len() && old[i] == new[j] {
+                i += 1;
+                j += 1;
+            } else if j == new.len() || (i < old.len() && common[i + 1][j] >= common[i][j + 1]) {
+                lines.push(line(DiffOp::Removed, i, &old, &old_keys));
+                i += 1;
+            } else {
+                lines.push(line(DiffOp::Added, j, &new, &new_keys));
+                j += 1;
+            }
+        }
+        Self { lines }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Keys set on the changed lines, each once, in the order they first appear.
+    pub fn changed_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = Vec::new();
+        for key in self.lines.iter().filter_map(|line| line.key.as_ref()) {
+            if !keys.contains(key) {
+                keys.push(key.clone());
+            }
+        }
+        keys
+    }
+}
+
+/// Keys that differ between the built-in renderings of `old` and `new`, for settings
+/// managers that write no file to diff.
+pub fn rendered_changed_keys(old: &ShairportConfig, new: &ShairportConfig) -> Vec<String> {
+    ConfigDiff::between(&render_config_file(old), &render_config_file(new)).changed_keys()
+}
+
+/// Length of the longest common subsequence of `old[i..]` and `new[j..]` at `[i][j]`.
+/// `None` past [`MAX_DIFF_CELLS`], checked before anything is allocated.
+fn common_suffix_lengths(old: &[&str], new: &[&str]) -> Option<Vec<Vec<u32>>> {
+    if old.len().checked_mul(new.len()).is_none_or(|cells| cells > MAX_DIFF_CELLS) {
+        return None;
+    }
+    let mut common = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    Some(common)
+}
+
+/// The key each line sets, qualified by the groups it is nested in (`general.name`).
+fn line_keys(lines: &[&str]) -> Vec<Option<String>> {
+    let mut groups: Vec<&str> = Vec::new();
+    lines
+        .iter()
+        .map(|line| {
+            let trimmed = line.trim();
+            if trimmed.starts_with('}') {
+                groups.pop();
+                return None;
+            }
+            let (name, value) = setting(trimmed)?;
+            if value.starts_with('{') {
+                groups.push(name);
+                return None;
+            }
+            let mut key = groups.join(".");
+            if !key.is_empty() {
+                key.push('.');
+            }
+            key.push_str(name);
+            Some(key)
+        })
+        .collect()
+}
+
+/// The name and (left-trimmed) value of a `name = value` line.
+fn setting(trimmed: &str) -> Option<(&str, &str)> {
+    let (name, value) = trimmed.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return None;
+    }
+    Some((name, value.trim_start()))
+}
+
+fn mask_secret(line: &str) -> String {
+    match setting(line.trim()) {
+        Some((name, _)) if SECRET_KEYS.contains(&name) => {
+            let indent = &line[..line.len() - line.trim_start().len()];
+            format!("{indent}{name} = \"[redacted]\";")
+        }
+        _ => line.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigChangeSource {
+    Settings,
+    Calibration,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConfigChange {
+    pub at_ms: u64,
+    pub source: ConfigChangeSource,
+    pub changed_keys: Vec<String>,
+    pub diff: ConfigDiff,
+}
+
+/// Shared ring buffer of the last `capacity` config changes.
+#[derive(Clone)]
+pub struct ConfigChangeLog {
+    inner: Arc<Mutex<VecDeque<ConfigChange>>>,
+    capacity: usize,
+}
+
+impl Default for ConfigChangeLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_CONFIG_CHANGES)
+    }
+}
+
+impl ConfigChangeLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    pub fn record(&self, source: ConfigChangeSource, diff: ConfigDiff) {
+        let change = ConfigChange {
+            at_ms: now_millis(),
+            source,
+            changed_keys: diff.changed_keys(),
+            diff,
+        };
+        let mut changes = self.inner.lock().unwrap();
+        if self.capacity == 0 {
+            return;
+        }
+        if changes.len() == self.capacity {
+            changes.pop_front();
+        }
+        changes.push_back(change);
+    }
+
+    /// The recorded changes, oldest first.
+    pub fn snapshot(&self) -> Vec<ConfigChange> {
+        self.inner.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::airplay::generate_config;
+    use airsync_shared_protocol::AudioOutput;
+
+    #[test]
+    fn latency_only_change_touches_exactly_the_offset_line() {
+        let mut config = generate_config(Some("Den"), AudioOutput::USB);
+        config.latency_offset_seconds = -0.040;
+        let old = render_config_file(&config);
+        config.latency_offset_seconds = -0.052;
+        let diff = ConfigDiff::between(&old, &render_config_file(&config));
+
+        let offset = |value: &str| format!("    audio_backend_latency_offset_in_seconds = {value};");
+        let lines: Vec<(DiffOp, usize, &str)> = diff.lines.iter().map(|l| (l.op, l.line, l.text.as_str())).collect();
+        assert_eq!(lines, [(DiffOp::Removed, 5, &*offset("-0.040")), (DiffOp::Added, 5, &*offset("-0.052"))]);
+        assert_eq!(diff.changed_keys(), ["general.audio_backend_latency_offset_in_seconds"]);
+        assert!(ConfigDiff::between(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn secret_values_are_masked_on_both_sides() {
+        let old = "general = {\n    name = \"Den\";\n    password = \"hunter2\";\n};\n";
+        let new = "general = {\n    name = \"Den\";\n    password=\"correct horse\"; // guests\n};\nmqtt = {\n    password = \"s3cret\";\n};\n";
+        let diff = ConfigDiff::between(old, new);
+        let json = serde_json::to_string(&diff).unwrap();
+        for secret in ["hunter2", "correct horse", "guests", "s3cret"] {
+            assert!(!json.contains(secret), "{json}");
+        }
+        assert_eq!(diff.changed_keys(), ["general.password", "mqtt.password"]);
+        assert!(diff.lines.iter().any(|l| l.text == "    password = \"[redacted]\";"));
+    }
+
+    #[test]
+    fn oversized_configs_are_diffed_as_replaced_without_a_table() {
+        let old = "a = 1;\n".repeat(1100);
+        let new = "b = 2;\n".repeat(1000);
+        assert!(common_suffix_lengths(&["x"; 1100], &["y"; 1000]).is_none());
+        let diff = ConfigDiff::between(&old, &new);
+        assert_eq!(diff.lines.len(), 2100);
+        assert_eq!(diff.changed_keys(), ["a", "b"]);
+    }
+}
@@ -0,0 +1,346 @@
+use airsync_shared_protocol::DiscoveredReceiver;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// DNS-SD service type advertised by `render_avahi_service`.
+pub use airsync_shared_protocol::SERVICE_TYPE;
+/// Peers not re-announced within this window are dropped from the table.
+pub const PEER_TTL_MS: u64 = 120_000;
+
+/// A sibling receiver seen on the LAN.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerRecord {
+    pub receiver_id: String,
+    pub name: String,
+    pub address: IpAddr,
+    pub port: u16,
+    pub capabilities: Vec<String>,
+    #[serde(skip)]
+    pub fullname: String,
+    pub last_seen_ms: u64,
+}
+
+impl PeerRecord {
+    /// Build a peer from a resolved `_airsync._tcp` instance and its TXT key/value pairs.
+    /// Returns `None` when the record lacks an `id` or a usable address.
+    pub fn from_txt<'a>(
+        fullname: &str,
+        txt: impl IntoIterator<Item = (&'a str, &'a str)>,
+        addresses: &[IpAddr],
+        port: u16,
+        seen_ms: u64,
+    ) -> Option<PeerRecord> {
+        let found = DiscoveredReceiver::from_txt(fullname, txt, addresses, port)?;
+        Some(PeerRecord {
+            receiver_id: found.receiver_id,
+            name: found.name,
+            address: found.address,
+            port: found.port,
+            capabilities: found.capabilities,
+            fullname: fullname.to_string(),
+            last_seen_ms: seen_ms,
+        })
+    }
+
+    /// Base URL of the peer's HTTP API.
+    pub fn base_url(&self) -> String {
+        format!("http://{}", SocketAddr::new(self.address, self.port))
+    }
+}
+
+/// Known peers keyed by receiver id, excluding this receiver.
+pub struct PeerTable {
+    self_id: String,
+    ttl_ms: u64,
+    peers: HashMap<String, PeerRecord>,
+}
+
+impl PeerTable {
+    pub fn new(self_id: impl Into<String>, ttl_ms: u64) -> Self {
+        Self {
+            self_id: self_id.into(),
+            ttl_ms,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Insert or refresh a peer. Our own announcement is ignored.
+    pub fn upsert(&mut self, peer: PeerRecord) -> bool {
+        if peer.receiver_id == self.self_id {
+            return false;
+        }
+        self.peers.insert(peer.receiver_id.clone(), peer);
+        true
+    }
+
+    /// Drop the peer registered under an mDNS instance name (goodbye packet).
+    pub fn remove_fullname(&mut self, fullname: &str) {
+        self.peers.retain(|_, peer| peer.fullname != fullname);
+    }
+
+    pub fn expire(&mut self, now_ms: u64) {
+        let ttl = self.ttl_ms;
+        self.peers
+            .retain(|_, peer| now_ms.saturating_sub(peer.last_seen_ms) <= ttl);
+    }
+
+    /// Live peers ordered by receiver id.
+    pub fn live(&self, now_ms: u64) -> Vec<PeerRecord> {
+        let mut live: Vec<PeerRecord> = self
+            .peers
+            .values()
+            .filter(|peer| now_ms.saturating_sub(peer.last_seen_ms) <= self.ttl_ms)
+            .cloned()
+            .collect();
+        live.sort_by(|a, b| a.receiver_id.cmp(&b.receiver_id));
+        live
+    }
+}
+
+/// Shared handle to the peer table, updated by the browse task and read by handlers.
+#[derive(Clone)]
+pub struct PeerDirectory {
+    table: Arc<Mutex<PeerTable>>,
+}
+
+impl PeerDirectory {
+    pub fn new(self_id: impl Into<String>) -> Self {
+        Self::with_ttl(self_id, PEER_TTL_MS)
+    }
+
+    pub fn with_ttl(self_id: impl Into<String>, ttl_ms: u64) -> Self {
+        Self {
+            table: Arc::new(Mutex::new(PeerTable::new(self_id, ttl_ms))),
+        }
+    }
+
+    pub fn upsert(&self, peer: PeerRecord) -> bool {
+        self.table.lock().unwrap().upsert(peer)
+    }
+
+    pub fn remove_fullname(&self, fullname: &str) {
+        self.table.lock().unwrap().remove_fullname(fullname)
+    }
+
+    pub fn expire(&self, now_ms: u64) {
+        self.table.lock().unwrap().expire(now_ms)
+    }
+
+    pub fn live(&self, now_ms: u64) -> Vec<PeerRecord> {
+        self.table.lock().unwrap().live(now_ms)
+    }
+}
+
+/// Exponential retry delay for when the mDNS daemon cannot be started or dies.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            current: min,
+        }
+    }
+
+    /// Delay to wait before the next attempt; doubles up to `max`.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
+
+/// Browse `_airsync._tcp` and keep `directory` current. The daemon is recreated with
+/// backoff whenever it fails to start or its event channel closes, and the browse is
+/// restarted periodically so new interfaces and stale caches are picked up.
+#[cfg(feature = "mdns")]
+pub fn spawn_peer_browser(
+    directory: PeerDirectory,
+    now_ms: fn() -> u64,
+) -> tokio::task::JoinHandle<()> {
+    use mdns_sd::{ServiceDaemon, ServiceEvent};
+
+    const REBROWSE_EVERY: Duration = Duration::from_secs(60);
+
+    tokio::spawn(async move {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        loop {
+            let browse = ServiceDaemon::new().and_then(|daemon| {
+                let events = daemon.browse(SERVICE_TYPE)?;
+                Ok((daemon, events))
+            });
+            let (daemon, events) = match browse {
+                Ok(pair) => pair,
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    eprintln!("[discovery] mDNS unavailable ({e}); retrying in {delay:?}");
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+            };
+
+            let deadline = tokio::time::Instant::now() + REBROWSE_EVERY;
+            loop {
+                let event = tokio::time::timeout_at(deadline, events.recv_async()).await;
+                directory.expire(now_ms());
+                match event {
+                    Err(_) => break,
+                    Ok(Err(_)) => {
+                        eprintln!("[discovery] mDNS event channel closed");
+                        break;
+                    }
+                    Ok(Ok(ServiceEvent::ServiceResolved(info))) => {
+                        backoff.reset();
+                        let addresses: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+                        let txt = info
+                            .get_properties()
+                            .iter()
+                            .map(|p| (p.key(), p.val_str()));
+                        match PeerRecord::from_txt(
+                            info.get_fullname(),
+                            txt,
+                            &addresses,
+                            info.get_port(),
+                            now_ms(),
+                        ) {
+                            Some(peer) => {
+                                directory.upsert(peer);
+                            }
+                            None => eprintln!(
+                                "[discovery] ignoring {} without id/address",
+                                info.get_fullname()
+                            ),
+                        }
+                    }
+                    Ok(Ok(ServiceEvent::ServiceRemoved(_, fullname))) => {
+                        directory.remove_fullname(&fullname);
+                    }
+                    Ok(Ok(_)) => {}
+                }
+            }
+            let _ = daemon.shutdown();
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const FULLNAME: &str = "Kitchen._airsync._tcp.local.";
+
+    fn lan() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20))
+    }
+
+    fn peer(id: &str, seen_ms: u64) -> PeerRecord {
+        PeerRecord::from_txt(
+            &format!("{id}._airsync._tcp.local."),
+            [("id", id), ("caps", "calibration")],
+            &[lan()],
+            5000,
+            seen_ms,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_emitted_txt_record() {
+        let txt = [
+            ("name", "Kitchen"),
+            ("ver", "1"),
+            ("api", "/api"),
+            ("caps", "calibration, multiroom"),
+            ("id", "rx-42"),
+        ];
+        let peer = PeerRecord::from_txt(FULLNAME, txt, &[lan()], 5000, 10).unwrap();
+        assert_eq!(peer.receiver_id, "rx-42");
+        assert_eq!(peer.name, "Kitchen");
+        assert_eq!(peer.address, lan());
+        assert_eq!(peer.port, 5000);
+        assert_eq!(peer.capabilities, vec!["calibration", "multiroom"]);
+        assert_eq!(peer.last_seen_ms, 10);
+    }
+
+    #[test]
+    fn falls_back_to_instance_name_and_requires_id() {
+        let peer = PeerRecord::from_txt(FULLNAME, [("id", "rx-1")], &[lan()], 5000, 0).unwrap();
+        assert_eq!(peer.name, "Kitchen");
+        assert!(peer.capabilities.is_empty());
+
+        assert!(PeerRecord::from_txt(FULLNAME, [("name", "Kitchen")], &[lan()], 5000, 0).is_none());
+        assert!(PeerRecord::from_txt(FULLNAME, [("id", " ")], &[lan()], 5000, 0).is_none());
+        assert!(PeerRecord::from_txt(FULLNAME, [("id", "rx-1")], &[], 5000, 0).is_none());
+    }
+
+    #[test]
+    fn prefers_routable_ipv4_address() {
+        let link_local_v6 = IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1));
+        let global_v6 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        let link_local_v4 = IpAddr::V4(Ipv4Addr::new(169, 254, 3, 4));
+        let address = |addresses: &[IpAddr]| {
+            PeerRecord::from_txt(FULLNAME, [("id", "rx-1")], addresses, 5000, 0).map(|p| p.address)
+        };
+        assert_eq!(address(&[link_local_v6, global_v6, link_local_v4, lan()]), Some(lan()));
+        assert_eq!(address(&[link_local_v6, global_v6]), Some(global_v6));
+    }
+
+    #[test]
+    fn table_filters_self_and_orders_peers() {
+        let mut table = PeerTable::new("rx-self", 1_000);
+        assert!(!table.upsert(peer("rx-self", 0)));
+        assert!(table.upsert(peer("rx-b", 0)));
+        assert!(table.upsert(peer("rx-a", 0)));
+        let ids: Vec<String> = table.live(0).into_iter().map(|p| p.receiver_id).collect();
+        assert_eq!(ids, vec!["rx-a", "rx-b"]);
+    }
+
+    #[test]
+    fn peers_expire_unless_refreshed() {
+        let mut table = PeerTable::new("rx-self", 1_000);
+        table.upsert(peer("rx-a", 0));
+        table.upsert(peer("rx-b", 0));
+        table.upsert(peer("rx-b", 800));
+
+        let live: Vec<String> = table.live(1_500).into_iter().map(|p| p.receiver_id).collect();
+        assert_eq!(live, vec!["rx-b"]);
+
+        table.expire(1_500);
+        assert_eq!(table.peers.len(), 1);
+        table.expire(2_000);
+        assert!(table.peers.is_empty());
+    }
+
+    #[test]
+    fn goodbye_removes_peer_by_instance_name() {
+        let directory = PeerDirectory::new("rx-self");
+        directory.upsert(peer("rx-a", 0));
+        directory.upsert(peer("rx-b", 0));
+        directory.remove_fullname("rx-a._airsync._tcp.local.");
+        let ids: Vec<String> = directory.live(0).into_iter().map(|p| p.receiver_id).collect();
+        assert_eq!(ids, vec!["rx-b"]);
+    }
+
+    #[test]
+    fn backoff_doubles_to_cap_and_resets() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+}
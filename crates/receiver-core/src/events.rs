@@ -0,0 +1,248 @@
+//! Fan-out of receiver state changes to everything that follows them: the calibration
+//! event stream, the status LED and any future push channel. Every subscriber gets its
+//! own bounded queue; one that falls behind loses its oldest events rather than holding
+//! up the others, and can tell from its drop count that it should re-read the state.
+
+use crate::airplay::ShairportConfig;
+use crate::calibration::session::SessionEvent;
+use crate::status::StatusSnapshot;
+use airsync_shared_protocol::HardwareCapabilities;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::Notify;
+
+/// Events queued per subscriber before the oldest are dropped.
+pub const EVENT_QUEUE_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Status,
+    Calibration,
+    Settings,
+    Hardware,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 4] = [
+        EventKind::Status,
+        EventKind::Calibration,
+        EventKind::Settings,
+        EventKind::Hardware,
+    ];
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", content = "data", rename_all = "snake_case")]
+pub enum ReceiverEvent {
+    Status(StatusSnapshot),
+    Calibration(SessionEvent),
+    /// The shairport-sync config after a settings change.
+    Settings(ShairportConfig),
+    /// Capabilities as last probed.
+    Hardware(HardwareCapabilities),
+}
+
+impl ReceiverEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            ReceiverEvent::Status(_) => EventKind::Status,
+            ReceiverEvent::Calibration(_) => EventKind::Calibration,
+            ReceiverEvent::Settings(_) => EventKind::Settings,
+            ReceiverEvent::Hardware(_) => EventKind::Hardware,
+        }
+    }
+}
+
+struct Queue {
+    kinds: Vec<EventKind>,
+    capacity: usize,
+    events: Mutex<VecDeque<ReceiverEvent>>,
+    dropped: AtomicU64,
+    notify: Notify,
+}
+
+impl Queue {
+    fn push(&self, event: ReceiverEvent) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() >= self.capacity {
+            events.pop_front();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        events.push_back(event);
+        drop(events);
+        self.notify.notify_one();
+    }
+}
+
+struct HubState {
+    capacity: usize,
+    /// The most recent event of each kind, handed to new subscribers.
+    latest: BTreeMap<EventKind, ReceiverEvent>,
+    subscribers: Vec<Weak<Queue>>,
+}
+
+impl Drop for HubState {
+    fn drop(&mut self) {
+        // Wake subscribers waiting on an empty queue so they see the hub is gone.
+        for queue in self.subscribers.iter().filter_map(Weak::upgrade) {
+            queue.notify.notify_one();
+        }
+    }
+}
+
+/// Publishes [`ReceiverEvent`]s to every live [`Subscription`] interested in their kind.
+#[derive(Clone)]
+pub struct EventHub {
+    state: Arc<Mutex<HubState>>,
+}
+
+impl Default for EventHub {
+    fn default() -> Self {
+        Self::with_capacity(EVENT_QUEUE_CAPACITY)
+    }
+}
+
+impl EventHub {
+    /// A hub whose subscribers each queue up to `capacity` events.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(HubState {
+                capacity: capacity.max(1),
+                latest: BTreeMap::new(),
+                subscribers: Vec::new(),
+            })),
+        }
+    }
+
+    pub fn publish(&self, event: ReceiverEvent) {
+        let mut state = self.state.lock().unwrap();
+        state.subscribers.retain(|queue| queue.strong_count() > 0);
+        let kind = event.kind();
+        // Queued under the hub lock so every subscriber sees events in the same order.
+        for queue in state.subscribers.iter().filter_map(Weak::upgrade) {
+            if queue.kinds.contains(&kind) {
+                queue.push(event.clone());
+            }
+        }
+        state.latest.insert(kind, event);
+    }
+
+    /// The latest event of each of `kinds` published so far, and a subscription to every
+    /// one of those kinds published after it.
+    pub fn subscribe(&self, kinds: &[EventKind]) -> (Vec<ReceiverEvent>, Subscription) {
+        let mut state = self.state.lock().unwrap();
+        let queue = Arc::new(Queue {
+            kinds: kinds.to_vec(),
+            capacity: state.capacity,
+            events: Mutex::new(VecDeque::new()),
+            dropped: AtomicU64::new(0),
+            notify: Notify::new(),
+        });
+        state.subscribers.push(Arc::downgrade(&queue));
+        let snapshot = kinds.iter().filter_map(|kind| state.latest.get(kind).cloned()).collect();
+        let subscription = Subscription {
+            queue,
+            hub: Arc::downgrade(&self.state),
+        };
+        (snapshot, subscription)
+    }
+
+    pub fn latest(&self, kind: EventKind) -> Option<ReceiverEvent> {
+        self.state.lock().unwrap().latest.get(&kind).cloned()
+    }
+}
+
+/// One subscriber's queue. Dropping it unsubscribes.
+pub struct Subscription {
+    queue: Arc<Queue>,
+    hub: Weak<Mutex<HubState>>,
+}
+
+impl Subscription {
+    /// The next queued event, waiting for one; `None` once the hub is gone and the queue
+    /// is drained.
+    pub async fn recv(&mut self) -> Option<ReceiverEvent> {
+        loop {
+            if let Some(event) = self.try_recv() {
+                return Some(event);
+            }
+            if self.hub.strong_count() == 0 {
+                return None;
+            }
+            self.queue.notify.notified().await;
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<ReceiverEvent> {
+        self.queue.events.lock().unwrap().pop_front()
+    }
+
+    /// Events dropped from this subscriber's queue because it fell behind.
+    pub fn dropped_count(&self) -> u64 {
+        self.queue.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use airsync_shared_protocol::PlaybackStatus;
+
+    fn status(since_ms: u64) -> ReceiverEvent {
+        ReceiverEvent::Status(StatusSnapshot {
+            status: PlaybackStatus::Playing,
+            since_ms,
+            metadata: None,
+            paired: true,
+            error: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn slow_subscribers_lose_the_oldest_events_and_keep_the_latest() {
+        let hub = EventHub::with_capacity(3);
+        let (_, mut slow) = hub.subscribe(&[EventKind::Status]);
+        let (_, mut fast) = hub.subscribe(&[EventKind::Status]);
+        for since_ms in 1..=5 {
+            hub.publish(status(since_ms));
+            assert_eq!(fast.recv().await, Some(status(since_ms)));
+        }
+        assert_eq!(fast.dropped_count(), 0);
+
+        assert_eq!(slow.dropped_count(), 2);
+        let mut received = Vec::new();
+        while let Some(event) = slow.try_recv() {
+            received.push(event);
+        }
+        assert_eq!(received, [status(3), status(4), status(5)]);
+        hub.publish(status(6));
+        assert_eq!(slow.dropped_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn late_subscribers_start_from_the_latest_state() {
+        let hub = EventHub::default();
+        hub.publish(status(1));
+        hub.publish(status(2));
+        let event = SessionEvent::Requested {
+            at_ms: 3,
+            delay_ms: 0,
+        };
+        hub.publish(ReceiverEvent::Calibration(event.clone()));
+
+        let (snapshot, mut events) = hub.subscribe(&EventKind::ALL);
+        assert_eq!(snapshot, [status(2), ReceiverEvent::Calibration(event)]);
+        assert_eq!(events.try_recv(), None);
+        let (snapshot, mut calibration_only) = hub.subscribe(&[EventKind::Calibration]);
+        assert_eq!(snapshot.len(), 1);
+        hub.publish(status(4));
+        assert_eq!(events.try_recv(), Some(status(4)));
+        assert_eq!(calibration_only.try_recv(), None);
+
+        drop(hub);
+        assert_eq!(events.recv().await, None);
+    }
+}
@@ -0,0 +1,260 @@
+//! Firmware updates pushed from a central server through `POST /api/firmware/update`.
+//! The image is downloaded to a temporary file, checked against the SHA-256 the server
+//! sent, and only then handed to the update script named by [`UPDATE_SCRIPT_ENV`]. Only
+//! the install waits in the job queue; the download runs alongside it.
+
+use crate::group::BoxFuture;
+use crate::jobs::JobError;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Script run with the verified image's path and version; updates are refused without it.
+pub const UPDATE_SCRIPT_ENV: &str = "AIRSYNC_UPDATE_SCRIPT";
+/// Longest a download may take before the update fails.
+pub const DOWNLOAD_TIMEOUT_SECS: u32 = 600;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FirmwareUpdateRequest {
+    /// Where to download the image; must be `https`.
+    pub url: String,
+    /// Hex SHA-256 of the image.
+    pub sha256: String,
+    pub version: String,
+}
+
+impl FirmwareUpdateRequest {
+    /// The error code for the first field that can't be used, if any.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if !self.url.starts_with("https://") || self.url.len() == "https://".len() {
+            return Err("invalid_url");
+        }
+        if self.sha256.len() != 64 || !self.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err("invalid_sha256");
+        }
+        if self.version.is_empty() || !self.version.chars().all(|c| c.is_ascii_alphanumeric() || "._-+".contains(c)) {
+            return Err("invalid_version");
+        }
+        Ok(())
+    }
+}
+
+/// Body returned with 202 once an update is queued.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FirmwareUpdateAccepted {
+    pub status: String,
+    pub update_id: Uuid,
+    /// The job to poll for the outcome.
+    pub status_url: String,
+}
+
+impl FirmwareUpdateAccepted {
+    pub fn new(update_id: Uuid) -> Self {
+        Self {
+            status: "accepted".into(),
+            update_id,
+            status_url: format!("/api/jobs/{update_id}"),
+        }
+    }
+}
+
+/// Result of an update whose script ran successfully.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FirmwareUpdateOutcome {
+    pub version: String,
+    pub sha256: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FirmwareUpdateError {
+    #[error("download failed: {0:#}")]
+    Download(anyhow::Error),
+    #[error("image hashes to {actual}, expected {expected}")]
+    HashMismatch { expected: String, actual: String },
+    #[error("update script failed: {0:#}")]
+    Script(anyhow::Error),
+}
+
+impl FirmwareUpdateError {
+    /// The update job's error: 502 for a failed download, 422 for a hash mismatch and
+    /// 500 for a failed script.
+    pub fn into_job_error(self) -> JobError {
+        let (status, body) = match &self {
+            FirmwareUpdateError::Download(_) => (502, serde_json::json!({"error": "download_failed", "message": self.to_string()})),
+            FirmwareUpdateError::HashMismatch { expected, actual } => (
+                422,
+                serde_json::json!({"error": "sha256_mismatch", "expected": expected, "actual": actual}),
+            ),
+            FirmwareUpdateError::Script(_) => (500, serde_json::json!({"error": "update_script_failed", "message": self.to_string()})),
+        };
+        JobError { status, body }
+    }
+}
+
+/// Downloads firmware images.
+pub trait FirmwareFetcher: Send + Sync {
+    /// Download `url` into `dest`, replacing its contents.
+    fn fetch<'a>(&'a self, url: &'a str, dest: &'a Path) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Downloads with `curl`, over HTTPS only, following redirects.
+pub struct CurlFetcher;
+
+impl FirmwareFetcher for CurlFetcher {
+    fn fetch<'a>(&'a self, url: &'a str, dest: &'a Path) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let output = tokio::process::Command::new("curl")
+                .args(["--fail", "--silent", "--show-error", "--location", "--proto", "=https"])
+                .args(["--proto-redir", "=https", "--max-time", &DOWNLOAD_TIMEOUT_SECS.to_string()])
+                .arg("--output")
+                .arg(dest)
+                .arg(url)
+                .output()
+                .await
+                .context("running curl")?;
+            if !output.status.success() {
+                return Err(anyhow!("curl {}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim()));
+            }
+            Ok(())
+        })
+    }
+}
+
+/// A downloaded image whose SHA-256 matched; the file is deleted when this is dropped.
+pub struct VerifiedImage {
+    file: tempfile::NamedTempFile,
+    sha256: String,
+}
+
+/// Downloads, verifies and installs firmware images.
+#[derive(Clone)]
+pub struct FirmwareUpdater {
+    fetcher: Arc<dyn FirmwareFetcher>,
+    script: PathBuf,
+}
+
+impl FirmwareUpdater {
+    pub fn new(fetcher: impl FirmwareFetcher + 'static, script: impl Into<PathBuf>) -> Self {
+        Self {
+            fetcher: Arc::new(fetcher),
+            script: script.into(),
+        }
+    }
+
+    /// An updater downloading with curl into the script named by [`UPDATE_SCRIPT_ENV`];
+    /// `None` when it isn't set.
+    pub fn from_env() -> Option<Self> {
+        let script = std::env::var_os(UPDATE_SCRIPT_ENV).filter(|s| !s.is_empty())?;
+        Some(Self::new(CurlFetcher, script))
+    }
+
+    /// Download the image to a temporary file and check it against the request's SHA-256.
+    pub async fn download(&self, request: &FirmwareUpdateRequest) -> Result<VerifiedImage, FirmwareUpdateError> {
+        let image = tempfile::Builder::new()
+            .prefix("airsync-firmware-")
+            .tempfile()
+            .context("creating a temporary file")
+            .map_err(FirmwareUpdateError::Download)?;
+        self.fetcher
+            .fetch(&request.url, image.path())
+            .await
+            .map_err(FirmwareUpdateError::Download)?;
+        let path = image.path().to_path_buf();
+        let actual = tokio::task::spawn_blocking(move || sha256_file(&path))
+            .await
+            .map_err(|err| FirmwareUpdateError::Download(err.into()))?
+            .context("hashing the image")
+            .map_err(FirmwareUpdateError::Download)?;
+        if !actual.eq_ignore_ascii_case(&request.sha256) {
+            eprintln!(
+                "[firmware] refusing {} from {}: sha256 {actual} instead of {}",
+                request.version, request.url, request.sha256
+            );
+            return Err(FirmwareUpdateError::HashMismatch {
+                expected: request.sha256.to_ascii_lowercase(),
+                actual,
+            });
+        }
+        Ok(VerifiedImage { file: image, sha256: actual })
+    }
+
+    /// Run the script as `<script> <image> <version>`. The image is deleted once the
+    /// script exits, so the script copies what it keeps.
+    pub async fn install(
+        &self,
+        image: VerifiedImage,
+        request: &FirmwareUpdateRequest,
+    ) -> Result<FirmwareUpdateOutcome, FirmwareUpdateError> {
+        eprintln!("[firmware] installing {} with {}", request.version, self.script.display());
+        let status = tokio::process::Command::new(&self.script)
+            .arg(image.file.path())
+            .arg(&request.version)
+            .status()
+            .await
+            .with_context(|| format!("running {}", self.script.display()))
+            .map_err(FirmwareUpdateError::Script)?;
+        if !status.success() {
+            return Err(FirmwareUpdateError::Script(anyhow!("{} exited with {status}", self.script.display())));
+        }
+        Ok(FirmwareUpdateOutcome {
+            version: request.version.clone(),
+            sha256: image.sha256,
+        })
+    }
+}
+
+fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{b:02x}")).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> FirmwareUpdateRequest {
+        FirmwareUpdateRequest {
+            url: "https://updates.example/airsync-1.2.3.bin".into(),
+            sha256: "a".repeat(64),
+            version: "1.2.3".into(),
+        }
+    }
+
+    #[test]
+    fn requests_are_validated_field_by_field() {
+        assert_eq!(request().validate(), Ok(()));
+        let with = |change: fn(&mut FirmwareUpdateRequest)| {
+            let mut request = request();
+            change(&mut request);
+            request.validate()
+        };
+        assert_eq!(with(|r| r.url = "http://updates.example/a.bin".into()), Err("invalid_url"));
+        assert_eq!(with(|r| r.url = "https://".into()), Err("invalid_url"));
+        assert_eq!(with(|r| r.sha256 = "abc".into()), Err("invalid_sha256"));
+        assert_eq!(with(|r| r.sha256 = "g".repeat(64)), Err("invalid_sha256"));
+        assert_eq!(with(|r| r.version = "1.2; rm -rf /".into()), Err("invalid_version"));
+    }
+
+    #[test]
+    fn files_hash_like_sha256sum() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), b"abc").unwrap();
+        assert_eq!(
+            sha256_file(file.path()).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,251 @@
+use crate::discovery::PeerDirectory;
+use crate::peer_client;
+use airsync_shared_protocol::{GroupAssignment, GroupConfig, GroupRelease};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// File inside the receiver state dir holding group state.
+pub const GROUP_STATE_FILE: &str = "group.json";
+
+/// This receiver's seat in a group, as assigned by the coordinator.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupMembership {
+    pub group_id: String,
+    pub coordinator_id: String,
+    pub relative_offset_ms: f32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GroupState {
+    /// Group this receiver coordinates, if any.
+    #[serde(default)]
+    pub coordinated: Option<GroupConfig>,
+    /// Group this receiver is a member of, if any (including one it coordinates).
+    #[serde(default)]
+    pub membership: Option<GroupMembership>,
+}
+
+/// Group state shared by the handlers, persisted as JSON when backed by a path.
+#[derive(Clone)]
+pub struct GroupStore {
+    path: Option<PathBuf>,
+    state: Arc<Mutex<GroupState>>,
+}
+
+impl GroupStore {
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            state: Arc::new(Mutex::new(GroupState::default())),
+        }
+    }
+
+    /// Load `<state_dir>/group.json`, starting empty if it doesn't exist yet.
+    pub fn open(state_dir: &Path) -> Result<Self> {
+        let path = state_dir.join(GROUP_STATE_FILE);
+        let state = if path.exists() {
+            let bytes = std::fs::read(&path)?;
+            serde_json::from_slice(&bytes).with_context(|| format!("parsing {}", path.display()))?
+        } else {
+            GroupState::default()
+        };
+        Ok(Self {
+            path: Some(path),
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    pub fn snapshot(&self) -> GroupState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Mutate the state under the store lock and persist it if `f` succeeds. The
+    /// in-memory state is left untouched when `f` or the write fails.
+    pub fn update<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut GroupState) -> Result<T>,
+    {
+        let mut state = self.state.lock().unwrap();
+        let mut next = state.clone();
+        let out = f(&mut next)?;
+        if let Some(path) = &self.path {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, serde_json::to_vec_pretty(&next)?)?;
+        }
+        *state = next;
+        Ok(out)
+    }
+}
+
+/// How a member answered an assignment or release.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberReply {
+    Accepted,
+    /// The member already belongs to a different group.
+    Conflict,
+}
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Carries coordinator requests to other members' `/api/group/assign`.
+pub trait GroupTransport: Send + Sync {
+    fn assign<'a>(
+        &'a self,
+        receiver_id: &'a str,
+        assignment: &'a GroupAssignment,
+    ) -> BoxFuture<'a, Result<MemberReply>>;
+
+    fn release<'a>(
+        &'a self,
+        receiver_id: &'a str,
+        release: &'a GroupRelease,
+    ) -> BoxFuture<'a, Result<MemberReply>>;
+}
+
+const MEMBER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reaches members over HTTP at the address discovery last saw them on.
+pub struct HttpGroupTransport {
+    peers: PeerDirectory,
+    now_ms: fn() -> u64,
+}
+
+impl HttpGroupTransport {
+    pub fn new(peers: PeerDirectory, now_ms: fn() -> u64) -> Self {
+        Self { peers, now_ms }
+    }
+
+    fn resolve(&self, receiver_id: &str) -> Result<SocketAddr> {
+        self.peers
+            .live((self.now_ms)())
+            .into_iter()
+            .find(|peer| peer.receiver_id == receiver_id)
+            .map(|peer| SocketAddr::new(peer.address, peer.port))
+            .ok_or_else(|| anyhow!("receiver {receiver_id} not found among discovered peers"))
+    }
+
+    async fn send<B: Serialize>(&self, receiver_id: &str, method: &str, body: &B) -> Result<MemberReply> {
+        let addr = self.resolve(receiver_id)?;
+        let body = serde_json::to_vec(body)?;
+        let response = peer_client::request(addr, method, "/api/group/assign", Some(&body), MEMBER_TIMEOUT).await?;
+        match response.status {
+            200..=299 => Ok(MemberReply::Accepted),
+            409 => Ok(MemberReply::Conflict),
+            other => Err(anyhow!("receiver {receiver_id} answered {other}")),
+        }
+    }
+}
+
+impl GroupTransport for HttpGroupTransport {
+    fn assign<'a>(
+        &'a self,
+        receiver_id: &'a str,
+        assignment: &'a GroupAssignment,
+    ) -> BoxFuture<'a, Result<MemberReply>> {
+        Box::pin(self.send(receiver_id, "POST", assignment))
+    }
+
+    fn release<'a>(
+        &'a self,
+        receiver_id: &'a str,
+        release: &'a GroupRelease,
+    ) -> BoxFuture<'a, Result<MemberReply>> {
+        Box::pin(self.send(receiver_id, "DELETE", release))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::PeerRecord;
+    use airsync_shared_protocol::GroupMember;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::{Json, Router};
+
+    fn downstairs() -> GroupConfig {
+        GroupConfig {
+            group_id: "g1".into(),
+            name: "Downstairs".into(),
+            members: vec![GroupMember {
+                receiver_id: "rx-a".into(),
+                relative_offset_ms: 0.0,
+            }],
+        }
+    }
+
+    #[test]
+    fn store_persists_and_reloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = GroupStore::open(dir.path()).unwrap();
+        assert_eq!(store.snapshot(), GroupState::default());
+
+        store
+            .update(|state| {
+                state.coordinated = Some(downstairs());
+                Ok(())
+            })
+            .unwrap();
+        let reloaded = GroupStore::open(dir.path()).unwrap();
+        assert_eq!(reloaded.snapshot().coordinated, Some(downstairs()));
+    }
+
+    #[test]
+    fn failed_update_leaves_state_untouched() {
+        let store = GroupStore::in_memory();
+        let result: Result<()> = store.update(|state| {
+            state.coordinated = Some(downstairs());
+            Err(anyhow!("nope"))
+        });
+        assert!(result.is_err());
+        assert!(store.snapshot().coordinated.is_none());
+    }
+
+    #[tokio::test]
+    async fn http_transport_reaches_discovered_member() {
+        let app = Router::new().route(
+            "/api/group/assign",
+            post(|Json(a): Json<GroupAssignment>| async move {
+                if a.group_id == "taken" {
+                    StatusCode::CONFLICT
+                } else {
+                    StatusCode::OK
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let peers = PeerDirectory::new("rx-a");
+        peers.upsert(
+            PeerRecord::from_txt(
+                "rx-b._airsync._tcp.local.",
+                [("id", "rx-b")],
+                &[addr.ip()],
+                addr.port(),
+                crate::http::now_millis(),
+            )
+            .unwrap(),
+        );
+        let transport = HttpGroupTransport::new(peers, crate::http::now_millis);
+        let mut assignment = GroupAssignment {
+            group_id: "g1".into(),
+            coordinator_id: "rx-a".into(),
+            relative_offset_ms: 5.0,
+            dry_run: true,
+        };
+        assert_eq!(transport.assign("rx-b", &assignment).await.unwrap(), MemberReply::Accepted);
+        assignment.group_id = "taken".into();
+        assert_eq!(transport.assign("rx-b", &assignment).await.unwrap(), MemberReply::Conflict);
+        assert!(transport.assign("rx-missing", &assignment).await.is_err());
+    }
+}
@@ -0,0 +1,321 @@
+use airsync_shared_protocol::{AudioOutput, CardRef, OutputDeviceSpec};
+
+/// One sound card as listed by `aplay -l`, optionally enriched with its sysfs modalias.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlsaCard {
+    pub index: u32,
+    pub id: String,
+    pub name: String,
+    pub modalias: Option<String>,
+}
+
+impl AlsaCard {
+    /// Parse a `card X: ID [Name], device Y: ...` line. Device lines that are not card
+    /// headers (e.g. `  Subdevices: 1/1`) yield `None`.
+    pub fn from_aplay_line(line: &str) -> Option<AlsaCard> {
+        let rest = line.trim().strip_prefix("card ")?;
+        let (index, rest) = rest.split_once(':')?;
+        let index = index.trim().parse().ok()?;
+        let open = rest.find('[')?;
+        let close = open + rest[open..].find(']')?;
+        let id = rest[..open].trim();
+        if id.is_empty() {
+            return None;
+        }
+        Some(AlsaCard {
+            index,
+            id: id.to_string(),
+            name: rest[open + 1..close].trim().to_string(),
+            modalias: None,
+        })
+    }
+
+    /// Parse every card in `aplay -l` output, keeping one entry per card index.
+    pub fn parse_aplay_list(output: &str) -> Vec<AlsaCard> {
+        let mut cards: Vec<AlsaCard> = Vec::new();
+        for card in output.lines().filter_map(AlsaCard::from_aplay_line) {
+            if !cards.iter().any(|c| c.index == card.index) {
+                cards.push(card);
+            }
+        }
+        cards
+    }
+
+    pub fn with_modalias(mut self, modalias: Option<String>) -> Self {
+        self.modalias = modalias.map(|m| m.trim().to_string());
+        self
+    }
+
+    /// Classify by bus first (a `usb:v` modalias is authoritative regardless of how the
+    /// driver names the card), then by the card's own id/name.
+    pub fn classify_output(&self) -> AudioOutput {
+        let label = format!("{} {}", self.id, self.name).to_lowercase();
+        match self.modalias.as_deref() {
+            Some(m) if m.starts_with("usb:v") => return AudioOutput::USB,
+            None if label.contains("usb") => return AudioOutput::USB,
+            _ => {}
+        }
+
+        if label.contains("hdmi") {
+            AudioOutput::HDMI
+        } else if label.contains("hifiberry") || label.contains("i2s") || label.contains("dac") {
+            AudioOutput::I2S
+        } else {
+            AudioOutput::Headphone
+        }
+    }
+}
+
+/// Output type behind `device`: `hw`/`plughw` devices are looked up in `cards`, `hdmi`
+/// PCMs are recognised by name. `None` when it can't be told, e.g. for `default`.
+pub fn classify_output_device(device: &OutputDeviceSpec, cards: &[AlsaCard]) -> Option<AudioOutput> {
+    match device {
+        OutputDeviceSpec::Hw { card, .. } => cards
+            .iter()
+            .find(|c| match card {
+                CardRef::Index(index) => c.index == *index,
+                CardRef::Name(id) => c.id == *id,
+            })
+            .map(AlsaCard::classify_output),
+        OutputDeviceSpec::Plug(slave) => slave
+            .parse::<OutputDeviceSpec>()
+            .ok()
+            .and_then(|slave| classify_output_device(&slave, cards)),
+        OutputDeviceSpec::Named(name) => name.to_lowercase().starts_with("hdmi").then_some(AudioOutput::HDMI),
+    }
+}
+
+/// Card index and PCM device behind `device`, resolving card ids through `cards`. `None`
+/// for PCMs that don't name a card, such as `default`.
+pub fn output_card_device(device: &OutputDeviceSpec, cards: &[AlsaCard]) -> Option<(u32, u32)> {
+    match device {
+        OutputDeviceSpec::Hw { card, device } => {
+            let index = match card {
+                CardRef::Index(index) => *index,
+                CardRef::Name(id) => cards.iter().find(|c| c.id == *id)?.index,
+            };
+            Some((index, *device))
+        }
+        OutputDeviceSpec::Plug(slave) => output_card_device(&slave.parse().ok()?, cards),
+        OutputDeviceSpec::Named(_) => None,
+    }
+}
+
+/// Sample rates a playback stream accepts, as ranges; a fixed rate is a range of one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SupportedRates(pub Vec<(u32, u32)>);
+
+impl SupportedRates {
+    /// Parse the `Playback:` section of `/proc/asound/card<C>/stream<D>`, which USB audio
+    /// cards expose. Rates are listed per altset, either as `32000, 44100` or as a
+    /// `8000 - 48000 (continuous)` range. `None` when the section lists no rates.
+    pub fn from_proc_stream(contents: &str) -> Option<SupportedRates> {
+        let mut ranges = Vec::new();
+        let mut in_playback = false;
+        for line in contents.lines() {
+            if !line.starts_with(' ') && !line.is_empty() {
+                in_playback = line.trim_end() == "Playback:";
+                continue;
+            }
+            let Some(rates) = line.trim().strip_prefix("Rates:").filter(|_| in_playback) else {
+                continue;
+            };
+            let rates = rates.trim().trim_end_matches("(continuous)").trim();
+            if let Some((min, max)) = rates.split_once(" - ") {
+                if let (Ok(min), Ok(max)) = (min.trim().parse(), max.trim().parse()) {
+                    ranges.push((min, max));
+                }
+            } else {
+                ranges.extend(rates.split(',').filter_map(|r| r.trim().parse().ok()).map(|r| (r, r)));
+            }
+        }
+        (!ranges.is_empty()).then_some(SupportedRates(ranges))
+    }
+
+    pub fn supports(&self, rate: u32) -> bool {
+        self.0.iter().any(|&(min, max)| (min..=max).contains(&rate))
+    }
+
+    /// `preferred` when supported, else the closest supported rate, preferring the higher
+    /// one on a tie.
+    pub fn nearest(&self, preferred: u32) -> u32 {
+        self.0
+            .iter()
+            .map(|&(min, max)| preferred.clamp(min, max))
+            .min_by_key(|&rate| (rate.abs_diff(preferred), std::cmp::Reverse(rate)))
+            .unwrap_or(preferred)
+    }
+}
+
+/// Rewrite `/proc/asound/cards` (` 0 [Headphones     ]: driver - Long Name`) as the
+/// equivalent `aplay -l` card lines so both sources share one parser.
+pub fn aplay_list_from_proc_cards(contents: &str) -> String {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (index, rest) = line.trim_start().split_once(' ')?;
+            let index: u32 = index.parse().ok()?;
+            let rest = rest.trim_start().strip_prefix('[')?;
+            let (id, rest) = rest.split_once(']')?;
+            let (_, name) = rest.split_once(" - ")?;
+            Some(format!("card {index}: {} [{}]", id.trim(), name.trim()))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PI4_BOOKWORM: &str = "\
+**** List of PLAYBACK Hardware Devices ****
+card 0: Headphones [bcm2835 Headphones], device 0: bcm2835 Headphones [bcm2835 Headphones]
+  Subdevices: 8/8
+  Subdevice #0: subdevice #0
+card 1: vc4hdmi0 [vc4-hdmi-0], device 0: MAI PCM i2s-hifi-0 [MAI PCM i2s-hifi-0]
+  Subdevices: 1/1
+  Subdevice #0: subdevice #0
+card 2: vc4hdmi1 [vc4-hdmi-1], device 0: MAI PCM i2s-hifi-0 [MAI PCM i2s-hifi-0]
+  Subdevices: 1/1";
+
+    const UBUNTU_UAC2: &str = "\
+**** List of PLAYBACK Hardware Devices ****
+card 0: PCH [HDA Intel PCH], device 0: ALC257 Analog [ALC257 Analog]
+  Subdevices: 1/1
+card 1: Gadget [UAC2 Audio], device 0: UAC2 PCM [UAC2 PCM]
+  Subdevices: 1/1";
+
+    const HIFIBERRY: &str = "\
+card 0: sndrpihifiberry [snd_rpi_hifiberry_dacplus], device 0: HiFiBerry DAC+ HiFi pcm512x-hifi-0 [HiFiBerry DAC+ HiFi pcm512x-hifi-0]
+  Subdevices: 1/1";
+
+    #[test]
+    fn parses_card_header_line() {
+        let card = AlsaCard::from_aplay_line(
+            "card 1: vc4hdmi0 [vc4-hdmi-0], device 0: MAI PCM i2s-hifi-0 [MAI PCM i2s-hifi-0]",
+        )
+        .unwrap();
+        assert_eq!(card.index, 1);
+        assert_eq!(card.id, "vc4hdmi0");
+        assert_eq!(card.name, "vc4-hdmi-0");
+        assert_eq!(card.modalias, None);
+    }
+
+    #[test]
+    fn ignores_non_card_lines() {
+        assert!(AlsaCard::from_aplay_line("  Subdevices: 1/1").is_none());
+        assert!(AlsaCard::from_aplay_line("**** List of PLAYBACK Hardware Devices ****").is_none());
+        assert!(AlsaCard::from_aplay_line("card x: Foo [Bar]").is_none());
+    }
+
+    #[test]
+    fn dedupes_cards_with_multiple_devices() {
+        let output = "card 0: PCH [HDA Intel PCH], device 0: ALC257 Analog [ALC257 Analog]\n\
+                      card 0: PCH [HDA Intel PCH], device 3: HDMI 0 [HDMI 0]";
+        assert_eq!(AlsaCard::parse_aplay_list(output).len(), 1);
+    }
+
+    #[test]
+    fn pi4_hdmi_is_not_mistaken_for_i2s() {
+        let kinds: Vec<AudioOutput> = AlsaCard::parse_aplay_list(PI4_BOOKWORM)
+            .iter()
+            .map(AlsaCard::classify_output)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![AudioOutput::Headphone, AudioOutput::HDMI, AudioOutput::HDMI]
+        );
+    }
+
+    #[test]
+    fn usb_modalias_wins_over_card_name() {
+        let cards: Vec<AlsaCard> = AlsaCard::parse_aplay_list(UBUNTU_UAC2)
+            .into_iter()
+            .map(|c| {
+                let modalias = (c.index == 1).then(|| "usb:v1D6Bp0104d0510dc00dsc00dp00\n".to_string());
+                c.with_modalias(modalias)
+            })
+            .collect();
+        assert_eq!(cards[0].classify_output(), AudioOutput::Headphone);
+        assert_eq!(cards[1].classify_output(), AudioOutput::USB);
+        assert_eq!(cards[1].modalias.as_deref(), Some("usb:v1D6Bp0104d0510dc00dsc00dp00"));
+    }
+
+    #[test]
+    fn usb_headphone_adapter_classifies_as_usb() {
+        let card = AlsaCard::from_aplay_line(
+            "card 2: A [Apple USB-C to 3.5mm Headphone Jack Adapter], device 0: USB Audio [USB Audio]",
+        )
+        .unwrap()
+        .with_modalias(Some("usb:v05ACp110Ad0101dc00dsc00dp00".into()));
+        assert_eq!(card.classify_output(), AudioOutput::USB);
+    }
+
+    #[test]
+    fn platform_modalias_falls_back_to_name() {
+        let card = AlsaCard::parse_aplay_list(HIFIBERRY)
+            .remove(0)
+            .with_modalias(Some("of:NsoundT(null)Csimple-audio-card".into()));
+        assert_eq!(card.classify_output(), AudioOutput::I2S);
+    }
+
+    #[test]
+    fn converts_proc_cards_to_aplay_form() {
+        let proc_cards = " 0 [Headphones     ]: bcm2835_headpho - bcm2835 Headphones
+                      bcm2835 Headphones
+ 1 [vc4hdmi0       ]: vc4-hdmi - vc4-hdmi-0
+                      vc4-hdmi-0
+ 2 [Gadget         ]: USB-Audio - UAC2 Audio
+                      Linux Gadget UAC2 Audio at usb-xhci-hcd.0-1, high speed";
+        let cards = AlsaCard::parse_aplay_list(&aplay_list_from_proc_cards(proc_cards));
+        let summary: Vec<(u32, &str, &str)> = cards
+            .iter()
+            .map(|c| (c.index, c.id.as_str(), c.name.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (0, "Headphones", "bcm2835 Headphones"),
+                (1, "vc4hdmi0", "vc4-hdmi-0"),
+                (2, "Gadget", "UAC2 Audio"),
+            ]
+        );
+    }
+
+    #[test]
+    fn classifies_configured_output_devices() {
+        let cards = AlsaCard::parse_aplay_list(HIFIBERRY)
+            .into_iter()
+            .chain(AlsaCard::parse_aplay_list("card 1: Headphones [bcm2835 Headphones]"))
+            .collect::<Vec<_>>();
+        let classify = |device: &str| classify_output_device(&device.parse().unwrap(), &cards);
+        assert_eq!(classify("hw:0,0"), Some(AudioOutput::I2S));
+        assert_eq!(classify("plughw:1,0"), Some(AudioOutput::Headphone));
+        assert_eq!(classify("hw:CARD=Headphones,DEV=0"), Some(AudioOutput::Headphone));
+        assert_eq!(classify("hdmi:CARD=vc4hdmi0"), Some(AudioOutput::HDMI));
+        assert_eq!(classify("hw:3,0"), None);
+        assert_eq!(classify("default"), None);
+    }
+
+    #[test]
+    fn parses_playback_rates_from_proc_stream() {
+        let rates = SupportedRates::from_proc_stream(include_str!("fixtures/asound-stream-usb-44100")).unwrap();
+        assert_eq!(rates, SupportedRates(vec![(44_100, 44_100), (32_000, 32_000), (44_100, 44_100)]));
+        assert!(!rates.supports(48_000));
+        assert_eq!(rates.nearest(48_000), 44_100);
+
+        let continuous = SupportedRates::from_proc_stream("Playback:\n  Interface 1\n    Rates: 8000 - 48000 (continuous)\n").unwrap();
+        assert!(continuous.supports(48_000));
+        assert_eq!(continuous.nearest(96_000), 48_000);
+        assert_eq!(SupportedRates::from_proc_stream("Capture:\n    Rates: 48000\n"), None);
+    }
+
+    #[test]
+    fn resolves_the_output_card() {
+        let cards = AlsaCard::parse_aplay_list(UBUNTU_UAC2);
+        assert_eq!(output_card_device(&OutputDeviceSpec::hw(1, 0), &cards), Some((1, 0)));
+        assert_eq!(output_card_device(&"plughw:CARD=Gadget,DEV=0".parse().unwrap(), &cards), Some((1, 0)));
+        assert_eq!(output_card_device(&OutputDeviceSpec::default(), &cards), None);
+    }
+}
@@ -1,6 +1,13 @@
-use airsync_shared_protocol::{AudioOutput, HardwareCapabilities};
-use anyhow::Result;
+#[cfg(feature = "embedded")]
+use super::aplay_list_from_proc_cards;
+use super::AlsaCard;
+use airsync_shared_protocol::{AudioOutput, HardwareCapabilities, OsInfo};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
 use std::fs;
+use std::path::Path;
+use std::time::Duration;
+#[cfg(not(feature = "embedded"))]
 use std::process::Command;
 
 pub trait SystemReaders: Send + Sync {
@@ -8,6 +15,81 @@ pub trait SystemReaders: Send + Sync {
     fn read_mem_info(&self) -> Result<String>;
     fn read_device_tree(&self) -> Result<Option<String>>;
     fn list_alsa_devices(&self) -> Result<String>;
+    /// Contents of `/sys/class/sound/card<index>/device/modalias`, if present.
+    fn read_card_modalias(&self, index: u32) -> Result<Option<String>>;
+    fn read_thermal(&self) -> Result<ThermalSources>;
+    /// Output of `df -B1M -T` for the filesystem holding [`STORAGE_PATH`].
+    fn read_df_output(&self) -> Result<String>;
+    fn read_os_release(&self) -> Result<String>;
+    /// Contents of `/proc/version`.
+    fn read_kernel_version(&self) -> Result<String>;
+}
+
+/// Calibration signals and receiver state live on the root filesystem.
+pub const STORAGE_PATH: &str = "/";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageInfo {
+    pub device: String,
+    pub total_mb: u64,
+    pub available_mb: u64,
+    pub filesystem: String,
+}
+
+/// Parse `df -B1M -T` output for a single filesystem. Older `df`s put a long device name
+/// on a line of its own, so the row is read as one run of fields after the header.
+pub fn parse_df_output(output: &str) -> Result<StorageInfo> {
+    let fields: Vec<&str> = output.lines().skip(1).flat_map(str::split_whitespace).collect();
+    let [device, filesystem, total, _used, available, ..] = fields[..] else {
+        return Err(anyhow!("unexpected df output: {output:?}"));
+    };
+    let megabytes = |field: &str| {
+        field
+            .trim_end_matches('M')
+            .parse::<u64>()
+            .map_err(|_| anyhow!("unexpected df size {field:?}"))
+    };
+    Ok(StorageInfo {
+        device: device.to_string(),
+        total_mb: megabytes(total)?,
+        available_mb: megabytes(available)?,
+        filesystem: filesystem.to_string(),
+    })
+}
+
+/// Parse the distribution from `os-release` contents and the kernel release from
+/// `/proc/version`. A missing `ID` means `linux`, as os-release(5) specifies.
+pub fn parse_os_info(os_release: &str, proc_version: &str) -> Result<OsInfo> {
+    let field = |key: &str| {
+        os_release.lines().find_map(|line| {
+            let value = line.trim().strip_prefix(key)?.strip_prefix('=')?;
+            let unquoted = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+                .unwrap_or(value);
+            Some(unquoted.replace("\\\"", "\"").replace("\\\\", "\\"))
+        })
+    };
+    let kernel = match proc_version.split_whitespace().collect::<Vec<_>>()[..] {
+        ["Linux", "version", release, ..] => release.to_string(),
+        _ => return Err(anyhow!("unexpected /proc/version: {:?}", proc_version.trim())),
+    };
+    Ok(OsInfo {
+        distro: field("ID").unwrap_or_else(|| "linux".to_string()),
+        version: field("VERSION_ID").unwrap_or_default(),
+        pretty_name: field("PRETTY_NAME").unwrap_or_default(),
+        kernel,
+    })
+}
+
+/// Raw thermal readings; each is `None` where the source doesn't exist on this system.
+#[derive(Debug, Clone, Default)]
+pub struct ThermalSources {
+    /// `/sys/class/thermal/thermal_zone0/temp`, in millidegrees Celsius.
+    pub zone_temp: Option<String>,
+    /// Output of `vcgencmd get_throttled`, e.g. `throttled=0x50005`.
+    pub throttled: Option<String>,
 }
 
 pub struct DefaultSystemReaders;
@@ -28,6 +110,14 @@ impl SystemReaders for DefaultSystemReaders {
         }
     }
 
+    /// Embedded images have no alsa-utils; the kernel's card list is rewritten into
+    /// `aplay -l` form instead.
+    #[cfg(feature = "embedded")]
+    fn list_alsa_devices(&self) -> Result<String> {
+        Ok(aplay_list_from_proc_cards(&fs::read_to_string("/proc/asound/cards")?))
+    }
+
+    #[cfg(not(feature = "embedded"))]
     fn list_alsa_devices(&self) -> Result<String> {
         let output = Command::new("aplay")
             .arg("-l")
@@ -36,8 +126,108 @@ impl SystemReaders for DefaultSystemReaders {
 
         Ok(String::from_utf8_lossy(&output.stdout).to_string())
     }
+
+    fn read_card_modalias(&self, index: u32) -> Result<Option<String>> {
+        match fs::read_to_string(format!("/sys/class/sound/card{index}/device/modalias")) {
+            Ok(content) => Ok(Some(content)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn read_thermal(&self) -> Result<ThermalSources> {
+        Ok(ThermalSources {
+            zone_temp: fs::read_to_string("/sys/class/thermal/thermal_zone0/temp").ok(),
+            throttled: read_throttled(),
+        })
+    }
+
+    fn read_df_output(&self) -> Result<String> {
+        let output = std::process::Command::new("df")
+            .args(["-B1M", "-T", STORAGE_PATH])
+            .output()
+            .map_err(|e| anyhow!("Failed to execute df: {}", e))?;
+        if !output.status.success() {
+            return Err(anyhow!("df failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn read_os_release(&self) -> Result<String> {
+        fs::read_to_string("/etc/os-release")
+            .or_else(|_| fs::read_to_string("/usr/lib/os-release"))
+            .map_err(|e| anyhow!("Failed to read os-release: {}", e))
+    }
+
+    fn read_kernel_version(&self) -> Result<String> {
+        Ok(fs::read_to_string("/proc/version")?)
+    }
+}
+
+/// Embedded images don't ship the Raspberry Pi userland.
+#[cfg(feature = "embedded")]
+fn read_throttled() -> Option<String> {
+    None
+}
+
+#[cfg(not(feature = "embedded"))]
+fn read_throttled() -> Option<String> {
+    let output = Command::new("vcgencmd").arg("get_throttled").output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Firmware throttle flags that describe the current state (under-voltage, frequency
+/// capped, throttled, soft temperature limit); higher bits only record past events.
+const THROTTLED_NOW_MASK: u32 = 0xf;
+
+fn parse_zone_temp(raw: &str) -> Option<f32> {
+    raw.trim().parse::<i64>().ok().map(|milli| milli as f32 / 1000.0)
+}
+
+fn parse_throttled(raw: &str) -> Option<bool> {
+    let hex = raw.trim().strip_prefix("throttled=")?.strip_prefix("0x")?;
+    u32::from_str_radix(hex, 16).ok().map(|flags| flags & THROTTLED_NOW_MASK != 0)
+}
+
+/// Below this estimated bandwidth a minimal-profile receiver is likely to underrun.
+pub const MIN_MEMORY_BANDWIDTH_MBPS: u64 = 2_000;
+
+/// Value of a `<key>: <n> kB` line of `/proc/meminfo`.
+fn meminfo_kb(meminfo: &str, key: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let rest = line.strip_prefix(key)?.strip_prefix(':')?;
+        rest.split_whitespace().next()?.parse().ok()
+    })
+}
+
+/// Rough memory bandwidth in MB/s from `/proc/meminfo`, for judging underrun risk. The
+/// kernel doesn't report memory speed, so this is a hint, not a measurement: boards are
+/// placed by total RAM (512 MB boards ship LPDDR2, larger ones LPDDR4/4X), then marked
+/// down by a quarter when swap is configured and the page cache is under a tenth of RAM
+/// (audio buffers may be paged out) and by half when the kernel has poisoned corrupted
+/// pages. `None` without `MemTotal`.
+pub fn estimate_memory_bandwidth(meminfo: &str) -> Option<u64> {
+    let total_kb = meminfo_kb(meminfo, "MemTotal")?;
+    let mut mbps: u64 = match total_kb / 1024 {
+        0..=1023 => 1_600,
+        1024..=4095 => 3_200,
+        _ => 6_400,
+    };
+    let cached_kb = meminfo_kb(meminfo, "Cached").unwrap_or(0);
+    if meminfo_kb(meminfo, "SwapTotal").unwrap_or(0) > 0 && cached_kb * 10 < total_kb {
+        mbps = mbps * 3 / 4;
+    }
+    if meminfo_kb(meminfo, "HardwareCorrupted").unwrap_or(0) > 0 {
+        mbps /= 2;
+    }
+    Some(mbps)
 }
 
+/// Caches older than this (by mtime) are re-detected.
+pub const HARDWARE_CACHE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
 pub struct HardwareDetector<R: SystemReaders> {
     readers: R,
 }
@@ -61,14 +251,87 @@ impl<R: SystemReaders> HardwareDetector<R> {
         let board_id = self.detect_board_id()?;
         let audio_outputs = self.detect_audio_outputs()?;
         let preferred_output = self.select_preferred_output(&audio_outputs);
+        let (temperature_c, throttled) = self.detect_thermal();
 
-        Ok(HardwareCapabilities {
+        let capabilities = HardwareCapabilities {
             cpu_cores,
             ram_mb,
             board_id,
             audio_outputs,
             preferred_output,
-        })
+            temperature_c,
+            throttled,
+            available_mb: self.detect_available_mb(),
+            os: self.detect_os(),
+            memory_speed_hint: self.detect_memory_speed()?,
+        };
+        if let Some(mbps) = capabilities.memory_speed_hint {
+            if mbps < MIN_MEMORY_BANDWIDTH_MBPS && capabilities.is_minimal() {
+                eprintln!(
+                    "[hardware] warning: estimated memory bandwidth {mbps} MB/s is below {MIN_MEMORY_BANDWIDTH_MBPS} MB/s; audio may underrun"
+                );
+            }
+        }
+        Ok(capabilities)
+    }
+
+    /// [`estimate_memory_bandwidth`] for this system.
+    pub fn detect_memory_speed(&self) -> Result<Option<u64>> {
+        Ok(estimate_memory_bandwidth(&self.readers.read_mem_info()?))
+    }
+
+    /// Capabilities from the cache at `path` unless it is missing, unreadable, older than
+    /// `max_age` or `force` is set; otherwise detect and rewrite it. Thermal state, free space
+    /// and the OS (the kernel changes with an upgrade and reboot) are always read fresh.
+    /// Failing to write the cache only logs a warning.
+    pub fn load_or_detect(&self, path: &Path, max_age: Duration, force: bool) -> Result<HardwareCapabilities> {
+        if !force && cache_age(path).is_some_and(|age| age <= max_age) {
+            match HardwareCapabilities::load(path) {
+                Ok(mut caps) => {
+                    (caps.temperature_c, caps.throttled) = self.detect_thermal();
+                    caps.available_mb = self.detect_available_mb();
+                    caps.os = self.detect_os();
+                    return Ok(caps);
+                }
+                Err(e) => eprintln!("[hardware] ignoring unreadable cache {}: {e}", path.display()),
+            }
+        }
+        let caps = self.detect()?;
+        if let Err(e) = caps.save(path) {
+            eprintln!("[hardware] failed to write cache {}: {e}", path.display());
+        }
+        Ok(caps)
+    }
+
+    /// Temperature and throttle state; unreadable or malformed sources are reported as unknown.
+    pub fn detect_thermal(&self) -> (Option<f32>, Option<bool>) {
+        let sources = self.readers.read_thermal().unwrap_or_default();
+        let temperature_c = sources.zone_temp.as_deref().and_then(parse_zone_temp);
+        let throttled = sources.throttled.as_deref().and_then(parse_throttled);
+        if throttled == Some(true) {
+            eprintln!(
+                "[hardware] warning: CPU is throttled ({}, temperature {:?}C); audio may glitch",
+                sources.throttled.as_deref().unwrap_or_default().trim(),
+                temperature_c
+            );
+        }
+        (temperature_c, throttled)
+    }
+
+    pub fn detect_storage(&self) -> Result<StorageInfo> {
+        parse_df_output(&self.readers.read_df_output()?)
+    }
+
+    /// Free space for [`HardwareCapabilities`]; unknown when `df` can't be run or parsed.
+    pub fn detect_available_mb(&self) -> Option<u64> {
+        self.detect_storage().ok().map(|storage| storage.available_mb)
+    }
+
+    /// Distribution and kernel for [`HardwareCapabilities`]; unknown when either can't be read.
+    pub fn detect_os(&self) -> Option<OsInfo> {
+        let os_release = self.readers.read_os_release().ok()?;
+        let proc_version = self.readers.read_kernel_version().ok()?;
+        parse_os_info(&os_release, &proc_version).ok()
     }
 
     fn detect_cpu_cores(&self) -> Result<usize> {
@@ -120,24 +383,36 @@ impl<R: SystemReaders> HardwareDetector<R> {
         Ok("unknown".to_string())
     }
 
+    /// Playback cards with their sysfs modaliases, for classifying output devices.
+    pub fn detect_alsa_cards(&self) -> Result<Vec<AlsaCard>> {
+        let alsa_devices = self.readers.list_alsa_devices()?;
+        AlsaCard::parse_aplay_list(&alsa_devices)
+            .into_iter()
+            .map(|card| {
+                let modalias = self.readers.read_card_modalias(card.index)?;
+                Ok(card.with_modalias(modalias))
+            })
+            .collect()
+    }
+
     fn detect_audio_outputs(&self) -> Result<Vec<AudioOutput>> {
         let mut outputs = Vec::new();
         let device_tree = self.readers.read_device_tree()?;
-        let alsa_devices = self.readers.list_alsa_devices()?;
+        let cards = self.detect_alsa_cards()?;
 
-        if self.has_i2s_dac(&device_tree, &alsa_devices) {
+        if self.has_i2s_dac(&device_tree, &cards) {
             outputs.push(AudioOutput::I2S);
         }
 
-        if self.has_usb_audio(&alsa_devices) {
+        if self.has_usb_audio(&cards) {
             outputs.push(AudioOutput::USB);
         }
 
-        if self.has_hdmi_audio(&alsa_devices) {
+        if self.has_hdmi_audio(&cards) {
             outputs.push(AudioOutput::HDMI);
         }
 
-        if self.has_headphone_jack(&alsa_devices) {
+        if self.has_headphone_jack(&cards) {
             outputs.push(AudioOutput::Headphone);
         }
 
@@ -148,27 +423,26 @@ impl<R: SystemReaders> HardwareDetector<R> {
         Ok(outputs)
     }
 
-    fn has_i2s_dac(&self, device_tree: &Option<String>, alsa_devices: &str) -> bool {
+    fn has_i2s_dac(&self, device_tree: &Option<String>, cards: &[AlsaCard]) -> bool {
         if let Some(dt) = device_tree {
             if dt.contains("HiFiBerry") {
                 return true;
             }
         }
 
-        let alsa_lower = alsa_devices.to_lowercase();
-        alsa_lower.contains("hifiberry") || alsa_lower.contains("i2s")
+        has_output(cards, AudioOutput::I2S)
     }
 
-    fn has_usb_audio(&self, alsa_devices: &str) -> bool {
-        alsa_devices.to_lowercase().contains("usb audio")
+    fn has_usb_audio(&self, cards: &[AlsaCard]) -> bool {
+        has_output(cards, AudioOutput::USB)
     }
 
-    fn has_hdmi_audio(&self, alsa_devices: &str) -> bool {
-        alsa_devices.to_lowercase().contains("hdmi")
+    fn has_hdmi_audio(&self, cards: &[AlsaCard]) -> bool {
+        has_output(cards, AudioOutput::HDMI)
     }
 
-    fn has_headphone_jack(&self, alsa_devices: &str) -> bool {
-        alsa_devices.contains("Headphones") || alsa_devices.contains("bcm2835")
+    fn has_headphone_jack(&self, cards: &[AlsaCard]) -> bool {
+        has_output(cards, AudioOutput::Headphone)
     }
 
     fn select_preferred_output(&self, outputs: &[AudioOutput]) -> AudioOutput {
@@ -189,34 +463,19 @@ impl<R: SystemReaders> HardwareDetector<R> {
     }
 }
 
+/// Time since `path` was last written; `None` if it is missing or dated in the future.
+fn cache_age(path: &Path) -> Option<Duration> {
+    fs::metadata(path).ok()?.modified().ok()?.elapsed().ok()
+}
+
+fn has_output(cards: &[AlsaCard], kind: AudioOutput) -> bool {
+    cards.iter().any(|card| card.classify_output() == kind)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-
-    struct MockSystemReaders {
-        cpu_info: String,
-        mem_info: String,
-        device_tree: Option<String>,
-        alsa_devices: String,
-    }
-
-    impl SystemReaders for MockSystemReaders {
-        fn read_cpu_info(&self) -> Result<String> {
-            Ok(self.cpu_info.clone())
-        }
-
-        fn read_mem_info(&self) -> Result<String> {
-            Ok(self.mem_info.clone())
-        }
-
-        fn read_device_tree(&self) -> Result<Option<String>> {
-            Ok(self.device_tree.clone())
-        }
-
-        fn list_alsa_devices(&self) -> Result<String> {
-            Ok(self.alsa_devices.clone())
-        }
-    }
+    use crate::test_util::MockSystemReaders;
 
     fn pi_zero_2_w_mock() -> MockSystemReaders {
         MockSystemReaders {
@@ -224,6 +483,7 @@ mod tests {
             mem_info: "MemTotal:        465920 kB\nMemFree:         123456 kB".to_string(),
             device_tree: None,
             alsa_devices: "card 0: Headphones [bcm2835 Headphones], device 0: bcm2835 Headphones".to_string(),
+            ..Default::default()
         }
     }
 
@@ -233,6 +493,7 @@ mod tests {
             mem_info: "MemTotal:        3964928 kB".to_string(),
             device_tree: Some("simple-audio-card,name = \"HiFiBerry DAC+\"".to_string()),
             alsa_devices: "card 0: sndrpihifiberry [snd_rpi_hifiberry_dac]\ncard 1: Headphones [bcm2835 Headphones]".to_string(),
+            ..Default::default()
         }
     }
 
@@ -242,6 +503,8 @@ mod tests {
             mem_info: "MemTotal:        8125440 kB".to_string(),
             device_tree: None,
             alsa_devices: "card 0: Device [USB Audio Device]\ncard 1: Headphones [bcm2835 Headphones]".to_string(),
+            card_modaliases: [(0, "usb:v0D8Cp0014d0100dc00dsc00dp00".to_string())].into(),
+            ..Default::default()
         }
     }
 
@@ -259,6 +522,31 @@ mod tests {
         assert!(caps.ram_mb > 400 && caps.ram_mb < 500);
     }
 
+    #[test]
+    fn memory_bandwidth_hint_follows_meminfo() {
+        let pi4 = include_str!("fixtures/meminfo-pi4-4gb");
+        assert_eq!(estimate_memory_bandwidth(pi4), Some(3_200));
+
+        let zero = "MemTotal:         439668 kB\nCached:           180220 kB\nSwapTotal:        102396 kB\nHardwareCorrupted:     0 kB";
+        assert_eq!(estimate_memory_bandwidth(zero), Some(1_600));
+        let tight = "MemTotal:         439668 kB\nCached:            20000 kB\nSwapTotal:        102396 kB";
+        assert_eq!(estimate_memory_bandwidth(tight), Some(1_200));
+        let tight_without_swap = "MemTotal:         439668 kB\nCached:            20000 kB\nSwapTotal:             0 kB";
+        assert_eq!(estimate_memory_bandwidth(tight_without_swap), Some(1_600));
+        let corrupted = pi4.replace("HardwareCorrupted:     0 kB", "HardwareCorrupted:     4 kB");
+        assert_eq!(estimate_memory_bandwidth(&corrupted), Some(1_600));
+        assert_eq!(estimate_memory_bandwidth("MemTotal:        8125440 kB"), Some(6_400));
+        assert_eq!(estimate_memory_bandwidth("Cached: 1 kB"), None);
+    }
+
+    #[test]
+    fn detect_reports_the_memory_speed_hint() {
+        let caps = HardwareDetector::new(pi_5_with_usb_audio_mock()).detect().unwrap();
+        assert_eq!(caps.memory_speed_hint, Some(6_400));
+        let caps = HardwareDetector::new(pi_zero_2_w_mock()).detect().unwrap();
+        assert!(caps.memory_speed_hint.is_some_and(|mbps| mbps < MIN_MEMORY_BANDWIDTH_MBPS));
+    }
+
     #[test]
     fn identifies_raspberry_pi_zero_2_w() {
         let detector = HardwareDetector::new(pi_zero_2_w_mock());
@@ -303,4 +591,239 @@ mod tests {
         assert!(caps.audio_outputs.contains(&AudioOutput::Headphone));
         assert_eq!(caps.preferred_output, AudioOutput::Headphone);
     }
+
+    #[test]
+    fn detects_usb_audio_by_modalias_when_name_differs() {
+        let detector = HardwareDetector::new(MockSystemReaders {
+            alsa_devices: "card 0: PCH [HDA Intel PCH], device 0: ALC257 Analog [ALC257 Analog]\n\
+                           card 1: Gadget [UAC2 Audio], device 0: UAC2 PCM [UAC2 PCM]"
+                .to_string(),
+            card_modaliases: [(1, "usb:v1D6Bp0104d0510dc00dsc00dp00".to_string())].into(),
+            ..Default::default()
+        });
+        let caps = detector.detect().unwrap();
+        assert_eq!(caps.audio_outputs, vec![AudioOutput::USB, AudioOutput::Headphone]);
+        assert_eq!(caps.preferred_output, AudioOutput::USB);
+    }
+
+    #[test]
+    fn reports_temperature_and_throttling_when_present() {
+        let detector = HardwareDetector::new(MockSystemReaders {
+            thermal: ThermalSources {
+                zone_temp: Some("61234\n".to_string()),
+                throttled: Some("throttled=0x50005\n".to_string()),
+            },
+            ..pi_zero_2_w_mock()
+        });
+        let caps = detector.detect().unwrap();
+        assert!((caps.temperature_c.unwrap() - 61.234).abs() < 1e-3);
+        assert_eq!(caps.throttled, Some(true));
+
+        let cool = HardwareDetector::new(MockSystemReaders {
+            thermal: ThermalSources {
+                zone_temp: Some("45000".to_string()),
+                throttled: Some("throttled=0x50000".to_string()),
+            },
+            ..Default::default()
+        });
+        assert_eq!(cool.detect_thermal(), (Some(45.0), Some(false)));
+    }
+
+    #[test]
+    fn absent_thermal_sources_are_unknown() {
+        let caps = HardwareDetector::new(pi_zero_2_w_mock()).detect().unwrap();
+        assert_eq!((caps.temperature_c, caps.throttled), (None, None));
+    }
+
+    #[test]
+    fn malformed_thermal_sources_are_unknown() {
+        for (zone_temp, throttled) in [("hot", "throttled=zero"), ("", "0x5"), ("48.5", "throttled=0x")] {
+            let detector = HardwareDetector::new(MockSystemReaders {
+                thermal: ThermalSources {
+                    zone_temp: Some(zone_temp.to_string()),
+                    throttled: Some(throttled.to_string()),
+                },
+                ..Default::default()
+            });
+            assert_eq!(detector.detect_thermal(), (None, None), "{zone_temp:?} {throttled:?}");
+        }
+    }
+
+    #[test]
+    fn parses_df_output() {
+        let output = "\
+Filesystem     Type 1M-blocks  Used Available Use% Mounted on
+/dev/mmcblk0p2 ext4     29340  4321     23689  16% /
+";
+        let storage = parse_df_output(output).unwrap();
+        assert_eq!(
+            storage,
+            StorageInfo {
+                device: "/dev/mmcblk0p2".into(),
+                total_mb: 29340,
+                available_mb: 23689,
+                filesystem: "ext4".into(),
+            }
+        );
+
+        // A long device name pushed onto its own line, and BusyBox's `M` suffixes.
+        let wrapped = "\
+Filesystem     Type 1M-blocks  Used Available Use% Mounted on
+/dev/disk/by-uuid/0123456789abcdef
+               ext4     29340  4321     23689  16% /
+";
+        assert_eq!(
+            parse_df_output(wrapped).unwrap(),
+            StorageInfo {
+                device: "/dev/disk/by-uuid/0123456789abcdef".into(),
+                ..storage
+            }
+        );
+        let suffixed = "Filesystem Type Size Used Available Use% Mounted on\n/dev/root ext4 29340M 4321M 23689M 16% /\n";
+        assert_eq!(parse_df_output(suffixed).unwrap().available_mb, 23689);
+    }
+
+    #[test]
+    fn parses_df_output_at_capacity() {
+        // Root reserves blocks, so a full card reports no space even with `Used` below the total.
+        let full = "\
+Filesystem     Type 1M-blocks  Used Available Use% Mounted on
+/dev/root      ext4      7179  6812         0 100% /
+";
+        let storage = parse_df_output(full).unwrap();
+        assert_eq!((storage.total_mb, storage.available_mb), (7179, 0));
+
+        let detector = HardwareDetector::new(MockSystemReaders {
+            df_output: full.into(),
+            ..pi_zero_2_w_mock()
+        });
+        assert_eq!(detector.detect().unwrap().available_mb, Some(0));
+    }
+
+    #[test]
+    fn malformed_df_output_is_an_error() {
+        for output in ["", "Filesystem Type 1M-blocks Used Available Use% Mounted on\n", "header\n/dev/root ext4 lots 1 2 3% /"] {
+            assert!(parse_df_output(output).is_err(), "{output:?}");
+        }
+        assert_eq!(HardwareDetector::new(pi_zero_2_w_mock()).detect().unwrap().available_mb, None);
+    }
+
+    fn cached_caps() -> HardwareCapabilities {
+        HardwareCapabilities {
+            board_id: "cached-board".to_string(),
+            temperature_c: Some(99.0),
+            ..HardwareDetector::new(pi_zero_2_w_mock()).detect().unwrap()
+        }
+    }
+
+    #[test]
+    fn fresh_cache_is_used_with_live_thermal_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hardware.json");
+        cached_caps().save(&path).unwrap();
+
+        let detector = HardwareDetector::new(MockSystemReaders {
+            thermal: ThermalSources {
+                zone_temp: Some("40000".to_string()),
+                throttled: None,
+            },
+            ..pi_4_with_i2s_dac_mock()
+        });
+        let caps = detector.load_or_detect(&path, HARDWARE_CACHE_MAX_AGE, false).unwrap();
+        assert_eq!(caps.board_id, "cached-board");
+        assert_eq!(caps.temperature_c, Some(40.0));
+        assert_eq!(
+            HardwareCapabilities { temperature_c: Some(99.0), ..caps },
+            HardwareCapabilities::load(&path).unwrap()
+        );
+    }
+
+    #[test]
+    fn missing_stale_corrupt_or_forced_cache_is_redetected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hardware.json");
+        let detector = HardwareDetector::new(pi_4_with_i2s_dac_mock());
+        let detected = detector.detect().unwrap();
+
+        assert_eq!(detector.load_or_detect(&path, HARDWARE_CACHE_MAX_AGE, false).unwrap(), detected);
+        assert_eq!(HardwareCapabilities::load(&path).unwrap(), detected);
+
+        cached_caps().save(&path).unwrap();
+        assert_eq!(detector.load_or_detect(&path, HARDWARE_CACHE_MAX_AGE, true).unwrap(), detected);
+
+        cached_caps().save(&path).unwrap();
+        let two_days_ago = std::time::SystemTime::now() - Duration::from_secs(48 * 60 * 60);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(two_days_ago)
+            .unwrap();
+        assert_eq!(detector.load_or_detect(&path, HARDWARE_CACHE_MAX_AGE, false).unwrap(), detected);
+        assert_eq!(HardwareCapabilities::load(&path).unwrap(), detected);
+
+        fs::write(&path, "{").unwrap();
+        assert_eq!(detector.load_or_detect(&path, HARDWARE_CACHE_MAX_AGE, false).unwrap(), detected);
+    }
+
+    #[test]
+    fn parses_raspberry_pi_os_release() {
+        let os = parse_os_info(
+            include_str!("fixtures/os-release-raspios-bookworm"),
+            include_str!("fixtures/proc-version-raspios-bookworm"),
+        )
+        .unwrap();
+        assert_eq!(
+            os,
+            OsInfo {
+                distro: "debian".into(),
+                version: "12".into(),
+                pretty_name: "Debian GNU/Linux 12 (bookworm)".into(),
+                kernel: "6.6.31+rpt-rpi-v8".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_ubuntu_arm64_release() {
+        let os = parse_os_info(
+            include_str!("fixtures/os-release-ubuntu-arm64"),
+            include_str!("fixtures/proc-version-ubuntu-arm64"),
+        )
+        .unwrap();
+        assert_eq!(os.distro, "ubuntu");
+        assert_eq!(os.version, "22.04");
+        assert_eq!(os.pretty_name, "Ubuntu 22.04.4 LTS");
+        assert_eq!(os.kernel, "5.15.0-1055-raspi");
+    }
+
+    #[test]
+    fn os_release_defaults_and_quoting() {
+        let os = parse_os_info("NAME='Arch Linux'\nPRETTY_NAME='Arch \\\"rolling\\\"'\n", "Linux version 6.9.1-arch1 (x)").unwrap();
+        assert_eq!(os.distro, "linux");
+        assert_eq!(os.version, "");
+        assert_eq!(os.pretty_name, "Arch \"rolling\"");
+        assert!(parse_os_info("ID=debian", "garbage").is_err());
+
+        assert_eq!(HardwareDetector::new(pi_zero_2_w_mock()).detect().unwrap().os, None);
+        let detector = HardwareDetector::new(MockSystemReaders {
+            os_release: include_str!("fixtures/os-release-raspios-bookworm").into(),
+            kernel_version: include_str!("fixtures/proc-version-raspios-bookworm").into(),
+            ..pi_4_with_i2s_dac_mock()
+        });
+        assert_eq!(detector.detect().unwrap().os.unwrap().kernel, "6.6.31+rpt-rpi-v8");
+    }
+
+    #[test]
+    fn hdmi_device_names_do_not_imply_i2s_dac() {
+        let detector = HardwareDetector::new(MockSystemReaders {
+            alsa_devices: "card 0: Headphones [bcm2835 Headphones], device 0: bcm2835 Headphones [bcm2835 Headphones]\n\
+                           card 1: vc4hdmi0 [vc4-hdmi-0], device 0: MAI PCM i2s-hifi-0 [MAI PCM i2s-hifi-0]"
+                .to_string(),
+            card_modaliases: [(1, "of:NhdmiT(null)Cbrcm,bcm2711-hdmi0".to_string())].into(),
+            ..Default::default()
+        });
+        let caps = detector.detect().unwrap();
+        assert_eq!(caps.audio_outputs, vec![AudioOutput::HDMI, AudioOutput::Headphone]);
+    }
 }
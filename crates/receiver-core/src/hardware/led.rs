@@ -0,0 +1,205 @@
+use crate::events::{ReceiverEvent, Subscription};
+use crate::status::{StatusSnapshot, StatusTracker};
+use airsync_shared_protocol::PlaybackStatus;
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Gpiochip used when `AIRSYNC_LED_GPIO_CHIP` is unset.
+pub const DEFAULT_GPIO_CHIP: &str = "/dev/gpiochip0";
+
+/// A single on/off indicator.
+pub trait StatusLed: Send + Sync {
+    fn set(&self, on: bool) -> Result<()>;
+}
+
+/// Status LED on one output line of a gpiochip character device.
+pub struct GpioStatusLed {
+    line: Mutex<gpio_cdev::LineHandle>,
+}
+
+impl GpioStatusLed {
+    /// Claim `line` on `chip` as an output, initially off.
+    pub fn open(chip: impl AsRef<Path>, line: u32) -> Result<Self> {
+        let chip = chip.as_ref();
+        let handle = gpio_cdev::Chip::new(chip)
+            .and_then(|mut c| c.get_line(line))
+            .and_then(|l| l.request(gpio_cdev::LineRequestFlags::OUTPUT, 0, "airsync-status"))
+            .with_context(|| format!("failed to claim {} line {line}", chip.display()))?;
+        Ok(Self {
+            line: Mutex::new(handle),
+        })
+    }
+}
+
+impl StatusLed for GpioStatusLed {
+    fn set(&self, on: bool) -> Result<()> {
+        self.line.lock().unwrap().set_value(on as u8)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedPattern {
+    Off,
+    Solid,
+    SlowBlink,
+    DoubleBlink,
+}
+
+impl LedPattern {
+    /// Errors win over calibration, which wins over waiting to be paired; otherwise dark.
+    pub fn for_snapshot(snapshot: &StatusSnapshot) -> Self {
+        if snapshot.error.is_some() {
+            LedPattern::Solid
+        } else if snapshot.status == PlaybackStatus::Calibrating {
+            LedPattern::DoubleBlink
+        } else if !snapshot.paired {
+            LedPattern::SlowBlink
+        } else {
+            LedPattern::Off
+        }
+    }
+
+    /// One cycle as `(on, hold_ms)` steps; steady patterns have a single step.
+    pub fn steps(self) -> &'static [(bool, u64)] {
+        match self {
+            LedPattern::Off => &[(false, 0)],
+            LedPattern::Solid => &[(true, 0)],
+            LedPattern::SlowBlink => &[(true, 1_000), (false, 1_000)],
+            LedPattern::DoubleBlink => &[(true, 120), (false, 120), (true, 120), (false, 640)],
+        }
+    }
+}
+
+/// Show the tracker's state on `led`, following the same updates streaming clients get.
+/// LED errors are logged once and otherwise ignored; the LED is switched off when the
+/// tracker goes away.
+pub fn spawn_status_led(led: Arc<dyn StatusLed>, tracker: &StatusTracker) -> tokio::task::JoinHandle<()> {
+    let (current, updates) = tracker.subscribe();
+    tokio::spawn(drive_led(led, current, updates))
+}
+
+async fn drive_led(led: Arc<dyn StatusLed>, mut current: StatusSnapshot, mut updates: Subscription) {
+    let mut writer = LedWriter {
+        led: led.as_ref(),
+        warned: false,
+    };
+    loop {
+        let pattern = LedPattern::for_snapshot(&current);
+        tokio::select! {
+            _ = writer.play(pattern) => {}
+            update = updates.recv() => match update {
+                Some(ReceiverEvent::Status(snapshot)) => current = snapshot,
+                Some(_) => {}
+                None => break,
+            }
+        }
+    }
+    writer.set(false);
+}
+
+struct LedWriter<'a> {
+    led: &'a dyn StatusLed,
+    warned: bool,
+}
+
+impl LedWriter<'_> {
+    fn set(&mut self, on: bool) {
+        if let Err(err) = self.led.set(on) {
+            if !self.warned {
+                eprintln!("[led] failed to set status LED: {err:#}");
+                self.warned = true;
+            }
+        }
+    }
+
+    /// Run `pattern` until cancelled.
+    async fn play(&mut self, pattern: LedPattern) {
+        let steps = pattern.steps();
+        if let [(on, _)] = steps {
+            self.set(*on);
+            return std::future::pending().await;
+        }
+        loop {
+            for &(on, hold_ms) in steps {
+                self.set(on);
+                tokio::time::sleep(Duration::from_millis(hold_ms)).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::StatusEvent;
+    use crate::test_util::MockStatusLed;
+
+    async fn settle(ms: u64) {
+        tokio::time::sleep(Duration::from_millis(ms)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unpaired_receiver_blinks_slowly() {
+        let led = MockStatusLed::new();
+        let tracker = StatusTracker::new(0);
+        spawn_status_led(Arc::new(led.clone()), &tracker);
+        settle(2_500).await;
+        assert_eq!(led.transitions(), vec![(0, true), (1_000, false), (2_000, true)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn calibration_double_blinks_then_goes_dark_once_paired() {
+        let led = MockStatusLed::new();
+        let tracker = StatusTracker::new(0);
+        tracker.record(StatusEvent::Paired, 0);
+        spawn_status_led(Arc::new(led.clone()), &tracker);
+        settle(100).await;
+        tracker.record(StatusEvent::CalibrationStarted, 100);
+        settle(1_100).await;
+        tracker.record(StatusEvent::CalibrationFinished, 1_200);
+        settle(100).await;
+        assert_eq!(
+            led.transitions(),
+            vec![
+                (0, false),
+                (100, true),
+                (220, false),
+                (340, true),
+                (460, false),
+                (1_100, true),
+                (1_200, false),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn errors_hold_the_led_on_until_recovered() {
+        let led = MockStatusLed::new();
+        let tracker = StatusTracker::new(0);
+        tracker.record(StatusEvent::Paired, 0);
+        tracker.record(StatusEvent::Failed("playback failed".into()), 0);
+        spawn_status_led(Arc::new(led.clone()), &tracker);
+        settle(5_000).await;
+        tracker.record(StatusEvent::Recovered, 5_000);
+        settle(10).await;
+        assert_eq!(led.transitions(), vec![(0, true), (5_000, false)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_led_does_not_stop_the_driver() {
+        let led = MockStatusLed::failing();
+        let tracker = StatusTracker::new(0);
+        let driver = spawn_status_led(Arc::new(led.clone()), &tracker);
+        settle(2_500).await;
+        assert!(!driver.is_finished());
+        assert_eq!(led.attempts(), 3);
+    }
+
+    #[test]
+    fn missing_gpiochip_is_an_error_not_a_panic() {
+        assert!(GpioStatusLed::open("/nonexistent/gpiochip", 17).is_err());
+    }
+}
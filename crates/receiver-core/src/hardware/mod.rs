@@ -1,3 +1,9 @@
+mod alsa;
 mod detector;
+#[cfg(feature = "led")]
+mod led;
 
+pub use alsa::*;
 pub use detector::*;
+#[cfg(feature = "led")]
+pub use led::*;
@@ -1,20 +1,71 @@
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::{FromRawFd, RawFd};
 use std::path::{Path, PathBuf};
+#[cfg(not(feature = "embedded"))]
 use std::process::Command;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::calibration::{CalibrationApplier, ConfigWriter, ShairportController};
-use crate::airplay::{render_config_file, ShairportConfig};
-use airsync_shared_protocol::{CalibrationSubmission, CalibrationSignalSpec, ChirpConfig};
+use crate::calibration::aggregate::{
+    aggregate_detections, aggregate_rounds, DetectionAggregate, DetectionPolicy, MarkerClass, MarkerDetection, MarkerUsage, RoundMeasurement,
+};
+use crate::calibration::schedule::{LoopbackCalibrator, LoopbackMeasurement, SelfCalHandle, SelfCalSchedule, SelfCalSnapshot};
+use crate::calibration::playback::compute_playback_schedule;
+use crate::calibration::freshness::{CalibrationFreshness, FreshnessInputs, FreshnessReport, FreshnessThresholds};
+use crate::calibration::session::{CalibrationSession, SessionEvent};
+pub use crate::calibration::store::AppliedCalibration;
+use crate::calibration::store::{CalibrationSource, CalibrationStore, HistoryEntry};
+use crate::calibration::timing::{
+    align_by_id, align_by_order, chirp_emissions, marker_emissions, measure_latency, Emission, LatencyMeasurement,
+};
+use crate::calibration::{diff_against_current, CalibrationApplier, ConfigWriter, ServiceState, ShairportController};
+use crate::config_changes::{rendered_changed_keys, ConfigChange, ConfigChangeLog, ConfigChangeSource};
+use crate::calibration::signal::loudness::gain_to_db;
+use crate::calibration::signal::render_structured_signal;
+use crate::calibration::signal::resample::{resample_spec, resample_wav};
+use crate::airplay::{
+    render_eq_fragment, AirplayTransportControl, BuiltinRenderer, ConfigRenderer, EqSettings, MetadataStore, NoopTransportControl,
+    ShairportConfig,
+};
+pub use airsync_shared_protocol::CalibrationApplyResponse;
+use airsync_shared_protocol::{
+    AudioOutput, Capability, CalibrationSubmission, HardwareCapabilities, HardwareProfile, CalibrationSignalSpec, ChirpConfig, ChirpPreset, GroupAssignment, GroupConfig, GroupRelease,
+    Metadata, API_PROTOCOL_VERSION, MIN_CLIENT_PROTOCOL_VERSION,
+    reference_samples, OutputDeviceSpec, PlaybackStatus, ReceiverAdvertisement, SampleFormat, TlsEndpoint, TimeSyncResponse,
+    TxtRecordError,
+};
 use crate::generate_chirp_samples;
-use anyhow::{anyhow, Context, Result};
-use axum::extract::State;
+use crate::discovery::{PeerDirectory, PeerRecord};
+use crate::access_log::{log_access, AccessLog, RecentRequestsResponse};
+use crate::admin_token::{AdminTokens, RotatedToken};
+use crate::events::{EventHub, ReceiverEvent};
+use crate::firmware::{FirmwareUpdateAccepted, FirmwareUpdateRequest, FirmwareUpdater};
+use crate::setup::{output_options, SetupCompleteRequest, SetupMode, SetupStatus, SetupStep};
+use crate::jobs::{JobAccepted, JobError, JobFuture, JobQueue, JobStatus};
+use crate::request_id::inherit;
+use crate::network::{summarize_interfaces, ConnectionInfo, IfAddrsProvider, NetworkInfoProvider, NetworkInterface};
+use crate::hardware::{
+    classify_output_device, output_card_device, AlsaCard, HardwareDetector, SupportedRates, SystemReaders,
+};
+use crate::group::{BoxFuture, GroupMembership, GroupStore, GroupTransport, HttpGroupTransport, MemberReply};
+use crate::request_id::{log_info, log_warn, RequestIdLayer};
+use crate::startup::SettingsFile;
+use crate::status::{StatusEvent, StatusSnapshot, StatusTracker};
+use crate::timesync::{measure_peer, PeerTimeSync, TimeSyncCache, TIMESYNC_ROUNDS};
+use crate::version::{add_version_header, VersionInfo};
+use crate::watchdog::{WatchdogHandle, WatchdogSnapshot};
+use anyhow::{anyhow, bail, Context, Result};
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Path as UrlPath, Query, State};
 use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::{Json, Router};
 use serde::{Deserialize, Serialize};
 use tokio::net::TcpListener;
+use tower_http::compression::CompressionLayer;
+use tower_http::decompression::RequestDecompressionLayer;
+use tower_http::limit::RequestBodyLimitLayer;
 use uuid::Uuid;
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -24,11 +75,36 @@ pub struct ReceiverInfo {
     pub capabilities: Vec<String>,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReceiverInfoResponse {
+    #[serde(flatten)]
+    pub info: ReceiverInfo,
+    /// The receiver's interfaces; absent when they couldn't be listed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<Vec<NetworkInterface>>,
+    /// Where HTTPS is served and the fingerprint of its self-signed certificate; absent
+    /// when the receiver serves plain HTTP only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsEndpoint>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PairingStartResponse {
     pub receiver_id: String,
     pub capabilities: Vec<String>,
-    pub output_device: String,
+    pub output_device: OutputDeviceSpec,
+    /// Whether a calibration (or a manual offset) is in effect, so the app only prompts
+    /// for one when needed.
+    #[serde(default)]
+    pub calibrated: bool,
+    #[serde(default)]
+    pub calibrated_at: Option<u64>,
+    /// Whether the calibration in effect is still trustworthy; absent when uncalibrated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub freshness: Option<FreshnessReport>,
+    /// Oldest protocol version the receiver accepts from clients.
+    #[serde(default = "protocol_v1")]
+    pub min_receiver_protocol: u32,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -37,16 +113,73 @@ struct PairingStartRequest {
     device_name: String,
     app_version: String,
     platform: String,
+    /// API protocol version the app speaks; apps predating the field speak 1.
+    #[serde(default = "protocol_v1")]
+    protocol_version: u32,
+}
+
+fn protocol_v1() -> u32 {
+    1
+}
+
+/// Body returned with 400 when a client's protocol version is outside the range the
+/// receiver speaks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProtocolVersionMismatch {
+    pub error: String,
+    pub receiver_min: u32,
+    pub receiver_max: u32,
+    pub client_version: u32,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct CalibrationRequestPayload {
     pub timestamp: u64,
-    pub chirp_config: ChirpConfig,
+    /// Explicit chirp; exclusive with `preset`, and one of them is required unless the
+    /// request is structured.
+    #[serde(default)]
+    pub chirp_config: Option<ChirpConfig>,
+    #[serde(default)]
+    pub preset: Option<ChirpPreset>,
     #[serde(default)]
     pub delay_ms: Option<u64>,
     #[serde(default)]
     pub structured: bool,
+    /// Pause the active AirPlay stream while the signal plays; it resumes once a result
+    /// is applied or `CalibrationLimits::max_pause_ms` passes.
+    #[serde(default = "default_pause_airplay")]
+    pub pause_airplay: bool,
+}
+
+fn default_pause_airplay() -> bool {
+    true
+}
+
+/// Returned when a calibration request is accepted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalibrationRequestResponse {
+    /// Identifies this calibration; echo it in the ready call and the result so they
+    /// can't be mistaken for another calibration's.
+    pub session_id: Uuid,
+    /// Identifier encoded in the structured signal that will play, so the phone can
+    /// check it is hearing this receiver. `None` for plain chirps.
+    #[serde(default)]
+    pub signal_id: Option<u16>,
+    /// Problems that didn't stop the request, e.g. the AirPlay stream failing to pause.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+/// Body of `GET /api/calibration/pending`: the request waiting for a ready call, if any.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingCalibrationResponse {
+    pub has_pending: bool,
+    /// Chirp that will play; `None` for structured-signal requests.
+    pub chirp_config: Option<ChirpConfig>,
+    pub requested_at_ms: Option<u64>,
+    pub delay_ms: Option<u64>,
+    #[serde(default)]
+    pub session_id: Option<Uuid>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -54,6 +187,14 @@ pub struct CalibrationReadyPayload {
     pub timestamp: Option<u64>,
     #[serde(default)]
     pub target_start_ms: Option<u64>,
+    /// Start this many milliseconds after the ready call arrives, ignoring
+    /// `target_start_ms`, so receivers with different clock offsets still start together.
+    #[serde(default)]
+    pub countdown_ms: Option<u64>,
+    /// Session from the calibration request; a different one than the pending request's
+    /// is refused with 409.
+    #[serde(default)]
+    pub session_id: Option<Uuid>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -63,6 +204,43 @@ pub struct CalibrationResultPayload {
     pub confidence: f32,
     #[serde(default)]
     pub detections: Vec<DetectionPayload>,
+    /// Output device the measurement was taken against; a mismatch rejects the result.
+    #[serde(default)]
+    pub expected_output_device: Option<OutputDeviceSpec>,
+    /// Config generation the measurement was taken against; a mismatch rejects the result.
+    #[serde(default)]
+    pub expected_generation: Option<u64>,
+    /// Session the measurement was taken in. `/api/calibration/result` refuses results
+    /// without one with 400, and a mismatch with the last playback's rejects the result.
+    #[serde(default)]
+    pub session_id: Option<Uuid>,
+}
+
+/// Raw detections for the receiver to turn into a latency, as in
+/// `CalibrationMessage::CalibrationData`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CalibrationDataPayload {
+    pub timestamp: u64,
+    /// When the phone started recording, on the receiver's clock (see `/api/time`).
+    pub recording_start_time: u64,
+    /// Detection times in ms after `recording_start_time`.
+    pub chirp_detection_times: Vec<u64>,
+    pub confidence: f32,
+    /// Marker id for each detection time, when the detector identified them; detections
+    /// are otherwise aligned by timing.
+    #[serde(default)]
+    pub marker_ids: Option<Vec<String>>,
+    /// Session the detections were recorded in; a mismatch with the last playback's
+    /// rejects them.
+    #[serde(default)]
+    pub session_id: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CalibrationDataResponse {
+    #[serde(flatten)]
+    pub measurement: LatencyMeasurement,
+    pub applied: CalibrationApplyResponse,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -75,11 +253,187 @@ pub struct DetectionPayload {
     pub latency_ms: Option<f32>,
 }
 
+/// Returned when a calibration result is applied.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalibrationResultResponse {
+    #[serde(flatten)]
+    pub applied: CalibrationApplyResponse,
+    /// Markers the latency was computed from, when the result reported per-marker latencies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub markers: Option<MarkerUsage>,
+}
+
+/// Body returned with 422 when too few markers survive screening to trust a result.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InsufficientDetectionsResponse {
+    pub error: String,
+    pub message: String,
+    pub markers: MarkerUsage,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CalibrationFinalizePayload {
+    /// Timestamp for the aggregated submission; defaults to the newest round's timestamp.
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoundResultResponse {
+    pub round: usize,
+    pub rounds_recorded: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoundSummary {
+    pub round: usize,
+    pub timestamp: u64,
+    pub latency_ms: f32,
+    pub confidence: f32,
+    pub rejected: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CalibrationFinalizeResponse {
+    pub rounds: Vec<RoundSummary>,
+    pub rejected: Vec<usize>,
+    pub aggregate_latency_ms: f32,
+    pub aggregate_confidence: f32,
+    pub applied: CalibrationApplyResponse,
+}
+
+#[cfg(feature = "simulation")]
+#[derive(Debug, Clone, Deserialize)]
+pub struct CalibrationSimulationPayload {
+    pub true_latency_ms: f32,
+    #[serde(default)]
+    pub noise_stddev_ms: f32,
+    /// Seeds the noise, so a run can be repeated exactly; random otherwise.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+#[cfg(feature = "simulation")]
 #[derive(Debug, Clone, Serialize)]
-pub struct CalibrationApplyResponse {
-    pub measured_latency_ms: f32,
-    pub applied_offset_ms: f32,
-    pub was_clamped: bool,
+pub struct CalibrationSimulationReport {
+    pub input_latency_ms: f32,
+    pub noise_ms: f32,
+    pub submitted_latency_ms: f32,
+    pub applied: CalibrationApplyResponse,
+}
+
+/// Bounds applied when scheduling calibration playback, accepting its results and judging
+/// how long they stay trustworthy.
+#[derive(Debug, Clone, Copy)]
+pub struct CalibrationLimits {
+    /// Largest `delay_ms` accepted by `/api/calibration/request`, and the furthest
+    /// into the future a `target_start_ms` may be.
+    pub max_delay_ms: u64,
+    /// Minimum lead time given to playback; earlier targets are pushed back to this.
+    pub min_lead_ms: u64,
+    /// How far in the past a `target_start_ms` may be before the ready call is rejected.
+    pub max_lateness_ms: u64,
+    /// How long AirPlay stays paused for a calibration before it is resumed without a result.
+    pub max_pause_ms: u64,
+    /// How long playback may overrun the signal's length before it is reported as failed.
+    pub playback_grace_ms: u64,
+    /// Screening for results that report a latency per detected marker.
+    pub detections: DetectionPolicy,
+    /// When the calibration in effect is reported as aging or stale.
+    pub freshness: FreshnessThresholds,
+}
+
+impl Default for CalibrationLimits {
+    fn default() -> Self {
+        Self {
+            max_delay_ms: 30_000,
+            min_lead_ms: 1_500,
+            max_lateness_ms: 1_000,
+            max_pause_ms: 60_000,
+            playback_grace_ms: 5_000,
+            detections: DetectionPolicy::default(),
+            freshness: FreshnessThresholds::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalibrationReadyResponse {
+    pub scheduled_start_ms: u64,
+    pub was_adjusted: bool,
+    #[serde(default)]
+    pub requested_start_ms: Option<u64>,
+    /// How far `requested_start_ms` had already passed when the ready call arrived.
+    #[serde(default)]
+    pub lateness_ms: u64,
+    #[serde(default)]
+    pub session_id: Option<Uuid>,
+}
+
+/// Body returned with 422 when a calibration schedule is rejected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalibrationScheduleError {
+    pub error: String,
+    pub message: String,
+}
+
+/// Body returned with 409 when a calibration result is rejected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalibrationConflictResponse {
+    pub error: String,
+    pub submitted_timestamp: u64,
+    pub current: Option<AppliedCalibration>,
+    pub output_device: OutputDeviceSpec,
+    pub config_generation: u64,
+}
+
+/// Receiver configuration a calibration result was measured against.
+#[derive(Debug, Clone, Default)]
+pub struct ExpectedConfig {
+    pub output_device: Option<OutputDeviceSpec>,
+    pub generation: Option<u64>,
+    /// Calibration session the result belongs to, checked against the last playback's.
+    pub session_id: Option<Uuid>,
+}
+
+impl ExpectedConfig {
+    /// Fill in whatever the submitter left out from the last playback, so a result measured
+    /// before a settings change is refused instead of applied to the new configuration.
+    fn or_playback(self, state: &ReceiverState) -> Self {
+        let last = state.last_timing.lock().unwrap();
+        let Some(timing) = last.as_ref() else {
+            return self;
+        };
+        Self {
+            output_device: self.output_device.or_else(|| Some(timing.output_device.clone())),
+            generation: self.generation.or(Some(timing.config_generation)),
+            ..self
+        }
+    }
+}
+
+/// Largest request body accepted by default; calibration and settings payloads are a few KiB.
+pub const DEFAULT_BODY_LIMIT_BYTES: usize = 64 * 1024;
+
+/// Request body size limits. Larger requests are refused with 413 before the handler runs.
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimits {
+    pub default_bytes: usize,
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        Self {
+            default_bytes: DEFAULT_BODY_LIMIT_BYTES,
+        }
+    }
+}
+
+/// Body returned with 413 when a request body is over its limit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BodyTooLargeResponse {
+    pub error: String,
+    pub max_bytes: usize,
 }
 
 #[derive(Clone)]
@@ -89,8 +443,52 @@ pub struct ReceiverState {
     settings: Arc<dyn SettingsManager + Send + Sync>,
     playback: Arc<dyn PlaybackSink + Send + Sync>,
     pending_playback: Arc<Mutex<Option<PendingPlayback>>>,
+    session: CalibrationSession,
+    /// Chirp played when a calibration request doesn't specify one.
+    chirp_config: Arc<Mutex<ChirpConfig>>,
     last_timing: Arc<Mutex<Option<PlaybackTiming>>>,
+    last_applied: CalibrationStore,
+    /// Held across every change to the applied offset (calibration results and group
+    /// assignments), since the sinks await shairport restarts.
+    apply_lock: Arc<tokio::sync::Mutex<()>>,
+    rounds: Arc<Mutex<Vec<CalibrationResultPayload>>>,
+    limits: CalibrationLimits,
+    body_limits: BodyLimits,
     structured: Option<crate::calibration::signal::StructuredSignal>,
+    /// Signal rendered from a spec posted to `/api/calibration/signal/spec`; replaces
+    /// `structured` once set.
+    custom_signal: Arc<OnceLock<crate::calibration::signal::StructuredSignal>>,
+    status: StatusTracker,
+    /// Status, calibration, settings and hardware changes, for streaming consumers.
+    events: EventHub,
+    /// Calibration applies, asynchronous settings updates and firmware updates, run one
+    /// at a time.
+    jobs: JobQueue,
+    /// Last track metadata seen, served by `/api/metadata` while nothing is playing.
+    last_metadata: Option<MetadataStore>,
+    peers: PeerDirectory,
+    groups: GroupStore,
+    group_transport: Arc<dyn GroupTransport>,
+    clock: fn() -> u64,
+    timesync: TimeSyncCache,
+    airplay: Arc<dyn AirplayTransportControl>,
+    watchdog: Option<WatchdogHandle>,
+    hardware: Option<Arc<dyn HardwareProbe>>,
+    network: Arc<dyn NetworkInfoProvider>,
+    /// Updated when the certificate is regenerated, so clients see the new fingerprint.
+    tls: Arc<RwLock<Option<TlsEndpoint>>>,
+    certificates: Option<Arc<dyn CertificateRotator>>,
+    access_log: AccessLog,
+    /// Diffs recorded by the config writers, served by `/admin/config-changes`.
+    config_changes: ConfigChangeLog,
+    /// Token of the calibration that currently holds AirPlay paused, if any.
+    airplay_pause: Arc<Mutex<Option<Uuid>>>,
+    self_calibration: SelfCalHandle,
+    firmware: Option<Arc<FirmwareUpdater>>,
+    /// Expected in `X-Admin-Token` by the admin endpoints, which refuse every request
+    /// without one.
+    admin_tokens: Option<AdminTokens>,
+    setup: SetupMode,
 }
 
 #[derive(Clone)]
@@ -98,15 +496,19 @@ struct PendingPlayback {
     request: PlaybackRequest,
     delay_ms: u64,
     requested_at: u64,
+    session_id: Uuid,
 }
 
 #[derive(Clone, Debug)]
 struct PlaybackTiming {
     target_ts: u64,
     start_ts: u64,
-    ready_rx_ts: u64,
-    request_ts: u64,
-    delay_ms: u64,
+    session_id: Uuid,
+    /// Where each marker of the played signal starts, relative to `start_ts`.
+    emissions: Vec<Emission>,
+    /// Settings in effect when playback started, checked again before its result is applied.
+    output_device: OutputDeviceSpec,
+    config_generation: u64,
 }
 
 impl ReceiverState {
@@ -117,20 +519,414 @@ impl ReceiverState {
         playback: Arc<dyn PlaybackSink + Send + Sync>,
         structured: Option<crate::calibration::signal::StructuredSignal>,
     ) -> Self {
+        let builder = Self::builder()
+            .info(info)
+            .calibration(calibration)
+            .settings(settings)
+            .playback(playback);
+        match structured {
+            Some(signal) => builder.structured(signal).build(),
+            None => builder.build(),
+        }
+    }
+
+    /// Start building a state with no-op sinks and in-memory settings; override only
+    /// the pieces under test.
+    pub fn builder() -> ReceiverStateBuilder {
+        ReceiverStateBuilder::default()
+    }
+
+    /// Where the receiver publishes its status, calibration, settings and hardware changes.
+    pub fn events(&self) -> &EventHub {
+        &self.events
+    }
+
+    pub fn with_calibration_limits(mut self, limits: CalibrationLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// The structured signal calibration plays and describes: the posted custom one if
+    /// there is one, else the generated one.
+    fn structured_signal(&self) -> Option<&crate::calibration::signal::StructuredSignal> {
+        self.custom_signal.get().or(self.structured.as_ref())
+    }
+
+    /// Spec of the structured signal as it is played, converted to the output's rate
+    /// when the playback sink resamples for it.
+    fn played_spec(&self) -> Option<CalibrationSignalSpec> {
+        let spec = &self.structured_signal()?.spec;
+        Some(match self.playback.output_rate() {
+            Some(rate) if rate != spec.sample_rate => resample_spec(spec, rate),
+            _ => spec.clone(),
+        })
+    }
+
+    /// `standard` follows the default set through `/api/chirp/config`, so it can be
+    /// tuned per receiver; the other presets come from shared-protocol.
+    fn preset_config(&self, preset: ChirpPreset) -> ChirpConfig {
+        match preset {
+            ChirpPreset::Standard => self.chirp_config.lock().unwrap().clone(),
+            other => other.config(),
+        }
+    }
+
+    pub(crate) fn now_ms(&self) -> u64 {
+        (self.clock)()
+    }
+
+    pub(crate) fn self_calibration_handle(&self) -> SelfCalHandle {
+        self.self_calibration.clone()
+    }
+
+    pub(crate) fn self_calibration_schedule(&self) -> Option<SelfCalSchedule> {
+        self.settings.current().self_calibration
+    }
+
+    /// Playback status now, counting a phone calibration that has been requested but not
+    /// yet played as calibrating.
+    pub(crate) fn playback_status(&self) -> PlaybackStatus {
+        let status = self.status.refresh(self.now_ms()).status;
+        if status == PlaybackStatus::Idle && self.pending_playback.lock().unwrap().is_some() {
+            return PlaybackStatus::Calibrating;
+        }
+        status
+    }
+
+    /// Measure through `calibrator`, reporting the receiver as calibrating meanwhile.
+    pub(crate) async fn measure_self_calibration(
+        &self,
+        calibrator: &dyn LoopbackCalibrator,
+        amplitude: f32,
+    ) -> Result<LoopbackMeasurement> {
+        self.status.record(StatusEvent::CalibrationStarted, self.now_ms());
+        let measured = calibrator.measure(amplitude).await;
+        self.status.record(StatusEvent::CalibrationFinished, self.now_ms());
+        measured
+    }
+
+    /// Latency the offset in effect corrects for, from the last calibration or, before
+    /// any, the configured offset.
+    pub(crate) fn latency_in_effect_ms(&self) -> f32 {
+        self.last_applied.current().map_or_else(
+            || -self.settings.current().latency_offset_seconds * 1000.0,
+            |applied| applied.measured_latency_ms,
+        )
+    }
+
+    pub(crate) async fn apply_self_calibration(&self, measurement: &LoopbackMeasurement) -> Result<CalibrationApplyResponse> {
+        let submission = CalibrationSubmission {
+            timestamp: self.now_ms(),
+            latency_ms: measurement.latency_ms,
+            confidence: measurement.confidence,
+            detections: Vec::new(),
+        };
+        apply_checked(self, &submission, &ExpectedConfig::default(), CalibrationSource::SelfCal)
+            .await
+            .map_err(|rejection| match rejection {
+                ApplyRejection::Conflict(conflict) => anyhow!("rejected: {}", conflict.error),
+                ApplyRejection::Failed => anyhow!("the calibration sink failed to apply it"),
+            })
+    }
+}
+
+pub struct ReceiverStateBuilder {
+    info: ReceiverInfo,
+    calibration: Option<Arc<dyn CalibrationSink + Send + Sync>>,
+    settings: Option<Arc<dyn SettingsManager + Send + Sync>>,
+    playback: Option<Arc<dyn PlaybackSink + Send + Sync>>,
+    limits: CalibrationLimits,
+    body_limits: BodyLimits,
+    structured: Option<crate::calibration::signal::StructuredSignal>,
+    status: Option<StatusTracker>,
+    metadata: Option<MetadataStore>,
+    peers: Option<PeerDirectory>,
+    groups: Option<GroupStore>,
+    calibration_store: Option<CalibrationStore>,
+    group_transport: Option<Arc<dyn GroupTransport>>,
+    clock: fn() -> u64,
+    airplay: Option<Arc<dyn AirplayTransportControl>>,
+    watchdog: Option<WatchdogHandle>,
+    hardware: Option<Arc<dyn HardwareProbe>>,
+    network: Option<Arc<dyn NetworkInfoProvider>>,
+    tls: Option<TlsEndpoint>,
+    certificates: Option<Arc<dyn CertificateRotator>>,
+    access_log: Option<AccessLog>,
+    config_changes: Option<ConfigChangeLog>,
+    firmware: Option<Arc<FirmwareUpdater>>,
+    admin_tokens: Option<AdminTokens>,
+    setup: SetupMode,
+}
+
+impl Default for ReceiverStateBuilder {
+    fn default() -> Self {
         Self {
-            info,
-            calibration,
+            info: ReceiverInfo {
+                receiver_id: "airsync-receiver".into(),
+                name: "AirSync".into(),
+                capabilities: vec!["calibration".into()],
+            },
+            calibration: None,
+            settings: None,
+            playback: None,
+            limits: CalibrationLimits::default(),
+            body_limits: BodyLimits::default(),
+            structured: None,
+            status: None,
+            metadata: None,
+            peers: None,
+            groups: None,
+            calibration_store: None,
+            group_transport: None,
+            clock: now_millis,
+            airplay: None,
+            watchdog: None,
+            hardware: None,
+            network: None,
+            tls: None,
+            certificates: None,
+            access_log: None,
+            config_changes: None,
+            firmware: None,
+            admin_tokens: None,
+            setup: SetupMode::default(),
+        }
+    }
+}
+
+impl ReceiverStateBuilder {
+    pub fn info(mut self, info: ReceiverInfo) -> Self {
+        self.info = info;
+        self
+    }
+
+    pub fn receiver_id(mut self, receiver_id: impl Into<String>) -> Self {
+        self.info.receiver_id = receiver_id.into();
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.info.name = name.into();
+        self
+    }
+
+    pub fn calibration(mut self, sink: Arc<dyn CalibrationSink + Send + Sync>) -> Self {
+        self.calibration = Some(sink);
+        self
+    }
+
+    pub fn settings(mut self, settings: Arc<dyn SettingsManager + Send + Sync>) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+
+    pub fn playback(mut self, playback: Arc<dyn PlaybackSink + Send + Sync>) -> Self {
+        self.playback = Some(playback);
+        self
+    }
+
+    pub fn calibration_limits(mut self, limits: CalibrationLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn body_limits(mut self, limits: BodyLimits) -> Self {
+        self.body_limits = limits;
+        self
+    }
+
+    pub fn structured(mut self, signal: crate::calibration::signal::StructuredSignal) -> Self {
+        self.structured = Some(signal);
+        self
+    }
+
+    /// Share a tracker with the metadata reader; a fresh one is created otherwise. The
+    /// receiver's other events are published on the tracker's hub.
+    pub fn status_tracker(mut self, tracker: StatusTracker) -> Self {
+        self.status = Some(tracker);
+        self
+    }
+
+    /// Fall back to the last track metadata seen when nothing is playing.
+    pub fn metadata_store(mut self, store: MetadataStore) -> Self {
+        self.metadata = Some(store);
+        self
+    }
+
+    /// Share a peer table with the discovery task; an empty one is created otherwise.
+    pub fn peer_directory(mut self, peers: PeerDirectory) -> Self {
+        self.peers = Some(peers);
+        self
+    }
+
+    /// Group state loaded from the receiver state dir; kept in memory otherwise.
+    pub fn group_store(mut self, groups: GroupStore) -> Self {
+        self.groups = Some(groups);
+        self
+    }
+
+    /// Where the calibration in effect is kept; in memory only when unset.
+    pub fn calibration_store(mut self, store: CalibrationStore) -> Self {
+        self.calibration_store = Some(store);
+        self
+    }
+
+    /// How a coordinator reaches other members; defaults to HTTP via the peer directory.
+    pub fn group_transport(mut self, transport: Arc<dyn GroupTransport>) -> Self {
+        self.group_transport = Some(transport);
+        self
+    }
+
+    /// Clock served by `/api/time`, used for peer time sync and to time marker progress
+    /// from the playback start the sink reports; defaults to `now_millis`.
+    pub fn clock(mut self, clock: fn() -> u64) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Pauses AirPlay around calibration playback; leaves the stream alone otherwise.
+    pub fn transport_control(mut self, control: Arc<dyn AirplayTransportControl>) -> Self {
+        self.airplay = Some(control);
+        self
+    }
+
+    /// Report this shairport-sync watchdog from `/api/health`.
+    pub fn watchdog(mut self, handle: WatchdogHandle) -> Self {
+        self.watchdog = Some(handle);
+        self
+    }
+
+    /// Served from `/api/hardware`, which is 404 without one.
+    pub fn hardware(mut self, probe: Arc<dyn HardwareProbe>) -> Self {
+        self.hardware = Some(probe);
+        self
+    }
+
+    /// Lists interfaces for `/api/receiver/info`; defaults to `getifaddrs`.
+    pub fn network_info(mut self, provider: Arc<dyn NetworkInfoProvider>) -> Self {
+        self.network = Some(provider);
+        self
+    }
+
+    /// HTTPS endpoint published by `/api/receiver/info` for clients to pin.
+    pub fn tls(mut self, endpoint: TlsEndpoint) -> Self {
+        self.tls = Some(endpoint);
+        self
+    }
+
+    /// Regenerates the certificate behind the [`tls`](Self::tls) endpoint for
+    /// `/admin/tls/regenerate`, which is 503 without one.
+    pub fn certificate_rotator(mut self, rotator: Arc<dyn CertificateRotator>) -> Self {
+        self.certificates = Some(rotator);
+        self
+    }
+
+    /// Where requests are recorded for `/admin/recent-requests`; defaults to the last
+    /// [`DEFAULT_RECENT_REQUESTS`](crate::access_log::DEFAULT_RECENT_REQUESTS).
+    pub fn access_log(mut self, log: AccessLog) -> Self {
+        self.access_log = Some(log);
+        self
+    }
+
+    /// The log the settings manager and calibration applier record their diffs in, for
+    /// `/admin/config-changes`.
+    pub fn config_change_log(mut self, log: ConfigChangeLog) -> Self {
+        self.config_changes = Some(log);
+        self
+    }
+
+    /// Installs images posted to `/api/firmware/update`, which is 503 without one.
+    pub fn firmware_updater(mut self, updater: FirmwareUpdater) -> Self {
+        self.firmware = Some(Arc::new(updater));
+        self
+    }
+
+    /// Token the admin endpoints expect in `X-Admin-Token`, kept in memory only; an empty
+    /// one leaves them refusing every request.
+    pub fn admin_token(mut self, token: impl Into<String>) -> Self {
+        let token = token.into();
+        self.admin_tokens = (!token.is_empty()).then(|| AdminTokens::new(token));
+        self
+    }
+
+    /// Admin token that `/admin/rotate-token` can replace, e.g. from [`AdminTokens::open`].
+    pub fn admin_tokens(mut self, tokens: AdminTokens) -> Self {
+        self.admin_tokens = Some(tokens);
+        self
+    }
+
+    /// Start in first-boot setup, left through `/api/setup/complete`; off by default.
+    pub fn setup_mode(mut self, setup: SetupMode) -> Self {
+        self.setup = setup;
+        self
+    }
+
+    pub fn build(self) -> ReceiverState {
+        let settings = self.settings.unwrap_or_else(|| {
+            Arc::new(InMemorySettingsManager::new(ConfigStore::new(ShairportConfig {
+                device_name: self.info.name.clone(),
+                output_device: OutputDeviceSpec::hw(0, 0),
+                latency_offset_seconds: 0.0,
+                calibration_gain: None,
+                eq: None,
+                self_calibration: None,
+            })))
+        });
+        let peers = self
+            .peers
+            .unwrap_or_else(|| PeerDirectory::new(self.info.receiver_id.clone()));
+        let group_transport = self
+            .group_transport
+            .unwrap_or_else(|| Arc::new(HttpGroupTransport::new(peers.clone(), now_millis)));
+        let status = self.status.unwrap_or_else(|| StatusTracker::new(now_millis()));
+        let events = status.events().clone();
+        ReceiverState {
+            info: self.info,
+            calibration: self.calibration.unwrap_or_else(|| Arc::new(NoopCalibrationSink)),
             settings,
-            playback,
+            playback: self.playback.unwrap_or_else(|| Arc::new(NoopPlaybackSink)),
             pending_playback: Arc::new(Mutex::new(None)),
+            session: CalibrationSession::with_hub(now_millis(), events.clone()),
+            chirp_config: Arc::new(Mutex::new(ChirpConfig::default())),
             last_timing: Arc::new(Mutex::new(None)),
-            structured,
+            last_applied: self.calibration_store.unwrap_or_else(CalibrationStore::in_memory),
+            apply_lock: Arc::new(tokio::sync::Mutex::new(())),
+            rounds: Arc::new(Mutex::new(Vec::new())),
+            limits: self.limits,
+            body_limits: self.body_limits,
+            structured: self.structured,
+            custom_signal: Arc::new(OnceLock::new()),
+            status,
+            events,
+            jobs: JobQueue::new(self.clock),
+            last_metadata: self.metadata,
+            peers,
+            groups: self.groups.unwrap_or_else(GroupStore::in_memory),
+            group_transport,
+            clock: self.clock,
+            timesync: TimeSyncCache::default(),
+            airplay: self.airplay.unwrap_or_else(|| Arc::new(NoopTransportControl)),
+            watchdog: self.watchdog,
+            hardware: self.hardware,
+            network: self.network.unwrap_or_else(|| Arc::new(IfAddrsProvider)),
+            tls: Arc::new(RwLock::new(self.tls)),
+            certificates: self.certificates,
+            access_log: self.access_log.unwrap_or_default(),
+            config_changes: self.config_changes.unwrap_or_default(),
+            airplay_pause: Arc::new(Mutex::new(None)),
+            self_calibration: SelfCalHandle::default(),
+            firmware: self.firmware,
+            admin_tokens: self.admin_tokens,
+            setup: self.setup,
         }
     }
 }
 
 pub trait CalibrationSink {
-    fn apply(&self, submission: &CalibrationSubmission) -> Result<CalibrationApplyResponse>;
+    fn apply<'a>(&'a self, submission: &'a CalibrationSubmission) -> BoxFuture<'a, Result<CalibrationApplyResponse>>;
+
+    /// Put back the config and latency of the most recent entry of `history`.
+    fn replay<'a>(&'a self, history: &'a [HistoryEntry]) -> BoxFuture<'a, Result<CalibrationApplyResponse>>;
 }
 
 #[derive(Clone)]
@@ -141,22 +937,150 @@ pub enum PlaybackRequest {
 
 pub trait PlaybackSink {
     fn play(&self, request: &PlaybackRequest) -> Result<()>;
+
+    /// Play `request`, calling `started` with the wall-clock time audio began as soon as
+    /// it is known, and again if playback is retried. The default reports the time just
+    /// before calling `play`.
+    fn play_reporting_start(&self, request: &PlaybackRequest, started: &dyn Fn(u64)) -> Result<()> {
+        started(now_millis());
+        self.play(request)
+    }
+
+    /// Sample rate the current output is fed at, when the sink converts signals for it.
+    /// `None` means signals play at the rate they were rendered at.
+    fn output_rate(&self) -> Option<u32> {
+        None
+    }
+
+    /// Linear gain `request` is played at relative to the signal as rendered, when the
+    /// sink scales it. `None` means it plays as rendered.
+    fn output_gain(&self, _request: &PlaybackRequest) -> Option<f32> {
+        None
+    }
+
+    /// How long playing `chirp` is expected to take; the rendered length unless the sink
+    /// knows better.
+    fn duration_hint(&self, chirp: &ChirpConfig) -> Duration {
+        Duration::from_millis(chirp.estimated_duration_ms())
+    }
 }
 
 pub trait SettingsManager {
     fn current(&self) -> ShairportConfig;
-    fn update(&self, update: SettingsUpdatePayload) -> Result<ShairportConfig>;
+    /// Apply `update`, returning the new config and the config keys it changed.
+    fn update(&self, update: SettingsUpdatePayload) -> BoxFuture<'_, Result<(ShairportConfig, Vec<String>)>>;
+    /// Monotonic counter bumped on every settings change.
+    fn generation(&self) -> u64;
+    /// Write the current config to the settings store, resolving once it is on disk.
+    fn save(&self) -> BoxFuture<'_, Result<()>>;
+}
+
+/// Shairport configuration shared by the sinks and settings manager, versioned so
+/// measurements can be tied to the configuration they were taken against. Readers share
+/// the lock, so `/api/settings` isn't held up by a calibration recording its offset.
+#[derive(Clone)]
+pub struct ConfigStore {
+    inner: Arc<RwLock<StoredConfig>>,
+    settings_file: Option<Arc<SettingsFile>>,
+}
+
+struct StoredConfig {
+    config: ShairportConfig,
+    generation: u64,
+}
+
+impl ConfigStore {
+    pub fn new(config: ShairportConfig) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(StoredConfig {
+                config,
+                generation: 0,
+            })),
+            settings_file: None,
+        }
+    }
+
+    /// Also save every committed config to `file`, the store reconciled at startup.
+    pub fn persist_to(mut self, file: SettingsFile) -> Self {
+        self.settings_file = Some(Arc::new(file));
+        self
+    }
+
+    pub fn current(&self) -> ShairportConfig {
+        self.inner.read().unwrap().config.clone()
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.inner.read().unwrap().generation
+    }
+
+    pub fn snapshot(&self) -> (ShairportConfig, u64) {
+        let stored = self.inner.read().unwrap();
+        (stored.config.clone(), stored.generation)
+    }
+
+    /// Compute and commit a new configuration while holding the store lock, bumping the
+    /// generation only when `f` succeeds.
+    pub fn update_with<F>(&self, f: F) -> Result<(ShairportConfig, u64)>
+    where
+        F: FnOnce(&ShairportConfig) -> Result<ShairportConfig>,
+    {
+        let committed = {
+            let mut stored = self.inner.write().unwrap();
+            let next = f(&stored.config)?;
+            stored.config = next;
+            stored.generation += 1;
+            (stored.config.clone(), stored.generation)
+        };
+        self.persist();
+        Ok(committed)
+    }
+
+    /// Record the latency offset a calibration wrote. The generation is left alone: it
+    /// tracks settings a measurement depends on, and the offset is what measurements produce.
+    pub fn record_latency_offset(&self, offset_seconds: f32) {
+        self.inner.write().unwrap().config.latency_offset_seconds = offset_seconds;
+        self.persist();
+    }
+
+    /// Save the current config now, returning once it is on disk or the save failed.
+    /// Nothing is written without a settings file.
+    pub async fn save(&self) -> Result<()> {
+        let Some(file) = self.settings_file.clone() else {
+            return Ok(());
+        };
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || file.save_with(|| inner.read().unwrap().config.clone())).await?
+    }
+
+    /// Save the current config off the runtime threads. Each save reads the config under
+    /// the file's write lock, so whichever saves last writes the newest state.
+    fn persist(&self) {
+        let Some(file) = self.settings_file.clone() else {
+            return;
+        };
+        let inner = self.inner.clone();
+        let save = move || {
+            if let Err(err) = file.save_with(|| inner.read().unwrap().config.clone()) {
+                eprintln!("[settings] failed to persist settings: {err:#}");
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(save)),
+            Err(_) => save(),
+        }
+    }
 }
 
 pub struct ShairportCalibrationSink<W: ConfigWriter + Send + Sync + 'static, C: ShairportController + Send + Sync + 'static> {
     applier: CalibrationApplier<W, C>,
-    config: Arc<Mutex<ShairportConfig>>,
+    config: ConfigStore,
 }
 
 impl<W: ConfigWriter + Send + Sync + 'static, C: ShairportController + Send + Sync + 'static>
     ShairportCalibrationSink<W, C>
 {
-    pub fn new(applier: CalibrationApplier<W, C>, config: Arc<Mutex<ShairportConfig>>) -> Self {
+    pub fn new(applier: CalibrationApplier<W, C>, config: ConfigStore) -> Self {
         Self { applier, config }
     }
 }
@@ -164,101 +1088,404 @@ impl<W: ConfigWriter + Send + Sync + 'static, C: ShairportController + Send + Sy
 impl<W: ConfigWriter + Send + Sync + 'static, C: ShairportController + Send + Sync + 'static>
     CalibrationSink for ShairportCalibrationSink<W, C>
 {
-    fn apply(&self, submission: &CalibrationSubmission) -> Result<CalibrationApplyResponse> {
-        let config = self.config.lock().unwrap().clone();
-        let outcome = self.applier.apply_submission(config, submission)?;
-        Ok(CalibrationApplyResponse {
-            measured_latency_ms: submission.latency_ms,
-            applied_offset_ms: outcome.applied_offset_ms,
-            was_clamped: outcome.was_clamped,
+    fn apply<'a>(&'a self, submission: &'a CalibrationSubmission) -> BoxFuture<'a, Result<CalibrationApplyResponse>> {
+        Box::pin(async move {
+            let (config, generation) = self.config.snapshot();
+            let output_device = config.output_device.clone();
+            let outcome = self.applier.apply_submission(config, submission).await?;
+            self.config.record_latency_offset(outcome.applied_offset_ms / 1000.0);
+            Ok(CalibrationApplyResponse {
+                output_device,
+                config_generation: generation,
+                ..CalibrationApplyResponse::from_outcome(&outcome)
+            })
         })
     }
-}
 
-pub fn router(state: ReceiverState) -> Router {
-    Router::new()
+    fn replay<'a>(&'a self, history: &'a [HistoryEntry]) -> BoxFuture<'a, Result<CalibrationApplyResponse>> {
+        Box::pin(async move {
+            let outcome = self.applier.replay_history(history).await?;
+            let (config, generation) = self.config.update_with(|_| {
+                let mut restored = history.last().expect("replay_history checked").config_snapshot.clone();
+                restored.latency_offset_seconds = outcome.applied_offset_ms / 1000.0;
+                Ok(restored)
+            })?;
+            Ok(CalibrationApplyResponse {
+                output_device: config.output_device,
+                config_generation: generation,
+                ..CalibrationApplyResponse::from_outcome(&outcome)
+            })
+        })
+    }
+}
+
+pub fn router(state: ReceiverState) -> Router {
+    let router = Router::new()
         .route("/api/pairing/start", post(pairing_start))
         .route("/api/calibration/request", post(calibration_request))
+        .route("/api/calibration/pending", get(calibration_pending))
+        .route("/api/calibration/presets", get(calibration_presets))
         .route("/api/calibration/ready", post(calibration_ready))
         .route("/api/calibration/result", post(calibration_result))
+        .route("/api/jobs/:id", get(job_status))
+        .route("/api/calibration/data", post(calibration_data))
+        .route("/api/calibration/round-result", post(calibration_round_result))
+        .route("/api/calibration/finalize", post(calibration_finalize))
+        .route("/api/calibration/events", get(calibration_events))
+        .route("/api/calibration/current", get(calibration_current))
+        .route("/api/calibration/replay", post(calibration_replay))
         .route("/api/calibration/spec", get(calibration_spec))
+        .route("/api/calibration/signal/spec", get(get_signal_spec).post(post_signal_spec))
+        .route("/api/calibration/signal.wav", get(signal_wav))
+        .route("/api/calibration/signal/reference/:marker_id", get(marker_reference))
         .route("/api/settings", get(get_settings).post(update_settings))
+        .route("/api/chirp/config", get(get_chirp_config).put(put_chirp_config))
         .route("/api/receiver/info", get(receiver_info))
+        .route("/api/status", get(playback_status))
+        .route("/api/metadata", get(metadata))
+        .route("/api/health", get(health))
+        .route("/api/version", get(version))
+        .route("/api/hardware", get(hardware))
+        .route("/api/setup", get(setup_status))
+        .route("/api/setup/complete", post(complete_setup))
+        .route("/api/peers", get(list_peers))
+        .route("/api/peers/:id/timesync", get(peer_timesync))
+        .route("/api/group", get(get_group).post(create_group).delete(delete_group))
+        .route("/api/group/assign", post(assign_group).delete(release_group))
         .route("/api/time", get(time_sync))
+        .merge(admin_router(&state));
+    #[cfg(feature = "simulation")]
+    let router = router.route("/api/calibration/simulate", post(calibration_simulate));
+    let default_limit = state.body_limits.default_bytes;
+    let access_log = state.access_log.clone();
+    limit_body(router, default_limit)
         .with_state(state)
+        .layer(axum::middleware::from_fn_with_state(access_log, log_access))
+        .layer(RequestIdLayer)
+        // Decompressed bodies are still held to the body limits, which run inside.
+        .layer(RequestDecompressionLayer::new())
+        .layer(CompressionLayer::new())
+        .layer(axum::middleware::map_response(add_version_header))
+}
+
+/// Routes for operators and the update server rather than the app, all behind
+/// [`require_admin_token`].
+fn admin_router(state: &ReceiverState) -> Router<ReceiverState> {
+    Router::new()
+        .route("/admin/recent-requests", get(recent_requests))
+        .route("/admin/config-changes", get(config_changes))
+        .route("/admin/tls/regenerate", post(regenerate_certificate))
+        .route("/admin/rotate-token", post(rotate_admin_token))
+        .route("/api/firmware/update", post(firmware_update))
+        .route_layer(axum::middleware::from_fn_with_state(state.clone(), require_admin_token))
+}
+
+/// Refuse bodies over `max_bytes` with a JSON 413. Bodies that declare their length are
+/// refused up front; others when the handler's extractor reads past the limit.
+fn limit_body<S: Clone + Send + Sync + 'static>(router: Router<S>, max_bytes: usize) -> Router<S> {
+    router
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(max_bytes))
+        .layer(axum::middleware::map_response(move |response: Response| async move {
+            if response.status() != StatusCode::PAYLOAD_TOO_LARGE {
+                return response;
+            }
+            let body = BodyTooLargeResponse {
+                error: "request_too_large".into(),
+                max_bytes,
+            };
+            (StatusCode::PAYLOAD_TOO_LARGE, Json(body)).into_response()
+        }))
 }
 
-async fn pairing_start(State(state): State<ReceiverState>, Json(_): Json<PairingStartRequest>) -> Result<Json<PairingStartResponse>, StatusCode> {
+async fn pairing_start(State(state): State<ReceiverState>, Json(req): Json<PairingStartRequest>) -> Result<Json<PairingStartResponse>, Response> {
+    if !(MIN_CLIENT_PROTOCOL_VERSION..=API_PROTOCOL_VERSION).contains(&req.protocol_version) {
+        log_warn!(
+            "[pairing] refusing {} {} speaking protocol {} (receiver speaks {}..={})",
+            req.platform, req.app_version, req.protocol_version, MIN_CLIENT_PROTOCOL_VERSION, API_PROTOCOL_VERSION
+        );
+        let body = ProtocolVersionMismatch {
+            error: "protocol_version_mismatch".into(),
+            receiver_min: MIN_CLIENT_PROTOCOL_VERSION,
+            receiver_max: API_PROTOCOL_VERSION,
+            client_version: req.protocol_version,
+        };
+        return Err((StatusCode::BAD_REQUEST, Json(body)).into_response());
+    }
+    state.status.record(StatusEvent::Paired, now_millis());
     let cfg = state.settings.current();
+    let applied = state.last_applied.current();
+    let freshness = match &applied {
+        Some(applied) => Some(calibration_freshness(&state, applied).await),
+        None => None,
+    };
     Ok(Json(PairingStartResponse {
         receiver_id: state.info.receiver_id.clone(),
         capabilities: state.info.capabilities.clone(),
         output_device: cfg.output_device,
+        calibrated: applied.is_some(),
+        calibrated_at: applied.map(|c| c.timestamp),
+        freshness,
+        min_receiver_protocol: MIN_CLIENT_PROTOCOL_VERSION,
     }))
 }
 
-async fn calibration_request(State(state): State<ReceiverState>, Json(req): Json<CalibrationRequestPayload>) -> StatusCode {
+async fn calibration_request(State(state): State<ReceiverState>, Json(req): Json<CalibrationRequestPayload>) -> Response {
     let delay = req.delay_ms.unwrap_or(2_000);
+    if delay > state.limits.max_delay_ms {
+        log_warn!(
+            "[calibration] rejecting request delay_ms={} (max {})",
+            delay, state.limits.max_delay_ms
+        );
+        return schedule_error(
+            "delay_too_long",
+            format!("delay_ms {} exceeds maximum of {}", delay, state.limits.max_delay_ms),
+        );
+    }
+    let mut signal_id = None;
     let request = if req.structured {
-        if let Some(structured) = &state.structured {
+        if let Some(structured) = state.structured_signal() {
+            signal_id = structured.spec.signal_id;
             PlaybackRequest::File(structured.path.clone())
         } else {
-            eprintln!("[calibration] structured request but no structured signal available");
-            return StatusCode::BAD_REQUEST;
+            log_warn!("[calibration] structured request but no structured signal available");
+            return StatusCode::BAD_REQUEST.into_response();
         }
     } else {
-        PlaybackRequest::Chirp(req.chirp_config.clone())
+        match resolve_chirp(&state, &req) {
+            Ok(chirp) => PlaybackRequest::Chirp(chirp),
+            Err((error, message)) => return schedule_error(error, message.into()),
+        }
     };
+    let mut warnings = Vec::new();
+    if req.pause_airplay {
+        warnings.extend(pause_airplay_for_calibration(&state).await);
+    }
     let mut slot = state.pending_playback.lock().unwrap();
+    let requested_at = now_millis();
+    let session_id = Uuid::new_v4();
     *slot = Some(PendingPlayback {
         request,
         delay_ms: delay,
-        requested_at: now_millis(),
+        requested_at,
+        session_id,
+    });
+    state.session.record(SessionEvent::Requested {
+        at_ms: requested_at,
+        delay_ms: delay,
     });
-    println!(
-        "[calibration] received request timestamp={} delay_ms={}",
-        req.timestamp, delay
+    log_info!(
+        "[calibration] received request timestamp={} delay_ms={} signal_id={:?} session={}",
+        req.timestamp, delay, signal_id, session_id
     );
-    StatusCode::OK
+    Json(CalibrationRequestResponse {
+        session_id,
+        signal_id,
+        warnings,
+    })
+    .into_response()
+}
+
+/// The chirp a plain calibration request asks for, by preset or spelled out; otherwise
+/// the error code and message to refuse it with.
+fn resolve_chirp(
+    state: &ReceiverState,
+    req: &CalibrationRequestPayload,
+) -> Result<ChirpConfig, (&'static str, &'static str)> {
+    match (req.preset, &req.chirp_config) {
+        (Some(_), Some(_)) => Err(("ambiguous_chirp", "send either preset or chirp_config, not both")),
+        (None, None) => Err(("missing_chirp", "send a preset or a chirp_config")),
+        (Some(preset), None) => Ok(state.preset_config(preset)),
+        (None, Some(chirp)) => Ok(chirp.clone()),
+    }
+}
+
+async fn calibration_pending(State(state): State<ReceiverState>) -> Json<PendingCalibrationResponse> {
+    let slot = state.pending_playback.lock().unwrap();
+    Json(PendingCalibrationResponse {
+        has_pending: slot.is_some(),
+        chirp_config: slot.as_ref().and_then(|pending| match &pending.request {
+            PlaybackRequest::Chirp(chirp) => Some(chirp.clone()),
+            PlaybackRequest::File(_) => None,
+        }),
+        requested_at_ms: slot.as_ref().map(|pending| pending.requested_at),
+        delay_ms: slot.as_ref().map(|pending| pending.delay_ms),
+        session_id: slot.as_ref().map(|pending| pending.session_id),
+    })
+}
+
+/// Pause the AirPlay stream ahead of calibration playback when a session is active.
+/// Failing to pause doesn't stop calibration; the returned warning goes to the caller.
+async fn pause_airplay_for_calibration(state: &ReceiverState) -> Option<String> {
+    if !state.status.session_active() || state.airplay_pause.lock().unwrap().is_some() {
+        return None;
+    }
+    if let Err(err) = state.airplay.pause().await {
+        log_warn!("[calibration] failed to pause airplay: {err:#}");
+        return Some(format!("airplay_pause_failed: {err:#}"));
+    }
+    let token = Uuid::new_v4();
+    *state.airplay_pause.lock().unwrap() = Some(token);
+    log_info!("[calibration] paused airplay for calibration");
+    let expiry = state.clone();
+    tokio::spawn(crate::request_id::inherit(async move {
+        tokio::time::sleep(Duration::from_millis(expiry.limits.max_pause_ms)).await;
+        resume_airplay(&expiry, Some(token), "pause expired").await;
+    }));
+    None
+}
+
+/// Resume AirPlay if a calibration paused it; with a `token`, only if that pause still holds.
+async fn resume_airplay(state: &ReceiverState, token: Option<Uuid>, reason: &str) {
+    {
+        let mut slot = state.airplay_pause.lock().unwrap();
+        match (*slot, token) {
+            (None, _) => return,
+            (Some(current), Some(token)) if current != token => return,
+            _ => *slot = None,
+        }
+    }
+    match state.airplay.resume().await {
+        Ok(()) => log_info!("[calibration] resumed airplay ({reason})"),
+        Err(err) => log_warn!("[calibration] failed to resume airplay ({reason}): {err:#}"),
+    }
+}
+
+/// Record a `MarkerEmitted` event as each marker goes out, timed from the start the
+/// playback sink reports, which `clock` places on the runtime's timer. A later report,
+/// from a retried playback, restarts the schedule.
+async fn emit_marker_progress(
+    session: CalibrationSession,
+    emissions: Vec<Emission>,
+    reported_start: Arc<tokio::sync::watch::Sender<Option<u64>>>,
+    clock: fn() -> u64,
+) {
+    // Holding the sender keeps `changed` pending rather than failing once playback is done.
+    let mut starts = reported_start.subscribe();
+    'restart: loop {
+        let Ok(start_ms) = starts.wait_for(Option::is_some).await.map(|start| start.unwrap()) else {
+            return;
+        };
+        starts.mark_unchanged();
+        let start = tokio::time::Instant::now() - Duration::from_millis(clock().saturating_sub(start_ms));
+        for emission in &emissions {
+            let deadline = start + Duration::from_secs_f64(emission.offset_ms.max(0.0) / 1000.0);
+            tokio::select! {
+                () = tokio::time::sleep_until(deadline) => {}
+                _ = starts.changed() => continue 'restart,
+            }
+            session.record(SessionEvent::MarkerEmitted {
+                at_ms: start_ms + emission.offset_ms.round() as u64,
+                marker_id: emission.marker_id.clone(),
+                offset_ms: emission.offset_ms,
+            });
+        }
+        return;
+    }
+}
+
+fn schedule_error(error: &str, message: String) -> Response {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(CalibrationScheduleError {
+            error: error.to_string(),
+            message,
+        }),
+    )
+        .into_response()
 }
 
 async fn calibration_ready(
     State(state): State<ReceiverState>,
     Json(req): Json<CalibrationReadyPayload>,
-) -> StatusCode {
+) -> Response {
     let received_at = req.timestamp.unwrap_or_else(now_millis);
-    let pending = state.pending_playback.lock().unwrap().take();
-    let Some(pending) = pending else {
-        eprintln!("[calibration] ready called with no pending request");
-        return StatusCode::BAD_REQUEST;
+    let limits = state.limits;
+    let mut slot = state.pending_playback.lock().unwrap();
+    let Some((delay_ms, session_id)) = slot.as_ref().map(|p| (p.delay_ms, p.session_id)) else {
+        log_warn!("[calibration] ready called with no pending request");
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    if let Some(other) = req.session_id.filter(|other| *other != session_id) {
+        log_warn!("[calibration] rejecting ready for session {} (pending {})", other, session_id);
+        let body = CalibrationScheduleError {
+            error: "session_mismatch".into(),
+            message: format!("session {other} is not the pending calibration's"),
+        };
+        return (StatusCode::CONFLICT, Json(body)).into_response();
+    }
+
+    let now = now_millis();
+    // A countdown, like the pending delay, counts from when the ready call arrived.
+    let (delay_ms, target_start_ms) = match req.countdown_ms {
+        Some(countdown) => (countdown, None),
+        None => (delay_ms, req.target_start_ms),
     };
+    let schedule = match compute_playback_schedule(now, now, delay_ms, target_start_ms, &limits) {
+        Ok(schedule) => schedule,
+        Err(err) => {
+            log_warn!("[calibration] rejecting ready: {}", err);
+            return schedule_error(err.code(), err.to_string());
+        }
+    };
+    log_info!(
+        "[calibration] playback scheduled - requested_ts={} start_ts={} lateness_ms={} adjusted={}",
+        schedule.requested_start_ms, schedule.start_at_ms, schedule.lateness_ms, schedule.adjusted
+    );
+    let target = schedule.start_at_ms;
+    let pending = slot.take().expect("pending checked above");
+    drop(slot);
 
     let playback = state.playback.clone();
     let request = pending.request.clone();
-    tokio::spawn(async move {
-        let now = now_millis();
-        let mut target = req.target_start_ms.unwrap_or_else(|| now + pending.delay_ms);
-        let min_future = now + 1_500;
-        if target < min_future {
-            println!(
-                "[calibration] target in past/soon; bumping target from {} to {}",
-                target, min_future
-            );
-            target = min_future;
-        }
-        let wait_ms = target.saturating_sub(now);
-        if wait_ms > 0 {
-            tokio::time::sleep(Duration::from_millis(wait_ms)).await;
-        }
+    let last_timing = state.last_timing.clone();
+    let played_spec = match &request {
+        PlaybackRequest::Chirp(_) => None,
+        PlaybackRequest::File(path) => state
+            .structured_signal()
+            .filter(|structured| structured.path == *path)
+            .and_then(|_| state.played_spec()),
+    };
+    let emissions = match &request {
+        PlaybackRequest::Chirp(chirp) => chirp_emissions(chirp),
+        PlaybackRequest::File(_) => played_spec.as_ref().map(marker_emissions).unwrap_or_default(),
+    };
+    let reference_level_db = played_spec.as_ref().and_then(|spec| spec.level_db);
+    // Files other than the structured signal have no known length and aren't timed out.
+    let playback_timeout = match &request {
+        PlaybackRequest::Chirp(chirp) => Some(playback.duration_hint(chirp)),
+        PlaybackRequest::File(_) => played_spec
+            .as_ref()
+            .map(|spec| Duration::from_secs_f64(spec.total_duration_ms() / 1000.0)),
+    }
+    .map(|length| length + Duration::from_millis(limits.playback_grace_ms));
+    // Only the pregenerated structured signal reports its markers as they go out.
+    let progress = match &request {
+        PlaybackRequest::File(_) if !emissions.is_empty() => Some(emissions.clone()),
+        _ => None,
+    };
+    let status = state.status.clone();
+    let session = state.session.clone();
+    let settings = state.settings.clone();
+    let clock = state.clock;
+    session.record(SessionEvent::Ready {
+        at_ms: now,
+        scheduled_start_ms: target,
+    });
+    // Sleep on the monotonic clock from `now`, so a countdown is measured from when the
+    // ready call arrived and wall-clock adjustments can't move the start.
+    let start_deadline = tokio::time::Instant::now() + Duration::from_millis(target - now);
+    tokio::spawn(crate::request_id::inherit(async move {
+        tokio::time::sleep_until(start_deadline).await;
         let start_at = now_millis();
         let slip = start_at as i64 - target as i64;
         if slip.abs() > 50 {
-            eprintln!(
+            log_warn!(
                 "[calibration] warning: playback slip_ms={} (target_ts={}, start_ts={})",
                 slip, target, start_at
             );
         }
-        println!(
+        log_info!(
             "[calibration] scheduling playback - ready_rx_ts={}ms req_ts={}ms target_ts={}ms start_ts={}ms slip_ms={} delay_ms={}",
             received_at,
             pending.requested_at,
@@ -267,37 +1494,81 @@ async fn calibration_ready(
             slip,
             pending.delay_ms
         );
+        let config_generation = settings.generation();
         {
-            let mut last = state.last_timing.lock().unwrap();
+            let mut last = last_timing.lock().unwrap();
             *last = Some(PlaybackTiming {
                 target_ts: target,
                 start_ts: start_at,
-                ready_rx_ts: received_at,
-                request_ts: pending.requested_at,
-                delay_ms: pending.delay_ms,
+                session_id: pending.session_id,
+                emissions,
+                output_device: settings.current().output_device,
+                config_generation,
             });
         }
-        if let Err(err) = playback.play(&request) {
-            eprintln!("[calibration] playback failed: {err:?}");
+        status.record(StatusEvent::CalibrationStarted, start_at);
+        let gain_db = playback.output_gain(&request).map(gain_to_db);
+        session.record(SessionEvent::PlaybackStarted {
+            at_ms: start_at,
+            scheduled_start_ms: target,
+            slip_ms: slip,
+            config_generation,
+            sample_rate: playback.output_rate(),
+            gain_db,
+            level_db: reference_level_db.map(|level| level + gain_db.unwrap_or(0.0)),
+        });
+        let reported_start = Arc::new(tokio::sync::watch::channel(None).0);
+        let emitter = progress.map(|emissions| {
+            tokio::spawn(emit_marker_progress(session.clone(), emissions, reported_start.clone(), clock))
+        });
+        let playing = tokio::task::spawn_blocking(move || {
+            playback.play_reporting_start(&request, &|at_ms| {
+                reported_start.send_replace(Some(at_ms));
+            })
+        });
+        // A timed-out player is left to finish on its own; the round is reported as failed.
+        let played = match playback_timeout {
+            Some(limit) => tokio::time::timeout(limit, playing).await.unwrap_or_else(|_| {
+                Ok(Err(anyhow!("playback did not finish within {}ms", limit.as_millis())))
+            }),
+            None => playing.await,
+        }
+        .unwrap_or_else(|err| Err(anyhow!("playback task failed: {err}")));
+        if let (Err(_), Some(emitter)) = (&played, &emitter) {
+            emitter.abort();
+        }
+        session.record(SessionEvent::PlaybackFinished {
+            at_ms: now_millis(),
+            error: played.as_ref().err().map(|err| format!("{err:#}")),
+        });
+        if let Err(err) = played {
+            log_warn!("[calibration] playback failed: {err:?}");
+            status.record(StatusEvent::Failed(format!("calibration playback failed: {err:#}")), now_millis());
         } else {
+            status.record(StatusEvent::Recovered, now_millis());
             let completed_at = now_millis();
-            println!(
+            log_info!(
                 "[calibration] playback completed start_ts={}ms complete_ts={}ms duration_ms={}",
                 start_at,
                 completed_at,
                 completed_at.saturating_sub(start_at)
             );
         }
-    });
+        status.record(StatusEvent::CalibrationFinished, now_millis());
+    }));
 
-    StatusCode::OK
+    Json(CalibrationReadyResponse {
+        scheduled_start_ms: schedule.start_at_ms,
+        was_adjusted: schedule.adjusted,
+        requested_start_ms: req.countdown_ms.map(|_| schedule.requested_start_ms).or(req.target_start_ms),
+        lateness_ms: schedule.lateness_ms,
+        session_id: Some(session_id),
+    })
+    .into_response()
 }
 
-async fn calibration_result(
-    State(state): State<ReceiverState>,
-    Json(req): Json<CalibrationResultPayload>,
-) -> Result<Json<CalibrationApplyResponse>, StatusCode> {
-    let submission = CalibrationSubmission {
+fn submission_from_payload(req: &CalibrationResultPayload) -> CalibrationSubmission {
+    CalibrationSubmission {
         timestamp: req.timestamp,
         latency_ms: req.latency_ms,
         confidence: req.confidence,
@@ -311,11 +1582,26 @@ async fn calibration_result(
                 latency_ms: d.latency_ms,
             })
             .collect(),
+    }
+}
+
+/// Screen a result and queue it to be applied, answering 202 with the job to poll. Jobs
+/// run one at a time, so a retried submission finds the first applied and is refused as
+/// `already_applied` rather than restarting shairport-sync again. Results have to name the
+/// session they were measured in, so one from an earlier session can't be applied.
+async fn calibration_result(
+    State(state): State<ReceiverState>,
+    Json(req): Json<CalibrationResultPayload>,
+) -> Result<Response, Response> {
+    let Some(session_id) = req.session_id else {
+        log_warn!("[calibration] rejecting result timestamp={} without a session id", req.timestamp);
+        return Err((StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": "missing_session_id"}))).into_response());
     };
+    let mut submission = submission_from_payload(&req);
     if !submission.detections.is_empty() {
         let timing = state.last_timing.lock().unwrap().clone();
         if let Some(t) = timing {
-            println!(
+            log_info!(
                 "[calibration] received detections: count={} target_ts={} start_ts={} slip_ms={} latency_ms={} top_corr={}",
                 submission.detections.len(),
                 t.target_ts,
@@ -329,7 +1615,7 @@ async fn calibration_result(
                     .fold(0.0f32, f32::max)
             );
         } else {
-            println!(
+            log_info!(
                 "[calibration] received detections: count={} latency_ms={} top_corr={}",
                 submission.detections.len(),
                 submission.latency_ms,
@@ -341,719 +1627,6492 @@ async fn calibration_result(
             );
         }
     }
-    let applied = state.calibration.apply(&submission).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    Ok(Json(applied))
+    let markers = match screen_detections(&state, &submission) {
+        None => None,
+        Some(Ok(aggregate)) => {
+            log_info!(
+                "[calibration] latency_ms={} from markers {:?} (phone reported {})",
+                aggregate.latency_ms, aggregate.markers.used, submission.latency_ms
+            );
+            submission.latency_ms = aggregate.latency_ms;
+            submission.confidence = aggregate.confidence;
+            Some(aggregate.markers)
+        }
+        Some(Err(markers)) => {
+            let min_markers = state.limits.detections.min_markers;
+            log_warn!(
+                "[calibration] rejecting result timestamp={}: {} of at least {} markers usable (discarded {:?})",
+                submission.timestamp,
+                markers.used.len(),
+                min_markers,
+                markers.discarded
+            );
+            state.session.record(SessionEvent::ResultRejected {
+                at_ms: now_millis(),
+                reason: "insufficient_detections".into(),
+            });
+            let body = InsufficientDetectionsResponse {
+                error: "insufficient_detections".into(),
+                message: format!("{} usable markers, at least {} required", markers.used.len(), min_markers),
+                markers,
+            };
+            return Err((StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response());
+        }
+    };
+    let expected = ExpectedConfig {
+        output_device: req.expected_output_device.clone(),
+        generation: req.expected_generation,
+        session_id: Some(session_id),
+    };
+    let expected = expected.or_playback(&state);
+    let job_state = state.clone();
+    let job: JobFuture = Box::pin(inherit(async move {
+        let applied = apply_checked(&job_state, &submission, &expected, CalibrationSource::Phone)
+            .await
+            .map_err(ApplyRejection::into_job_error)?;
+        Ok(json_value(&CalibrationResultResponse { applied, markers }))
+    }));
+    let id = state.jobs.submit("calibration_result", job);
+    Ok(job_accepted(id))
 }
 
-async fn receiver_info(State(state): State<ReceiverState>) -> Json<ReceiverInfo> {
-    Json(state.info.clone())
+fn job_accepted(id: Uuid) -> Response {
+    let accepted = JobAccepted::new(id);
+    let location = [(axum::http::header::LOCATION, accepted.status_url.clone())];
+    (StatusCode::ACCEPTED, location, Json(accepted)).into_response()
 }
 
-#[derive(Debug, Serialize)]
-struct CalibrationSpecResponse {
-    spec: CalibrationSignalSpec,
+fn json_value<T: Serialize>(value: &T) -> serde_json::Value {
+    serde_json::to_value(value).expect("response bodies serialize")
 }
 
-async fn calibration_spec(State(state): State<ReceiverState>) -> Result<Json<CalibrationSpecResponse>, StatusCode> {
-    let Some(structured) = &state.structured else {
-        return Err(StatusCode::NOT_FOUND);
+/// Refuse requests without the configured admin token in `X-Admin-Token`: 401 when it is
+/// missing or wrong, 403 when no token is configured.
+async fn require_admin_token(
+    State(state): State<ReceiverState>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let Some(tokens) = &state.admin_tokens else {
+        return (StatusCode::FORBIDDEN, Json(serde_json::json!({"error": "admin_token_not_configured"}))).into_response();
     };
-    Ok(Json(CalibrationSpecResponse {
-        spec: structured.spec.clone(),
-    }))
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct TimeSyncResponse {
-    server_time_ms: u64,
-}
-
-async fn time_sync() -> Json<TimeSyncResponse> {
-    let now = now_millis();
-    println!("[time] /api/time called server_time_ms={}", now);
-    Json(TimeSyncResponse { server_time_ms: now })
+    let presented = request.headers().get("x-admin-token").map(|value| value.as_bytes());
+    if !presented.is_some_and(|presented| tokens.accepts(presented, state.now_ms())) {
+        log_warn!("[http] refusing {} without a valid admin token", request.uri().path());
+        return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error": "invalid_admin_token"}))).into_response();
+    }
+    next.run(request).await
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SettingsResponse {
-    pub device_name: String,
-    pub output_device: String,
-    pub latency_offset_seconds: f32,
+/// Replace the admin token, answering with the new one; it isn't sent again. The token
+/// used for the call is still accepted for the grace period, so clients can switch over.
+async fn rotate_admin_token(State(state): State<ReceiverState>) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
+    let tokens = state.admin_tokens.as_ref().expect("require_admin_token refuses receivers without one");
+    let rotated = tokens.rotate(state.now_ms()).map_err(|err| {
+        log_warn!("[admin] failed to rotate the admin token: {err:#}");
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "token_rotation_failed"})))
+    })?;
+    log_info!(
+        "[admin] rotated the admin token; the previous one is accepted until {}",
+        rotated.previous_valid_until_ms
+    );
+    let no_store = [(axum::http::header::CACHE_CONTROL, "no-store")];
+    Ok((no_store, Json::<RotatedToken>(rotated)).into_response())
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct SettingsUpdatePayload {
-    pub device_name: Option<String>,
-    pub output_device: Option<String>,
-    pub latency_offset_seconds: Option<f32>,
+/// Replace the HTTPS certificate, answering with the endpoint and its new fingerprint.
+/// Clients that pinned the old fingerprint have to pair again.
+async fn regenerate_certificate(State(state): State<ReceiverState>) -> Result<Json<TlsEndpoint>, (StatusCode, Json<serde_json::Value>)> {
+    let port = state.tls.read().unwrap().as_ref().map(|endpoint| endpoint.port);
+    let (Some(rotator), Some(port)) = (state.certificates.clone(), port) else {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "tls_disabled"}))));
+    };
+    let fingerprint = rotator.regenerate().await.map_err(|err| {
+        log_warn!("[tls] failed to regenerate the certificate: {err:#}");
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"error": "tls_regeneration_failed"})))
+    })?;
+    log_info!("[tls] regenerated the certificate, sha256={fingerprint}");
+    let endpoint = TlsEndpoint { port, fingerprint };
+    *state.tls.write().unwrap() = Some(endpoint.clone());
+    Ok(Json(endpoint))
 }
 
-async fn get_settings(State(state): State<ReceiverState>) -> Json<SettingsResponse> {
-    let cfg = state.settings.current();
-    Json(SettingsResponse {
-        device_name: cfg.device_name,
-        output_device: cfg.output_device,
-        latency_offset_seconds: cfg.latency_offset_seconds,
-    })
+/// Download and verify the posted firmware image on its own task, then queue its install,
+/// answering with the job that reports how it went.
+async fn firmware_update(
+    State(state): State<ReceiverState>,
+    Json(req): Json<FirmwareUpdateRequest>,
+) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
+    let Some(updater) = state.firmware.clone() else {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": "firmware_updates_disabled"}))));
+    };
+    if let Err(error) = req.validate() {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, Json(serde_json::json!({"error": error}))));
+    }
+    log_info!("[firmware] update to {} requested from {}", req.version, req.url);
+    let prepare = Box::pin(inherit(async move {
+        let image = updater.download(&req).await.map_err(|err| err.into_job_error())?;
+        let install: JobFuture = Box::pin(inherit(async move {
+            let outcome = updater.install(image, &req).await.map_err(|err| err.into_job_error())?;
+            Ok(json_value(&outcome))
+        }));
+        Ok(install)
+    }));
+    let accepted = FirmwareUpdateAccepted::new(state.jobs.submit_prepared("firmware_update", prepare));
+    let location = [(axum::http::header::LOCATION, accepted.status_url.clone())];
+    Ok((StatusCode::ACCEPTED, location, Json(accepted)).into_response())
 }
 
-async fn update_settings(
-    State(state): State<ReceiverState>,
-    Json(req): Json<SettingsUpdatePayload>,
-) -> Result<Json<SettingsResponse>, StatusCode> {
-    let cfg = state.settings.update(req).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    Ok(Json(SettingsResponse {
-        device_name: cfg.device_name,
-        output_device: cfg.output_device,
-        latency_offset_seconds: cfg.latency_offset_seconds,
-    }))
+async fn job_status(State(state): State<ReceiverState>, UrlPath(id): UrlPath<Uuid>) -> Result<Json<JobStatus>, StatusCode> {
+    state.jobs.status(id).map(Json).ok_or(StatusCode::NOT_FOUND)
 }
 
-pub struct ShairportSettingsManager<W: ConfigWriter + Send + Sync + 'static, C: ShairportController + Send + Sync + 'static> {
-    writer: W,
-    controller: C,
-    config: Arc<Mutex<ShairportConfig>>,
+/// Recompute the latency from the per-marker latencies in `submission`, weighting markers
+/// by the kind the structured signal gives them. `None` when no detection carries both a
+/// marker id and a latency, in which case the phone's latency stands.
+fn screen_detections(
+    state: &ReceiverState,
+    submission: &CalibrationSubmission,
+) -> Option<Result<DetectionAggregate, MarkerUsage>> {
+    let class_of = |id: &str| {
+        let spec = &state.structured_signal()?.spec;
+        spec.markers.iter().find(|m| m.id == id).map(|m| MarkerClass::of(&m.kind))
+    };
+    let detections: Vec<MarkerDetection> = submission
+        .detections
+        .iter()
+        .filter_map(|d| {
+            let marker_id = d.marker_id.clone()?;
+            Some(MarkerDetection {
+                class: class_of(&marker_id),
+                marker_id,
+                latency_ms: d.latency_ms?,
+                correlation: d.correlation,
+            })
+        })
+        .collect();
+    if detections.is_empty() {
+        return None;
+    }
+    Some(aggregate_detections(&detections, &state.limits.detections))
 }
 
-pub struct NoopPlaybackSink;
+/// Sample rate detection positions are reported at in the submitted `DetectionReport`s.
+const DETECTION_SAMPLE_RATE_HZ: u64 = 48_000;
 
-impl PlaybackSink for NoopPlaybackSink {
-    fn play(&self, _request: &PlaybackRequest) -> Result<()> {
-        Ok(())
-    }
+/// Compute latency from raw detection times against the last playback's emission times,
+/// then apply it like a phone-computed result.
+async fn calibration_data(
+    State(state): State<ReceiverState>,
+    Json(req): Json<CalibrationDataPayload>,
+) -> Result<Json<CalibrationDataResponse>, Response> {
+    let Some(timing) = state.last_timing.lock().unwrap().clone() else {
+        return Err(schedule_error("no_playback", "no calibration playback to measure against".into()));
+    };
+    let detected_at: Vec<u64> = req
+        .chirp_detection_times
+        .iter()
+        .map(|t| req.recording_start_time + t)
+        .collect();
+    let alignment = match &req.marker_ids {
+        Some(ids) if ids.len() != detected_at.len() => {
+            return Err(schedule_error(
+                "marker_ids_mismatch",
+                format!("{} marker ids for {} detection times", ids.len(), detected_at.len()),
+            ));
+        }
+        Some(ids) => {
+            let detections: Vec<(String, u64)> = ids.iter().cloned().zip(detected_at).collect();
+            align_by_id(timing.start_ts, &timing.emissions, &detections)
+        }
+        None => align_by_order(timing.start_ts, &timing.emissions, &detected_at),
+    };
+    let Some(measurement) = measure_latency(alignment, req.confidence) else {
+        log_warn!(
+            "[calibration] none of {} detections matched the {} emitted markers",
+            req.chirp_detection_times.len(),
+            timing.emissions.len()
+        );
+        return Err(schedule_error(
+            "no_detections",
+            "no detection matched an emitted marker".into(),
+        ));
+    };
+    log_info!(
+        "[calibration] computed latency_ms={} from {} markers (missing={:?} rejected={:?} unmatched={})",
+        measurement.latency_ms,
+        measurement.markers.len(),
+        measurement.missing_markers,
+        measurement.rejected_markers,
+        measurement.unmatched_detections.len()
+    );
+    let submission = CalibrationSubmission {
+        timestamp: req.timestamp,
+        latency_ms: measurement.latency_ms,
+        confidence: measurement.confidence,
+        detections: measurement
+            .markers
+            .iter()
+            .map(|m| airsync_shared_protocol::DetectionReport {
+                marker_id: Some(m.marker_id.clone()),
+                sample_index: (m.detected_at_ms.saturating_sub(req.recording_start_time) * DETECTION_SAMPLE_RATE_HZ
+                    / 1000) as u32,
+                correlation: req.confidence,
+                latency_ms: Some(m.latency_ms),
+            })
+            .collect(),
+    };
+    let expected = ExpectedConfig {
+        session_id: req.session_id,
+        ..ExpectedConfig::default()
+    };
+    let applied = apply_checked(&state, &submission, &expected.or_playback(&state), CalibrationSource::Phone)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    Ok(Json(CalibrationDataResponse { measurement, applied }))
 }
 
-pub struct SystemPlaybackSink {
-    sample_rate: u32,
-    gain: f32,
-    config: Arc<Mutex<ShairportConfig>>,
-    pregen_path: Option<std::path::PathBuf>,
+enum ApplyRejection {
+    Conflict(CalibrationConflictResponse),
+    Failed,
 }
 
-impl SystemPlaybackSink {
-    pub fn new(
-        sample_rate: u32,
-        config: Arc<Mutex<ShairportConfig>>,
-        gain: f32,
-        pregen_path: Option<std::path::PathBuf>,
-    ) -> Self {
-        Self {
-            sample_rate,
-            gain,
-            config,
-            pregen_path,
+impl ApplyRejection {
+    fn into_job_error(self) -> JobError {
+        match self {
+            ApplyRejection::Conflict(body) => JobError {
+                status: StatusCode::CONFLICT.as_u16(),
+                body: json_value(&body),
+            },
+            ApplyRejection::Failed => JobError {
+                status: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                body: serde_json::json!({"error": "apply_failed"}),
+            },
         }
     }
+}
 
-    fn write_wave(&self, chirp: &ChirpConfig) -> Result<tempfile::NamedTempFile> {
-        let file = tempfile::NamedTempFile::new()?;
-        let spec = hound::WavSpec {
-            channels: 1,
-            sample_rate: self.sample_rate,
-            bits_per_sample: 16,
-            sample_format: hound::SampleFormat::Int,
-        };
-        let mut writer = hound::WavWriter::create(file.path(), spec)?;
-        let samples = generate_chirp_samples(
-            chirp,
-            self.sample_rate,
-            (self.gain * chirp.amplitude.unwrap_or(1.0)).clamp(0.0, 1.0),
-        );
-        for s in samples {
-            writer.write_sample(s)?;
+impl IntoResponse for ApplyRejection {
+    fn into_response(self) -> Response {
+        match self {
+            ApplyRejection::Conflict(body) => (StatusCode::CONFLICT, Json(body)).into_response(),
+            ApplyRejection::Failed => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
-        writer.finalize()?;
-        Ok(file)
     }
 }
 
-impl PlaybackSink for SystemPlaybackSink {
-    fn play(&self, request: &PlaybackRequest) -> Result<()> {
-        let wav_path = match request {
-            PlaybackRequest::Chirp(chirp) => {
-                let use_pregen = chirp.amplitude.unwrap_or(1.0) >= 0.99 && self.pregen_path.is_some();
-                if use_pregen {
-                    self.pregen_path.clone().unwrap()
-                } else {
-                    let file = self.write_wave(chirp)?;
-                    file.into_temp_path().keep()?
-                }
-            }
-            PlaybackRequest::File(path) => path.clone(),
-        };
-        let mut cmd = Command::new("aplay");
-        let dev = { self.config.lock().unwrap().output_device.clone() };
-        if !dev.is_empty() {
-            cmd.args(["-D", dev.as_str()]);
-        }
-        cmd.args(["-q", wav_path.to_str().unwrap_or("")]);
-        println!(
-            "[calibration] invoking aplay device={} file={}",
-            if dev.is_empty() { "<default>" } else { dev.as_str() },
-            wav_path.to_string_lossy()
+/// Apply a submission through the calibration sink unless it is older than, or the same
+/// as, the result already in effect.
+async fn apply_checked(
+    state: &ReceiverState,
+    submission: &CalibrationSubmission,
+    expected: &ExpectedConfig,
+    source: CalibrationSource,
+) -> Result<CalibrationApplyResponse, ApplyRejection> {
+    // Hold the apply lock across the apply so two concurrent results can't both pass the check.
+    let _applying = state.apply_lock.lock().await;
+    let last_applied = state.last_applied.current();
+    let output_device = state.settings.current().output_device;
+    let generation = state.settings.generation();
+    let played_session = state.last_timing.lock().unwrap().as_ref().map(|timing| timing.session_id);
+    let conflict = match last_applied.as_ref() {
+        Some(current) if submission.timestamp < current.timestamp => Some("stale_result"),
+        Some(current) if submission.timestamp == current.timestamp => Some("already_applied"),
+        _ if expected.output_device.as_ref().is_some_and(|d| *d != output_device) => {
+            Some("output_device_changed")
+        }
+        _ if expected.generation.is_some_and(|g| g != generation) => Some("config_generation_changed"),
+        _ if expected.session_id.is_some_and(|s| Some(s) != played_session) => Some("session_mismatch"),
+        _ => None,
+    };
+    if let Some(conflict) = conflict {
+        state.session.record(SessionEvent::ResultRejected {
+            at_ms: now_millis(),
+            reason: conflict.to_string(),
+        });
+        log_warn!(
+            "[calibration] rejecting result timestamp={} ({}); current={:?} output_device={} generation={}",
+            submission.timestamp,
+            conflict,
+            last_applied.as_ref().map(|c| c.timestamp),
+            output_device,
+            generation
         );
-        let run_cmd = |mut c: Command| -> Result<()> {
-            match c.status() {
-                Ok(s) if s.success() => Ok(()),
-                Ok(s) => Err(anyhow!("aplay failed with status {}", s)),
-                Err(e) => Err(anyhow!("failed to run aplay: {}", e)),
-            }
-        };
-        let mut retry_cmd = Command::new("aplay");
-        if !dev.is_empty() {
-            retry_cmd.args(["-D", dev.as_str()]);
+        return Err(ApplyRejection::Conflict(CalibrationConflictResponse {
+            error: conflict.to_string(),
+            submitted_timestamp: submission.timestamp,
+            current: last_applied,
+            output_device,
+            config_generation: generation,
+        }));
+    }
+    let applied = match state.calibration.apply(submission).await {
+        Ok(applied) => applied,
+        Err(err) => {
+            state
+                .status
+                .record(StatusEvent::Failed(format!("applying calibration failed: {err:#}")), now_millis());
+            state.session.record(SessionEvent::ResultRejected {
+                at_ms: now_millis(),
+                reason: "apply_failed".into(),
+            });
+            return Err(ApplyRejection::Failed);
         }
-        retry_cmd.args(["-q", wav_path.to_str().unwrap_or("")]);
+    };
+    state.session.record(SessionEvent::ResultApplied {
+        at_ms: now_millis(),
+        applied_offset_ms: applied.applied_offset_ms,
+        config_generation: applied.config_generation,
+    });
+    state.status.record(StatusEvent::Paired, now_millis());
+    state.status.record(StatusEvent::Recovered, now_millis());
+    record_applied(
+        state,
+        AppliedCalibration {
+            timestamp: submission.timestamp,
+            measured_latency_ms: applied.measured_latency_ms,
+            applied_offset_ms: applied.applied_offset_ms,
+            confidence: submission.confidence,
+            output_device: applied.output_device.clone(),
+            source,
+            config_generation: Some(applied.config_generation),
+            temperature_c: soc_temperature(state).await,
+        },
+    );
+    resume_airplay(state, None, "result applied").await;
+    Ok(applied)
+}
 
-        if let Err(e) = run_cmd(cmd) {
-            // Retry once after a brief pause (helps with transient device busy)
-            std::thread::sleep(std::time::Duration::from_millis(120));
-            println!("[calibration] retrying aplay after error: {e}");
-            run_cmd(retry_cmd).map_err(|e2| anyhow!("{e}; retry_error={e2}"))
-        } else {
-            println!("[calibration] aplay completed OK");
-            Ok(())
-        }
+fn record_applied(state: &ReceiverState, applied: AppliedCalibration) {
+    if let Err(err) = state.last_applied.record(applied, state.settings.current()) {
+        log_warn!("[calibration] failed to persist the applied calibration: {err:#}");
     }
 }
 
-impl<W: ConfigWriter + Send + Sync + 'static, C: ShairportController + Send + Sync + 'static>
-    ShairportSettingsManager<W, C>
-{
-    pub fn new(writer: W, controller: C, config: Arc<Mutex<ShairportConfig>>) -> Self {
-        Self { writer, controller, config }
-    }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CurrentCalibrationResponse {
+    #[serde(flatten)]
+    pub applied: AppliedCalibration,
+    pub freshness: FreshnessReport,
 }
 
-impl<W: ConfigWriter + Send + Sync + 'static, C: ShairportController + Send + Sync + 'static>
-    SettingsManager for ShairportSettingsManager<W, C>
-{
-    fn current(&self) -> ShairportConfig {
-        self.config.lock().unwrap().clone()
-    }
+async fn calibration_current(State(state): State<ReceiverState>) -> Result<Json<CurrentCalibrationResponse>, StatusCode> {
+    let applied = state.last_applied.current().ok_or(StatusCode::NOT_FOUND)?;
+    let freshness = calibration_freshness(&state, &applied).await;
+    Ok(Json(CurrentCalibrationResponse { applied, freshness }))
+}
 
-    fn update(&self, update: SettingsUpdatePayload) -> Result<ShairportConfig> {
-        let mut cfg = self.config.lock().unwrap();
-        if let Some(name) = update.device_name {
-            cfg.device_name = name;
-        }
-        if let Some(output) = update.output_device {
-            cfg.output_device = output;
+/// Re-apply the most recently applied calibration from the history, config included,
+/// without playing anything; `404` when nothing was ever applied.
+async fn calibration_replay(State(state): State<ReceiverState>) -> Result<Json<CalibrationApplyResponse>, StatusCode> {
+    let _applying = state.apply_lock.lock().await;
+    let history = state.last_applied.history();
+    let entry = history.last().cloned().ok_or(StatusCode::NOT_FOUND)?;
+    let applied = state.calibration.replay(&history).await.map_err(|err| {
+        log_warn!("[calibration] replaying calibration from {} failed: {err:#}", entry.applied.timestamp);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    log_info!(
+        "[calibration] replayed calibration from {}: offset {}ms",
+        entry.applied.timestamp,
+        applied.applied_offset_ms
+    );
+    record_applied(
+        &state,
+        AppliedCalibration {
+            timestamp: (state.clock)(),
+            measured_latency_ms: applied.measured_latency_ms,
+            applied_offset_ms: applied.applied_offset_ms,
+            confidence: entry.applied.confidence,
+            output_device: applied.output_device.clone(),
+            source: entry.applied.source,
+            config_generation: Some(applied.config_generation),
+            temperature_c: soc_temperature(&state).await,
+        },
+    );
+    Ok(Json(applied))
+}
+
+/// SoC temperature from the hardware probe, when there is one and it reports it.
+async fn soc_temperature(state: &ReceiverState) -> Option<f32> {
+    let probe = state.hardware.clone()?;
+    match tokio::task::spawn_blocking(move || probe.detect()).await {
+        Ok(Ok(capabilities)) => capabilities.temperature_c,
+        Ok(Err(err)) => {
+            log_warn!("[hardware] detection failed: {err:#}");
+            None
         }
-        if let Some(latency) = update.latency_offset_seconds {
-            cfg.latency_offset_seconds = latency;
+        Err(err) => {
+            log_warn!("[hardware] detection task failed: {err}");
+            None
         }
-        let rendered = render_config_file(&cfg);
-        self.writer.write(&rendered)?;
-        self.controller.restart()?;
-        Ok(cfg.clone())
     }
 }
 
-pub async fn serve(router: Router, addr: SocketAddr) -> Result<()> {
-    let listener = TcpListener::bind(addr).await.context("bind")?;
-    axum::serve(listener, router).await.context("serve")?;
-    Ok(())
+/// Judge `applied` against the receiver as it is now. Restarts are the settings changes
+/// since it was applied plus watchdog restarts after it; generations reset when the
+/// service does, so a reboot can undercount them.
+async fn calibration_freshness(state: &ReceiverState, applied: &AppliedCalibration) -> FreshnessReport {
+    let settings_restarts = applied
+        .config_generation
+        .map_or(0, |generation| state.settings.generation().saturating_sub(generation));
+    let watchdog_restarts = state.watchdog.as_ref().map_or(0, |watchdog| {
+        watchdog
+            .snapshot()
+            .restarts
+            .iter()
+            .filter(|restart| restart.at_ms > applied.timestamp)
+            .count() as u64
+    });
+    let inputs = FreshnessInputs {
+        now_ms: (state.clock)(),
+        calibrated_at_ms: applied.timestamp,
+        restarts_since: u32::try_from(settings_restarts + watchdog_restarts).unwrap_or(u32::MAX),
+        calibrated_output: applied.output_device.clone(),
+        current_output: state.settings.current().output_device,
+        calibrated_temperature_c: applied.temperature_c,
+        current_temperature_c: match applied.temperature_c {
+            Some(_) => soc_temperature(state).await,
+            None => None,
+        },
+    };
+    CalibrationFreshness::new(state.limits.freshness).evaluate(&inputs)
 }
 
-pub fn load_or_create_receiver_id(path: &Path) -> Result<String> {
-    if path.exists() {
-        let bytes = std::fs::read(path)?;
-        let existing: StoredReceiver = serde_json::from_slice(&bytes)?;
-        Ok(existing.receiver_id)
-    } else {
-        let id = Uuid::new_v4().to_string();
-        let stored = StoredReceiver { receiver_id: id.clone() };
-        let parent = path.parent().unwrap_or_else(|| Path::new("."));
-        std::fs::create_dir_all(parent)?;
-        std::fs::write(path, serde_json::to_vec_pretty(&stored)?)?;
-        Ok(id)
-    }
-}
+/// Comment line sent on idle calibration event streams so proxies keep them open.
+pub const SSE_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
 
-#[derive(Serialize, Deserialize)]
-struct StoredReceiver {
-    receiver_id: String,
+/// Stream session transitions as server-sent events, starting with a `snapshot` of the
+/// current state. A client that falls too far behind gets a fresh snapshot instead of
+/// the events it missed.
+async fn calibration_events(
+    State(state): State<ReceiverState>,
+) -> Sse<impl futures_util::Stream<Item = Result<Event, axum::Error>>> {
+    use futures_util::stream::{self, StreamExt};
+
+    let (snapshot, updates) = state.session.subscribe();
+    let session = state.session.clone();
+    let first = stream::once(std::future::ready(Event::default().event("snapshot").json_data(snapshot)));
+    let rest = stream::unfold((updates, 0), move |(mut updates, reported)| {
+        let session = session.clone();
+        async move {
+            let event = updates.recv().await?;
+            let dropped = updates.dropped_count();
+            let event = if dropped > reported {
+                log_warn!("[calibration] event stream lagged by {} events; resending snapshot", dropped - reported);
+                Event::default().event("snapshot").json_data(session.snapshot())
+            } else {
+                Event::default().event(event.name()).json_data(event)
+            };
+            Some((event, (updates, dropped)))
+        }
+    });
+    Sse::new(first.chain(rest)).keep_alive(
+        KeepAlive::new()
+            .interval(SSE_HEARTBEAT_INTERVAL)
+            .text("heartbeat"),
+    )
 }
 
-pub fn render_avahi_service(name: &str, receiver_id: &str, port: u16, caps: &[&str]) -> String {
-    let caps_str = caps.join(",");
-    format!(
-        r#"<service-group>
-  <name replace-wildcards="yes">{name}</name>
-  <service>
-    <type>_airsync._tcp</type>
-    <port>{port}</port>
-    <txt-record>name={name}</txt-record>
-    <txt-record>ver=1</txt-record>
-    <txt-record>api=/api</txt-record>
-    <txt-record>caps={caps}</txt-record>
-    <txt-record>id={id}</txt-record>
-  </service>
-</service-group>
-"#,
-        name = name,
-        port = port,
-        caps = caps_str,
-        id = receiver_id
+async fn calibration_round_result(
+    State(state): State<ReceiverState>,
+    Json(req): Json<CalibrationResultPayload>,
+) -> Json<RoundResultResponse> {
+    let mut rounds = state.rounds.lock().unwrap();
+    log_info!(
+        "[calibration] recorded round {} latency_ms={} confidence={}",
+        rounds.len(),
+        req.latency_ms,
+        req.confidence
+    );
+    rounds.push(req);
+    Json(RoundResultResponse {
+        round: rounds.len() - 1,
+        rounds_recorded: rounds.len(),
+    })
+}
+
+async fn calibration_finalize(
+    State(state): State<ReceiverState>,
+    Json(req): Json<CalibrationFinalizePayload>,
+) -> Result<Json<CalibrationFinalizeResponse>, Response> {
+    // Taken out rather than copied, so rounds recorded while this applies are kept for
+    // the next finalize; the taken ones are put back if it fails.
+    let rounds = std::mem::take(&mut *state.rounds.lock().unwrap());
+    let restore = |rounds: Vec<CalibrationResultPayload>| {
+        let mut current = state.rounds.lock().unwrap();
+        let newer = std::mem::replace(&mut *current, rounds);
+        current.extend(newer);
+    };
+    let measurements: Vec<RoundMeasurement> = rounds
+        .iter()
+        .map(|r| RoundMeasurement {
+            latency_ms: r.latency_ms,
+            confidence: r.confidence,
+        })
+        .collect();
+    let Some(aggregate) = aggregate_rounds(&measurements) else {
+        log_warn!("[calibration] finalize called with no usable rounds");
+        restore(rounds);
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    };
+
+    let timestamp = req
+        .timestamp
+        .unwrap_or_else(|| rounds.iter().map(|r| r.timestamp).max().unwrap_or(0));
+    let submission = CalibrationSubmission {
+        timestamp,
+        latency_ms: aggregate.latency_ms,
+        confidence: aggregate.confidence,
+        detections: aggregate
+            .accepted
+            .iter()
+            .flat_map(|&i| submission_from_payload(&rounds[i]).detections)
+            .collect(),
+    };
+    log_info!(
+        "[calibration] finalizing {} rounds: aggregate latency_ms={} confidence={} rejected={:?}",
+        rounds.len(),
+        aggregate.latency_ms,
+        aggregate.confidence,
+        aggregate.rejected
+    );
+    let applied = match apply_checked(&state, &submission, &ExpectedConfig::default(), CalibrationSource::Phone).await {
+        Ok(applied) => applied,
+        Err(rejection) => {
+            restore(rounds);
+            return Err(rejection.into_response());
+        }
+    };
+
+    Ok(Json(CalibrationFinalizeResponse {
+        rounds: rounds
+            .iter()
+            .enumerate()
+            .map(|(i, r)| RoundSummary {
+                round: i,
+                timestamp: r.timestamp,
+                latency_ms: r.latency_ms,
+                confidence: r.confidence,
+                rejected: aggregate.rejected.contains(&i),
+            })
+            .collect(),
+        rejected: aggregate.rejected.clone(),
+        aggregate_latency_ms: aggregate.latency_ms,
+        aggregate_confidence: aggregate.confidence,
+        applied,
+    }))
+}
+
+/// Sample zero-mean Gaussian noise via the Box-Muller transform.
+#[cfg(feature = "simulation")]
+fn gaussian_noise<R: rand::Rng>(rng: &mut R, stddev: f32) -> f32 {
+    if stddev <= 0.0 {
+        return 0.0;
+    }
+    let u1: f32 = rng.gen_range(f32::EPSILON..1.0);
+    let u2: f32 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos() * stddev
+}
+
+/// Apply a synthetic measurement without playing audio, for end-to-end tests.
+#[cfg(feature = "simulation")]
+async fn calibration_simulate(
+    State(state): State<ReceiverState>,
+    Json(req): Json<CalibrationSimulationPayload>,
+) -> Result<Json<CalibrationSimulationReport>, Response> {
+    use rand::SeedableRng;
+    if !req.true_latency_ms.is_finite() || !req.noise_stddev_ms.is_finite() || req.noise_stddev_ms < 0.0 {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY.into_response());
+    }
+    let noise_ms = match req.seed {
+        Some(seed) => gaussian_noise(&mut rand::rngs::StdRng::seed_from_u64(seed), req.noise_stddev_ms),
+        None => gaussian_noise(&mut rand::thread_rng(), req.noise_stddev_ms),
+    };
+    let submission = CalibrationSubmission {
+        timestamp: now_millis(),
+        latency_ms: req.true_latency_ms + noise_ms,
+        confidence: 1.0,
+        detections: Vec::new(),
+    };
+    log_info!(
+        "[calibration] simulating result true_latency_ms={} noise_ms={} submitted_latency_ms={}",
+        req.true_latency_ms, noise_ms, submission.latency_ms
+    );
+    let applied = apply_checked(&state, &submission, &ExpectedConfig::default(), CalibrationSource::Phone)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    Ok(Json(CalibrationSimulationReport {
+        input_latency_ms: req.true_latency_ms,
+        noise_ms,
+        submitted_latency_ms: submission.latency_ms,
+        applied,
+    }))
+}
+
+async fn recent_requests(State(state): State<ReceiverState>) -> Json<RecentRequestsResponse> {
+    Json(state.access_log.snapshot())
+}
+
+async fn config_changes(State(state): State<ReceiverState>) -> Json<Vec<ConfigChange>> {
+    Json(state.config_changes.snapshot())
+}
+
+async fn receiver_info(
+    State(state): State<ReceiverState>,
+    connection: Option<ConnectInfo<ConnectionInfo>>,
+) -> Json<ReceiverInfoResponse> {
+    let local = connection.map(|ConnectInfo(conn)| conn.local.ip());
+    let network = match state.network.addresses() {
+        Ok(addresses) => Some(summarize_interfaces(addresses, local)),
+        Err(err) => {
+            log_warn!("[http] {err:#}");
+            None
+        }
+    };
+    Json(ReceiverInfoResponse {
+        info: state.info.clone(),
+        network,
+        tls: state.tls.read().unwrap().clone(),
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct CalibrationSpecResponse {
+    spec: CalibrationSignalSpec,
+}
+
+async fn calibration_spec(State(state): State<ReceiverState>) -> Result<Json<CalibrationSpecResponse>, StatusCode> {
+    let Some(spec) = state.played_spec() else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    Ok(Json(CalibrationSpecResponse { spec }))
+}
+
+async fn get_signal_spec(State(state): State<ReceiverState>) -> Result<Json<CalibrationSignalSpec>, StatusCode> {
+    state.played_spec().map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Longest custom signal accepted, so a posted spec can't have the receiver render
+/// minutes of audio.
+pub const MAX_CUSTOM_SIGNAL_MS: u64 = 30_000;
+
+/// Replace the structured signal with one rendered from the posted spec. It is written
+/// next to the generated signal and can be set once per run; later posts get 409.
+async fn post_signal_spec(State(state): State<ReceiverState>, Json(mut spec): Json<CalibrationSignalSpec>) -> Response {
+    let Some(generated) = &state.structured else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let already_set = || {
+        (
+            StatusCode::CONFLICT,
+            Json(CalibrationScheduleError {
+                error: "spec_already_set".into(),
+                message: "a custom signal spec is already in use".into(),
+            }),
+        )
+            .into_response()
+    };
+    if state.custom_signal.get().is_some() {
+        return already_set();
+    }
+    if let Err(err) = spec.validate() {
+        return schedule_error("invalid_spec", err.to_string());
+    }
+    if spec.sample_rate == 0
+        || u64::from(spec.length_samples) * 1000 > MAX_CUSTOM_SIGNAL_MS * u64::from(spec.sample_rate)
+    {
+        return schedule_error(
+            "invalid_spec",
+            format!("signal must be at most {MAX_CUSTOM_SIGNAL_MS}ms at a sample rate above 0 Hz"),
+        );
+    }
+    // The playback sink scales and resamples 16-bit files only.
+    spec.sample_format = SampleFormat::I16;
+    let path = generated
+        .path
+        .with_file_name(format!("structured_custom_{}.wav", Uuid::new_v4().simple()));
+    let rendered = tokio::task::spawn_blocking(move || render_structured_signal(&path, spec))
+        .await
+        .map_err(|err| anyhow!("signal render task failed: {err}"))
+        .and_then(|rendered| rendered);
+    let signal = match rendered {
+        Ok(signal) => signal,
+        Err(err) => {
+            log_warn!("[calibration] failed to render custom signal: {err:#}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let spec = signal.spec.clone();
+    if let Err(lost) = state.custom_signal.set(signal) {
+        let _ = std::fs::remove_file(&lost.path);
+        return already_set();
+    }
+    log_info!(
+        "[calibration] using custom signal with {} markers ({} samples)",
+        spec.markers.len(),
+        spec.length_samples
+    );
+    Json(spec).into_response()
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct MarkerReferenceQuery {
+    #[serde(default)]
+    format: Option<String>,
+}
+
+/// JSON form of `GET /api/calibration/signal/reference/{marker_id}?format=json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MarkerReferenceResponse {
+    pub marker_id: String,
+    pub sample_rate: u32,
+    pub samples: Vec<f32>,
+}
+
+/// Matched-filter reference for one marker of the structured signal, as raw little-endian
+/// f32 by default or JSON with `?format=json`.
+async fn marker_reference(
+    State(state): State<ReceiverState>,
+    UrlPath(marker_id): UrlPath<String>,
+    Query(query): Query<MarkerReferenceQuery>,
+) -> Result<Response, StatusCode> {
+    let Some(spec) = state.played_spec() else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let marker = spec
+        .markers
+        .iter()
+        .find(|m| m.id == marker_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let samples = reference_samples(marker, spec.sample_rate);
+    match query.format.as_deref() {
+        Some("json") => Ok(Json(MarkerReferenceResponse {
+            marker_id,
+            sample_rate: spec.sample_rate,
+            samples,
+        })
+        .into_response()),
+        None | Some("f32le") => {
+            let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+            Ok(([(axum::http::header::CONTENT_TYPE, "application/octet-stream")], bytes).into_response())
+        }
+        Some(_) => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+/// The structured signal as it is played, tagged with its spec's `content_hash` so
+/// clients can revalidate a cached copy; a matching `If-None-Match` gets `304`.
+async fn signal_wav(State(state): State<ReceiverState>, headers: axum::http::HeaderMap) -> Result<Response, StatusCode> {
+    use axum::http::header::{CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+
+    let (Some(signal), Some(spec)) = (state.structured_signal().cloned(), state.played_spec()) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let etag = spec.content_hash.as_ref().map(|hash| format!("\"{hash}\""));
+    if let Some(etag) = &etag {
+        let cached = headers
+            .get_all(IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|tag| tag.trim() == etag || tag.trim() == "*");
+        if cached {
+            return Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag.clone())]).into_response());
+        }
+    }
+    let rate = spec.sample_rate;
+    let bytes = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+        if rate == signal.spec.sample_rate {
+            return Ok(std::fs::read(&signal.path)?);
+        }
+        let converted = tempfile::NamedTempFile::new()?;
+        resample_wav(&signal.path, converted.path(), rate, 1.0)?;
+        Ok(std::fs::read(converted.path())?)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|err| {
+        log_warn!("[calibration] failed to read the structured signal: {err:#}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let mut response = ([(CONTENT_TYPE, "audio/wav")], bytes).into_response();
+    if let Some(etag) = etag {
+        if let Ok(value) = etag.parse() {
+            response.headers_mut().insert(ETAG, value);
+        }
+    }
+    Ok(response)
+}
+
+async fn playback_status(State(state): State<ReceiverState>) -> Json<StatusSnapshot> {
+    Json(state.status.refresh(now_millis()))
+}
+
+/// Track metadata from `/api/metadata`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetadataResponse {
+    #[serde(flatten)]
+    pub metadata: Metadata,
+    /// False when this is the last track seen rather than what is playing now.
+    pub live: bool,
+}
+
+async fn metadata(State(state): State<ReceiverState>) -> Result<Json<MetadataResponse>, StatusCode> {
+    if let Some(metadata) = state.status.refresh(now_millis()).metadata {
+        return Ok(Json(MetadataResponse { metadata, live: true }));
+    }
+    state
+        .last_metadata
+        .as_ref()
+        .and_then(MetadataStore::load)
+        .map(|metadata| Json(MetadataResponse { metadata, live: false }))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Replaces the certificate served over HTTPS for `/admin/tls/regenerate`.
+pub trait CertificateRotator: Send + Sync {
+    /// Generate, save and start serving a new certificate, returning its fingerprint.
+    fn regenerate(&self) -> BoxFuture<'_, Result<String>>;
+}
+
+/// Source of `/api/hardware`. Detection reads /proc and sysfs and shells out, so the
+/// handler runs it on the blocking pool.
+pub trait HardwareProbe: Send + Sync {
+    fn detect(&self) -> Result<HardwareCapabilities>;
+}
+
+impl<R: SystemReaders> HardwareProbe for HardwareDetector<R> {
+    fn detect(&self) -> Result<HardwareCapabilities> {
+        HardwareDetector::detect(self)
+    }
+}
+
+/// Capabilities detected once at startup, with thermal state and free space re-read on
+/// every request.
+pub struct CachedHardware<R: SystemReaders> {
+    capabilities: HardwareCapabilities,
+    detector: HardwareDetector<R>,
+}
+
+impl<R: SystemReaders> CachedHardware<R> {
+    pub fn new(capabilities: HardwareCapabilities, detector: HardwareDetector<R>) -> Self {
+        Self {
+            capabilities,
+            detector,
+        }
+    }
+}
+
+impl<R: SystemReaders> HardwareProbe for CachedHardware<R> {
+    fn detect(&self) -> Result<HardwareCapabilities> {
+        let (temperature_c, throttled) = self.detector.detect_thermal();
+        Ok(HardwareCapabilities {
+            temperature_c,
+            throttled,
+            available_mb: self.detector.detect_available_mb(),
+            ..self.capabilities.clone()
+        })
+    }
+}
+
+async fn hardware(State(state): State<ReceiverState>) -> Response {
+    let Some(probe) = state.hardware.clone() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match tokio::task::spawn_blocking(move || probe.detect()).await {
+        Ok(Ok(capabilities)) => {
+            state.events.publish(ReceiverEvent::Hardware(capabilities.clone()));
+            Json(capabilities).into_response()
+        }
+        Ok(Err(err)) => {
+            log_warn!("[hardware] detection failed: {err:#}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        Err(err) => {
+            log_warn!("[hardware] detection task failed: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn setup_status(State(state): State<ReceiverState>) -> Json<SetupStatus> {
+    let setup_mode = state.setup.is_active();
+    let mut missing = Vec::new();
+    if setup_mode {
+        missing.extend([SetupStep::Name, SetupStep::Output]);
+    }
+    if state.last_applied.last_calibrated_at().is_none() {
+        missing.push(SetupStep::Calibration);
+    }
+    let hardware = match state.hardware.clone() {
+        Some(probe) => match tokio::task::spawn_blocking(move || probe.detect()).await {
+            Ok(Ok(capabilities)) => Some(capabilities),
+            Ok(Err(err)) => {
+                log_warn!("[setup] hardware detection failed: {err:#}");
+                None
+            }
+            Err(err) => {
+                log_warn!("[setup] hardware detection task failed: {err}");
+                None
+            }
+        },
+        None => None,
+    };
+    Json(SetupStatus {
+        setup_mode,
+        missing,
+        output_options: hardware.as_ref().map(output_options).unwrap_or_default(),
+        hardware,
+    })
+}
+
+async fn complete_setup(
+    State(state): State<ReceiverState>,
+    Json(req): Json<SetupCompleteRequest>,
+) -> Result<Json<SettingsResponse>, (StatusCode, Json<serde_json::Value>)> {
+    if let Err(error) = req.validate() {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, Json(serde_json::json!({"error": error}))));
+    }
+    let update = SettingsUpdatePayload {
+        device_name: Some(req.device_name.trim().to_string()),
+        output_device: Some(req.output_device),
+        latency_offset_seconds: None,
+        calibration_gain: None,
+        eq: None,
+        self_calibration: None,
+    };
+    // Setup is only over once the choice is in the store the next boot reads.
+    let apply = async {
+        let settings = apply_settings_update(&state, update).await?;
+        state.settings.save().await.map_err(|err| {
+            log_warn!("[setup] failed to save settings: {err:#}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        Ok(settings)
+    };
+    match state.setup.complete(apply).await {
+        None => Err((StatusCode::CONFLICT, Json(serde_json::json!({"error": "setup_already_complete"})))),
+        Some(Err(status)) => Err((status, Json(serde_json::json!({"error": "setup_failed"})))),
+        Some(Ok(settings)) => {
+            log_info!("[setup] completed as {:?} on {}", settings.device_name, settings.output_device);
+            Ok(Json(settings))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthResponse {
+    /// `degraded` while the watchdog last found shairport-sync failed, `ok` otherwise.
+    pub status: String,
+    /// Present when the shairport-sync watchdog is running.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watchdog: Option<WatchdogSnapshot>,
+    /// Present when scheduled self-calibration is running.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub self_calibration: Option<SelfCalSnapshot>,
+}
+
+async fn version() -> Json<VersionInfo> {
+    Json(VersionInfo::current())
+}
+
+async fn health(State(state): State<ReceiverState>) -> Json<HealthResponse> {
+    let watchdog = state.watchdog.as_ref().map(WatchdogHandle::snapshot);
+    let degraded = watchdog
+        .as_ref()
+        .is_some_and(|w| w.last_state == Some(ServiceState::Failed));
+    Json(HealthResponse {
+        status: if degraded { "degraded" } else { "ok" }.to_string(),
+        watchdog,
+        self_calibration: state.self_calibration.snapshot(),
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeersResponse {
+    pub peers: Vec<PeerRecord>,
+}
+
+async fn list_peers(State(state): State<ReceiverState>) -> Json<PeersResponse> {
+    Json(PeersResponse {
+        peers: state.peers.live(now_millis()),
+    })
+}
+
+/// Body returned when a group request is rejected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupErrorResponse {
+    pub error: String,
+    pub message: String,
+    /// Receivers the error concerns: the coordinator for `not_coordinator`, the
+    /// offending members otherwise.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub receiver_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupAssignResponse {
+    pub group_id: String,
+    pub dry_run: bool,
+    /// Offset written to the member's config; absent for dry runs.
+    #[serde(default)]
+    pub applied_offset_ms: Option<f32>,
+}
+
+struct GroupRejection {
+    status: StatusCode,
+    body: GroupErrorResponse,
+}
+
+impl GroupRejection {
+    fn new(status: StatusCode, error: &str, message: String, receiver_ids: Vec<String>) -> Self {
+        log_warn!("[group] rejecting: {error}: {message}");
+        Self {
+            status,
+            body: GroupErrorResponse {
+                error: error.to_string(),
+                message,
+                receiver_ids,
+            },
+        }
+    }
+}
+
+impl IntoResponse for GroupRejection {
+    fn into_response(self) -> Response {
+        (self.status, Json(self.body)).into_response()
+    }
+}
+
+/// Apply `relative_offset_ms` on top of the latency measured by the last calibration,
+/// so a positive offset makes this receiver play later than its calibrated position.
+async fn apply_group_offset(state: &ReceiverState, relative_offset_ms: f32) -> Result<CalibrationApplyResponse> {
+    let base_latency_ms = state.last_applied.current().map_or(0.0, |c| c.measured_latency_ms);
+    let submission = CalibrationSubmission {
+        timestamp: now_millis(),
+        latency_ms: base_latency_ms - relative_offset_ms,
+        confidence: 1.0,
+        detections: Vec::new(),
+    };
+    state.calibration.apply(&submission).await
+}
+
+fn already_grouped(current: &GroupMembership) -> GroupRejection {
+    GroupRejection::new(
+        StatusCode::CONFLICT,
+        "already_in_group",
+        format!("receiver already belongs to group {}", current.group_id),
+        Vec::new(),
+    )
+}
+
+async fn assign_local(state: &ReceiverState, assignment: &GroupAssignment) -> Result<GroupAssignResponse, GroupRejection> {
+    if !assignment.relative_offset_ms.is_finite()
+        || assignment.relative_offset_ms.abs() > airsync_shared_protocol::MAX_RELATIVE_OFFSET_MS
+    {
+        return Err(GroupRejection::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_offset",
+            format!("relative_offset_ms {} is out of range", assignment.relative_offset_ms),
+            Vec::new(),
+        ));
+    }
+    // Under the apply lock so the membership check and the offset it leads to can't
+    // interleave with a calibration result or another assignment.
+    let _applying = state.apply_lock.lock().await;
+    if let Some(current) = state
+        .groups
+        .snapshot()
+        .membership
+        .filter(|m| m.group_id != assignment.group_id)
+    {
+        return Err(already_grouped(&current));
+    }
+    let applied_offset_ms = if assignment.dry_run {
+        None
+    } else {
+        let applied = apply_group_offset(state, assignment.relative_offset_ms)
+            .await
+            .map_err(apply_failed)?;
+        state
+            .groups
+            .update(|groups| {
+                groups.membership = Some(GroupMembership {
+                    group_id: assignment.group_id.clone(),
+                    coordinator_id: assignment.coordinator_id.clone(),
+                    relative_offset_ms: assignment.relative_offset_ms,
+                });
+                Ok(())
+            })
+            .map_err(apply_failed)?;
+        Some(applied.applied_offset_ms)
+    };
+    log_info!(
+        "[group] assigned group_id={} relative_offset_ms={} dry_run={}",
+        assignment.group_id, assignment.relative_offset_ms, assignment.dry_run
+    );
+    Ok(GroupAssignResponse {
+        group_id: assignment.group_id.clone(),
+        dry_run: assignment.dry_run,
+        applied_offset_ms,
+    })
+}
+
+fn apply_failed(err: anyhow::Error) -> GroupRejection {
+    GroupRejection::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "apply_failed",
+        format!("{err:#}"),
+        Vec::new(),
     )
 }
 
-fn now_millis() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_else(|_| Duration::from_secs(0))
-        .as_millis() as u64
-}
+/// Leave `release.group_id`, restoring the plain calibrated offset. Releasing a group
+/// this receiver isn't in is a no-op so coordinators can retry.
+async fn release_local(state: &ReceiverState, release: &GroupRelease) -> Result<(), GroupRejection> {
+    let _applying = state.apply_lock.lock().await;
+    match state.groups.snapshot().membership {
+        None => return Ok(()),
+        Some(current) if current.group_id != release.group_id => return Err(already_grouped(&current)),
+        Some(_) => {}
+    }
+    apply_group_offset(state, 0.0).await.map_err(apply_failed)?;
+    state
+        .groups
+        .update(|groups| {
+            groups.membership = None;
+            Ok(())
+        })
+        .map_err(apply_failed)
+}
+
+async fn assign_group(
+    State(state): State<ReceiverState>,
+    Json(assignment): Json<GroupAssignment>,
+) -> Result<Json<GroupAssignResponse>, GroupRejection> {
+    assign_local(&state, &assignment).await.map(Json)
+}
+
+async fn release_group(
+    State(state): State<ReceiverState>,
+    Json(release): Json<GroupRelease>,
+) -> Result<StatusCode, GroupRejection> {
+    release_local(&state, &release).await.map(|_| StatusCode::NO_CONTENT)
+}
+
+fn reply_from_local<T>(result: Result<T, GroupRejection>) -> Result<MemberReply> {
+    match result {
+        Ok(_) => Ok(MemberReply::Accepted),
+        Err(rejection) if rejection.status == StatusCode::CONFLICT => Ok(MemberReply::Conflict),
+        Err(rejection) => Err(anyhow!(rejection.body.message)),
+    }
+}
+
+/// Send an assignment to `receiver_id`, handling this receiver's own seat in-process.
+async fn dispatch_assign(state: &ReceiverState, receiver_id: &str, assignment: &GroupAssignment) -> Result<MemberReply> {
+    if receiver_id == state.info.receiver_id {
+        return reply_from_local(assign_local(state, assignment).await);
+    }
+    state.group_transport.assign(receiver_id, assignment).await
+}
+
+async fn dispatch_release(state: &ReceiverState, receiver_id: &str, release: &GroupRelease) -> Result<MemberReply> {
+    if receiver_id == state.info.receiver_id {
+        return reply_from_local(release_local(state, release).await);
+    }
+    state.group_transport.release(receiver_id, release).await
+}
+
+/// Send `group_id`'s assignment to every member, returning the members that conflicted
+/// and the ones that could not be reached or failed to apply.
+async fn assign_members(state: &ReceiverState, group: &GroupConfig, dry_run: bool) -> (Vec<String>, Vec<String>) {
+    let mut conflicts = Vec::new();
+    let mut failures = Vec::new();
+    for member in &group.members {
+        let assignment = GroupAssignment {
+            group_id: group.group_id.clone(),
+            coordinator_id: state.info.receiver_id.clone(),
+            relative_offset_ms: member.relative_offset_ms,
+            dry_run,
+        };
+        match dispatch_assign(state, &member.receiver_id, &assignment).await {
+            Ok(MemberReply::Accepted) => {}
+            Ok(MemberReply::Conflict) => conflicts.push(member.receiver_id.clone()),
+            Err(err) => {
+                log_warn!("[group] assigning {} failed: {err:#}", member.receiver_id);
+                failures.push(member.receiver_id.clone());
+            }
+        }
+    }
+    (conflicts, failures)
+}
+
+/// Best-effort release of `receiver_ids` from `group_id`; failures are only logged.
+async fn release_members<'a>(state: &ReceiverState, group_id: &str, receiver_ids: impl Iterator<Item = &'a str>) {
+    let release = GroupRelease {
+        group_id: group_id.to_string(),
+    };
+    for receiver_id in receiver_ids {
+        if let Err(err) = dispatch_release(state, receiver_id, &release).await {
+            log_warn!("[group] releasing {receiver_id} failed: {err:#}");
+        }
+    }
+}
+
+/// Create or update a group. Every member is dry-run first so a member already in
+/// another group rejects the whole request before anything is applied.
+async fn create_group(
+    State(state): State<ReceiverState>,
+    Json(group): Json<GroupConfig>,
+) -> Result<Json<GroupConfig>, GroupRejection> {
+    group.validate().map_err(|err| {
+        GroupRejection::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid_group", err.to_string(), Vec::new())
+    })?;
+    let coordinator_id = group.coordinator_id().unwrap_or_default().to_string();
+    if coordinator_id != state.info.receiver_id {
+        return Err(GroupRejection::new(
+            StatusCode::MISDIRECTED_REQUEST,
+            "not_coordinator",
+            format!("group {} is coordinated by {coordinator_id}", group.group_id),
+            vec![coordinator_id],
+        ));
+    }
+
+    let (conflicts, failures) = assign_members(&state, &group, true).await;
+    if !conflicts.is_empty() {
+        return Err(GroupRejection::new(
+            StatusCode::CONFLICT,
+            "member_in_other_group",
+            format!("{} member(s) already belong to another group", conflicts.len()),
+            conflicts,
+        ));
+    }
+    if !failures.is_empty() {
+        return Err(GroupRejection::new(
+            StatusCode::BAD_GATEWAY,
+            "member_unreachable",
+            format!("{} member(s) could not be reached", failures.len()),
+            failures,
+        ));
+    }
+
+    let (conflicts, failures) = assign_members(&state, &group, false).await;
+    let failed: Vec<String> = conflicts.into_iter().chain(failures).collect();
+    if !failed.is_empty() {
+        return Err(GroupRejection::new(
+            StatusCode::BAD_GATEWAY,
+            "apply_failed",
+            format!("{} member(s) failed to apply the group offset", failed.len()),
+            failed,
+        ));
+    }
+
+    // Members dropped by an update go back to their plain calibrated offsets.
+    if let Some(previous) = state.groups.snapshot().coordinated {
+        if previous.group_id == group.group_id {
+            let dropped = previous
+                .members
+                .iter()
+                .map(|m| m.receiver_id.as_str())
+                .filter(|id| group.member(id).is_none());
+            release_members(&state, &group.group_id, dropped).await;
+        }
+    }
+    state
+        .groups
+        .update(|groups| {
+            groups.coordinated = Some(group.clone());
+            Ok(())
+        })
+        .map_err(|err| {
+            GroupRejection::new(StatusCode::INTERNAL_SERVER_ERROR, "persist_failed", format!("{err:#}"), Vec::new())
+        })?;
+    log_info!(
+        "[group] applied group_id={} name={} members={}",
+        group.group_id,
+        group.name,
+        group.members.len()
+    );
+    Ok(Json(group))
+}
+
+async fn get_group(State(state): State<ReceiverState>) -> Result<Json<GroupConfig>, StatusCode> {
+    state.groups.snapshot().coordinated.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn delete_group(State(state): State<ReceiverState>) -> Result<StatusCode, GroupRejection> {
+    let Some(group) = state.groups.snapshot().coordinated else {
+        return Err(GroupRejection::new(
+            StatusCode::NOT_FOUND,
+            "no_group",
+            "this receiver does not coordinate a group".into(),
+            Vec::new(),
+        ));
+    };
+    release_members(&state, &group.group_id, group.members.iter().map(|m| m.receiver_id.as_str())).await;
+    state
+        .groups
+        .update(|groups| {
+            groups.coordinated = None;
+            Ok(())
+        })
+        .map_err(|err| {
+            GroupRejection::new(StatusCode::INTERNAL_SERVER_ERROR, "persist_failed", format!("{err:#}"), Vec::new())
+        })?;
+    log_info!("[group] dissolved group_id={}", group.group_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct TimeSyncQuery {
+    /// Client send time; when present the response carries all four timestamps.
+    #[serde(default)]
+    t0: Option<u64>,
+}
+
+async fn time_sync(State(state): State<ReceiverState>, Query(query): Query<TimeSyncQuery>) -> Json<TimeSyncResponse> {
+    let received = (state.clock)();
+    log_info!("[time] /api/time called server_time_ms={}", received);
+    let extended = query.t0.is_some();
+    Json(TimeSyncResponse {
+        server_time_ms: received,
+        client_send_ms: query.t0,
+        server_receive_ms: extended.then_some(received),
+        server_send_ms: extended.then(|| (state.clock)()),
+    })
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PeerTimeSyncQuery {
+    #[serde(default)]
+    refresh: bool,
+}
+
+async fn peer_timesync(
+    State(state): State<ReceiverState>,
+    UrlPath(receiver_id): UrlPath<String>,
+    Query(query): Query<PeerTimeSyncQuery>,
+) -> Result<Json<PeerTimeSync>, StatusCode> {
+    let now = (state.clock)();
+    if !query.refresh {
+        if let Some(cached) = state.timesync.fresh(&receiver_id, now) {
+            return Ok(Json(cached));
+        }
+    }
+    let Some(peer) = state
+        .peers
+        .live(now_millis())
+        .into_iter()
+        .find(|p| p.receiver_id == receiver_id)
+    else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let sync = measure_peer(&peer.base_url(), TIMESYNC_ROUNDS, state.clock)
+        .await
+        .map_err(|err| {
+            log_warn!("[time] measuring {receiver_id} failed: {err:#}");
+            StatusCode::BAD_GATEWAY
+        })?;
+    log_info!(
+        "[time] peer {} offset_ms={} rtt_ms={} stddev_ms={}",
+        receiver_id, sync.offset_ms, sync.rtt_ms, sync.stddev_ms
+    );
+    state.timesync.insert(&receiver_id, sync);
+    Ok(Json(sync))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SettingsResponse {
+    pub device_name: String,
+    pub output_device: OutputDeviceSpec,
+    pub latency_offset_seconds: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub calibration_gain: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eq: Option<EqSettings>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub self_calibration: Option<SelfCalSchedule>,
+    #[serde(default)]
+    pub config_generation: u64,
+    /// When the newest calibration in the history was applied; `null` before the first.
+    #[serde(default)]
+    pub last_calibrated_at: Option<u64>,
+    /// Config keys an update changed, as `group.key`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changed_keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SettingsUpdatePayload {
+    pub device_name: Option<String>,
+    pub output_device: Option<OutputDeviceSpec>,
+    pub latency_offset_seconds: Option<f32>,
+    /// Calibration playback gain in `0.0..=1.0`, overriding the per-output default.
+    #[serde(default)]
+    pub calibration_gain: Option<f32>,
+    /// Absent leaves the EQ as is; `null` clears it.
+    #[serde(default, deserialize_with = "present_or_null", skip_serializing_if = "Option::is_none")]
+    pub eq: Option<Option<EqSettings>>,
+    /// Absent leaves the schedule as is; `null` stops scheduled self-calibration.
+    #[serde(default, deserialize_with = "present_or_null", skip_serializing_if = "Option::is_none")]
+    pub self_calibration: Option<Option<SelfCalSchedule>>,
+}
+
+/// Tells an explicit `null` (`Some(None)`) apart from an absent field, which `default` makes `None`.
+fn present_or_null<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+impl SettingsUpdatePayload {
+    /// An update that sets every field to its value in `cfg`.
+    pub fn from_config(cfg: &ShairportConfig) -> Self {
+        Self {
+            device_name: Some(cfg.device_name.clone()),
+            output_device: Some(cfg.output_device.clone()),
+            latency_offset_seconds: Some(cfg.latency_offset_seconds),
+            calibration_gain: cfg.calibration_gain,
+            eq: Some(cfg.eq.clone()),
+            self_calibration: Some(cfg.self_calibration.clone()),
+        }
+    }
+
+    /// `base` with the fields present in this update replaced. An absent gain keeps the
+    /// base's gain; updates can't clear it.
+    pub fn merge(&self, base: &ShairportConfig) -> ShairportConfig {
+        ShairportConfig {
+            device_name: self.device_name.clone().unwrap_or_else(|| base.device_name.clone()),
+            output_device: self.output_device.clone().unwrap_or_else(|| base.output_device.clone()),
+            latency_offset_seconds: self.latency_offset_seconds.unwrap_or(base.latency_offset_seconds),
+            calibration_gain: self.calibration_gain.or(base.calibration_gain),
+            eq: self.eq.clone().unwrap_or_else(|| base.eq.clone()),
+            self_calibration: self
+                .self_calibration
+                .clone()
+                .unwrap_or_else(|| base.self_calibration.clone()),
+        }
+    }
+}
+
+/// One entry of `/api/calibration/presets`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChirpPresetInfo {
+    pub preset: ChirpPreset,
+    pub chirp_config: ChirpConfig,
+    pub expected_duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChirpPresetsResponse {
+    pub presets: Vec<ChirpPresetInfo>,
+}
+
+async fn calibration_presets(State(state): State<ReceiverState>) -> Json<ChirpPresetsResponse> {
+    let presets = ChirpPreset::ALL
+        .into_iter()
+        .map(|preset| {
+            let chirp_config = state.preset_config(preset);
+            ChirpPresetInfo {
+                preset,
+                expected_duration_ms: chirp_config.estimated_duration_ms(),
+                chirp_config,
+            }
+        })
+        .collect();
+    Json(ChirpPresetsResponse { presets })
+}
+
+async fn get_chirp_config(State(state): State<ReceiverState>) -> Json<ChirpConfig> {
+    Json(state.chirp_config.lock().unwrap().clone())
+}
+
+async fn put_chirp_config(
+    State(state): State<ReceiverState>,
+    Json(chirp): Json<ChirpConfig>,
+) -> Result<Json<ChirpConfig>, Response> {
+    chirp
+        .validate()
+        .map_err(|err| schedule_error("invalid_chirp_config", err.to_string()))?;
+    log_info!("[calibration] default chirp config updated: {chirp:?}");
+    *state.chirp_config.lock().unwrap() = chirp.clone();
+    Ok(Json(chirp))
+}
+
+async fn get_settings(State(state): State<ReceiverState>) -> Json<SettingsResponse> {
+    let cfg = state.settings.current();
+    Json(SettingsResponse {
+        device_name: cfg.device_name,
+        output_device: cfg.output_device,
+        latency_offset_seconds: cfg.latency_offset_seconds,
+        calibration_gain: cfg.calibration_gain,
+        eq: cfg.eq,
+        self_calibration: cfg.self_calibration,
+        config_generation: state.settings.generation(),
+        last_calibrated_at: state.last_applied.last_calibrated_at(),
+        changed_keys: Vec::new(),
+    })
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SettingsUpdateQuery {
+    /// Queue the update as a job and answer 202 instead of waiting for the restart.
+    #[serde(default, rename = "async")]
+    run_async: bool,
+}
+
+async fn update_settings(
+    State(state): State<ReceiverState>,
+    Query(query): Query<SettingsUpdateQuery>,
+    Json(req): Json<SettingsUpdatePayload>,
+) -> Result<Response, StatusCode> {
+    if req.calibration_gain.is_some_and(|g| !(0.0..=1.0).contains(&g)) {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    if let Some(Some(eq)) = &req.eq {
+        eq.validate().map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?;
+    }
+    if let Some(Some(schedule)) = &req.self_calibration {
+        schedule.validate().map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?;
+    }
+    if !query.run_async {
+        return Ok(Json(apply_settings_update(&state, req).await?).into_response());
+    }
+    let job_state = state.clone();
+    let job: JobFuture = Box::pin(inherit(async move {
+        match apply_settings_update(&job_state, req).await {
+            Ok(response) => Ok(json_value(&response)),
+            Err(status) => Err(JobError {
+                status: status.as_u16(),
+                body: serde_json::json!({"error": "settings_update_failed"}),
+            }),
+        }
+    }));
+    Ok(job_accepted(state.jobs.submit("settings", job)))
+}
+
+async fn apply_settings_update(state: &ReceiverState, req: SettingsUpdatePayload) -> Result<SettingsResponse, StatusCode> {
+    let previous_offset = state.settings.current().latency_offset_seconds;
+    let latency_changed = req.latency_offset_seconds.is_some_and(|offset| offset != previous_offset);
+    let (cfg, changed_keys) = state
+        .settings
+        .update(req)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    state.events.publish(ReceiverEvent::Settings(cfg.clone()));
+    if latency_changed {
+        let applied_offset_ms = cfg.latency_offset_seconds * 1000.0;
+        record_applied(
+            state,
+            AppliedCalibration {
+                timestamp: (state.clock)(),
+                measured_latency_ms: -applied_offset_ms,
+                applied_offset_ms,
+                confidence: 1.0,
+                output_device: cfg.output_device.clone(),
+                source: CalibrationSource::Manual,
+                config_generation: Some(state.settings.generation()),
+                temperature_c: soc_temperature(state).await,
+            },
+        );
+    }
+    Ok(SettingsResponse {
+        device_name: cfg.device_name,
+        output_device: cfg.output_device,
+        latency_offset_seconds: cfg.latency_offset_seconds,
+        calibration_gain: cfg.calibration_gain,
+        eq: cfg.eq,
+        self_calibration: cfg.self_calibration,
+        config_generation: state.settings.generation(),
+        last_calibrated_at: state.last_applied.last_calibrated_at(),
+        changed_keys,
+    })
+}
+
+pub struct ShairportSettingsManager<W: ConfigWriter + Send + Sync + 'static, C: ShairportController + Send + Sync + 'static> {
+    writer: Arc<W>,
+    /// Writes the ALSA fragment defining the EQ PCM; without one, EQ can't be enabled.
+    eq_writer: Option<Arc<dyn ConfigWriter>>,
+    controller: Arc<C>,
+    renderer: Arc<dyn ConfigRenderer>,
+    config: ConfigStore,
+    change_log: Option<ConfigChangeLog>,
+    /// Serializes updates across the restart await, which the store lock can't be held over.
+    update_lock: tokio::sync::Mutex<()>,
+}
+
+pub struct NoopPlaybackSink;
+
+impl PlaybackSink for NoopPlaybackSink {
+    fn play(&self, _request: &PlaybackRequest) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Accepts every submission without touching shairport; reports the offset the real
+/// sink would have applied.
+pub struct NoopCalibrationSink;
+
+impl CalibrationSink for NoopCalibrationSink {
+    fn apply<'a>(&'a self, submission: &'a CalibrationSubmission) -> BoxFuture<'a, Result<CalibrationApplyResponse>> {
+        Box::pin(std::future::ready(Ok(CalibrationApplyResponse {
+            measured_latency_ms: submission.latency_ms,
+            applied_offset_ms: -submission.latency_ms,
+            was_clamped: false,
+            output_device: OutputDeviceSpec::default(),
+            config_generation: 0,
+            changed_keys: Vec::new(),
+        })))
+    }
+
+    fn replay<'a>(&'a self, history: &'a [HistoryEntry]) -> BoxFuture<'a, Result<CalibrationApplyResponse>> {
+        let replayed = history.last().map(|entry| CalibrationApplyResponse {
+            measured_latency_ms: entry.applied.measured_latency_ms,
+            applied_offset_ms: -entry.applied.measured_latency_ms,
+            was_clamped: false,
+            output_device: entry.config_snapshot.output_device.clone(),
+            config_generation: 0,
+            changed_keys: Vec::new(),
+        });
+        Box::pin(std::future::ready(replayed.ok_or_else(|| anyhow!("no calibration history to replay"))))
+    }
+}
+
+/// Settings held purely in a [`ConfigStore`]; nothing is written or restarted.
+pub struct InMemorySettingsManager {
+    config: ConfigStore,
+}
+
+impl InMemorySettingsManager {
+    pub fn new(config: ConfigStore) -> Self {
+        Self { config }
+    }
+}
+
+impl SettingsManager for InMemorySettingsManager {
+    fn current(&self) -> ShairportConfig {
+        self.config.current()
+    }
+
+    fn update(&self, update: SettingsUpdatePayload) -> BoxFuture<'_, Result<(ShairportConfig, Vec<String>)>> {
+        let previous = self.config.current();
+        let updated = self.config.update_with(|current| Ok(update.merge(current)));
+        Box::pin(std::future::ready(updated.map(|(cfg, _)| {
+            let changed_keys = rendered_changed_keys(&previous, &cfg);
+            (cfg, changed_keys)
+        })))
+    }
+
+    fn generation(&self) -> u64 {
+        self.config.generation()
+    }
+
+    fn save(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.config.save())
+    }
+}
+
+/// Default calibration playback gain per output type. A full-scale chirp through a DAC
+/// into efficient speakers is painfully loud, through the Pi's headphone jack barely audible.
+pub const I2S_DEFAULT_GAIN: f32 = 0.5;
+pub const USB_DEFAULT_GAIN: f32 = 0.6;
+pub const HDMI_DEFAULT_GAIN: f32 = 0.7;
+pub const HEADPHONE_DEFAULT_GAIN: f32 = 1.0;
+/// Used when the output type can't be determined; the quietest default errs on the safe side.
+pub const FALLBACK_DEFAULT_GAIN: f32 = I2S_DEFAULT_GAIN;
+
+pub fn default_gain(output: AudioOutput) -> f32 {
+    match output {
+        AudioOutput::I2S => I2S_DEFAULT_GAIN,
+        AudioOutput::USB => USB_DEFAULT_GAIN,
+        AudioOutput::HDMI => HDMI_DEFAULT_GAIN,
+        AudioOutput::Headphone => HEADPHONE_DEFAULT_GAIN,
+    }
+}
+
+/// Where a playback's gain came from, highest precedence first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GainSource {
+    /// `ChirpConfig.amplitude` on the request.
+    Request,
+    /// The `calibration_gain` setting.
+    Setting,
+    /// The default for the output type, `None` when it couldn't be determined.
+    OutputDefault(Option<AudioOutput>),
+}
+
+impl std::fmt::Display for GainSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GainSource::Request => write!(f, "request"),
+            GainSource::Setting => write!(f, "setting"),
+            GainSource::OutputDefault(Some(output)) => write!(f, "default({output:?})"),
+            GainSource::OutputDefault(None) => write!(f, "default(unknown output)"),
+        }
+    }
+}
+
+/// Where the per-card stream descriptions listing supported rates live.
+pub const PROC_ASOUND_PATH: &str = "/proc/asound";
+
+/// Overrides the binary calibration audio is played with (`aplay` by default).
+pub const PLAY_COMMAND_ENV: &str = "AIRSYNC_PLAY_COMMAND";
+/// Whitespace-separated arguments passed to the player ahead of the device and file.
+pub const PLAY_ARGS_ENV: &str = "AIRSYNC_PLAY_ARGS";
+
+/// The player invocation for one playback: `program [extra args] -D <device> -q <wav>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayCommand {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl PlayCommand {
+    /// The invocation for `wav` on `device`, honouring [`PLAY_COMMAND_ENV`] and
+    /// [`PLAY_ARGS_ENV`] as they are set now.
+    pub fn from_env(device: &str, wav: &Path) -> Self {
+        Self::resolve(|key| std::env::var(key).ok(), device, wav)
+    }
+
+    fn resolve(var: impl Fn(&str) -> Option<String>, device: &str, wav: &Path) -> Self {
+        let program = var(PLAY_COMMAND_ENV)
+            .map(|program| program.trim().to_string())
+            .filter(|program| !program.is_empty())
+            .unwrap_or_else(|| "aplay".to_string());
+        let mut args: Vec<String> = var(PLAY_ARGS_ENV)
+            .map(|extra| extra.split_whitespace().map(str::to_string).collect())
+            .unwrap_or_default();
+        args.extend(["-D".to_string(), device.to_string()]);
+        args.extend(["-q".to_string(), wav.to_string_lossy().into_owned()]);
+        Self { program, args }
+    }
+
+    #[cfg(not(feature = "embedded"))]
+    pub fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(&self.args);
+        cmd
+    }
+}
+
+pub struct SystemPlaybackSink {
+    sample_rate: u32,
+    config: ConfigStore,
+    cards: Vec<AlsaCard>,
+    pregen_path: Option<std::path::PathBuf>,
+    asound_root: PathBuf,
+    /// Wall time aplay took for the last chirp played successfully.
+    #[cfg(not(feature = "embedded"))]
+    last_chirp: Mutex<Option<(ChirpConfig, Duration)>>,
+}
+
+impl SystemPlaybackSink {
+    /// `cards` classify the configured output device for its default gain; `pregen_path`
+    /// is a full-scale chirp played as-is when the effective gain is full scale.
+    pub fn new(
+        sample_rate: u32,
+        config: ConfigStore,
+        cards: Vec<AlsaCard>,
+        pregen_path: Option<std::path::PathBuf>,
+    ) -> Self {
+        Self {
+            sample_rate,
+            config,
+            cards,
+            pregen_path,
+            asound_root: PathBuf::from(PROC_ASOUND_PATH),
+            #[cfg(not(feature = "embedded"))]
+            last_chirp: Mutex::new(None),
+        }
+    }
+
+    /// Read card stream descriptions from `root` instead of [`PROC_ASOUND_PATH`].
+    pub fn with_asound_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.asound_root = root.into();
+        self
+    }
+
+    /// Rate to feed the configured output: the rendering rate unless the card lists
+    /// its rates (USB audio does) and that isn't one of them, then the nearest one.
+    pub fn playback_rate(&self) -> u32 {
+        let device = self.config.current().output_device;
+        let Some((card, pcm)) = output_card_device(&device, &self.cards) else {
+            return self.sample_rate;
+        };
+        let stream = self.asound_root.join(format!("card{card}/stream{pcm}"));
+        match std::fs::read_to_string(&stream)
+            .ok()
+            .and_then(|contents| SupportedRates::from_proc_stream(&contents))
+        {
+            Some(rates) if !rates.supports(self.sample_rate) => rates.nearest(self.sample_rate),
+            _ => self.sample_rate,
+        }
+    }
+
+    /// Gain for `request`: the request's amplitude, else the `calibration_gain` setting,
+    /// else the default for the current output device.
+    pub fn effective_gain(&self, request: &PlaybackRequest) -> (f32, GainSource) {
+        if let PlaybackRequest::Chirp(ChirpConfig {
+            amplitude: Some(amplitude),
+            ..
+        }) = request
+        {
+            return (amplitude.clamp(0.0, 1.0), GainSource::Request);
+        }
+        let cfg = self.config.current();
+        if let Some(gain) = cfg.calibration_gain {
+            return (gain.clamp(0.0, 1.0), GainSource::Setting);
+        }
+        let output = classify_output_device(&cfg.output_device, &self.cards);
+        (
+            output.map_or(FALLBACK_DEFAULT_GAIN, default_gain),
+            GainSource::OutputDefault(output),
+        )
+    }
+
+    fn write_wave(&self, chirp: &ChirpConfig, gain: f32, rate: u32) -> Result<tempfile::NamedTempFile> {
+        let file = tempfile::NamedTempFile::new()?;
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(file.path(), spec)?;
+        // The amplitude is already folded into `gain`.
+        let chirp = ChirpConfig {
+            amplitude: None,
+            ..chirp.clone()
+        };
+        for s in generate_chirp_samples(&chirp, rate, gain) {
+            writer.write_sample(s)?;
+        }
+        writer.finalize()?;
+        Ok(file)
+    }
+
+    /// Copy of the 16-bit WAV at `path` scaled by `gain`.
+    fn write_scaled(path: &Path, gain: f32) -> Result<tempfile::NamedTempFile> {
+        let mut reader = hound::WavReader::open(path)?;
+        let file = tempfile::NamedTempFile::new()?;
+        let mut writer = hound::WavWriter::create(file.path(), reader.spec())?;
+        for s in reader.samples::<i16>() {
+            writer.write_sample((s? as f32 * gain).round() as i16)?;
+        }
+        writer.finalize()?;
+        Ok(file)
+    }
+
+    /// The WAV to hand aplay for `request` at `gain`, rendered or converted at `rate`.
+    fn resolve_wav(&self, request: &PlaybackRequest, gain: f32, rate: u32) -> Result<PathBuf> {
+        let full_scale = gain >= 0.99;
+        let native = rate == self.sample_rate;
+        Ok(match request {
+            PlaybackRequest::Chirp(_) if full_scale && native && self.pregen_path.is_some() => {
+                self.pregen_path.clone().unwrap()
+            }
+            PlaybackRequest::Chirp(chirp) => self.write_wave(chirp, gain, rate)?.into_temp_path().keep()?,
+            PlaybackRequest::File(path) if hound::WavReader::open(path)?.spec().sample_rate != rate => {
+                let file = tempfile::NamedTempFile::new()?;
+                resample_wav(path, file.path(), rate, gain)?;
+                file.into_temp_path().keep()?
+            }
+            PlaybackRequest::File(path) if full_scale => path.clone(),
+            PlaybackRequest::File(path) => Self::write_scaled(path, gain)?.into_temp_path().keep()?,
+        })
+    }
+}
+
+/// Embedded images ship without alsa-utils; the signal is still rendered so it can be
+/// played by other means, but playback itself reports failure.
+#[cfg(feature = "embedded")]
+impl PlaybackSink for SystemPlaybackSink {
+    fn play(&self, request: &PlaybackRequest) -> Result<()> {
+        let (gain, source) = self.effective_gain(request);
+        let rate = self.playback_rate();
+        let wav_path = self.resolve_wav(request, gain, rate)?;
+        Err(anyhow!(
+            "audio playback unavailable in embedded build (device={} file={} rate={rate} gain={:.1}dB gain_source={source})",
+            self.config.current().output_device,
+            wav_path.display(),
+            gain_to_db(gain)
+        ))
+    }
+
+    fn output_rate(&self) -> Option<u32> {
+        Some(self.playback_rate())
+    }
+
+    fn output_gain(&self, request: &PlaybackRequest) -> Option<f32> {
+        Some(self.effective_gain(request).0)
+    }
+}
+
+#[cfg(not(feature = "embedded"))]
+impl PlaybackSink for SystemPlaybackSink {
+    fn play(&self, request: &PlaybackRequest) -> Result<()> {
+        self.play_reporting_start(request, &|_| {})
+    }
+
+    /// Reports the start once aplay has been spawned, which is as close to the first
+    /// sample as the receiver can see.
+    fn play_reporting_start(&self, request: &PlaybackRequest, started: &dyn Fn(u64)) -> Result<()> {
+        let (gain, source) = self.effective_gain(request);
+        let rate = self.playback_rate();
+        let wav_path = self.resolve_wav(request, gain, rate)?;
+        let dev = self.config.current().output_device.to_string();
+        // Read per playback so the player can be swapped without restarting the service.
+        let play = PlayCommand::from_env(&dev, &wav_path);
+        let program = play.program.as_str();
+        log_info!(
+            "[calibration] invoking {} device={} file={} rate={} gain={:.1}dB gain_source={}",
+            program,
+            dev,
+            wav_path.to_string_lossy(),
+            rate,
+            gain_to_db(gain),
+            source
+        );
+        let run_cmd = |mut c: Command| -> Result<()> {
+            let mut child = c.spawn().map_err(|e| anyhow!("failed to run {}: {}", program, e))?;
+            started(now_millis());
+            match child.wait() {
+                Ok(s) if s.success() => Ok(()),
+                Ok(s) => Err(anyhow!("{} failed with status {}", program, s)),
+                Err(e) => Err(anyhow!("failed to run {}: {}", program, e)),
+            }
+        };
+        let cmd = play.command();
+        let retry_cmd = play.command();
+
+        let started = std::time::Instant::now();
+        let result = if let Err(e) = run_cmd(cmd) {
+            // Retry once after a brief pause (helps with transient device busy)
+            std::thread::sleep(std::time::Duration::from_millis(120));
+            log_info!("[calibration] retrying {program} after error: {e}");
+            let retry_started = std::time::Instant::now();
+            run_cmd(retry_cmd)
+                .map(|()| retry_started)
+                .map_err(|e2| anyhow!("{e}; retry_error={e2}"))
+        } else {
+            log_info!("[calibration] {program} completed OK");
+            Ok(started)
+        };
+        let started = result?;
+        if let PlaybackRequest::Chirp(chirp) = request {
+            *self.last_chirp.lock().unwrap() = Some((chirp.clone(), started.elapsed()));
+        }
+        Ok(())
+    }
+
+    fn output_rate(&self) -> Option<u32> {
+        Some(self.playback_rate())
+    }
+
+    fn output_gain(&self, request: &PlaybackRequest) -> Option<f32> {
+        Some(self.effective_gain(request).0)
+    }
+
+    /// The measured aplay time when the same chirp was last played, which includes
+    /// device open and buffer drain; otherwise the rendered length.
+    fn duration_hint(&self, chirp: &ChirpConfig) -> Duration {
+        match &*self.last_chirp.lock().unwrap() {
+            Some((last, measured)) if last == chirp => *measured,
+            _ => Duration::from_millis(chirp.estimated_duration_ms()),
+        }
+    }
+}
+
+impl<W: ConfigWriter + Send + Sync + 'static, C: ShairportController + Send + Sync + 'static>
+    ShairportSettingsManager<W, C>
+{
+    pub fn new(writer: W, controller: C, config: ConfigStore) -> Self {
+        Self {
+            writer: Arc::new(writer),
+            eq_writer: None,
+            controller: Arc::new(controller),
+            renderer: Arc::new(BuiltinRenderer),
+            config,
+            change_log: None,
+            update_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn eq_writer(mut self, writer: impl ConfigWriter) -> Self {
+        self.eq_writer = Some(Arc::new(writer));
+        self
+    }
+
+    /// Render the config file with `renderer` instead of the built-in format.
+    pub fn renderer(mut self, renderer: impl ConfigRenderer + 'static) -> Self {
+        self.renderer = Arc::new(renderer);
+        self
+    }
+
+    /// Record the diff of every config written in `log`.
+    pub fn change_log(mut self, log: ConfigChangeLog) -> Self {
+        self.change_log = Some(log);
+        self
+    }
+}
+
+impl<W: ConfigWriter + Send + Sync + 'static, C: ShairportController + Send + Sync + 'static>
+    SettingsManager for ShairportSettingsManager<W, C>
+{
+    fn current(&self) -> ShairportConfig {
+        self.config.current()
+    }
+
+    fn update(&self, update: SettingsUpdatePayload) -> BoxFuture<'_, Result<(ShairportConfig, Vec<String>)>> {
+        Box::pin(async move {
+            let _updating = self.update_lock.lock().await;
+            let current = self.config.current();
+            let cfg = update.merge(&current);
+            // Rendered before anything is written, so a broken template changes nothing.
+            let rendered = self.renderer.render(&cfg)?;
+            // The fragment names the output device too, so it's compared as rendered. It's
+            // written first so shairport-sync never points at an undefined PCM.
+            let fragment = render_eq_fragment(&cfg);
+            if fragment != render_eq_fragment(&current) {
+                match &self.eq_writer {
+                    Some(eq_writer) => Arc::clone(eq_writer).write_async(fragment).await?,
+                    None if cfg.eq.is_some() => bail!("EQ is not supported without an ALSA fragment writer"),
+                    None => {}
+                }
+            }
+            let diff = diff_against_current(&self.writer, &rendered).await;
+            Arc::clone(&self.writer).write_async(rendered).await?;
+            let changed_keys = diff.changed_keys();
+            if let Some(log) = &self.change_log {
+                log.record(ConfigChangeSource::Settings, diff);
+            }
+            Arc::clone(&self.controller).restart_async().await?;
+            let (cfg, _) = self.config.update_with(|_| Ok(cfg))?;
+            Ok((cfg, changed_keys))
+        })
+    }
+
+    fn generation(&self) -> u64 {
+        self.config.generation()
+    }
+
+    fn save(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.config.save())
+    }
+}
+
+pub async fn serve(router: Router, addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr).await.context("bind")?;
+    axum::serve(listener, router.into_make_service_with_connect_info::<ConnectionInfo>())
+        .await
+        .context("serve")?;
+    Ok(())
+}
+
+/// Serve on `[::]:port` accepting both IPv6 and IPv4-mapped connections,
+/// falling back to `0.0.0.0:port` when the host has no IPv6 support.
+pub async fn serve_dual_stack(router: Router, port: u16) -> Result<()> {
+    let listener = bind_dual_stack(port)?;
+    axum::serve(listener, router.into_make_service_with_connect_info::<ConnectionInfo>())
+        .await
+        .context("serve")?;
+    Ok(())
+}
+
+/// First file descriptor systemd passes to a socket-activated service (`SD_LISTEN_FDS_START`).
+pub const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// The socket-activation variables systemd sets, per sd_listen_fds(3).
+#[derive(Debug, Clone)]
+pub struct ListenFds {
+    pub listen_pid: Option<String>,
+    pub listen_fds: Option<String>,
+    pub first_fd: RawFd,
+}
+
+impl ListenFds {
+    pub fn from_env() -> Self {
+        Self {
+            listen_pid: std::env::var("LISTEN_PID").ok(),
+            listen_fds: std::env::var("LISTEN_FDS").ok(),
+            first_fd: SD_LISTEN_FDS_START,
+        }
+    }
+
+    /// The first socket passed to process `pid`. Extra sockets are left untouched.
+    fn take_listener(&self, pid: u32) -> Result<std::net::TcpListener> {
+        let listen_pid = self.listen_pid.as_deref().context("LISTEN_PID is not set")?;
+        if listen_pid.trim().parse::<u32>().ok() != Some(pid) {
+            bail!("LISTEN_PID {listen_pid} is not this process ({pid})");
+        }
+        let count: u32 = self
+            .listen_fds
+            .as_deref()
+            .context("LISTEN_FDS is not set")?
+            .trim()
+            .parse()
+            .context("invalid LISTEN_FDS")?;
+        if count == 0 {
+            bail!("systemd passed no sockets");
+        }
+        if count > 1 {
+            log_warn!("[http] systemd passed {count} sockets; serving on the first");
+        }
+        // SAFETY: systemd hands this process ownership of the descriptors it lists, and
+        // the LISTEN_PID check above makes sure they were meant for us.
+        let socket = unsafe { socket2::Socket::from_raw_fd(self.first_fd) };
+        let local = socket
+            .local_addr()
+            .ok()
+            .and_then(|addr| addr.as_socket())
+            .context("inherited descriptor is not an internet socket")?;
+        // systemd doesn't set close-on-exec; keep the socket out of aplay and systemctl.
+        socket.set_cloexec(true)?;
+        socket.set_nonblocking(true)?;
+        log_info!("[http] serving on socket-activated listener {local}");
+        Ok(socket.into())
+    }
+}
+
+/// Serve on the listening socket systemd passed in, then tell systemd the service is ready.
+pub async fn socket_activate(router: Router) -> Result<()> {
+    socket_activate_with(router, ListenFds::from_env()).await
+}
+
+pub async fn socket_activate_with(router: Router, fds: ListenFds) -> Result<()> {
+    let listener = TcpListener::from_std(fds.take_listener(std::process::id())?)?;
+    if let Err(err) = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]) {
+        log_warn!("[http] failed to notify systemd of readiness: {err}");
+    }
+    axum::serve(listener, router.into_make_service_with_connect_info::<ConnectionInfo>())
+        .await
+        .context("serve")?;
+    Ok(())
+}
+
+pub fn bind_dual_stack(port: u16) -> Result<TcpListener> {
+    match bind_v6_any(port) {
+        Ok(listener) => Ok(listener),
+        Err(err) => {
+            log_warn!("[http] IPv6 bind unavailable ({err:#}); falling back to 0.0.0.0:{port}");
+            let std_listener = std::net::TcpListener::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+                .context("bind")?;
+            std_listener.set_nonblocking(true)?;
+            Ok(TcpListener::from_std(std_listener)?)
+        }
+    }
+}
+
+fn bind_v6_any(port: u16) -> Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+pub fn load_or_create_receiver_id(path: &Path) -> Result<String> {
+    if path.exists() {
+        let bytes = std::fs::read(path)?;
+        let existing: StoredReceiver = serde_json::from_slice(&bytes)?;
+        Ok(existing.receiver_id)
+    } else {
+        let id = Uuid::new_v4().to_string();
+        let stored = StoredReceiver { receiver_id: id.clone() };
+        let parent = path.parent().unwrap_or_else(|| Path::new("."));
+        std::fs::create_dir_all(parent)?;
+        std::fs::write(path, serde_json::to_vec_pretty(&stored)?)?;
+        Ok(id)
+    }
+}
+
+/// [`load_or_create_receiver_id`] on the blocking pool, for callers already on the runtime.
+pub async fn load_or_create_receiver_id_async(path: PathBuf) -> Result<String> {
+    tokio::task::spawn_blocking(move || load_or_create_receiver_id(&path))
+        .await
+        .map_err(|err| anyhow!("receiver id task failed: {err}"))?
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredReceiver {
+    receiver_id: String,
+}
+
+/// Avahi service file advertising `_airsync._tcp` with the TXT records from
+/// [`ReceiverAdvertisement`]; fails rather than letting avahi truncate an oversized record.
+/// `setup` adds `setup=1` for a receiver still in first-boot setup.
+pub fn render_avahi_service(
+    name: &str,
+    receiver_id: &str,
+    port: u16,
+    caps: &[Capability],
+    tls: Option<&TlsEndpoint>,
+    setup: bool,
+) -> Result<String, TxtRecordError> {
+    let firmware_version = VersionInfo::current().label();
+    let records = ReceiverAdvertisement {
+        name,
+        receiver_id,
+        firmware_version: &firmware_version,
+        capabilities: caps,
+        tls,
+        setup,
+    }
+    .txt_records()?;
+    let txt: String = records
+        .iter()
+        .map(|(key, value)| format!("    <txt-record>{key}={value}</txt-record>\n"))
+        .collect();
+    Ok(format!(
+        r#"<service-group>
+  <name replace-wildcards="yes">{name}</name>
+  <service>
+    <type>_airsync._tcp</type>
+    <port>{port}</port>
+{txt}  </service>
+</service-group>
+"#
+    ))
+}
+
+/// Advertise the detected audio outputs followed by the features of the profile the
+/// hardware selects, e.g. `out=i2s` and `feat=cal`.
+pub fn render_avahi_service_from_caps(
+    name: &str,
+    receiver_id: &str,
+    port: u16,
+    caps: &HardwareCapabilities,
+    tls: Option<&TlsEndpoint>,
+    setup: bool,
+) -> Result<String, TxtRecordError> {
+    let features = HardwareProfile::select(caps).features();
+    let advertised: Vec<Capability> = caps
+        .audio_outputs
+        .iter()
+        .map(|output| Capability::Output(*output))
+        .chain(features.capabilities())
+        .collect();
+    render_avahi_service(name, receiver_id, port, &advertised, tls, setup)
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0))
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::body::to_bytes;
+    use axum::http::Request;
+    use serde_json::json;
+    use tower::ServiceExt;
+    use crate::generate_chirp_samples;
+    use airsync_shared_protocol::{CalibrationSignalSpec, MarkerKind, MarkerSpec};
+    use crate::calibration::signal::StructuredSignal;
+    use std::path::PathBuf;
+    use crate::calibration::schedule::Weekday;
+    use crate::events::EventKind;
+    use std::convert::Infallible;
+    use airsync_shared_protocol::{Metadata, PlaybackStatus};
+    use crate::test_util::{MockCalibrationSink, MockPlaybackSink, MockSettingsManager, MockTransportControl};
+
+    fn test_builder() -> ReceiverStateBuilder {
+        ReceiverState::builder().receiver_id("rx-1").name("Test")
+    }
+
+    fn test_state() -> ReceiverState {
+        test_builder().build()
+    }
+
+    /// Record a playback under the current settings, as a ready call would, and return
+    /// its session for results to name.
+    fn played_session(state: &ReceiverState) -> Uuid {
+        let session_id = Uuid::new_v4();
+        *state.last_timing.lock().unwrap() = Some(PlaybackTiming {
+            target_ts: 0,
+            start_ts: 0,
+            session_id,
+            emissions: Vec::new(),
+            output_device: state.settings.current().output_device,
+            config_generation: state.settings.generation(),
+        });
+        session_id
+    }
+
+    #[tokio::test]
+    async fn pairing_start_returns_receiver_info() {
+        let state = test_state();
+        let app = router(state.clone());
+
+        let req_body = json!({
+            "device_name": "iPhone",
+            "app_version": "1.0",
+            "platform": "ios"
+        });
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/api/pairing/start")
+                    .header("content-type", "application/json")
+                    .body(Body::from(req_body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let start: PairingStartResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(start.receiver_id, "rx-1");
+        assert_eq!(start.capabilities, vec!["calibration"]);
+        assert_eq!(start.output_device.to_string(), "hw:0,0");
+        assert_eq!(start.min_receiver_protocol, MIN_CLIENT_PROTOCOL_VERSION);
+        assert!(state.status.refresh(now_millis()).paired);
+    }
+
+    #[tokio::test]
+    async fn pairing_start_checks_the_client_protocol_version() {
+        let state = test_state();
+        let app = router(state.clone());
+        let pair = |protocol_version: u32| {
+            json_post(
+                "/api/pairing/start",
+                json!({ "device_name": "iPhone", "app_version": "2.0", "platform": "ios", "protocol_version": protocol_version }),
+            )
+        };
+
+        for incompatible in [MIN_CLIENT_PROTOCOL_VERSION - 1, API_PROTOCOL_VERSION + 1] {
+            let response = app.clone().oneshot(pair(incompatible)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let mismatch: ProtocolVersionMismatch = serde_json::from_slice(&body).unwrap();
+            assert_eq!(mismatch.error, "protocol_version_mismatch");
+            assert_eq!(mismatch.receiver_min, MIN_CLIENT_PROTOCOL_VERSION);
+            assert_eq!(mismatch.receiver_max, API_PROTOCOL_VERSION);
+            assert_eq!(mismatch.client_version, incompatible);
+        }
+        assert!(!state.status.refresh(now_millis()).paired);
+
+        for compatible in MIN_CLIENT_PROTOCOL_VERSION..=API_PROTOCOL_VERSION {
+            let response = app.clone().oneshot(pair(compatible)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "protocol {compatible}");
+        }
+        assert!(state.status.refresh(now_millis()).paired);
+
+        // Shipped apps don't send a version at all and are taken as version 1.
+        let unversioned = json!({ "device_name": "iPhone", "app_version": "1.0", "platform": "ios" });
+        let response = app.oneshot(json_post("/api/pairing/start", unversioned)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn calibration_goes_stale_when_the_output_device_changes() {
+        use crate::calibration::freshness::{Freshness, FreshnessReason};
+
+        let state = test_builder()
+            .calibration(Arc::new(MockCalibrationSink::new()))
+            .clock(|| 10_000)
+            .build();
+        let session_id = played_session(&state);
+        let app = router(state);
+        let current = || async {
+            let response = app
+                .clone()
+                .oneshot(Request::get("/api/calibration/current").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<CurrentCalibrationResponse>(&body).unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot_completed(json_post(
+                "/api/calibration/result",
+                json!({ "timestamp": 9_000, "latency_ms": 42.0, "confidence": 0.9, "session_id": session_id }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let fresh = current().await;
+        assert_eq!(fresh.applied.config_generation, Some(0));
+        assert_eq!(fresh.freshness.status, Freshness::Fresh);
+
+        let response = app
+            .clone()
+            .oneshot(json_post("/api/settings", json!({"output_device": "hw:1,0"})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let stale = current().await;
+        assert_eq!(stale.freshness.status, Freshness::Stale);
+        assert_eq!(
+            stale.freshness.reasons,
+            vec![FreshnessReason::OutputDeviceChanged {
+                calibrated: OutputDeviceSpec::hw(0, 0),
+                current: OutputDeviceSpec::hw(1, 0),
+            }]
+        );
+
+        let response = app
+            .oneshot(json_post(
+                "/api/pairing/start",
+                json!({ "device_name": "iPhone", "app_version": "1.0", "platform": "ios" }),
+            ))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let start: PairingStartResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(start.freshness, Some(stale.freshness));
+    }
+
+    #[tokio::test]
+    async fn pairing_start_reports_whether_calibrated() {
+        let state = test_state();
+        let session_id = played_session(&state);
+        let app = router(state);
+        let pair = || {
+            Request::post("/api/pairing/start")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    json!({ "device_name": "iPhone", "app_version": "1.0", "platform": "ios" }).to_string(),
+                ))
+                .unwrap()
+        };
+        let current = || Request::get("/api/calibration/current").body(Body::empty()).unwrap();
+
+        let response = app.clone().oneshot(pair()).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let start: PairingStartResponse = serde_json::from_slice(&body).unwrap();
+        assert!(!start.calibrated);
+        assert_eq!(start.calibrated_at, None);
+        let response = app.clone().oneshot(current()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app
+            .clone()
+            .oneshot_completed(
+                Request::post("/api/calibration/result")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        json!({ "timestamp": 1_000, "latency_ms": 42.0, "confidence": 0.9, "session_id": session_id })
+                            .to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app.clone().oneshot(pair()).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let start: PairingStartResponse = serde_json::from_slice(&body).unwrap();
+        assert!(start.calibrated);
+        assert_eq!(start.calibrated_at, Some(1_000));
+
+        let response = app.oneshot(current()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let applied: AppliedCalibration = serde_json::from_slice(&body).unwrap();
+        assert_eq!(applied.source, CalibrationSource::Phone);
+        assert_eq!(applied.measured_latency_ms, 42.0);
+        assert_eq!(applied.confidence, 0.9);
+    }
+
+    #[tokio::test]
+    async fn manual_latency_change_is_persisted_as_manual() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_builder()
+            .calibration_store(CalibrationStore::open(dir.path()).unwrap())
+            .clock(|| 5_000)
+            .build();
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::post("/api/settings")
+                    .header("content-type", "application/json")
+                    .body(Body::from(json!({ "latency_offset_seconds": -0.05 }).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let applied = CalibrationStore::open(dir.path()).unwrap().current().unwrap();
+        assert_eq!(applied.source, CalibrationSource::Manual);
+        assert_eq!(applied.timestamp, 5_000);
+        assert!((applied.applied_offset_ms + 50.0).abs() < 1e-3);
+        assert_eq!(applied.output_device.to_string(), "hw:0,0");
+    }
+
+    #[tokio::test]
+    async fn calibration_result_calls_sink() {
+        let sink = Arc::new(MockCalibrationSink::new());
+        let playback = Arc::new(MockPlaybackSink::new());
+        let settings = Arc::new(MockSettingsManager::new());
+        let state = test_builder()
+            .calibration(sink.clone())
+            .settings(settings)
+            .playback(playback)
+            .build();
+        let session_id = played_session(&state);
+        let app = router(state);
+        let mut req_body = json!({
+            "timestamp": 1,
+            "latency_ms": 42.0,
+            "confidence": 0.9
+        });
+        let submit = |body: &serde_json::Value| {
+            Request::post("/api/calibration/result")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(submit(&req_body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error["error"], "missing_session_id");
+        assert!(sink.last().is_none());
+
+        req_body["session_id"] = json!(session_id);
+        let response = app.oneshot_completed(submit(&req_body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let recorded = sink.last().unwrap();
+        assert_eq!(recorded.latency_ms, 42.0);
+        assert_eq!(recorded.confidence, 0.9);
+    }
+
+    #[tokio::test]
+    async fn calibration_result_recomputes_latency_from_marker_detections() {
+        let marker = |id: &str, kind: MarkerKind| MarkerSpec {
+            id: id.into(),
+            kind,
+            start_sample: 0,
+            duration_samples: 10,
+            fade_samples: 0,
+            amplitude: 0.5,
+            search_window_samples: None,
+        };
+        let tone = |freq| MarkerKind::Chirp {
+            start_freq: freq,
+            end_freq: freq,
+            duration_ms: 120,
+        };
+        let structured = StructuredSignal {
+            spec: CalibrationSignalSpec {
+                sample_rate: 48_000,
+                sample_format: SampleFormat::I16,
+                length_samples: 1000,
+                markers: vec![
+                    marker("sweep_anchor", MarkerKind::Chirp {
+                        start_freq: 400,
+                        end_freq: 9_000,
+                        duration_ms: 150,
+                    }),
+                    marker("chirp_1", tone(800)),
+                    marker("chirp_2", tone(1_000)),
+                    marker("chirp_6", tone(10_000)),
+                ],
+                signal_id: None,
+                content_hash: None,
+                level_db: None,
+                suggested_decimation: 1,
+                max_expected_latency_ms: None,
+            },
+            path: PathBuf::from("/tmp/structured.wav"),
+        };
+        let sink = Arc::new(MockCalibrationSink::new());
+        let state = test_builder().calibration(sink.clone()).structured(structured).build();
+        let session_id = played_session(&state);
+        let app = router(state);
+        let detection = |id: &str, latency_ms: f32, correlation: f32| {
+            json!({"marker_id": id, "sample_index": 0, "correlation": correlation, "latency_ms": latency_ms})
+        };
+        let submit = |timestamp: u64, detections: Vec<serde_json::Value>| {
+            json_post(
+                "/api/calibration/result",
+                json!({
+                    "timestamp": timestamp,
+                    "latency_ms": 500.0,
+                    "confidence": 0.9,
+                    "detections": detections,
+                    "session_id": session_id
+                }),
+            )
+        };
+
+        let response = app
+            .clone()
+            .oneshot_completed(submit(
+                1,
+                vec![
+                    detection("sweep_anchor", 100.0, 0.8),
+                    detection("chirp_1", 102.0, 0.8),
+                    detection("chirp_6", 101.0, 0.05),
+                ],
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let rejected: InsufficientDetectionsResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(rejected.error, "insufficient_detections");
+        assert_eq!(rejected.markers.used, vec!["sweep_anchor", "chirp_1"]);
+        assert_eq!(rejected.markers.discarded[0].marker_id, "chirp_6");
+        assert!(sink.last().is_none());
+
+        let response = app
+            .oneshot_completed(submit(
+                2,
+                vec![
+                    detection("sweep_anchor", 100.0, 0.8),
+                    detection("chirp_1", 102.0, 0.8),
+                    detection("chirp_2", 102.0, 0.8),
+                    detection("chirp_6", 101.0, 0.05),
+                ],
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let result: CalibrationResultResponse = serde_json::from_slice(&body).unwrap();
+        let markers = result.markers.unwrap();
+        assert_eq!(markers.used, vec!["sweep_anchor", "chirp_1", "chirp_2"]);
+        assert_eq!(markers.discarded.len(), 1);
+        // The sweep outweighs both tones, so the latency leans towards its 100ms.
+        let applied = sink.last().unwrap().latency_ms;
+        assert!((applied - 100.75).abs() < 0.01, "{applied}");
+        assert_eq!(result.applied.measured_latency_ms, applied);
+    }
+
+    #[tokio::test]
+    async fn calibration_result_rejects_older_submission() {
+        let sink = Arc::new(MockCalibrationSink::new());
+        let state = test_builder().calibration(sink.clone()).build();
+        let session_id = played_session(&state);
+        let app = router(state);
+        let submit = |timestamp: u64, latency_ms: f32| {
+            Request::post("/api/calibration/result")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    json!({
+                        "timestamp": timestamp,
+                        "latency_ms": latency_ms,
+                        "confidence": 0.9,
+                        "session_id": session_id
+                    })
+                    .to_string(),
+                ))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot_completed(submit(200, 42.0)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app.clone().oneshot_completed(submit(100, 80.0)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let conflict: CalibrationConflictResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(conflict.error, "stale_result");
+        assert_eq!(conflict.submitted_timestamp, 100);
+        let current = conflict.current.unwrap();
+        assert_eq!(current.timestamp, 200);
+        assert_eq!(current.measured_latency_ms, 42.0);
+        assert_eq!(sink.last().unwrap().latency_ms, 42.0);
+
+        let response = app.clone().oneshot_completed(submit(200, 42.0)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let response = app.oneshot_completed(submit(300, 50.0)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(sink.last().unwrap().latency_ms, 50.0);
+    }
+
+    #[tokio::test]
+    async fn round_results_are_aggregated_on_finalize() {
+        let sink = Arc::new(MockCalibrationSink::new());
+        let state = test_builder().calibration(sink.clone()).build();
+        let app = router(state);
+        let post = |path: &str, body: serde_json::Value| {
+            Request::post(path)
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        for (timestamp, latency_ms) in [(10, 40.0), (20, 44.0)] {
+            let response = app
+                .clone()
+                .oneshot(post(
+                    "/api/calibration/round-result",
+                    json!({"timestamp": timestamp, "latency_ms": latency_ms, "confidence": 0.8}),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        assert!(sink.last().is_none(), "rounds must not be applied individually");
+
+        let response = app
+            .clone()
+            .oneshot(post("/api/calibration/finalize", json!({})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(payload["rounds"].as_array().unwrap().len(), 2);
+        assert_eq!(payload["rounds"][1]["latency_ms"], 44.0);
+        assert_eq!(payload["rejected"].as_array().unwrap().len(), 0);
+        assert!((payload["aggregate_latency_ms"].as_f64().unwrap() - 42.0).abs() < 1e-3);
+
+        let applied = sink.last().unwrap();
+        assert!((applied.latency_ms - 42.0).abs() < 1e-3);
+        assert_eq!(applied.timestamp, 20);
+
+        // Rounds are consumed by a successful finalize.
+        let response = app
+            .oneshot(post("/api/calibration/finalize", json!({})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn finalize_keeps_rounds_it_did_not_apply() {
+        let sink = Arc::new(MockCalibrationSink::with_delay(Duration::from_millis(50)));
+        let state = test_builder().calibration(sink.clone()).build();
+        let app = router(state.clone());
+        let round = |timestamp: u64| json_post("/api/calibration/round-result", json!({"timestamp": timestamp, "latency_ms": 40.0, "confidence": 0.8}));
+        let finalize = || json_post("/api/calibration/finalize", json!({}));
+
+        app.clone().oneshot(round(100)).await.unwrap();
+        let finalizing = tokio::spawn(app.clone().oneshot(finalize()));
+        while !state.rounds.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        app.clone().oneshot(round(200)).await.unwrap();
+        assert_eq!(finalizing.await.unwrap().unwrap().status(), StatusCode::OK);
+        assert_eq!(sink.last().unwrap().timestamp, 100);
+        let kept: Vec<u64> = state.rounds.lock().unwrap().iter().map(|r| r.timestamp).collect();
+        assert_eq!(kept, [200]);
+
+        // A rejected apply puts its rounds back ahead of newer ones.
+        app.clone().oneshot(round(50)).await.unwrap();
+        let response = app.clone().oneshot(json_post("/api/calibration/finalize", json!({"timestamp": 50}))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let kept: Vec<u64> = state.rounds.lock().unwrap().iter().map(|r| r.timestamp).collect();
+        assert_eq!(kept, [200, 50]);
+    }
+
+    #[cfg(feature = "simulation")]
+    #[tokio::test]
+    async fn simulate_submits_noisy_latency_within_three_sigma() {
+        use rand::SeedableRng;
+        let sink = Arc::new(MockCalibrationSink::new());
+        let state = test_builder().calibration(sink.clone()).build();
+        let app = router(state);
+        for seed in 0..20u64 {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::post("/api/calibration/simulate")
+                        .header("content-type", "application/json")
+                        .body(Body::from(
+                            json!({"true_latency_ms": 60.0, "noise_stddev_ms": 2.0, "seed": seed}).to_string(),
+                        ))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let submitted = report["submitted_latency_ms"].as_f64().unwrap() as f32;
+            // Seeded, so the bound holds on every run rather than 95% of them.
+            assert!((submitted - 60.0).abs() <= 6.0, "seed {seed} submitted {submitted}");
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            assert_eq!(report["noise_ms"].as_f64().unwrap() as f32, gaussian_noise(&mut rng, 2.0));
+            assert_eq!(report["input_latency_ms"], 60.0);
+            assert_eq!(sink.last().unwrap().latency_ms, submitted);
+            // Each simulated submission needs a fresh timestamp to pass the stale check.
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+    }
+
+    #[cfg(feature = "simulation")]
+    #[test]
+    fn gaussian_noise_is_zero_without_stddev() {
+        assert_eq!(gaussian_noise(&mut rand::thread_rng(), 0.0), 0.0);
+    }
+
+    #[tokio::test]
+    async fn settings_and_calibration_changes_reach_one_subscriber() {
+        let state = test_state();
+        let app = router(state.clone());
+        let (snapshot, mut events) = state.events().subscribe(&EventKind::ALL);
+        assert!(matches!(snapshot.as_slice(), [ReceiverEvent::Status(_)]));
+
+        let response = app
+            .clone()
+            .oneshot(json_post("/api/settings", json!({"output_device": "hw:1,0"})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        app.oneshot(chirp_request(1)).await.unwrap();
+
+        let Some(ReceiverEvent::Settings(config)) = events.try_recv() else {
+            panic!("expected the new settings first");
+        };
+        assert_eq!(config.output_device, OutputDeviceSpec::hw(1, 0));
+        assert!(matches!(
+            events.try_recv(),
+            Some(ReceiverEvent::Calibration(SessionEvent::Requested { .. }))
+        ));
+        assert_eq!(events.dropped_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn settings_report_when_the_receiver_was_last_calibrated() {
+        let state = test_state();
+        let session_id = played_session(&state);
+        let app = router(state);
+        let settings = || async {
+            let response = app
+                .clone()
+                .oneshot(Request::get("/api/settings").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+        };
+        assert_eq!(settings().await["last_calibrated_at"], serde_json::Value::Null);
+
+        let calibrated_at = now_millis();
+        let response = app
+            .clone()
+            .oneshot_completed(json_post(
+                "/api/calibration/result",
+                json!({"timestamp": calibrated_at, "latency_ms": 40.0, "confidence": 0.9, "session_id": session_id}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let reported = settings().await["last_calibrated_at"].as_u64().unwrap();
+        assert!(reported.abs_diff(calibrated_at) < 5, "{reported} vs {calibrated_at}");
+    }
+
+    #[tokio::test]
+    async fn calibration_result_conflicts_when_settings_changed_since_request() {
+        let sink = Arc::new(MockCalibrationSink::new());
+        let state = test_builder().calibration(sink.clone()).build();
+        let app = router(state.clone());
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/api/settings").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let before: SettingsResponse = serde_json::from_slice(&body).unwrap();
+
+        let response = app.clone().oneshot(chirp_request(1)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/api/settings")
+                    .header("content-type", "application/json")
+                    .body(Body::from(json!({"output_device": "hw:1,0"}).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let session_id = played_session(&state);
+
+        let submit = |body: serde_json::Value| {
+            Request::post("/api/calibration/result")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let response = app
+            .clone()
+            .oneshot_completed(submit(json!({
+                "timestamp": 1,
+                "latency_ms": 30.0,
+                "confidence": 0.9,
+                "expected_generation": before.config_generation,
+                "session_id": session_id
+            })))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let conflict: CalibrationConflictResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(conflict.error, "config_generation_changed");
+        assert_eq!(conflict.config_generation, before.config_generation + 1);
+        assert_eq!(conflict.output_device, OutputDeviceSpec::hw(1, 0));
+
+        let response = app
+            .clone()
+            .oneshot_completed(submit(json!({
+                "timestamp": 1,
+                "latency_ms": 30.0,
+                "confidence": 0.9,
+                "expected_output_device": before.output_device,
+                "session_id": session_id
+            })))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let conflict: CalibrationConflictResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(conflict.error, "output_device_changed");
+        assert!(sink.last().is_none());
+
+        let response = app
+            .oneshot_completed(submit(json!({
+                "timestamp": 1,
+                "latency_ms": 30.0,
+                "confidence": 0.9,
+                "expected_output_device": "hw:1,0",
+                "expected_generation": before.config_generation + 1,
+                "session_id": session_id
+            })))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(sink.last().unwrap().latency_ms, 30.0);
+    }
+
+    #[test]
+    fn config_store_bumps_generation_only_on_success() {
+        let store = ConfigStore::new(ShairportConfig {
+            device_name: "AirSync".into(),
+            output_device: OutputDeviceSpec::hw(0, 0),
+            latency_offset_seconds: 0.0,
+            calibration_gain: None,
+            eq: None,
+            self_calibration: None,
+        });
+        assert_eq!(store.generation(), 0);
+        let failed = store.update_with(|_| Err(anyhow!("write failed")));
+        assert!(failed.is_err());
+        assert_eq!(store.generation(), 0);
+        let (cfg, generation) = store
+            .update_with(|current| {
+                let mut cfg = current.clone();
+                cfg.device_name = "Kitchen".into();
+                Ok(cfg)
+            })
+            .unwrap();
+        assert_eq!(generation, 1);
+        assert_eq!(cfg.device_name, "Kitchen");
+        assert_eq!(store.snapshot(), (cfg, 1));
+    }
+
+    #[test]
+    fn config_store_readers_and_writer_finish_with_consistent_snapshots() {
+        let store = ConfigStore::new(crate::airplay::generate_config(None, AudioOutput::I2S));
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let worker_store = store.clone();
+        std::thread::spawn(move || {
+            std::thread::scope(|scope| {
+                for _ in 0..10 {
+                    scope.spawn(|| {
+                        let mut last = 0;
+                        for _ in 0..2_000 {
+                            let (cfg, generation) = worker_store.snapshot();
+                            // The writer sets the offset to its generation in ms.
+                            assert_eq!(cfg.latency_offset_seconds, generation as f32 / 1000.0);
+                            assert!(generation >= last);
+                            last = generation;
+                            assert!(worker_store.generation() >= generation);
+                        }
+                    });
+                }
+                scope.spawn(|| {
+                    for _ in 0..500 {
+                        worker_store
+                            .update_with(|current| {
+                                let mut next = current.clone();
+                                next.latency_offset_seconds += 0.001;
+                                next.latency_offset_seconds = (next.latency_offset_seconds * 1000.0).round() / 1000.0;
+                                Ok(next)
+                            })
+                            .unwrap();
+                    }
+                });
+            });
+            done_tx.send(()).unwrap();
+        });
+        done_rx
+            .recv_timeout(std::time::Duration::from_secs(30))
+            .expect("readers and writer deadlocked or panicked");
+        assert_eq!(store.generation(), 500);
+        assert_eq!(store.current().latency_offset_seconds, 0.5);
+    }
+
+    /// How long 10 readers take to snapshot the config while a writer commits, against the
+    /// same work behind a plain `Mutex`. Run with
+    /// `cargo test --release -p airsync-receiver-core config_store_contention -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn config_store_contention() {
+        const READS: usize = 50_000;
+        const WRITES: usize = 5_000;
+        fn contended(read: impl Fn() + Sync, write: impl Fn() + Sync) -> std::time::Duration {
+            let started = std::time::Instant::now();
+            std::thread::scope(|scope| {
+                for _ in 0..10 {
+                    scope.spawn(|| (0..READS).for_each(|_| read()));
+                }
+                scope.spawn(|| (0..WRITES).for_each(|_| write()));
+            });
+            started.elapsed()
+        }
+
+        let next = |current: &ShairportConfig| ShairportConfig {
+            latency_offset_seconds: 0.01,
+            ..current.clone()
+        };
+        // Both writers build the next config, bump the generation and copy the result out
+        // under the lock; the store has no settings file, so it doesn't persist anything.
+        let store = ConfigStore::new(crate::airplay::generate_config(None, AudioOutput::I2S));
+        let rwlock = contended(
+            || drop(std::hint::black_box(store.snapshot())),
+            || drop(store.update_with(|current| Ok(next(current)))),
+        );
+        let mutex = Mutex::new((crate::airplay::generate_config(None, AudioOutput::I2S), 0u64));
+        let mutexed = contended(
+            || drop(std::hint::black_box(mutex.lock().unwrap().clone())),
+            || {
+                let mut stored = mutex.lock().unwrap();
+                stored.0 = next(&stored.0);
+                stored.1 += 1;
+                drop(std::hint::black_box(stored.clone()));
+            },
+        );
+        println!("10 readers x {READS} snapshots, 1 writer x {WRITES} commits: RwLock {rwlock:?}, Mutex {mutexed:?}");
+    }
+
+    #[tokio::test]
+    async fn settings_update_changes_config_and_tracks_restart() {
+        let settings = Arc::new(MockSettingsManager::new());
+        let state = test_builder().settings(settings.clone()).build();
+        let app = router(state);
+
+        let update = json!({
+            "device_name": "Living Room",
+            "output_device": "hw:1,0",
+            "latency_offset_seconds": 0.05
+        });
+        let response = app
+            .oneshot(
+                Request::post("/api/settings")
+                    .header("content-type", "application/json")
+                    .body(Body::from(update.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let cfg = settings.current();
+        assert_eq!(cfg.device_name, "Living Room");
+        assert_eq!(cfg.output_device, OutputDeviceSpec::hw(1, 0));
+        assert_eq!(cfg.latency_offset_seconds, 0.05);
+        assert_eq!(settings.restart_calls(), 1);
+    }
+
+    /// Send `request` and, when it was queued as a job, poll the job at its `Location`
+    /// until it finishes and answer with its result or error as the endpoint would have
+    /// synchronously.
+    trait OneshotCompleted {
+        fn oneshot_completed(self, request: Request<Body>) -> BoxFuture<'static, Result<Response, Infallible>>;
+    }
+
+    impl OneshotCompleted for Router {
+        fn oneshot_completed(self, request: Request<Body>) -> BoxFuture<'static, Result<Response, Infallible>> {
+            Box::pin(async move {
+                let response = self.clone().oneshot(request).await?;
+                if response.status() != StatusCode::ACCEPTED {
+                    return Ok(response);
+                }
+                let status_url = response.headers()[axum::http::header::LOCATION].to_str().unwrap().to_string();
+                loop {
+                    let poll = Request::get(&status_url).body(Body::empty()).unwrap();
+                    let response = self.clone().oneshot(poll).await?;
+                    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                    let job: JobStatus = serde_json::from_slice(&body).unwrap();
+                    match (job.result, job.error) {
+                        (Some(result), _) => return Ok(Json(result).into_response()),
+                        (_, Some(error)) => {
+                            return Ok((StatusCode::from_u16(error.status).unwrap(), Json(error.body)).into_response())
+                        }
+                        _ => tokio::time::sleep(Duration::from_millis(5)).await,
+                    }
+                }
+            })
+        }
+    }
+
+    fn json_post(path: &str, body: serde_json::Value) -> Request<Body> {
+        Request::post(path)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    async fn job(app: &Router, url: &str) -> JobStatus {
+        let response = app.clone().oneshot(Request::get(url).body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+    }
+
+    async fn job_leaving(app: &Router, url: &str, state: crate::jobs::JobState) -> JobStatus {
+        loop {
+            let job = job(app, url).await;
+            if job.state != state {
+                return job;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn calibration_result_is_applied_as_a_job_polled_to_completion() {
+        use crate::jobs::JobState;
+
+        let sink = Arc::new(MockCalibrationSink::with_delay(Duration::from_millis(200)));
+        let state = test_builder().calibration(sink.clone()).build();
+        let session_id = played_session(&state);
+        let app = router(state);
+        let mut urls = Vec::new();
+        for timestamp in [1, 2] {
+            let result = json!({
+                "timestamp": timestamp,
+                "latency_ms": 40.0 + timestamp as f64,
+                "confidence": 0.9,
+                "session_id": session_id
+            });
+            let response = app.clone().oneshot(json_post("/api/calibration/result", result)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+            let location = response.headers()[axum::http::header::LOCATION].to_str().unwrap().to_string();
+            let accepted: JobAccepted = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+            assert_eq!(location, accepted.status_url);
+            urls.push(accepted.status_url);
+        }
+
+        let first = job_leaving(&app, &urls[0], JobState::Pending).await;
+        assert_eq!(first.kind, "calibration_result");
+        assert_eq!(first.state, JobState::Running);
+        assert_eq!(job(&app, &urls[1]).await.state, JobState::Pending);
+        assert!(sink.last().is_none());
+
+        let first = job_leaving(&app, &urls[0], JobState::Running).await;
+        assert_eq!(first.state, JobState::Succeeded);
+        assert_eq!(first.result.unwrap()["applied_offset_ms"], 41.0);
+        assert!(first.finished_at_ms.is_some());
+        let second = job_leaving(&app, &urls[1], JobState::Pending).await;
+        let second = job_leaving(&app, &urls[1], second.state).await;
+        assert_eq!(second.state, JobState::Succeeded);
+        assert_eq!(sink.last().unwrap().latency_ms, 42.0);
+
+        let response = app
+            .oneshot(Request::get(format!("/api/jobs/{}", Uuid::new_v4())).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn settings_update_runs_as_a_job_when_asked() {
+        use crate::jobs::JobState;
+
+        let settings = Arc::new(MockSettingsManager::new().with_delay(Duration::from_millis(100)));
+        let app = router(test_builder().settings(settings.clone()).build());
+        let response = app
+            .clone()
+            .oneshot(json_post("/api/settings?async=true", json!({"device_name": "Den"})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let accepted: JobAccepted = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(settings.restart_calls(), 0);
+
+        let mut job = job_leaving(&app, &accepted.status_url, JobState::Pending).await;
+        if job.state == JobState::Running {
+            job = job_leaving(&app, &accepted.status_url, JobState::Running).await;
+        }
+        assert_eq!(job.state, JobState::Succeeded);
+        assert_eq!(job.result.unwrap()["device_name"], "Den");
+        assert_eq!(settings.restart_calls(), 1);
+
+        let invalid = app
+            .oneshot(json_post("/api/settings?async=true", json!({"calibration_gain": 1.5})))
+            .await
+            .unwrap();
+        assert_eq!(invalid.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_refused_before_the_handler_runs() {
+        let sink = Arc::new(MockCalibrationSink::new());
+        let state = test_builder()
+            .calibration(sink.clone())
+            .body_limits(BodyLimits { default_bytes: 1024 })
+            .build();
+        let session_id = played_session(&state);
+        let app = router(state);
+        let padding = "x".repeat(2048);
+        let oversized = json!({"timestamp": 1, "latency_ms": 40.0, "confidence": 0.9, "padding": padding});
+
+        let response = app
+            .clone()
+            .oneshot_completed(json_post("/api/calibration/result", oversized.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!({"error": "request_too_large", "max_bytes": 1024}));
+
+        // Without a Content-Length the limit trips while the body is read.
+        let chunks: Vec<_> = oversized
+            .to_string()
+            .into_bytes()
+            .chunks(256)
+            .map(|chunk| Ok::<_, std::io::Error>(chunk.to_vec()))
+            .collect();
+        let response = app
+            .clone()
+            .oneshot_completed(
+                Request::post("/api/calibration/result")
+                    .header("content-type", "application/json")
+                    .body(Body::from_stream(futures_util::stream::iter(chunks)))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["error"], "request_too_large");
+        assert!(sink.last().is_none());
+
+        let response = app
+            .oneshot_completed(json_post(
+                "/api/calibration/result",
+                json!({"timestamp": 1, "latency_ms": 40.0, "confidence": 0.9, "session_id": session_id}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(sink.last().unwrap().latency_ms, 40.0);
+    }
+
+    #[tokio::test]
+    async fn shairport_managers_restart_through_the_async_path() {
+        let writer = crate::test_util::MockWriter::new();
+        let controller = crate::test_util::MockController::new();
+        let store = ConfigStore::new(ShairportConfig {
+            device_name: "AirSync".into(),
+            output_device: OutputDeviceSpec::hw(0, 0),
+            latency_offset_seconds: 0.0,
+            calibration_gain: None,
+            eq: None,
+            self_calibration: None,
+        });
+        let sink = ShairportCalibrationSink::new(
+            CalibrationApplier::new(writer.clone(), controller.clone()),
+            store.clone(),
+        );
+        let settings = ShairportSettingsManager::new(writer.clone(), controller.clone(), store.clone());
+        let state = test_builder()
+            .calibration(Arc::new(sink))
+            .settings(Arc::new(settings))
+            .build();
+        let app = router(state.clone());
+
+        let response = app
+            .clone()
+            .oneshot(json_post("/api/settings", json!({"device_name": "Kitchen"})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let session_id = played_session(&state);
+        let response = app
+            .oneshot_completed(json_post(
+                "/api/calibration/result",
+                json!({"timestamp": 1, "latency_ms": 40.0, "confidence": 0.9, "session_id": session_id}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        assert_eq!(controller.calls(), 2);
+        assert_eq!(controller.blocking_calls(), 0);
+        assert!(writer.last_contents().unwrap().contains("Kitchen"));
+        assert_eq!(store.snapshot().0.device_name, "Kitchen");
+    }
+
+    #[tokio::test]
+    async fn failed_restart_leaves_settings_unchanged() {
+        let controller = crate::test_util::MockController::failing("systemctl unavailable");
+        let store = ConfigStore::new(ShairportConfig {
+            device_name: "AirSync".into(),
+            output_device: OutputDeviceSpec::hw(0, 0),
+            latency_offset_seconds: 0.0,
+            calibration_gain: None,
+            eq: None,
+            self_calibration: None,
+        });
+        let settings =
+            ShairportSettingsManager::new(crate::test_util::MockWriter::new(), controller.clone(), store.clone());
+        let app = router(test_builder().settings(Arc::new(settings)).build());
+
+        let response = app
+            .oneshot(json_post("/api/settings", json!({"device_name": "Kitchen"})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(controller.calls(), 1);
+        assert_eq!(store.snapshot().0.device_name, "AirSync");
+        assert_eq!(store.generation(), 0);
+    }
+
+    #[tokio::test]
+    async fn settings_and_calibration_applies_render_through_the_template() {
+        use crate::airplay::{ConfigTemplate, TemplateFileRenderer};
+
+        const TEMPLATE: &str = "general = {\n    name = \"{{name}}\";\n    audio_backend_latency_offset_in_seconds = {{latency_offset}};\n};\n\nalsa = {\n    output_device = \"{{output_device}}\";\n    mixer_control_name = \"{{mixer_control}}\";\n};\n\ndsp = {\n    loudness = \"yes\";\n};\n";
+        let template = || ConfigTemplate::parse(TEMPLATE).unwrap();
+        let writer = crate::test_util::MockWriter::new();
+        let controller = crate::test_util::MockController::new();
+        let store = ConfigStore::new(crate::airplay::generate_config(None, AudioOutput::I2S));
+        let sink = ShairportCalibrationSink::new(
+            CalibrationApplier::new(writer.clone(), controller.clone()).renderer(template()),
+            store.clone(),
+        );
+        let settings = ShairportSettingsManager::new(writer.clone(), controller.clone(), store.clone()).renderer(template());
+        let state = test_builder().calibration(Arc::new(sink)).settings(Arc::new(settings)).build();
+        let app = router(state.clone());
+
+        let response = app
+            .clone()
+            .oneshot(json_post("/api/settings", json!({"device_name": "Kitchen"})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(writer.last_contents().unwrap().contains("loudness = \"yes\";"));
+        let session_id = played_session(&state);
+        let response = app
+            .oneshot_completed(json_post(
+                "/api/calibration/result",
+                json!({"timestamp": 1, "latency_ms": 40.0, "confidence": 0.9, "session_id": session_id}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let written = writer.last_contents().unwrap();
+        assert!(written.contains("loudness = \"yes\";"));
+        assert!(written.contains("name = \"Kitchen\";"));
+        assert!(written.contains("audio_backend_latency_offset_in_seconds = -0.040;"));
+
+        // A template missing a placeholder fails the update before anything is written.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shairport-template.conf");
+        std::fs::write(&path, TEMPLATE.replace("{{latency_offset}}", "0.0")).unwrap();
+        let writer = crate::test_util::MockWriter::new();
+        let controller = crate::test_util::MockController::new();
+        let settings = ShairportSettingsManager::new(writer.clone(), controller.clone(), store.clone())
+            .renderer(TemplateFileRenderer::new(&path));
+        let app = router(test_builder().settings(Arc::new(settings)).build());
+        let response = app
+            .oneshot(json_post("/api/settings", json!({"device_name": "Den"})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(writer.last_contents(), None);
+        assert_eq!(controller.calls(), 0);
+        assert_eq!(store.snapshot().0.device_name, "Kitchen");
+    }
+
+    #[test]
+    fn playback_gain_prefers_request_then_setting_then_output_default() {
+        let cards = AlsaCard::parse_aplay_list(
+            "card 0: sndrpihifiberry [snd_rpi_hifiberry_dacplus]\ncard 1: Headphones [bcm2835 Headphones]",
+        );
+        let store = ConfigStore::new(crate::airplay::generate_config(None, AudioOutput::I2S));
+        let sink = SystemPlaybackSink::new(48_000, store.clone(), cards, None);
+        let chirp = |amplitude| {
+            PlaybackRequest::Chirp(ChirpConfig {
+                amplitude,
+                ..ChirpConfig::default()
+            })
+        };
+        let set = |update: fn(&mut ShairportConfig)| {
+            store
+                .update_with(|current| {
+                    let mut next = current.clone();
+                    update(&mut next);
+                    Ok(next)
+                })
+                .unwrap();
+        };
+
+        assert_eq!(
+            sink.effective_gain(&chirp(None)),
+            (I2S_DEFAULT_GAIN, GainSource::OutputDefault(Some(AudioOutput::I2S)))
+        );
+        set(|cfg| cfg.output_device = OutputDeviceSpec::hw(1, 0));
+        assert_eq!(
+            sink.effective_gain(&chirp(None)),
+            (HEADPHONE_DEFAULT_GAIN, GainSource::OutputDefault(Some(AudioOutput::Headphone)))
+        );
+        set(|cfg| cfg.output_device = OutputDeviceSpec::hw(7, 0));
+        assert_eq!(
+            sink.effective_gain(&chirp(None)),
+            (FALLBACK_DEFAULT_GAIN, GainSource::OutputDefault(None))
+        );
+
+        set(|cfg| cfg.calibration_gain = Some(0.3));
+        assert_eq!(sink.effective_gain(&chirp(None)), (0.3, GainSource::Setting));
+        assert_eq!(
+            sink.effective_gain(&PlaybackRequest::File(PathBuf::from("/tmp/s.wav"))),
+            (0.3, GainSource::Setting)
+        );
+        assert_eq!(sink.effective_gain(&chirp(Some(0.8))), (0.8, GainSource::Request));
+    }
+
+    #[test]
+    fn file_playback_is_scaled_by_gain() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tone.wav");
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 48_000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for s in [1_000i16, -2_000, 30_000] {
+            writer.write_sample(s).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let config = ConfigStore::new(crate::airplay::generate_config(None, AudioOutput::I2S));
+        let sink = SystemPlaybackSink::new(48_000, config, Vec::new(), None);
+        let request = PlaybackRequest::File(path.clone());
+        assert_eq!(sink.resolve_wav(&request, 1.0, 48_000).unwrap(), path);
+        let scaled = sink.resolve_wav(&request, 0.5, 48_000).unwrap();
+        let samples: Vec<i16> = hound::WavReader::open(&scaled)
+            .unwrap()
+            .samples::<i16>()
+            .map(|s| s.unwrap())
+            .collect();
+        std::fs::remove_file(scaled).unwrap();
+        assert_eq!(samples, vec![500, -1_000, 15_000]);
+    }
+
+    #[test]
+    fn play_command_defaults_to_aplay() {
+        let play = PlayCommand::resolve(|_| None, "hw:1,0", Path::new("/tmp/signal.wav"));
+        assert_eq!(play.program, "aplay");
+        assert_eq!(play.args, ["-D", "hw:1,0", "-q", "/tmp/signal.wav"]);
+    }
+
+    #[test]
+    fn play_command_env_overrides_the_binary_and_prepends_args() {
+        let env = |key: &str| match key {
+            PLAY_COMMAND_ENV => Some("echo".to_string()),
+            PLAY_ARGS_ENV => Some(" --volume 0.8  -v ".to_string()),
+            _ => None,
+        };
+        let play = PlayCommand::resolve(env, "default", Path::new("/tmp/signal.wav"));
+        assert_eq!(play.program, "echo");
+        assert_eq!(play.args, ["--volume", "0.8", "-v", "-D", "default", "-q", "/tmp/signal.wav"]);
+
+        #[cfg(not(feature = "embedded"))]
+        {
+            let output = play.command().output().unwrap();
+            assert!(output.status.success());
+            assert_eq!(
+                String::from_utf8_lossy(&output.stdout).trim(),
+                "--volume 0.8 -v -D default -q /tmp/signal.wav"
+            );
+        }
+
+        let blank = |key: &str| (key == PLAY_COMMAND_ENV).then(|| "  ".to_string());
+        assert_eq!(PlayCommand::resolve(blank, "default", Path::new("x.wav")).program, "aplay");
+    }
+
+    #[test]
+    fn outputs_without_48k_are_fed_resampled_signals() {
+        let dir = tempfile::tempdir().unwrap();
+        let stream = dir.path().join("card1/stream0");
+        std::fs::create_dir_all(stream.parent().unwrap()).unwrap();
+        std::fs::write(&stream, "Playback:\n  Interface 1\n    Rates: 32000, 44100\n").unwrap();
+        let cards = AlsaCard::parse_aplay_list("card 0: Headphones [bcm2835 Headphones]\ncard 1: DAC [USB Audio DAC]");
+        let store = ConfigStore::new(crate::airplay::generate_config(None, AudioOutput::Headphone));
+        let sink = SystemPlaybackSink::new(48_000, store.clone(), cards, Some(dir.path().join("pregen.wav")))
+            .with_asound_root(dir.path());
+        assert_eq!(sink.playback_rate(), 48_000);
+        store
+            .update_with(|current| {
+                Ok(ShairportConfig {
+                    output_device: OutputDeviceSpec::hw(1, 0),
+                    ..current.clone()
+                })
+            })
+            .unwrap();
+        assert_eq!(sink.playback_rate(), 44_100);
+        assert_eq!(sink.output_rate(), Some(44_100));
+
+        let chirp = sink
+            .resolve_wav(&PlaybackRequest::Chirp(ChirpConfig::default()), 1.0, 44_100)
+            .unwrap();
+        assert_ne!(chirp, dir.path().join("pregen.wav"));
+        let structured = crate::calibration::signal::generate_structured_signal(dir.path().join("structured.wav")).unwrap();
+        let converted = sink
+            .resolve_wav(&PlaybackRequest::File(structured.path.clone()), 1.0, 44_100)
+            .unwrap();
+        for path in [chirp, converted.clone()] {
+            assert_eq!(hound::WavReader::open(&path).unwrap().spec().sample_rate, 44_100);
+        }
+        let converted_len = hound::WavReader::open(&converted).unwrap().duration();
+        std::fs::remove_file(converted).unwrap();
+        assert_eq!(converted_len, resample_spec(&structured.spec, 44_100).length_samples);
+    }
+
+    #[tokio::test]
+    async fn spec_and_playback_report_follow_the_output_rate() {
+        let dir = tempfile::tempdir().unwrap();
+        let structured = crate::calibration::signal::generate_structured_signal(dir.path().join("structured.wav")).unwrap();
+        let state = test_builder()
+            .playback(Arc::new(MockPlaybackSink::with_output_rate(44_100)))
+            .structured(structured.clone())
+            .build();
+        let app = router(state.clone());
+        let response = app
+            .clone()
+            .oneshot(Request::get("/api/calibration/spec").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let reported: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let reported: CalibrationSignalSpec = serde_json::from_value(reported["spec"].clone()).unwrap();
+        assert_eq!(reported, resample_spec(&structured.spec, 44_100));
+
+        let (_, mut events) = state.session.subscribe();
+        let request = json!({"timestamp": 1, "delay_ms": 0, "structured": true});
+        app.clone().oneshot(json_post("/api/calibration/request", request)).await.unwrap();
+        app.oneshot(ready_request(json!({"countdown_ms": 0}))).await.unwrap();
+        loop {
+            if let SessionEvent::PlaybackStarted { sample_rate, .. } = events.recv().await.unwrap() {
+                assert_eq!(sample_rate, Some(44_100));
+                break;
+            }
+        }
+        let timing = state.last_timing.lock().unwrap().clone().unwrap();
+        for (played, rendered) in timing.emissions.iter().zip(marker_emissions(&structured.spec)) {
+            assert_eq!(played.marker_id, rendered.marker_id);
+            assert!((played.offset_ms - rendered.offset_ms).abs() <= 1000.0 / 44_100.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn playback_reports_its_gain_relative_to_the_signal_level() {
+        let dir = tempfile::tempdir().unwrap();
+        let structured = crate::calibration::signal::generate_structured_signal(dir.path().join("structured.wav")).unwrap();
+        let reference = structured.spec.level_db.unwrap();
+        let state = test_builder()
+            .playback(Arc::new(MockPlaybackSink::with_output_gain(0.5)))
+            .structured(structured)
+            .build();
+        let app = router(state.clone());
+        let (_, mut events) = state.session.subscribe();
+        let request = json!({"timestamp": 1, "delay_ms": 0, "structured": true});
+        app.clone().oneshot(json_post("/api/calibration/request", request)).await.unwrap();
+        app.oneshot(ready_request(json!({"countdown_ms": 0}))).await.unwrap();
+        loop {
+            if let SessionEvent::PlaybackStarted { gain_db, level_db, .. } = events.recv().await.unwrap() {
+                let gain_db = gain_db.unwrap();
+                assert!((gain_db + 6.02).abs() < 0.01, "{gain_db}");
+                assert!((level_db.unwrap() - (reference + gain_db)).abs() < 1e-4);
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn playback_overrunning_the_signal_length_is_reported_failed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut short = crate::calibration::signal::generate_structured_signal(dir.path().join("generated.wav"))
+            .unwrap()
+            .spec;
+        short.markers.truncate(2);
+        short.length_samples = short.sample_at_time_ms(400.0);
+        let structured = render_structured_signal(dir.path().join("short.wav"), short).unwrap();
+        assert_eq!(structured.total_duration_ms(), 400.0);
+        let state = test_builder()
+            .playback(Arc::new(MockPlaybackSink::with_delay(Duration::from_millis(2_000))))
+            .structured(structured)
+            .calibration_limits(CalibrationLimits {
+                playback_grace_ms: 100,
+                ..CalibrationLimits::default()
+            })
+            .build();
+        let app = router(state.clone());
+        let (_, mut events) = state.session.subscribe();
+        let request = json!({"timestamp": 1, "delay_ms": 0, "structured": true});
+        app.clone().oneshot(json_post("/api/calibration/request", request)).await.unwrap();
+        app.oneshot(ready_request(json!({"countdown_ms": 0}))).await.unwrap();
+        let error = loop {
+            if let SessionEvent::PlaybackFinished { error, .. } = events.recv().await.unwrap() {
+                break error;
+            }
+        };
+        assert_eq!(error.as_deref(), Some("playback did not finish within 500ms"));
+    }
+
+    #[tokio::test]
+    async fn signal_wav_is_tagged_with_the_spec_hash() {
+        async fn fetch(app: Router, if_none_match: Option<&str>) -> Response {
+            let mut request = Request::get("/api/calibration/signal.wav");
+            if let Some(tag) = if_none_match {
+                request = request.header("if-none-match", tag);
+            }
+            app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap()
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let structured = crate::calibration::signal::generate_structured_signal(dir.path().join("structured.wav")).unwrap();
+        let hash = structured.spec.content_hash.clone().unwrap();
+        let app = router(test_builder().structured(structured.clone()).build());
+
+        let response = fetch(app.clone(), None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["etag"], format!("\"{hash}\"").as_str());
+        assert_eq!(response.headers()["content-type"], "audio/wav");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, std::fs::read(&structured.path).unwrap());
+
+        let response = fetch(app.clone(), Some(&format!("\"{hash}\""))).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let response = fetch(app, Some("\"stale\"")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let resampled = router(
+            test_builder()
+                .playback(Arc::new(MockPlaybackSink::with_output_rate(44_100)))
+                .structured(structured.clone())
+                .build(),
+        );
+        let response = fetch(resampled, Some(&format!("\"{hash}\""))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()["etag"].to_str().unwrap().to_string();
+        assert_eq!(etag, format!("\"{}\"", resample_spec(&structured.spec, 44_100).content_hash.unwrap()));
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let reader = hound::WavReader::new(std::io::Cursor::new(body)).unwrap();
+        assert_eq!(reader.spec().sample_rate, 44_100);
+        assert_eq!(reader.duration(), resample_spec(&structured.spec, 44_100).length_samples);
+    }
+
+    #[test]
+    fn settings_merge_only_overrides_present_fields() {
+        let base = ShairportConfig {
+            device_name: "Kitchen".into(),
+            output_device: OutputDeviceSpec::hw(1, 0),
+            latency_offset_seconds: -0.12,
+            calibration_gain: Some(0.4),
+            eq: Some(EqSettings {
+                preamp_db: -4.0,
+                high_pass_hz: Some(60),
+            }),
+            self_calibration: None,
+        };
+        assert_eq!(SettingsUpdatePayload::from_config(&base).merge(&base), base);
+        let without_gain = ShairportConfig {
+            calibration_gain: None,
+            ..base.clone()
+        };
+        assert_eq!(SettingsUpdatePayload::from_config(&without_gain).merge(&without_gain), without_gain);
+
+        let update: SettingsUpdatePayload = serde_json::from_value(json!({"latency_offset_seconds": 0.05})).unwrap();
+        assert_eq!(
+            update.merge(&base),
+            ShairportConfig {
+                latency_offset_seconds: 0.05,
+                ..base.clone()
+            }
+        );
+
+        let clear: SettingsUpdatePayload = serde_json::from_value(json!({"eq": null})).unwrap();
+        assert_eq!(clear.eq, Some(None));
+        assert_eq!(clear.merge(&base).eq, None);
+        let roundtripped: SettingsUpdatePayload =
+            serde_json::from_value(serde_json::to_value(SettingsUpdatePayload::from_config(&without_gain)).unwrap())
+                .unwrap();
+        assert_eq!(roundtripped, SettingsUpdatePayload::from_config(&without_gain));
+    }
+
+    #[test]
+    fn duration_hint_defaults_to_rendered_chirp_length() {
+        let chirp = ChirpConfig {
+            duration: 100,
+            repetitions: 3,
+            interval_ms: 250,
+            ..ChirpConfig::default()
+        };
+        let sink = crate::test_util::MockPlaybackSink::new();
+        assert_eq!(sink.duration_hint(&chirp), Duration::from_millis(1_050));
+        let samples = generate_chirp_samples(&chirp, 48_000, 1.0);
+        assert_eq!(samples.len(), 48 * 1_050);
+
+        let config = ConfigStore::new(crate::airplay::generate_config(None, AudioOutput::I2S));
+        let system = SystemPlaybackSink::new(48_000, config, Vec::new(), None);
+        assert_eq!(system.duration_hint(&chirp), Duration::from_millis(1_050));
+    }
+
+    #[test]
+    fn duration_hint_can_be_overridden() {
+        struct PaddedSink;
+        impl PlaybackSink for PaddedSink {
+            fn play(&self, _: &PlaybackRequest) -> Result<()> {
+                Ok(())
+            }
+
+            fn duration_hint(&self, chirp: &ChirpConfig) -> Duration {
+                Duration::from_millis(chirp.estimated_duration_ms() + 200)
+            }
+        }
+        let chirp = ChirpConfig::default();
+        assert_eq!(PaddedSink.duration_hint(&chirp), Duration::from_millis(3_200));
+    }
+
+    #[tokio::test]
+    async fn calibration_gain_setting_is_validated_and_reported() {
+        let settings = Arc::new(MockSettingsManager::new());
+        let app = router(test_builder().settings(settings.clone()).build());
+
+        let response = app
+            .clone()
+            .oneshot(json_post("/api/settings", json!({"calibration_gain": 1.5})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(settings.restart_calls(), 0);
+
+        let response = app
+            .clone()
+            .oneshot(json_post("/api/settings", json!({"calibration_gain": 0.4})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .oneshot(Request::get("/api/settings").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let payload: SettingsResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(payload.calibration_gain, Some(0.4));
+        assert_eq!(settings.current().calibration_gain, Some(0.4));
+    }
+
+    #[tokio::test]
+    async fn self_calibration_schedule_is_validated_persisted_and_cleared() {
+        let settings = Arc::new(MockSettingsManager::new());
+        let app = router(test_builder().settings(settings.clone()).build());
+        let post = |body| app.clone().oneshot(json_post("/api/settings", body));
+
+        let response = post(json!({"self_calibration": {"hour": 25, "minute": 0}})).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(settings.restart_calls(), 0);
+
+        let response = post(json!({"self_calibration": {"hour": 3, "minute": 0, "days": ["mon", "thu"], "utc_offset_minutes": 60}}))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let payload: SettingsResponse = serde_json::from_slice(&body).unwrap();
+        let schedule = SelfCalSchedule {
+            hour: 3,
+            minute: 0,
+            days: vec![Weekday::Mon, Weekday::Thu],
+            utc_offset_minutes: 60,
+        };
+        assert_eq!(payload.self_calibration, Some(schedule.clone()));
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = SettingsFile::new(dir.path().join("settings.json"));
+        file.save(&settings.current()).unwrap();
+        let stored = file.load().unwrap().unwrap();
+        assert_eq!(stored.self_calibration, Some(Some(schedule.clone())));
+        assert_eq!(stored.merge(&settings.current()).self_calibration, Some(schedule.clone()));
+
+        // Other updates leave the schedule alone; `null` stops it.
+        post(json!({"device_name": "Kitchen"})).await.unwrap();
+        assert_eq!(settings.current().self_calibration, Some(schedule));
+        let response = post(json!({"self_calibration": null})).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(settings.current().self_calibration, None);
+    }
+
+    #[tokio::test]
+    async fn eq_settings_are_set_updated_and_cleared() {
+        let writer = crate::test_util::MockWriter::new();
+        let eq_writer = crate::test_util::MockWriter::new();
+        let store = ConfigStore::new(crate::airplay::generate_config(None, AudioOutput::USB));
+        let settings = ShairportSettingsManager::new(
+            writer.clone(),
+            crate::test_util::MockController::new(),
+            store.clone(),
+        )
+        .eq_writer(eq_writer.clone());
+        let app = router(test_builder().settings(Arc::new(settings)).build());
+        let post = |body| app.clone().oneshot(json_post("/api/settings", body));
+
+        let response = post(json!({"eq": {"preamp_db": 3.0}})).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(writer.last_contents(), None);
+
+        let response = post(json!({"eq": {"preamp_db": -6.0, "high_pass_hz": 80}})).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let payload: SettingsResponse = serde_json::from_slice(&body).unwrap();
+        let set = EqSettings {
+            preamp_db: -6.0,
+            high_pass_hz: Some(80),
+        };
+        assert_eq!(payload.eq, Some(set.clone()));
+        assert!(writer.last_contents().unwrap().contains("eq_high_pass_hz = 80;"));
+        assert!(eq_writer.last_contents().unwrap().contains("controls [ 80 ]"));
+
+        // Other updates leave the EQ alone.
+        let response = post(json!({"device_name": "Kitchen"})).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(store.current().eq, Some(set));
+
+        let response = post(json!({"eq": {"preamp_db": -2.0}})).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(writer.last_contents().unwrap().contains("eq_preamp_db = -2.000;"));
+        assert!(!eq_writer.last_contents().unwrap().contains("ladspa"));
+
+        let response = post(json!({"eq": null})).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(store.current().eq, None);
+        let rendered = writer.last_contents().unwrap();
+        assert!(!rendered.contains("eq_") && !rendered.contains(crate::airplay::EQ_PCM_NAME));
+        assert!(eq_writer.last_contents().unwrap().lines().all(|l| l.starts_with('#')));
+    }
+
+    #[tokio::test]
+    async fn eq_cannot_be_enabled_without_a_fragment_writer() {
+        let writer = crate::test_util::MockWriter::new();
+        let store = ConfigStore::new(crate::airplay::generate_config(None, AudioOutput::USB));
+        let settings =
+            ShairportSettingsManager::new(writer.clone(), crate::test_util::MockController::new(), store.clone());
+        let update: SettingsUpdatePayload = serde_json::from_value(json!({"eq": {"preamp_db": -6.0}})).unwrap();
+        assert!(settings.update(update).await.is_err());
+        assert_eq!(writer.last_contents(), None);
+        assert_eq!(store.generation(), 0);
+    }
+
+    #[tokio::test]
+    async fn malformed_output_device_is_rejected_with_422() {
+        let settings = Arc::new(MockSettingsManager::new());
+        let app = router(test_builder().settings(settings.clone()).build());
+        let post = |path: &str, body: serde_json::Value| {
+            Request::post(path)
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(post("/api/settings", json!({"output_device": "hw:1,x"})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(settings.restart_calls(), 0);
+        assert_eq!(settings.current().output_device, OutputDeviceSpec::hw(0, 0));
+
+        let response = app
+            .oneshot_completed(post(
+                "/api/calibration/result",
+                json!({
+                    "timestamp": 1,
+                    "latency_ms": 40.0,
+                    "confidence": 0.9,
+                    "expected_output_device": "hw:0,0\"; injected"
+                }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn calibration_request_triggers_playback() {
+        let playback = Arc::new(MockPlaybackSink::new());
+        let state = test_builder().playback(playback.clone()).build();
+        let app = router(state);
+        let req_body = json!({
+            "timestamp": 1,
+            "chirp_config": {
+                "start_freq": 2000,
+                "end_freq": 8000,
+                "duration": 50,
+                "repetitions": 5,
+                "interval_ms": 500
+            },
+            "delay_ms": 1
+        });
+        let response = app.clone()
+            .oneshot(
+                Request::post("/api/calibration/request")
+                    .header("content-type", "application/json")
+                    .body(Body::from(req_body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(playback.call_count(), 0);
+
+        let response = app.clone()
+            .oneshot(
+                Request::post("/api/calibration/ready")
+                    .header("content-type", "application/json")
+                    .body(Body::from(json!({"timestamp": 5}).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        tokio::time::sleep(Duration::from_millis(1800)).await;
+        assert_eq!(playback.call_count(), 1);
+        let last = playback.last().unwrap();
+        match last {
+            PlaybackRequest::Chirp(cfg) => {
+                assert_eq!(cfg.start_freq, 2000);
+                assert_eq!(cfg.end_freq, 8000);
+            }
+            _ => panic!("expected chirp playback"),
+        }
+    }
+
+    #[tokio::test]
+    async fn calibration_request_failure_logs_and_returns_ok() {
+        let playback = Arc::new(MockPlaybackSink::failing());
+        let state = test_builder().playback(playback.clone()).build();
+        let app = router(state.clone());
+        let req_body = json!({
+            "timestamp": 1,
+            "chirp_config": {
+                "start_freq": 2000,
+                "end_freq": 8000,
+                "duration": 50,
+                "repetitions": 5,
+                "interval_ms": 500
+            },
+            "delay_ms": 1
+        });
+        let response = app.clone()
+            .oneshot(
+                Request::post("/api/calibration/request")
+                    .header("content-type", "application/json")
+                    .body(Body::from(req_body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.clone()
+            .oneshot(
+                Request::post("/api/calibration/ready")
+                    .header("content-type", "application/json")
+                    .body(Body::from(json!({"timestamp": 5, "target_start_ms": now_millis() - 200}).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        tokio::time::sleep(Duration::from_millis(1800)).await;
+        assert_eq!(playback.call_count(), 1);
+        let error = state.status.refresh(now_millis()).error.unwrap();
+        assert!(error.starts_with("calibration playback failed"));
+    }
+
+    fn chirp_request(delay_ms: u64) -> Request<Body> {
+        let req_body = json!({
+            "timestamp": 1,
+            "chirp_config": {
+                "start_freq": 2000,
+                "end_freq": 8000,
+                "duration": 50,
+                "repetitions": 5,
+                "interval_ms": 500
+            },
+            "delay_ms": delay_ms
+        });
+        Request::post("/api/calibration/request")
+            .header("content-type", "application/json")
+            .body(Body::from(req_body.to_string()))
+            .unwrap()
+    }
+
+    fn ready_request(body: serde_json::Value) -> Request<Body> {
+        Request::post("/api/calibration/ready")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    /// Read server-sent events from `body` until `count` have arrived, as `(name, data)`.
+    async fn read_events(
+        body: &mut axum::body::BodyDataStream,
+        buffer: &mut String,
+        count: usize,
+    ) -> Vec<(String, serde_json::Value)> {
+        use futures_util::StreamExt;
+        let mut events = Vec::new();
+        while events.len() < count {
+            if let Some(end) = buffer.find("\n\n") {
+                let block: String = buffer.drain(..end + 2).collect();
+                let field = |name: &str| {
+                    block
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                        .map(str::trim)
+                };
+                if let (Some(name), Some(data)) = (field("event"), field("data")) {
+                    events.push((name.to_string(), serde_json::from_str(data).unwrap()));
+                }
+                continue;
+            }
+            let chunk = tokio::time::timeout(Duration::from_secs(5), body.next())
+                .await
+                .expect("event stream stalled")
+                .expect("event stream ended")
+                .unwrap();
+            buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn calibration_events_stream_session_transitions() {
+        let app = router(
+            test_builder()
+                .calibration(Arc::new(MockCalibrationSink::new()))
+                .playback(Arc::new(MockPlaybackSink::new()))
+                .calibration_limits(CalibrationLimits {
+                    min_lead_ms: 0,
+                    ..CalibrationLimits::default()
+                })
+                .build(),
+        );
+        let response = app
+            .clone()
+            .oneshot(Request::get("/api/calibration/events").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let mut body = response.into_body().into_data_stream();
+        let mut buffer = String::new();
+
+        let snapshot = read_events(&mut body, &mut buffer, 1).await;
+        assert_eq!(snapshot[0].0, "snapshot");
+        assert_eq!(snapshot[0].1["phase"], "idle");
+
+        let response = app.clone().oneshot(chirp_request(1_000)).await.unwrap();
+        let requested: CalibrationRequestResponse =
+            serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        let before_ready = now_millis();
+        let response = app.clone().oneshot(ready_request(json!({"countdown_ms": 20}))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let ready = read_events(&mut body, &mut buffer, 4).await;
+        let result = json!({"timestamp": 1, "latency_ms": 42.0, "confidence": 0.9, "session_id": requested.session_id});
+        let response = app.clone().oneshot_completed(json_post("/api/calibration/result", result.clone())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot_completed(json_post("/api/calibration/result", result)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let results = read_events(&mut body, &mut buffer, 2).await;
+
+        let events: Vec<_> = ready.into_iter().chain(results).collect();
+        let names: Vec<&str> = events.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["requested", "ready", "playback_started", "playback_finished", "result_applied", "result_rejected"]
+        );
+        for (name, data) in &events {
+            assert_eq!(data["event"], name.as_str());
+        }
+        assert_eq!(events[0].1["delay_ms"], 1_000);
+        let scheduled = events[1].1["scheduled_start_ms"].as_u64().unwrap();
+        assert!(scheduled >= before_ready + 20);
+        assert_eq!(events[2].1["scheduled_start_ms"], scheduled);
+        let started = events[2].1["at_ms"].as_u64().unwrap();
+        assert!(started >= scheduled);
+        assert_eq!(events[2].1["slip_ms"], (started - scheduled) as i64);
+        assert!(events[3].1.get("error").is_none());
+        assert_eq!(events[4].1["applied_offset_ms"], 42.0);
+        assert_eq!(events[5].1["reason"], "already_applied");
+    }
+
+    fn progress_state(playback: MockPlaybackSink) -> ReceiverState {
+        let click = |id: &str, start_sample| MarkerSpec {
+            id: String::from(id),
+            kind: MarkerKind::Click,
+            start_sample,
+            duration_samples: 48,
+            fade_samples: 0,
+            amplitude: 0.5,
+            search_window_samples: None,
+        };
+        test_builder()
+            .playback(Arc::new(playback))
+            .structured(StructuredSignal {
+                spec: CalibrationSignalSpec {
+                    sample_rate: 48_000,
+                    sample_format: SampleFormat::I16,
+                    length_samples: 240_000,
+                    markers: vec![click("click_2", 120_000), click("sweep_anchor", 4_800), click("click_1", 48_000)],
+                    signal_id: None,
+                    content_hash: None,
+                    level_db: None,
+                    suggested_decimation: 1,
+                    max_expected_latency_ms: None,
+                },
+                path: PathBuf::from("/tmp/structured.wav"),
+            })
+            .calibration_limits(CalibrationLimits {
+                min_lead_ms: 0,
+                ..CalibrationLimits::default()
+            })
+            .build()
+    }
+
+    async fn start_structured_playback(state: &ReceiverState) {
+        let app = router(state.clone());
+        let request = json!({"timestamp": 1, "delay_ms": 0, "structured": true});
+        let response = app.clone().oneshot(json_post("/api/calibration/request", request)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(ready_request(json!({"countdown_ms": 0}))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    /// Milliseconds of tokio time since the first call, for tests running on paused time.
+    fn tokio_clock() -> u64 {
+        static EPOCH: OnceLock<std::time::Instant> = OnceLock::new();
+        let now = tokio::time::Instant::now().into_std();
+        1_000_000 + now.duration_since(*EPOCH.get_or_init(|| now)).as_millis() as u64
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn structured_playback_reports_markers_as_they_go_out() {
+        let state = ReceiverState {
+            clock: tokio_clock,
+            ..progress_state(MockPlaybackSink::new().reporting_clock(tokio_clock))
+        };
+        let (_, mut events) = state.session.subscribe();
+        let start_ms = tokio_clock();
+        let begun = tokio::time::Instant::now();
+        start_structured_playback(&state).await;
+
+        let mut emitted = Vec::new();
+        while emitted.len() < 3 {
+            if let SessionEvent::MarkerEmitted {
+                at_ms,
+                marker_id,
+                offset_ms,
+            } = events.recv().await.unwrap()
+            {
+                emitted.push((marker_id, offset_ms, at_ms, begun.elapsed()));
+            }
+        }
+        let ids: Vec<&str> = emitted.iter().map(|(id, ..)| id.as_str()).collect();
+        assert_eq!(ids, vec!["sweep_anchor", "click_1", "click_2"]);
+        let offsets: Vec<f64> = emitted.iter().map(|(_, offset, ..)| *offset).collect();
+        assert_eq!(offsets, vec![100.0, 1_000.0, 2_500.0]);
+        // Every clock involved follows paused time, so each marker goes out exactly on time.
+        for (id, offset_ms, at_ms, elapsed) in &emitted {
+            assert_eq!(*at_ms, start_ms + *offset_ms as u64, "{id}");
+            assert_eq!(elapsed.as_millis(), *offset_ms as u128, "{id}");
+        }
+        assert_eq!(state.session.snapshot().phase, crate::session::SessionPhase::Played);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_playback_stops_marker_progress() {
+        let state = progress_state(MockPlaybackSink::failing());
+        let (_, mut events) = state.session.subscribe();
+        start_structured_playback(&state).await;
+        tokio::time::sleep(Duration::from_secs(5)).await;
+
+        let mut names = Vec::new();
+        while let Some(event) = events.try_recv() {
+            names.push(event.name());
+        }
+        assert_eq!(names, vec!["requested", "ready", "playback_started", "playback_finished"]);
+    }
+
+    #[tokio::test]
+    async fn calibration_data_computes_latency_from_detection_times() {
+        let sink = Arc::new(MockCalibrationSink::new());
+        let state = test_builder()
+            .calibration(sink.clone())
+            .playback(Arc::new(MockPlaybackSink::new()))
+            .calibration_limits(CalibrationLimits {
+                min_lead_ms: 0,
+                ..CalibrationLimits::default()
+            })
+            .build();
+        let app = router(state.clone());
+        let data = |recording_start_time: u64, times: Vec<u64>| {
+            json_post(
+                "/api/calibration/data",
+                json!({
+                    "timestamp": 1,
+                    "recording_start_time": recording_start_time,
+                    "chirp_detection_times": times,
+                    "confidence": 0.9
+                }),
+            )
+        };
+        let response = app.clone().oneshot(data(0, vec![100])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        app.clone().oneshot(chirp_request(1_000)).await.unwrap();
+        app.clone().oneshot(ready_request(json!({"countdown_ms": 0}))).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let start = state.last_timing.lock().unwrap().as_ref().unwrap().start_ts;
+
+        // 5 chirps every 550ms heard 150ms late, except the second; recording began 1s early.
+        let recording_start = start - 1_000;
+        let times = [0u64, 2, 3, 4].iter().map(|k| 1_000 + k * 550 + 150).collect();
+        let response = app.oneshot(data(recording_start, times)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let breakdown: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(breakdown["latency_ms"], 150.0);
+        assert_eq!(breakdown["missing_markers"], json!(["chirp_1"]));
+        let markers: Vec<&str> = breakdown["markers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["marker_id"].as_str().unwrap())
+            .collect();
+        assert_eq!(markers, vec!["chirp_0", "chirp_2", "chirp_3", "chirp_4"]);
+        assert_eq!(breakdown["applied"]["measured_latency_ms"], 150.0);
+        let submitted = sink.last().unwrap();
+        assert_eq!(submitted.latency_ms, 150.0);
+        assert_eq!(submitted.detections.len(), 4);
+        assert!((submitted.confidence - 0.9 * 0.8).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn calibration_session_id_follows_the_request_through_ready_data_and_result() {
+        let sink = Arc::new(MockCalibrationSink::new());
+        let state = test_builder()
+            .calibration(sink.clone())
+            .playback(Arc::new(MockPlaybackSink::new()))
+            .calibration_limits(CalibrationLimits {
+                min_lead_ms: 0,
+                ..CalibrationLimits::default()
+            })
+            .build();
+        let app = router(state.clone());
+        let response = app.clone().oneshot(chirp_request(1_000)).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let session = serde_json::from_slice::<CalibrationRequestResponse>(&body).unwrap().session_id;
+        let other = Uuid::new_v4();
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/api/calibration/pending").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let pending: PendingCalibrationResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(pending.session_id, Some(session));
+
+        let response = app
+            .clone()
+            .oneshot(ready_request(json!({"countdown_ms": 0, "session_id": other})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let refused: CalibrationScheduleError = serde_json::from_slice(&body).unwrap();
+        assert_eq!(refused.error, "session_mismatch");
+        assert!(state.pending_playback.lock().unwrap().is_some());
+
+        let response = app
+            .clone()
+            .oneshot(ready_request(json!({"countdown_ms": 0, "session_id": session})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let ready: CalibrationReadyResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(ready.session_id, Some(session));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let start = {
+            let timing = state.last_timing.lock().unwrap();
+            let timing = timing.as_ref().unwrap();
+            assert_eq!(timing.session_id, session);
+            timing.start_ts
+        };
+
+        let data = json_post(
+            "/api/calibration/data",
+            json!({
+                "timestamp": 1,
+                "recording_start_time": start,
+                "chirp_detection_times": [150],
+                "confidence": 0.9,
+                "session_id": other
+            }),
+        );
+        let response = app.clone().oneshot(data).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let result = |timestamp: u64, session_id: Uuid| {
+            json_post(
+                "/api/calibration/result",
+                json!({"timestamp": timestamp, "latency_ms": 40.0, "confidence": 0.9, "session_id": session_id}),
+            )
+        };
+        let response = app.clone().oneshot_completed(result(2, other)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let conflict: CalibrationConflictResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(conflict.error, "session_mismatch");
+        assert!(sink.last().is_none());
+
+        let response = app.oneshot_completed(result(2, session)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(sink.last().unwrap().latency_ms, 40.0);
+    }
+
+    #[tokio::test]
+    async fn result_for_a_superseded_session_names_the_result_in_effect() {
+        let sink = Arc::new(MockCalibrationSink::new());
+        let state = test_builder()
+            .calibration(sink.clone())
+            .playback(Arc::new(MockPlaybackSink::new()))
+            .calibration_limits(CalibrationLimits {
+                min_lead_ms: 0,
+                ..CalibrationLimits::default()
+            })
+            .build();
+        let app = router(state.clone());
+        let play = || async {
+            let response = app.clone().oneshot(chirp_request(1_000)).await.unwrap();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let session = serde_json::from_slice::<CalibrationRequestResponse>(&body).unwrap().session_id;
+            let response = app
+                .clone()
+                .oneshot(ready_request(json!({"countdown_ms": 0, "session_id": session})))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(state.last_timing.lock().unwrap().as_ref().unwrap().session_id, session);
+            session
+        };
+        let result = |timestamp: u64, latency_ms: f32, session_id: Uuid| {
+            json_post(
+                "/api/calibration/result",
+                json!({"timestamp": timestamp, "latency_ms": latency_ms, "confidence": 0.9, "session_id": session_id}),
+            )
+        };
+
+        let first = play().await;
+        let response = app.clone().oneshot_completed(result(1, 40.0, first)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // A second calibration played since, so a late result from the first is refused.
+        let second = play().await;
+        let response = app.clone().oneshot_completed(result(2, 55.0, first)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let conflict: CalibrationConflictResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(conflict.error, "session_mismatch");
+        assert_eq!(conflict.submitted_timestamp, 2);
+        let current = conflict.current.unwrap();
+        assert_eq!((current.timestamp, current.measured_latency_ms), (1, 40.0));
+        assert_eq!(sink.last().unwrap().latency_ms, 40.0);
+
+        let response = app.oneshot_completed(result(2, 55.0, second)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(sink.last().unwrap().latency_ms, 55.0);
+    }
+
+    #[tokio::test]
+    async fn calibration_result_is_checked_against_the_config_active_during_playback() {
+        for bump in [false, true] {
+            let sink = Arc::new(MockCalibrationSink::new());
+            let state = test_builder()
+                .calibration(sink.clone())
+                .playback(Arc::new(MockPlaybackSink::new()))
+                .calibration_limits(CalibrationLimits {
+                    min_lead_ms: 0,
+                    ..CalibrationLimits::default()
+                })
+                .build();
+            let app = router(state.clone());
+            let response = app.clone().oneshot(chirp_request(1_000)).await.unwrap();
+            let requested: CalibrationRequestResponse =
+                serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+            app.clone().oneshot(ready_request(json!({"countdown_ms": 0}))).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let played_under = state.settings.generation();
+            assert_eq!(
+                state.last_timing.lock().unwrap().as_ref().unwrap().config_generation,
+                played_under
+            );
+
+            if bump {
+                let response = app
+                    .clone()
+                    .oneshot(json_post("/api/settings", json!({"device_name": "Kitchen"})))
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+            }
+            let response = app
+                .oneshot_completed(json_post(
+                    "/api/calibration/result",
+                    json!({"timestamp": 1, "latency_ms": 30.0, "confidence": 0.9, "session_id": requested.session_id}),
+                ))
+                .await
+                .unwrap();
+            if !bump {
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(sink.last().unwrap().latency_ms, 30.0);
+                continue;
+            }
+            assert_eq!(response.status(), StatusCode::CONFLICT);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let conflict: CalibrationConflictResponse = serde_json::from_slice(&body).unwrap();
+            assert_eq!(conflict.error, "config_generation_changed");
+            assert_eq!(conflict.config_generation, played_under + 1);
+            assert!(sink.last().is_none());
+            assert_eq!(state.session.snapshot().phase, crate::session::SessionPhase::Rejected);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_calibration_event_stream_sends_heartbeats() {
+        use futures_util::StreamExt;
+        let response = router(test_state())
+            .oneshot(Request::get("/api/calibration/events").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let mut body = response.into_body().into_data_stream();
+        let snapshot = body.next().await.unwrap().unwrap();
+        assert!(snapshot.starts_with(b"event: snapshot"));
+        let started = tokio::time::Instant::now();
+        let heartbeat = body.next().await.unwrap().unwrap();
+        assert_eq!(&heartbeat[..], b": heartbeat\n\n");
+        assert_eq!(started.elapsed(), SSE_HEARTBEAT_INTERVAL);
+    }
+
+    #[tokio::test]
+    async fn responses_are_gzipped_for_clients_that_accept_it() {
+        use std::io::Read;
+
+        let app = router(test_state());
+        let fetch = |accept: Option<&'static str>| {
+            let app = app.clone();
+            async move {
+                let mut request = Request::get("/api/calibration/presets");
+                if let Some(accept) = accept {
+                    request = request.header("accept-encoding", accept);
+                }
+                let response = app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+                let encoding = response.headers().get("content-encoding").map(|v| v.to_str().unwrap().to_string());
+                (encoding, to_bytes(response.into_body(), usize::MAX).await.unwrap())
+            }
+        };
+
+        let (encoding, plain) = fetch(None).await;
+        assert_eq!(encoding, None);
+        let (encoding, gzipped) = fetch(Some("gzip")).await;
+        assert_eq!(encoding.as_deref(), Some("gzip"));
+        assert_ne!(gzipped, plain);
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(&gzipped[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, plain);
+        assert_eq!(fetch(Some("br")).await.0.as_deref(), Some("br"));
+    }
+
+    #[tokio::test]
+    async fn gzipped_request_bodies_are_decompressed_within_the_body_limit() {
+        use std::io::Write;
+
+        let gzip = |body: serde_json::Value| {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(body.to_string().as_bytes()).unwrap();
+            Request::post("/api/calibration/result")
+                .header("content-type", "application/json")
+                .header("content-encoding", "gzip")
+                .body(Body::from(encoder.finish().unwrap()))
+                .unwrap()
+        };
+        let sink = Arc::new(MockCalibrationSink::new());
+        let state = test_builder()
+            .calibration(sink.clone())
+            .body_limits(BodyLimits { default_bytes: 1024 })
+            .build();
+        let session_id = played_session(&state);
+        let app = router(state);
+
+        // Compresses to well under the limit, but not once inflated.
+        let padding = "x".repeat(4096);
+        let response = app
+            .clone()
+            .oneshot_completed(gzip(json!({"timestamp": 1, "latency_ms": 40.0, "confidence": 0.9, "padding": padding})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(sink.last().is_none());
+
+        let response = app
+            .oneshot_completed(gzip(json!({"timestamp": 1, "latency_ms": 40.0, "confidence": 0.9, "session_id": session_id})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(sink.last().unwrap().latency_ms, 40.0);
+    }
+
+    #[tokio::test]
+    async fn chirp_config_tunes_the_standard_preset() {
+        let state = test_state();
+        let app = router(state.clone());
+        let get_config = |app: Router| async move {
+            let response = app
+                .oneshot(Request::get("/api/chirp/config").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<ChirpConfig>(&body).unwrap()
+        };
+        let put_config = |app: Router, body: serde_json::Value| async move {
+            let request = Request::put("/api/chirp/config")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            app.oneshot(request).await.unwrap().status()
+        };
+        assert_eq!(get_config(app.clone()).await, ChirpConfig::default());
+
+        let tuned = ChirpConfig {
+            start_freq: 500,
+            end_freq: 4_000,
+            duration: 80,
+            repetitions: 3,
+            interval_ms: 600,
+            amplitude: Some(0.4),
+        };
+        assert_eq!(put_config(app.clone(), json!(tuned)).await, StatusCode::OK);
+        let too_short = ChirpConfig {
+            duration: 5,
+            ..tuned.clone()
+        };
+        assert_eq!(
+            put_config(app.clone(), json!(too_short)).await,
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(get_config(app.clone()).await, tuned);
+
+        let response = app
+            .oneshot(json_post(
+                "/api/calibration/request",
+                json!({"timestamp": 1, "preset": "standard"}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let pending = state.pending_playback.lock().unwrap().clone().unwrap();
+        let PlaybackRequest::Chirp(played) = pending.request else {
+            panic!("expected a chirp request");
+        };
+        assert_eq!(played, tuned);
+    }
+
+    #[tokio::test]
+    async fn calibration_request_needs_exactly_one_of_preset_and_chirp_config() {
+        let state = test_state();
+        let app = router(state.clone());
+        let request = |body: serde_json::Value| {
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(json_post("/api/calibration/request", body)).await.unwrap();
+                let status = response.status();
+                let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+
+        let (status, body) = request(json!({"timestamp": 1})).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"], "missing_chirp");
+        let (status, body) = request(json!({
+            "timestamp": 1,
+            "preset": "quick",
+            "chirp_config": ChirpConfig::default(),
+        }))
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"], "ambiguous_chirp");
+        assert!(state.pending_playback.lock().unwrap().is_none());
+
+        let (status, _) = request(json!({"timestamp": 1, "preset": "thorough"})).await;
+        assert_eq!(status, StatusCode::OK);
+        let pending = state.pending_playback.lock().unwrap().clone().unwrap();
+        assert!(matches!(pending.request, PlaybackRequest::Chirp(played) if played == ChirpPreset::Thorough.config()));
+
+        let (status, _) = request(json!({"timestamp": 1, "preset": "loud"})).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn presets_are_listed_with_their_duration() {
+        let state = test_state();
+        *state.chirp_config.lock().unwrap() = ChirpConfig {
+            repetitions: 2,
+            ..ChirpConfig::default()
+        };
+        let response = router(state)
+            .oneshot(Request::get("/api/calibration/presets").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let listed: ChirpPresetsResponse = serde_json::from_slice(&body).unwrap();
+
+        let names: Vec<ChirpPreset> = listed.presets.iter().map(|info| info.preset).collect();
+        assert_eq!(names, ChirpPreset::ALL);
+        assert_eq!(listed.presets[0].chirp_config, ChirpPreset::Quick.config());
+        assert_eq!(listed.presets[1].chirp_config.repetitions, 2);
+        assert_eq!(listed.presets[1].expected_duration_ms, 2 * (100 + 400));
+        for info in &listed.presets {
+            assert_eq!(info.expected_duration_ms, info.chirp_config.estimated_duration_ms());
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_reflects_request_until_ready() {
+        async fn pending(app: Router) -> PendingCalibrationResponse {
+            let response = app
+                .oneshot(Request::get("/api/calibration/pending").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice(&body).unwrap()
+        }
+
+        let app = router(test_state());
+        let empty = pending(app.clone()).await;
+        assert!(!empty.has_pending);
+        assert_eq!(empty.chirp_config, None);
+        assert_eq!(empty.requested_at_ms, None);
+        assert_eq!(empty.delay_ms, None);
+
+        let response = app.clone().oneshot(chirp_request(1_000)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let requested = pending(app.clone()).await;
+        assert!(requested.has_pending);
+        assert_eq!(
+            requested.chirp_config,
+            Some(ChirpConfig {
+                start_freq: 2000,
+                end_freq: 8000,
+                duration: 50,
+                repetitions: 5,
+                interval_ms: 500,
+                amplitude: None,
+            })
+        );
+        assert!(requested.requested_at_ms.is_some());
+        assert_eq!(requested.delay_ms, Some(1_000));
+
+        let response = app.clone().oneshot(ready_request(json!({}))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!pending(app).await.has_pending);
+    }
+
+    #[tokio::test]
+    async fn calibration_request_rejects_huge_delay() {
+        let app = router(test_state());
+        let response = app.clone().oneshot(chirp_request(2_000_000)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let err: CalibrationScheduleError = serde_json::from_slice(&body).unwrap();
+        assert_eq!(err.error, "delay_too_long");
+
+        // Nothing was left pending.
+        let response = app.oneshot(ready_request(json!({}))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn calibration_request_honours_configured_max_delay() {
+        let state = test_state().with_calibration_limits(CalibrationLimits {
+            max_delay_ms: 500,
+            ..CalibrationLimits::default()
+        });
+        let app = router(state);
+        let response = app.clone().oneshot(chirp_request(400)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(chirp_request(600)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    /// Records playback into the transport mock's log so ordering can be checked.
+    struct LoggedPlayback(Arc<Mutex<Vec<&'static str>>>);
+
+    impl PlaybackSink for LoggedPlayback {
+        fn play(&self, _request: &PlaybackRequest) -> Result<()> {
+            self.0.lock().unwrap().push("play");
+            Ok(())
+        }
+    }
+
+    fn paused_state(transport: &MockTransportControl, max_pause_ms: u64) -> ReceiverState {
+        let state = test_builder()
+            .playback(Arc::new(LoggedPlayback(transport.log())))
+            .transport_control(Arc::new(transport.clone()))
+            .calibration_limits(CalibrationLimits {
+                min_lead_ms: 10,
+                max_pause_ms,
+                ..CalibrationLimits::default()
+            })
+            .build();
+        state.status.record(StatusEvent::SessionStarted, now_millis());
+        state
+    }
+
+    #[tokio::test]
+    async fn calibration_pauses_airplay_until_result_applied() {
+        let transport = MockTransportControl::new();
+        let app = router(paused_state(&transport, 60_000));
+
+        let response = app.clone().oneshot(chirp_request(1)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(transport.events(), vec!["pause"]);
+        let requested: CalibrationRequestResponse =
+            serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        let response = app.clone().oneshot(ready_request(json!({}))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(transport.events(), vec!["pause", "play"]);
+
+        let response = app
+            .oneshot_completed(json_post(
+                "/api/calibration/result",
+                json!({"timestamp": 1, "latency_ms": 40.0, "confidence": 0.9, "session_id": requested.session_id}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(transport.events(), vec!["pause", "play", "resume"]);
+    }
+
+    #[tokio::test]
+    async fn calibration_leaves_airplay_alone_without_session_or_when_opted_out() {
+        let transport = MockTransportControl::new();
+        let state = paused_state(&transport, 60_000);
+        state.status.record(StatusEvent::SessionEnded, now_millis());
+        let app = router(state);
+        app.clone().oneshot(chirp_request(1)).await.unwrap();
+        assert!(transport.events().is_empty());
+
+        let transport = MockTransportControl::new();
+        let app = router(paused_state(&transport, 60_000));
+        let response = app
+            .oneshot(json_post(
+                "/api/calibration/request",
+                json!({
+                    "timestamp": 1,
+                    "chirp_config": {
+                        "start_freq": 2000,
+                        "end_freq": 8000,
+                        "duration": 50,
+                        "repetitions": 5,
+                        "interval_ms": 500
+                    },
+                    "pause_airplay": false
+                }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(transport.events().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_airplay_pause_is_a_warning() {
+        let transport = MockTransportControl::failing();
+        let app = router(paused_state(&transport, 60_000));
+        let response = app.clone().oneshot(chirp_request(1)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let accepted: CalibrationRequestResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(accepted.warnings.len(), 1);
+        assert!(accepted.warnings[0].starts_with("airplay_pause_failed"));
+
+        app.oneshot(ready_request(json!({}))).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(transport.events(), vec!["play"]);
+    }
+
+    #[tokio::test]
+    async fn airplay_resumes_when_pause_expires() {
+        let transport = MockTransportControl::new();
+        let app = router(paused_state(&transport, 50));
+        app.oneshot(chirp_request(1)).await.unwrap();
+        assert_eq!(transport.events(), vec!["pause"]);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(transport.events(), vec!["pause", "resume"]);
+    }
+
+    /// Remembers when playback started.
+    struct TimedPlayback(Arc<Mutex<Option<std::time::Instant>>>);
+
+    impl PlaybackSink for TimedPlayback {
+        fn play(&self, _request: &PlaybackRequest) -> Result<()> {
+            *self.0.lock().unwrap() = Some(std::time::Instant::now());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn calibration_ready_countdown_overrides_target_start() {
+        for target_offset in [-5_000i64, 10_000] {
+            let started = Arc::new(Mutex::new(None));
+            let app = router(
+                test_builder()
+                    .playback(Arc::new(TimedPlayback(started.clone())))
+                    .calibration_limits(CalibrationLimits {
+                        min_lead_ms: 0,
+                        ..CalibrationLimits::default()
+                    })
+                    .build(),
+            );
+            app.clone().oneshot(chirp_request(1)).await.unwrap();
+            let target = (now_millis() as i64 + target_offset) as u64;
+            let sent = std::time::Instant::now();
+            let response = app
+                .oneshot(ready_request(json!({"countdown_ms": 50, "target_start_ms": target})))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let ready: CalibrationReadyResponse = serde_json::from_slice(&body).unwrap();
+            assert!(!ready.was_adjusted);
+            assert_ne!(ready.requested_start_ms, Some(target));
+
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            let waited = started.lock().unwrap().expect("playback ran").duration_since(sent);
+            let waited_ms = waited.as_millis() as i64;
+            assert!((waited_ms - 50).abs() <= 5, "countdown slept {waited_ms}ms");
+        }
+    }
+
+    #[tokio::test]
+    async fn calibration_ready_reports_adjustment_for_slightly_past_target() {
+        let app = router(test_state());
+        app.clone().oneshot(chirp_request(1)).await.unwrap();
+        let target = now_millis() - 300;
+        let response = app
+            .oneshot(ready_request(json!({"target_start_ms": target})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let ready: CalibrationReadyResponse = serde_json::from_slice(&body).unwrap();
+        assert!(ready.was_adjusted);
+        assert_eq!(ready.requested_start_ms, Some(target));
+        assert!(ready.scheduled_start_ms >= target + 1_500);
+        assert!((300..1_000).contains(&ready.lateness_ms), "{}", ready.lateness_ms);
+    }
+
+    #[tokio::test]
+    async fn calibration_ready_rejects_badly_missed_target_and_keeps_pending() {
+        let app = router(test_state());
+        app.clone().oneshot(chirp_request(1)).await.unwrap();
+        let response = app
+            .clone()
+            .oneshot(ready_request(json!({"target_start_ms": now_millis() - 5_000})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let err: CalibrationScheduleError = serde_json::from_slice(&body).unwrap();
+        assert_eq!(err.error, "target_missed");
+
+        let response = app
+            .oneshot(ready_request(json!({"target_start_ms": now_millis() + 2_000})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn calibration_ready_rejects_target_beyond_horizon() {
+        let app = router(test_state());
+        app.clone().oneshot(chirp_request(1)).await.unwrap();
+        let response = app
+            .oneshot(ready_request(json!({"target_start_ms": now_millis() + 120_000})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let err: CalibrationScheduleError = serde_json::from_slice(&body).unwrap();
+        assert_eq!(err.error, "target_beyond_horizon");
+    }
+
+    #[tokio::test]
+    async fn calibration_ready_reports_schedule_on_time() {
+        let app = router(test_state());
+        app.clone().oneshot(chirp_request(1)).await.unwrap();
+        let target = now_millis() + 2_000;
+        let response = app
+            .oneshot(ready_request(json!({"target_start_ms": target})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let ready: CalibrationReadyResponse = serde_json::from_slice(&body).unwrap();
+        assert!(!ready.was_adjusted);
+        assert_eq!(ready.scheduled_start_ms, target);
+    }
+
+    #[tokio::test]
+    async fn calibration_spec_returns_metadata_when_available() {
+        let spec = CalibrationSignalSpec {
+            sample_rate: 48_000,
+            sample_format: SampleFormat::I16,
+            length_samples: 1000,
+            markers: vec![MarkerSpec {
+                id: "m1".into(),
+                kind: MarkerKind::Click,
+                start_sample: 0,
+                duration_samples: 10,
+                fade_samples: 5,
+                amplitude: 0.5,
+                search_window_samples: None,
+            }],
+            signal_id: Some(42),
+            content_hash: None,
+            level_db: None,
+            suggested_decimation: 1,
+            max_expected_latency_ms: None,
+        };
+        let structured = StructuredSignal {
+            spec: spec.clone(),
+            path: PathBuf::from("/tmp/structured.wav"),
+        };
+        let state = test_builder().structured(structured).build();
+        let app = router(state);
+        let response = app
+            .clone()
+            .oneshot(Request::get("/api/calibration/spec").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(payload["spec"]["sample_rate"], 48_000);
+        assert_eq!(payload["spec"]["signal_id"], 42);
+
+        let response = app
+            .oneshot(
+                Request::post("/api/calibration/request")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        json!({
+                            "timestamp": 1,
+                            "chirp_config": ChirpConfig::default(),
+                            "structured": true
+                        })
+                        .to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let accepted: CalibrationRequestResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(accepted.signal_id, Some(42));
+    }
+
+    #[tokio::test]
+    async fn custom_signal_spec_replaces_the_generated_one() {
+        async fn get_spec(app: Router) -> CalibrationSignalSpec {
+            let response = app
+                .oneshot(Request::get("/api/calibration/signal/spec").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice(&body).unwrap()
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let generated = crate::calibration::signal::generate_structured_signal(dir.path().join("s.wav")).unwrap();
+        let state = test_builder().structured(generated.clone()).build();
+        let app = router(state.clone());
+        assert_eq!(get_spec(app.clone()).await, generated.spec);
+
+        let mut overlapping = generated.spec.clone();
+        overlapping.markers[2].start_sample = overlapping.markers[1].start_sample;
+        let response = app
+            .clone()
+            .oneshot(json_post("/api/calibration/signal/spec", json!(overlapping)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let err: CalibrationScheduleError = serde_json::from_slice(&body).unwrap();
+        assert_eq!(err.error, "invalid_spec");
+        assert_eq!(get_spec(app.clone()).await, generated.spec);
+
+        let custom = CalibrationSignalSpec {
+            sample_rate: 48_000,
+            sample_format: SampleFormat::I16,
+            length_samples: 48_000,
+            markers: vec![MarkerSpec {
+                id: "custom_sweep".into(),
+                kind: MarkerKind::Chirp {
+                    start_freq: 500,
+                    end_freq: 8_000,
+                    duration_ms: 100,
+                },
+                start_sample: 4_800,
+                duration_samples: 4_800,
+                fade_samples: 480,
+                amplitude: 0.6,
+                search_window_samples: None,
+            }],
+            signal_id: None,
+            content_hash: None,
+            level_db: None,
+            suggested_decimation: 1,
+            max_expected_latency_ms: None,
+        };
+        let response = app
+            .clone()
+            .oneshot(json_post("/api/calibration/signal/spec", json!(custom)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let served = get_spec(app.clone()).await;
+        assert!(served.content_hash.is_some());
+        assert_ne!(served.content_hash, generated.spec.content_hash);
+        // The receiver fills in the hash, level and search hints of what it rendered.
+        assert_eq!(served.max_expected_latency_ms, Some(250));
+        assert_eq!(served.suggested_decimation, 2);
+        assert_eq!(served.markers[0].search_window_samples, Some((4_800, 21_600)));
+        let mut markers = served.markers.clone();
+        markers[0].search_window_samples = None;
+        assert_eq!(
+            CalibrationSignalSpec {
+                content_hash: None,
+                level_db: None,
+                suggested_decimation: 1,
+                max_expected_latency_ms: None,
+                markers,
+                ..served
+            },
+            custom
+        );
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/api/calibration/signal/reference/custom_sweep")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .clone()
+            .oneshot(json_post("/api/calibration/request", json!({"timestamp": 1, "structured": true})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let pending = state.pending_playback.lock().unwrap().clone().unwrap();
+        let PlaybackRequest::File(played) = pending.request else {
+            panic!("expected the structured signal");
+        };
+        assert_ne!(played, generated.path);
+        assert_eq!(
+            crate::calibration::signal::validation::validate_wav_length(&played, custom.length_samples)
+                .unwrap()
+                .sample_rate,
+            48_000
+        );
+
+        let response = app
+            .oneshot(json_post("/api/calibration/signal/spec", json!(generated.spec)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn marker_reference_serves_binary_and_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let structured = crate::calibration::signal::generate_structured_signal(dir.path().join("s.wav")).unwrap();
+        let sweep = structured.spec.markers.iter().find(|m| m.id == "sweep_anchor").unwrap().clone();
+        let expected = reference_samples(&sweep, structured.spec.sample_rate);
+        let app = router(test_builder().structured(structured).build());
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/api/calibration/signal/reference/sweep_anchor")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/octet-stream");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let decoded: Vec<f32> = body
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        assert_eq!(decoded, expected);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/api/calibration/signal/reference/sweep_anchor?format=json")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let payload: MarkerReferenceResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(payload.marker_id, "sweep_anchor");
+        assert_eq!(payload.sample_rate, 48_000);
+        assert_eq!(payload.samples, expected);
+
+        let response = app
+            .oneshot(
+                Request::get("/api/calibration/signal/reference/nope")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = router(test_state())
+            .oneshot(
+                Request::get("/api/calibration/signal/reference/sweep_anchor")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn calibration_ready_without_request_fails() {
+        let app = router(test_state());
+        let response = app
+            .oneshot(
+                Request::post("/api/calibration/ready")
+                    .header("content-type", "application/json")
+                    .body(Body::from(json!({}).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn time_sync_returns_server_time() {
+        let app = router(test_state());
+        let response = app
+            .oneshot(Request::get("/api/time").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let payload: super::TimeSyncResponse = serde_json::from_slice(&body).unwrap();
+        assert!(payload.server_time_ms > 0);
+    }
+
+    #[tokio::test]
+    async fn applied_calibration_is_persisted_without_a_new_generation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(crate::startup::SETTINGS_STATE_FILE);
+        let config = ConfigStore::new(crate::airplay::generate_config(None, AudioOutput::I2S))
+            .persist_to(SettingsFile::new(&path));
+        let applier = CalibrationApplier::new(crate::test_util::MockWriter::new(), crate::test_util::MockController::new());
+        let sink = ShairportCalibrationSink::new(applier, config.clone());
+        let submission = CalibrationSubmission {
+            timestamp: 1,
+            latency_ms: 40.0,
+            confidence: 0.9,
+            detections: Vec::new(),
+        };
+        sink.apply(&submission).await.unwrap();
+
+        assert!((config.current().latency_offset_seconds + 0.040).abs() < 1e-6);
+        assert_eq!(config.generation(), 0);
+        // Saved on the blocking pool; wait for it to land.
+        let stored = SettingsFile::new(&path);
+        let mut saved = None;
+        for _ in 0..100 {
+            saved = stored.load().unwrap().and_then(|s| s.latency_offset_seconds);
+            if saved.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!((saved.unwrap() + 0.040).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn replay_reapplies_the_last_calibration_and_its_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ConfigStore::new(crate::airplay::generate_config(Some("Den"), AudioOutput::I2S));
+        let writer = crate::test_util::MockWriter::new();
+        let controller = crate::test_util::MockController::new();
+        let applier = CalibrationApplier::new(writer.clone(), controller.clone());
+        let state = test_builder()
+            .calibration(Arc::new(ShairportCalibrationSink::new(applier, config.clone())))
+            .settings(Arc::new(InMemorySettingsManager::new(config.clone())))
+            .calibration_store(CalibrationStore::open(dir.path()).unwrap())
+            .clock(|| 9_000)
+            .build();
+        let session_id = played_session(&state);
+        let app = router(state);
+
+        let response = app.clone().oneshot(json_post("/api/calibration/replay", json!({}))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app
+            .clone()
+            .oneshot_completed(json_post(
+                "/api/calibration/result",
+                json!({"timestamp": 1, "latency_ms": 30.0, "confidence": 0.8, "session_id": session_id}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // Something since knocked the config off what was calibrated.
+        config
+            .update_with(|current| {
+                Ok(ShairportConfig {
+                    device_name: "Changed".into(),
+                    latency_offset_seconds: 0.0,
+                    ..current.clone()
+                })
+            })
+            .unwrap();
+
+        let response = app.oneshot(json_post("/api/calibration/replay", json!({}))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let replayed: CalibrationApplyResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(replayed.measured_latency_ms, 30.0);
+        assert!((replayed.applied_offset_ms + 30.0).abs() < 1e-3);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use axum::body::Body;
-    use axum::body::to_bytes;
-    use axum::http::Request;
-    use serde_json::json;
-    use tower::ServiceExt;
-    use crate::generate_chirp_samples;
-    use airsync_shared_protocol::{CalibrationSignalSpec, MarkerKind, MarkerSpec};
-    use crate::calibration::signal::StructuredSignal;
-    use std::path::PathBuf;
+        let written = writer.last_contents().unwrap();
+        assert!(written.contains("audio_backend_latency_offset_in_seconds = -0.03"), "{written}");
+        assert!(written.contains("name = \"Den\""), "{written}");
+        assert_eq!(controller.calls(), 2);
+        assert_eq!(config.current().device_name, "Den");
+        assert!((config.current().latency_offset_seconds + 0.030).abs() < 1e-6);
 
-    #[derive(Clone)]
-    struct MockCalibrationSink {
-        last: Arc<Mutex<Option<CalibrationSubmission>>>,
+        let store = CalibrationStore::open(dir.path()).unwrap();
+        let current = store.current().unwrap();
+        assert_eq!((current.timestamp, current.source, current.confidence), (9_000, CalibrationSource::Phone, 0.8));
+        assert_eq!(store.history().len(), 2);
     }
 
-    impl MockCalibrationSink {
-        fn new() -> Self {
-            Self {
-                last: Arc::new(Mutex::new(None)),
-            }
-        }
+    #[tokio::test]
+    async fn slow_config_writes_do_not_stall_other_requests() {
+        // The default test runtime has a single thread, like the receiver's.
+        let config = ConfigStore::new(crate::airplay::generate_config(None, AudioOutput::I2S));
+        let writer = crate::test_util::MockWriter::new().with_delay(Duration::from_millis(300));
+        let applier = CalibrationApplier::new(writer.clone(), crate::test_util::MockController::new());
+        let state = test_builder()
+            .calibration(Arc::new(ShairportCalibrationSink::new(applier, config)))
+            .build();
+        let session_id = played_session(&state);
+        let app = router(state);
 
-        fn last(&self) -> Option<CalibrationSubmission> {
-            self.last.lock().unwrap().clone()
-        }
+        let apply = tokio::spawn(app.clone().oneshot_completed(json_post(
+            "/api/calibration/result",
+            json!({"timestamp": 1, "latency_ms": 30.0, "confidence": 0.9, "session_id": session_id}),
+        )));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let started = std::time::Instant::now();
+        let response = app
+            .oneshot(Request::get("/api/time").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(started.elapsed() < Duration::from_millis(100), "{:?}", started.elapsed());
+        assert!(writer.last_contents().is_none());
+
+        assert_eq!(apply.await.unwrap().unwrap().status(), StatusCode::OK);
+        assert!(writer.last_contents().is_some());
     }
 
-    impl CalibrationSink for MockCalibrationSink {
-        fn apply(&self, submission: &CalibrationSubmission) -> Result<CalibrationApplyResponse> {
-            *self.last.lock().unwrap() = Some(submission.clone());
-            Ok(CalibrationApplyResponse {
-                measured_latency_ms: submission.latency_ms,
-                applied_offset_ms: submission.latency_ms,
-                was_clamped: false,
-            })
-        }
+    #[tokio::test]
+    async fn time_sync_answers_while_a_settings_update_is_in_flight() {
+        let release = Arc::new(tokio::sync::Notify::new());
+        let settings = Arc::new(MockSettingsManager::new().held_until(release.clone()));
+        let app = router(test_builder().settings(settings.clone()).build());
+
+        let update = tokio::spawn(
+            app.clone()
+                .oneshot(json_post("/api/settings", json!({"latency_offset_seconds": -0.02}))),
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // The update can't finish before it is released, so time sync answered around it.
+        let response = app
+            .oneshot(Request::get("/api/time").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!update.is_finished());
+        assert_eq!(settings.restart_calls(), 0);
+
+        release.notify_one();
+        assert_eq!(update.await.unwrap().unwrap().status(), StatusCode::OK);
+        assert_eq!(settings.restart_calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn socket_activation_serves_on_the_inherited_listener() {
+        use std::os::fd::IntoRawFd;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Stands in for the socket systemd would have bound and passed down.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fds = ListenFds {
+            listen_pid: Some(std::process::id().to_string()),
+            listen_fds: Some("1".into()),
+            first_fd: listener.into_raw_fd(),
+        };
+        tokio::spawn(socket_activate_with(router(test_state()), fds));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /api/time HTTP/1.1\r\nhost: receiver\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
     }
 
-    #[derive(Clone)]
-    struct MockPlaybackSink {
-        last: Arc<Mutex<Option<PlaybackRequest>>>,
-        calls: Arc<Mutex<u32>>,
-        fail: bool,
+    #[test]
+    fn socket_activation_rejects_sockets_meant_for_another_process() {
+        let pid = std::process::id();
+        let fds = |listen_pid: Option<&str>, listen_fds: Option<&str>| ListenFds {
+            listen_pid: listen_pid.map(String::from),
+            listen_fds: listen_fds.map(String::from),
+            first_fd: -1,
+        };
+        let own = pid.to_string();
+        let other = (pid + 1).to_string();
+        assert!(fds(None, Some("1")).take_listener(pid).is_err());
+        assert!(fds(Some(&other), Some("1")).take_listener(pid).is_err());
+        assert!(fds(Some(&own), None).take_listener(pid).is_err());
+        assert!(fds(Some(&own), Some("0")).take_listener(pid).is_err());
     }
 
-    impl MockPlaybackSink {
-        fn new() -> Self {
-            Self {
-                last: Arc::new(Mutex::new(None)),
-                calls: Arc::new(Mutex::new(0)),
-                fail: false,
-            }
-        }
+    #[test]
+    fn chirp_samples_have_energy() {
+        let cfg = ChirpConfig {
+            start_freq: 1000,
+            end_freq: 10000,
+            duration: 100,
+            repetitions: 2,
+            interval_ms: 100,
+            amplitude: None,
+        };
+        let samples = generate_chirp_samples(&cfg, 48_000, 1.0);
+        assert!(samples.iter().any(|&s| s != 0));
+        // Ensure spacing for interval
+        let expected_min = (cfg.duration as f32 / 1000.0 * 48000.0) as usize * 2;
+        assert!(samples.len() >= expected_min);
+    }
+
+    #[tokio::test]
+    async fn dual_stack_listener_accepts_v4_and_v6() {
+        let listener = bind_dual_stack(0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            axum::serve(listener, router(test_state())).await.unwrap();
+        });
 
-        fn last(&self) -> Option<PlaybackRequest> {
-            self.last.lock().unwrap().clone()
+        for host in ["127.0.0.1", "[::1]"] {
+            let addr: SocketAddr = format!("{host}:{port}").parse().unwrap();
+            let stream = tokio::net::TcpStream::connect(addr).await;
+            assert!(stream.is_ok(), "expected connection on {addr}");
         }
+    }
 
-    fn call_count(&self) -> u32 {
-        *self.calls.lock().unwrap()
+    #[test]
+    fn load_or_create_receiver_id_persists() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("receiver.json");
+        let first = load_or_create_receiver_id(&path).unwrap();
+        let second = load_or_create_receiver_id(&path).unwrap();
+        assert_eq!(first, second);
     }
-}
 
-impl PlaybackSink for MockPlaybackSink {
-        fn play(&self, request: &PlaybackRequest) -> Result<()> {
-            *self.calls.lock().unwrap() += 1;
-            *self.last.lock().unwrap() = Some(request.clone());
-            if self.fail {
-                return Err(anyhow!("fail"));
-            }
-            Ok(())
+    #[test]
+    fn avahi_service_contains_fields() {
+        let rendered = render_avahi_service("Living Room", "rx-1", 5000, &[Capability::Calibration], None, false).unwrap();
+        assert!(rendered.contains("_airsync._tcp"));
+        assert!(rendered.contains("rx-1"));
+        assert!(rendered.contains("<txt-record>feat=cal</txt-record>"));
+        assert!(rendered.contains("<txt-record>ver=2</txt-record>"));
+        assert!(rendered.contains("<txt-record>proto=2</txt-record>"));
+        assert!(rendered.contains("<port>5000</port>"));
+    }
+
+    fn pi_with(audio_outputs: Vec<AudioOutput>, ram_mb: usize) -> HardwareCapabilities {
+        HardwareCapabilities {
+            cpu_cores: 4,
+            ram_mb,
+            board_id: "raspberrypi4".into(),
+            preferred_output: audio_outputs[0],
+            audio_outputs,
+            temperature_c: None,
+            throttled: None,
+            available_mb: None,
+            os: None,
+            memory_speed_hint: None,
         }
     }
 
-    #[derive(Clone)]
-    struct MockSettingsManager {
-        cfg: Arc<Mutex<ShairportConfig>>,
-        restarts: Arc<Mutex<u32>>,
+    #[test]
+    fn avahi_caps_come_from_detected_hardware() {
+        let rendered =
+            render_avahi_service_from_caps("Living Room", "rx-1", 5000, &pi_with(vec![AudioOutput::I2S], 1024), None, false).unwrap();
+        assert!(rendered.contains("<txt-record>out=i2s</txt-record>"));
+        assert!(rendered.contains("<txt-record>feat=cal</txt-record>"));
     }
 
-    impl MockSettingsManager {
-        fn new() -> Self {
-            Self {
-                cfg: Arc::new(Mutex::new(ShairportConfig {
-                    device_name: "AirSync".into(),
-                    output_device: "hw:0,0".into(),
-                    latency_offset_seconds: 0.0,
-                })),
-                restarts: Arc::new(Mutex::new(0)),
-            }
-        }
+    #[test]
+    fn avahi_caps_include_web_ui_when_profile_enables_it() {
+        let caps = pi_with(vec![AudioOutput::I2S, AudioOutput::Headphone], 4096);
+        assert!(HardwareProfile::select(&caps).features().web_ui);
+        let rendered = render_avahi_service_from_caps("Living Room", "rx-1", 5000, &caps, None, false).unwrap();
+        assert!(rendered.contains("<txt-record>out=i2s,headphone</txt-record>"));
+        assert!(rendered.contains("<txt-record>feat=cal,web</txt-record>"));
+    }
 
-        fn restart_calls(&self) -> u32 {
-            *self.restarts.lock().unwrap()
-        }
+    #[test]
+    fn avahi_txt_records_parse_as_discovered_receiver() {
+        let caps = [Capability::Output(AudioOutput::USB), Capability::Calibration, Capability::Multiroom];
+        let rendered = render_avahi_service("Living Room", "rx-1", 5000, &caps, None, false).unwrap();
+        let txt: Vec<(&str, &str)> = rendered
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("<txt-record>")?.strip_suffix("</txt-record>"))
+            .filter_map(|record| record.split_once('='))
+            .collect();
+        let address = std::net::IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+        let found = airsync_shared_protocol::DiscoveredReceiver::from_txt(
+            "Living Room._airsync._tcp.local.",
+            txt,
+            &[address],
+            5000,
+        )
+        .unwrap();
+        assert_eq!(found.receiver_id, "rx-1");
+        assert_eq!(found.name, "Living Room");
+        assert_eq!(found.address, address);
+        assert_eq!(found.port, 5000);
+        assert_eq!(found.capabilities, vec!["usb", "calibration", "multiroom"]);
+        assert_eq!(found.version, Some(VersionInfo::current().label()));
+        assert_eq!(found.protocol_version, Some(API_PROTOCOL_VERSION));
     }
 
-    impl SettingsManager for MockSettingsManager {
-        fn current(&self) -> ShairportConfig {
-            self.cfg.lock().unwrap().clone()
-        }
+    #[tokio::test]
+    async fn receiver_info_and_advertisement_publish_the_tls_fingerprint() {
+        let endpoint = TlsEndpoint {
+            port: 5443,
+            fingerprint: "0f".repeat(32),
+        };
+        let info = |app: Router| async move {
+            let response = app
+                .oneshot(Request::get("/api/receiver/info").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        let published = info(router(test_builder().tls(endpoint.clone()).build())).await;
+        assert_eq!(published["tls"], json!({"port": 5443, "fingerprint": endpoint.fingerprint}));
+        assert!(info(router(test_state())).await.get("tls").is_none());
 
-        fn update(&self, update: SettingsUpdatePayload) -> Result<ShairportConfig> {
-            let mut cfg = self.cfg.lock().unwrap();
-            if let Some(name) = update.device_name {
-                cfg.device_name = name;
-            }
-            if let Some(out) = update.output_device {
-                cfg.output_device = out;
-            }
-            if let Some(lat) = update.latency_offset_seconds {
-                cfg.latency_offset_seconds = lat;
-            }
-            *self.restarts.lock().unwrap() += 1;
-            Ok(cfg.clone())
+        let rendered = render_avahi_service("Living Room", "rx-1", 5000, &[Capability::Calibration], Some(&endpoint), false).unwrap();
+        assert!(rendered.contains("<txt-record>tls=5443</txt-record>"));
+        assert!(rendered.contains(&format!("<txt-record>tlsfp={}</txt-record>", endpoint.fingerprint)));
+    }
+
+    struct FixedRotator;
+
+    impl CertificateRotator for FixedRotator {
+        fn regenerate(&self) -> BoxFuture<'_, Result<String>> {
+            Box::pin(async { Ok("ab".repeat(32)) })
         }
     }
 
-    fn test_state() -> ReceiverState {
-        ReceiverState::new(
-            ReceiverInfo {
-                receiver_id: "rx-1".into(),
-                name: "Test".into(),
-                capabilities: vec!["calibration".into()],
-            },
-            Arc::new(MockCalibrationSink::new()),
-            Arc::new(MockSettingsManager::new()),
-            Arc::new(MockPlaybackSink::new()),
-            None,
-        )
+    #[tokio::test]
+    async fn admins_can_regenerate_the_tls_certificate() {
+        let endpoint = TlsEndpoint {
+            port: 5443,
+            fingerprint: "0f".repeat(32),
+        };
+        let app = router(
+            test_builder()
+                .tls(endpoint)
+                .certificate_rotator(Arc::new(FixedRotator))
+                .admin_token("admin")
+                .build(),
+        );
+        let regenerate = |token: &str| {
+            Request::post("/admin/tls/regenerate")
+                .header("x-admin-token", token)
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert_eq!(app.clone().oneshot(regenerate("nope")).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+        let response = app.clone().oneshot(regenerate("admin")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let regenerated: TlsEndpoint = serde_json::from_slice(&body).unwrap();
+        assert_eq!((regenerated.port, regenerated.fingerprint.as_str()), (5443, "ab".repeat(32).as_str()));
+        let response = app.oneshot(Request::get("/api/receiver/info").body(Body::empty()).unwrap()).await.unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(info["tls"]["fingerprint"], "ab".repeat(32));
+
+        let plain = router(test_builder().admin_token("admin").build());
+        assert_eq!(plain.oneshot(regenerate("admin")).await.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
     }
 
     #[tokio::test]
-    async fn pairing_start_returns_receiver_info() {
-        let state = test_state();
-        let app = router(state.clone());
+    async fn rotated_admin_token_overlaps_the_old_one_for_the_grace_period() {
+        use crate::admin_token::ADMIN_TOKEN_STATE_FILE;
+        use std::sync::atomic::{AtomicU64, Ordering};
 
-        let req_body = json!({
-            "device_name": "iPhone",
-            "app_version": "1.0",
-            "platform": "ios"
-        });
-        let response = app
-            .clone()
+        static NOW_MS: AtomicU64 = AtomicU64::new(10_000);
+        let dir = tempfile::tempdir().unwrap();
+        let tokens = AdminTokens::open(dir.path(), Some("old".into()))
+            .unwrap()
+            .unwrap()
+            .grace_period(Duration::from_secs(60));
+        let app = router(
+            test_builder()
+                .admin_tokens(tokens)
+                .clock(|| NOW_MS.load(Ordering::SeqCst))
+                .build(),
+        );
+        let admin_get = |token: &str| {
+            Request::get("/admin/config-changes")
+                .header("x-admin-token", token)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let rotate = |token: &str| {
+            Request::post("/admin/rotate-token")
+                .header("x-admin-token", token)
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert_eq!(app.clone().oneshot(rotate("nope")).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+        let response = app.clone().oneshot(rotate("old")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["cache-control"], "no-store");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let rotated: RotatedToken = serde_json::from_slice(&body).unwrap();
+        assert_eq!(rotated.previous_valid_until_ms, 70_000);
+        assert_ne!(rotated.token, "old");
+        assert_eq!(std::fs::read_to_string(dir.path().join(ADMIN_TOKEN_STATE_FILE)).unwrap(), rotated.token);
+
+        // Both work until the grace period is up.
+        NOW_MS.store(69_999, Ordering::SeqCst);
+        assert_eq!(app.clone().oneshot(admin_get("old")).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(app.clone().oneshot(admin_get(&rotated.token)).await.unwrap().status(), StatusCode::OK);
+
+        NOW_MS.store(70_000, Ordering::SeqCst);
+        assert_eq!(app.clone().oneshot(admin_get("old")).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(app.clone().oneshot(admin_get(&rotated.token)).await.unwrap().status(), StatusCode::OK);
+
+        // Rotating again retires the token used for it, and the one before is gone for good.
+        let response = app.clone().oneshot(rotate(&rotated.token)).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let next: RotatedToken = serde_json::from_slice(&body).unwrap();
+        assert_eq!(app.clone().oneshot(admin_get(&rotated.token)).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(app.clone().oneshot(admin_get(&next.token)).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(app.oneshot(admin_get("old")).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn recent_requests_lists_what_was_served_without_credentials() {
+        let app = router(test_builder().access_log(AccessLog::new(3)).admin_token("admin").build());
+        for uri in ["/api/health", "/api/nope", "/api/version?token=s3cret"] {
+            app.clone().oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+        }
+        app.clone()
             .oneshot(
-                Request::post("/api/pairing/start")
+                Request::post("/api/settings")
+                    .header("authorization", "Bearer s3cret")
+                    .header("user-agent", "AirSync/2.1 iOS")
                     .header("content-type", "application/json")
-                    .body(Body::from(req_body.to_string()))
+                    .body(Body::from(r#"{"password":"s3cret"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let response = app
+            .oneshot(
+                Request::get("/admin/recent-requests")
+                    .header("x-admin-token", "admin")
+                    .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(!String::from_utf8_lossy(&body).contains("s3cret"));
+        let recent: RecentRequestsResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(recent.total, 4);
+        assert_eq!((recent.by_status["2xx"], recent.by_status["4xx"]), (3, 1));
+        let seen: Vec<_> = recent.requests.iter().map(|e| (e.method.as_str(), e.path.as_str(), e.status)).collect();
+        assert_eq!(
+            seen,
+            [
+                ("GET", "/api/nope", 404),
+                ("GET", "/api/version?token=[redacted]", 200),
+                ("POST", "/api/settings", 200),
+            ]
+        );
+        let settings = &recent.requests[2];
+        assert_eq!(settings.auth_scheme.as_deref(), Some("Bearer"));
+        assert_eq!(settings.user_agent.as_deref(), Some("AirSync/2.1 iOS"));
+        assert!(settings.request_id.is_some());
+    }
+
+    #[test]
+    fn oversized_advertisement_is_an_error() {
+        let caps: Vec<Capability> = (0..200).map(|i| Capability::Other(format!("feature-{i:03}"))).collect();
+        assert!(matches!(
+            render_avahi_service("Living Room", "rx-1", 5000, &caps, None, false),
+            Err(TxtRecordError::TotalTooLong(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn version_endpoint_matches_the_response_header() {
+        let response = router(test_state())
+            .oneshot(Request::get("/api/version").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
         assert_eq!(response.status(), StatusCode::OK);
+        let header = response.headers()[&crate::version::VERSION_HEADER].to_str().unwrap().to_string();
         let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
-        let start: PairingStartResponse = serde_json::from_slice(&body).unwrap();
-        assert_eq!(start.receiver_id, "rx-1");
-        assert_eq!(start.capabilities, vec!["calibration"]);
-        assert_eq!(start.output_device, "hw:0,0");
+        let info: VersionInfo = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.target.is_empty());
+        assert_eq!(header, info.label());
+
+        let missing = router(test_state())
+            .oneshot(Request::get("/api/nope").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        assert_eq!(missing.headers()[&crate::version::VERSION_HEADER], header.as_str());
     }
 
     #[tokio::test]
-    async fn calibration_result_calls_sink() {
-        let sink = Arc::new(MockCalibrationSink::new());
-        let playback = Arc::new(MockPlaybackSink::new());
-        let settings = Arc::new(MockSettingsManager::new());
-        let state = ReceiverState::new(
-            ReceiverInfo {
-                receiver_id: "rx-1".into(),
-                name: "Test".into(),
-                capabilities: vec!["calibration".into()],
+    async fn hardware_endpoint_reports_thermal_state() {
+        let response = router(test_state())
+            .oneshot(Request::get("/api/hardware").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let detector = HardwareDetector::new(crate::test_util::MockSystemReaders {
+            thermal: crate::hardware::ThermalSources {
+                zone_temp: Some("70500".into()),
+                throttled: Some("throttled=0x4".into()),
             },
-            sink.clone(),
-            settings,
-            playback,
-            None,
-        );
-        let app = router(state);
-        let req_body = json!({
-            "timestamp": 1,
-            "latency_ms": 42.0,
-            "confidence": 0.9
+            df_output: "Filesystem Type 1M-blocks Used Available Use% Mounted on\n/dev/root ext4 14831 9203 4996 65% /\n".into(),
+            mem_info: "MemTotal:        3964928 kB\nCached:           660312 kB".into(),
+            ..Default::default()
         });
+        let app = router(test_builder().hardware(Arc::new(detector)).build());
         let response = app
-            .oneshot(
-                Request::post("/api/calibration/result")
-                    .header("content-type", "application/json")
-                    .body(Body::from(req_body.to_string()))
-                    .unwrap(),
-            )
+            .oneshot(Request::get("/api/hardware").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let caps: HardwareCapabilities = serde_json::from_slice(&body).unwrap();
+        assert_eq!(caps.temperature_c, Some(70.5));
+        assert_eq!(caps.throttled, Some(true));
+        assert_eq!(caps.available_mb, Some(4996));
+        assert_eq!(caps.memory_speed_hint, Some(3_200));
+    }
+
+    #[tokio::test]
+    async fn receiver_info_flags_the_serving_interface() {
+        use crate::test_util::MockNetworkInfo;
+        use axum::extract::connect_info::MockConnectInfo;
+
+        let get_info = |app: Router| async move {
+            let response = app
+                .oneshot(Request::get("/api/receiver/info").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<ReceiverInfoResponse>(&body).unwrap()
+        };
+        let network = MockNetworkInfo::new([
+            ("lo", "127.0.0.1"),
+            ("eth0", "10.0.0.5"),
+            ("wlan0", "192.168.1.20"),
+            ("wlan0", "fe80::1"),
+        ]);
+        let connection = ConnectionInfo {
+            local: "[::ffff:192.168.1.20]:5000".parse().unwrap(),
+            remote: "[::ffff:192.168.1.77]:52000".parse().unwrap(),
+        };
+        let app = router(test_builder().network_info(Arc::new(network)).build()).layer(MockConnectInfo(connection));
+        let info = get_info(app).await;
+        let interfaces = info.network.unwrap();
+        let serving: Vec<&str> = interfaces.iter().filter(|i| i.serving).map(|i| i.name.as_str()).collect();
+        assert_eq!(serving, vec!["wlan0"]);
+        assert!(interfaces.iter().any(|i| i.name == "lo" && i.loopback));
+
+        let app = router(test_builder().network_info(Arc::new(MockNetworkInfo::failing())).build());
+        let info = get_info(app).await;
+        assert_eq!(info.info.receiver_id, "rx-1");
+        assert!(info.network.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn health_reports_watchdog_state() {
+        let get_health = |app: Router| async move {
+            let response = app
+                .oneshot(Request::get("/api/health").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        assert_eq!(get_health(router(test_state())).await, json!({"status": "ok"}));
+
+        let controller = crate::test_util::MockController::new().with_statuses([ServiceState::Failed]);
+        let watchdog = crate::watchdog::ShairportWatchdog::new(
+            Arc::new(controller),
+            crate::watchdog::WatchdogConfig::default(),
+            || 7,
+        );
+        let app = router(test_builder().watchdog(watchdog.handle()).build());
+        watchdog.spawn();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        let health = get_health(app).await;
+        assert_eq!(health["status"], "degraded");
+        assert_eq!(health["watchdog"]["last_state"], "failed");
+        assert_eq!(health["watchdog"]["consecutive_failures"], 1);
+        assert_eq!(health["watchdog"]["restarts"][0]["at_ms"], 7);
+    }
+
+    #[tokio::test]
+    async fn status_endpoint_reports_tracker_state() {
+        let tracker = StatusTracker::new(now_millis());
+        let app = router(test_builder().status_tracker(tracker.clone()).build());
+        let get_status = || async {
+            let response = app
+                .clone()
+                .oneshot(Request::get("/api/status").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<StatusSnapshot>(&body).unwrap()
+        };
+
+        assert_eq!(get_status().await.status, PlaybackStatus::Idle);
+
+        let started_at = now_millis();
+        tracker.record(StatusEvent::SessionStarted, started_at);
+        tracker.record(
+            StatusEvent::Metadata(Metadata {
+                artist: Some("Band".into()),
+                title: Some("Song".into()),
+                album: None,
+            }),
+            started_at,
+        );
+        let status = get_status().await;
+        assert_eq!(status.status, PlaybackStatus::Playing);
+        assert_eq!(status.since_ms, started_at);
+        assert_eq!(status.metadata.unwrap().title.as_deref(), Some("Song"));
+    }
+
+    #[tokio::test]
+    async fn metadata_endpoint_falls_back_to_the_last_track() {
+        let dir = tempfile::tempdir().unwrap();
+        let song = Metadata {
+            artist: Some("Band".into()),
+            title: Some("Song".into()),
+            album: None,
+        };
+        MetadataStore::open(dir.path()).update(song.clone()).unwrap();
+        let tracker = StatusTracker::new(now_millis());
+        let app = router(
+            test_builder()
+                .status_tracker(tracker.clone())
+                .metadata_store(MetadataStore::open(dir.path()))
+                .build(),
+        );
+        let get_metadata = || async {
+            let response = app
+                .clone()
+                .oneshot(Request::get("/api/metadata").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<MetadataResponse>(&body).unwrap()
+        };
+
+        assert_eq!(get_metadata().await, MetadataResponse { metadata: song, live: false });
+
+        let other = Metadata {
+            artist: None,
+            title: Some("Other Song".into()),
+            album: None,
+        };
+        tracker.record(StatusEvent::SessionStarted, now_millis());
+        tracker.record(StatusEvent::Metadata(other.clone()), now_millis());
+        assert_eq!(get_metadata().await, MetadataResponse { metadata: other, live: true });
+
+        let response = router(test_builder().build())
+            .oneshot(Request::get("/api/metadata").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    struct SlowPlaybackSink;
+
+    impl PlaybackSink for SlowPlaybackSink {
+        fn play(&self, _request: &PlaybackRequest) -> Result<()> {
+            std::thread::sleep(Duration::from_millis(200));
+            Ok(())
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn calibration_playback_is_reported_as_calibrating() {
+        let tracker = StatusTracker::new(now_millis());
+        let (_, mut updates) = tracker.subscribe();
+        let app = router(
+            test_builder()
+                .status_tracker(tracker.clone())
+                .playback(Arc::new(SlowPlaybackSink))
+                .build(),
+        );
+
+        app.clone().oneshot(chirp_request(0)).await.unwrap();
+        let response = app
+            .oneshot(ready_request(json!({ "target_start_ms": now_millis() })))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let mut seen = Vec::new();
+        while seen.last() != Some(&PlaybackStatus::Idle) {
+            let update = tokio::time::timeout(Duration::from_secs(5), updates.recv())
+                .await
+                .expect("status transition");
+            let Some(ReceiverEvent::Status(snapshot)) = update else {
+                panic!("unexpected update {update:?}");
+            };
+            seen.push(snapshot.status);
+        }
+        assert_eq!(seen, vec![PlaybackStatus::Calibrating, PlaybackStatus::Idle]);
+    }
+
+    #[tokio::test]
+    async fn peers_endpoint_lists_live_peers_without_self() {
+        let peers = crate::discovery::PeerDirectory::new("rx-1");
+        let app = router(test_builder().peer_directory(peers.clone()).build());
+        let lan = "192.168.1.30".parse().unwrap();
+        for id in ["rx-1", "rx-2"] {
+            let txt = [("id", id), ("name", "Den"), ("caps", "calibration")];
+            let fullname = format!("{id}._airsync._tcp.local.");
+            peers.upsert(PeerRecord::from_txt(&fullname, txt, &[lan], 5000, now_millis()).unwrap());
+        }
+
+        let response = app
+            .oneshot(Request::get("/api/peers").body(Body::empty()).unwrap())
             .await
             .unwrap();
         assert_eq!(response.status(), StatusCode::OK);
-        let recorded = sink.last().unwrap();
-        assert_eq!(recorded.latency_ms, 42.0);
-        assert_eq!(recorded.confidence, 0.9);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let listed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            listed,
+            json!({
+                "peers": [{
+                    "receiver_id": "rx-2",
+                    "name": "Den",
+                    "address": "192.168.1.30",
+                    "port": 5000,
+                    "capabilities": ["calibration"],
+                    "last_seen_ms": listed["peers"][0]["last_seen_ms"],
+                }]
+            })
+        );
     }
 
-    #[tokio::test]
-    async fn settings_update_changes_config_and_tracks_restart() {
-        let settings = Arc::new(MockSettingsManager::new());
-        let state = ReceiverState::new(
-            ReceiverInfo {
-                receiver_id: "rx-1".into(),
-                name: "Test".into(),
-                capabilities: vec!["calibration".into()],
-            },
-            Arc::new(MockCalibrationSink::new()),
-            settings.clone(),
-            Arc::new(MockPlaybackSink::new()),
-            None,
-        );
-        let app = router(state);
+    /// Routes coordinator requests straight into other members' in-process routers.
+    struct RouterTransport {
+        members: std::collections::HashMap<String, Router>,
+    }
 
-        let update = json!({
-            "device_name": "Living Room",
-            "output_device": "hw:1,0",
-            "latency_offset_seconds": 0.05
+    impl RouterTransport {
+        async fn call(&self, receiver_id: &str, method: &str, body: Vec<u8>) -> Result<MemberReply> {
+            let router = self
+                .members
+                .get(receiver_id)
+                .cloned()
+                .ok_or_else(|| anyhow!("unknown member {receiver_id}"))?;
+            let response = router
+                .oneshot(
+                    Request::builder()
+                        .method(method)
+                        .uri("/api/group/assign")
+                        .header("content-type", "application/json")
+                        .body(Body::from(body))?,
+                )
+                .await?;
+            match response.status() {
+                status if status.is_success() => Ok(MemberReply::Accepted),
+                StatusCode::CONFLICT => Ok(MemberReply::Conflict),
+                other => Err(anyhow!("member answered {other}")),
+            }
+        }
+    }
+
+    impl GroupTransport for RouterTransport {
+        fn assign<'a>(
+            &'a self,
+            receiver_id: &'a str,
+            assignment: &'a GroupAssignment,
+        ) -> crate::group::BoxFuture<'a, Result<MemberReply>> {
+            Box::pin(self.call(receiver_id, "POST", serde_json::to_vec(assignment).unwrap()))
+        }
+
+        fn release<'a>(
+            &'a self,
+            receiver_id: &'a str,
+            release: &'a GroupRelease,
+        ) -> crate::group::BoxFuture<'a, Result<MemberReply>> {
+            Box::pin(self.call(receiver_id, "DELETE", serde_json::to_vec(release).unwrap()))
+        }
+    }
+
+    /// A member whose calibration sink renders a real shairport config into a MockWriter.
+    fn group_member(receiver_id: &str) -> (ReceiverStateBuilder, crate::test_util::MockWriter) {
+        let writer = crate::test_util::MockWriter::new();
+        let store = ConfigStore::new(ShairportConfig {
+            device_name: receiver_id.into(),
+            output_device: OutputDeviceSpec::hw(0, 0),
+            latency_offset_seconds: 0.0,
+            calibration_gain: None,
+            eq: None,
+            self_calibration: None,
         });
-        let response = app
-            .oneshot(
-                Request::post("/api/settings")
-                    .header("content-type", "application/json")
-                    .body(Body::from(update.to_string()))
-                    .unwrap(),
-            )
+        let sink = ShairportCalibrationSink::new(
+            CalibrationApplier::new(writer.clone(), crate::test_util::MockController::new()),
+            store.clone(),
+        );
+        let builder = ReceiverState::builder()
+            .receiver_id(receiver_id)
+            .calibration(Arc::new(sink))
+            .settings(Arc::new(InMemorySettingsManager::new(store)));
+        (builder, writer)
+    }
+
+    fn group_request(method: &str, body: serde_json::Value) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri("/api/group")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    fn downstairs() -> serde_json::Value {
+        json!({
+            "group_id": "downstairs",
+            "name": "Downstairs",
+            "members": [
+                { "receiver_id": "rx-a", "relative_offset_ms": 10.0 },
+                { "receiver_id": "rx-b", "relative_offset_ms": 40.0 }
+            ]
+        })
+    }
+
+    #[tokio::test]
+    async fn group_offsets_land_in_each_members_config() {
+        let (member_b, writer_b) = group_member("rx-b");
+        let app_b = router(member_b.build());
+        let transport = RouterTransport {
+            members: [("rx-b".to_string(), app_b)].into_iter().collect(),
+        };
+        let (coordinator, writer_a) = group_member("rx-a");
+        let app_a = router(coordinator.group_transport(Arc::new(transport)).build());
+
+        let response = app_a.clone().oneshot(group_request("POST", downstairs())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(writer_a
+            .last_contents()
+            .unwrap()
+            .contains("audio_backend_latency_offset_in_seconds = 0.010"));
+        assert!(writer_b
+            .last_contents()
+            .unwrap()
+            .contains("audio_backend_latency_offset_in_seconds = 0.040"));
+
+        let response = app_a
+            .clone()
+            .oneshot(Request::get("/api/group").body(Body::empty()).unwrap())
             .await
             .unwrap();
         assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let group: GroupConfig = serde_json::from_slice(&body).unwrap();
+        assert_eq!(group.members.len(), 2);
 
-        let cfg = settings.current();
-        assert_eq!(cfg.device_name, "Living Room");
-        assert_eq!(cfg.output_device, "hw:1,0");
-        assert_eq!(cfg.latency_offset_seconds, 0.05);
-        assert_eq!(settings.restart_calls(), 1);
+        let response = app_a.clone().oneshot(group_request("DELETE", json!({}))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(writer_b
+            .last_contents()
+            .unwrap()
+            .contains("audio_backend_latency_offset_in_seconds = 0.000"));
     }
 
     #[tokio::test]
-    async fn calibration_request_triggers_playback() {
-        let playback = Arc::new(MockPlaybackSink::new());
-        let state = ReceiverState::new(
-            ReceiverInfo {
-                receiver_id: "rx-1".into(),
-                name: "Test".into(),
-                capabilities: vec!["calibration".into()],
-            },
-            Arc::new(MockCalibrationSink::new()),
-            Arc::new(MockSettingsManager::new()),
-            playback.clone(),
-            None,
-        );
-        let app = router(state);
-        let req_body = json!({
-            "timestamp": 1,
-            "chirp_config": {
-                "start_freq": 2000,
-                "end_freq": 8000,
-                "duration": 50,
-                "repetitions": 5,
-                "interval_ms": 500
-            },
-            "delay_ms": 1
-        });
-        let response = app.clone()
+    async fn group_rejects_member_in_another_group() {
+        let (member_b, writer_b) = group_member("rx-b");
+        let app_b = router(member_b.build());
+        let response = app_b
+            .clone()
             .oneshot(
-                Request::post("/api/calibration/request")
+                Request::post("/api/group/assign")
                     .header("content-type", "application/json")
-                    .body(Body::from(req_body.to_string()))
+                    .body(Body::from(
+                        json!({
+                            "group_id": "upstairs",
+                            "coordinator_id": "rx-0",
+                            "relative_offset_ms": 5.0
+                        })
+                        .to_string(),
+                    ))
                     .unwrap(),
             )
             .await
             .unwrap();
         assert_eq!(response.status(), StatusCode::OK);
-        tokio::time::sleep(Duration::from_millis(10)).await;
-        assert_eq!(playback.call_count(), 0);
+        let before_b = writer_b.last_contents();
 
-        let response = app.clone()
-            .oneshot(
-                Request::post("/api/calibration/ready")
-                    .header("content-type", "application/json")
-                    .body(Body::from(json!({"timestamp": 5}).to_string()))
-                    .unwrap(),
-            )
+        let transport = RouterTransport {
+            members: [("rx-b".to_string(), app_b)].into_iter().collect(),
+        };
+        let (coordinator, writer_a) = group_member("rx-a");
+        let app_a = router(coordinator.group_transport(Arc::new(transport)).build());
+        let response = app_a.clone().oneshot(group_request("POST", downstairs())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let error: GroupErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error.error, "member_in_other_group");
+        assert_eq!(error.receiver_ids, vec!["rx-b"]);
+
+        // Nothing was applied anywhere.
+        assert!(writer_a.last_contents().is_none());
+        assert_eq!(writer_b.last_contents(), before_b);
+        let response = app_a
+            .oneshot(Request::get("/api/group").body(Body::empty()).unwrap())
             .await
             .unwrap();
-        assert_eq!(response.status(), StatusCode::OK);
-        tokio::time::sleep(Duration::from_millis(1800)).await;
-        assert_eq!(playback.call_count(), 1);
-        let last = playback.last().unwrap();
-        match last {
-            PlaybackRequest::Chirp(cfg) => {
-                assert_eq!(cfg.start_freq, 2000);
-                assert_eq!(cfg.end_freq, 8000);
-            }
-            _ => panic!("expected chirp playback"),
-        }
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
     }
 
     #[tokio::test]
-    async fn calibration_request_failure_logs_and_returns_ok() {
-        let playback = Arc::new(MockPlaybackSink { last: Arc::new(Mutex::new(None)), calls: Arc::new(Mutex::new(0)), fail: true });
-        let state = ReceiverState::new(
-            ReceiverInfo {
-                receiver_id: "rx-1".into(),
-                name: "Test".into(),
-                capabilities: vec!["calibration".into()],
-            },
-            Arc::new(MockCalibrationSink::new()),
-            Arc::new(MockSettingsManager::new()),
-            playback.clone(),
-            None,
-        );
-        let app = router(state);
-        let req_body = json!({
-            "timestamp": 1,
-            "chirp_config": {
-                "start_freq": 2000,
-                "end_freq": 8000,
-                "duration": 50,
-                "repetitions": 5,
-                "interval_ms": 500
+    async fn group_must_be_created_on_its_coordinator() {
+        let app = router(test_builder().receiver_id("rx-b").build());
+        let response = app.oneshot(group_request("POST", downstairs())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::MISDIRECTED_REQUEST);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let error: GroupErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error.error, "not_coordinator");
+        assert_eq!(error.receiver_ids, vec!["rx-a"]);
+    }
+
+    #[tokio::test]
+    async fn invalid_group_is_unprocessable() {
+        let app = router(test_builder().receiver_id("rx-a").build());
+        let mut group = downstairs();
+        group["members"][1]["relative_offset_ms"] = json!(900.0);
+        let response = app.oneshot(group_request("POST", group)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    fn skewed_clock() -> u64 {
+        now_millis() + 250
+    }
+
+    /// Serve `router` on an ephemeral port, delaying each request and response by `delay`.
+    async fn serve_with_delay(router: Router, delay: Duration) -> SocketAddr {
+        let app = router.layer(axum::middleware::from_fn(
+            move |req: axum::extract::Request, next: axum::middleware::Next| async move {
+                tokio::time::sleep(delay).await;
+                let response = next.run(req).await;
+                tokio::time::sleep(delay).await;
+                response
             },
-            "delay_ms": 1
-        });
-        let response = app.clone()
-            .oneshot(
-                Request::post("/api/calibration/request")
-                    .header("content-type", "application/json")
-                    .body(Body::from(req_body.to_string()))
-                    .unwrap(),
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    fn directory_with(addr: SocketAddr) -> PeerDirectory {
+        let peers = PeerDirectory::new("rx-a");
+        peers.upsert(
+            PeerRecord::from_txt(
+                "rx-b._airsync._tcp.local.",
+                [("id", "rx-b")],
+                &[addr.ip()],
+                addr.port(),
+                now_millis(),
             )
-            .await
-            .unwrap();
-        assert_eq!(response.status(), StatusCode::OK);
-        let response = app.clone()
+            .unwrap(),
+        );
+        peers
+    }
+
+    async fn measure(app: &Router, query: &str) -> (StatusCode, Option<PeerTimeSync>) {
+        let response = app
+            .clone()
             .oneshot(
-                Request::post("/api/calibration/ready")
-                    .header("content-type", "application/json")
-                    .body(Body::from(json!({"timestamp": 5, "target_start_ms": 25}).to_string()))
+                Request::get(format!("/api/peers/rx-b/timesync{query}"))
+                    .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
-        assert_eq!(response.status(), StatusCode::OK);
-        tokio::time::sleep(Duration::from_millis(1800)).await;
-        assert_eq!(playback.call_count(), 1);
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).ok())
     }
 
     #[tokio::test]
-    async fn calibration_spec_returns_metadata_when_available() {
-        let spec = CalibrationSignalSpec {
-            sample_rate: 48_000,
-            length_samples: 1000,
-            markers: vec![MarkerSpec {
-                id: "m1".into(),
-                kind: MarkerKind::Click,
-                start_sample: 0,
-                duration_samples: 10,
-            }],
-        };
-        let structured = StructuredSignal {
-            spec: spec.clone(),
-            path: PathBuf::from("/tmp/structured.wav"),
-        };
-        let state = ReceiverState::new(
-            ReceiverInfo {
-                receiver_id: "rx-1".into(),
-                name: "Test".into(),
-                capabilities: vec!["calibration".into()],
-            },
-            Arc::new(MockCalibrationSink::new()),
-            Arc::new(MockSettingsManager::new()),
-            Arc::new(MockPlaybackSink::new()),
-            Some(structured),
-        );
-        let app = router(state);
+    async fn time_sync_echoes_client_send_time() {
+        let app = router(test_state());
         let response = app
-            .oneshot(Request::get("/api/calibration/spec").body(Body::empty()).unwrap())
+            .oneshot(Request::get("/api/time?t0=1234").body(Body::empty()).unwrap())
             .await
             .unwrap();
-        assert_eq!(response.status(), StatusCode::OK);
         let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
-        let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
-        assert_eq!(payload["spec"]["sample_rate"], 48_000);
+        let payload: TimeSyncResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(payload.client_send_ms, Some(1234));
+        let (received, sent) = (payload.server_receive_ms.unwrap(), payload.server_send_ms.unwrap());
+        assert!(received <= sent);
     }
 
     #[tokio::test]
-    async fn calibration_ready_without_request_fails() {
+    async fn peer_timesync_is_near_zero_with_symmetric_delay() {
+        let addr = serve_with_delay(router(test_builder().receiver_id("rx-b").build()), Duration::from_millis(20)).await;
+        let app = router(test_builder().receiver_id("rx-a").peer_directory(directory_with(addr)).build());
+
+        let (status, sync) = measure(&app, "").await;
+        assert_eq!(status, StatusCode::OK);
+        let sync = sync.unwrap();
+        assert!(sync.offset_ms.abs() <= 5.0, "offset {}", sync.offset_ms);
+        assert!(sync.rtt_ms >= 40.0, "rtt {}", sync.rtt_ms);
+
+        // Served from cache until refreshed.
+        let (_, cached) = measure(&app, "").await;
+        assert_eq!(cached.unwrap().sampled_at, sync.sampled_at);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let (_, refreshed) = measure(&app, "?refresh=true").await;
+        assert!(refreshed.unwrap().sampled_at > sync.sampled_at);
+    }
+
+    #[tokio::test]
+    async fn peer_timesync_detects_fixed_skew() {
+        let peer = test_builder().receiver_id("rx-b").clock(skewed_clock).build();
+        let addr = serve_with_delay(router(peer), Duration::from_millis(5)).await;
+        let app = router(test_builder().receiver_id("rx-a").peer_directory(directory_with(addr)).build());
+
+        let (status, sync) = measure(&app, "").await;
+        assert_eq!(status, StatusCode::OK);
+        let offset = sync.unwrap().offset_ms;
+        assert!((offset - 250.0).abs() <= 5.0, "offset {offset}");
+    }
+
+    #[tokio::test]
+    async fn peer_timesync_unknown_peer_is_not_found() {
+        let (status, _) = measure(&router(test_state()), "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn every_response_carries_a_request_id() {
         let app = router(test_state());
+        let requests = [
+            Request::get("/api/receiver/info").body(Body::empty()).unwrap(),
+            Request::get("/api/calibration/spec").body(Body::empty()).unwrap(),
+            Request::get("/api/does-not-exist").body(Body::empty()).unwrap(),
+            Request::post("/api/settings")
+                .header("content-type", "application/json")
+                .body(Body::from(json!({"output_device": ""}).to_string()))
+                .unwrap(),
+        ];
+        for request in requests {
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert!(
+                response.headers().contains_key("x-request-id"),
+                "missing request id on {} response",
+                response.status()
+            );
+        }
+
         let response = app
             .oneshot(
-                Request::post("/api/calibration/ready")
-                    .header("content-type", "application/json")
-                    .body(Body::from(json!({}).to_string()))
+                Request::get("/api/status")
+                    .header("x-request-id", "client-7f3a")
+                    .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers()["x-request-id"], "client-7f3a");
     }
 
     #[tokio::test]
-    async fn time_sync_returns_server_time() {
-        let app = router(test_state());
+    async fn applies_record_their_config_diff_with_secrets_masked() {
+        use crate::airplay::ConfigTemplate;
+        use crate::config_changes::DiffOp;
+
+        const TEMPLATE: &str = "general = {\n    name = \"{{name}}\";\n    password = \"hunter2\";\n    audio_backend_latency_offset_in_seconds = {{latency_offset}};\n};\n\nalsa = {\n    output_device = \"{{output_device}}\";\n    mixer_control_name = \"{{mixer_control}}\";\n};\n";
+        let template = || ConfigTemplate::parse(TEMPLATE).unwrap();
+        let writer = crate::test_util::MockWriter::new();
+        let controller = crate::test_util::MockController::new();
+        let store = ConfigStore::new(crate::airplay::generate_config(None, AudioOutput::I2S));
+        let log = ConfigChangeLog::default();
+        let sink = ShairportCalibrationSink::new(
+            CalibrationApplier::new(writer.clone(), controller.clone()).renderer(template()).change_log(log.clone()),
+            store.clone(),
+        );
+        let settings = ShairportSettingsManager::new(writer.clone(), controller.clone(), store.clone())
+            .renderer(template())
+            .change_log(log.clone());
+        let state = test_builder()
+            .calibration(Arc::new(sink))
+            .settings(Arc::new(settings))
+            .config_change_log(log.clone())
+            .admin_token("admin")
+            .build();
+        let app = router(state.clone());
+        let body = |response: Response| async move {
+            serde_json::from_slice::<serde_json::Value>(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+        };
+
+        // Nothing written yet, so the first apply adds every line.
+        let response = app.clone().oneshot(json_post("/api/settings", json!({"device_name": "Kitchen"}))).await.unwrap();
+        assert_eq!(body(response).await["changed_keys"].as_array().unwrap().len(), 5);
+        let session_id = played_session(&state);
         let response = app
-            .oneshot(Request::get("/api/time").body(Body::empty()).unwrap())
+            .clone()
+            .oneshot_completed(json_post(
+                "/api/calibration/result",
+                json!({"timestamp": 1, "latency_ms": 40.0, "confidence": 0.9, "session_id": session_id}),
+            ))
             .await
             .unwrap();
-        assert_eq!(response.status(), StatusCode::OK);
-        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
-        let payload: super::TimeSyncResponse = serde_json::from_slice(&body).unwrap();
-        assert!(payload.server_time_ms > 0);
+        assert_eq!(body(response).await["changed_keys"], json!(["general.audio_backend_latency_offset_in_seconds"]));
+        let response = app
+            .clone()
+            .oneshot(json_post("/api/settings", json!({"latency_offset_seconds": -0.025})))
+            .await
+            .unwrap();
+        assert_eq!(body(response).await["changed_keys"], json!(["general.audio_backend_latency_offset_in_seconds"]));
+
+        let changes = log.snapshot();
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[1].source, crate::config_changes::ConfigChangeSource::Calibration);
+        assert_eq!(changes[2].source, crate::config_changes::ConfigChangeSource::Settings);
+        let lines: Vec<(DiffOp, &str)> = changes[2].diff.lines.iter().map(|line| (line.op, line.text.as_str())).collect();
+        assert_eq!(
+            lines,
+            [
+                (DiffOp::Removed, "    audio_backend_latency_offset_in_seconds = -0.040;"),
+                (DiffOp::Added, "    audio_backend_latency_offset_in_seconds = -0.025;"),
+            ]
+        );
+
+        let changes = || Request::get("/admin/config-changes");
+        let response = app.clone().oneshot(changes().body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.oneshot(changes().header("x-admin-token", "admin").body(Body::empty()).unwrap()).await.unwrap();
+        let served = body(response).await;
+        assert_eq!(served.as_array().unwrap().len(), 3);
+        assert!(served[0].to_string().contains(r#"password = \"[redacted]\";"#), "{served}");
+        assert!(!served.to_string().contains("hunter2"));
     }
 
-    #[test]
-    fn chirp_samples_have_energy() {
-        let cfg = ChirpConfig {
-            start_freq: 1000,
-            end_freq: 10000,
-            duration: 100,
-            repetitions: 2,
-            interval_ms: 100,
-            amplitude: None,
-        };
-        let samples = generate_chirp_samples(&cfg, 48_000, 1.0);
-        assert!(samples.iter().any(|&s| s != 0));
-        // Ensure spacing for interval
-        let expected_min = (cfg.duration as f32 / 1000.0 * 48000.0) as usize * 2;
-        assert!(samples.len() >= expected_min);
+    /// An updater whose script records its arguments in `installed` next to it.
+    fn firmware_updater(dir: &Path, fetcher: crate::test_util::MockFirmwareFetcher) -> FirmwareUpdater {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script = dir.join("update.sh");
+        std::fs::write(&script, format!("#!/bin/sh\necho \"$2\" > {}/installed\n", dir.display())).unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        FirmwareUpdater::new(fetcher, script)
     }
 
-    #[test]
-    fn load_or_create_receiver_id_persists() {
+    fn firmware_post(token: Option<&str>, body: serde_json::Value) -> Request<Body> {
+        let mut request = json_post("/api/firmware/update", body);
+        if let Some(token) = token {
+            request.headers_mut().insert("x-admin-token", token.parse().unwrap());
+        }
+        request
+    }
+
+    async fn firmware_job(app: &Router, request: Request<Body>) -> JobStatus {
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let accepted: FirmwareUpdateAccepted =
+            serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(accepted.status, "accepted");
+        assert_eq!(accepted.status_url, format!("/api/jobs/{}", accepted.update_id));
+        job_leaving(app, &accepted.status_url, crate::jobs::JobState::Pending).await;
+        job_leaving(app, &accepted.status_url, crate::jobs::JobState::Running).await
+    }
+
+    fn sha256_hex(bytes: &[u8]) -> String {
+        use sha2::{Digest, Sha256};
+        Sha256::digest(bytes).iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[tokio::test]
+    async fn firmware_update_installs_a_verified_image() {
         let dir = tempfile::tempdir().unwrap();
-        let path = dir.path().join("receiver.json");
-        let first = load_or_create_receiver_id(&path).unwrap();
-        let second = load_or_create_receiver_id(&path).unwrap();
-        assert_eq!(first, second);
+        let fetcher = crate::test_util::MockFirmwareFetcher::new(b"firmware 1.2.3".to_vec());
+        let app = router(test_builder().admin_token("s3cret").firmware_updater(firmware_updater(dir.path(), fetcher.clone())).build());
+        let url = "https://updates.example/airsync-1.2.3.bin";
+        let body = json!({"url": url, "sha256": sha256_hex(b"firmware 1.2.3").to_uppercase(), "version": "1.2.3"});
+
+        let job = firmware_job(&app, firmware_post(Some("s3cret"), body)).await;
+        assert_eq!(job.kind, "firmware_update");
+        assert_eq!(job.state, crate::jobs::JobState::Succeeded, "{:?}", job.error);
+        assert_eq!(job.result.unwrap()["sha256"], sha256_hex(b"firmware 1.2.3"));
+        assert_eq!(fetcher.fetched(), [url]);
+        assert_eq!(std::fs::read_to_string(dir.path().join("installed")).unwrap(), "1.2.3\n");
     }
 
-    #[test]
-    fn avahi_service_contains_fields() {
-        let rendered = render_avahi_service("Living Room", "rx-1", 5000, &["calibration"]);
-        assert!(rendered.contains("_airsync._tcp"));
-        assert!(rendered.contains("rx-1"));
-        assert!(rendered.contains("caps=calibration"));
-        assert!(rendered.contains("<port>5000</port>"));
+    #[tokio::test]
+    async fn firmware_hash_mismatch_is_422_without_running_the_script() {
+        let dir = tempfile::tempdir().unwrap();
+        let fetcher = crate::test_util::MockFirmwareFetcher::new(b"tampered".to_vec());
+        let app = router(test_builder().admin_token("s3cret").firmware_updater(firmware_updater(dir.path(), fetcher.clone())).build());
+        let expected = sha256_hex(b"firmware 1.2.3");
+        let body = json!({"url": "https://updates.example/airsync-1.2.3.bin", "sha256": expected, "version": "1.2.3"});
+
+        let response = app.clone().oneshot_completed(firmware_post(Some("s3cret"), body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body, json!({"error": "sha256_mismatch", "expected": expected, "actual": sha256_hex(b"tampered")}));
+        assert_eq!(fetcher.fetched().len(), 1);
+        assert!(!dir.path().join("installed").exists());
+    }
+
+    #[tokio::test]
+    async fn firmware_update_needs_the_admin_token_and_a_valid_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let fetcher = crate::test_util::MockFirmwareFetcher::new(b"firmware".to_vec());
+        let body = json!({"url": "https://updates.example/a.bin", "sha256": sha256_hex(b"firmware"), "version": "1.2.3"});
+        let status = |app: Router, request: Request<Body>| async move { app.oneshot(request).await.unwrap().status() };
+
+        let app = router(test_builder().admin_token("s3cret").firmware_updater(firmware_updater(dir.path(), fetcher.clone())).build());
+        assert_eq!(status(app.clone(), firmware_post(None, body.clone())).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(app.clone(), firmware_post(Some("s3cre"), body.clone())).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(app.clone(), firmware_post(Some("S3CRET"), body.clone())).await, StatusCode::UNAUTHORIZED);
+        let insecure = json!({"url": "http://updates.example/a.bin", "sha256": sha256_hex(b"firmware"), "version": "1.2.3"});
+        let response = app.clone().oneshot(firmware_post(Some("s3cret"), insecure)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let error: serde_json::Value = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(error, json!({"error": "invalid_url"}));
+        assert!(fetcher.fetched().is_empty());
+
+        let unconfigured = router(test_builder().firmware_updater(firmware_updater(dir.path(), fetcher.clone())).build());
+        assert_eq!(status(unconfigured, firmware_post(Some(""), body.clone())).await, StatusCode::FORBIDDEN);
+        let disabled = router(test_builder().admin_token("s3cret").build());
+        assert_eq!(status(disabled, firmware_post(Some("s3cret"), body)).await, StatusCode::SERVICE_UNAVAILABLE);
     }
 }
@@ -0,0 +1,295 @@
+//! Work that takes too long to hold a request open for, such as applying a calibration
+//! (a config write and a shairport-sync restart). Jobs run one at a time, in the order
+//! they were submitted, and stay pollable at `/api/jobs/{id}` for a while after they
+//! finish.
+
+use crate::group::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// How long a finished job can still be polled.
+pub const JOB_RETENTION_MS: u64 = 10 * 60 * 1000;
+/// Finished jobs kept at most, however recent.
+pub const MAX_RETAINED_JOBS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// Why a job failed: the status and body the request would have been answered with had
+/// it run synchronously.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobError {
+    pub status: u16,
+    pub body: serde_json::Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub id: Uuid,
+    /// What the job does, e.g. `calibration_result`.
+    pub kind: String,
+    pub state: JobState,
+    pub submitted_at_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at_ms: Option<u64>,
+    /// The response body of a succeeded job.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<JobError>,
+}
+
+/// Body returned with 202 when a job is queued.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobAccepted {
+    pub job_id: Uuid,
+    pub status_url: String,
+}
+
+impl JobAccepted {
+    pub fn new(job_id: Uuid) -> Self {
+        Self {
+            job_id,
+            status_url: format!("/api/jobs/{job_id}"),
+        }
+    }
+}
+
+pub type JobFuture = BoxFuture<'static, Result<serde_json::Value, JobError>>;
+
+/// Single-worker queue of jobs and their outcomes.
+#[derive(Clone)]
+pub struct JobQueue {
+    jobs: Arc<Mutex<VecDeque<JobStatus>>>,
+    /// Started on the first submission, so a queue can be created outside a runtime.
+    worker: Arc<OnceLock<mpsc::UnboundedSender<(Uuid, JobFuture)>>>,
+    clock: fn() -> u64,
+}
+
+impl JobQueue {
+    pub fn new(clock: fn() -> u64) -> Self {
+        Self {
+            jobs: Arc::new(Mutex::new(VecDeque::new())),
+            worker: Arc::new(OnceLock::new()),
+            clock,
+        }
+    }
+
+    /// Queue `job` behind those already queued and return its id.
+    pub fn submit(&self, kind: &str, job: JobFuture) -> Uuid {
+        let id = self.record(kind);
+        self.enqueue(id, job);
+        id
+    }
+
+    /// Run `prepare` on its own task and queue the job it returns, so slow groundwork such
+    /// as a download doesn't hold up the jobs behind it. The job is pending until then,
+    /// and fails without being queued if `prepare` does.
+    pub fn submit_prepared(&self, kind: &str, prepare: BoxFuture<'static, Result<JobFuture, JobError>>) -> Uuid {
+        let id = self.record(kind);
+        let queue = self.clone();
+        tokio::spawn(async move {
+            match prepare.await {
+                Ok(job) => queue.enqueue(id, job),
+                Err(error) => queue.finish(id, Err(error)),
+            }
+        });
+        id
+    }
+
+    fn record(&self, kind: &str) -> Uuid {
+        let now = (self.clock)();
+        let id = Uuid::new_v4();
+        let mut jobs = self.jobs.lock().unwrap();
+        prune(&mut jobs, now);
+        jobs.push_back(JobStatus {
+            id,
+            kind: kind.to_string(),
+            state: JobState::Pending,
+            submitted_at_ms: now,
+            finished_at_ms: None,
+            result: None,
+            error: None,
+        });
+        id
+    }
+
+    fn enqueue(&self, id: Uuid, job: JobFuture) {
+        let worker = self.worker.get_or_init(|| self.spawn_worker());
+        if worker.send((id, job)).is_err() {
+            self.finish(
+                id,
+                Err(JobError {
+                    status: 503,
+                    body: serde_json::json!({"error": "job_queue_stopped"}),
+                }),
+            );
+        }
+    }
+
+    pub fn status(&self, id: Uuid) -> Option<JobStatus> {
+        let mut jobs = self.jobs.lock().unwrap();
+        prune(&mut jobs, (self.clock)());
+        jobs.iter().find(|job| job.id == id).cloned()
+    }
+
+    fn spawn_worker(&self) -> mpsc::UnboundedSender<(Uuid, JobFuture)> {
+        let (sender, mut queued) = mpsc::unbounded_channel::<(Uuid, JobFuture)>();
+        let queue = self.clone();
+        tokio::spawn(async move {
+            while let Some((id, job)) = queued.recv().await {
+                queue.update(id, |status| status.state = JobState::Running);
+                let outcome = job.await;
+                queue.finish(id, outcome);
+            }
+        });
+        sender
+    }
+
+    fn finish(&self, id: Uuid, outcome: Result<serde_json::Value, JobError>) {
+        let now = (self.clock)();
+        self.update(id, |status| {
+            status.finished_at_ms = Some(now);
+            match outcome {
+                Ok(result) => {
+                    status.state = JobState::Succeeded;
+                    status.result = Some(result);
+                }
+                Err(error) => {
+                    status.state = JobState::Failed;
+                    status.error = Some(error);
+                }
+            }
+        });
+    }
+
+    fn update(&self, id: Uuid, change: impl FnOnce(&mut JobStatus)) {
+        if let Some(status) = self.jobs.lock().unwrap().iter_mut().find(|job| job.id == id) {
+            change(status);
+        }
+    }
+}
+
+/// Forget finished jobs past their retention, and the oldest beyond [`MAX_RETAINED_JOBS`].
+fn prune(jobs: &mut VecDeque<JobStatus>, now_ms: u64) {
+    jobs.retain(|job| job.finished_at_ms.is_none_or(|at| now_ms.saturating_sub(at) < JOB_RETENTION_MS));
+    let mut excess = jobs.iter().filter(|job| job.finished_at_ms.is_some()).count().saturating_sub(MAX_RETAINED_JOBS);
+    jobs.retain(|job| {
+        if excess > 0 && job.finished_at_ms.is_some() {
+            excess -= 1;
+            return false;
+        }
+        true
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn clock() -> u64 {
+        0
+    }
+
+    async fn settled(queue: &JobQueue, id: Uuid) -> JobStatus {
+        loop {
+            let status = queue.status(id).unwrap();
+            if matches!(status.state, JobState::Succeeded | JobState::Failed) {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn jobs_run_one_at_a_time_in_order() {
+        let queue = JobQueue::new(clock);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let job = |n: u64| -> JobFuture {
+            let order = order.clone();
+            Box::pin(async move {
+                order.lock().unwrap().push(format!("start {n}"));
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                order.lock().unwrap().push(format!("end {n}"));
+                if n == 2 {
+                    return Err(JobError {
+                        status: 409,
+                        body: serde_json::json!({"error": "stale_result"}),
+                    });
+                }
+                Ok(serde_json::json!(n))
+            })
+        };
+        let first = queue.submit("test", job(1));
+        let second = queue.submit("test", job(2));
+        assert_eq!(queue.status(second).unwrap().state, JobState::Pending);
+
+        assert_eq!(settled(&queue, first).await.result, Some(serde_json::json!(1)));
+        let failed = settled(&queue, second).await;
+        assert_eq!(failed.state, JobState::Failed);
+        assert_eq!(failed.error.unwrap().status, 409);
+        assert_eq!(*order.lock().unwrap(), ["start 1", "end 1", "start 2", "end 2"]);
+    }
+
+    #[tokio::test]
+    async fn preparing_a_job_does_not_hold_up_the_queue() {
+        let queue = JobQueue::new(clock);
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let prepared = queue.submit_prepared(
+            "test",
+            Box::pin(async move {
+                released.await.unwrap();
+                Ok(Box::pin(async { Ok(serde_json::json!("prepared")) }) as JobFuture)
+            }),
+        );
+        let queued = queue.submit("test", Box::pin(async { Ok(serde_json::json!("queued")) }));
+
+        assert_eq!(settled(&queue, queued).await.result, Some(serde_json::json!("queued")));
+        assert_eq!(queue.status(prepared).unwrap().state, JobState::Pending);
+        release.send(()).unwrap();
+        assert_eq!(settled(&queue, prepared).await.result, Some(serde_json::json!("prepared")));
+
+        let failed = queue.submit_prepared(
+            "test",
+            Box::pin(async {
+                Err(JobError {
+                    status: 502,
+                    body: serde_json::json!({"error": "download_failed"}),
+                })
+            }),
+        );
+        assert_eq!(settled(&queue, failed).await.error.unwrap().status, 502);
+    }
+
+    #[test]
+    fn finished_jobs_are_forgotten_after_the_retention() {
+        let entry = |n: u64, finished_at_ms: Option<u64>| JobStatus {
+            id: Uuid::from_u128(n as u128),
+            kind: "test".into(),
+            state: if finished_at_ms.is_some() { JobState::Succeeded } else { JobState::Running },
+            submitted_at_ms: 0,
+            finished_at_ms,
+            result: None,
+            error: None,
+        };
+        let mut jobs: VecDeque<JobStatus> = (0..MAX_RETAINED_JOBS as u64 + 2).map(|n| entry(n, Some(JOB_RETENTION_MS))).collect();
+        jobs.push_back(entry(999, None));
+        jobs.push_front(entry(1_000, Some(0)));
+
+        prune(&mut jobs, JOB_RETENTION_MS + 1);
+        let ids: Vec<u128> = jobs.iter().map(|job| job.id.as_u128()).collect();
+        assert_eq!(ids.len(), MAX_RETAINED_JOBS + 1);
+        assert_eq!(ids.first(), Some(&2));
+        assert_eq!(ids.last(), Some(&999));
+    }
+}
@@ -1,11 +1,34 @@
+pub mod access_log;
+pub mod admin_token;
 pub mod airplay;
 pub mod calibration;
 pub mod hardware;
 pub mod http;
+pub mod jobs;
 pub mod chirp;
+pub mod cli;
+pub mod config_changes;
+pub mod discovery;
+pub mod events;
+pub mod firmware;
+pub mod group;
+pub mod network;
+mod peer_client;
+pub mod request_id;
+pub mod setup;
+pub mod startup;
+pub mod status;
+pub mod timesync;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod version;
+pub mod watchdog;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 
 pub use airplay::*;
 pub use calibration::*;
 pub use hardware::*;
 pub use http::*;
 pub use chirp::*;
+pub use status::*;
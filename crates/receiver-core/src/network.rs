@@ -0,0 +1,154 @@
+use anyhow::{Context, Result};
+use axum::extract::connect_info::Connected;
+use axum::serve::IncomingStream;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// One address assigned to a named interface, as the OS lists them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceAddress {
+    pub name: String,
+    pub addr: IpAddr,
+}
+
+/// Source of the receiver's own addresses.
+pub trait NetworkInfoProvider: Send + Sync {
+    fn addresses(&self) -> Result<Vec<InterfaceAddress>>;
+}
+
+/// Reads interfaces with `getifaddrs` via the `if-addrs` crate.
+pub struct IfAddrsProvider;
+
+impl NetworkInfoProvider for IfAddrsProvider {
+    fn addresses(&self) -> Result<Vec<InterfaceAddress>> {
+        let interfaces = if_addrs::get_if_addrs().context("failed to list network interfaces")?;
+        Ok(interfaces
+            .into_iter()
+            .map(|iface| InterfaceAddress {
+                addr: iface.ip(),
+                name: iface.name,
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub ipv4: Vec<Ipv4Addr>,
+    pub ipv6: Vec<Ipv6Addr>,
+    pub loopback: bool,
+    /// Only link-local addresses, so clients on other subnets can't reach it.
+    pub link_local_only: bool,
+    /// Holds the local address the current request arrived on.
+    pub serving: bool,
+}
+
+/// Group addresses by interface name, in name order, flagging the interface that owns
+/// `local` (IPv4-mapped addresses from a dual-stack listener are matched as IPv4).
+pub fn summarize_interfaces(addresses: Vec<InterfaceAddress>, local: Option<IpAddr>) -> Vec<NetworkInterface> {
+    let local = local.map(|addr| addr.to_canonical());
+    let mut by_name: BTreeMap<String, Vec<IpAddr>> = BTreeMap::new();
+    for InterfaceAddress { name, addr } in addresses {
+        by_name.entry(name).or_default().push(addr);
+    }
+    by_name
+        .into_iter()
+        .map(|(name, addrs)| {
+            let loopback = addrs.iter().all(IpAddr::is_loopback);
+            NetworkInterface {
+                ipv4: addrs
+                    .iter()
+                    .filter_map(|a| match a {
+                        IpAddr::V4(v4) => Some(*v4),
+                        IpAddr::V6(_) => None,
+                    })
+                    .collect(),
+                ipv6: addrs
+                    .iter()
+                    .filter_map(|a| match a {
+                        IpAddr::V6(v6) => Some(*v6),
+                        IpAddr::V4(_) => None,
+                    })
+                    .collect(),
+                loopback,
+                link_local_only: !loopback && addrs.iter().all(is_link_local),
+                serving: local.is_some_and(|local| addrs.contains(&local)),
+                name,
+            }
+        })
+        .collect()
+}
+
+fn is_link_local(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => v4.is_link_local(),
+        IpAddr::V6(v6) => (v6.segments()[0] & 0xffc0) == 0xfe80,
+    }
+}
+
+/// Both ends of an accepted connection, recorded by `serve` for `ConnectInfo`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+}
+
+impl Connected<IncomingStream<'_>> for ConnectionInfo {
+    fn connect_info(target: IncomingStream<'_>) -> Self {
+        let remote = target.remote_addr();
+        Self {
+            local: target
+                .local_addr()
+                .unwrap_or_else(|_| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)),
+            remote,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(name: &str, addr: &str) -> InterfaceAddress {
+        InterfaceAddress {
+            name: name.into(),
+            addr: addr.parse().unwrap(),
+        }
+    }
+
+    fn sample() -> Vec<InterfaceAddress> {
+        vec![
+            addr("wlan0", "192.168.1.20"),
+            addr("lo", "127.0.0.1"),
+            addr("wlan0", "fe80::1"),
+            addr("eth0", "169.254.10.2"),
+            addr("lo", "::1"),
+            addr("eth0", "fe80::2"),
+        ]
+    }
+
+    #[test]
+    fn groups_addresses_and_flags_unroutable_interfaces() {
+        let interfaces = summarize_interfaces(sample(), None);
+        let names: Vec<&str> = interfaces.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["eth0", "lo", "wlan0"]);
+
+        let [eth0, lo, wlan0] = &interfaces[..] else { unreachable!() };
+        assert!(eth0.link_local_only && !eth0.loopback);
+        assert!(lo.loopback && !lo.link_local_only);
+        assert!(!wlan0.loopback && !wlan0.link_local_only);
+        assert_eq!(wlan0.ipv4, vec![Ipv4Addr::new(192, 168, 1, 20)]);
+        assert_eq!(wlan0.ipv6, vec!["fe80::1".parse::<Ipv6Addr>().unwrap()]);
+        assert!(interfaces.iter().all(|i| !i.serving));
+    }
+
+    #[test]
+    fn serving_interface_matches_ipv4_mapped_local_address() {
+        let local = "::ffff:192.168.1.20".parse().ok();
+        let interfaces = summarize_interfaces(sample(), local);
+        let serving: Vec<&str> = interfaces.iter().filter(|i| i.serving).map(|i| i.name.as_str()).collect();
+        assert_eq!(serving, vec!["wlan0"]);
+    }
+}
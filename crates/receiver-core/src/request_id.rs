@@ -0,0 +1,217 @@
+use crate::cli::{CommonArgs, LogLevel};
+use axum::http::{HeaderName, HeaderValue, Request};
+use axum::response::Response;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+use uuid::Uuid;
+
+/// Header carrying the correlation id between clients and receiver logs.
+pub static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Correlation id of the request being handled; also stored in request extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+tokio::task_local! {
+    static CURRENT: RequestId;
+}
+
+/// Id of the request the current task is handling, if any.
+pub fn current_request_id() -> Option<String> {
+    CURRENT.try_with(|id| id.0.clone()).ok()
+}
+
+/// Run `fut` (typically a spawned follow-up task) under the current request's id so its
+/// log lines stay correlated.
+pub fn inherit<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    let id = CURRENT.try_with(Clone::clone).ok();
+    async move {
+        match id {
+            Some(id) => CURRENT.scope(id, fut).await,
+            None => fut.await,
+        }
+    }
+}
+
+/// Prefix for log lines, `[req=<id>] ` inside a request and empty elsewhere.
+pub fn log_prefix() -> String {
+    current_request_id()
+        .map(|id| format!("[req={id}] "))
+        .unwrap_or_default()
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+static JSON_LOGS: AtomicBool = AtomicBool::new(false);
+
+/// Apply `--log-level` and `--json` to the lines logged through `log_info!` and `log_warn!`.
+pub fn configure_logging(common: &CommonArgs) {
+    LOG_LEVEL.store(common.log_level as u8, Ordering::Relaxed);
+    JSON_LOGS.store(common.json, Ordering::Relaxed);
+}
+
+/// Print `message` if `level` is shown: warnings and errors to stderr, the rest to stdout.
+pub fn log(level: LogLevel, message: fmt::Arguments<'_>) {
+    if level as u8 > LOG_LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+    let line = format_line(level, message, JSON_LOGS.load(Ordering::Relaxed));
+    if level <= LogLevel::Warn {
+        eprintln!("{line}");
+    } else {
+        println!("{line}");
+    }
+}
+
+/// `message` with the current request id prepended, or as a JSON object with `level`,
+/// `request_id` (inside a request) and `message`.
+fn format_line(level: LogLevel, message: fmt::Arguments<'_>, json: bool) -> String {
+    if !json {
+        return format!("{}{message}", log_prefix());
+    }
+    let mut line = serde_json::json!({"level": format!("{level:?}").to_lowercase(), "message": message.to_string()});
+    if let Some(id) = current_request_id() {
+        line["request_id"] = id.into();
+    }
+    line.to_string()
+}
+
+/// Logs at [`LogLevel::Info`] with the current request id prepended.
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        $crate::request_id::log($crate::cli::LogLevel::Info, format_args!($($arg)*))
+    };
+}
+
+/// Logs at [`LogLevel::Warn`] with the current request id prepended.
+macro_rules! log_warn {
+    ($($arg:tt)*) => {
+        $crate::request_id::log($crate::cli::LogLevel::Warn, format_args!($($arg)*))
+    };
+}
+
+pub(crate) use {log_info, log_warn};
+
+/// Reads `X-Request-Id` from the request (generating a UUID when absent or unusable),
+/// exposes it to handlers, and echoes it on every response.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestIdLayer;
+
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = RequestIdService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestIdService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestIdService<S> {
+    inner: S,
+}
+
+impl<S, B> Service<Request<B>> for RequestIdService<S>
+where
+    S: Service<Request<B>, Response = Response>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let header = req
+            .headers()
+            .get(&REQUEST_ID_HEADER)
+            .filter(|v| v.to_str().is_ok_and(|s| !s.trim().is_empty()))
+            .cloned();
+        let header = header.unwrap_or_else(|| {
+            HeaderValue::from_str(&Uuid::new_v4().to_string()).expect("uuid is a valid header value")
+        });
+        let id = RequestId(header.to_str().expect("checked above").to_string());
+        req.extensions_mut().insert(id.clone());
+
+        let fut = CURRENT.sync_scope(id.clone(), || self.inner.call(req));
+        Box::pin(CURRENT.scope(id, async move {
+            let mut response = fut.await?;
+            response.headers_mut().insert(REQUEST_ID_HEADER.clone(), header);
+            Ok(response)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::routing::get;
+    use axum::{Extension, Router};
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        Router::new()
+            .route(
+                "/id",
+                get(|Extension(id): Extension<RequestId>| async move {
+                    assert_eq!(current_request_id().as_deref(), Some(id.0.as_str()));
+                    let spawned = tokio::spawn(inherit(async { current_request_id() })).await.unwrap();
+                    assert_eq!(spawned.as_deref(), Some(id.0.as_str()));
+                    id.0
+                }),
+            )
+            .layer(RequestIdLayer)
+    }
+
+    #[tokio::test]
+    async fn preserves_client_supplied_id() {
+        let response = app()
+            .oneshot(
+                Request::get("/id")
+                    .header("X-Request-Id", "ios-42 / abc")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["x-request-id"], "ios-42 / abc");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"ios-42 / abc");
+    }
+
+    #[tokio::test]
+    async fn generates_id_when_absent() {
+        let response = app()
+            .oneshot(Request::get("/id").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let id = response.headers()["x-request-id"].to_str().unwrap();
+        assert!(Uuid::parse_str(id).is_ok());
+    }
+
+    #[test]
+    fn no_prefix_outside_requests() {
+        assert_eq!(log_prefix(), "");
+        assert_eq!(current_request_id(), None);
+    }
+
+    #[tokio::test]
+    async fn json_lines_carry_the_level_and_request_id() {
+        let line = CURRENT
+            .scope(RequestId("ios-42".into()), async {
+                format_line(LogLevel::Warn, format_args!("refusing {}", "/admin"), true)
+            })
+            .await;
+        let line: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(line, serde_json::json!({"level": "warn", "request_id": "ios-42", "message": "refusing /admin"}));
+        assert_eq!(format_line(LogLevel::Info, format_args!("ready"), false), "ready");
+        let plain: serde_json::Value = serde_json::from_str(&format_line(LogLevel::Info, format_args!("ready"), true)).unwrap();
+        assert_eq!(plain, serde_json::json!({"level": "info", "message": "ready"}));
+    }
+}
@@ -0,0 +1,140 @@
+//! First-boot setup. A receiver that boots without a settings store advertises `setup=1`
+//! and reports through `GET /api/setup` what is still to be chosen; `POST
+//! /api/setup/complete` applies the chosen name and output, which writes the store, so
+//! later boots start configured.
+
+use crate::airplay::generate_config;
+use airsync_shared_protocol::{AudioOutput, HardwareCapabilities, OutputDeviceSpec};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Longest name accepted, the limit of a DNS-SD instance label.
+pub const MAX_DEVICE_NAME_BYTES: usize = 63;
+
+/// Something setup hasn't settled yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SetupStep {
+    /// The receiver still uses its hostname.
+    Name,
+    /// The output was guessed from the detected hardware, not confirmed.
+    Output,
+    /// No calibration has been applied yet. Setup completes without one.
+    Calibration,
+}
+
+/// An output setup can pick, with the device it is configured as.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutputOption {
+    pub output: AudioOutput,
+    pub output_device: OutputDeviceSpec,
+    /// The output detection prefers on this board.
+    pub preferred: bool,
+}
+
+/// Detected outputs, preferred first.
+pub fn output_options(caps: &HardwareCapabilities) -> Vec<OutputOption> {
+    let mut outputs = caps.audio_outputs.clone();
+    outputs.sort_by_key(|output| *output != caps.preferred_output);
+    outputs
+        .into_iter()
+        .map(|output| OutputOption {
+            output,
+            output_device: generate_config(None, output).output_device,
+            preferred: output == caps.preferred_output,
+        })
+        .collect()
+}
+
+/// Body of `GET /api/setup`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SetupStatus {
+    pub setup_mode: bool,
+    pub missing: Vec<SetupStep>,
+    /// `None` when the receiver has no hardware probe or detection failed.
+    pub hardware: Option<HardwareCapabilities>,
+    pub output_options: Vec<OutputOption>,
+}
+
+/// Body of `POST /api/setup/complete`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SetupCompleteRequest {
+    pub device_name: String,
+    pub output_device: OutputDeviceSpec,
+}
+
+impl SetupCompleteRequest {
+    /// The error code for the name if it can't be advertised.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let name = self.device_name.trim();
+        if name.is_empty() || name.len() > MAX_DEVICE_NAME_BYTES || name.chars().any(char::is_control) {
+            return Err("invalid_device_name");
+        }
+        Ok(())
+    }
+}
+
+/// Whether the receiver is still in setup mode, shared by every clone.
+#[derive(Clone, Default)]
+pub struct SetupMode {
+    active: Arc<AtomicBool>,
+    /// Held while a completion applies, so a second one waits and then finds setup over.
+    completing: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl SetupMode {
+    pub fn new(active: bool) -> Self {
+        Self {
+            active: Arc::new(AtomicBool::new(active)),
+            completing: Arc::default(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Run `apply` and leave setup mode if it succeeds; `None` without running it when
+    /// setup is already over. Completions run one at a time.
+    pub async fn complete<T, E>(&self, apply: impl Future<Output = Result<T, E>>) -> Option<Result<T, E>> {
+        let _completing = self.completing.lock().await;
+        if !self.is_active() {
+            return None;
+        }
+        let result = apply.await;
+        if result.is_ok() {
+            self.active.store(false, Ordering::SeqCst);
+        }
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_must_fit_a_dns_sd_label() {
+        let request = |name: &str| SetupCompleteRequest {
+            device_name: name.into(),
+            output_device: OutputDeviceSpec::hw(1, 0),
+        };
+        assert_eq!(request("Living Room").validate(), Ok(()));
+        assert_eq!(request("  ").validate(), Err("invalid_device_name"));
+        assert_eq!(request(&"n".repeat(64)).validate(), Err("invalid_device_name"));
+        assert_eq!(request("Den\n").validate(), Ok(()));
+        assert_eq!(request("Den\u{7}").validate(), Err("invalid_device_name"));
+    }
+
+    #[tokio::test]
+    async fn only_a_successful_completion_leaves_setup_mode() {
+        let setup = SetupMode::new(true);
+        assert_eq!(setup.complete(async { Err::<(), _>("apply failed") }).await, Some(Err("apply failed")));
+        assert!(setup.is_active());
+        assert_eq!(setup.complete(async { Ok::<_, ()>(1) }).await, Some(Ok(1)));
+        assert!(!setup.is_active());
+        assert_eq!(setup.complete(async { Ok::<_, ()>(2) }).await, None);
+    }
+}
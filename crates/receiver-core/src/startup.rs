@@ -0,0 +1,447 @@
+use crate::airplay::{parse_config_file, render_eq_fragment, ConfigRenderer, ShairportConfig};
+use crate::calibration::{ConfigWriter, READBACK_EPSILON};
+use crate::hardware::AlsaCard;
+use crate::http::SettingsUpdatePayload;
+use airsync_shared_protocol::{CardRef, OutputDeviceSpec};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// File inside the receiver state dir holding the persisted settings.
+pub const SETTINGS_STATE_FILE: &str = "settings.json";
+
+/// The receiver's settings as last committed, kept outside `/etc` so they survive the
+/// shairport-sync config being replaced, e.g. by a package upgrade.
+pub struct SettingsFile {
+    path: PathBuf,
+    /// Orders concurrent saves, which run on the blocking pool.
+    write_lock: Mutex<()>,
+}
+
+impl SettingsFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The stored settings, `None` if none have been saved yet. Fields missing from the
+    /// file are left unset.
+    pub fn load(&self) -> Result<Option<SettingsUpdatePayload>> {
+        let bytes = match std::fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).with_context(|| format!("reading {}", self.path.display())),
+        };
+        let stored = serde_json::from_slice(&bytes).with_context(|| format!("parsing {}", self.path.display()))?;
+        Ok(Some(stored))
+    }
+
+    pub fn save(&self, config: &ShairportConfig) -> Result<()> {
+        self.save_with(|| config.clone())
+    }
+
+    /// Save the config `snapshot` returns, taking it only once earlier saves are done so
+    /// the last save to finish always writes the newest config. The file is written to
+    /// `<path>.new` and renamed into place, so a crash mid-write leaves the old settings.
+    pub fn save_with(&self, snapshot: impl FnOnce() -> ShairportConfig) -> Result<()> {
+        let _writing = self.write_lock.lock().unwrap();
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let bytes = serde_json::to_vec_pretty(&SettingsUpdatePayload::from_config(&snapshot()))?;
+        let mut staging = self.path.clone().into_os_string();
+        staging.push(".new");
+        let staging = PathBuf::from(staging);
+        let staged = std::fs::write(&staging, bytes).and_then(|()| std::fs::rename(&staging, &self.path));
+        if staged.is_err() {
+            let _ = std::fs::remove_file(&staging);
+        }
+        staged.with_context(|| format!("writing {}", self.path.display()))
+    }
+}
+
+/// Where a field of the boot-time config came from, highest precedence first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigSource {
+    Store,
+    ConfigFile,
+    /// Generated for the detected preferred output.
+    Detected,
+    /// Generated without hardware detection.
+    Fallback,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConfigSource::Store => "settings store",
+            ConfigSource::ConfigFile => "config file",
+            ConfigSource::Detected => "detected hardware",
+            ConfigSource::Fallback => "fallback defaults",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldSources {
+    pub device_name: ConfigSource,
+    pub output_device: ConfigSource,
+    pub latency_offset_seconds: ConfigSource,
+    pub calibration_gain: ConfigSource,
+    pub eq: ConfigSource,
+}
+
+/// Everything the boot-time config can be taken from.
+pub struct StartupInputs<'a> {
+    pub stored: Option<SettingsUpdatePayload>,
+    /// Contents of the shairport-sync config, when the file exists.
+    pub config_file: Option<&'a str>,
+    /// Config generated for the detected hardware, when detection succeeded.
+    pub detected: Option<ShairportConfig>,
+    /// Used when neither the config file nor detection provides a config.
+    pub fallback: ShairportConfig,
+    /// ALSA cards present now; `None` skips checking the stored output device.
+    pub cards: Option<&'a [AlsaCard]>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reconciliation {
+    pub config: ShairportConfig,
+    pub sources: FieldSources,
+    /// The config file is missing or differs from `config`. An unparsable file is kept
+    /// for the operator to inspect, so it is never rewritten.
+    pub write_config_file: bool,
+    /// The store exists and differs from `config`. A missing one is left for first-boot
+    /// setup to write.
+    pub write_store: bool,
+    /// There is no settings store, so the receiver starts in setup mode.
+    pub setup_required: bool,
+    /// Why an existing config file couldn't be used.
+    pub config_file_error: Option<String>,
+    /// Set when the stored output device is no longer present. It is still used, so a
+    /// DAC that is briefly missing at boot doesn't lose its configuration.
+    pub needs_attention: Option<String>,
+}
+
+/// Settle the boot-time config field by field: the settings store wins, then the parsed
+/// config file, then a config generated for the detected hardware.
+pub fn reconcile(inputs: StartupInputs<'_>) -> Reconciliation {
+    let parsed = inputs.config_file.map(parse_config_file);
+    let config_file_error = match &parsed {
+        Some(Err(err)) => Some(err.to_string()),
+        _ => None,
+    };
+    let on_disk = parsed.and_then(Result::ok);
+    let (base, base_source) = match (&on_disk, inputs.detected) {
+        (Some(on_disk), _) => (on_disk.clone(), ConfigSource::ConfigFile),
+        (None, Some(detected)) => (detected, ConfigSource::Detected),
+        (None, None) => (inputs.fallback, ConfigSource::Fallback),
+    };
+
+    let stored = inputs.stored.as_ref();
+    let source = |present: bool| if present { ConfigSource::Store } else { base_source };
+    let sources = FieldSources {
+        device_name: source(stored.is_some_and(|s| s.device_name.is_some())),
+        output_device: source(stored.is_some_and(|s| s.output_device.is_some())),
+        latency_offset_seconds: source(stored.is_some_and(|s| s.latency_offset_seconds.is_some())),
+        calibration_gain: source(stored.is_some_and(|s| s.calibration_gain.is_some())),
+        eq: source(stored.is_some_and(|s| s.eq.is_some())),
+    };
+    let config = stored.map_or_else(|| base.clone(), |stored| stored.merge(&base));
+
+    let needs_attention = match (stored.and_then(|s| s.output_device.as_ref()), inputs.cards) {
+        (Some(device), Some(cards)) if !output_device_present(device, cards) => {
+            Some(format!("stored output device {device} is no longer present"))
+        }
+        _ => None,
+    };
+
+    Reconciliation {
+        write_config_file: config_file_error.is_none()
+            && !on_disk.is_some_and(|on_disk| on_disk.approx_eq(&config, READBACK_EPSILON)),
+        write_store: stored.is_some_and(|stored| *stored != SettingsUpdatePayload::from_config(&config)),
+        setup_required: stored.is_none(),
+        config,
+        sources,
+        config_file_error,
+        needs_attention,
+    }
+}
+
+/// Whether `device` names a card in `cards`. Named PCMs can't be checked and count as present.
+fn output_device_present(device: &OutputDeviceSpec, cards: &[AlsaCard]) -> bool {
+    match device {
+        OutputDeviceSpec::Hw { card, .. } => cards.iter().any(|c| match card {
+            CardRef::Index(index) => c.index == *index,
+            CardRef::Name(id) => c.id == *id,
+        }),
+        OutputDeviceSpec::Plug(slave) => slave
+            .parse::<OutputDeviceSpec>()
+            .map_or(true, |slave| output_device_present(&slave, cards)),
+        OutputDeviceSpec::Named(_) => true,
+    }
+}
+
+/// Reconcile the settings store with the config file `writer` targets, log where each
+/// field came from, and write the result back, rendered by `renderer`, to whichever of the
+/// two differs.
+pub fn reconcile_startup_config<W: ConfigWriter>(
+    writer: &W,
+    renderer: &dyn ConfigRenderer,
+    store: &SettingsFile,
+    detected: Option<ShairportConfig>,
+    fallback: ShairportConfig,
+    cards: Option<&[AlsaCard]>,
+) -> Result<Reconciliation> {
+    let stored = store.load().unwrap_or_else(|err| {
+        eprintln!("[startup] ignoring unreadable settings store: {err:#}");
+        None
+    });
+    let config_file = match writer.target_path() {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(contents) => Some(contents),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err).with_context(|| format!("reading {}", path.display())),
+        },
+        None => None,
+    };
+
+    let reconciled = reconcile(StartupInputs {
+        stored,
+        config_file: config_file.as_deref(),
+        detected,
+        fallback,
+        cards,
+    });
+    if let Some(err) = &reconciled.config_file_error {
+        eprintln!("[startup] keeping unparsable shairport-sync config as is: {err}");
+    }
+    let FieldSources {
+        device_name,
+        output_device,
+        latency_offset_seconds,
+        calibration_gain,
+        eq,
+    } = reconciled.sources;
+    eprintln!(
+        "[startup] device_name from {device_name}, output_device from {output_device}, \
+         latency_offset_seconds from {latency_offset_seconds}, calibration_gain from {calibration_gain}, \
+         eq from {eq}"
+    );
+    if let Some(reason) = &reconciled.needs_attention {
+        eprintln!("[startup] needs attention: {reason}");
+    }
+
+    if reconciled.write_config_file {
+        eprintln!("[startup] writing reconciled shairport-sync config");
+        writer.write(&renderer.render(&reconciled.config)?)?;
+    }
+    if reconciled.write_store {
+        eprintln!("[startup] writing reconciled settings to {}", store.path().display());
+        store.save(&reconciled.config)?;
+    }
+    Ok(reconciled)
+}
+
+/// Write the EQ fragment for `config` unless `writer`'s file already holds it. Returns
+/// whether it was written.
+pub fn sync_eq_fragment<W: ConfigWriter>(writer: &W, config: &ShairportConfig) -> Result<bool> {
+    let fragment = render_eq_fragment(config);
+    if let Some(path) = writer.target_path() {
+        match std::fs::read_to_string(path) {
+            Ok(existing) if existing == fragment => return Ok(false),
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+    eprintln!("[startup] writing EQ fragment");
+    writer.write(&fragment)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::airplay::{generate_config, render_config_file, BuiltinRenderer};
+    use crate::test_util::MockWriter;
+    use airsync_shared_protocol::AudioOutput;
+    use ConfigSource::{ConfigFile, Detected, Fallback, Store};
+
+    fn on_disk() -> ShairportConfig {
+        ShairportConfig {
+            latency_offset_seconds: -0.042,
+            ..generate_config(Some("Kitchen"), AudioOutput::I2S)
+        }
+    }
+
+    /// Sets the name and device only, so the other fields show the fallback order.
+    fn stored() -> SettingsUpdatePayload {
+        SettingsUpdatePayload {
+            device_name: Some("Den".into()),
+            output_device: Some(OutputDeviceSpec::hw(1, 0)),
+            latency_offset_seconds: None,
+            calibration_gain: None,
+            eq: None,
+            self_calibration: None,
+        }
+    }
+
+    fn card(index: u32, id: &str) -> AlsaCard {
+        AlsaCard {
+            index,
+            id: id.into(),
+            name: id.into(),
+            modalias: None,
+        }
+    }
+
+    #[test]
+    fn store_then_config_file_then_detected() {
+        let file = render_config_file(&on_disk());
+        let detected = generate_config(Some("Detected"), AudioOutput::USB);
+        let fallback = generate_config(Some("Fallback"), AudioOutput::Headphone);
+        let cards = [card(0, "Headphones"), card(1, "Device")];
+
+        // (store, config file, detected) => (name source, latency source, name, latency)
+        let cases = [
+            ((true, true, true), (Store, ConfigFile, "Den", -0.042)),
+            ((true, true, false), (Store, ConfigFile, "Den", -0.042)),
+            ((true, false, true), (Store, Detected, "Den", 0.0)),
+            ((true, false, false), (Store, Fallback, "Den", 0.0)),
+            ((false, true, true), (ConfigFile, ConfigFile, "Kitchen", -0.042)),
+            ((false, true, false), (ConfigFile, ConfigFile, "Kitchen", -0.042)),
+            ((false, false, true), (Detected, Detected, "Detected", 0.0)),
+            ((false, false, false), (Fallback, Fallback, "Fallback", 0.0)),
+        ];
+        for ((has_store, has_file, has_detected), (name_source, latency_source, name, latency)) in cases {
+            let case = format!("store={has_store} file={has_file} detected={has_detected}");
+            let reconciled = reconcile(StartupInputs {
+                stored: has_store.then(stored),
+                config_file: has_file.then_some(file.as_str()),
+                detected: has_detected.then(|| detected.clone()),
+                fallback: fallback.clone(),
+                cards: Some(&cards),
+            });
+            assert_eq!(reconciled.sources.device_name, name_source, "{case}");
+            assert_eq!(reconciled.sources.latency_offset_seconds, latency_source, "{case}");
+            assert_eq!(reconciled.config.device_name, name, "{case}");
+            assert!((reconciled.config.latency_offset_seconds - latency).abs() < 1e-6, "{case}");
+            // A stored name always differs from the file's, and a partial store is completed.
+            assert_eq!(reconciled.write_config_file, has_store || !has_file, "{case}");
+            assert_eq!(reconciled.write_store, has_store, "{case}");
+            assert_eq!(reconciled.setup_required, !has_store, "{case}");
+            assert_eq!(reconciled.needs_attention, None, "{case}");
+            assert_eq!(reconciled.config_file_error, None, "{case}");
+        }
+    }
+
+    #[test]
+    fn nothing_is_rewritten_once_the_sources_agree() {
+        let file = render_config_file(&on_disk());
+        let reconciled = reconcile(StartupInputs {
+            stored: Some(SettingsUpdatePayload::from_config(&on_disk())),
+            config_file: Some(&file),
+            detected: None,
+            fallback: generate_config(None, AudioOutput::Headphone),
+            cards: None,
+        });
+        assert_eq!(reconciled.config, on_disk());
+        assert!(!reconciled.write_config_file);
+        assert!(!reconciled.write_store);
+    }
+
+    #[test]
+    fn unparsable_config_file_is_kept_and_the_store_used() {
+        let reconciled = reconcile(StartupInputs {
+            stored: Some(stored()),
+            config_file: Some("general = { name = "),
+            detected: Some(generate_config(Some("Detected"), AudioOutput::USB)),
+            fallback: generate_config(None, AudioOutput::Headphone),
+            cards: None,
+        });
+        assert!(reconciled.config_file_error.is_some());
+        assert!(!reconciled.write_config_file);
+        assert_eq!(reconciled.sources.device_name, Store);
+        assert_eq!(reconciled.sources.latency_offset_seconds, Detected);
+        assert_eq!(reconciled.config.device_name, "Den");
+    }
+
+    #[test]
+    fn missing_stored_output_device_needs_attention() {
+        let reconciled = reconcile(StartupInputs {
+            stored: Some(stored()),
+            config_file: None,
+            detected: None,
+            fallback: generate_config(None, AudioOutput::Headphone),
+            cards: Some(&[card(0, "Headphones")]),
+        });
+        assert_eq!(reconciled.config.output_device, OutputDeviceSpec::hw(1, 0));
+        assert!(reconciled.needs_attention.unwrap().contains("hw:1,0"));
+    }
+
+    #[test]
+    fn startup_writes_back_only_what_differs() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SettingsFile::new(dir.path().join(SETTINGS_STATE_FILE));
+        let writer = MockWriter::new();
+        let fallback = generate_config(Some("Fallback"), AudioOutput::Headphone);
+
+        let first = reconcile_startup_config(&writer, &BuiltinRenderer, &store, None, fallback.clone(), None).unwrap();
+        assert!(first.setup_required && first.write_config_file && !first.write_store);
+        assert_eq!(store.load().unwrap(), None);
+        assert_eq!(writer.last_contents(), Some(render_config_file(&fallback)));
+
+        // Completing setup saves the store, which later boots start from.
+        store.save(&fallback).unwrap();
+        let detected = generate_config(Some("Detected"), AudioOutput::USB);
+        let second = reconcile_startup_config(&writer, &BuiltinRenderer, &store, Some(detected), fallback.clone(), None).unwrap();
+        assert_eq!(second.config, fallback);
+        assert!(!second.write_store && !second.setup_required);
+    }
+
+    #[test]
+    fn failed_settings_save_keeps_the_previous_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(SETTINGS_STATE_FILE);
+        let store = SettingsFile::new(&path);
+        let saved = generate_config(Some("Saved"), AudioOutput::USB);
+        store.save(&saved).unwrap();
+        assert!(!dir.path().join("settings.json.new").exists());
+
+        // A directory in the staging path's place makes the write fail before the rename.
+        std::fs::create_dir(dir.path().join("settings.json.new")).unwrap();
+        assert!(store.save(&generate_config(Some("Lost"), AudioOutput::USB)).is_err());
+        let stored = store.load().unwrap().unwrap();
+        assert_eq!(stored.device_name.as_deref(), Some("Saved"));
+    }
+
+    #[test]
+    fn eq_fragment_is_written_only_when_it_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let writer = crate::calibration::FileConfigWriter::unchecked(dir.path().join("60-airsync-eq.conf"));
+        let mut config = generate_config(None, AudioOutput::USB);
+        config.eq = Some(crate::airplay::EqSettings {
+            preamp_db: -6.0,
+            high_pass_hz: None,
+        });
+        assert!(sync_eq_fragment(&writer, &config).unwrap());
+        assert!(!sync_eq_fragment(&writer, &config).unwrap());
+        config.eq = None;
+        assert!(sync_eq_fragment(&writer, &config).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(writer.target_path().unwrap()).unwrap(),
+            render_eq_fragment(&config)
+        );
+    }
+}
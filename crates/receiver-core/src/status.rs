@@ -0,0 +1,400 @@
+use crate::events::{EventHub, EventKind, ReceiverEvent, Subscription};
+use airsync_shared_protocol::{Metadata, PlaybackStatus, WebSocketMessage};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long after the last progress report AirPlay audio is still considered flowing.
+pub const PROGRESS_TIMEOUT_MS: u64 = 5_000;
+
+/// Inputs to the status derivation, fed by the metadata-pipe reader and the
+/// calibration handlers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatusEvent {
+    SessionStarted,
+    SessionEnded,
+    /// The source paused the stream; lasts until `Resumed` or the session ends.
+    Paused,
+    Resumed,
+    Progress,
+    /// A new metadata bundle is starting; previously known fields are discarded.
+    MetadataStarted,
+    /// Fields present here replace the current ones; `None` fields are left alone.
+    Metadata(Metadata),
+    CalibrationStarted,
+    CalibrationFinished,
+    /// A phone paired with or calibrated this receiver since it started.
+    Paired,
+    /// Something failed that needs attention; reported until `Recovered`.
+    Failed(String),
+    Recovered,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatusSnapshot {
+    pub status: PlaybackStatus,
+    pub since_ms: u64,
+    pub metadata: Option<Metadata>,
+    #[serde(default)]
+    pub paired: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl StatusSnapshot {
+    pub fn to_message(&self, timestamp: u64) -> WebSocketMessage {
+        WebSocketMessage::StatusUpdate {
+            timestamp,
+            status: self.status,
+            metadata: self.metadata.clone(),
+        }
+    }
+}
+
+struct TrackerState {
+    session_active: bool,
+    paused: bool,
+    last_progress_ms: Option<u64>,
+    calibrations: u32,
+    snapshot: StatusSnapshot,
+}
+
+impl TrackerState {
+    fn derive(&self, now_ms: u64) -> PlaybackStatus {
+        let recent_progress = self
+            .last_progress_ms
+            .is_some_and(|at| now_ms.saturating_sub(at) <= PROGRESS_TIMEOUT_MS);
+        if self.calibrations > 0 {
+            PlaybackStatus::Calibrating
+        } else if self.paused {
+            PlaybackStatus::Paused
+        } else if self.session_active || recent_progress {
+            PlaybackStatus::Playing
+        } else {
+            PlaybackStatus::Idle
+        }
+    }
+
+    /// Re-derive the status at `now_ms`, returning whether the snapshot changed.
+    fn settle(&mut self, now_ms: u64) -> bool {
+        let status = self.derive(now_ms);
+        if status == self.snapshot.status {
+            return false;
+        }
+        // A progress timeout noticed late still dates the transition to the expiry.
+        let since_ms = match (status, self.last_progress_ms) {
+            (PlaybackStatus::Idle, Some(at)) if !self.session_active => {
+                (at + PROGRESS_TIMEOUT_MS).clamp(self.snapshot.since_ms, now_ms)
+            }
+            _ => now_ms,
+        };
+        self.snapshot.status = status;
+        self.snapshot.since_ms = since_ms;
+        true
+    }
+}
+
+/// Single source of truth for the receiver's playback status. The HTTP status endpoint
+/// reads snapshots and streaming clients subscribe to the same transitions, published as
+/// [`ReceiverEvent::Status`] on the tracker's hub.
+#[derive(Clone)]
+pub struct StatusTracker {
+    state: Arc<Mutex<TrackerState>>,
+    events: EventHub,
+}
+
+impl StatusTracker {
+    pub fn new(now_ms: u64) -> Self {
+        Self::with_hub(now_ms, EventHub::default())
+    }
+
+    /// A tracker publishing to `events`, which other sources of receiver events can share.
+    pub fn with_hub(now_ms: u64, events: EventHub) -> Self {
+        let snapshot = StatusSnapshot {
+            status: PlaybackStatus::Idle,
+            since_ms: now_ms,
+            metadata: None,
+            paired: false,
+            error: None,
+        };
+        events.publish(ReceiverEvent::Status(snapshot.clone()));
+        Self {
+            state: Arc::new(Mutex::new(TrackerState {
+                session_active: false,
+                paused: false,
+                last_progress_ms: None,
+                calibrations: 0,
+                snapshot,
+            })),
+            events,
+        }
+    }
+
+    pub fn events(&self) -> &EventHub {
+        &self.events
+    }
+
+    /// Apply an event observed at `at_ms` and return the new snapshot if it changed.
+    pub fn record(&self, event: StatusEvent, at_ms: u64) -> Option<StatusSnapshot> {
+        let mut state = self.state.lock().unwrap();
+        let mut changed = false;
+        match event {
+            StatusEvent::SessionStarted => {
+                state.session_active = true;
+                state.paused = false;
+            }
+            StatusEvent::SessionEnded => {
+                state.session_active = false;
+                state.paused = false;
+                state.last_progress_ms = None;
+                changed = state.snapshot.metadata.take().is_some();
+            }
+            StatusEvent::Paused => state.paused = true,
+            StatusEvent::Resumed => state.paused = false,
+            StatusEvent::Progress => state.last_progress_ms = Some(at_ms),
+            StatusEvent::MetadataStarted => {
+                changed = state.snapshot.metadata.take().is_some();
+            }
+            StatusEvent::Metadata(update) => {
+                let current = state.snapshot.metadata.get_or_insert(Metadata {
+                    artist: None,
+                    title: None,
+                    album: None,
+                });
+                let before = current.clone();
+                current.artist = update.artist.or(current.artist.take());
+                current.title = update.title.or(current.title.take());
+                current.album = update.album.or(current.album.take());
+                changed = *current != before;
+            }
+            StatusEvent::CalibrationStarted => state.calibrations += 1,
+            StatusEvent::CalibrationFinished => {
+                state.calibrations = state.calibrations.saturating_sub(1)
+            }
+            StatusEvent::Paired => {
+                changed = !state.snapshot.paired;
+                state.snapshot.paired = true;
+            }
+            StatusEvent::Failed(message) => {
+                changed = state.snapshot.error.as_ref() != Some(&message);
+                state.snapshot.error = Some(message);
+            }
+            StatusEvent::Recovered => changed = state.snapshot.error.take().is_some(),
+        }
+        changed |= state.settle(at_ms);
+        self.publish(&state, changed)
+    }
+
+    /// Whether an AirPlay session is currently open.
+    pub fn session_active(&self) -> bool {
+        self.state.lock().unwrap().session_active
+    }
+
+    /// Re-derive the status at `now_ms` so progress timeouts take effect even when no
+    /// further events arrive.
+    pub fn refresh(&self, now_ms: u64) -> StatusSnapshot {
+        let mut state = self.state.lock().unwrap();
+        let changed = state.settle(now_ms);
+        self.publish(&state, changed);
+        state.snapshot.clone()
+    }
+
+    /// The current status and a subscription to every change after it.
+    pub fn subscribe(&self) -> (StatusSnapshot, Subscription) {
+        let state = self.state.lock().unwrap();
+        let (_, updates) = self.events.subscribe(&[EventKind::Status]);
+        (state.snapshot.clone(), updates)
+    }
+
+    fn publish(&self, state: &TrackerState, changed: bool) -> Option<StatusSnapshot> {
+        if !changed {
+            return None;
+        }
+        // Published under the state lock so subscribers see transitions in order.
+        self.events.publish(ReceiverEvent::Status(state.snapshot.clone()));
+        Some(state.snapshot.clone())
+    }
+}
+
+/// Periodically refresh the tracker so subscribers observe progress timeouts.
+pub fn spawn_status_refresh(
+    tracker: StatusTracker,
+    period: Duration,
+    now_ms: fn() -> u64,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            tracker.refresh(now_ms());
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn title(value: &str) -> StatusEvent {
+        StatusEvent::Metadata(Metadata {
+            artist: None,
+            title: Some(value.into()),
+            album: None,
+        })
+    }
+
+    #[test]
+    fn starts_idle() {
+        let tracker = StatusTracker::new(1_000);
+        let snap = tracker.refresh(2_000);
+        assert_eq!(snap.status, PlaybackStatus::Idle);
+        assert_eq!(snap.since_ms, 1_000);
+        assert!(snap.metadata.is_none());
+    }
+
+    #[test]
+    fn session_start_and_end_toggle_playing() {
+        let tracker = StatusTracker::new(0);
+        let snap = tracker.record(StatusEvent::SessionStarted, 100).unwrap();
+        assert_eq!((snap.status, snap.since_ms), (PlaybackStatus::Playing, 100));
+        assert!(tracker.record(StatusEvent::Progress, 200).is_none());
+
+        let snap = tracker.record(StatusEvent::SessionEnded, 900).unwrap();
+        assert_eq!((snap.status, snap.since_ms), (PlaybackStatus::Idle, 900));
+    }
+
+    #[test]
+    fn pause_and_resume_are_published_as_status_updates() {
+        let tracker = StatusTracker::new(0);
+        tracker.record(StatusEvent::SessionStarted, 100);
+        tracker.record(title("Song"), 110);
+        let snap = tracker.record(StatusEvent::Paused, 200).unwrap();
+        assert_eq!((snap.status, snap.since_ms), (PlaybackStatus::Paused, 200));
+        assert_eq!(
+            snap.to_message(250),
+            WebSocketMessage::StatusUpdate {
+                timestamp: 250,
+                status: PlaybackStatus::Paused,
+                metadata: snap.metadata.clone(),
+            }
+        );
+        assert_eq!(tracker.refresh(200 + 2 * PROGRESS_TIMEOUT_MS).status, PlaybackStatus::Paused);
+
+        let snap = tracker.record(StatusEvent::Resumed, 300).unwrap();
+        assert_eq!((snap.status, snap.since_ms), (PlaybackStatus::Playing, 300));
+
+        tracker.record(StatusEvent::Paused, 400);
+        let snap = tracker.record(StatusEvent::SessionEnded, 500).unwrap();
+        assert_eq!(snap.status, PlaybackStatus::Idle);
+        let snap = tracker.record(StatusEvent::SessionStarted, 600).unwrap();
+        assert_eq!(snap.status, PlaybackStatus::Playing);
+    }
+
+    #[test]
+    fn progress_alone_counts_as_playing_until_timeout() {
+        let tracker = StatusTracker::new(0);
+        let snap = tracker.record(StatusEvent::Progress, 1_000).unwrap();
+        assert_eq!(snap.status, PlaybackStatus::Playing);
+
+        assert_eq!(tracker.refresh(1_000 + PROGRESS_TIMEOUT_MS).status, PlaybackStatus::Playing);
+        let snap = tracker.refresh(20_000);
+        assert_eq!(snap.status, PlaybackStatus::Idle);
+        assert_eq!(snap.since_ms, 1_000 + PROGRESS_TIMEOUT_MS);
+    }
+
+    #[test]
+    fn calibration_overrides_playing_and_restores_it() {
+        let tracker = StatusTracker::new(0);
+        tracker.record(StatusEvent::SessionStarted, 10);
+        let snap = tracker.record(StatusEvent::CalibrationStarted, 50).unwrap();
+        assert_eq!((snap.status, snap.since_ms), (PlaybackStatus::Calibrating, 50));
+
+        let snap = tracker.record(StatusEvent::CalibrationFinished, 80).unwrap();
+        assert_eq!((snap.status, snap.since_ms), (PlaybackStatus::Playing, 80));
+    }
+
+    #[test]
+    fn overlapping_calibrations_stay_calibrating_until_last_finishes() {
+        let tracker = StatusTracker::new(0);
+        tracker.record(StatusEvent::CalibrationStarted, 10);
+        tracker.record(StatusEvent::CalibrationStarted, 20);
+        assert!(tracker.record(StatusEvent::CalibrationFinished, 30).is_none());
+        let snap = tracker.record(StatusEvent::CalibrationFinished, 40).unwrap();
+        assert_eq!(snap.status, PlaybackStatus::Idle);
+        // A stray finish must not underflow.
+        assert!(tracker.record(StatusEvent::CalibrationFinished, 50).is_none());
+    }
+
+    #[test]
+    fn metadata_merges_and_clears_with_session() {
+        let tracker = StatusTracker::new(0);
+        tracker.record(StatusEvent::SessionStarted, 0);
+        tracker.record(title("Song"), 10);
+        let snap = tracker
+            .record(
+                StatusEvent::Metadata(Metadata {
+                    artist: Some("Band".into()),
+                    title: None,
+                    album: None,
+                }),
+                20,
+            )
+            .unwrap();
+        let metadata = snap.metadata.unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Song"));
+        assert_eq!(metadata.artist.as_deref(), Some("Band"));
+        assert_eq!(snap.since_ms, 0);
+
+        assert!(tracker.record(title("Song"), 30).is_none());
+        tracker.record(StatusEvent::MetadataStarted, 40);
+        assert!(tracker.refresh(40).metadata.is_none());
+
+        tracker.record(title("Other"), 50);
+        let snap = tracker.record(StatusEvent::SessionEnded, 60).unwrap();
+        assert!(snap.metadata.is_none());
+    }
+
+    #[test]
+    fn subscribers_see_the_same_transitions() {
+        let tracker = StatusTracker::new(0);
+        let (current, mut rx) = tracker.subscribe();
+        assert_eq!(current, tracker.refresh(0));
+        assert_eq!(rx.try_recv(), None);
+
+        let recorded = tracker.record(StatusEvent::SessionStarted, 5).unwrap();
+        assert_eq!(rx.try_recv(), Some(ReceiverEvent::Status(recorded)));
+
+        tracker.record(StatusEvent::Progress, 6);
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[test]
+    fn pairing_and_errors_are_published() {
+        let tracker = StatusTracker::new(0);
+        assert!(!tracker.refresh(0).paired);
+        assert!(tracker.record(StatusEvent::Paired, 1).unwrap().paired);
+        assert!(tracker.record(StatusEvent::Paired, 2).is_none());
+
+        let snap = tracker.record(StatusEvent::Failed("playback failed".into()), 3).unwrap();
+        assert_eq!(snap.error.as_deref(), Some("playback failed"));
+        assert_eq!(snap.status, PlaybackStatus::Idle);
+        assert!(tracker.record(StatusEvent::Failed("playback failed".into()), 4).is_none());
+        assert!(tracker.record(StatusEvent::Recovered, 5).unwrap().error.is_none());
+        assert!(tracker.record(StatusEvent::Recovered, 6).is_none());
+    }
+
+    #[test]
+    fn snapshot_converts_to_status_update_message() {
+        let tracker = StatusTracker::new(0);
+        let snap = tracker.record(StatusEvent::CalibrationStarted, 7).unwrap();
+        match snap.to_message(9) {
+            WebSocketMessage::StatusUpdate {
+                timestamp, status, ..
+            } => {
+                assert_eq!(timestamp, 9);
+                assert_eq!(status, PlaybackStatus::Calibrating);
+            }
+            other => panic!("unexpected message {other:?}"),
+        }
+    }
+}
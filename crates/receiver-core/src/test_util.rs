@@ -0,0 +1,669 @@
+//! Mock implementations of the receiver's extension traits, shared by the in-crate
+//! tests and, behind the `test-util` feature, by downstream integration tests.
+
+use crate::airplay::{AirplayTransportControl, ShairportConfig};
+use crate::calibration::schedule::{LoopbackCalibrator, LoopbackMeasurement};
+use crate::calibration::store::HistoryEntry;
+use crate::calibration::{ConfigWriter, ServiceState, ShairportController};
+use crate::config_changes::rendered_changed_keys;
+use crate::firmware::FirmwareFetcher;
+use crate::group::BoxFuture;
+use crate::hardware::{SystemReaders, ThermalSources};
+use crate::network::{InterfaceAddress, NetworkInfoProvider};
+use crate::http::{
+    CalibrationApplyResponse, CalibrationSink, ConfigStore, PlaybackRequest, PlaybackSink,
+    SettingsManager, SettingsUpdatePayload,
+};
+use airsync_shared_protocol::{CalibrationSubmission, OutputDeviceSpec};
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Records the last submission and echoes its latency back as the applied offset.
+/// `with_delay` makes each apply take that long, like a shairport restart.
+#[derive(Clone)]
+pub struct MockCalibrationSink {
+    last: Arc<Mutex<Option<CalibrationSubmission>>>,
+    delay: Duration,
+}
+
+impl MockCalibrationSink {
+    pub fn new() -> Self {
+        Self {
+            last: Arc::new(Mutex::new(None)),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn with_delay(delay: Duration) -> Self {
+        Self {
+            delay,
+            ..Self::new()
+        }
+    }
+
+    pub fn last(&self) -> Option<CalibrationSubmission> {
+        self.last.lock().unwrap().clone()
+    }
+}
+
+impl Default for MockCalibrationSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CalibrationSink for MockCalibrationSink {
+    fn apply<'a>(&'a self, submission: &'a CalibrationSubmission) -> BoxFuture<'a, Result<CalibrationApplyResponse>> {
+        Box::pin(async move {
+            if !self.delay.is_zero() {
+                tokio::time::sleep(self.delay).await;
+            }
+            *self.last.lock().unwrap() = Some(submission.clone());
+            Ok(CalibrationApplyResponse {
+                measured_latency_ms: submission.latency_ms,
+                applied_offset_ms: submission.latency_ms,
+                was_clamped: false,
+                output_device: OutputDeviceSpec::hw(0, 0),
+                config_generation: 0,
+                changed_keys: Vec::new(),
+            })
+        })
+    }
+
+    fn replay<'a>(&'a self, history: &'a [HistoryEntry]) -> BoxFuture<'a, Result<CalibrationApplyResponse>> {
+        let replayed = history.last().map(|entry| CalibrationApplyResponse {
+            measured_latency_ms: entry.applied.measured_latency_ms,
+            applied_offset_ms: entry.applied.measured_latency_ms,
+            was_clamped: false,
+            output_device: entry.config_snapshot.output_device.clone(),
+            config_generation: 0,
+            changed_keys: Vec::new(),
+        });
+        Box::pin(std::future::ready(replayed.ok_or_else(|| anyhow!("no calibration history to replay"))))
+    }
+}
+
+/// Counts playback requests and remembers the last one; `failing()` errors on every call.
+#[derive(Clone)]
+pub struct MockPlaybackSink {
+    last: Arc<Mutex<Option<PlaybackRequest>>>,
+    calls: Arc<Mutex<u32>>,
+    fail: bool,
+    output_rate: Option<u32>,
+    output_gain: Option<f32>,
+    delay: Duration,
+    clock: fn() -> u64,
+}
+
+impl MockPlaybackSink {
+    pub fn new() -> Self {
+        Self {
+            last: Arc::new(Mutex::new(None)),
+            calls: Arc::new(Mutex::new(0)),
+            fail: false,
+            output_rate: None,
+            output_gain: None,
+            delay: Duration::ZERO,
+            clock: crate::http::now_millis,
+        }
+    }
+
+    /// Report playback starts read from `clock` instead of the wall clock, e.g. one
+    /// following paused tokio time.
+    pub fn reporting_clock(mut self, clock: fn() -> u64) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Report feeding the output at `rate`, as a sink resampling for it would.
+    pub fn with_output_rate(rate: u32) -> Self {
+        Self {
+            output_rate: Some(rate),
+            ..Self::new()
+        }
+    }
+
+    /// Take `delay` to play each request, like a player blocking until the audio is out.
+    pub fn with_delay(delay: Duration) -> Self {
+        Self {
+            delay,
+            ..Self::new()
+        }
+    }
+
+    /// Report playing every request at `gain`, as a sink scaling for the output would.
+    pub fn with_output_gain(gain: f32) -> Self {
+        Self {
+            output_gain: Some(gain),
+            ..Self::new()
+        }
+    }
+
+    pub fn failing() -> Self {
+        Self {
+            fail: true,
+            ..Self::new()
+        }
+    }
+
+    pub fn last(&self) -> Option<PlaybackRequest> {
+        self.last.lock().unwrap().clone()
+    }
+
+    pub fn call_count(&self) -> u32 {
+        *self.calls.lock().unwrap()
+    }
+}
+
+impl Default for MockPlaybackSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PlaybackSink for MockPlaybackSink {
+    fn play(&self, request: &PlaybackRequest) -> Result<()> {
+        *self.calls.lock().unwrap() += 1;
+        *self.last.lock().unwrap() = Some(request.clone());
+        std::thread::sleep(self.delay);
+        if self.fail {
+            return Err(anyhow!("fail"));
+        }
+        Ok(())
+    }
+
+    fn play_reporting_start(&self, request: &PlaybackRequest, started: &dyn Fn(u64)) -> Result<()> {
+        started((self.clock)());
+        self.play(request)
+    }
+
+    fn output_rate(&self) -> Option<u32> {
+        self.output_rate
+    }
+
+    fn output_gain(&self, _request: &PlaybackRequest) -> Option<f32> {
+        self.output_gain
+    }
+}
+
+/// In-memory settings that count how many times shairport would have been restarted.
+/// `with_delay` makes each update wait first, like a shairport restart; `held_until` makes
+/// it wait for a release instead.
+#[derive(Clone)]
+pub struct MockSettingsManager {
+    cfg: ConfigStore,
+    restarts: Arc<Mutex<u32>>,
+    delay: Duration,
+    release: Option<Arc<tokio::sync::Notify>>,
+}
+
+impl MockSettingsManager {
+    pub fn new() -> Self {
+        Self::with_store(ConfigStore::new(ShairportConfig {
+            device_name: "AirSync".into(),
+            output_device: OutputDeviceSpec::hw(0, 0),
+            latency_offset_seconds: 0.0,
+            calibration_gain: None,
+            eq: None,
+            self_calibration: None,
+        }))
+    }
+
+    pub fn with_store(cfg: ConfigStore) -> Self {
+        Self {
+            cfg,
+            restarts: Arc::new(Mutex::new(0)),
+            delay: Duration::ZERO,
+            release: None,
+        }
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Hold each update until `release` is notified.
+    pub fn held_until(mut self, release: Arc<tokio::sync::Notify>) -> Self {
+        self.release = Some(release);
+        self
+    }
+
+    pub fn store(&self) -> ConfigStore {
+        self.cfg.clone()
+    }
+
+    pub fn restart_calls(&self) -> u32 {
+        *self.restarts.lock().unwrap()
+    }
+}
+
+impl Default for MockSettingsManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SettingsManager for MockSettingsManager {
+    fn current(&self) -> ShairportConfig {
+        self.cfg.current()
+    }
+
+    fn update(&self, update: SettingsUpdatePayload) -> BoxFuture<'_, Result<(ShairportConfig, Vec<String>)>> {
+        Box::pin(async move {
+            if !self.delay.is_zero() {
+                tokio::time::sleep(self.delay).await;
+            }
+            if let Some(release) = &self.release {
+                release.notified().await;
+            }
+            let previous = self.cfg.current();
+            let (cfg, _) = self.cfg.update_with(|current| Ok(update.merge(current)))?;
+            *self.restarts.lock().unwrap() += 1;
+            let changed_keys = rendered_changed_keys(&previous, &cfg);
+            Ok((cfg, changed_keys))
+        })
+    }
+
+    fn generation(&self) -> u64 {
+        self.cfg.generation()
+    }
+
+    fn save(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.cfg.save())
+    }
+}
+
+/// Captures the last rendered config instead of writing it to disk. `with_delay` makes
+/// each write block its thread first, like a slow SD card.
+#[derive(Clone)]
+pub struct MockWriter {
+    contents: Arc<Mutex<Option<String>>>,
+    delay: Duration,
+}
+
+impl MockWriter {
+    pub fn new() -> Self {
+        Self {
+            contents: Arc::new(Mutex::new(None)),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn last_contents(&self) -> Option<String> {
+        self.contents.lock().unwrap().clone()
+    }
+}
+
+impl Default for MockWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfigWriter for MockWriter {
+    fn write(&self, contents: &str) -> Result<()> {
+        std::thread::sleep(self.delay);
+        *self.contents.lock().unwrap() = Some(contents.to_string());
+        Ok(())
+    }
+
+    fn read_current(&self) -> Result<Option<String>> {
+        Ok(self.last_contents())
+    }
+}
+
+/// Counts restart requests instead of calling systemctl, keeping blocking `restart`
+/// calls apart so tests can assert the async path never takes them. `failing()` makes
+/// every restart return an error; `with_statuses` scripts what `status` reports, one
+/// entry per call, repeating the last (`Active` when unscripted).
+#[derive(Clone)]
+pub struct MockController {
+    restart_calls: Arc<Mutex<u32>>,
+    blocking_calls: Arc<Mutex<u32>>,
+    error: Option<String>,
+    statuses: Arc<Mutex<VecDeque<ServiceState>>>,
+}
+
+impl MockController {
+    pub fn new() -> Self {
+        Self {
+            restart_calls: Arc::new(Mutex::new(0)),
+            blocking_calls: Arc::new(Mutex::new(0)),
+            error: None,
+            statuses: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    pub fn with_statuses(self, statuses: impl IntoIterator<Item = ServiceState>) -> Self {
+        *self.statuses.lock().unwrap() = statuses.into_iter().collect();
+        self
+    }
+
+    pub fn failing(message: &str) -> Self {
+        Self {
+            error: Some(message.to_string()),
+            ..Self::new()
+        }
+    }
+
+    /// Restarts requested through either path.
+    pub fn calls(&self) -> u32 {
+        *self.restart_calls.lock().unwrap()
+    }
+
+    /// Restarts requested through the blocking `restart`.
+    pub fn blocking_calls(&self) -> u32 {
+        *self.blocking_calls.lock().unwrap()
+    }
+
+    fn result(&self) -> Result<()> {
+        *self.restart_calls.lock().unwrap() += 1;
+        match &self.error {
+            Some(message) => Err(anyhow!("{message}")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for MockController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShairportController for MockController {
+    fn restart(&self) -> Result<()> {
+        *self.blocking_calls.lock().unwrap() += 1;
+        self.result()
+    }
+
+    fn restart_async(self: Arc<Self>) -> BoxFuture<'static, Result<()>> {
+        Box::pin(async move { self.result() })
+    }
+
+    fn status(self: Arc<Self>) -> BoxFuture<'static, Result<ServiceState>> {
+        let mut statuses = self.statuses.lock().unwrap();
+        let state = match statuses.len() {
+            0 => ServiceState::Active,
+            1 => statuses[0],
+            _ => statuses.pop_front().unwrap(),
+        };
+        Box::pin(std::future::ready(Ok(state)))
+    }
+}
+
+/// Returns scripted measurements, one per call, repeating the last; an `Err` entry is a
+/// missed measurement with that message. Records the amplitude of every call.
+#[derive(Clone)]
+pub struct MockLoopbackCalibrator {
+    results: Arc<Mutex<VecDeque<Result<LoopbackMeasurement, String>>>>,
+    amplitudes: Arc<Mutex<Vec<f32>>>,
+}
+
+impl MockLoopbackCalibrator {
+    pub fn new(results: impl IntoIterator<Item = Result<LoopbackMeasurement, String>>) -> Self {
+        Self {
+            results: Arc::new(Mutex::new(results.into_iter().collect())),
+            amplitudes: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn amplitudes(&self) -> Vec<f32> {
+        self.amplitudes.lock().unwrap().clone()
+    }
+}
+
+impl LoopbackCalibrator for MockLoopbackCalibrator {
+    fn measure(&self, amplitude: f32) -> BoxFuture<'_, Result<LoopbackMeasurement>> {
+        self.amplitudes.lock().unwrap().push(amplitude);
+        let mut results = self.results.lock().unwrap();
+        let result = match results.len() {
+            0 => Err("no measurement scripted".to_string()),
+            1 => results[0].clone(),
+            _ => results.pop_front().unwrap(),
+        };
+        Box::pin(std::future::ready(result.map_err(|message| anyhow!("{message}"))))
+    }
+}
+
+/// Records `pause`/`resume` calls into an event log that can be shared with other mocks,
+/// so tests can assert ordering against playback. `failing()` errors on every pause.
+#[derive(Clone)]
+pub struct MockTransportControl {
+    events: Arc<Mutex<Vec<&'static str>>>,
+    fail_pause: bool,
+}
+
+impl MockTransportControl {
+    pub fn new() -> Self {
+        Self {
+            events: Arc::new(Mutex::new(Vec::new())),
+            fail_pause: false,
+        }
+    }
+
+    pub fn failing() -> Self {
+        Self {
+            fail_pause: true,
+            ..Self::new()
+        }
+    }
+
+    /// The shared log; push other events into it to check where they fall.
+    pub fn log(&self) -> Arc<Mutex<Vec<&'static str>>> {
+        self.events.clone()
+    }
+
+    pub fn events(&self) -> Vec<&'static str> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl Default for MockTransportControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AirplayTransportControl for MockTransportControl {
+    fn pause(&self) -> BoxFuture<'_, Result<()>> {
+        let result = if self.fail_pause {
+            Err(anyhow!("pause failed"))
+        } else {
+            self.events.lock().unwrap().push("pause");
+            Ok(())
+        };
+        Box::pin(std::future::ready(result))
+    }
+
+    fn resume(&self) -> BoxFuture<'_, Result<()>> {
+        self.events.lock().unwrap().push("resume");
+        Box::pin(std::future::ready(Ok(())))
+    }
+}
+
+/// Records LED state changes as `(ms since creation, on)` on the tokio clock, so pattern
+/// timing can be checked with paused time. `failing()` errors on every set.
+#[cfg(feature = "led")]
+#[derive(Clone)]
+pub struct MockStatusLed {
+    started: tokio::time::Instant,
+    transitions: Arc<Mutex<Vec<(u64, bool)>>>,
+    attempts: Arc<Mutex<u32>>,
+    fail: bool,
+}
+
+#[cfg(feature = "led")]
+impl MockStatusLed {
+    pub fn new() -> Self {
+        Self {
+            started: tokio::time::Instant::now(),
+            transitions: Arc::new(Mutex::new(Vec::new())),
+            attempts: Arc::new(Mutex::new(0)),
+            fail: false,
+        }
+    }
+
+    pub fn failing() -> Self {
+        Self {
+            fail: true,
+            ..Self::new()
+        }
+    }
+
+    pub fn transitions(&self) -> Vec<(u64, bool)> {
+        self.transitions.lock().unwrap().clone()
+    }
+
+    pub fn attempts(&self) -> u32 {
+        *self.attempts.lock().unwrap()
+    }
+}
+
+#[cfg(feature = "led")]
+impl Default for MockStatusLed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "led")]
+impl crate::hardware::StatusLed for MockStatusLed {
+    fn set(&self, on: bool) -> Result<()> {
+        *self.attempts.lock().unwrap() += 1;
+        if self.fail {
+            return Err(anyhow!("gpio unavailable"));
+        }
+        let mut transitions = self.transitions.lock().unwrap();
+        if transitions.last().map(|&(_, last)| last) != Some(on) {
+            transitions.push((self.started.elapsed().as_millis() as u64, on));
+        }
+        Ok(())
+    }
+}
+
+/// Canned `/proc` and ALSA contents for hardware detection.
+#[derive(Clone, Default)]
+pub struct MockSystemReaders {
+    pub cpu_info: String,
+    pub mem_info: String,
+    pub device_tree: Option<String>,
+    pub alsa_devices: String,
+    /// sysfs modalias keyed by ALSA card index.
+    pub card_modaliases: HashMap<u32, String>,
+    pub thermal: ThermalSources,
+    /// `df -B1M -T` output; empty reads as unparsable.
+    pub df_output: String,
+    pub os_release: String,
+    /// `/proc/version`; empty reads as unparsable.
+    pub kernel_version: String,
+}
+
+impl SystemReaders for MockSystemReaders {
+    fn read_cpu_info(&self) -> Result<String> {
+        Ok(self.cpu_info.clone())
+    }
+
+    fn read_mem_info(&self) -> Result<String> {
+        Ok(self.mem_info.clone())
+    }
+
+    fn read_device_tree(&self) -> Result<Option<String>> {
+        Ok(self.device_tree.clone())
+    }
+
+    fn list_alsa_devices(&self) -> Result<String> {
+        Ok(self.alsa_devices.clone())
+    }
+
+    fn read_card_modalias(&self, index: u32) -> Result<Option<String>> {
+        Ok(self.card_modaliases.get(&index).cloned())
+    }
+
+    fn read_thermal(&self) -> Result<ThermalSources> {
+        Ok(self.thermal.clone())
+    }
+
+    fn read_df_output(&self) -> Result<String> {
+        Ok(self.df_output.clone())
+    }
+
+    fn read_os_release(&self) -> Result<String> {
+        Ok(self.os_release.clone())
+    }
+
+    fn read_kernel_version(&self) -> Result<String> {
+        Ok(self.kernel_version.clone())
+    }
+}
+
+/// Fixed interface list given as `(name, address)` pairs; `failing()` can't list any.
+#[derive(Clone, Default)]
+pub struct MockNetworkInfo {
+    addresses: Option<Vec<InterfaceAddress>>,
+}
+
+impl MockNetworkInfo {
+    pub fn new<'a>(addresses: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        Self {
+            addresses: Some(
+                addresses
+                    .into_iter()
+                    .map(|(name, addr)| InterfaceAddress {
+                        name: name.into(),
+                        addr: addr.parse().expect("valid IP address"),
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn failing() -> Self {
+        Self { addresses: None }
+    }
+}
+
+impl NetworkInfoProvider for MockNetworkInfo {
+    fn addresses(&self) -> Result<Vec<InterfaceAddress>> {
+        self.addresses.clone().ok_or_else(|| anyhow!("getifaddrs failed"))
+    }
+}
+
+/// "Downloads" fixed bytes, recording each URL fetched.
+#[derive(Clone)]
+pub struct MockFirmwareFetcher {
+    image: Vec<u8>,
+    fetched: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockFirmwareFetcher {
+    pub fn new(image: impl Into<Vec<u8>>) -> Self {
+        Self {
+            image: image.into(),
+            fetched: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn fetched(&self) -> Vec<String> {
+        self.fetched.lock().unwrap().clone()
+    }
+}
+
+impl FirmwareFetcher for MockFirmwareFetcher {
+    fn fetch<'a>(&'a self, url: &'a str, dest: &'a Path) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.fetched.lock().unwrap().push(url.to_string());
+            std::fs::write(dest, &self.image)?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,133 @@
+use crate::peer_client;
+use airsync_shared_protocol::{estimate_offset, TimeSample, TimeSyncResponse};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Exchanges per measurement; the slower half is discarded by `estimate_offset`.
+pub const TIMESYNC_ROUNDS: usize = 8;
+/// How long a measurement is served from cache before it is taken again.
+pub const TIMESYNC_TTL_MS: u64 = 60_000;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Clock offset of a peer relative to this receiver (peer minus local), as served by
+/// `GET /api/peers/{id}/timesync`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PeerTimeSync {
+    pub offset_ms: f32,
+    pub rtt_ms: f32,
+    pub stddev_ms: f32,
+    pub sampled_at: u64,
+}
+
+/// Resolve `http://host:port[/...]` to a socket address.
+fn peer_addr(url: &str) -> Result<SocketAddr> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow!("unsupported peer url {url}"))?;
+    let authority = rest.split('/').next().unwrap_or(rest);
+    authority
+        .to_socket_addrs()
+        .with_context(|| format!("resolving {authority}"))?
+        .next()
+        .ok_or_else(|| anyhow!("{authority} did not resolve"))
+}
+
+/// Run `rounds` four-timestamp exchanges against the peer's `/api/time` and reduce them
+/// with `estimate_offset`. `now_ms` is this receiver's clock.
+pub async fn measure_peer(url: &str, rounds: usize, now_ms: fn() -> u64) -> Result<PeerTimeSync> {
+    let addr = peer_addr(url)?;
+    let mut samples = Vec::with_capacity(rounds);
+    for _ in 0..rounds {
+        let t0 = now_ms();
+        let response = peer_client::request(addr, "GET", &format!("/api/time?t0={t0}"), None, PROBE_TIMEOUT).await?;
+        let t3 = now_ms();
+        if response.status != 200 {
+            return Err(anyhow!("{url}/api/time answered {}", response.status));
+        }
+        let body: TimeSyncResponse = serde_json::from_slice(&response.body)?;
+        if body.client_send_ms.is_some_and(|echo| echo != t0) {
+            return Err(anyhow!("{url}/api/time echoed the wrong t0"));
+        }
+        samples.push(TimeSample::from_response(t0, &body, t3));
+    }
+    let estimate = estimate_offset(&samples).ok_or_else(|| anyhow!("no time samples taken"))?;
+    Ok(PeerTimeSync {
+        offset_ms: estimate.offset_ms,
+        rtt_ms: estimate.rtt_ms,
+        stddev_ms: estimate.stddev_ms,
+        sampled_at: now_ms(),
+    })
+}
+
+/// Last measurement per peer, served until it is `ttl_ms` old.
+#[derive(Clone)]
+pub struct TimeSyncCache {
+    ttl_ms: u64,
+    entries: Arc<Mutex<HashMap<String, PeerTimeSync>>>,
+}
+
+impl TimeSyncCache {
+    pub fn new(ttl_ms: u64) -> Self {
+        Self {
+            ttl_ms,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn fresh(&self, receiver_id: &str, now_ms: u64) -> Option<PeerTimeSync> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(receiver_id)
+            .filter(|sync| now_ms.saturating_sub(sync.sampled_at) < self.ttl_ms)
+            .copied()
+    }
+
+    pub fn insert(&self, receiver_id: &str, sync: PeerTimeSync) {
+        self.entries.lock().unwrap().insert(receiver_id.to_string(), sync);
+    }
+}
+
+impl Default for TimeSyncCache {
+    fn default() -> Self {
+        Self::new(TIMESYNC_TTL_MS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sync(sampled_at: u64) -> PeerTimeSync {
+        PeerTimeSync {
+            offset_ms: 1.0,
+            rtt_ms: 2.0,
+            stddev_ms: 0.5,
+            sampled_at,
+        }
+    }
+
+    #[test]
+    fn cache_expires_after_ttl() {
+        let cache = TimeSyncCache::new(1_000);
+        cache.insert("rx-b", sync(5_000));
+        assert_eq!(cache.fresh("rx-b", 5_999), Some(sync(5_000)));
+        assert_eq!(cache.fresh("rx-b", 6_000), None);
+        assert_eq!(cache.fresh("rx-c", 5_000), None);
+    }
+
+    #[test]
+    fn parses_peer_urls() {
+        assert_eq!(
+            peer_addr("http://192.168.1.20:5000").unwrap(),
+            "192.168.1.20:5000".parse().unwrap()
+        );
+        assert_eq!(peer_addr("http://[::1]:5000/api").unwrap(), "[::1]:5000".parse().unwrap());
+        assert!(peer_addr("https://192.168.1.20:5000").is_err());
+    }
+}
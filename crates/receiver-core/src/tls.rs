@@ -0,0 +1,355 @@
+//! HTTPS next to the plain listener. Receivers have no CA to vouch for them, so each
+//! serves a self-signed certificate kept in its state dir and publishes the certificate's
+//! SHA-256 fingerprint (in `/api/receiver/info` and the `tlsfp` TXT record) for clients
+//! to pin the first time they connect.
+
+use crate::group::BoxFuture;
+use crate::http::CertificateRotator;
+use airsync_shared_protocol::TlsEndpoint;
+use anyhow::{anyhow, bail, Context, Result};
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const TLS_CERT_FILE: &str = "tls-cert.pem";
+pub const TLS_KEY_FILE: &str = "tls-key.pem";
+
+/// A certificate and its private key, as served over HTTPS.
+#[derive(Clone)]
+pub struct TlsIdentity {
+    cert_pem: String,
+    key_pem: String,
+    fingerprint: String,
+}
+
+impl TlsIdentity {
+    /// A new self-signed certificate valid for `names` (hostnames or addresses).
+    pub fn generate(names: Vec<String>) -> Result<Self> {
+        let certified = rcgen::generate_simple_self_signed(names).context("generating TLS certificate")?;
+        Ok(Self {
+            fingerprint: fingerprint(certified.cert.der()),
+            cert_pem: certified.cert.pem(),
+            key_pem: certified.key_pair.serialize_pem(),
+        })
+    }
+
+    pub fn from_pem(cert_pem: String, key_pem: String) -> Result<Self> {
+        let cert = CertificateDer::from_pem_slice(cert_pem.as_bytes()).context("parsing TLS certificate")?;
+        PrivateKeyDer::from_pem_slice(key_pem.as_bytes()).context("parsing TLS key")?;
+        Ok(Self {
+            fingerprint: fingerprint(&cert),
+            cert_pem,
+            key_pem,
+        })
+    }
+
+    pub fn load(state_dir: &Path) -> Result<Self> {
+        let read = |name: &str| {
+            let path = state_dir.join(name);
+            std::fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))
+        };
+        Self::from_pem(read(TLS_CERT_FILE)?, read(TLS_KEY_FILE)?)
+    }
+
+    /// Write the certificate and key into `state_dir`, each replaced by a rename so a
+    /// crash can't leave a half-written file. The key is readable by its owner only.
+    pub fn save(&self, state_dir: &Path) -> Result<()> {
+        write_replacing(state_dir, TLS_KEY_FILE, &self.key_pem, 0o600)?;
+        write_replacing(state_dir, TLS_CERT_FILE, &self.cert_pem, 0o644)
+    }
+
+    /// The identity saved in `state_dir`, or a new one for `names` saved there when there
+    /// is none or it can't be read.
+    pub fn load_or_generate(state_dir: &Path, names: Vec<String>) -> Result<Self> {
+        match Self::load(state_dir) {
+            Ok(identity) => Ok(identity),
+            Err(err) => {
+                if state_dir.join(TLS_CERT_FILE).exists() {
+                    eprintln!("[tls] replacing unreadable certificate: {err:#}");
+                }
+                Self::regenerate(state_dir, names)
+            }
+        }
+    }
+
+    /// Replace the identity saved in `state_dir` with a new one. Clients that pinned the
+    /// old fingerprint have to pin the new one.
+    pub fn regenerate(state_dir: &Path, names: Vec<String>) -> Result<Self> {
+        let identity = Self::generate(names)?;
+        identity.save(state_dir)?;
+        eprintln!("[tls] generated certificate sha256={}", identity.fingerprint);
+        Ok(identity)
+    }
+
+    /// Lowercase hex SHA-256 of the certificate's DER encoding.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    pub fn endpoint(&self, port: u16) -> TlsEndpoint {
+        TlsEndpoint {
+            port,
+            fingerprint: self.fingerprint.clone(),
+        }
+    }
+
+    pub fn server_config(&self) -> Result<ServerConfig> {
+        let cert = CertificateDer::from_pem_slice(self.cert_pem.as_bytes())?;
+        let key = PrivateKeyDer::from_pem_slice(self.key_pem.as_bytes())?;
+        let mut config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+/// Lowercase hex SHA-256 of a DER-encoded certificate.
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn write_replacing(dir: &Path, name: &str, contents: &str, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = dir.join(name);
+    let mut staged = tempfile::NamedTempFile::new_in(dir).with_context(|| format!("writing {}", path.display()))?;
+    staged.write_all(contents.as_bytes())?;
+    staged.as_file().set_permissions(std::fs::Permissions::from_mode(mode))?;
+    staged.as_file().sync_all()?;
+    staged
+        .persist(&path)
+        .map_err(|err| anyhow!(err.error).context(format!("writing {}", path.display())))?;
+    Ok(())
+}
+
+/// Serve `router` over HTTPS on `addr` with `identity`'s certificate.
+pub async fn serve_tls(router: Router, addr: SocketAddr, identity: &TlsIdentity) -> Result<()> {
+    let listener = std::net::TcpListener::bind(addr).context("bind")?;
+    serve_tls_listener(router, listener, identity).await
+}
+
+/// Serve `router` over HTTPS on an already bound `listener`.
+pub async fn serve_tls_listener(router: Router, listener: std::net::TcpListener, identity: &TlsIdentity) -> Result<()> {
+    serve_rustls(router, listener, RustlsConfig::from_config(Arc::new(identity.server_config()?))).await
+}
+
+async fn serve_rustls(router: Router, listener: std::net::TcpListener, config: RustlsConfig) -> Result<()> {
+    listener.set_nonblocking(true)?;
+    axum_server::from_tcp_rustls(listener, config)
+        .serve(router.into_make_service())
+        .await
+        .context("serve")
+}
+
+/// The certificate being served, which `/admin/tls/regenerate` replaces without
+/// restarting the listener. New connections get the new certificate.
+#[derive(Clone)]
+pub struct ReloadableIdentity {
+    state_dir: PathBuf,
+    names: Vec<String>,
+    config: RustlsConfig,
+}
+
+impl ReloadableIdentity {
+    /// Serve `identity`, regenerating into `state_dir` for `names`.
+    pub fn new(identity: &TlsIdentity, state_dir: impl Into<PathBuf>, names: Vec<String>) -> Result<Self> {
+        Ok(Self {
+            state_dir: state_dir.into(),
+            names,
+            config: RustlsConfig::from_config(Arc::new(identity.server_config()?)),
+        })
+    }
+
+    pub async fn serve(&self, router: Router, addr: SocketAddr) -> Result<()> {
+        self.serve_listener(router, std::net::TcpListener::bind(addr).context("bind")?).await
+    }
+
+    pub async fn serve_listener(&self, router: Router, listener: std::net::TcpListener) -> Result<()> {
+        serve_rustls(router, listener, self.config.clone()).await
+    }
+}
+
+impl CertificateRotator for ReloadableIdentity {
+    fn regenerate(&self) -> BoxFuture<'_, Result<String>> {
+        Box::pin(async move {
+            let (state_dir, names) = (self.state_dir.clone(), self.names.clone());
+            let identity = tokio::task::spawn_blocking(move || TlsIdentity::regenerate(&state_dir, names)).await??;
+            self.config.reload_from_config(Arc::new(identity.server_config()?));
+            Ok(identity.fingerprint)
+        })
+    }
+}
+
+/// Accepts exactly the server certificate with the pinned fingerprint, whatever name or
+/// issuer it carries, which is how clients trust a receiver's self-signed certificate.
+#[derive(Debug)]
+pub struct FingerprintVerifier {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl FingerprintVerifier {
+    pub fn new(fingerprint: &str) -> Self {
+        Self {
+            fingerprint: fingerprint.trim().to_ascii_lowercase(),
+            provider: provider(),
+        }
+    }
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("certificate fingerprint doesn't match the pinned one".into()))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Client config trusting only the certificate with `fingerprint`.
+pub fn pinned_client_config(fingerprint: &str) -> Result<ClientConfig> {
+    if fingerprint.trim().len() != 64 {
+        bail!("expected a 64-digit SHA-256 fingerprint, got {fingerprint:?}");
+    }
+    Ok(ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(FingerprintVerifier::new(fingerprint)))
+        .with_no_client_auth())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    async fn spawn_server(identity: &TlsIdentity) -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().route("/api/health", get(|| async { "ok" }));
+        let identity = identity.clone();
+        tokio::spawn(async move { serve_tls_listener(router, listener, &identity).await.unwrap() });
+        addr
+    }
+
+    async fn get_health(addr: SocketAddr, fingerprint: &str) -> std::io::Result<String> {
+        let connector = TlsConnector::from(Arc::new(pinned_client_config(fingerprint).unwrap()));
+        let tcp = tokio::net::TcpStream::connect(addr).await?;
+        let name = ServerName::try_from("airsync.local").unwrap();
+        let mut tls = connector.connect(name, tcp).await?;
+        tls.write_all(b"GET /api/health HTTP/1.1\r\nHost: airsync.local\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        tls.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn clients_pinning_the_published_fingerprint_complete_the_handshake() {
+        let identity = TlsIdentity::generate(vec!["localhost".into()]).unwrap();
+        let addr = spawn_server(&identity).await;
+
+        let response = get_health(addr, identity.fingerprint()).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("ok"));
+
+        let other = TlsIdentity::generate(vec!["localhost".into()]).unwrap();
+        let err = get_health(addr, other.fingerprint()).await.unwrap_err();
+        assert!(err.to_string().contains("fingerprint"), "{err}");
+    }
+
+    #[test]
+    fn identity_is_kept_across_restarts_until_regenerated() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let first = TlsIdentity::load_or_generate(dir.path(), vec!["rx.local".into()]).unwrap();
+        assert_eq!(first.fingerprint().len(), 64);
+        let mode = std::fs::metadata(dir.path().join(TLS_KEY_FILE)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let reloaded = TlsIdentity::load_or_generate(dir.path(), vec!["rx.local".into()]).unwrap();
+        assert_eq!(reloaded.fingerprint(), first.fingerprint());
+
+        let regenerated = TlsIdentity::regenerate(dir.path(), vec!["rx.local".into()]).unwrap();
+        assert_ne!(regenerated.fingerprint(), first.fingerprint());
+        assert_eq!(TlsIdentity::load(dir.path()).unwrap().fingerprint(), regenerated.fingerprint());
+
+        std::fs::write(dir.path().join(TLS_CERT_FILE), "not a certificate").unwrap();
+        let replaced = TlsIdentity::load_or_generate(dir.path(), vec!["rx.local".into()]).unwrap();
+        assert_ne!(replaced.fingerprint(), regenerated.fingerprint());
+    }
+
+    #[tokio::test]
+    async fn regenerated_certificates_are_served_without_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = vec!["localhost".to_string()];
+        let first = TlsIdentity::load_or_generate(dir.path(), names.clone()).unwrap();
+        let served = ReloadableIdentity::new(&first, dir.path(), names).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().route("/api/health", get(|| async { "ok" }));
+        let server = served.clone();
+        tokio::spawn(async move { server.serve_listener(router, listener).await.unwrap() });
+        assert!(get_health(addr, first.fingerprint()).await.unwrap().ends_with("ok"));
+
+        let fingerprint = served.regenerate().await.unwrap();
+        assert_ne!(fingerprint, first.fingerprint());
+        assert_eq!(TlsIdentity::load(dir.path()).unwrap().fingerprint(), fingerprint);
+        assert!(get_health(addr, first.fingerprint()).await.is_err());
+        assert!(get_health(addr, &fingerprint).await.unwrap().ends_with("ok"));
+    }
+
+    #[test]
+    fn pinned_config_needs_a_full_fingerprint() {
+        assert!(pinned_client_config("abcd").is_err());
+        assert!(pinned_client_config(&"AB".repeat(32)).is_ok());
+    }
+}
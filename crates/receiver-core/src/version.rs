@@ -0,0 +1,83 @@
+use axum::http::{HeaderName, HeaderValue};
+use axum::response::Response;
+use serde::{Deserialize, Serialize};
+
+/// Response header carrying [`VersionInfo::label`], so any reply identifies the firmware.
+pub static VERSION_HEADER: HeaderName = HeaderName::from_static("x-airsync-version");
+
+/// The crate version and, when built from a git checkout, its `git describe`.
+pub const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_DESCRIBE: Option<&str> = option_env!("AIRSYNC_GIT_DESCRIBE");
+/// Target triple the binary was compiled for.
+pub const BUILD_TARGET: &str = env!("AIRSYNC_BUILD_TARGET");
+
+/// What `/api/version` reports.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionInfo {
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_describe: Option<String>,
+    /// Seconds since the Unix epoch; `SOURCE_DATE_EPOCH` when it was set at build time.
+    pub build_timestamp: u64,
+    pub target: String,
+}
+
+impl VersionInfo {
+    pub fn current() -> Self {
+        Self {
+            version: PKG_VERSION.to_string(),
+            git_describe: GIT_DESCRIBE.map(String::from),
+            build_timestamp: env!("AIRSYNC_BUILD_TIMESTAMP").parse().unwrap_or(0),
+            target: BUILD_TARGET.to_string(),
+        }
+    }
+
+    /// `0.1.0`, or `0.1.0+<git describe>` from a git checkout. Sent in the version
+    /// header and the `fw` TXT record.
+    pub fn label(&self) -> String {
+        match &self.git_describe {
+            Some(describe) => format!("{}+{describe}", self.version),
+            None => self.version.clone(),
+        }
+    }
+
+    /// One line for the startup log.
+    pub fn banner(&self) -> String {
+        format!(
+            "AirSync receiver {} ({}, built at {})",
+            self.label(),
+            self.target,
+            self.build_timestamp
+        )
+    }
+}
+
+/// Adds [`VERSION_HEADER`] to a response; used with `axum::middleware::map_response`.
+pub async fn add_version_header(mut response: Response) -> Response {
+    if let Ok(value) = HeaderValue::from_str(&VersionInfo::current().label()) {
+        response.headers_mut().insert(VERSION_HEADER.clone(), value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_appends_git_description_as_build_metadata() {
+        let mut info = VersionInfo {
+            version: "0.1.0".into(),
+            git_describe: None,
+            build_timestamp: 1_700_000_000,
+            target: "aarch64-unknown-linux-gnu".into(),
+        };
+        assert_eq!(info.label(), "0.1.0");
+        info.git_describe = Some("v0.1.0-3-gabc1234-dirty".into());
+        assert_eq!(info.label(), "0.1.0+v0.1.0-3-gabc1234-dirty");
+        assert_eq!(
+            info.banner(),
+            "AirSync receiver 0.1.0+v0.1.0-3-gabc1234-dirty (aarch64-unknown-linux-gnu, built at 1700000000)"
+        );
+    }
+}
@@ -0,0 +1,238 @@
+use crate::calibration::{ServiceState, ShairportController};
+use crate::discovery::Backoff;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Restart attempts kept for `/api/health`.
+pub const RESTART_LOG_LEN: usize = 20;
+
+#[derive(Debug, Clone, Copy)]
+pub struct WatchdogConfig {
+    /// Time between checks while shairport-sync is healthy.
+    pub interval: Duration,
+    /// Wait after the first restart before checking again; doubles per failed check.
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            min_backoff: Duration::from_secs(5),
+            max_backoff: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RestartAttempt {
+    pub at_ms: u64,
+    /// Consecutive failed checks when this restart was made.
+    pub failures: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WatchdogSnapshot {
+    pub last_check_ms: Option<u64>,
+    pub last_state: Option<ServiceState>,
+    /// Checks in a row that found shairport-sync down; reset once it is seen running.
+    pub consecutive_failures: u32,
+    /// Most recent restarts, oldest first.
+    pub restarts: Vec<RestartAttempt>,
+}
+
+/// Shared view of the watchdog's progress, read by the health endpoint.
+#[derive(Clone, Default)]
+pub struct WatchdogHandle {
+    state: Arc<Mutex<WatchdogSnapshot>>,
+}
+
+impl WatchdogHandle {
+    pub fn snapshot(&self) -> WatchdogSnapshot {
+        self.state.lock().unwrap().clone()
+    }
+}
+
+/// Polls the controller and restarts shairport-sync when it has failed, backing off
+/// exponentially while it keeps failing. Clean stops and states it can't read are left
+/// alone, so an operator's `systemctl stop` isn't undone.
+pub struct ShairportWatchdog<C: ShairportController> {
+    controller: Arc<C>,
+    config: WatchdogConfig,
+    handle: WatchdogHandle,
+    now_ms: fn() -> u64,
+}
+
+impl<C: ShairportController> ShairportWatchdog<C> {
+    pub fn new(controller: Arc<C>, config: WatchdogConfig, now_ms: fn() -> u64) -> Self {
+        Self {
+            controller,
+            config,
+            handle: WatchdogHandle::default(),
+            now_ms,
+        }
+    }
+
+    pub fn handle(&self) -> WatchdogHandle {
+        self.handle.clone()
+    }
+
+    pub fn spawn(self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut backoff = Backoff::new(self.config.min_backoff, self.config.max_backoff);
+            loop {
+                tokio::time::sleep(self.check(&mut backoff).await).await;
+            }
+        })
+    }
+
+    /// Run one check, restarting if needed, and return how long to wait before the next.
+    async fn check(&self, backoff: &mut Backoff) -> Duration {
+        let now = (self.now_ms)();
+        let state = match self.controller.clone().status().await {
+            Ok(state) => state,
+            Err(err) => {
+                eprintln!("[watchdog] failed to read shairport-sync state: {err:#}");
+                ServiceState::Unknown
+            }
+        };
+        let failures = {
+            let mut snapshot = self.handle.state.lock().unwrap();
+            snapshot.last_check_ms = Some(now);
+            snapshot.last_state = Some(state);
+            match state {
+                ServiceState::Failed => snapshot.consecutive_failures += 1,
+                ServiceState::Active | ServiceState::Stopped => snapshot.consecutive_failures = 0,
+                _ => {}
+            }
+            snapshot.consecutive_failures
+        };
+        if state != ServiceState::Failed {
+            if failures == 0 {
+                backoff.reset();
+            }
+            return self.config.interval;
+        }
+
+        let delay = backoff.next_delay();
+        eprintln!("[watchdog] shairport-sync has failed (check {failures}); restarting, next check in {delay:?}");
+        let error = self
+            .controller
+            .clone()
+            .restart_async()
+            .await
+            .err()
+            .map(|err| format!("{err:#}"));
+        if let Some(error) = &error {
+            eprintln!("[watchdog] shairport-sync restart failed: {error}");
+        }
+        let mut snapshot = self.handle.state.lock().unwrap();
+        snapshot.restarts.push(RestartAttempt {
+            at_ms: now,
+            failures,
+            error,
+        });
+        let excess = snapshot.restarts.len().saturating_sub(RESTART_LOG_LEN);
+        snapshot.restarts.drain(..excess);
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::MockController;
+
+    fn config() -> WatchdogConfig {
+        WatchdogConfig {
+            interval: Duration::from_secs(10),
+            min_backoff: Duration::from_secs(5),
+            max_backoff: Duration::from_secs(20),
+        }
+    }
+
+    fn start(controller: &MockController) -> WatchdogHandle {
+        let watchdog = ShairportWatchdog::new(Arc::new(controller.clone()), config(), || 0);
+        let handle = watchdog.handle();
+        watchdog.spawn();
+        handle
+    }
+
+    async fn advance(secs: u64) {
+        tokio::time::sleep(Duration::from_secs(secs)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restarts_with_backoff_until_recovered() {
+        use ServiceState::{Active, Failed};
+        let controller = MockController::new().with_statuses([Failed, Failed, Failed, Active]);
+        let handle = start(&controller);
+
+        // Checks at t=0 (restart, wait 5s), t=5 (restart, wait 10s), t=15 (restart, wait 20s).
+        advance(1).await;
+        assert_eq!(controller.calls(), 1);
+        advance(5).await;
+        assert_eq!(controller.calls(), 2);
+        advance(5).await;
+        assert_eq!(controller.calls(), 2);
+        advance(5).await;
+        assert_eq!(controller.calls(), 3);
+        let snapshot = handle.snapshot();
+        assert_eq!(snapshot.consecutive_failures, 3);
+        assert_eq!(
+            snapshot.restarts.iter().map(|r| r.failures).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+
+        // Recovered at t=35; back to the normal interval with no more restarts.
+        advance(40).await;
+        let snapshot = handle.snapshot();
+        assert_eq!(snapshot.last_state, Some(Active));
+        assert_eq!(snapshot.consecutive_failures, 0);
+        assert_eq!(controller.calls(), 3);
+        assert_eq!(controller.blocking_calls(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_is_capped() {
+        let controller = MockController::new().with_statuses([ServiceState::Failed]);
+        start(&controller);
+        // Restarts at 0, 5, 15, 35, 55, 75, 95.
+        advance(100).await;
+        assert_eq!(controller.calls(), 7);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_restarts_are_logged() {
+        let controller = MockController::failing("systemctl unavailable").with_statuses([ServiceState::Failed]);
+        let watchdog = ShairportWatchdog::new(Arc::new(controller.clone()), config(), || 42);
+        let handle = watchdog.handle();
+        watchdog.spawn();
+        advance(1).await;
+        let snapshot = handle.snapshot();
+        assert_eq!(snapshot.last_check_ms, Some(42));
+        assert_eq!(
+            snapshot.restarts,
+            vec![RestartAttempt {
+                at_ms: 42,
+                failures: 1,
+                error: Some("systemctl unavailable".into()),
+            }]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clean_stops_and_unknown_states_are_left_alone() {
+        for state in [ServiceState::Stopped, ServiceState::Unknown, ServiceState::Activating] {
+            let controller = MockController::new().with_statuses([state]);
+            let handle = start(&controller);
+            advance(60).await;
+            assert_eq!(controller.calls(), 0, "{state:?}");
+            assert_eq!(handle.snapshot().last_state, Some(state));
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use airsync_receiver_core::setup::SetupMode;
+use airsync_receiver_core::startup::{reconcile_startup_config, SettingsFile, SETTINGS_STATE_FILE};
+use airsync_receiver_core::test_util::{MockCalibrationSink, MockWriter};
+use airsync_receiver_core::{
+    generate_config, router, BuiltinRenderer, CalibrationLimits, ConfigStore, HardwareProbe, InMemorySettingsManager,
+    ReceiverState,
+};
+use airsync_shared_protocol::{AudioOutput, HardwareCapabilities};
+use axum::body::{to_bytes, Body};
+use axum::http::{Request, StatusCode};
+use axum::Router;
+use serde_json::json;
+use std::sync::Arc;
+use tower::ServiceExt;
+
+struct FixedHardware;
+
+impl HardwareProbe for FixedHardware {
+    fn detect(&self) -> anyhow::Result<HardwareCapabilities> {
+        Ok(HardwareCapabilities {
+            cpu_cores: 4,
+            ram_mb: 4096,
+            board_id: "rpi4".into(),
+            audio_outputs: vec![AudioOutput::Headphone, AudioOutput::USB],
+            preferred_output: AudioOutput::USB,
+            temperature_c: None,
+            throttled: None,
+            available_mb: None,
+            os: None,
+            memory_speed_hint: None,
+        })
+    }
+}
+
+async fn send(app: &Router, request: Request<Body>) -> (StatusCode, serde_json::Value) {
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+fn complete_setup(body: serde_json::Value) -> Request<Body> {
+    Request::post("/api/setup/complete")
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn json_post(path: &str, body: serde_json::Value) -> Request<Body> {
+    Request::post(path)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Request a calibration and play it straight away, returning the session its result
+/// has to name once playback has started.
+async fn play_calibration(app: &Router) -> serde_json::Value {
+    use futures_util::StreamExt;
+
+    let response = app
+        .clone()
+        .oneshot(Request::get("/api/calibration/events").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let mut events = response.into_body().into_data_stream();
+    let chirp = json!({"start_freq": 2000, "end_freq": 8000, "duration": 50, "repetitions": 1, "interval_ms": 100});
+    let (status, requested) =
+        send(app, json_post("/api/calibration/request", json!({"timestamp": 1, "chirp_config": chirp}))).await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = requested["session_id"].clone();
+    let (status, _) = send(
+        app,
+        json_post("/api/calibration/ready", json!({"countdown_ms": 0, "session_id": session_id})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    while let Some(chunk) = events.next().await {
+        if String::from_utf8_lossy(&chunk.unwrap()).contains("event: playback_started") {
+            return session_id;
+        }
+    }
+    panic!("event stream ended before playback started");
+}
+
+#[tokio::test]
+async fn builds_router_from_builder_with_mock_sink() {
+    let sink = Arc::new(MockCalibrationSink::new());
+    let app = router(
+        ReceiverState::builder()
+            .calibration(sink.clone())
+            .calibration_limits(CalibrationLimits {
+                min_lead_ms: 0,
+                ..CalibrationLimits::default()
+            })
+            .build(),
+    );
+
+    let session_id = play_calibration(&app).await;
+    let body = json!({
+        "timestamp": 1,
+        "latency_ms": 42.0,
+        "confidence": 0.9,
+        "session_id": session_id,
+    });
+    let response = app
+        .clone()
+        .oneshot(
+            Request::post("/api/calibration/result")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let accepted: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let status_url = accepted["status_url"].as_str().unwrap();
+    loop {
+        let response = app
+            .clone()
+            .oneshot(Request::get(status_url).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        if job["state"] == "succeeded" {
+            break;
+        }
+        assert!(job["state"] == "pending" || job["state"] == "running", "{job}");
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+    assert_eq!(sink.last().unwrap().latency_ms, 42.0);
+}
+
+#[tokio::test]
+async fn default_builder_serves_in_memory_settings() {
+    let app = router(ReceiverState::builder().name("Kitchen").build());
+
+    let response = app
+        .oneshot(Request::get("/api/settings").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let settings: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(settings["device_name"], "Kitchen");
+    assert_eq!(settings["output_device"], "hw:0,0");
+}
+
+#[tokio::test]
+async fn fresh_receiver_completes_setup_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let store_path = dir.path().join(SETTINGS_STATE_FILE);
+    let fallback = generate_config(Some("raspberrypi"), AudioOutput::Headphone);
+    let boot = reconcile_startup_config(
+        &MockWriter::new(),
+        &BuiltinRenderer,
+        &SettingsFile::new(&store_path),
+        None,
+        fallback.clone(),
+        None,
+    )
+    .unwrap();
+    assert!(boot.setup_required);
+    let config = ConfigStore::new(boot.config).persist_to(SettingsFile::new(&store_path));
+    let app = router(
+        ReceiverState::builder()
+            .settings(Arc::new(InMemorySettingsManager::new(config)))
+            .hardware(Arc::new(FixedHardware))
+            .setup_mode(SetupMode::new(boot.setup_required))
+            .build(),
+    );
+
+    let (status, setup) = send(&app, Request::get("/api/setup").body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(setup["setup_mode"], true);
+    assert_eq!(setup["missing"], json!(["name", "output", "calibration"]));
+    assert_eq!(setup["hardware"]["board_id"], "rpi4");
+    assert_eq!(
+        setup["output_options"],
+        json!([
+            {"output": "usb", "output_device": "hw:1,0", "preferred": true},
+            {"output": "headphone", "output_device": "hw:0,0", "preferred": false},
+        ])
+    );
+
+    let (status, error) = send(&app, complete_setup(json!({"device_name": " ", "output_device": "hw:1,0"}))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error, json!({"error": "invalid_device_name"}));
+    assert!(!store_path.exists());
+
+    // Setup stays open while the store can't be written.
+    let chosen = setup["output_options"][0]["output_device"].clone();
+    let staging = dir.path().join(format!("{SETTINGS_STATE_FILE}.new"));
+    std::fs::create_dir(&staging).unwrap();
+    let (status, error) = send(&app, complete_setup(json!({"device_name": "Living Room", "output_device": chosen}))).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(error, json!({"error": "setup_failed"}));
+    let (_, setup) = send(&app, Request::get("/api/setup").body(Body::empty()).unwrap()).await;
+    assert_eq!(setup["setup_mode"], true);
+
+    std::fs::remove_dir(&staging).unwrap();
+    let (status, settings) = send(&app, complete_setup(json!({"device_name": "Living Room", "output_device": chosen}))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(settings["device_name"], "Living Room");
+    assert_eq!(settings["output_device"], "hw:1,0");
+    let stored = SettingsFile::new(&store_path).load().unwrap().unwrap();
+    assert_eq!(stored.device_name.as_deref(), Some("Living Room"));
+
+    let (_, setup) = send(&app, Request::get("/api/setup").body(Body::empty()).unwrap()).await;
+    assert_eq!(setup["setup_mode"], false);
+    assert_eq!(setup["missing"], json!(["calibration"]));
+    let (status, error) = send(&app, complete_setup(json!({"device_name": "Den", "output_device": "hw:0,0"}))).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(error, json!({"error": "setup_already_complete"}));
+    let (_, settings) = send(&app, Request::get("/api/settings").body(Body::empty()).unwrap()).await;
+    assert_eq!(settings["device_name"], "Living Room");
+
+    let next_boot = reconcile_startup_config(
+        &MockWriter::new(),
+        &BuiltinRenderer,
+        &SettingsFile::new(&store_path),
+        None,
+        fallback,
+        None,
+    )
+    .unwrap();
+    assert!(!next_boot.setup_required);
+    assert_eq!(next_boot.config.device_name, "Living Room");
+}
@@ -1,4 +1,6 @@
+use crate::device::OutputDeviceSpec;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ChirpConfig {
@@ -24,14 +26,233 @@ impl Default for ChirpConfig {
     }
 }
 
+/// Named calibration modes the app can offer without knowing chirp parameters. The
+/// receiver resolves them, so presets can be retuned without an app update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChirpPreset {
+    Quick,
+    Standard,
+    Thorough,
+}
+
+impl ChirpPreset {
+    pub const ALL: [ChirpPreset; 3] = [ChirpPreset::Quick, ChirpPreset::Standard, ChirpPreset::Thorough];
+
+    pub fn config(self) -> ChirpConfig {
+        match self {
+            ChirpPreset::Quick => ChirpConfig {
+                repetitions: 3,
+                interval_ms: 300,
+                ..ChirpConfig::default()
+            },
+            ChirpPreset::Standard => ChirpConfig::default(),
+            ChirpPreset::Thorough => ChirpConfig {
+                start_freq: 500,
+                end_freq: 12_000,
+                duration: 150,
+                repetitions: 12,
+                interval_ms: 500,
+                amplitude: None,
+            },
+        }
+    }
+}
+
+/// Shortest sweep a chirp may use; shorter sweeps carry too little energy to detect.
+pub const MIN_CHIRP_DURATION_MS: u32 = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ChirpConfigError {
+    #[error("start_freq and end_freq must be above 0 Hz")]
+    ZeroFrequency,
+    #[error("duration must be at least {MIN_CHIRP_DURATION_MS}ms, got {0}ms")]
+    TooShort(u32),
+    #[error("repetitions must be at least 1")]
+    NoRepetitions,
+}
+
+impl ChirpConfig {
+    pub fn validate(&self) -> Result<(), ChirpConfigError> {
+        if self.start_freq == 0 || self.end_freq == 0 {
+            return Err(ChirpConfigError::ZeroFrequency);
+        }
+        if self.duration < MIN_CHIRP_DURATION_MS {
+            return Err(ChirpConfigError::TooShort(self.duration));
+        }
+        if self.repetitions == 0 {
+            return Err(ChirpConfigError::NoRepetitions);
+        }
+        Ok(())
+    }
+
+    /// Length of the rendered signal: each repetition is the sweep followed by its interval.
+    pub fn estimated_duration_ms(&self) -> u64 {
+        u64::from(self.repetitions.max(1)) * (u64::from(self.duration) + u64::from(self.interval_ms))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    const SESSION: Uuid = Uuid::from_u128(0x5e55_1011_0000_4000_8000_0000_0000_0001);
+
+    fn every_calibration_message() -> Vec<CalibrationMessage> {
+        vec![
+            CalibrationMessage::CalibrationRequest {
+                timestamp: 1_700_000_000_000,
+                session_id: SESSION,
+            },
+            CalibrationMessage::CalibrationReady {
+                timestamp: 1_700_000_000_010,
+                session_id: SESSION,
+                countdown: 3,
+                chirp_config: ChirpConfig {
+                    amplitude: Some(0.5),
+                    ..ChirpConfig::default()
+                },
+            },
+            CalibrationMessage::CalibrationData {
+                timestamp: 1_700_000_000_020,
+                session_id: SESSION,
+                recording_start_time: 1_700_000_000_015,
+                chirp_detection_times: vec![1_700_000_000_100, 1_700_000_000_600, 1_700_000_001_100],
+                confidence: 0.92,
+            },
+            CalibrationMessage::CalibrationResult {
+                timestamp: 1_700_000_000_030,
+                session_id: SESSION,
+                measured_latency_ms: 41.5,
+                applied_offset_ms: -41.5,
+                confidence: 0.92,
+            },
+        ]
+    }
+
+    #[test]
+    fn calibration_messages_roundtrip_through_binary_frames() {
+        for msg in every_calibration_message() {
+            let bytes = msg.encode_binary().unwrap();
+            assert_eq!(CalibrationMessage::decode_binary(&bytes).unwrap(), msg);
+            let json = serde_json::to_vec(&msg).unwrap();
+            assert!(bytes.len() < json.len() / 2, "{} bytes vs {} JSON", bytes.len(), json.len());
+        }
+        assert!(CalibrationMessage::decode_binary(&[9, 0, 0, 0]).is_err());
+        let truncated = every_calibration_message()[2].encode_binary().unwrap();
+        assert!(CalibrationMessage::decode_binary(&truncated[..truncated.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn every_message_carries_its_session() {
+        for msg in every_calibration_message() {
+            assert_eq!(msg.session_id(), SESSION);
+            let bytes = msg.encode_binary().unwrap();
+            assert_eq!(CalibrationMessage::decode_binary(&bytes).unwrap().session_id(), SESSION);
+            let json = serde_json::to_value(&msg).unwrap();
+            assert_eq!(json["session_id"], SESSION.to_string());
+            let decoded: CalibrationMessage = serde_json::from_value(json).unwrap();
+            assert_eq!(decoded.session_id(), SESSION);
+        }
+        let legacy: CalibrationMessage =
+            serde_json::from_str(r#"{"type":"calibration_request","timestamp":1}"#).unwrap();
+        assert_eq!(legacy.session_id(), Uuid::nil());
+    }
+
+    #[test]
+    fn outcome_roundtrips_through_result_message() {
+        let outcome = CalibrationOutcome {
+            measured_latency_ms: 42.0,
+            applied_offset_ms: -42.0,
+            was_clamped: false,
+            changed_keys: Vec::new(),
+        };
+        let msg = CalibrationMessage::from(outcome.clone());
+        match &msg {
+            CalibrationMessage::CalibrationResult {
+                timestamp, confidence, ..
+            } => {
+                assert!(*timestamp > 0);
+                assert_eq!(*confidence, 0.0);
+            }
+            other => panic!("unexpected message {other:?}"),
+        }
+        assert_eq!(CalibrationOutcome::from_result_message(&msg), Some(outcome));
+    }
+
+    #[test]
+    fn only_result_messages_convert_to_outcomes() {
+        let msg = CalibrationMessage::CalibrationRequest {
+            timestamp: 1,
+            session_id: SESSION,
+        };
+        assert_eq!(CalibrationOutcome::from_result_message(&msg), None);
+    }
+
+    #[test]
+    fn apply_response_roundtrips_through_json() {
+        let response = CalibrationApplyResponse {
+            output_device: OutputDeviceSpec::hw(1, 0),
+            config_generation: 7,
+            ..CalibrationApplyResponse::from_outcome(&CalibrationOutcome {
+                measured_latency_ms: 300.0,
+                applied_offset_ms: -250.0,
+                was_clamped: true,
+                changed_keys: vec!["general.audio_backend_latency_offset_in_seconds".into()],
+            })
+        };
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["measured_latency_ms"], 300.0);
+        assert_eq!(json["applied_offset_ms"], -250.0);
+        assert_eq!(json["was_clamped"], true);
+        assert_eq!(json["config_generation"], 7);
+        assert_eq!(json["changed_keys"][0], "general.audio_backend_latency_offset_in_seconds");
+        let parsed: CalibrationApplyResponse = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, response);
+    }
+
+    #[test]
+    fn validates_chirp_parameters() {
+        assert_eq!(ChirpConfig::default().validate(), Ok(()));
+        let with = |f: fn(&mut ChirpConfig)| {
+            let mut cfg = ChirpConfig::default();
+            f(&mut cfg);
+            cfg.validate()
+        };
+        assert_eq!(with(|c| c.end_freq = 0), Err(ChirpConfigError::ZeroFrequency));
+        assert_eq!(with(|c| c.duration = 9), Err(ChirpConfigError::TooShort(9)));
+        assert_eq!(with(|c| c.repetitions = 0), Err(ChirpConfigError::NoRepetitions));
+    }
+
+    #[test]
+    fn estimated_duration_covers_every_repetition_and_gap() {
+        assert_eq!(ChirpConfig::default().estimated_duration_ms(), 6 * 500);
+        let single = ChirpConfig {
+            repetitions: 0,
+            ..ChirpConfig::default()
+        };
+        assert_eq!(single.estimated_duration_ms(), 500);
+    }
+
+    #[test]
+    fn presets_are_valid_and_ordered_by_length() {
+        let durations: Vec<u64> = ChirpPreset::ALL
+            .iter()
+            .map(|preset| {
+                let config = preset.config();
+                config.validate().unwrap();
+                config.estimated_duration_ms()
+            })
+            .collect();
+        assert!(durations.windows(2).all(|pair| pair[0] < pair[1]), "{durations:?}");
+        assert_eq!(serde_json::to_value(ChirpPreset::Thorough).unwrap(), "thorough");
+    }
+
     #[test]
     fn marker_spec_serializes() {
         let spec = CalibrationSignalSpec {
             sample_rate: 48_000,
+            sample_format: SampleFormat::I16,
             length_samples: 240_000,
             markers: vec![
                 MarkerSpec {
@@ -39,6 +260,9 @@ mod tests {
                     kind: MarkerKind::Click,
                     start_sample: 0,
                     duration_samples: 480,
+                    fade_samples: 240,
+                    amplitude: 0.72,
+                    search_window_samples: None,
                 },
                 MarkerSpec {
                     id: "chirp1".into(),
@@ -49,8 +273,16 @@ mod tests {
                     },
                     start_sample: 10_000,
                     duration_samples: 4_800,
+                    fade_samples: 400,
+                    amplitude: 0.65,
+                    search_window_samples: None,
                 },
             ],
+            signal_id: None,
+            content_hash: None,
+            level_db: None,
+            suggested_decimation: 1,
+            max_expected_latency_ms: None,
         };
 
         let json = serde_json::to_string(&spec).unwrap();
@@ -60,6 +292,263 @@ mod tests {
         assert_eq!(round_trip.sample_rate, 48_000);
         assert_eq!(round_trip.markers.len(), 2);
     }
+
+    #[test]
+    fn sample_format_defaults_to_16_bit_and_parses() {
+        let json = r#"{"sample_rate":48000,"length_samples":480,"markers":[]}"#;
+        let spec: CalibrationSignalSpec = serde_json::from_str(json).unwrap();
+        assert_eq!(spec.sample_format, SampleFormat::I16);
+        assert_eq!("i24".parse::<SampleFormat>(), Ok(SampleFormat::I24));
+        assert_eq!("f32".parse::<SampleFormat>().unwrap().bits_per_sample(), 32);
+        assert!("s16le".parse::<SampleFormat>().is_err());
+    }
+
+    #[test]
+    fn builder_produces_submission_with_detections() {
+        let submission = CalibrationSubmission::builder()
+            .timestamp(42)
+            .latency_ms(18.5)
+            .confidence(0.8)
+            .detection(DetectionReport {
+                marker_id: Some("chirp_1".into()),
+                sample_index: 1_200,
+                correlation: 0.7,
+                latency_ms: Some(18.0),
+            })
+            .detection(DetectionReport {
+                marker_id: None,
+                sample_index: 2_400,
+                correlation: 0.6,
+                latency_ms: None,
+            })
+            .build()
+            .unwrap();
+
+        assert_eq!(submission.timestamp, 42);
+        assert_eq!(submission.latency_ms, 18.5);
+        assert_eq!(submission.confidence, 0.8);
+        assert_eq!(submission.detections.len(), 2);
+        assert_eq!(submission.detections[0].marker_id.as_deref(), Some("chirp_1"));
+    }
+
+    #[test]
+    fn builder_defaults_detections_to_empty() {
+        let submission = CalibrationSubmission::builder()
+            .timestamp(1)
+            .latency_ms(0.0)
+            .confidence(1.0)
+            .build()
+            .unwrap();
+        assert!(submission.detections.is_empty());
+    }
+
+    #[test]
+    fn builder_rejects_missing_fields() {
+        let err = CalibrationSubmission::builder()
+            .latency_ms(10.0)
+            .confidence(0.5)
+            .build()
+            .unwrap_err();
+        assert_eq!(err, SubmissionBuildError::MissingField("timestamp"));
+    }
+
+    #[test]
+    fn builder_rejects_out_of_range_confidence() {
+        for confidence in [-0.1, 1.01, f32::NAN] {
+            let err = CalibrationSubmission::builder()
+                .timestamp(1)
+                .latency_ms(10.0)
+                .confidence(confidence)
+                .build()
+                .unwrap_err();
+            assert!(matches!(err, SubmissionBuildError::ConfidenceOutOfRange(_)));
+            assert!(err.to_string().contains("confidence"));
+        }
+    }
+
+    #[test]
+    fn builder_rejects_non_finite_latency() {
+        for latency in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let err = CalibrationSubmission::builder()
+                .timestamp(1)
+                .latency_ms(latency)
+                .confidence(0.5)
+                .build()
+                .unwrap_err();
+            assert!(matches!(err, SubmissionBuildError::NonFiniteLatency(_)));
+        }
+    }
+
+    #[test]
+    fn signal_id_roundtrips_through_tones() {
+        assert_eq!(signal_id_tones(0), Some([1_209, 1_209, 1_209]));
+        assert_eq!(signal_id_tones(0o123), Some([1_336, 1_477, 1_633]));
+        assert_eq!(signal_id_tones(MAX_SIGNAL_ID + 1), None);
+        for id in [0, 7, 8, 300, MAX_SIGNAL_ID] {
+            let detected: Vec<f32> = signal_id_tones(id)
+                .unwrap()
+                .iter()
+                .map(|&hz| hz as f32 * 1.01)
+                .collect();
+            assert_eq!(decode_signal_id(&detected), Some(id));
+        }
+    }
+
+    fn click(id: &str, start_sample: u32, duration_samples: u32) -> MarkerSpec {
+        MarkerSpec {
+            id: id.into(),
+            kind: MarkerKind::Click,
+            start_sample,
+            duration_samples,
+            fade_samples: 0,
+            amplitude: 1.0,
+            search_window_samples: None,
+        }
+    }
+
+    fn spec_with(markers: Vec<MarkerSpec>) -> CalibrationSignalSpec {
+        CalibrationSignalSpec {
+            sample_rate: 48_000,
+            sample_format: SampleFormat::I16,
+            length_samples: 10_000,
+            markers,
+            signal_id: None,
+            content_hash: None,
+            level_db: None,
+            suggested_decimation: 1,
+            max_expected_latency_ms: None,
+        }
+    }
+
+    #[test]
+    fn adjacent_markers_do_not_overlap() {
+        let a = click("a", 0, 100);
+        let b = click("b", 100, 50);
+        assert!(!a.overlap(&b));
+        assert!(!b.overlap(&a));
+        let spec = spec_with(vec![a, b, click("c", 500, 10)]);
+        assert!(spec.conflicts().is_empty());
+        assert_eq!(spec.validate(), Ok(()));
+    }
+
+    #[test]
+    fn exact_boundaries() {
+        let a = click("a", 100, 100);
+        // One sample into either end overlaps; identical ranges overlap.
+        assert!(a.overlap(&click("b", 199, 10)));
+        assert!(a.overlap(&click("c", 90, 11)));
+        assert!(!a.overlap(&click("d", 90, 10)));
+        assert!(a.overlap(&a.clone()));
+        // Zero-length markers never overlap.
+        assert!(!a.overlap(&click("e", 150, 0)));
+    }
+
+    #[test]
+    fn reports_overlapping_pair() {
+        let spec = spec_with(vec![click("a", 0, 100), click("b", 200, 100), click("c", 250, 10)]);
+        let conflicts = spec.conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!((conflicts[0].0.id.as_str(), conflicts[0].1.id.as_str()), ("b", "c"));
+        assert_eq!(spec.validate(), Err(SignalSpecError::Overlap("b".into(), "c".into())));
+    }
+
+    #[test]
+    fn validate_rejects_markers_past_the_end() {
+        let spec = spec_with(vec![click("late", 9_990, 11)]);
+        assert_eq!(spec.validate(), Err(SignalSpecError::MarkerOutOfBounds("late".into())));
+    }
+
+    fn sweep(id: &str, start_sample: u32, duration_samples: u32) -> MarkerSpec {
+        MarkerSpec {
+            kind: MarkerKind::Chirp {
+                start_freq: 400,
+                end_freq: 9_000,
+                duration_ms: duration_samples / 48,
+            },
+            ..click(id, start_sample, duration_samples)
+        }
+    }
+
+    #[test]
+    fn search_hints_round_trip_and_default_for_old_clients() {
+        let mut spec = spec_with(vec![click("a", 0, 480), sweep("anchor", 1_440, 4_800), click("b", 7_200, 480)]);
+        spec.annotate_search_hints(100);
+        let json = serde_json::to_value(&spec).unwrap();
+        assert_eq!(json["suggested_decimation"], 2);
+        assert_eq!(json["max_expected_latency_ms"], 100);
+        assert_eq!(json["markers"][1]["search_window_samples"], serde_json::json!([1_440, 11_040]));
+        let round_trip: CalibrationSignalSpec = serde_json::from_value(json).unwrap();
+        assert_eq!(round_trip, spec);
+
+        let old = r#"{"sample_rate":48000,"length_samples":480,"markers":[{"id":"a","kind":"click","start_sample":0,"duration_samples":48}]}"#;
+        let spec: CalibrationSignalSpec = serde_json::from_str(old).unwrap();
+        assert_eq!(spec.suggested_decimation, 1);
+        assert_eq!(spec.max_expected_latency_ms, None);
+        assert_eq!(spec.markers[0].search_window_samples, None);
+        assert!(!serde_json::to_string(&spec).unwrap().contains("search_window_samples"));
+    }
+
+    #[test]
+    fn search_windows_stay_clear_of_adjacent_markers() {
+        // Out of order, with gaps both wider and narrower than the tolerance.
+        let mut spec = spec_with(vec![
+            click("late", 9_000, 200),
+            click("first", 0, 480),
+            sweep("anchor", 1_440, 4_800),
+            click("close", 6_300, 240),
+            click("tight", 6_600, 240),
+        ]);
+        spec.annotate_search_hints(250);
+        let latency = 250 * 48;
+        let anchor = spec.anchor().unwrap().clone();
+        assert_eq!(anchor.search_window_samples, Some((1_440, 6_240 + latency)));
+
+        let mut others: Vec<&MarkerSpec> = spec.markers.iter().filter(|m| m.id != anchor.id).collect();
+        others.sort_by_key(|m| m.start_sample);
+        let by_start: Vec<&MarkerSpec> = {
+            let mut all: Vec<&MarkerSpec> = spec.markers.iter().collect();
+            all.sort_by_key(|m| m.start_sample);
+            all
+        };
+        for (i, marker) in by_start.iter().enumerate() {
+            if marker.id == anchor.id {
+                continue;
+            }
+            let (start, end) = marker.search_window_samples.unwrap();
+            assert!(start <= marker.start_sample && end as u64 >= marker.end_sample(), "{}", marker.id);
+            assert!(end - start <= marker.duration_samples + 2 * SEARCH_WINDOW_TOLERANCE_MS * 48);
+            if let Some(prev) = i.checked_sub(1).map(|p| by_start[p]) {
+                assert!(start as u64 >= prev.end_sample(), "{} reaches into {}", marker.id, prev.id);
+            }
+            if let Some(next) = by_start.get(i + 1) {
+                assert!(end <= next.start_sample, "{} reaches into {}", marker.id, next.id);
+            }
+        }
+        for pair in others.windows(2) {
+            assert!(pair[0].search_window_samples.unwrap().1 <= pair[1].search_window_samples.unwrap().0);
+        }
+        assert_eq!(spec.markers[0].search_window_samples, Some((8_520, 9_680)));
+    }
+
+    #[test]
+    fn converts_between_samples_and_milliseconds() {
+        let spec = CalibrationSignalSpec {
+            length_samples: 225_600,
+            ..spec_with(Vec::new())
+        };
+        assert_eq!(spec.total_duration_ms(), 4_700.0);
+        assert_eq!(spec.time_at_sample(24_000), 500.0);
+        assert_eq!(spec.sample_at_time_ms(500.0), 24_000);
+        assert_eq!(spec.sample_at_time_ms(spec.time_at_sample(12_345)), 12_345);
+        assert_eq!(spec.sample_at_time_ms(0.01), 0);
+        assert_eq!(spec.sample_at_time_ms(-20.0), 0);
+    }
+
+    #[test]
+    fn decode_rejects_off_alphabet_tones() {
+        assert_eq!(decode_signal_id(&[1_209.0, 1_800.0, 1_209.0]), None);
+        assert_eq!(decode_signal_id(&[1_209.0, 1_209.0]), None);
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -71,6 +560,71 @@ pub struct CalibrationSubmission {
     pub detections: Vec<DetectionReport>,
 }
 
+impl CalibrationSubmission {
+    pub fn builder() -> CalibrationSubmissionBuilder {
+        CalibrationSubmissionBuilder::default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum SubmissionBuildError {
+    #[error("missing required field `{0}`")]
+    MissingField(&'static str),
+    #[error("latency_ms must be finite, got {0}")]
+    NonFiniteLatency(f32),
+    #[error("confidence must be within [0.0, 1.0], got {0}")]
+    ConfidenceOutOfRange(f32),
+}
+
+/// Builds a validated `CalibrationSubmission`; `detections` defaults to empty.
+#[derive(Debug, Clone, Default)]
+pub struct CalibrationSubmissionBuilder {
+    timestamp: Option<u64>,
+    latency_ms: Option<f32>,
+    confidence: Option<f32>,
+    detections: Vec<DetectionReport>,
+}
+
+impl CalibrationSubmissionBuilder {
+    pub fn timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn latency_ms(mut self, latency_ms: f32) -> Self {
+        self.latency_ms = Some(latency_ms);
+        self
+    }
+
+    pub fn confidence(mut self, confidence: f32) -> Self {
+        self.confidence = Some(confidence);
+        self
+    }
+
+    pub fn detection(mut self, detection: DetectionReport) -> Self {
+        self.detections.push(detection);
+        self
+    }
+
+    pub fn build(self) -> Result<CalibrationSubmission, SubmissionBuildError> {
+        let timestamp = self.timestamp.ok_or(SubmissionBuildError::MissingField("timestamp"))?;
+        let latency_ms = self.latency_ms.ok_or(SubmissionBuildError::MissingField("latency_ms"))?;
+        let confidence = self.confidence.ok_or(SubmissionBuildError::MissingField("confidence"))?;
+        if !latency_ms.is_finite() {
+            return Err(SubmissionBuildError::NonFiniteLatency(latency_ms));
+        }
+        if !(0.0..=1.0).contains(&confidence) {
+            return Err(SubmissionBuildError::ConfidenceOutOfRange(confidence));
+        }
+        Ok(CalibrationSubmission {
+            timestamp,
+            latency_ms,
+            confidence,
+            detections: self.detections,
+        })
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DetectionReport {
     #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -94,36 +648,520 @@ pub struct MarkerSpec {
     pub kind: MarkerKind,
     pub start_sample: u32,
     pub duration_samples: u32,
+    /// Raised-cosine fade applied at each end, in samples; see [`crate::reference_samples`].
+    #[serde(default)]
+    pub fade_samples: u32,
+    /// Peak level the marker is mixed at, relative to full scale.
+    #[serde(default = "unit_amplitude")]
+    pub amplitude: f32,
+    /// Half-open sample range to correlate this marker's reference over; see
+    /// [`CalibrationSignalSpec::annotate_search_hints`]. Clients without one search the
+    /// whole recording.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub search_window_samples: Option<(u32, u32)>,
+}
+
+fn unit_amplitude() -> f32 {
+    1.0
+}
+
+fn unit_decimation() -> u32 {
+    1
+}
+
+/// Most a marker's search window extends either side of it once the anchor is found.
+pub const SEARCH_WINDOW_TOLERANCE_MS: u32 = 10;
+/// Headroom kept between a decimated rate's Nyquist frequency and the highest tone.
+const DECIMATION_MARGIN: f32 = 1.2;
+
+impl MarkerSpec {
+    /// One past the last sample of this marker.
+    pub fn end_sample(&self) -> u64 {
+        self.start_sample as u64 + self.duration_samples as u64
+    }
+
+    /// Whether the half-open ranges `[start_sample, start_sample + duration_samples)`
+    /// intersect; markers that merely touch, or have no duration, do not overlap.
+    pub fn overlap(&self, other: &MarkerSpec) -> bool {
+        self.duration_samples > 0
+            && other.duration_samples > 0
+            && (self.start_sample as u64) < other.end_sample()
+            && (other.start_sample as u64) < self.end_sample()
+    }
+}
+
+/// Sample encoding of a rendered signal file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleFormat {
+    #[default]
+    I16,
+    I24,
+    F32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("unknown sample format `{0}`, expected i16, i24 or f32")]
+pub struct UnknownSampleFormat(pub String);
+
+impl std::str::FromStr for SampleFormat {
+    type Err = UnknownSampleFormat;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "i16" => Ok(SampleFormat::I16),
+            "i24" => Ok(SampleFormat::I24),
+            "f32" => Ok(SampleFormat::F32),
+            other => Err(UnknownSampleFormat(other.to_string())),
+        }
+    }
+}
+
+impl SampleFormat {
+    pub fn bits_per_sample(self) -> u16 {
+        match self {
+            SampleFormat::I16 => 16,
+            SampleFormat::I24 => 24,
+            SampleFormat::F32 => 32,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CalibrationSignalSpec {
     pub sample_rate: u32,
+    /// Encoding of the rendered file; sample positions don't depend on it.
+    #[serde(default)]
+    pub sample_format: SampleFormat,
     pub length_samples: u32,
     pub markers: Vec<MarkerSpec>,
+    /// Identifier encoded in the `signal_id_*` tone markers, when the layout has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal_id: Option<u16>,
+    /// Hex SHA-256 of the rendered samples and the rest of this spec; changes whenever
+    /// the signal does, so clients can cache the WAV and spec against it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    /// RMS level of the samples the markers cover, in dB relative to full scale. Playback
+    /// gains are reported in dB relative to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level_db: Option<f32>,
+    /// Factor the recording can be decimated by before correlating and still hold every
+    /// marker's tones.
+    #[serde(default = "unit_decimation")]
+    pub suggested_decimation: u32,
+    /// Largest latency the markers' search windows allow for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_expected_latency_ms: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SignalSpecError {
+    #[error("marker `{0}` extends past the end of the signal")]
+    MarkerOutOfBounds(String),
+    #[error("markers `{0}` and `{1}` overlap")]
+    Overlap(String, String),
 }
 
+impl CalibrationSignalSpec {
+    /// Every pair of markers whose sample ranges overlap, in marker order.
+    pub fn conflicts(&self) -> Vec<(&MarkerSpec, &MarkerSpec)> {
+        let mut pairs = Vec::new();
+        for (i, a) in self.markers.iter().enumerate() {
+            for b in &self.markers[i + 1..] {
+                if a.overlap(b) {
+                    pairs.push((a, b));
+                }
+            }
+        }
+        pairs
+    }
+
+    /// Length of the signal in milliseconds.
+    pub fn total_duration_ms(&self) -> f64 {
+        self.time_at_sample(self.length_samples)
+    }
+
+    /// Milliseconds from the start of the signal to `sample`.
+    pub fn time_at_sample(&self, sample: u32) -> f64 {
+        sample as f64 / self.sample_rate as f64 * 1000.0
+    }
+
+    /// The sample nearest `time_ms` into the signal; times before the start are sample 0.
+    pub fn sample_at_time_ms(&self, time_ms: f64) -> u32 {
+        (time_ms * self.sample_rate as f64 / 1000.0).round().clamp(0.0, u32::MAX as f64) as u32
+    }
+
+    /// The marker the search windows are aligned on: the first sweep.
+    pub fn anchor(&self) -> Option<&MarkerSpec> {
+        self.markers
+            .iter()
+            .filter(|m| matches!(m.kind, MarkerKind::Chirp { start_freq, end_freq, .. } if start_freq != end_freq))
+            .min_by_key(|m| m.start_sample)
+    }
+
+    /// Fill in the hints that let a client correlate less than the whole recording.
+    ///
+    /// The anchor's window runs from its start to `max_expected_latency_ms` past its end,
+    /// in the recording's timeline from the reported playback start. Every other marker's
+    /// window is in the timeline realigned on where the anchor was found: its own range
+    /// plus up to [`SEARCH_WINDOW_TOLERANCE_MS`] either side, never more than half the
+    /// silence to a neighbouring marker, so windows don't reach into adjacent markers.
+    /// Without an anchor every window is anchored to playback start like the anchor's.
+    pub fn annotate_search_hints(&mut self, max_expected_latency_ms: u32) {
+        let rate = self.sample_rate as u64;
+        let latency = (max_expected_latency_ms as u64 * rate / 1000) as u32;
+        let tolerance = (SEARCH_WINDOW_TOLERANCE_MS as u64 * rate / 1000) as u32;
+        let anchor = self.anchor().map(|m| m.id.clone());
+        let length = self.length_samples;
+
+        let mut order: Vec<usize> = (0..self.markers.len()).collect();
+        order.sort_by_key(|&i| self.markers[i].start_sample);
+        let windows: Vec<(usize, (u32, u32))> = order
+            .iter()
+            .enumerate()
+            .map(|(pos, &i)| {
+                let marker = &self.markers[i];
+                let end = marker.end_sample().min(u32::MAX as u64) as u32;
+                if anchor.is_none() || anchor.as_deref() == Some(marker.id.as_str()) {
+                    return (i, (marker.start_sample, end.saturating_add(latency)));
+                }
+                let before = pos
+                    .checked_sub(1)
+                    .map(|p| marker.start_sample.saturating_sub(self.markers[order[p]].end_sample() as u32) / 2);
+                let after = order
+                    .get(pos + 1)
+                    .map(|&n| self.markers[n].start_sample.saturating_sub(end) / 2);
+                let lead = before.map_or(tolerance, |gap| gap.min(tolerance));
+                let trail = after.map_or(tolerance, |gap| gap.min(tolerance));
+                (i, (marker.start_sample.saturating_sub(lead), end.saturating_add(trail).min(length)))
+            })
+            .collect();
+        for (i, window) in windows {
+            self.markers[i].search_window_samples = Some(window);
+        }
+
+        let highest_tone = self
+            .markers
+            .iter()
+            .filter_map(|m| match m.kind {
+                MarkerKind::Chirp { start_freq, end_freq, .. } => Some(start_freq.max(end_freq)),
+                MarkerKind::Click => None,
+            })
+            .max();
+        self.suggested_decimation = highest_tone.map_or(1, |tone| {
+            ((self.sample_rate as f32 / (2.0 * tone as f32 * DECIMATION_MARGIN)) as u32).max(1)
+        });
+        self.max_expected_latency_ms = Some(max_expected_latency_ms);
+    }
+
+    pub fn validate(&self) -> Result<(), SignalSpecError> {
+        if let Some(marker) = self
+            .markers
+            .iter()
+            .find(|m| m.end_sample() > self.length_samples as u64)
+        {
+            return Err(SignalSpecError::MarkerOutOfBounds(marker.id.clone()));
+        }
+        if let Some((a, b)) = self.conflicts().first() {
+            return Err(SignalSpecError::Overlap(a.id.clone(), b.id.clone()));
+        }
+        Ok(())
+    }
+}
+
+/// Tones for the identifier sequence, one per base-8 digit. They sit between the
+/// structured signal's other marker frequencies and are ~9% apart from each other.
+pub const SIGNAL_ID_ALPHABET: [u32; 8] = [1_209, 1_336, 1_477, 1_633, 2_093, 2_349, 2_637, 2_960];
+/// Tones per identifier sequence.
+pub const SIGNAL_ID_TONES: usize = 3;
+/// Largest identifier that fits in [`SIGNAL_ID_TONES`] base-8 digits.
+pub const MAX_SIGNAL_ID: u16 = 511;
+
+/// Detected tones further than this fraction from every alphabet entry are rejected.
+const SIGNAL_ID_TOLERANCE: f32 = 0.025;
+
+/// Tone frequencies encoding `id`, most significant digit first.
+pub fn signal_id_tones(id: u16) -> Option<[u32; SIGNAL_ID_TONES]> {
+    if id > MAX_SIGNAL_ID {
+        return None;
+    }
+    let mut tones = [0; SIGNAL_ID_TONES];
+    for (i, tone) in tones.iter_mut().enumerate() {
+        let shift = 3 * (SIGNAL_ID_TONES - 1 - i);
+        *tone = SIGNAL_ID_ALPHABET[((id >> shift) & 0b111) as usize];
+    }
+    Some(tones)
+}
+
+/// Decode an identifier from the dominant frequencies detected in each `signal_id_*`
+/// marker window, in order.
+pub fn decode_signal_id(tones_hz: &[f32]) -> Option<u16> {
+    if tones_hz.len() != SIGNAL_ID_TONES {
+        return None;
+    }
+    tones_hz.iter().try_fold(0u16, |id, &hz| {
+        let digit = SIGNAL_ID_ALPHABET
+            .iter()
+            .position(|&tone| (hz - tone as f32).abs() <= tone as f32 * SIGNAL_ID_TOLERANCE)?;
+        Some((id << 3) | digit as u16)
+    })
+}
+
+/// Every message carries the `session_id` the receiver issued for its calibration request,
+/// so messages from calibrations running side by side (e.g. one per stereo channel) can be
+/// told apart. JSON from clients that predate sessions decodes with the nil id.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum CalibrationMessage {
     CalibrationRequest {
         timestamp: u64,
+        #[serde(default)]
+        session_id: Uuid,
     },
     CalibrationReady {
         timestamp: u64,
+        #[serde(default)]
+        session_id: Uuid,
         countdown: u32,
         chirp_config: ChirpConfig,
     },
     CalibrationData {
         timestamp: u64,
+        #[serde(default)]
+        session_id: Uuid,
         recording_start_time: u64,
         chirp_detection_times: Vec<u64>,
         confidence: f32,
     },
     CalibrationResult {
         timestamp: u64,
+        #[serde(default)]
+        session_id: Uuid,
         measured_latency_ms: f32,
         applied_offset_ms: f32,
         confidence: f32,
     },
 }
+
+impl CalibrationMessage {
+    pub fn session_id(&self) -> Uuid {
+        match self {
+            CalibrationMessage::CalibrationRequest { session_id, .. }
+            | CalibrationMessage::CalibrationReady { session_id, .. }
+            | CalibrationMessage::CalibrationData { session_id, .. }
+            | CalibrationMessage::CalibrationResult { session_id, .. } => *session_id,
+        }
+    }
+
+    /// Compact encoding for WebSocket binary frames. Unlike the JSON form, it carries no
+    /// field names, only the variant index and the fields in declaration order.
+    pub fn encode_binary(&self) -> Result<Vec<u8>, bincode::Error> {
+        bincode::serialize(&BinaryMessage::from(self.clone()))
+    }
+
+    pub fn decode_binary(bytes: &[u8]) -> Result<Self, bincode::Error> {
+        bincode::deserialize::<BinaryMessage>(bytes).map(Self::from)
+    }
+}
+
+/// Externally tagged mirror of [`CalibrationMessage`]: bincode can't decode the JSON form's
+/// internal `type` tag. Variants are matched by index, so new ones must go at the end.
+#[derive(Serialize, Deserialize)]
+enum BinaryMessage {
+    Request {
+        timestamp: u64,
+        session_id: Uuid,
+    },
+    Ready {
+        timestamp: u64,
+        session_id: Uuid,
+        countdown: u32,
+        chirp_config: ChirpConfig,
+    },
+    Data {
+        timestamp: u64,
+        session_id: Uuid,
+        recording_start_time: u64,
+        chirp_detection_times: Vec<u64>,
+        confidence: f32,
+    },
+    Result {
+        timestamp: u64,
+        session_id: Uuid,
+        measured_latency_ms: f32,
+        applied_offset_ms: f32,
+        confidence: f32,
+    },
+}
+
+impl From<CalibrationMessage> for BinaryMessage {
+    fn from(msg: CalibrationMessage) -> Self {
+        match msg {
+            CalibrationMessage::CalibrationRequest { timestamp, session_id } => {
+                BinaryMessage::Request { timestamp, session_id }
+            }
+            CalibrationMessage::CalibrationReady {
+                timestamp,
+                session_id,
+                countdown,
+                chirp_config,
+            } => BinaryMessage::Ready {
+                timestamp,
+                session_id,
+                countdown,
+                chirp_config,
+            },
+            CalibrationMessage::CalibrationData {
+                timestamp,
+                session_id,
+                recording_start_time,
+                chirp_detection_times,
+                confidence,
+            } => BinaryMessage::Data {
+                timestamp,
+                session_id,
+                recording_start_time,
+                chirp_detection_times,
+                confidence,
+            },
+            CalibrationMessage::CalibrationResult {
+                timestamp,
+                session_id,
+                measured_latency_ms,
+                applied_offset_ms,
+                confidence,
+            } => BinaryMessage::Result {
+                timestamp,
+                session_id,
+                measured_latency_ms,
+                applied_offset_ms,
+                confidence,
+            },
+        }
+    }
+}
+
+impl From<BinaryMessage> for CalibrationMessage {
+    fn from(msg: BinaryMessage) -> Self {
+        match msg {
+            BinaryMessage::Request { timestamp, session_id } => {
+                CalibrationMessage::CalibrationRequest { timestamp, session_id }
+            }
+            BinaryMessage::Ready {
+                timestamp,
+                session_id,
+                countdown,
+                chirp_config,
+            } => CalibrationMessage::CalibrationReady {
+                timestamp,
+                session_id,
+                countdown,
+                chirp_config,
+            },
+            BinaryMessage::Data {
+                timestamp,
+                session_id,
+                recording_start_time,
+                chirp_detection_times,
+                confidence,
+            } => CalibrationMessage::CalibrationData {
+                timestamp,
+                session_id,
+                recording_start_time,
+                chirp_detection_times,
+                confidence,
+            },
+            BinaryMessage::Result {
+                timestamp,
+                session_id,
+                measured_latency_ms,
+                applied_offset_ms,
+                confidence,
+            } => CalibrationMessage::CalibrationResult {
+                timestamp,
+                session_id,
+                measured_latency_ms,
+                applied_offset_ms,
+                confidence,
+            },
+        }
+    }
+}
+
+/// What applying a measured latency did to the receiver's offset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalibrationOutcome {
+    pub measured_latency_ms: f32,
+    pub applied_offset_ms: f32,
+    pub was_clamped: bool,
+    /// Config keys the apply changed, as `group.key`; empty when it is not known.
+    pub changed_keys: Vec<String>,
+}
+
+impl CalibrationOutcome {
+    /// Recover an outcome from a `CalibrationResult` message. The message doesn't carry
+    /// `was_clamped`, so it is inferred from the offset not mirroring the latency, nor
+    /// `changed_keys`, which are left empty.
+    pub fn from_result_message(msg: &CalibrationMessage) -> Option<Self> {
+        match msg {
+            CalibrationMessage::CalibrationResult {
+                measured_latency_ms,
+                applied_offset_ms,
+                ..
+            } => Some(CalibrationOutcome {
+                measured_latency_ms: *measured_latency_ms,
+                applied_offset_ms: *applied_offset_ms,
+                was_clamped: (measured_latency_ms + applied_offset_ms).abs() > 0.01,
+                changed_keys: Vec::new(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Stamped with the current time; the outcome carries no confidence or session, so they are
+/// reported as 0.0 and the nil id.
+impl From<CalibrationOutcome> for CalibrationMessage {
+    fn from(outcome: CalibrationOutcome) -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0);
+        CalibrationMessage::CalibrationResult {
+            timestamp,
+            session_id: Uuid::nil(),
+            measured_latency_ms: outcome.measured_latency_ms,
+            applied_offset_ms: outcome.applied_offset_ms,
+            confidence: 0.0,
+        }
+    }
+}
+
+/// Body of a successful calibration apply, e.g. from `/api/calibration/result`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CalibrationApplyResponse {
+    pub measured_latency_ms: f32,
+    pub applied_offset_ms: f32,
+    pub was_clamped: bool,
+    pub output_device: OutputDeviceSpec,
+    pub config_generation: u64,
+    /// Config keys the apply changed, as `group.key`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changed_keys: Vec<String>,
+}
+
+impl CalibrationApplyResponse {
+    /// Response for an outcome applied against the default output device at generation 0;
+    /// receivers that know better overwrite those two fields.
+    pub fn from_outcome(outcome: &CalibrationOutcome) -> Self {
+        Self {
+            measured_latency_ms: outcome.measured_latency_ms,
+            applied_offset_ms: outcome.applied_offset_ms,
+            was_clamped: outcome.was_clamped,
+            output_device: OutputDeviceSpec::default(),
+            config_generation: 0,
+            changed_keys: outcome.changed_keys.clone(),
+        }
+    }
+}
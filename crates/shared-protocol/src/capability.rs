@@ -0,0 +1,346 @@
+use crate::device::AudioOutput;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Layout of the `_airsync._tcp` TXT records, advertised as `ver`. Version 2 splits the
+/// single `caps` record into the `out` and `feat` groups; features that don't fit one
+/// record continue in `feat2`, `feat3` and so on.
+pub const TXT_LAYOUT_VERSION: u32 = 2;
+/// HTTP API protocol the receiver speaks, advertised as `proto`. Clients pick the highest
+/// version both sides support, treating a missing record as 1. Version 2 answers
+/// `POST /api/calibration/result` with `202` and a job to poll instead of the applied
+/// calibration; version 1 clients only check the status, so they keep working.
+pub const API_PROTOCOL_VERSION: u32 = 2;
+/// Oldest protocol version a receiver accepts from a client at `POST /api/pairing/start`.
+pub const MIN_CLIENT_PROTOCOL_VERSION: u32 = 1;
+
+/// Longest `key=value` string a single TXT entry can hold (one length byte).
+pub const MAX_TXT_RECORD_BYTES: usize = 255;
+/// Size budget for all TXT entries, length bytes included, so the announcement still fits
+/// a single mDNS packet on a standard Ethernet MTU.
+pub const MAX_TXT_TOTAL_BYTES: usize = 1300;
+
+/// Something a receiver can do, advertised in its TXT records. Audio outputs go in the
+/// `out` group by their serialized name, everything else in `feat` by a short code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Capability {
+    Output(AudioOutput),
+    Calibration,
+    SelfCalibration,
+    WebSocket,
+    Volume,
+    Groups,
+    Multiroom,
+    WebUi,
+    /// A capability this build doesn't know, kept by name so it survives a round trip.
+    Other(String),
+}
+
+const FEATURES: [(Capability, &str, &str); 7] = [
+    (Capability::Calibration, "calibration", "cal"),
+    (Capability::SelfCalibration, "selfcal", "sc"),
+    (Capability::WebSocket, "ws", "ws"),
+    (Capability::Volume, "volume", "vol"),
+    (Capability::Groups, "groups", "grp"),
+    (Capability::Multiroom, "multiroom", "mr"),
+    (Capability::WebUi, "web_ui", "web"),
+];
+
+const OUTPUTS: [AudioOutput; 4] = [AudioOutput::I2S, AudioOutput::USB, AudioOutput::HDMI, AudioOutput::Headphone];
+
+impl Capability {
+    /// Full name, as listed in `DiscoveredReceiver::capabilities` and the legacy `caps` record.
+    pub fn name(&self) -> &str {
+        match self {
+            Capability::Output(output) => output.as_str(),
+            Capability::Other(name) => name,
+            known => FEATURES.iter().find(|(c, ..)| c == known).map_or("", |(_, name, _)| name),
+        }
+    }
+
+    /// Code used in the `feat` record; outputs and unknown capabilities use their name.
+    pub fn code(&self) -> &str {
+        match self {
+            Capability::Output(_) | Capability::Other(_) => self.name(),
+            known => FEATURES.iter().find(|(c, ..)| c == known).map_or("", |(.., code)| code),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Self {
+        if let Some(output) = OUTPUTS.into_iter().find(|o| o.as_str() == name) {
+            return Capability::Output(output);
+        }
+        FEATURES
+            .iter()
+            .find(|(_, n, _)| *n == name)
+            .map_or_else(|| Capability::Other(name.to_string()), |(c, ..)| c.clone())
+    }
+
+    fn from_code(code: &str) -> Self {
+        FEATURES
+            .iter()
+            .find(|(.., c)| *c == code)
+            .map_or_else(|| Capability::from_name(code), |(c, ..)| c.clone())
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum TxtRecordError {
+    #[error("TXT record `{key}` is {len} bytes, the limit is {MAX_TXT_RECORD_BYTES}")]
+    RecordTooLong { key: String, len: usize },
+    #[error("TXT records total {0} bytes, the limit is {MAX_TXT_TOTAL_BYTES}")]
+    TotalTooLong(usize),
+}
+
+/// Where a receiver serves HTTPS and the certificate it serves there. The certificate is
+/// self-signed, so clients pin `fingerprint` the first time they see it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsEndpoint {
+    pub port: u16,
+    /// Lowercase hex SHA-256 of the certificate's DER encoding.
+    pub fingerprint: String,
+}
+
+impl TlsEndpoint {
+    /// The endpoint advertised in a receiver's `tls` and `tlsfp` TXT records, if both are
+    /// present and the port parses.
+    pub fn from_txt(txt: &HashMap<&str, &str>) -> Option<Self> {
+        Some(Self {
+            port: txt.get("tls")?.trim().parse().ok()?,
+            fingerprint: txt.get("tlsfp")?.trim().to_ascii_lowercase(),
+        })
+    }
+}
+
+/// What a receiver announces about itself over mDNS.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceiverAdvertisement<'a> {
+    pub name: &'a str,
+    pub receiver_id: &'a str,
+    pub firmware_version: &'a str,
+    pub capabilities: &'a [Capability],
+    /// HTTPS endpoint, advertised as `tls` (port) and `tlsfp` (fingerprint).
+    pub tls: Option<&'a TlsEndpoint>,
+    /// Still in first-boot setup, advertised as `setup=1`.
+    pub setup: bool,
+}
+
+impl ReceiverAdvertisement<'_> {
+    /// TXT entries in announcement order, checked against the per-entry and total limits
+    /// (avahi would otherwise truncate them without a word).
+    pub fn txt_records(&self) -> Result<Vec<(String, String)>, TxtRecordError> {
+        let (outputs, features): (Vec<&Capability>, Vec<&Capability>) =
+            self.capabilities.iter().partition(|c| matches!(c, Capability::Output(_)));
+        let outputs: Vec<&str> = outputs.into_iter().map(Capability::code).collect();
+
+        let mut records: Vec<(String, String)> = vec![
+            ("name".into(), self.name.to_string()),
+            ("ver".into(), TXT_LAYOUT_VERSION.to_string()),
+            ("proto".into(), API_PROTOCOL_VERSION.to_string()),
+            ("fw".into(), self.firmware_version.to_string()),
+            ("api".into(), "/api".into()),
+            ("out".into(), outputs.join(",")),
+        ];
+        let mut feature_group = String::new();
+        for code in features.into_iter().map(Capability::code) {
+            let key = feature_key(records.len() - 5);
+            if !feature_group.is_empty() && key.len() + 1 + feature_group.len() + 1 + code.len() > MAX_TXT_RECORD_BYTES {
+                records.push((key, std::mem::take(&mut feature_group)));
+            }
+            if !feature_group.is_empty() {
+                feature_group.push(',');
+            }
+            feature_group.push_str(code);
+        }
+        records.push((feature_key(records.len() - 5), feature_group));
+        if let Some(tls) = self.tls {
+            records.push(("tls".into(), tls.port.to_string()));
+            records.push(("tlsfp".into(), tls.fingerprint.clone()));
+        }
+        if self.setup {
+            records.push(("setup".into(), "1".into()));
+        }
+        records.push(("id".into(), self.receiver_id.to_string()));
+
+        let mut total = 0;
+        for (key, value) in &records {
+            let len = key.len() + 1 + value.len();
+            if len > MAX_TXT_RECORD_BYTES {
+                return Err(TxtRecordError::RecordTooLong { key: key.clone(), len });
+            }
+            total += len + 1;
+        }
+        if total > MAX_TXT_TOTAL_BYTES {
+            return Err(TxtRecordError::TotalTooLong(total));
+        }
+        Ok(records)
+    }
+}
+
+/// Key of the `index`th feature record (from 1): `feat`, then `feat2`, `feat3`...
+fn feature_key(index: usize) -> String {
+    match index {
+        1 => "feat".into(),
+        n => format!("feat{n}"),
+    }
+}
+
+/// Capabilities from a receiver's TXT records: the `out` and `feat` groups, or the
+/// legacy `caps` list from receivers advertising layout version 1.
+pub fn capabilities_from_txt(txt: &HashMap<&str, &str>) -> Vec<Capability> {
+    let split = |value: &str| -> Vec<String> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .map(String::from)
+            .collect()
+    };
+    if !txt.contains_key("out") && !txt.contains_key("feat") {
+        return txt
+            .get("caps")
+            .map(|caps| split(caps).iter().map(|name| Capability::from_name(name)).collect())
+            .unwrap_or_default();
+    }
+    let outputs = txt.get("out").map(|out| split(out)).unwrap_or_default();
+    let features = (1..)
+        .map_while(|index| txt.get(feature_key(index).as_str()))
+        .flat_map(|feat| split(feat));
+    outputs
+        .iter()
+        .map(|name| Capability::from_name(name))
+        .chain(features.map(|code| Capability::from_code(&code)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn advertisement(capabilities: &[Capability]) -> ReceiverAdvertisement<'_> {
+        ReceiverAdvertisement {
+            name: "Living Room",
+            receiver_id: "rx-1",
+            firmware_version: "0.1.0",
+            capabilities,
+            tls: None,
+            setup: false,
+        }
+    }
+
+    #[test]
+    fn capabilities_roundtrip_through_grouped_records() {
+        let caps = vec![
+            Capability::Output(AudioOutput::I2S),
+            Capability::Output(AudioOutput::Headphone),
+            Capability::Calibration,
+            Capability::SelfCalibration,
+            Capability::WebSocket,
+            Capability::Volume,
+            Capability::Groups,
+            Capability::Multiroom,
+            Capability::WebUi,
+            Capability::Other("lossless".into()),
+        ];
+        let records = advertisement(&caps).txt_records().unwrap();
+        let txt: HashMap<&str, &str> = records.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(txt["out"], "i2s,headphone");
+        assert_eq!(txt["feat"], "cal,sc,ws,vol,grp,mr,web,lossless");
+        assert_eq!(txt["ver"], "2");
+        assert_eq!(txt["proto"], "2");
+        assert_eq!(capabilities_from_txt(&txt), caps);
+    }
+
+    #[test]
+    fn tls_endpoint_roundtrips_through_records() {
+        let endpoint = TlsEndpoint {
+            port: 5443,
+            fingerprint: "ab".repeat(32),
+        };
+        let caps = [Capability::Calibration];
+        let records = ReceiverAdvertisement {
+            tls: Some(&endpoint),
+            ..advertisement(&caps)
+        }
+        .txt_records()
+        .unwrap();
+        let keys: Vec<&str> = records.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, vec!["name", "ver", "proto", "fw", "api", "out", "feat", "tls", "tlsfp", "id"]);
+        let txt: HashMap<&str, &str> = records.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(TlsEndpoint::from_txt(&txt), Some(endpoint));
+        assert_eq!(capabilities_from_txt(&txt), caps);
+
+        let plain = advertisement(&caps).txt_records().unwrap();
+        let txt: HashMap<&str, &str> = plain.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(TlsEndpoint::from_txt(&txt), None);
+    }
+
+    #[test]
+    fn setup_mode_is_advertised_only_while_active() {
+        let caps = [Capability::Calibration];
+        let records = ReceiverAdvertisement {
+            setup: true,
+            ..advertisement(&caps)
+        }
+        .txt_records()
+        .unwrap();
+        assert!(records.contains(&("setup".into(), "1".into())));
+        assert!(!advertisement(&caps).txt_records().unwrap().iter().any(|(k, _)| k == "setup"));
+    }
+
+    #[test]
+    fn legacy_caps_record_is_still_understood() {
+        let txt = HashMap::from([("caps", "i2s, calibration,web_ui")]);
+        assert_eq!(
+            capabilities_from_txt(&txt),
+            vec![Capability::Output(AudioOutput::I2S), Capability::Calibration, Capability::WebUi]
+        );
+        assert!(capabilities_from_txt(&HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn long_feature_lists_continue_in_numbered_records() {
+        let caps: Vec<Capability> = (0..40).map(|i| Capability::Other(format!("feature-{i:02}"))).collect();
+        let records = advertisement(&caps).txt_records().unwrap();
+        let keys: Vec<&str> = records.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, vec!["name", "ver", "proto", "fw", "api", "out", "feat", "feat2", "id"]);
+        assert!(records.iter().all(|(k, v)| k.len() + 1 + v.len() <= MAX_TXT_RECORD_BYTES));
+        let txt: HashMap<&str, &str> = records.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(capabilities_from_txt(&txt), caps);
+    }
+
+    #[test]
+    fn oversized_records_are_rejected() {
+        let long = vec![Capability::Other("x".repeat(251))];
+        assert_eq!(
+            advertisement(&long).txt_records(),
+            Err(TxtRecordError::RecordTooLong {
+                key: "feat".into(),
+                len: 256,
+            })
+        );
+
+        let name = "n".repeat(300);
+        let unnamed = ReceiverAdvertisement {
+            name: &name,
+            ..advertisement(&[])
+        };
+        assert!(matches!(
+            unnamed.txt_records(),
+            Err(TxtRecordError::RecordTooLong { key, len: 305 }) if key == "name"
+        ));
+
+        let many: Vec<Capability> = (0..200).map(|i| Capability::Other(format!("feature-{i:03}"))).collect();
+        assert!(matches!(
+            advertisement(&many).txt_records(),
+            Err(TxtRecordError::TotalTooLong(total)) if total > MAX_TXT_TOTAL_BYTES
+        ));
+    }
+}
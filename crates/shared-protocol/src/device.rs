@@ -1,4 +1,10 @@
-use serde::{Deserialize, Serialize};
+use crate::capability::Capability;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct HardwareCapabilities {
@@ -7,6 +13,75 @@ pub struct HardwareCapabilities {
     pub board_id: String,
     pub audio_outputs: Vec<AudioOutput>,
     pub preferred_output: AudioOutput,
+    /// SoC temperature; `None` where the board exposes no thermal zone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature_c: Option<f32>,
+    /// Whether the firmware reports the CPU as throttled right now (Raspberry Pi only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub throttled: Option<bool>,
+    /// Free space on the root filesystem, where calibration signals are rendered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub available_mb: Option<u64>,
+    /// Distribution and kernel; `None` where `/etc/os-release` or `/proc/version` can't be read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<OsInfo>,
+    /// Estimated memory bandwidth in MB/s, a heuristic from `/proc/meminfo`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_speed_hint: Option<u64>,
+}
+
+/// Operating system the receiver runs on, for diagnostic reports.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OsInfo {
+    /// `ID` from os-release, e.g. `debian` or `ubuntu`.
+    pub distro: String,
+    /// `VERSION_ID` from os-release, e.g. `12` or `22.04`; empty for rolling releases.
+    pub version: String,
+    /// `PRETTY_NAME` from os-release, e.g. `Debian GNU/Linux 12 (bookworm)`.
+    #[serde(default)]
+    pub pretty_name: String,
+    /// Kernel release, e.g. `6.6.31+rpt-rpi-v8`.
+    pub kernel: String,
+}
+
+impl HardwareCapabilities {
+    /// Write the capabilities as JSON, e.g. to the receiver's `hardware.json` cache.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    /// Whether the hardware meets `profile`'s requirements, i.e. it selects that profile
+    /// or a higher one.
+    pub fn is_capable_for_profile(&self, profile: HardwareProfile) -> bool {
+        HardwareProfile::select(self) >= profile
+    }
+
+    /// Whether the hardware selects the enhanced profile.
+    pub fn is_enhanced(&self) -> bool {
+        HardwareProfile::select(self) == HardwareProfile::Enhanced
+    }
+
+    /// Whether the hardware selects the standard profile, and not the enhanced one.
+    pub fn is_standard(&self) -> bool {
+        HardwareProfile::select(self) == HardwareProfile::Standard
+    }
+
+    /// Whether the hardware falls short of the standard profile.
+    pub fn is_minimal(&self) -> bool {
+        HardwareProfile::select(self) == HardwareProfile::Minimal
+    }
+
+    /// Every profile the hardware meets the requirements of, lowest first.
+    pub fn all_qualifying_profiles(&self) -> Vec<HardwareProfile> {
+        HardwareProfile::ALL
+            .into_iter()
+            .filter(|profile| self.is_capable_for_profile(*profile))
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -18,6 +93,162 @@ pub enum AudioOutput {
     Headphone,
 }
 
+impl AudioOutput {
+    /// Lowercase name, as serialized and as advertised in the mDNS `caps` record.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AudioOutput::I2S => "i2s",
+            AudioOutput::USB => "usb",
+            AudioOutput::HDMI => "hdmi",
+            AudioOutput::Headphone => "headphone",
+        }
+    }
+}
+
+/// Sound card reference inside an ALSA `hw` device: `hw:1,0` or `hw:CARD=name,DEV=0`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CardRef {
+    Index(u32),
+    Name(String),
+}
+
+/// ALSA PCM that shairport-sync and aplay write to.
+///
+/// Serialized as its ALSA string form. Index cards render as `hw:C,D`, named cards as
+/// `hw:CARD=name,DEV=D`; `plughw:` and `plug:` prefixes are preserved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputDeviceSpec {
+    Hw { card: CardRef, device: u32 },
+    /// Slave PCM wrapped by the `plug` converter, e.g. `hw:1,0` for `plughw:1,0`.
+    Plug(String),
+    /// Any other PCM name, such as `default`, `hdmi` or `hdmi:CARD=vc4hdmi0`.
+    Named(String),
+}
+
+impl OutputDeviceSpec {
+    pub fn hw(card: u32, device: u32) -> Self {
+        OutputDeviceSpec::Hw {
+            card: CardRef::Index(card),
+            device,
+        }
+    }
+}
+
+impl Default for OutputDeviceSpec {
+    fn default() -> Self {
+        OutputDeviceSpec::Named("default".into())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum OutputDeviceParseError {
+    #[error("output device is empty")]
+    Empty,
+    #[error("output device `{0}` contains characters not allowed in an ALSA PCM name")]
+    InvalidCharacter(String),
+    #[error("malformed hw device `{0}`")]
+    InvalidHw(String),
+}
+
+fn validate_pcm_name(s: &str) -> Result<(), OutputDeviceParseError> {
+    if s.is_empty() {
+        return Err(OutputDeviceParseError::Empty);
+    }
+    if s.chars().any(|c| c.is_whitespace() || c.is_control() || matches!(c, '"' | ';' | '\\')) {
+        return Err(OutputDeviceParseError::InvalidCharacter(s.to_string()));
+    }
+    Ok(())
+}
+
+fn parse_card_ref(value: &str) -> Option<CardRef> {
+    if value.is_empty() {
+        return None;
+    }
+    match value.parse() {
+        Ok(index) => Some(CardRef::Index(index)),
+        Err(_) if value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') => {
+            Some(CardRef::Name(value.to_string()))
+        }
+        Err(_) => None,
+    }
+}
+
+/// Parse the argument list after `hw:`, positional (`1,0`) or keyed (`CARD=x,DEV=0`).
+fn parse_hw_args(args: &str) -> Option<(CardRef, u32)> {
+    let mut card = None;
+    let mut device = None;
+    for (position, arg) in args.split(',').enumerate() {
+        let (key, value) = match arg.split_once('=') {
+            Some((key, value)) => (key, value),
+            None if position == 0 => ("CARD", arg),
+            None if position == 1 => ("DEV", arg),
+            None => return None,
+        };
+        match key {
+            "CARD" if card.is_none() => card = Some(parse_card_ref(value)?),
+            "DEV" if device.is_none() => device = Some(value.parse().ok()?),
+            _ => return None,
+        }
+    }
+    Some((card?, device.unwrap_or(0)))
+}
+
+impl FromStr for OutputDeviceSpec {
+    type Err = OutputDeviceParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        validate_pcm_name(s)?;
+        if let Some(args) = s.strip_prefix("hw:") {
+            let (card, device) =
+                parse_hw_args(args).ok_or_else(|| OutputDeviceParseError::InvalidHw(s.to_string()))?;
+            return Ok(OutputDeviceSpec::Hw { card, device });
+        }
+        if let Some(args) = s.strip_prefix("plughw:") {
+            parse_hw_args(args).ok_or_else(|| OutputDeviceParseError::InvalidHw(s.to_string()))?;
+            return Ok(OutputDeviceSpec::Plug(format!("hw:{args}")));
+        }
+        if let Some(slave) = s.strip_prefix("plug:") {
+            validate_pcm_name(slave)?;
+            return Ok(OutputDeviceSpec::Plug(slave.to_string()));
+        }
+        Ok(OutputDeviceSpec::Named(s.to_string()))
+    }
+}
+
+impl fmt::Display for OutputDeviceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputDeviceSpec::Hw {
+                card: CardRef::Index(card),
+                device,
+            } => write!(f, "hw:{card},{device}"),
+            OutputDeviceSpec::Hw {
+                card: CardRef::Name(card),
+                device,
+            } => write!(f, "hw:CARD={card},DEV={device}"),
+            OutputDeviceSpec::Plug(slave) => match slave.strip_prefix("hw:") {
+                Some(args) => write!(f, "plughw:{args}"),
+                None => write!(f, "plug:{slave}"),
+            },
+            OutputDeviceSpec::Named(name) => f.write_str(name),
+        }
+    }
+}
+
+impl Serialize for OutputDeviceSpec {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for OutputDeviceSpec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        raw.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 /// Minimum requirements for AirPlay 2 receiver
 pub const MIN_CPU_CORES: usize = 4;
 pub const MIN_RAM_MB: usize = 1024; // AirPlay 2 requires at least 1GB for reliable performance
@@ -29,6 +260,64 @@ pub fn is_capable(capabilities: &HardwareCapabilities) -> bool {
         && !capabilities.audio_outputs.is_empty()
 }
 
+/// RAM needed for the enhanced profile, which also serves the web UI.
+pub const ENHANCED_RAM_MB: usize = 2048;
+
+/// Resource tier a receiver runs at, ordered from least to most demanding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HardwareProfile {
+    Minimal,
+    Standard,
+    Enhanced,
+}
+
+impl HardwareProfile {
+    pub const ALL: [HardwareProfile; 3] = [HardwareProfile::Minimal, HardwareProfile::Standard, HardwareProfile::Enhanced];
+
+    /// Minimal below the AirPlay 2 requirements, enhanced from `ENHANCED_RAM_MB`.
+    pub fn select(capabilities: &HardwareCapabilities) -> Self {
+        if !is_capable(capabilities) {
+            HardwareProfile::Minimal
+        } else if capabilities.ram_mb >= ENHANCED_RAM_MB {
+            HardwareProfile::Enhanced
+        } else {
+            HardwareProfile::Standard
+        }
+    }
+
+    pub fn features(self) -> FeatureSet {
+        FeatureSet {
+            calibration: true,
+            web_ui: self == HardwareProfile::Enhanced,
+        }
+    }
+}
+
+/// Optional features a receiver offers, advertised alongside its audio outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct FeatureSet {
+    pub calibration: bool,
+    pub web_ui: bool,
+}
+
+impl FeatureSet {
+    /// Names of the enabled features, in advertisement order.
+    pub fn names(&self) -> Vec<&'static str> {
+        [(self.calibration, "calibration"), (self.web_ui, "web_ui")]
+            .into_iter()
+            .filter_map(|(enabled, name)| enabled.then_some(name))
+            .collect()
+    }
+
+    pub fn capabilities(&self) -> Vec<Capability> {
+        [(self.calibration, Capability::Calibration), (self.web_ui, Capability::WebUi)]
+            .into_iter()
+            .filter_map(|(enabled, capability)| enabled.then_some(capability))
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -40,6 +329,11 @@ mod tests {
             board_id: "test".to_string(),
             audio_outputs: vec![AudioOutput::Headphone],
             preferred_output: AudioOutput::Headphone,
+            temperature_c: None,
+            throttled: None,
+            available_mb: None,
+            os: None,
+            memory_speed_hint: None,
         }
     }
 
@@ -61,6 +355,47 @@ mod tests {
         assert!(is_capable(&caps));
     }
 
+    #[test]
+    fn profile_follows_resources() {
+        assert_eq!(HardwareProfile::select(&create_capabilities(512, 4)), HardwareProfile::Minimal);
+        assert_eq!(HardwareProfile::select(&create_capabilities(1024, 4)), HardwareProfile::Standard);
+        let enhanced = HardwareProfile::select(&create_capabilities(4096, 4));
+        assert_eq!(enhanced, HardwareProfile::Enhanced);
+        assert_eq!(enhanced.features().names(), vec!["calibration", "web_ui"]);
+        assert_eq!(HardwareProfile::Standard.features().names(), vec!["calibration"]);
+    }
+
+    #[test]
+    fn profile_checks_cover_every_ram_and_core_count() {
+        use HardwareProfile::{Enhanced, Minimal, Standard};
+        let cases = [
+            (512, 1, Minimal),
+            (512, 4, Minimal),
+            (1024, 2, Minimal),
+            (1024, 4, Standard),
+            (1024, 8, Standard),
+            (2048, 1, Minimal),
+            (2048, 4, Enhanced),
+            (4096, 2, Minimal),
+            (4096, 4, Enhanced),
+            (8192, 8, Enhanced),
+        ];
+        for (ram_mb, cores, selected) in cases {
+            let caps = create_capabilities(ram_mb, cores);
+            let label = format!("{ram_mb}MB/{cores} cores");
+            assert_eq!(caps.is_minimal(), selected == Minimal, "{label}");
+            assert_eq!(caps.is_standard(), selected == Standard, "{label}");
+            assert_eq!(caps.is_enhanced(), selected == Enhanced, "{label}");
+            for profile in HardwareProfile::ALL {
+                assert_eq!(caps.is_capable_for_profile(profile), profile <= selected, "{label} vs {profile:?}");
+            }
+            let expected: Vec<_> = HardwareProfile::ALL.into_iter().filter(|p| *p <= selected).collect();
+            assert_eq!(caps.all_qualifying_profiles(), expected, "{label}");
+        }
+        assert_eq!(create_capabilities(4096, 4).all_qualifying_profiles(), vec![Minimal, Standard, Enhanced]);
+        assert_eq!(create_capabilities(512, 1).all_qualifying_profiles(), vec![Minimal]);
+    }
+
     #[test]
     fn rejects_raspberry_pi_zero_2w_insufficient_ram() {
         let caps = create_capabilities(512, 4); // Has 4 cores but only 512MB RAM
@@ -81,7 +416,122 @@ mod tests {
             board_id: "test".to_string(),
             audio_outputs: vec![], // No audio outputs
             preferred_output: AudioOutput::Headphone,
+            temperature_c: None,
+            throttled: None,
+            available_mb: None,
+            os: None,
+            memory_speed_hint: None,
         };
         assert!(!is_capable(&caps));
     }
+
+    #[test]
+    fn saved_capabilities_load_back_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hardware.json");
+        let caps = HardwareCapabilities {
+            temperature_c: Some(52.5),
+            throttled: Some(false),
+            available_mb: Some(1_024),
+            audio_outputs: vec![AudioOutput::I2S, AudioOutput::Headphone],
+            ..create_capabilities(4096, 4)
+        };
+        caps.save(&path).unwrap();
+        assert_eq!(HardwareCapabilities::load(&path).unwrap(), caps);
+
+        assert!(HardwareCapabilities::load(&dir.path().join("missing.json")).is_err());
+        fs::write(&path, "{not json").unwrap();
+        assert_eq!(
+            HardwareCapabilities::load(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn thermal_fields_default_when_absent() {
+        let caps: HardwareCapabilities = serde_json::from_str(
+            r#"{"cpu_cores":4,"ram_mb":1024,"board_id":"test","audio_outputs":["usb"],"preferred_output":"usb"}"#,
+        )
+        .unwrap();
+        assert_eq!((caps.temperature_c, caps.throttled), (None, None));
+        assert!(!serde_json::to_string(&caps).unwrap().contains("temperature_c"));
+    }
+
+    fn roundtrip(s: &str) -> OutputDeviceSpec {
+        let spec: OutputDeviceSpec = s.parse().unwrap();
+        assert_eq!(spec.to_string(), s);
+        spec
+    }
+
+    #[test]
+    fn parses_positional_hw_device() {
+        assert_eq!(roundtrip("hw:0,0"), OutputDeviceSpec::hw(0, 0));
+        assert_eq!(roundtrip("hw:1,3"), OutputDeviceSpec::hw(1, 3));
+    }
+
+    #[test]
+    fn parses_keyed_hw_device() {
+        assert_eq!(
+            roundtrip("hw:CARD=sndrpihifiberry,DEV=0"),
+            OutputDeviceSpec::Hw {
+                card: CardRef::Name("sndrpihifiberry".into()),
+                device: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn hw_defaults_device_zero_and_normalizes() {
+        let spec: OutputDeviceSpec = "hw:1".parse().unwrap();
+        assert_eq!(spec, OutputDeviceSpec::hw(1, 0));
+        assert_eq!(spec.to_string(), "hw:1,0");
+
+        let spec: OutputDeviceSpec = "hw:CARD=1,DEV=2".parse().unwrap();
+        assert_eq!(spec, OutputDeviceSpec::hw(1, 2));
+
+        let spec: OutputDeviceSpec = "hw:Headphones,0".parse().unwrap();
+        assert_eq!(spec.to_string(), "hw:CARD=Headphones,DEV=0");
+    }
+
+    #[test]
+    fn parses_named_pcms() {
+        assert_eq!(roundtrip("default"), OutputDeviceSpec::Named("default".into()));
+        assert_eq!(
+            roundtrip("hdmi:CARD=vc4hdmi0"),
+            OutputDeviceSpec::Named("hdmi:CARD=vc4hdmi0".into())
+        );
+        assert_eq!(roundtrip("hdmi"), OutputDeviceSpec::Named("hdmi".into()));
+    }
+
+    #[test]
+    fn parses_plug_devices() {
+        assert_eq!(roundtrip("plughw:1,0"), OutputDeviceSpec::Plug("hw:1,0".into()));
+        assert_eq!(roundtrip("plug:dmix"), OutputDeviceSpec::Plug("dmix".into()));
+    }
+
+    #[test]
+    fn rejects_malformed_devices() {
+        assert_eq!("".parse::<OutputDeviceSpec>(), Err(OutputDeviceParseError::Empty));
+        assert_eq!("   ".parse::<OutputDeviceSpec>(), Err(OutputDeviceParseError::Empty));
+        for bad in ["hw:", "hw:0,x", "hw:0,0,0", "hw:CARD=a,CARD=b", "hw:DEV=0", "plughw:x y"] {
+            assert!(bad.parse::<OutputDeviceSpec>().is_err(), "{bad} should not parse");
+        }
+        assert!(matches!(
+            "hw:0\";\ninjected".parse::<OutputDeviceSpec>(),
+            Err(OutputDeviceParseError::InvalidCharacter(_))
+        ));
+        assert!(matches!(
+            "plug:".parse::<OutputDeviceSpec>(),
+            Err(OutputDeviceParseError::Empty)
+        ));
+    }
+
+    #[test]
+    fn serializes_as_alsa_string() {
+        let json = serde_json::to_string(&OutputDeviceSpec::hw(1, 0)).unwrap();
+        assert_eq!(json, "\"hw:1,0\"");
+        let spec: OutputDeviceSpec = serde_json::from_str("\"hw:CARD=sndrpihifiberry,DEV=0\"").unwrap();
+        assert!(matches!(spec, OutputDeviceSpec::Hw { card: CardRef::Name(_), .. }));
+        assert!(serde_json::from_str::<OutputDeviceSpec>("\"hw:nope,\"").is_err());
+    }
 }
@@ -0,0 +1,330 @@
+use crate::capability::{capabilities_from_txt, TlsEndpoint};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// DNS-SD service type receivers advertise their HTTP API under.
+pub const SERVICE_TYPE: &str = "_airsync._tcp.local.";
+
+/// A receiver found on the LAN, parsed from its `_airsync._tcp` advertisement.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiscoveredReceiver {
+    pub receiver_id: String,
+    pub name: String,
+    pub address: IpAddr,
+    pub port: u16,
+    pub capabilities: Vec<String>,
+    /// Firmware version label from the `fw` key; older receivers don't send one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// API protocol version from the `proto` key; absent means version 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol_version: Option<u32>,
+    /// HTTPS endpoint from the `tls` and `tlsfp` keys, for receivers that serve it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsEndpoint>,
+    /// Advertises `setup=1`: still in first-boot setup, see `GET /api/setup`.
+    #[serde(default)]
+    pub setup: bool,
+}
+
+impl DiscoveredReceiver {
+    /// Parse a resolved instance from the TXT keys receivers emit (`id`, `name`, `fw`,
+    /// `proto`, `tls`/`tlsfp`, `setup`, and the capability groups read by [`capabilities_from_txt`]).
+    /// The name falls back to the instance name; returns `None` without an `id` or address.
+    pub fn from_txt<'a>(
+        fullname: &str,
+        txt: impl IntoIterator<Item = (&'a str, &'a str)>,
+        addresses: &[IpAddr],
+        port: u16,
+    ) -> Option<DiscoveredReceiver> {
+        let txt: HashMap<&str, &str> = txt.into_iter().collect();
+        let receiver_id = txt.get("id").map(|id| id.trim()).filter(|id| !id.is_empty())?;
+        let name = txt
+            .get("name")
+            .map(|n| n.trim().to_string())
+            .filter(|n| !n.is_empty())
+            .unwrap_or_else(|| instance_name(fullname).to_string());
+        let capabilities = capabilities_from_txt(&txt).iter().map(|c| c.name().to_string()).collect();
+        let version = txt.get("fw").map(|v| v.trim()).filter(|v| !v.is_empty()).map(String::from);
+        Some(DiscoveredReceiver {
+            receiver_id: receiver_id.to_string(),
+            name,
+            address: pick_address(addresses)?,
+            port,
+            capabilities,
+            version,
+            protocol_version: txt.get("proto").and_then(|p| p.trim().parse().ok()),
+            tls: TlsEndpoint::from_txt(&txt),
+            setup: txt.get("setup").is_some_and(|s| s.trim() == "1"),
+        })
+    }
+}
+
+fn instance_name(fullname: &str) -> &str {
+    fullname
+        .strip_suffix(SERVICE_TYPE)
+        .map(|n| n.trim_end_matches('.'))
+        .unwrap_or(fullname)
+}
+
+/// Prefer routable IPv4, then any IPv4, then IPv6 (non-link-local first).
+pub fn pick_address(addresses: &[IpAddr]) -> Option<IpAddr> {
+    let rank = |addr: &IpAddr| match addr {
+        IpAddr::V4(v4) if !v4.is_link_local() && !v4.is_loopback() => 0,
+        IpAddr::V4(_) => 1,
+        IpAddr::V6(v6) if (v6.segments()[0] & 0xffc0) != 0xfe80 => 2,
+        IpAddr::V6(_) => 3,
+    };
+    addresses.iter().min_by_key(|a| (rank(a), **a)).copied()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DiscoveryError {
+    #[error("mDNS browse failed: {0}")]
+    Browse(String),
+}
+
+/// A service instance as resolved by the mDNS layer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedService {
+    pub fullname: String,
+    pub txt: Vec<(String, String)>,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BrowseEvent {
+    Resolved(ResolvedService),
+    /// Goodbye for the instance with this full name.
+    Removed(String),
+}
+
+/// Source of mDNS browse results; `ReceiverDiscovery` only folds the events it returns.
+pub trait MdnsBrowser: Send + Sync {
+    /// Browse `service_type` for `timeout`, returning events in the order they arrived.
+    fn browse(&self, service_type: &str, timeout: Duration) -> Result<Vec<BrowseEvent>, DiscoveryError>;
+}
+
+/// Finds receivers on the LAN by browsing `_airsync._tcp`.
+pub struct ReceiverDiscovery {
+    browser: Box<dyn MdnsBrowser>,
+}
+
+impl ReceiverDiscovery {
+    /// Browse with the system mDNS stack.
+    #[cfg(feature = "mdns")]
+    pub fn new() -> Self {
+        Self::with_browser(MdnsSdBrowser)
+    }
+
+    pub fn with_browser(browser: impl MdnsBrowser + 'static) -> Self {
+        Self {
+            browser: Box::new(browser),
+        }
+    }
+
+    /// Receivers announced within `timeout` and not withdrawn since, ordered by receiver
+    /// id. Instances without an `id` or address are skipped; if one receiver is seen under
+    /// several instance names the last announcement wins.
+    pub fn discover(&self, timeout: Duration) -> Result<Vec<DiscoveredReceiver>, DiscoveryError> {
+        let mut instances: BTreeMap<String, DiscoveredReceiver> = BTreeMap::new();
+        for event in self.browser.browse(SERVICE_TYPE, timeout)? {
+            match event {
+                BrowseEvent::Resolved(service) => {
+                    let txt = service.txt.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+                    if let Some(receiver) =
+                        DiscoveredReceiver::from_txt(&service.fullname, txt, &service.addresses, service.port)
+                    {
+                        instances.retain(|_, r| r.receiver_id != receiver.receiver_id);
+                        instances.insert(service.fullname, receiver);
+                    }
+                }
+                BrowseEvent::Removed(fullname) => {
+                    instances.remove(&fullname);
+                }
+            }
+        }
+        let mut receivers: Vec<DiscoveredReceiver> = instances.into_values().collect();
+        receivers.sort_by(|a, b| a.receiver_id.cmp(&b.receiver_id));
+        Ok(receivers)
+    }
+}
+
+#[cfg(feature = "mdns")]
+impl Default for ReceiverDiscovery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `MdnsBrowser` backed by an `mdns-sd` daemon started for each browse.
+#[cfg(feature = "mdns")]
+pub struct MdnsSdBrowser;
+
+#[cfg(feature = "mdns")]
+impl MdnsBrowser for MdnsSdBrowser {
+    fn browse(&self, service_type: &str, timeout: Duration) -> Result<Vec<BrowseEvent>, DiscoveryError> {
+        use mdns_sd::{ServiceDaemon, ServiceEvent};
+        use std::time::Instant;
+
+        let daemon = ServiceDaemon::new().map_err(|e| DiscoveryError::Browse(e.to_string()))?;
+        let receiver = daemon
+            .browse(service_type)
+            .map_err(|e| DiscoveryError::Browse(e.to_string()))?;
+        let deadline = Instant::now() + timeout;
+        let mut events = Vec::new();
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            match receiver.recv_timeout(remaining) {
+                Ok(ServiceEvent::ServiceResolved(info)) => events.push(BrowseEvent::Resolved(ResolvedService {
+                    fullname: info.get_fullname().to_string(),
+                    txt: info
+                        .get_properties()
+                        .iter()
+                        .map(|p| (p.key().to_string(), p.val_str().to_string()))
+                        .collect(),
+                    addresses: info.get_addresses().iter().copied().collect(),
+                    port: info.get_port(),
+                })),
+                Ok(ServiceEvent::ServiceRemoved(_, fullname)) => events.push(BrowseEvent::Removed(fullname)),
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        let _ = daemon.shutdown();
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::sync::{Arc, Mutex};
+
+    /// Replays canned events and records the browse it was asked for.
+    struct MockBrowser {
+        events: Vec<BrowseEvent>,
+        requested: Arc<Mutex<Option<(String, Duration)>>>,
+    }
+
+    impl MdnsBrowser for MockBrowser {
+        fn browse(&self, service_type: &str, timeout: Duration) -> Result<Vec<BrowseEvent>, DiscoveryError> {
+            *self.requested.lock().unwrap() = Some((service_type.to_string(), timeout));
+            Ok(self.events.clone())
+        }
+    }
+
+    fn lan(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 168, 1, last))
+    }
+
+    /// An advertisement with the TXT records `render_avahi_service` writes.
+    fn advertised(name: &str, id: &str, caps: &str, address: IpAddr) -> BrowseEvent {
+        let txt = [("name", name), ("ver", "1"), ("fw", "0.1.0"), ("api", "/api"), ("caps", caps), ("id", id)];
+        BrowseEvent::Resolved(ResolvedService {
+            fullname: format!("{name}.{SERVICE_TYPE}"),
+            txt: txt.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            addresses: vec![address],
+            port: 5000,
+        })
+    }
+
+    fn discover(events: Vec<BrowseEvent>) -> Vec<DiscoveredReceiver> {
+        let discovery = ReceiverDiscovery::with_browser(MockBrowser {
+            events,
+            requested: Arc::default(),
+        });
+        discovery.discover(Duration::from_millis(10)).unwrap()
+    }
+
+    #[test]
+    fn parses_each_advertised_txt_field() {
+        let receivers = discover(vec![advertised("Kitchen", "rx-42", "calibration,multiroom", lan(20))]);
+        assert_eq!(
+            receivers,
+            vec![DiscoveredReceiver {
+                receiver_id: "rx-42".into(),
+                name: "Kitchen".into(),
+                address: lan(20),
+                port: 5000,
+                capabilities: vec!["calibration".into(), "multiroom".into()],
+                version: Some("0.1.0".into()),
+                protocol_version: None,
+                tls: None,
+                setup: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn receivers_in_setup_mode_are_flagged() {
+        let mut event = advertised("raspberrypi", "rx-new", "calibration", lan(30));
+        if let BrowseEvent::Resolved(service) = &mut event {
+            service.txt.push(("setup".into(), "1".into()));
+        }
+        let receivers = discover(vec![event, advertised("Kitchen", "rx-a", "calibration", lan(20))]);
+        let flags: Vec<(&str, bool)> = receivers.iter().map(|r| (r.receiver_id.as_str(), r.setup)).collect();
+        assert_eq!(flags, vec![("rx-a", false), ("rx-new", true)]);
+    }
+
+    #[test]
+    fn browses_airsync_service_for_requested_timeout() {
+        let requested = Arc::default();
+        let discovery = ReceiverDiscovery::with_browser(MockBrowser {
+            events: vec![],
+            requested: Arc::clone(&requested),
+        });
+        assert!(discovery.discover(Duration::from_secs(2)).unwrap().is_empty());
+        assert_eq!(
+            *requested.lock().unwrap(),
+            Some((SERVICE_TYPE.to_string(), Duration::from_secs(2)))
+        );
+    }
+
+    #[test]
+    fn withdrawn_and_incomplete_instances_are_skipped() {
+        let no_id = BrowseEvent::Resolved(ResolvedService {
+            fullname: format!("Attic.{SERVICE_TYPE}"),
+            txt: vec![("name".into(), "Attic".into())],
+            addresses: vec![lan(9)],
+            port: 5000,
+        });
+        let receivers = discover(vec![
+            advertised("Kitchen", "rx-b", "calibration", lan(20)),
+            advertised("Den", "rx-a", "calibration", lan(21)),
+            advertised("Porch", "rx-c", "calibration", lan(22)),
+            BrowseEvent::Removed(format!("Porch.{SERVICE_TYPE}")),
+            no_id,
+        ]);
+        let ids: Vec<&str> = receivers.iter().map(|r| r.receiver_id.as_str()).collect();
+        assert_eq!(ids, vec!["rx-a", "rx-b"]);
+    }
+
+    #[test]
+    fn renamed_receiver_appears_once() {
+        let receivers = discover(vec![
+            advertised("Kitchen", "rx-a", "calibration", lan(20)),
+            advertised("Galley", "rx-a", "calibration", lan(20)),
+        ]);
+        assert_eq!(receivers.len(), 1);
+        assert_eq!(receivers[0].name, "Galley");
+    }
+
+    #[test]
+    fn name_falls_back_to_instance_and_address_prefers_routable_ipv4() {
+        let link_local_v6 = IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1));
+        let global_v6 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        let link_local_v4 = IpAddr::V4(Ipv4Addr::new(169, 254, 3, 4));
+        let addresses = [link_local_v6, global_v6, link_local_v4, lan(20)];
+        let receiver =
+            DiscoveredReceiver::from_txt(&format!("Kitchen.{SERVICE_TYPE}"), [("id", "rx-1")], &addresses, 5000)
+                .unwrap();
+        assert_eq!(receiver.name, "Kitchen");
+        assert_eq!(receiver.address, lan(20));
+        assert!(receiver.capabilities.is_empty());
+        assert_eq!(pick_address(&[link_local_v6, global_v6]), Some(global_v6));
+    }
+}
@@ -0,0 +1,105 @@
+use crate::{MarkerKind, MarkerSpec};
+use std::f32::consts::PI;
+
+/// Raised-cosine envelope gain for sample `n` of a `len`-sample segment faded in and out
+/// over `fade_samples` (at least one sample, at most half the segment).
+pub fn raised_cosine_window(n: usize, len: usize, fade_samples: usize) -> f32 {
+    if len <= 1 {
+        return 1.0;
+    }
+    let fade = fade_samples.max(1).min(len / 2);
+    if n < fade {
+        0.5 - 0.5 * (PI * n as f32 / fade as f32).cos()
+    } else if n >= len - fade {
+        let k = len - 1 - n;
+        0.5 - 0.5 * (PI * k as f32 / fade as f32).cos()
+    } else {
+        1.0
+    }
+}
+
+/// Matched-filter reference for `marker`: exactly the samples the receiver's signal builder
+/// mixes at `start_sample`, scaled by `amplitude` and faded by `fade_samples`. Clicks are a
+/// DC pulse; chirps with equal start and end frequencies are pure tones, others linear sweeps.
+pub fn reference_samples(marker: &MarkerSpec, sample_rate: u32) -> Vec<f32> {
+    let len = marker.duration_samples as usize;
+    let fade = marker.fade_samples as usize;
+    let sr = sample_rate as f32;
+    let wave: Box<dyn Fn(f32) -> f32> = match marker.kind {
+        MarkerKind::Click => Box::new(|_| 1.0),
+        MarkerKind::Chirp {
+            start_freq,
+            end_freq,
+            ..
+        } if start_freq == end_freq => {
+            let freq = start_freq as f32;
+            Box::new(move |t| (2.0 * PI * freq * t).sin())
+        }
+        MarkerKind::Chirp {
+            start_freq,
+            end_freq,
+            ..
+        } => {
+            let (start_freq, end_freq) = (start_freq as f32, end_freq as f32);
+            let k = (end_freq - start_freq) / (len as f32 / sr);
+            Box::new(move |t| (2.0 * PI * (start_freq * t + 0.5 * k * t * t)).sin())
+        }
+    };
+    (0..len)
+        .map(|n| marker.amplitude * wave(n as f32 / sr) * raised_cosine_window(n, len, fade))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marker(kind: MarkerKind, duration_samples: u32, fade_samples: u32) -> MarkerSpec {
+        MarkerSpec {
+            id: "m".into(),
+            kind,
+            start_sample: 0,
+            duration_samples,
+            fade_samples,
+            amplitude: 1.0,
+            search_window_samples: None,
+        }
+    }
+
+    #[test]
+    fn window_fades_both_ends() {
+        assert_eq!(raised_cosine_window(0, 100, 10), 0.0);
+        assert_eq!(raised_cosine_window(50, 100, 10), 1.0);
+        assert_eq!(raised_cosine_window(99, 100, 10), 0.0);
+        assert!((raised_cosine_window(5, 100, 10) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn click_reference_is_windowed_dc() {
+        let reference = reference_samples(&marker(MarkerKind::Click, 48, 12), 48_000);
+        assert_eq!(reference.len(), 48);
+        assert_eq!(reference[0], 0.0);
+        assert!(reference.iter().all(|s| (0.0..=1.0).contains(s)));
+        assert_eq!(reference[24], 1.0);
+
+        let quiet = MarkerSpec {
+            amplitude: 0.25,
+            ..marker(MarkerKind::Click, 48, 12)
+        };
+        assert_eq!(reference_samples(&quiet, 48_000)[24], 0.25);
+    }
+
+    #[test]
+    fn tone_reference_has_expected_period() {
+        let tone = MarkerKind::Chirp {
+            start_freq: 1_000,
+            end_freq: 1_000,
+            duration_ms: 10,
+        };
+        let reference = reference_samples(&marker(tone, 480, 0), 48_000);
+        // 48 samples per cycle at 1kHz; away from the edges the waveform repeats.
+        for n in 48..200 {
+            assert!((reference[n] - reference[n + 48]).abs() < 1e-3);
+        }
+    }
+}
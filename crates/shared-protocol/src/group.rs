@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+
+/// Largest relative delay a member may be given, matching the calibration clamp.
+pub const MAX_RELATIVE_OFFSET_MS: f32 = 250.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupMember {
+    pub receiver_id: String,
+    /// Extra delay applied to this member relative to the group, in milliseconds.
+    /// Positive values make the member play later.
+    pub relative_offset_ms: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupConfig {
+    pub group_id: String,
+    pub name: String,
+    pub members: Vec<GroupMember>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum GroupValidationError {
+    #[error("group has no members")]
+    NoMembers,
+    #[error("receiver `{0}` is listed more than once")]
+    DuplicateMember(String),
+    #[error("relative offset for `{0}` must be finite and within ±{MAX_RELATIVE_OFFSET_MS}ms")]
+    OffsetOutOfRange(String),
+    #[error("group_id must not be empty")]
+    EmptyGroupId,
+}
+
+impl GroupConfig {
+    /// The member responsible for the group: the lowest receiver id, so every member
+    /// agrees without extra coordination.
+    pub fn coordinator_id(&self) -> Option<&str> {
+        self.members.iter().map(|m| m.receiver_id.as_str()).min()
+    }
+
+    pub fn member(&self, receiver_id: &str) -> Option<&GroupMember> {
+        self.members.iter().find(|m| m.receiver_id == receiver_id)
+    }
+
+    pub fn validate(&self) -> Result<(), GroupValidationError> {
+        if self.group_id.trim().is_empty() {
+            return Err(GroupValidationError::EmptyGroupId);
+        }
+        if self.members.is_empty() {
+            return Err(GroupValidationError::NoMembers);
+        }
+        for (i, member) in self.members.iter().enumerate() {
+            if self.members[..i].iter().any(|m| m.receiver_id == member.receiver_id) {
+                return Err(GroupValidationError::DuplicateMember(member.receiver_id.clone()));
+            }
+            if !member.relative_offset_ms.is_finite()
+                || member.relative_offset_ms.abs() > MAX_RELATIVE_OFFSET_MS
+            {
+                return Err(GroupValidationError::OffsetOutOfRange(member.receiver_id.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sent by the coordinator to each member's `/api/group/assign`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupAssignment {
+    pub group_id: String,
+    pub coordinator_id: String,
+    pub relative_offset_ms: f32,
+    /// Only check whether the member could join; nothing is applied or stored.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Sent to a member's `DELETE /api/group/assign` when its group is dissolved.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupRelease {
+    pub group_id: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(id: &str, offset: f32) -> GroupMember {
+        GroupMember {
+            receiver_id: id.into(),
+            relative_offset_ms: offset,
+        }
+    }
+
+    fn group(members: Vec<GroupMember>) -> GroupConfig {
+        GroupConfig {
+            group_id: "g1".into(),
+            name: "Downstairs".into(),
+            members,
+        }
+    }
+
+    #[test]
+    fn coordinator_is_lowest_receiver_id() {
+        let g = group(vec![member("rx-c", 0.0), member("rx-a", 12.0), member("rx-b", 0.0)]);
+        assert_eq!(g.coordinator_id(), Some("rx-a"));
+        assert_eq!(g.member("rx-a").unwrap().relative_offset_ms, 12.0);
+        assert!(group(vec![]).coordinator_id().is_none());
+    }
+
+    #[test]
+    fn validates_members_and_offsets() {
+        assert!(group(vec![member("rx-a", 0.0), member("rx-b", -250.0)]).validate().is_ok());
+        assert_eq!(group(vec![]).validate(), Err(GroupValidationError::NoMembers));
+        assert_eq!(
+            group(vec![member("rx-a", 0.0), member("rx-a", 5.0)]).validate(),
+            Err(GroupValidationError::DuplicateMember("rx-a".into()))
+        );
+        assert_eq!(
+            group(vec![member("rx-a", f32::NAN)]).validate(),
+            Err(GroupValidationError::OffsetOutOfRange("rx-a".into()))
+        );
+        assert_eq!(
+            group(vec![member("rx-a", 251.0)]).validate(),
+            Err(GroupValidationError::OffsetOutOfRange("rx-a".into()))
+        );
+        let mut unnamed = group(vec![member("rx-a", 0.0)]);
+        unnamed.group_id = " ".into();
+        assert_eq!(unnamed.validate(), Err(GroupValidationError::EmptyGroupId));
+    }
+
+    #[test]
+    fn assignment_dry_run_defaults_to_false() {
+        let assignment: GroupAssignment = serde_json::from_str(
+            r#"{"group_id":"g1","coordinator_id":"rx-a","relative_offset_ms":10.0}"#,
+        )
+        .unwrap();
+        assert!(!assignment.dry_run);
+    }
+}
@@ -1,7 +1,17 @@
+pub mod capability;
 pub mod device;
 pub mod messages;
 pub mod calibration;
+pub mod discovery;
+pub mod dsp;
+pub mod group;
+pub mod timesync;
 
+pub use capability::*;
 pub use device::*;
 pub use messages::*;
 pub use calibration::*;
+pub use discovery::*;
+pub use dsp::*;
+pub use group::*;
+pub use timesync::*;
@@ -26,6 +26,8 @@ pub enum WebSocketMessage {
 pub enum PlaybackStatus {
     Idle,
     Playing,
+    /// The AirPlay source paused; the session is still open.
+    Paused,
     Calibrating,
 }
 
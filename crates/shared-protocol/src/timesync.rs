@@ -0,0 +1,146 @@
+use serde::{Deserialize, Serialize};
+
+/// Body of `GET /api/time`. When the request carries `?t0=<client send ms>` the server
+/// echoes it and adds its own receive/send stamps so clients can separate offset from RTT.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimeSyncResponse {
+    pub server_time_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_send_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_receive_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_send_ms: Option<u64>,
+}
+
+/// One four-timestamp exchange: client send (t0), server receive (t1), server send (t2)
+/// and client receive (t3), each on its own clock, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSample {
+    pub t0: u64,
+    pub t1: u64,
+    pub t2: u64,
+    pub t3: u64,
+}
+
+impl TimeSample {
+    /// Build a sample from a multi-timestamp response received at `t3`. Responses from
+    /// servers without the extension fall back to `server_time_ms` for both stamps.
+    pub fn from_response(t0: u64, response: &TimeSyncResponse, t3: u64) -> TimeSample {
+        let t1 = response.server_receive_ms.unwrap_or(response.server_time_ms);
+        let t2 = response.server_send_ms.unwrap_or(response.server_time_ms);
+        TimeSample { t0, t1, t2, t3 }
+    }
+
+    /// Server clock minus client clock, assuming symmetric network delay.
+    pub fn offset_ms(&self) -> f32 {
+        ((self.t1 as f64 - self.t0 as f64) + (self.t2 as f64 - self.t3 as f64)) as f32 / 2.0
+    }
+
+    /// Round trip spent on the network, excluding server processing time.
+    pub fn rtt_ms(&self) -> f32 {
+        let total = self.t3 as f64 - self.t0 as f64;
+        let processing = self.t2 as f64 - self.t1 as f64;
+        (total - processing).max(0.0) as f32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct OffsetEstimate {
+    pub offset_ms: f32,
+    pub rtt_ms: f32,
+    /// Spread of the offsets that contributed to the estimate.
+    pub stddev_ms: f32,
+    pub samples_used: usize,
+}
+
+/// Estimate the clock offset from a batch of exchanges. Only the faster half of the
+/// samples (by RTT) is used, since queueing delay is rarely symmetric; the offset and
+/// RTT are the medians of that half.
+pub fn estimate_offset(samples: &[TimeSample]) -> Option<OffsetEstimate> {
+    if samples.is_empty() {
+        return None;
+    }
+    let mut by_rtt = samples.to_vec();
+    by_rtt.sort_by(|a, b| a.rtt_ms().total_cmp(&b.rtt_ms()));
+    by_rtt.truncate(samples.len().div_ceil(2));
+
+    let offsets: Vec<f32> = by_rtt.iter().map(TimeSample::offset_ms).collect();
+    let rtts: Vec<f32> = by_rtt.iter().map(TimeSample::rtt_ms).collect();
+    let mean = offsets.iter().sum::<f32>() / offsets.len() as f32;
+    let variance = offsets.iter().map(|o| (o - mean).powi(2)).sum::<f32>() / offsets.len() as f32;
+    Some(OffsetEstimate {
+        offset_ms: median(offsets),
+        rtt_ms: median(rtts),
+        stddev_ms: variance.sqrt(),
+        samples_used: by_rtt.len(),
+    })
+}
+
+fn median(mut values: Vec<f32>) -> f32 {
+    values.sort_by(f32::total_cmp);
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A server `skew` ms ahead, with `up`/`down` ms of network delay and 1ms processing.
+    fn exchange(t0: u64, skew: i64, up: u64, down: u64) -> TimeSample {
+        let t1 = (t0 + up) as i64 + skew;
+        TimeSample {
+            t0,
+            t1: t1 as u64,
+            t2: (t1 + 1) as u64,
+            t3: t0 + up + 1 + down,
+        }
+    }
+
+    #[test]
+    fn symmetric_delay_has_no_offset() {
+        let sample = exchange(1_000, 0, 20, 20);
+        assert_eq!(sample.offset_ms(), 0.0);
+        assert_eq!(sample.rtt_ms(), 40.0);
+    }
+
+    #[test]
+    fn recovers_fixed_skew() {
+        let samples: Vec<TimeSample> = (0..8).map(|i| exchange(i * 100, 250, 10, 10)).collect();
+        let estimate = estimate_offset(&samples).unwrap();
+        assert_eq!(estimate.offset_ms, 250.0);
+        assert_eq!(estimate.rtt_ms, 20.0);
+        assert_eq!(estimate.stddev_ms, 0.0);
+        assert_eq!(estimate.samples_used, 4);
+    }
+
+    #[test]
+    fn slow_asymmetric_samples_are_discarded() {
+        let mut samples: Vec<TimeSample> = (0..4).map(|i| exchange(i * 100, -40, 5, 5)).collect();
+        // Queueing on the way back inflates RTT and biases the offset; it must not win.
+        samples.extend((4..8).map(|i| exchange(i * 100, -40, 5, 300)));
+        let estimate = estimate_offset(&samples).unwrap();
+        assert_eq!(estimate.offset_ms, -40.0);
+        assert!(estimate_offset(&[]).is_none());
+    }
+
+    #[test]
+    fn legacy_response_uses_server_time_for_both_stamps() {
+        let response = TimeSyncResponse {
+            server_time_ms: 5_010,
+            client_send_ms: None,
+            server_receive_ms: None,
+            server_send_ms: None,
+        };
+        let sample = TimeSample::from_response(5_000, &response, 5_020);
+        assert_eq!((sample.t1, sample.t2), (5_010, 5_010));
+        assert_eq!(sample.offset_ms(), 0.0);
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json, serde_json::json!({ "server_time_ms": 5_010 }));
+    }
+}
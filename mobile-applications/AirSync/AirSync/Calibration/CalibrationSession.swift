@@ -8,12 +8,15 @@ struct CalibrationResultPayload: Codable, Equatable {
     let latencyMs: Double
     let confidence: Double
     let detections: [DetectionPayload]
+    /// Session from the calibration request; the receiver refuses results without it.
+    let sessionId: String
 
     enum CodingKeys: String, CodingKey {
         case timestamp
         case latencyMs = "latency_ms"
         case confidence
         case detections
+        case sessionId = "session_id"
     }
 }
 
@@ -47,8 +50,9 @@ struct CalibrationRequestPayload: Codable {
 
 protocol CalibrationAPI {
     func serverTimeMs() async throws -> UInt64
-    func startPlayback(_ config: ChirpConfig, delayMs: UInt64, structured: Bool) async throws
-    func triggerPlayback(targetStartMs: UInt64) async throws
+    /// Returns the session id the receiver assigned, to send back with ready and the result.
+    func startPlayback(_ config: ChirpConfig, delayMs: UInt64, structured: Bool) async throws -> String
+    func triggerPlayback(targetStartMs: UInt64, sessionId: String) async throws
     func submitResult(_ result: CalibrationResultPayload) async throws
     func fetchCalibrationSpec() async throws -> CalibrationSignalSpec
 }
@@ -119,6 +123,8 @@ enum CalibrationError: LocalizedError {
 final class CalibrationSession: ObservableObject {
     @Published private(set) var stage: CalibrationStage = .idle
     @Published private(set) var latestMeasurement: LatencyMeasurement?
+    /// Session `latestMeasurement` was taken in.
+    private var latestSessionId: String?
     @Published private(set) var progress: Double = 0
     @Published private(set) var calculationProgress: Double = 0
     @Published private(set) var micPulse: Bool = false
@@ -171,7 +177,7 @@ final class CalibrationSession: ObservableObject {
             let sampleRate = Double(spec.sampleRate)
             frequencyRange = Self.frequencyRange(from: spec)
 
-            try await api.startPlayback(config, delayMs: playbackDelayMs, structured: true)
+            let sessionId = try await api.startPlayback(config, delayMs: playbackDelayMs, structured: true)
             let delaySeconds = Double(playbackDelayMs) / 1_000
             let lengthSeconds = Double(spec.lengthSamples) / Double(spec.sampleRate)
             let recordDuration: TimeInterval = delaySeconds + lengthSeconds + 1.0
@@ -196,7 +202,7 @@ final class CalibrationSession: ObservableObject {
                     }
                 )
             }
-            try await api.triggerPlayback(targetStartMs: targetStart)
+            try await api.triggerPlayback(targetStartMs: targetStart, sessionId: sessionId)
             let recording = try await recordingTask?.value ?? RecordedAudio(samples: [], startedAtMs: Self.timestampNow())
             let rms = Self.rms(recording.samples)
             let peak = recording.samples.map { abs($0) }.max() ?? 0
@@ -224,6 +230,7 @@ final class CalibrationSession: ObservableObject {
                 "Calibration measurement lat_ms=\(measurement.latencyMs) conf=\(measurement.confidence) detections=\(detectionCount) top_corr=\(topDetection?.correlation ?? 0) top_sample_idx=\(topDetection?.sampleIndex ?? 0)"
             )
             latestMeasurement = measurement
+            latestSessionId = sessionId
             calcProgressTask?.cancel()
             calculationProgress = 1
 
@@ -243,7 +250,8 @@ final class CalibrationSession: ObservableObject {
                         correlation: $0.correlation,
                         latencyMs: $0.latencyMs
                     )
-                }
+                },
+                sessionId: sessionId
             )
 
             try await api.submitResult(payload)
@@ -406,7 +414,7 @@ extension CalibrationSession {
 
 extension CalibrationSession {
     func applyLatestMeasurement() async {
-        guard let measurement = latestMeasurement else { return }
+        guard let measurement = latestMeasurement, let sessionId = latestSessionId else { return }
         stage = .sending
         do {
             let payload = CalibrationResultPayload(
@@ -420,7 +428,8 @@ extension CalibrationSession {
                         correlation: $0.correlation,
                         latencyMs: $0.latencyMs
                     )
-                }
+                },
+                sessionId: sessionId
             )
             try await api.submitResult(payload)
             stage = .completed(measurement)
@@ -445,8 +454,10 @@ private struct SilentRecorder: MicrophoneRecorder {
 
 private struct NoopCalibrationAPI: CalibrationAPI {
     func serverTimeMs() async throws -> UInt64 { 0 }
-    func startPlayback(_ config: ChirpConfig, delayMs: UInt64, structured: Bool) async throws {}
-    func triggerPlayback(targetStartMs: UInt64) async throws {}
+    func startPlayback(_ config: ChirpConfig, delayMs: UInt64, structured: Bool) async throws -> String {
+        UUID().uuidString
+    }
+    func triggerPlayback(targetStartMs: UInt64, sessionId: String) async throws {}
     func submitResult(_ result: CalibrationResultPayload) async throws {}
     func fetchCalibrationSpec() async throws -> CalibrationSignalSpec {
         throw URLError(.badURL)
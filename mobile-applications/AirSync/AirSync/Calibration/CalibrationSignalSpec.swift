@@ -73,10 +73,13 @@ struct CalibrationSignalSpec: Decodable, Equatable {
     let sampleRate: UInt32
     let lengthSamples: UInt32
     let markers: [MarkerSpec]
+    /// Identifier encoded in the `signal_id_*` tone markers, if the receiver adds one.
+    var signalId: UInt16? = nil
 
     private enum CodingKeys: String, CodingKey {
         case sampleRate = "sample_rate"
         case lengthSamples = "length_samples"
         case markers
+        case signalId = "signal_id"
     }
 }
@@ -33,7 +33,7 @@ final class ReceiverCalibrationClient: CalibrationAPI {
         return response.serverTimeMs
     }
 
-    func startPlayback(_ config: ChirpConfig, delayMs: UInt64, structured: Bool) async throws {
+    func startPlayback(_ config: ChirpConfig, delayMs: UInt64, structured: Bool) async throws -> String {
         let payload = CalibrationRequestPayload(
             timestamp: Self.timestampNow(),
             chirpConfig: config,
@@ -46,16 +46,21 @@ final class ReceiverCalibrationClient: CalibrationAPI {
         request.httpBody = try JSONEncoder().encode(payload)
         request.setValue("application/json", forHTTPHeaderField: "Content-Type")
 
-        _ = try await session.data(for: request)
+        let (data, response) = try await session.data(for: request)
+        guard let http = response as? HTTPURLResponse, http.statusCode == 200 else {
+            throw URLError(.badServerResponse)
+        }
+        return try JSONDecoder().decode(CalibrationRequestResponse.self, from: data).sessionId
     }
 
-    func triggerPlayback(targetStartMs: UInt64) async throws {
+    func triggerPlayback(targetStartMs: UInt64, sessionId: String) async throws {
         var request = URLRequest(url: endpoint(path: "api/calibration/ready"))
         request.httpMethod = "POST"
         request.httpBody = try JSONEncoder().encode(
             CalibrationReadyPayload(
                 timestamp: Self.timestampNow(),
-                targetStartMs: targetStartMs
+                targetStartMs: targetStartMs,
+                sessionId: sessionId
             )
         )
         request.setValue("application/json", forHTTPHeaderField: "Content-Type")
@@ -93,12 +98,22 @@ private struct CalibrationSpecResponse: Decodable {
     let spec: CalibrationSignalSpec
 }
 
+private struct CalibrationRequestResponse: Decodable {
+    let sessionId: String
+
+    enum CodingKeys: String, CodingKey {
+        case sessionId = "session_id"
+    }
+}
+
 private struct CalibrationReadyPayload: Encodable {
     let timestamp: UInt64
     let targetStartMs: UInt64
+    let sessionId: String
 
     enum CodingKeys: String, CodingKey {
         case timestamp
         case targetStartMs = "target_start_ms"
+        case sessionId = "session_id"
     }
 }
@@ -45,6 +45,8 @@ final class CalibrationSessionTests: XCTestCase {
         XCTAssertEqual(api.lastDelayMs, 3_000)
         XCTAssertNotNil(api.submittedResult)
         XCTAssertEqual(api.submittedResult?.latencyMs ?? 0, measurement.latencyMs, accuracy: 7)
+        XCTAssertEqual(api.lastReadySessionId, MockCalibrationAPI.sessionId)
+        XCTAssertEqual(api.submittedResult?.sessionId, MockCalibrationAPI.sessionId)
         XCTAssertGreaterThan(api.submittedResult?.confidence ?? 0, 0.3)
     }
 
@@ -100,12 +102,15 @@ private final class FailingRecorder: MicrophoneRecorder {
 }
 
 private final class MockCalibrationAPI: CalibrationAPI {
+    static let sessionId = "6f1c2f0e-3b9a-4d2e-9c51-0a7d8e4b2c13"
+
     private(set) var startRequests = 0
     private(set) var submittedResult: CalibrationResultPayload?
     private(set) var lastDelayMs: UInt64?
     private(set) var serverTimeRequests = 0
     private(set) var triggerRequests = 0
     private(set) var lastTargetStartMs: UInt64?
+    private(set) var lastReadySessionId: String?
     private var spec: CalibrationSignalSpec = CalibrationSignalSpec(
         sampleRate: 48_000,
         lengthSamples: 4_000,
@@ -117,14 +122,16 @@ private final class MockCalibrationAPI: CalibrationAPI {
         return 1_000
     }
 
-    func startPlayback(_ config: ChirpConfig, delayMs: UInt64, structured: Bool) async throws {
+    func startPlayback(_ config: ChirpConfig, delayMs: UInt64, structured: Bool) async throws -> String {
         startRequests += 1
         lastDelayMs = delayMs
+        return Self.sessionId
     }
 
-    func triggerPlayback(targetStartMs: UInt64) async throws {
+    func triggerPlayback(targetStartMs: UInt64, sessionId: String) async throws {
         triggerRequests += 1
         lastTargetStartMs = targetStartMs
+        lastReadySessionId = sessionId
     }
 
     func submitResult(_ result: CalibrationResultPayload) async throws {